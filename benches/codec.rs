@@ -0,0 +1,52 @@
+#![feature(test)]
+
+extern crate test;
+
+use bytes::BytesMut;
+use coap::message::Codec;
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet, RequestType as Method};
+use tokio_util::codec::{Decoder, Encoder};
+
+/// A small request with a few options, as sent at high packet rates.
+fn request() -> Packet {
+    let mut packet = Packet::new();
+    packet.header.set_type(MessageType::Confirmable);
+    packet.header.code = MessageClass::Request(Method::Get);
+    packet.header.message_id = 1;
+    packet.set_token(vec![0x51, 0x55, 0x77, 0xE8]);
+    packet.add_option(CoapOption::UriHost, b"sensor.example".to_vec());
+    packet.add_option(CoapOption::UriPath, b"sensors".to_vec());
+    packet.add_option(CoapOption::UriPath, b"temperature".to_vec());
+    packet.add_option(CoapOption::Accept, vec![50]);
+    packet.add_option(CoapOption::UriQuery, b"unit=celsius".to_vec());
+    packet
+}
+
+/// The copy of the message that encoding takes, to subtract from
+/// `bench_encode`.
+#[bench]
+fn bench_clone(b: &mut test::Bencher) {
+    let packet = request();
+    b.iter(|| test::black_box(packet.clone()));
+}
+
+#[bench]
+fn bench_encode(b: &mut test::Bencher) {
+    let packet = request();
+    let mut buf = BytesMut::with_capacity(1500);
+    b.iter(|| {
+        buf.clear();
+        Codec::new().encode(packet.clone(), &mut buf).unwrap();
+        test::black_box(&buf);
+    });
+}
+
+#[bench]
+fn bench_decode(b: &mut test::Bencher) {
+    let bytes = request().to_bytes().unwrap();
+    let mut buf = BytesMut::with_capacity(1500);
+    b.iter(|| {
+        buf.extend_from_slice(&bytes);
+        test::black_box(Codec::new().decode(&mut buf).unwrap());
+    });
+}
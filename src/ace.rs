@@ -0,0 +1,738 @@
+//! ACE-OAuth resource server ([RFC 9200](https://tools.ietf.org/html/rfc9200))
+//! for the DTLS profile ([RFC 9202](https://tools.ietf.org/html/rfc9202))
+//! with raw public keys.
+//!
+//! Clients obtain an access token from an authorization server and POST it
+//! to `/authz-info`. The token is a CWT ([RFC 8392](https://tools.ietf.org/html/rfc8392)),
+//! signed with ES256 (COSE_Sign1) or authenticated with HMAC 256/256
+//! (COSE_Mac0) by the authorization server, whose `cnf` claim holds the key
+//! the client uses for its DTLS handshake. [`ResourceServer::key_filter`]
+//! admits these keys to a [`DtlsTransport`](crate::transport::DtlsTransport)
+//! and [`ResourceServer::authorize`] checks requests against the scope of
+//! the token, which is either text naming resources or an AIF
+//! ([RFC 9237](https://tools.ietf.org/html/rfc9237)).
+//!
+//! Requires the `ace` feature. The OSCORE profile (RFC 9203) and tokens
+//! referring to symmetric keys are not supported.
+use ciborium::value::Value;
+use coap_lite::{CoapRequest, RequestType as Method, ResponseType as Status};
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey, EcPoint};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::{hash, MessageDigest};
+use openssl::memcmp;
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey, PKeyRef, Public};
+use openssl::sign::Signer;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::audit::{self, SecurityEvent, SecurityEventHandler};
+
+/// Path of the authorization information endpoint.
+pub const AUTHZ_INFO_PATH: &str = "authz-info";
+
+const TAG_CWT: u64 = 61;
+const TAG_COSE_SIGN1: u64 = 18;
+const TAG_COSE_MAC0: u64 = 17;
+const ALG_ES256: i128 = -7;
+const ALG_HMAC_256: i128 = 5;
+
+/// The key the authorization server protects its tokens with.
+#[derive(Clone)]
+pub enum Verifier {
+    /// Verify COSE_Sign1 tokens signed with ES256 by this key.
+    Es256(PKey<Public>),
+    /// Verify COSE_Mac0 tokens authenticated with HMAC 256/256 by this
+    /// shared secret.
+    Hmac256(Vec<u8>),
+}
+
+#[derive(Debug)]
+pub enum TokenError {
+    /// The token is not a CWT this server understands.
+    Malformed(String),
+    /// The signature or MAC does not match.
+    BadSignature,
+    /// The token is meant for another audience.
+    WrongAudience,
+    Expired,
+    NotYetValid,
+    /// The token does not confirm a key of the client.
+    MissingConfirmation,
+}
+
+impl fmt::Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenError::Malformed(reason) => write!(f, "malformed token: {}", reason),
+            TokenError::BadSignature => write!(f, "bad token signature"),
+            TokenError::WrongAudience => write!(f, "token for another audience"),
+            TokenError::Expired => write!(f, "token expired"),
+            TokenError::NotYetValid => write!(f, "token not yet valid"),
+            TokenError::MissingConfirmation => write!(f, "token without confirmation key"),
+        }
+    }
+}
+
+impl error::Error for TokenError {}
+
+fn malformed<T>(reason: &str) -> Result<T, TokenError> {
+    Err(TokenError::Malformed(reason.to_string()))
+}
+
+/// The access rights granted by a token.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// Space-separated scope elements, each naming a resource that may be
+    /// accessed with any method.
+    Text(Vec<String>),
+    /// Resource paths with the methods allowed on them, as a bit mask with
+    /// bit `n - 1` for method code `n`.
+    Aif(Vec<(String, u64)>),
+}
+
+impl Scope {
+    /// Return whether the scope allows `method` on the resource at `path`.
+    pub fn allows(&self, path: &str, method: Method) -> bool {
+        let path = path.trim_start_matches('/');
+        match self {
+            Scope::Text(elements) => elements
+                .iter()
+                .any(|element| element.trim_start_matches('/') == path),
+            Scope::Aif(rights) => rights.iter().any(|(resource, methods)| {
+                resource.trim_start_matches('/') == path && methods & method_bit(method) != 0
+            }),
+        }
+    }
+}
+
+fn method_bit(method: Method) -> u64 {
+    match method {
+        Method::Get => 1,
+        Method::Post => 1 << 1,
+        Method::Put => 1 << 2,
+        Method::Delete => 1 << 3,
+        Method::Fetch => 1 << 4,
+        Method::Patch => 1 << 5,
+        Method::IPatch => 1 << 6,
+        _ => 0,
+    }
+}
+
+/// A validated access token.
+#[derive(Clone, Debug)]
+pub struct AccessToken {
+    pub issuer: Option<String>,
+    pub subject: Option<String>,
+    pub expires: Option<SystemTime>,
+    pub scope: Scope,
+    /// The key the client proves possession of.
+    pub key: PKey<Public>,
+}
+
+impl AccessToken {
+    fn is_expired(&self) -> bool {
+        self.expires
+            .is_some_and(|expires| expires <= SystemTime::now())
+    }
+}
+
+/// Validates access tokens and keeps those of the clients, by key.
+#[derive(Clone)]
+pub struct ResourceServer {
+    audience: String,
+    verifier: Verifier,
+    tokens: Arc<Mutex<HashMap<Vec<u8>, AccessToken>>>,
+    security_event_handler: Option<SecurityEventHandler>,
+}
+
+impl ResourceServer {
+    /// Create a resource server known as `audience` to the authorization
+    /// server, which protects its tokens with `verifier`.
+    pub fn new<S: Into<String>>(audience: S, verifier: Verifier) -> ResourceServer {
+        ResourceServer {
+            audience: audience.into(),
+            verifier,
+            tokens: Arc::default(),
+            security_event_handler: None,
+        }
+    }
+
+    /// Report the tokens rejected at `/authz-info` to `handler`.
+    pub fn set_security_event_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(&SecurityEvent) + Send + Sync + 'static,
+    {
+        self.security_event_handler = Some(Arc::new(handler));
+    }
+
+    /// Validate a token without keeping it.
+    pub fn validate(&self, token: &[u8]) -> Result<AccessToken, TokenError> {
+        let claims = self.open(token)?;
+        self.read_claims(&claims)
+    }
+
+    /// Validate a token and keep it for the key it confirms, replacing any
+    /// previous token of that key.
+    pub fn add_token(&self, token: &[u8]) -> Result<AccessToken, TokenError> {
+        let token = self.validate(token)?;
+        let spki = token
+            .key
+            .public_key_to_der()
+            .map_err(|e| TokenError::Malformed(e.to_string()))?;
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, token| !token.is_expired());
+        tokens.insert(spki, token.clone());
+        Ok(token)
+    }
+
+    /// Answer a POST of a token to `/authz-info` and return true, or return
+    /// false for any other request.
+    pub fn handle_authz_info(&self, request: &mut CoapRequest<SocketAddr>) -> bool {
+        if request.get_path() != AUTHZ_INFO_PATH {
+            return false;
+        }
+        let status = if *request.get_method() != Method::Post {
+            Status::MethodNotAllowed
+        } else {
+            let result = self.add_token(&request.message.payload);
+            if let (Err(e), Some(peer)) = (&result, request.source) {
+                let reason = e.to_string();
+                let event = SecurityEvent::TokenRejected { peer, reason };
+                audit::emit(&self.security_event_handler, event);
+            }
+            match result {
+                Ok(_) => Status::Created,
+                Err(TokenError::Malformed(_)) => Status::BadRequest,
+                Err(_) => Status::Unauthorized,
+            }
+        };
+        if let Some(response) = request.response.as_mut() {
+            response.set_status(status);
+        }
+        true
+    }
+
+    /// Return the valid token of the client with the given key.
+    pub fn token(&self, key: &PKeyRef<Public>) -> Option<AccessToken> {
+        let spki = key.public_key_to_der().ok()?;
+        self.token_of(&spki)
+    }
+
+    fn token_of(&self, spki: &[u8]) -> Option<AccessToken> {
+        let tokens = self.tokens.lock().unwrap();
+        tokens
+            .get(spki)
+            .filter(|token| !token.is_expired())
+            .cloned()
+    }
+
+    /// Return a filter for
+    /// [`RawPublicKeyConfig::set_key_filter`](crate::transport::dtls::RawPublicKeyConfig::set_key_filter)
+    /// that admits the keys of valid tokens to DTLS.
+    pub fn key_filter(&self) -> impl Fn(&[u8]) -> bool + Send + Sync + 'static {
+        let server = self.clone();
+        move |spki| server.token_of(spki).is_some()
+    }
+
+    /// Check a request of the client with the given key, as authenticated by
+    /// DTLS, against the scope of its token. Fails with 4.01 Unauthorized
+    /// without valid token and 4.03 Forbidden outside its scope.
+    ///
+    /// Only keys of requests that arrived over DTLS may be passed; when the
+    /// server also listens on other transports, check
+    /// [`server::ingress`](crate::server::ingress) first.
+    pub fn authorize(
+        &self,
+        key: Option<&PKeyRef<Public>>,
+        request: &CoapRequest<SocketAddr>,
+    ) -> Result<AccessToken, Status> {
+        let token = key
+            .and_then(|key| self.token(key))
+            .ok_or(Status::Unauthorized)?;
+        if token
+            .scope
+            .allows(&request.get_path(), *request.get_method())
+        {
+            Ok(token)
+        } else {
+            Err(Status::Forbidden)
+        }
+    }
+
+    /// Verify the COSE wrapping of a token and return its claims.
+    fn open(&self, token: &[u8]) -> Result<Vec<u8>, TokenError> {
+        let mut value = decode(token)?;
+        if let Value::Tag(TAG_CWT, inner) = value {
+            value = *inner;
+        }
+        let (tag, value) = match value {
+            Value::Tag(tag, inner) => (Some(tag), *inner),
+            value => (None, value),
+        };
+        let Value::Array(items) = value else {
+            return malformed("not a COSE message");
+        };
+        let Ok([protected, _unprotected, payload, signature]) = <[Value; 4]>::try_from(items)
+        else {
+            return malformed("not a COSE message");
+        };
+        let (protected, payload, signature) =
+            (bytes(protected)?, bytes(payload)?, bytes(signature)?);
+        let alg = algorithm(&protected)?;
+
+        let valid = match (&self.verifier, tag) {
+            (Verifier::Es256(key), None | Some(TAG_COSE_SIGN1)) if alg == Some(ALG_ES256) => {
+                let to_be_signed = structure("Signature1", &protected, &payload);
+                verify_es256(key, &to_be_signed, &signature)
+            }
+            (Verifier::Hmac256(key), None | Some(TAG_COSE_MAC0)) if alg == Some(ALG_HMAC_256) => {
+                let to_be_maced = structure("MAC0", &protected, &payload);
+                hmac_sha256(key, &to_be_maced)
+                    .is_some_and(|tag| tag.len() == signature.len() && memcmp::eq(&tag, &signature))
+            }
+            _ => return malformed("unexpected COSE message or algorithm"),
+        };
+        if valid {
+            Ok(payload)
+        } else {
+            Err(TokenError::BadSignature)
+        }
+    }
+
+    fn read_claims(&self, claims: &[u8]) -> Result<AccessToken, TokenError> {
+        let Value::Map(claims) = decode(claims)? else {
+            return malformed("claims are not a map");
+        };
+        let now = SystemTime::now();
+        let (mut issuer, mut subject, mut expires, mut not_before) = (None, None, None, None);
+        let (mut key, mut scope) = (None, None);
+        let mut audience_matches = false;
+        // claim keys of RFC 8392, cnf of RFC 8747 and scope of RFC 9200
+        for (label, value) in claims {
+            match label_of(&label) {
+                Some(1) => issuer = Some(text(value)?),
+                Some(2) => subject = Some(text(value)?),
+                Some(3) => {
+                    audience_matches = match value {
+                        Value::Array(audiences) => audiences
+                            .into_iter()
+                            .any(|audience| audience.as_text() == Some(&self.audience)),
+                        audience => audience.as_text() == Some(&self.audience),
+                    }
+                }
+                Some(4) => expires = Some(time(value)?),
+                Some(5) => not_before = Some(time(value)?),
+                Some(8) => key = Some(read_confirmation(value)?),
+                Some(9) => scope = Some(read_scope(value)?),
+                _ => {}
+            }
+        }
+        if !audience_matches {
+            return Err(TokenError::WrongAudience);
+        }
+        if expires.is_some_and(|expires| expires <= now) {
+            return Err(TokenError::Expired);
+        }
+        if not_before.is_some_and(|not_before| not_before > now) {
+            return Err(TokenError::NotYetValid);
+        }
+        Ok(AccessToken {
+            issuer,
+            subject,
+            expires,
+            scope: scope.unwrap_or(Scope::Text(Vec::new())),
+            key: key.ok_or(TokenError::MissingConfirmation)?,
+        })
+    }
+}
+
+fn decode(data: &[u8]) -> Result<Value, TokenError> {
+    ciborium::de::from_reader(data).map_err(|e| TokenError::Malformed(e.to_string()))
+}
+
+fn encode(value: &Value) -> Vec<u8> {
+    let mut data = Vec::new();
+    // writing to a vector cannot fail
+    ciborium::ser::into_writer(value, &mut data).unwrap();
+    data
+}
+
+fn label_of(value: &Value) -> Option<i128> {
+    value.as_integer().map(i128::from)
+}
+
+fn bytes(value: Value) -> Result<Vec<u8>, TokenError> {
+    match value {
+        Value::Bytes(bytes) => Ok(bytes),
+        _ => malformed("expected a byte string"),
+    }
+}
+
+fn text(value: Value) -> Result<String, TokenError> {
+    match value {
+        Value::Text(text) => Ok(text),
+        _ => malformed("expected a text string"),
+    }
+}
+
+fn time(value: Value) -> Result<SystemTime, TokenError> {
+    let secs = match value {
+        Value::Integer(secs) => {
+            u64::try_from(secs).map_err(|_| TokenError::Malformed("negative time".into()))?
+        }
+        Value::Float(secs) if secs >= 0.0 => secs as u64,
+        _ => return malformed("expected a time"),
+    };
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Return the `alg` of a protected header.
+fn algorithm(protected: &[u8]) -> Result<Option<i128>, TokenError> {
+    if protected.is_empty() {
+        return Ok(None);
+    }
+    let Value::Map(header) = decode(protected)? else {
+        return malformed("protected header is not a map");
+    };
+    Ok(header
+        .iter()
+        .find(|(label, _)| label_of(label) == Some(1))
+        .and_then(|(_, alg)| label_of(alg)))
+}
+
+/// Build the Sig_structure or MAC_structure of a message without external
+/// data.
+fn structure(context: &str, protected: &[u8], payload: &[u8]) -> Vec<u8> {
+    encode(&Value::Array(vec![
+        Value::Text(context.to_string()),
+        Value::Bytes(protected.to_vec()),
+        Value::Bytes(Vec::new()),
+        Value::Bytes(payload.to_vec()),
+    ]))
+}
+
+fn verify_es256(key: &PKey<Public>, data: &[u8], signature: &[u8]) -> bool {
+    // COSE signatures are r and s of 32 bytes each, not DER
+    if signature.len() != 64 {
+        return false;
+    }
+    let verify = || -> Result<bool, openssl::error::ErrorStack> {
+        let r = BigNum::from_slice(&signature[..32])?;
+        let s = BigNum::from_slice(&signature[32..])?;
+        let digest = hash(MessageDigest::sha256(), data)?;
+        let key = key.ec_key()?;
+        EcdsaSig::from_private_components(r, s)?.verify(&digest, &key)
+    };
+    verify().unwrap_or(false)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    let key = PKey::hmac(key).ok()?;
+    Signer::new(MessageDigest::sha256(), &key)
+        .and_then(|mut signer| signer.sign_oneshot_to_vec(data))
+        .ok()
+}
+
+/// Read the COSE_Key of a `cnf` claim.
+fn read_confirmation(value: Value) -> Result<PKey<Public>, TokenError> {
+    let Value::Map(cnf) = value else {
+        return malformed("cnf is not a map");
+    };
+    let Some((_, Value::Map(key))) = cnf
+        .into_iter()
+        .find(|(label, _)| label_of(label) == Some(1))
+    else {
+        return Err(TokenError::MissingConfirmation);
+    };
+    let parameter = |label: i128| {
+        key.iter()
+            .find(|(l, _)| label_of(l) == Some(label))
+            .map(|(_, value)| value)
+    };
+    let coordinate = |label: i128| match parameter(label) {
+        Some(Value::Bytes(bytes)) => Ok(bytes.clone()),
+        _ => malformed("missing key coordinate"),
+    };
+    let invalid = |e: openssl::error::ErrorStack| TokenError::Malformed(e.to_string());
+    match (
+        parameter(1).and_then(label_of),
+        parameter(-1).and_then(label_of),
+    ) {
+        // EC2 on P-256
+        (Some(2), Some(1)) => {
+            let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(invalid)?;
+            let mut point = vec![4];
+            point.extend(coordinate(-2)?);
+            point.extend(coordinate(-3)?);
+            let mut context = openssl::bn::BigNumContext::new().map_err(invalid)?;
+            let point = EcPoint::from_bytes(&group, &point, &mut context).map_err(invalid)?;
+            let key = EcKey::from_public_key(&group, &point).map_err(invalid)?;
+            PKey::from_ec_key(key).map_err(invalid)
+        }
+        // OKP on Ed25519
+        (Some(1), Some(6)) => {
+            PKey::public_key_from_raw_bytes(&coordinate(-2)?, Id::ED25519).map_err(invalid)
+        }
+        _ => malformed("unsupported key type"),
+    }
+}
+
+fn read_scope(value: Value) -> Result<Scope, TokenError> {
+    let value = match value {
+        Value::Text(text) => {
+            return Ok(Scope::Text(
+                text.split_whitespace().map(str::to_string).collect(),
+            ));
+        }
+        // an AIF, encoded
+        Value::Bytes(bytes) => decode(&bytes)?,
+        value => value,
+    };
+    let Value::Array(rights) = value else {
+        return malformed("unsupported scope");
+    };
+    rights
+        .into_iter()
+        .map(|right| match right {
+            Value::Array(right) => match <[Value; 2]>::try_from(right) {
+                Ok([Value::Text(path), Value::Integer(methods)]) => u64::try_from(methods)
+                    .map(|methods| (path, methods))
+                    .map_err(|_| TokenError::Malformed("invalid permissions".into())),
+                _ => malformed("invalid AIF entry"),
+            },
+            _ => malformed("invalid AIF entry"),
+        })
+        .collect::<Result<_, _>>()
+        .map(Scope::Aif)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::transport::dtls::{DtlsClientTransport, RawPublicKeyConfig};
+    use super::super::transport::{DtlsTransport, Transport};
+    use super::super::*;
+    use super::*;
+    use openssl::bn::BigNumContext;
+    use openssl::ec::PointConversionForm;
+    use openssl::pkey::Private;
+
+    const AUDIENCE: &str = "coaps://rs.example";
+
+    fn generate_key() -> PKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    fn public(key: &PKey<Private>) -> PKey<Public> {
+        PKey::public_key_from_der(&key.public_key_to_der().unwrap()).unwrap()
+    }
+
+    fn int(value: i64) -> Value {
+        Value::Integer(value.into())
+    }
+
+    fn aif(path: &str, methods: u64) -> Value {
+        Value::Array(vec![Value::Array(vec![
+            Value::Text(path.to_string()),
+            Value::Integer(methods.into()),
+        ])])
+    }
+
+    /// Claims of a token confirming the key of `client`, valid for an hour.
+    fn claims(client: &PKey<Private>, scope: Value) -> Vec<(Value, Value)> {
+        let ec = client.ec_key().unwrap();
+        let mut context = BigNumContext::new().unwrap();
+        let point = ec
+            .public_key()
+            .to_bytes(ec.group(), PointConversionForm::UNCOMPRESSED, &mut context)
+            .unwrap();
+        let cose_key = Value::Map(vec![
+            (int(1), int(2)),
+            (int(-1), int(1)),
+            (int(-2), Value::Bytes(point[1..33].to_vec())),
+            (int(-3), Value::Bytes(point[33..].to_vec())),
+        ]);
+        let expires =
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap() + Duration::from_secs(3600);
+        vec![
+            (int(1), Value::Text("as.example".to_string())),
+            (int(3), Value::Text(AUDIENCE.to_string())),
+            (int(4), Value::Integer(expires.as_secs().into())),
+            (int(8), Value::Map(vec![(int(1), cose_key)])),
+            (int(9), scope),
+        ]
+    }
+
+    fn cose(
+        tag: u64,
+        alg: i64,
+        claims: Vec<(Value, Value)>,
+        sign: impl Fn(&[u8]) -> Vec<u8>,
+    ) -> Vec<u8> {
+        let protected = encode(&Value::Map(vec![(int(1), int(alg))]));
+        let payload = encode(&Value::Map(claims));
+        let context = if tag == TAG_COSE_SIGN1 {
+            "Signature1"
+        } else {
+            "MAC0"
+        };
+        let signature = sign(&structure(context, &protected, &payload));
+        encode(&Value::Tag(
+            tag,
+            Box::new(Value::Array(vec![
+                Value::Bytes(protected),
+                Value::Map(Vec::new()),
+                Value::Bytes(payload),
+                Value::Bytes(signature),
+            ])),
+        ))
+    }
+
+    fn sign_es256(claims: Vec<(Value, Value)>, key: &PKey<Private>) -> Vec<u8> {
+        cose(TAG_COSE_SIGN1, -7, claims, |data| {
+            let digest = hash(MessageDigest::sha256(), data).unwrap();
+            let signature = EcdsaSig::sign(&digest, &key.ec_key().unwrap()).unwrap();
+            let mut raw = signature.r().to_vec_padded(32).unwrap();
+            raw.extend(signature.s().to_vec_padded(32).unwrap());
+            raw
+        })
+    }
+
+    #[test]
+    fn test_validate() {
+        let as_key = generate_key();
+        let client = generate_key();
+        let rs = ResourceServer::new(AUDIENCE, Verifier::Es256(public(&as_key)));
+
+        let token = rs
+            .validate(&sign_es256(claims(&client, aif("/temp", 1)), &as_key))
+            .unwrap();
+        assert_eq!(token.issuer.as_deref(), Some("as.example"));
+        assert!(token.key.public_eq(&client));
+        assert!(token.scope.allows("temp", Method::Get));
+        assert!(!token.scope.allows("temp", Method::Put));
+        assert!(!token.scope.allows("humidity", Method::Get));
+
+        let forged = sign_es256(claims(&client, aif("/temp", 1)), &generate_key());
+        assert!(matches!(
+            rs.validate(&forged),
+            Err(TokenError::BadSignature)
+        ));
+        let mut expired = claims(&client, aif("/temp", 1));
+        expired[2].1 = int(1_000_000_000);
+        assert!(matches!(
+            rs.validate(&sign_es256(expired, &as_key)),
+            Err(TokenError::Expired)
+        ));
+        let other = ResourceServer::new("coaps://other.example", Verifier::Es256(public(&as_key)));
+        assert!(matches!(
+            other.validate(&sign_es256(claims(&client, aif("/temp", 1)), &as_key)),
+            Err(TokenError::WrongAudience)
+        ));
+        assert!(matches!(
+            rs.validate(b"\x01"),
+            Err(TokenError::Malformed(_))
+        ));
+
+        let secret = b"shared with the AS".to_vec();
+        let rs = ResourceServer::new(AUDIENCE, Verifier::Hmac256(secret.clone()));
+        let scope = Value::Text("temp humidity".to_string());
+        let token = cose(TAG_COSE_MAC0, 5, claims(&client, scope), |data| {
+            hmac_sha256(&secret, data).unwrap()
+        });
+        let token = rs.validate(&token).unwrap();
+        assert!(token.scope.allows("/humidity", Method::Put));
+    }
+
+    #[test]
+    fn test_authz_info() {
+        let as_key = generate_key();
+        let server_key = generate_key();
+        let client_key = generate_key();
+        let mut rs = ResourceServer::new(AUDIENCE, Verifier::Es256(public(&as_key)));
+        let (events_tx, events) = std::sync::mpsc::channel();
+        rs.set_security_event_handler(move |event| {
+            events_tx.send(event.clone()).unwrap();
+        });
+
+        let mut dtls_config = RawPublicKeyConfig::new(server_key.clone());
+        dtls_config.set_key_filter(rs.key_filter());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let dtls =
+                        DtlsTransport::bind_raw_public_key("127.0.0.1:0", &dtls_config).unwrap();
+                    let dtls_addr = dtls.local_addr().unwrap();
+                    let peers = dtls.peer_certificates();
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    let dtls_index = server.add_transport(dtls);
+                    tx.send((server.socket_addr().unwrap(), dtls_addr)).unwrap();
+                    server
+                        .run(move |mut req: CoapRequest<SocketAddr>| {
+                            // only DTLS authenticates the key
+                            let key = peers
+                                .public_key(&req.source.unwrap())
+                                .filter(|_| server::ingress() == Some(dtls_index));
+                            let rs = rs.clone();
+                            async move {
+                                if !rs.handle_authz_info(&mut req) {
+                                    let status = match rs.authorize(key.as_deref(), &req) {
+                                        Ok(_) => Status::Content,
+                                        Err(status) => status,
+                                    };
+                                    req.response.as_mut()?.set_status(status);
+                                }
+                                req.response
+                            }
+                        })
+                        .await
+                        .unwrap();
+                })
+        });
+        let (udp_addr, dtls_addr) = rx.recv().unwrap();
+
+        let mut dtls_config = RawPublicKeyConfig::new(client_key.clone());
+        dtls_config.add_pinned_key(&server_key).unwrap();
+        dtls_config.set_handshake_timeout(Duration::from_secs(5));
+        // without token the key is unknown
+        assert!(DtlsClientTransport::connect_with_raw_public_key(dtls_addr, &dtls_config).is_err());
+
+        let mut client = CoAPClient::new(udp_addr).unwrap();
+        let forged = sign_es256(claims(&client_key, aif("/temp", 1)), &client_key);
+        let response = client
+            .request_path("/authz-info", Method::Post, Some(forged), None, None)
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::Unauthorized);
+        assert!(matches!(
+            events.try_recv().unwrap(),
+            SecurityEvent::TokenRejected { .. }
+        ));
+        let token = sign_es256(claims(&client_key, aif("/temp", 1)), &as_key);
+        let response = client
+            .request_path("/authz-info", Method::Post, Some(token), None, None)
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::Created);
+        let response = client
+            .request_path("/temp", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::Unauthorized);
+
+        let mut client = CoAPClient::new_dtls_raw_public_key(dtls_addr, &dtls_config).unwrap();
+        let response = client
+            .request_path("/temp", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        let response = client
+            .request_path("/temp", Method::Put, None, None, None)
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::Forbidden);
+    }
+}
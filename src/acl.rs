@@ -0,0 +1,394 @@
+//! Access control lists granting methods on resources to authenticated
+//! peers, enforced by the server before the handler runs.
+//!
+//! An [`Acl`] is a list of rules, each allowing the peers matching a
+//! [`Principal`] to use some methods on the resources matching a pattern.
+//! Peers are identified by the security layer of the transport they use,
+//! see [`PeerIdentity`]. A request that no rule allows is answered with
+//! 4.01 Unauthorized if the peer did not authenticate and with 4.03
+//! Forbidden otherwise.
+//!
+//! Resource patterns are paths in which `*` stands for any sequence of
+//! characters, so `/sensors/*` covers `/sensors/temp` and everything below
+//! it. Rules can be read from a file with one rule per line: the principal,
+//! the resource pattern and a comma-separated list of methods, or `*` for
+//! all of them. Empty lines and lines starting with `#` are skipped.
+//!
+//! ```text
+//! # principal              resource            methods
+//! psk:sensor-1             /sensors/*          GET,PUT
+//! cert:CN=admin, O=example *                   *
+//! rpk:3059301306072a86...  /actuators/valve    POST
+//! authenticated            /status             GET
+//! anyone                   /.well-known/core   GET
+//! ```
+//!
+//! Principals are `anyone`, `authenticated`, `psk:` followed by the PSK
+//! identity, `cert:` followed by the certificate subject as reported by
+//! [`PeerIdentity::CertSubject`], `rpk:` followed by the hex encoded
+//! SubjectPublicKeyInfo of a raw public key and `oscore:` followed by the hex
+//! encoded OSCORE Sender ID. No transport authenticates peers with OSCORE
+//! yet, so `oscore:` rules match nobody. The subject may contain spaces; the
+//! resource pattern and methods may not.
+use coap_lite::{RequestType as Method, ResponseType as Status};
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use super::transport::PeerIdentity;
+
+/// The methods granted by `*`.
+const ALL_METHODS: [Method; 7] = [
+    Method::Get,
+    Method::Post,
+    Method::Put,
+    Method::Delete,
+    Method::Fetch,
+    Method::Patch,
+    Method::IPatch,
+];
+
+/// The peers a rule applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Principal {
+    /// Every peer, authenticated or not.
+    Anyone,
+    /// Every peer that authenticated, no matter how.
+    Authenticated,
+    /// The peer with this identity.
+    Identity(PeerIdentity),
+}
+
+impl Principal {
+    fn matches(&self, identity: &PeerIdentity) -> bool {
+        match self {
+            Principal::Anyone => true,
+            Principal::Authenticated => identity.is_authenticated(),
+            Principal::Identity(expected) => expected == identity,
+        }
+    }
+
+    fn parse(text: &str) -> std::result::Result<Principal, &'static str> {
+        if text == "anyone" {
+            return Ok(Principal::Anyone);
+        }
+        if text == "authenticated" {
+            return Ok(Principal::Authenticated);
+        }
+        let identity = match text.split_once(':') {
+            Some(("psk", identity)) => PeerIdentity::PskIdentity(identity.as_bytes().to_vec()),
+            Some(("cert", subject)) => PeerIdentity::CertSubject(subject.to_string()),
+            Some(("rpk", spki)) => {
+                PeerIdentity::RawPublicKey(decode_hex(spki).ok_or("invalid raw public key")?)
+            }
+            Some(("oscore", kid)) => {
+                PeerIdentity::OscoreKid(decode_hex(kid).ok_or("invalid OSCORE sender ID")?)
+            }
+            _ => return Err("unknown principal"),
+        };
+        Ok(Principal::Identity(identity))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    principal: Principal,
+    resource: String,
+    methods: Vec<Method>,
+}
+
+/// Rules allowing peers to use methods on resources. Nothing is allowed
+/// until a rule allows it.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    rules: Vec<Rule>,
+}
+
+impl Acl {
+    /// Create a list without any rules, which denies every request.
+    pub fn new() -> Acl {
+        Acl::default()
+    }
+
+    /// Allow `principal` to use `methods` on the resources matching the
+    /// `resource` pattern.
+    pub fn allow(&mut self, principal: Principal, resource: &str, methods: &[Method]) {
+        self.rules.push(Rule {
+            principal,
+            resource: resource.trim_start_matches('/').to_string(),
+            methods: methods.to_vec(),
+        });
+    }
+
+    /// Parse rules in the file format described in the [module](self)
+    /// documentation.
+    pub fn parse(text: &str) -> Result<Acl> {
+        let mut acl = Acl::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |reason: &str| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line {}: {}", number + 1, reason),
+                )
+            };
+            let (rest, methods) = line
+                .rsplit_once(char::is_whitespace)
+                .ok_or_else(|| invalid("expected principal, resource and methods"))?;
+            let (principal, resource) = rest
+                .trim_end()
+                .rsplit_once(char::is_whitespace)
+                .ok_or_else(|| invalid("expected principal, resource and methods"))?;
+            let principal = Principal::parse(principal.trim_end()).map_err(invalid)?;
+            let methods = parse_methods(methods).ok_or_else(|| invalid("unknown method"))?;
+            acl.allow(principal, resource, &methods);
+        }
+        Ok(acl)
+    }
+
+    /// Read rules from a file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Acl> {
+        Acl::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Check whether the peer with `identity` may use `method` on the
+    /// resource at `path`, and return the status to answer with if not.
+    pub fn check(
+        &self,
+        identity: &PeerIdentity,
+        path: &str,
+        method: Method,
+    ) -> std::result::Result<(), Status> {
+        let path = path.trim_start_matches('/');
+        let allowed = self.rules.iter().any(|rule| {
+            rule.principal.matches(identity)
+                && rule.methods.contains(&method)
+                && matches_pattern(&rule.resource, path)
+        });
+        match (allowed, identity.is_authenticated()) {
+            (true, _) => Ok(()),
+            (false, false) => Err(Status::Unauthorized),
+            (false, true) => Err(Status::Forbidden),
+        }
+    }
+}
+
+fn parse_methods(text: &str) -> Option<Vec<Method>> {
+    if text == "*" {
+        return Some(ALL_METHODS.to_vec());
+    }
+    text.split(',')
+        .map(|name| match name.to_ascii_uppercase().as_str() {
+            "GET" => Some(Method::Get),
+            "POST" => Some(Method::Post),
+            "PUT" => Some(Method::Put),
+            "DELETE" => Some(Method::Delete),
+            "FETCH" => Some(Method::Fetch),
+            "PATCH" => Some(Method::Patch),
+            "IPATCH" => Some(Method::IPatch),
+            _ => None,
+        })
+        .collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Return whether `path` matches `pattern`, in which `*` stands for any
+/// sequence of characters.
+pub(crate) fn matches_pattern(pattern: &str, path: &str) -> bool {
+    let (pattern, path) = (pattern.as_bytes(), path.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // the position after the last star and the text it was matched up to
+    let mut backtrack = None;
+    while t < path.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            backtrack = Some((p, t));
+        } else if p < pattern.len() && pattern[p] == path[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // let the star cover one more character
+            p = star;
+            t = matched + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("sensors/*", "sensors/temp"));
+        assert!(matches_pattern("sensors/*", "sensors/a/b"));
+        assert!(matches_pattern("*/temp", "sensors/temp"));
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("status", "status"));
+        assert!(!matches_pattern("sensors/*", "actuators/valve"));
+        assert!(!matches_pattern("status", "status/x"));
+    }
+
+    #[test]
+    fn test_parse() {
+        let acl = Acl::parse(
+            "# principal  resource  methods\n\
+             \n\
+             psk:sensor-1   /sensors/*  GET,PUT\n\
+             cert:CN=admin, O=example  *  *\n\
+             rpk:0a0b  /keys  post\n\
+             oscore:0a0b  /oscore/*  GET\n\
+             authenticated /status GET\n\
+             anyone /.well-known/core GET\n",
+        )
+        .unwrap();
+        let psk = PeerIdentity::PskIdentity(b"sensor-1".to_vec());
+        let admin = PeerIdentity::CertSubject("CN=admin, O=example".to_string());
+        let key = PeerIdentity::RawPublicKey(vec![0x0a, 0x0b]);
+        let kid = PeerIdentity::OscoreKid(vec![0x0a, 0x0b]);
+
+        assert_eq!(acl.check(&psk, "sensors/temp", Method::Put), Ok(()));
+        assert_eq!(
+            acl.check(&psk, "sensors/temp", Method::Delete),
+            Err(Status::Forbidden)
+        );
+        assert_eq!(acl.check(&admin, "anything", Method::Delete), Ok(()));
+        assert_eq!(acl.check(&key, "/keys", Method::Post), Ok(()));
+        assert_eq!(acl.check(&key, "status", Method::Get), Ok(()));
+        assert_eq!(acl.check(&kid, "oscore/temp", Method::Get), Ok(()));
+        assert_eq!(
+            acl.check(&key, "oscore/temp", Method::Get),
+            Err(Status::Forbidden)
+        );
+        assert_eq!(
+            acl.check(&PeerIdentity::Unauthenticated, "status", Method::Get),
+            Err(Status::Unauthorized)
+        );
+        assert_eq!(
+            acl.check(
+                &PeerIdentity::Unauthenticated,
+                ".well-known/core",
+                Method::Get
+            ),
+            Ok(())
+        );
+
+        let error = Acl::parse("psk:a /x GET\nnobody /x GET").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "line 2: unknown principal");
+        assert!(Acl::parse("psk:a /x SUBSCRIBE").is_err());
+        assert!(Acl::parse("psk:a GET").is_err());
+    }
+
+    #[cfg(feature = "dtls")]
+    mod dtls {
+        use super::*;
+        use crate::transport::dtls::{PskConfig, PskServerConfig};
+        use crate::transport::{DtlsTransport, Transport, UdpTransport};
+        use crate::*;
+        use coap_lite::{CoapRequest, CoapResponse};
+        use std::net::SocketAddr;
+        use std::time::Duration;
+
+        fn request(client: &CoAPClient, path: &str, method: Method) -> Status {
+            *exchange(client, path, method).get_status()
+        }
+
+        fn exchange(client: &CoAPClient, path: &str, method: Method) -> CoapResponse {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(method);
+            request.set_path(path);
+            client.send(&request).unwrap();
+            client.receive().unwrap()
+        }
+
+        #[test]
+        fn test_acl_enforced() {
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::spawn(move || {
+                tokio::runtime::Runtime::new()
+                    .unwrap()
+                    .block_on(async move {
+                        let mut config = PskServerConfig::new();
+                        config.add_key("sensor-1", "secretPSK");
+                        let dtls = DtlsTransport::bind_psk("127.0.0.1:0", &config).unwrap();
+                        let dtls_addr = dtls.local_addr().unwrap();
+                        let mut server =
+                            Server::from_transport(UdpTransport::bind("127.0.0.1:0").unwrap());
+                        server.add_transport(dtls);
+                        let mut acl = Acl::new();
+                        acl.allow(
+                            Principal::Identity(PeerIdentity::PskIdentity(b"sensor-1".to_vec())),
+                            "/sensors/*",
+                            &[Method::Get, Method::Put],
+                        );
+                        acl.allow(Principal::Anyone, "/status", &[Method::Get]);
+                        server.set_acl(Some(acl));
+                        tx.send((server.socket_addr().unwrap(), dtls_addr)).unwrap();
+
+                        server
+                            .run(|request: CoapRequest<SocketAddr>| async move {
+                                let mut response: CoapResponse = request.response?;
+                                response.message.payload = match server::peer_identity()? {
+                                    PeerIdentity::PskIdentity(identity) => identity,
+                                    identity => format!("{:?}", identity).into_bytes(),
+                                };
+                                Some(response)
+                            })
+                            .await
+                            .unwrap();
+                    });
+            });
+            let (udp_addr, dtls_addr) = rx.recv().unwrap();
+
+            let udp = CoAPClient::new(udp_addr).unwrap();
+            udp.set_receive_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            assert_eq!(
+                exchange(&udp, "/status", Method::Get).message.payload,
+                b"Unauthenticated".to_vec()
+            );
+            assert_eq!(
+                request(&udp, "/sensors/temp", Method::Get),
+                Status::Unauthorized
+            );
+
+            let config = PskConfig::new("sensor-1", "secretPSK");
+            let dtls = CoAPClient::new_dtls(dtls_addr, &config).unwrap();
+            dtls.set_receive_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            assert_eq!(
+                request(&dtls, "/sensors/temp", Method::Get),
+                Status::Content
+            );
+            assert_eq!(
+                request(&dtls, "/sensors/temp", Method::Delete),
+                Status::Forbidden
+            );
+            assert_eq!(
+                exchange(&dtls, "/status", Method::Get).message.payload,
+                b"sensor-1".to_vec()
+            );
+
+            let mut unknown = PskConfig::new("sensor-2", "secretPSK");
+            unknown.set_handshake_timeout(Duration::from_secs(5));
+            assert!(CoAPClient::new_dtls(dtls_addr, &unknown).is_err());
+        }
+    }
+}
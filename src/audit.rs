@@ -0,0 +1,145 @@
+//! Security events for audit logs.
+//!
+//! The server and its transports report failed handshakes, rejected access
+//! tokens, suspected replays and access control denials as
+//! [`SecurityEvent`]s to the handler set with
+//! [`Server::set_security_event_handler`](crate::Server::set_security_event_handler),
+//! e.g. to forward them to a SIEM system. Tokens are validated by the
+//! application, so the handler of an ACE
+//! [`ResourceServer`](crate::ace::ResourceServer) is set on it directly.
+//!
+//! Handlers are called on the threads and tasks that detect the events, so
+//! they should return quickly.
+use coap_lite::RequestType as Method;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use super::transport::PeerIdentity;
+
+/// An event of interest to the security monitoring of a deployment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SecurityEvent {
+    /// A TLS, DTLS or WebSocket handshake with the peer failed or timed
+    /// out.
+    HandshakeFailed { peer: SocketAddr, reason: String },
+    /// The access token the peer posted was rejected.
+    TokenRejected { peer: SocketAddr, reason: String },
+    /// A request carried an Echo value that was not issued to its sender or
+    /// is no longer fresh, as a replayed or delayed request would.
+    ReplayDetected { peer: SocketAddr, resource: String },
+    /// The access control list denied a request.
+    AccessDenied {
+        peer: SocketAddr,
+        identity: PeerIdentity,
+        resource: String,
+        method: Method,
+    },
+}
+
+impl SecurityEvent {
+    /// Return the address of the peer the event is about.
+    pub fn peer(&self) -> SocketAddr {
+        match self {
+            SecurityEvent::HandshakeFailed { peer, .. }
+            | SecurityEvent::TokenRejected { peer, .. }
+            | SecurityEvent::ReplayDetected { peer, .. }
+            | SecurityEvent::AccessDenied { peer, .. } => *peer,
+        }
+    }
+}
+
+/// Receives the security events.
+pub type SecurityEventHandler = Arc<dyn Fn(&SecurityEvent) + Send + Sync>;
+
+/// Pass `event` to the handler, if there is one.
+pub(crate) fn emit(handler: &Option<SecurityEventHandler>, event: SecurityEvent) {
+    if let Some(handler) = handler {
+        handler(&event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use acl::{Acl, Principal};
+    use coap_lite::{CoapOption, CoapRequest, CoapResponse, ResponseType as Status};
+    use echo::{EchoPolicy, Requirement};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_security_events() {
+        let (events_tx, events) = mpsc::channel();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    let mut acl = Acl::new();
+                    acl.allow(Principal::Anyone, "/lock", &[Method::Put]);
+                    server.set_acl(Some(acl));
+                    let mut policy = EchoPolicy::new();
+                    policy.require("/lock", &[Method::Put], Requirement::Fresh);
+                    server.set_echo_policy(Some(policy));
+                    server.set_security_event_handler(move |event| {
+                        events_tx.send(event.clone()).unwrap();
+                    });
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|request: CoapRequest<SocketAddr>| async move {
+                            let response: CoapResponse = request.response?;
+                            Some(response)
+                        })
+                        .await
+                        .unwrap();
+                });
+        });
+        let addr = rx.recv().unwrap();
+
+        let client = CoAPClient::new(addr).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let send = |method: Method, echo: Option<&[u8]>| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(method);
+            request.set_path("/lock");
+            if let Some(echo) = echo {
+                request
+                    .message
+                    .add_option(CoapOption::Unknown(echo::ECHO), echo.to_vec());
+            }
+            client.send(&request).unwrap();
+            *client.receive().unwrap().get_status()
+        };
+
+        assert_eq!(send(Method::Delete, None), Status::Unauthorized);
+        let event = events.recv().unwrap();
+        assert_eq!(event.peer().ip(), addr.ip());
+        assert_eq!(
+            event,
+            SecurityEvent::AccessDenied {
+                peer: event.peer(),
+                identity: PeerIdentity::Unauthenticated,
+                resource: "lock".to_string(),
+                method: Method::Delete,
+            }
+        );
+
+        // a challenge is no event, an Echo value never issued is
+        assert_eq!(send(Method::Put, None), Status::Unauthorized);
+        assert_eq!(send(Method::Put, Some(b"replayed")), Status::Unauthorized);
+        let event = events.recv().unwrap();
+        assert_eq!(
+            event,
+            SecurityEvent::ReplayDetected {
+                peer: event.peer(),
+                resource: "lock".to_string(),
+            }
+        );
+        assert!(events.try_recv().is_err());
+    }
+}
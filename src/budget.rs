@@ -0,0 +1,171 @@
+//! Memory budgets of the per-transfer state of a server.
+//!
+//! The bodies of unfinished block-wise transfers stay in memory until the
+//! transfer ends or its lifetime runs out, so a peer starting many
+//! transfers without finishing them could make the server hold any amount
+//! of memory. A [`BudgetedCache`] counts the bytes of its entries and
+//! evicts the least recently used ones when a new entry would exceed its
+//! budget; an entry larger than the whole budget is refused.
+use log::debug;
+use lru_time_cache::LruCache;
+use std::time::Duration;
+
+/// The bytes an entry holds on the heap.
+pub(crate) trait Footprint {
+    fn footprint(&self) -> usize;
+}
+
+impl Footprint for Vec<u8> {
+    fn footprint(&self) -> usize {
+        self.capacity()
+    }
+}
+
+/// An LRU cache with a limit of both its entries and their bytes.
+pub(crate) struct BudgetedCache<K, V> {
+    entries: LruCache<K, V>,
+    capacity: usize,
+    budget: Option<usize>,
+    used: usize,
+}
+
+impl<K: Ord + Clone, V: Footprint> BudgetedCache<K, V> {
+    /// Create a cache of up to `capacity` entries, each forgotten after
+    /// `lifetime` without use, and no budget.
+    pub(crate) fn new(lifetime: Duration, capacity: usize) -> BudgetedCache<K, V> {
+        BudgetedCache {
+            // the capacity is enforced here, so that the evictions are counted
+            entries: LruCache::with_expiry_duration(lifetime),
+            capacity,
+            budget: None,
+            used: 0,
+        }
+    }
+
+    /// Hold entries of at most `budget` bytes in total, or of any size with
+    /// `None`.
+    pub(crate) fn set_budget(&mut self, budget: Option<usize>) {
+        self.budget = budget;
+        self.evict(None);
+    }
+
+    pub(crate) fn budget(&self) -> Option<usize> {
+        self.budget
+    }
+
+    pub(crate) fn get(&mut self, key: &K) -> Option<&V> {
+        let (value, expired) = self.entries.notify_get(key);
+        self.used -= expired
+            .iter()
+            .map(|(_, value)| value.footprint())
+            .sum::<usize>();
+        value
+    }
+
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        let value = self.entries.remove(key)?;
+        self.used -= value.footprint();
+        Some(value)
+    }
+
+    /// Remove the entries whose key does not satisfy `keep`.
+    pub(crate) fn retain<F: FnMut(&K) -> bool>(&mut self, mut keep: F) {
+        let removed: Vec<K> = self
+            .entries
+            .peek_iter()
+            .map(|(key, _)| key)
+            .filter(|key| !keep(key))
+            .cloned()
+            .collect();
+        for key in removed {
+            self.remove(&key);
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+        self.used = 0;
+    }
+
+    /// Insert `value`, evicting the least recently used entries to make
+    /// room for it. A value that exceeds the budget on its own is returned.
+    pub(crate) fn insert(&mut self, key: K, value: V) -> Result<(), V> {
+        self.remove(&key);
+        let size = value.footprint();
+        if self.budget.is_some_and(|budget| size > budget) {
+            return Err(value);
+        }
+        // drops the expired entries before anything is evicted
+        self.get(&key);
+        self.evict(Some(size));
+        self.entries.insert(key, value);
+        self.used += size;
+        Ok(())
+    }
+
+    /// Evict the least recently used entries until the others are within
+    /// the limits, leaving room for one more of `size` bytes if given.
+    fn evict(&mut self, room: Option<usize>) {
+        let budget = self.budget.unwrap_or(usize::MAX);
+        let (entries, size) = room.map_or((0, 0), |size| (1, size));
+        while self.entries.len() + entries > self.capacity
+            || self.used.saturating_add(size) > budget
+        {
+            let oldest = match self.entries.peek_iter().last() {
+                Some((key, _)) => key.clone(),
+                None => break,
+            };
+            if let Some(value) = self.remove(&oldest) {
+                debug!("evict transfer state of {} bytes", value.footprint());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let mut cache = BudgetedCache::new(Duration::from_secs(60), 3);
+        cache.set_budget(Some(100));
+        cache.insert(1, vec![0; 40]).unwrap();
+        cache.insert(2, vec![0; 40]).unwrap();
+        assert_eq!(cache.used, 80);
+
+        // the least recently used entry makes room
+        cache.get(&1);
+        cache.insert(3, vec![0; 40]).unwrap();
+        assert!(cache.get(&2).is_none());
+        assert_eq!(cache.used, 80);
+        // replacing an entry frees its bytes first
+        cache.insert(3, vec![0; 60]).unwrap();
+        assert_eq!(cache.used, 100);
+        assert!(cache.get(&1).is_some());
+
+        // too large on its own
+        assert!(cache.insert(4, vec![0; 101]).is_err());
+        assert_eq!(cache.used, 100);
+
+        // the entry limit applies as well
+        cache.set_budget(None);
+        cache.insert(4, vec![0; 10]).unwrap();
+        cache.insert(5, vec![0; 10]).unwrap();
+        assert!(cache.get(&3).is_none());
+        assert_eq!(cache.used, 60);
+
+        // a smaller budget evicts at once
+        cache.set_budget(Some(50));
+        assert_eq!(cache.used, 20);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut cache = BudgetedCache::new(Duration::from_millis(50), 8);
+        cache.insert(1, vec![0; 10]).unwrap();
+        std::thread::sleep(Duration::from_millis(60));
+        cache.insert(2, vec![0; 10]).unwrap();
+        assert_eq!(cache.used, 10);
+    }
+}
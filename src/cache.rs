@@ -0,0 +1,399 @@
+//! A server-side cache of responses
+//! ([RFC 7252](https://tools.ietf.org/html/rfc7252) section 5.6).
+//!
+//! With a [`ResponseCache`] set with
+//! [`Server::set_response_cache`](crate::Server::set_response_cache), the
+//! server keeps the 2.05 Content responses to GET requests for as long as
+//! their Max-Age says they are fresh, 60 seconds without the option, and
+//! answers identical requests from any client with them instead of calling
+//! the handler. Requests are identical if they ask for the same resource
+//! with the same options, apart from those that are no cache key and those
+//! that only concern the transfer of the response: ETag, Block2 and
+//! Q-Block2. ETags are still validated and large responses still sent
+//! block-wise.
+//!
+//! A POST, PUT, DELETE, PATCH or iPATCH request to a resource drops its
+//! responses once it has been handled. Resources changed otherwise are
+//! dropped with [`ResponseCache::invalidate`]. Observe requests always
+//! reach the handler, as do requests to be forwarded by a proxy.
+//!
+//! Only resources whose representation is the same for every client
+//! should be served through the cache, as the client is no cache key.
+//!
+//! A [`ForwardProxy`](crate::proxy::ForwardProxy) caches the responses of
+//! its targets in a `ResponseCache` of its own, set with
+//! [`ForwardProxy::set_cache`](crate::proxy::ForwardProxy::set_cache),
+//! keyed by the URI of the target instead of the path. It also keeps
+//! responses with an ETag after they became stale, and validates them
+//! with the target before it serves them again: a 2.03 Valid makes them
+//! fresh again without the payload being sent another time.
+use coap_lite::{
+    option_value::OptionValueU32, CoapOption, CoapRequest, CoapResponse, MessageClass, Packet,
+    RequestType as Method, ResponseType as Status,
+};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::budget::{BudgetedCache, Footprint};
+use super::message;
+use super::options;
+use super::proxy::HOP_LIMIT;
+use super::qblock::Q_BLOCK2;
+use super::throttle::DEFAULT_MAX_AGE;
+
+/// How many responses a cache holds by default.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// How many bytes of responses a cache holds by default.
+pub const DEFAULT_BUDGET: usize = 4 * 1024 * 1024;
+
+/// How long a response stays in the cache without being used, even if it
+/// is fresh for longer.
+const MAX_IDLE: Duration = Duration::from_secs(3600);
+
+/// The resource and the cache-key options of a request.
+pub(crate) type Key = (String, Vec<(u16, Vec<u8>)>);
+
+/// A response and the time it stops being fresh.
+struct Entry {
+    response: Packet,
+    expires: Instant,
+}
+
+impl Footprint for Entry {
+    fn footprint(&self) -> usize {
+        message::encoded_len(&self.response)
+    }
+}
+
+/// Responses of the server, kept while they are fresh.
+pub struct ResponseCache {
+    entries: BudgetedCache<Key, Entry>,
+}
+
+impl ResponseCache {
+    /// Create a cache of [`DEFAULT_CAPACITY`] responses of
+    /// [`DEFAULT_BUDGET`] bytes at most.
+    pub fn new() -> ResponseCache {
+        ResponseCache::with_capacity(DEFAULT_CAPACITY)
+    }
+
+    /// Create a cache of `capacity` responses of [`DEFAULT_BUDGET`] bytes
+    /// at most.
+    pub fn with_capacity(capacity: usize) -> ResponseCache {
+        let mut entries = BudgetedCache::new(MAX_IDLE, capacity);
+        entries.set_budget(Some(DEFAULT_BUDGET));
+        ResponseCache { entries }
+    }
+
+    /// Hold responses of at most `budget` bytes in total, or of any size
+    /// with `None`. The least recently used responses make room for new
+    /// ones.
+    pub fn set_budget(&mut self, budget: Option<usize>) {
+        self.entries.set_budget(budget);
+    }
+
+    /// Drop the responses of the resource at `path`, e.g. after it changed.
+    pub fn invalidate(&mut self, path: &str) {
+        let path = path.trim_matches('/');
+        self.entries.retain(|(resource, _)| resource != path);
+    }
+
+    /// Drop all responses.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Return the response to `request` from the cache, with the Max-Age
+    /// left, or `None` if there is no fresh one.
+    pub(crate) fn get(&mut self, request: &CoapRequest<SocketAddr>) -> Option<CoapResponse> {
+        if request.message.header.code != MessageClass::Request(Method::Get) {
+            return None;
+        }
+        let key = key(&request.message)?;
+        let stored = match self.lookup(&key) {
+            Some((stored, true)) => stored,
+            _ => {
+                self.entries.remove(&key);
+                return None;
+            }
+        };
+        let mut response = request.response.clone()?;
+        response.message.header.code = stored.header.code;
+        for (&number, values) in stored.options() {
+            response
+                .message
+                .set_option(CoapOption::from(number), values.clone());
+        }
+        response.message.payload = stored.payload;
+        Some(response)
+    }
+
+    /// Return the response kept under `key`, with the Max-Age left, and
+    /// whether it is still fresh.
+    pub(crate) fn lookup(&mut self, key: &Key) -> Option<(Packet, bool)> {
+        let entry = self.entries.get(key)?;
+        let max_age = entry.expires.checked_duration_since(Instant::now());
+        let max_age = max_age.map_or(0, |max_age| max_age.as_secs());
+        let mut response = entry.response.clone();
+        response.clear_option(CoapOption::MaxAge);
+        let max_age = OptionValueU32(u32::try_from(max_age).unwrap_or(u32::MAX));
+        response.add_option_as(CoapOption::MaxAge, max_age);
+        Some((response, max_age.0 > 0))
+    }
+
+    /// Keep a 2.05 Content response of a target of a proxy under `key`: while
+    /// it is fresh, and after that if it has an ETag to validate it with.
+    pub(crate) fn store(&mut self, key: Key, response: &Packet) {
+        if response.header.code != MessageClass::Response(Status::Content) {
+            return;
+        }
+        let validated = response
+            .get_option(CoapOption::ETag)
+            .is_some_and(|values| !values.is_empty());
+        if let Some(entry) = entry(response).filter(|entry| validated || !entry.is_stale()) {
+            // a response beyond the budget is not kept
+            let _ = self.entries.insert(key, entry);
+        }
+    }
+
+    /// Make the response kept under `key` fresh again, as a 2.03 Valid
+    /// response says, and return it.
+    pub(crate) fn refresh(&mut self, key: Key, valid: &Packet) -> Option<Packet> {
+        let mut stored = self.entries.remove(&key)?.response;
+        // the options of the 2.03 replace those of the response
+        for (&number, values) in valid.options() {
+            match CoapOption::from(number) {
+                CoapOption::Block2 => {}
+                option => stored.set_option(option, values.clone()),
+            }
+        }
+        let entry = entry(&stored)?;
+        let _ = self.entries.insert(key.clone(), entry);
+        Some(self.lookup(&key)?.0)
+    }
+
+    /// Drop the responses of the resource a proxy forwarded a request to,
+    /// by its URI.
+    pub(crate) fn invalidate_target(&mut self, uri: &str) {
+        self.entries.retain(|(resource, _)| resource != uri);
+    }
+
+    /// Keep the response to a GET request while it is fresh, or drop the
+    /// responses of a resource changed by the request.
+    pub(crate) fn update(&mut self, request: &Packet, response: Option<&Packet>) {
+        if changes(request) {
+            if let Some((path, _)) = key(request) {
+                self.invalidate(&path);
+            }
+            return;
+        }
+        if request.header.code != MessageClass::Request(Method::Get) {
+            return;
+        }
+        let response = match response {
+            Some(response) if response.header.code == MessageClass::Response(Status::Content) => {
+                response
+            }
+            _ => return,
+        };
+        let entry = match entry(response) {
+            Some(entry) if !entry.is_stale() => entry,
+            _ => return,
+        };
+        if let Some(key) = key(request) {
+            // a response beyond the budget is not kept
+            let _ = self.entries.insert(key, entry);
+        }
+    }
+}
+
+impl Entry {
+    fn is_stale(&self) -> bool {
+        self.expires <= Instant::now()
+    }
+}
+
+/// Return the entry to keep a response in, fresh for its Max-Age, or
+/// `None` if the Max-Age is invalid.
+fn entry(response: &Packet) -> Option<Entry> {
+    let max_age = match response.get_first_option_as::<OptionValueU32>(CoapOption::MaxAge) {
+        Some(Ok(max_age)) => Duration::from_secs(u64::from(max_age.0)),
+        Some(Err(_)) => return None,
+        None => DEFAULT_MAX_AGE,
+    };
+    let mut stored = Packet::new();
+    stored.header.code = response.header.code;
+    for (&number, values) in response.options() {
+        stored.set_option(CoapOption::from(number), values.clone());
+    }
+    stored.payload = response.payload.clone();
+    Some(Entry {
+        response: stored,
+        expires: Instant::now() + max_age,
+    })
+}
+
+impl Default for ResponseCache {
+    fn default() -> ResponseCache {
+        ResponseCache::new()
+    }
+}
+
+/// Return whether a request may change the resource it is for, so that its
+/// responses are no longer to be served from the cache.
+pub(crate) fn changes(request: &Packet) -> bool {
+    matches!(
+        request.header.code,
+        MessageClass::Request(Method::Post)
+            | MessageClass::Request(Method::Put)
+            | MessageClass::Request(Method::Delete)
+            | MessageClass::Request(Method::Patch)
+            | MessageClass::Request(Method::IPatch)
+    )
+}
+
+/// Return the cache key of a request a proxy forwards to the resource at
+/// `uri`, or `None` if it is no GET request or an Observe request. The
+/// options that make up the URI are keyed by the URI itself, and the
+/// Hop-Limit, which changes from hop to hop, is no key.
+pub(crate) fn target_key(uri: &str, request: &Packet) -> Option<Key> {
+    if request.header.code != MessageClass::Request(Method::Get) {
+        return None;
+    }
+    let (_, mut options) = key(request)?;
+    options.retain(|(number, _)| {
+        !matches!(
+            CoapOption::from(*number),
+            CoapOption::UriHost
+                | CoapOption::UriPort
+                | CoapOption::UriQuery
+                | CoapOption::ProxyUri
+                | CoapOption::ProxyScheme
+        ) && *number != HOP_LIMIT
+    });
+    Some((uri.to_string(), options))
+}
+
+/// Return the cache key of a request, or `None` if it is an Observe
+/// request, whose response is not to be taken from the cache.
+fn key(request: &Packet) -> Option<Key> {
+    let mut path = Vec::new();
+    let mut options = Vec::new();
+    for (&number, values) in request.options() {
+        match CoapOption::from(number) {
+            CoapOption::Observe if !values.is_empty() => return None,
+            CoapOption::UriPath => {
+                path.extend(values.iter().map(|value| String::from_utf8_lossy(value)));
+            }
+            CoapOption::ETag | CoapOption::Block2 => {}
+            _ if number == Q_BLOCK2 || options::is_no_cache_key(number) => {}
+            _ => options.extend(values.iter().map(|value| (number, value.clone()))),
+        }
+    }
+    Some((path.join("/"), options))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request(method: Method, path: &str) -> CoapRequest<SocketAddr> {
+        let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+        request.set_method(method);
+        request.set_path(path);
+        request.response = CoapResponse::new(&request.message);
+        request
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut cache = ResponseCache::new();
+        let get = request(Method::Get, "/sensors/temp");
+        let mut response = get.response.clone().unwrap();
+        response.message.payload = b"21.5".to_vec();
+        response
+            .message
+            .add_option_as(CoapOption::MaxAge, OptionValueU32(30));
+        assert!(cache.get(&get).is_none());
+        cache.update(&get.message, Some(&response.message));
+
+        // the token and Message ID are those of the new request
+        let mut other = request(Method::Get, "sensors/temp");
+        other.message.set_token(vec![1, 2]);
+        other.response = CoapResponse::new(&other.message);
+        other.message.add_option(CoapOption::ETag, vec![3]);
+        let cached = cache.get(&other).unwrap();
+        assert_eq!(cached.message.payload, b"21.5");
+        assert_eq!(cached.message.get_token(), [1, 2]);
+        let max_age = cached
+            .message
+            .get_first_option_as::<OptionValueU32>(CoapOption::MaxAge);
+        assert!(max_age.unwrap().unwrap().0 <= 30);
+
+        // another query is another request
+        let mut query = request(Method::Get, "/sensors/temp");
+        query
+            .message
+            .add_option(CoapOption::UriQuery, b"unit=F".to_vec());
+        assert!(cache.get(&query).is_none());
+        // as is an Observe request
+        let mut observe = request(Method::Get, "/sensors/temp");
+        observe.message.set_observe_value(0);
+        assert!(cache.get(&observe).is_none());
+
+        // changing the resource drops its responses
+        let put = request(Method::Put, "/sensors/temp");
+        assert!(cache.get(&put).is_none());
+        cache.update(&put.message, None);
+        assert!(cache.get(&get).is_none());
+
+        // stale at once
+        response.message.clear_option(CoapOption::MaxAge);
+        response
+            .message
+            .add_option_as(CoapOption::MaxAge, OptionValueU32(0));
+        cache.update(&get.message, Some(&response.message));
+        assert!(cache.get(&get).is_none());
+    }
+
+    #[test]
+    fn test_target_cache() {
+        let mut cache = ResponseCache::new();
+        let uri = "coap://sensor.example/temp";
+        let mut get = request(Method::Get, "/temp").message;
+        get.add_option(CoapOption::UriHost, b"sensor.example".to_vec());
+        let key = target_key(uri, &get).unwrap();
+        // the Hop-Limit is no key
+        get.add_option(CoapOption::Unknown(HOP_LIMIT), vec![8]);
+        assert_eq!(target_key(uri, &get), Some(key.clone()));
+        let mut observe = get.clone();
+        observe.set_observe_value(0);
+        assert!(target_key(uri, &observe).is_none());
+
+        let mut response = Packet::new();
+        response.header.code = MessageClass::Response(Status::Content);
+        response.payload = b"21.5".to_vec();
+        response.add_option_as(CoapOption::MaxAge, OptionValueU32(0));
+        cache.store(key.clone(), &response);
+        assert!(cache.lookup(&key).is_none());
+
+        // a stale response with an ETag is kept to be validated
+        response.add_option(CoapOption::ETag, vec![1]);
+        cache.store(key.clone(), &response);
+        let (stored, fresh) = cache.lookup(&key).unwrap();
+        assert!(!fresh);
+        assert_eq!(stored.payload, b"21.5");
+        let mut valid = Packet::new();
+        valid.header.code = MessageClass::Response(Status::Valid);
+        valid.add_option(CoapOption::ETag, vec![1]);
+        valid.add_option_as(CoapOption::MaxAge, OptionValueU32(30));
+        let refreshed = cache.refresh(key.clone(), &valid).unwrap();
+        assert_eq!(refreshed.header.code, MessageClass::Response(Status::Content));
+        assert_eq!(refreshed.payload, b"21.5");
+        assert!(cache.lookup(&key).unwrap().1);
+
+        cache.invalidate_target(uri);
+        assert!(cache.lookup(&key).is_none());
+    }
+}
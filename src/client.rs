@@ -1,35 +1,112 @@
 use coap_lite::{
-    CoapOption, CoapRequest, CoapResponse, ObserveOption, Packet, RequestType as Method,
-    ResponseType as Status, error::HandlingError,
+    CoapOption, CoapRequest, CoapResponse, MessageClass, MessageType, ObserveOption, Packet,
+    RequestType as Method, ResponseType as Status, error::HandlingError,
     block_handler::{BlockValue, RequestCacheKey, extending_splice},
+    option_value::{OptionValueU16, OptionValueU32},
 };
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
 use log::*;
 use regex::Regex;
+use serde::{de::DeserializeOwned, Serialize};
 use std::io::{Error, ErrorKind, Result};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
-use std::sync::mpsc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::collections::BTreeMap;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use url::Url;
 use lru_time_cache::LruCache;
+use super::echo::{ECHO, REQUEST_TAG};
+use super::link_format::{self, Link};
+use super::message_id::MessageIds;
+use super::mtu::{self, PathMtu};
+use super::payload::{self, Format, PayloadError};
+use super::pubsub;
+use super::qblock::{self, MAX_PAYLOADS, MAX_RECOVERY_ROUNDS, Q_BLOCK1, Q_BLOCK2};
+use super::throttle;
+#[cfg(feature = "dtls")]
+use super::transport::dtls;
+#[cfg(feature = "quic")]
+use super::transport::quic;
+#[cfg(feature = "tls")]
+use super::transport::tls;
+#[cfg(unix)]
+use super::transport::unix;
+use super::transport::{tcp::TcpClientTransport, ClientTransport};
 use core::mem;
 use core::ops::Deref;
 use alloc::string::String;
 use alloc::vec::Vec;
 
 const DEFAULT_RECEIVE_TIMEOUT: u64 = 1; // 1s
+const DEFAULT_PORT: u16 = 5683;
+const DEFAULT_SECURE_PORT: u16 = 5684;
+
+/// Default limit of the back-off the client honors before repeating a
+/// request answered with 4.29 Too Many Requests.
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(60);
 
 enum ObserveMessage {
     Terminate,
 }
 
+/// How an observing client keeps NAT and firewall bindings alive while no
+/// notifications arrive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeepaliveMode {
+    /// Send a CoAP ping, an empty confirmable message answered with a reset.
+    Ping,
+    /// Register the observation again. The server answers with a
+    /// notification, which is passed to the observe handler.
+    Reregister,
+}
+
+type KeepaliveFailureHandler = Arc<Mutex<dyn FnMut(&Error) + Send>>;
+
+/// Metadata about a single request/response exchange.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExchangeStats {
+    /// Time from sending the request until the final response arrived.
+    pub rtt: Duration,
+    /// Number of times a confirmable message was retransmitted.
+    pub retransmissions: u32,
+    /// Whether the response was piggybacked on the ACK, as opposed to being
+    /// sent separately or non-confirmably.
+    pub piggybacked: bool,
+    /// Number of blocks the response was transferred in (1 if not block-wise).
+    pub blocks: u32,
+}
+
+/// A hook to observe or mutate the exchanges of a client, e.g. to inject an
+/// authorization option or to record metrics.
+pub trait Interceptor: Send {
+    /// Called once before a request is sent. Retransmissions and follow-up
+    /// block requests reuse the mutated request.
+    fn on_request(&mut self, _request: &mut CoapRequest<SocketAddr>) {}
+
+    /// Called once with the final response of an exchange.
+    fn on_response(&mut self, _request: &CoapRequest<SocketAddr>, _response: &mut CoapResponse) {}
+}
+
 pub struct CoAPClient {
-    socket: UdpSocket,
+    socket: Box<dyn ClientTransport>,
     peer_addr: SocketAddr,
     observe_sender: Option<mpsc::Sender<ObserveMessage>>,
     observe_thread: Option<thread::JoinHandle<()>>,
     block_states: LruCache<RequestCacheKey<SocketAddr>, BlockState>,
-    message_id: u16,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    max_retransmit: u32,
+    last_stats: Option<ExchangeStats>,
+    message_types: Vec<(Method, MessageType)>,
+    message_ids: Arc<Mutex<MessageIds>>,
+    token: u32,
+    keepalive: Option<(Duration, KeepaliveMode)>,
+    keepalive_failure: Option<KeepaliveFailureHandler>,
+    path_mtu: PathMtu,
+    q_block: bool,
+    max_backoff: Option<Duration>,
+    /// Whether the request being exchanged was repeated after a 4.29.
+    backed_off: bool,
 }
 
 impl CoAPClient {
@@ -37,29 +114,133 @@ impl CoAPClient {
     pub fn new_with_specific_source<A: ToSocketAddrs, B: ToSocketAddrs>(
         bind_addr: A,
         peer_addr: B,
+    ) -> Result<CoAPClient> {
+        Self::from_transport(UdpSocket::bind(bind_addr)?, peer_addr)
+    }
+
+    /// Create a CoAP client that talks to the peer address over an arbitrary
+    /// transport.
+    pub fn from_transport<T: ClientTransport + 'static, A: ToSocketAddrs>(
+        transport: T,
+        peer_addr: A,
     ) -> Result<CoAPClient> {
         peer_addr
             .to_socket_addrs()
             .and_then(|mut iter| match iter.next() {
-                Some(paddr) => UdpSocket::bind(bind_addr).and_then(|s| {
-                    s.set_read_timeout(Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)))
-                        .and_then(|_| {
-                            Ok(CoAPClient {
-                                socket: s,
-                                peer_addr: paddr,
-                                observe_sender: None,
-                                observe_thread: None,
-                                block_states: LruCache::with_expiry_duration(
-                                    Duration::from_secs(120),
-                                ),
-                                message_id: 0,
-                            })
-                        })
-                }),
+                Some(paddr) => transport
+                    .set_read_timeout(Some(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0)))
+                    .map(|_| CoAPClient {
+                        socket: Box::new(transport),
+                        peer_addr: paddr,
+                        observe_sender: None,
+                        observe_thread: None,
+                        block_states: LruCache::with_expiry_duration(Duration::from_secs(120)),
+                        interceptors: Vec::new(),
+                        max_retransmit: 0,
+                        last_stats: None,
+                        message_types: Vec::new(),
+                        message_ids: Arc::new(Mutex::new(MessageIds::new())),
+                        // start at an unpredictable value so tokens differ between clients
+                        token: SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map(|d| d.subsec_nanos())
+                            .unwrap_or(0),
+                        keepalive: None,
+                        keepalive_failure: None,
+                        path_mtu: PathMtu::new(),
+                        q_block: false,
+                        max_backoff: Some(DEFAULT_MAX_BACKOFF),
+                        backed_off: false,
+                    }),
                 None => Err(Error::new(ErrorKind::Other, "no address")),
             })
     }
 
+    /// Create a CoAP over TCP client connected to the peer address.
+    pub fn new_tcp<A: ToSocketAddrs>(addr: A) -> Result<CoAPClient> {
+        let transport = TcpClientTransport::connect(&addr)?;
+        Self::from_transport(transport, addr)
+    }
+
+    /// Create a CoAP over TLS (coaps+tcp) client connected to the peer
+    /// address. `server_name` is used for SNI and certificate verification.
+    #[cfg(feature = "tls")]
+    pub fn new_tls<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: std::sync::Arc<tls::rustls::ClientConfig>,
+    ) -> Result<CoAPClient> {
+        let transport = TcpClientTransport::connect_tls(&addr, server_name, config)?;
+        Self::from_transport(transport, addr)
+    }
+
+    /// Create a CoAP over DTLS (coaps) client connected to the peer address,
+    /// authenticated with a pre-shared key.
+    #[cfg(feature = "dtls")]
+    pub fn new_dtls<A: ToSocketAddrs>(addr: A, config: &dtls::PskConfig) -> Result<CoAPClient> {
+        let transport = dtls::DtlsClientTransport::connect(&addr, config)?;
+        Self::from_transport(transport, addr)
+    }
+
+    /// Create a CoAP over DTLS (coaps) client connected to the peer address,
+    /// authenticated with certificates. `server_name` is used for SNI and
+    /// certificate verification.
+    #[cfg(feature = "dtls")]
+    pub fn new_dtls_certificate<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: &dtls::CertificateConfig,
+    ) -> Result<CoAPClient> {
+        let transport = dtls::DtlsClientTransport::connect_with_certificate(&addr, server_name, config)?;
+        Self::from_transport(transport, addr)
+    }
+
+    /// Create a CoAP over DTLS (coaps) client connected to the peer address,
+    /// authenticated with raw public keys.
+    #[cfg(feature = "dtls")]
+    pub fn new_dtls_raw_public_key<A: ToSocketAddrs>(
+        addr: A,
+        config: &dtls::RawPublicKeyConfig,
+    ) -> Result<CoAPClient> {
+        let transport = dtls::DtlsClientTransport::connect_with_raw_public_key(&addr, config)?;
+        Self::from_transport(transport, addr)
+    }
+
+    /// Create a CoAP over QUIC client connected to the peer address.
+    /// `server_name` is used for SNI and certificate verification.
+    #[cfg(feature = "quic")]
+    pub fn new_quic<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: std::sync::Arc<tls::rustls::ClientConfig>,
+    ) -> Result<CoAPClient> {
+        let transport = quic::QuicClientTransport::connect(&addr, server_name, config)?;
+        Self::from_transport(transport, addr)
+    }
+
+    /// Create a CoAP over WebSocket (coap+ws) client connected to the peer
+    /// address.
+    #[cfg(feature = "websocket")]
+    pub fn new_ws<A: ToSocketAddrs>(addr: A) -> Result<CoAPClient> {
+        let peer = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::other("no address"))?;
+        let transport = TcpClientTransport::connect_ws(peer, &peer.to_string())?;
+        Self::from_transport(transport, peer)
+    }
+
+    /// Create a CoAP client talking to the server at `peer_path` over a Unix
+    /// domain datagram socket bound to `local_path`.
+    #[cfg(unix)]
+    pub fn new_unix<P: AsRef<std::path::Path>, Q: AsRef<std::path::Path>>(
+        local_path: P,
+        peer_path: Q,
+    ) -> Result<CoAPClient> {
+        let transport = unix::UnixClientTransport::connect(local_path, peer_path)?;
+        Self::from_transport(transport, unix::LOCAL_ADDR)
+    }
+
     /// Create a CoAP client with the peer address.
     pub fn new<A: ToSocketAddrs>(addr: A) -> Result<CoAPClient> {
         addr.to_socket_addrs()
@@ -110,11 +291,55 @@ impl CoAPClient {
         Self::request_with_timeout(url, Method::Delete, None, timeout)
     }
 
+    /// Execute a single get request and deserialize the response payload
+    /// according to its Content-Format (JSON or CBOR).
+    pub fn get_as<T: DeserializeOwned>(url: &str) -> std::result::Result<T, PayloadError> {
+        let response = Self::get(url)?;
+        let status = *response.get_status();
+        if u8::from(response.message.header.code) >> 5 != 2 {
+            return Err(PayloadError::UnexpectedStatus(status));
+        }
+        payload::read(&response.message)
+    }
+
+    /// Execute a single post request with a value serialized as JSON.
+    pub fn post_json<T: Serialize + ?Sized>(url: &str, value: &T) -> Result<CoapResponse> {
+        Self::request_typed(url, Method::Post, value, Format::Json)
+    }
+
+    /// Execute a single post request with a value serialized as CBOR.
+    pub fn post_cbor<T: Serialize + ?Sized>(url: &str, value: &T) -> Result<CoapResponse> {
+        Self::request_typed(url, Method::Post, value, Format::Cbor)
+    }
+
+    /// Discover the topic collections of publish-subscribe brokers, see
+    /// [`pubsub`]. The url addresses the server, or a group
+    /// of servers with a multicast address.
+    pub fn discover_brokers(url: &str) -> Result<Vec<Link>> {
+        let mut url =
+            Url::parse(url).map_err(|_| Error::new(ErrorKind::InvalidInput, "url error"))?;
+        url.set_query(Some(&format!("rt={}", pubsub::RESOURCE_TYPE)));
+        Self::discover(url.as_str())
+    }
+
+    /// Discover the resources of a server via `/.well-known/core`.
+    /// A query in the url (e.g. `?rt=temperature`) is passed on as a filter.
+    pub fn discover(url: &str) -> Result<Vec<Link>> {
+        let (mut client, mut request) = Self::url_request(url, Method::Get, None)?;
+        request.set_path("/.well-known/core");
+        let response = client.perform_request(&mut request, Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0))?;
+        if *response.get_status() != Status::Content {
+            return Err(Error::new(ErrorKind::NotFound, "discovery failed"));
+        }
+
+        let document = String::from_utf8(response.message.payload)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "link format error"))?;
+        link_format::parse(&document).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
     /// Execute a single request (GET, POST, PUT, DELETE) with a coap url
     pub fn request(url: &str, method: Method, data: Option<Vec<u8>>) -> Result<CoapResponse> {
-        let (domain, port, path, queries) = Self::parse_coap_url(url)?;
-        let mut client = Self::new((domain.as_str(), port))?;
-        client.request_path(&path, method, data, queries, Some(domain))
+        Self::request_with_timeout(url, method, data, Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0))
     }
 
     /// Execute a single request (GET, POST, PUT, DELETE) with a coap url and a specfic timeout
@@ -124,9 +349,22 @@ impl CoAPClient {
         data: Option<Vec<u8>>,
         timeout: Duration,
     ) -> Result<CoapResponse> {
-        let (domain, port, path, queries) = Self::parse_coap_url(url)?;
-        let mut client = Self::new((domain.as_str(), port))?;
-        client.request_path_with_timeout(&path, method, data, queries, Some(domain), timeout)
+        let (mut client, mut request) = Self::url_request(url, method, data)?;
+        client.perform_request(&mut request, timeout)
+    }
+
+    /// Execute a single request with a coaps url over a DTLS session
+    /// authenticated with a pre-shared key.
+    #[cfg(feature = "dtls")]
+    pub fn request_dtls(
+        url: &str,
+        method: Method,
+        data: Option<Vec<u8>>,
+        config: &dtls::PskConfig,
+    ) -> Result<CoapResponse> {
+        let (mut client, mut request) =
+            Self::url_request_with(url, method, data, |addr| Self::new_dtls(addr, config))?;
+        client.perform_request(&mut request, Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0))
     }
 
     /// Execute a request (GET, POST, PUT, DELETE)
@@ -158,25 +396,137 @@ impl CoAPClient {
         domain: Option<String>,
         timeout: Duration,
     ) -> Result<CoapResponse> {
-        let mut request = CoapRequest::new();
-        request.set_method(method);
-        request.set_path(path);
-        if let Some(q) = queries {
-            request.message.add_option(CoapOption::UriQuery, q);
-        }
-        if let Some(d) = domain {
-            request.message.add_option(CoapOption::UriHost, d.as_str().as_bytes().to_vec());
-        }
-        request.message.header.message_id = Self::gen_message_id(&mut self.message_id);
+        let message_type = self.default_message_type(&method);
+        let mut request = Self::build_request(path, method, data, queries, domain);
+        request.message.header.set_type(message_type);
+        self.perform_request(&mut request, timeout)
+    }
+
+    /// Execute a prepared request with the current receive timeout. Unlike
+    /// `send`, this waits for the response and handles retransmissions,
+    /// block-wise responses and interceptors. The message type of the request
+    /// is used as is.
+    pub fn exchange(&mut self, request: &mut CoapRequest<SocketAddr>) -> Result<CoapResponse> {
+        let timeout = self
+            .socket
+            .read_timeout()?
+            .unwrap_or(Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0));
+        self.perform_request(request, timeout)
+    }
+
+    /// Set the message type used by `request_path` and friends, Observe
+    /// registrations and topic requests for a method. All requests are
+    /// confirmable by default, so e.g. non-confirmable GET requests are an
+    /// explicit choice. The static helpers like `CoAPClient::get` create their
+    /// own client and always send confirmable requests.
+    ///
+    /// The client exchanges one request at a time and has no send queue, so
+    /// there is no congestion control that could order requests by priority.
+    pub fn set_default_message_type(&mut self, method: Method, message_type: MessageType) {
+        self.message_types.retain(|(m, _)| *m != method);
+        self.message_types.push((method, message_type));
+    }
 
-        match data {
-            Some(data) => request.message.payload = data,
-            None => (),
+    fn default_message_type(&self, method: &Method) -> MessageType {
+        self.message_types
+            .iter()
+            .find(|(m, _)| m == method)
+            .map_or(MessageType::Confirmable, |(_, message_type)| *message_type)
+    }
+
+    /// Add an interceptor that is run for every request made with
+    /// `request_path` and friends. Interceptors run in the order they were added.
+    pub fn add_interceptor<I: Interceptor + 'static>(&mut self, interceptor: I) {
+        self.interceptors.push(Box::new(interceptor));
+    }
+
+    /// Set how often a confirmable request is retransmitted when no answer
+    /// arrives within the receive timeout. Each retransmission doubles the
+    /// timeout. Defaults to 0; RFC 7252 suggests 4.
+    pub fn set_max_retransmit(&mut self, max_retransmit: u32) {
+        self.max_retransmit = max_retransmit;
+    }
+
+    /// Set the path MTU to a destination, or forget it with `None`. Request
+    /// payloads that would make a message exceed it are sent block-wise, and
+    /// GET requests ask for response blocks that fit.
+    pub fn set_path_mtu(&mut self, addr: IpAddr, mtu: Option<usize>) {
+        match mtu {
+            Some(mtu) => self.path_mtu.set(addr, mtu),
+            None => self.path_mtu.remove(addr),
         }
+    }
 
-        self.set_receive_timeout(Some(timeout))?;
-        self.send(&request).unwrap();
-        self.receive2(&mut request)
+    /// Set the path MTU of destinations without their own value. `None`
+    /// restores the default assumption of 1280 bytes.
+    pub fn set_default_path_mtu(&mut self, mtu: Option<usize>) {
+        self.path_mtu.set_default(mtu);
+    }
+
+    /// Transfer bodies with the Q-Block1 and Q-Block2 options (RFC 9177)
+    /// instead of Block1 and Block2 over unreliable transports, which
+    /// recovers lost blocks without a round trip per block. All requests
+    /// then go out non-confirmable and ask for Q-Block2 responses, so the
+    /// server must support the options. See [`qblock`](crate::qblock).
+    pub fn set_q_block(&mut self, enabled: bool) {
+        self.q_block = enabled;
+    }
+
+    /// Set how long the client waits at most to repeat a request answered
+    /// with 4.29 Too Many Requests (RFC 8516). The request is repeated once
+    /// after the Max-Age of the response; a longer back-off, or `None`,
+    /// returns the 4.29 response instead. Defaults to 60 seconds.
+    pub fn set_max_backoff(&mut self, max_backoff: Option<Duration>) {
+        self.max_backoff = max_backoff;
+    }
+
+    /// Return the statistics of the last exchange completed with `receive2`,
+    /// which backs `request_path` and friends.
+    pub fn last_exchange_stats(&self) -> Option<&ExchangeStats> {
+        self.last_stats.as_ref()
+    }
+
+    /// Keep the bindings of NATs and firewalls on the path alive during
+    /// an observation: when nothing was received for `interval`, send a
+    /// keepalive as selected by `mode`. `None`, the default, disables it.
+    /// Applies to observations started afterwards.
+    pub fn set_keepalive(&mut self, interval: Option<Duration>, mode: KeepaliveMode) {
+        self.keepalive = interval.map(|interval| (interval, mode));
+    }
+
+    /// Call `handler` when a keepalive cannot be sent or is not answered
+    /// within the observe timeout. The observation continues and the next
+    /// keepalive is sent after another interval.
+    pub fn set_keepalive_failure_handler<F: FnMut(&Error) + Send + 'static>(
+        &mut self,
+        handler: F,
+    ) {
+        self.keepalive_failure = Some(Arc::new(Mutex::new(handler)));
+    }
+
+    /// Send a CoAP ping, an empty confirmable message, and return once the
+    /// peer answered it, or an error if it did not within the receive
+    /// timeout. Over a reliable transport the connection shows that the
+    /// peer is there, and it returns at once.
+    pub fn ping(&self) -> Result<()> {
+        if self.socket.is_reliable() {
+            return Ok(());
+        }
+        let mut ping = Packet::new();
+        ping.header.code = MessageClass::Empty;
+        ping.header.set_type(MessageType::Confirmable);
+        ping.header.message_id = self.next_message_id();
+        Self::send_with_socket(&*self.socket, &self.peer_addr, &ping)?;
+        loop {
+            let (packet, _src) = Self::receive_from_socket(&*self.socket)?;
+            let answer = matches!(
+                packet.header.get_type(),
+                MessageType::Reset | MessageType::Acknowledgement
+            );
+            if answer && packet.header.message_id == ping.header.message_id {
+                return Ok(());
+            }
+        }
     }
 
     pub fn set_broadcast(&self, value: bool) -> Result<()> {
@@ -199,15 +549,29 @@ impl CoAPClient {
     pub fn observe_with_timeout<H: FnMut(Packet) + Send + 'static>(
         &mut self,
         resource_path: &str,
+        handler: H,
+        timeout: Duration,
+    ) -> Result<()> {
+        let mut request = CoapRequest::new();
+        request.set_path(resource_path);
+        request.message.header.set_type(self.default_message_type(&Method::Get));
+        self.observe_request(&request, handler, timeout)
+    }
+
+    /// Observe the resource `request` is for, with all its options, e.g.
+    /// Uri-Host or Uri-Query. The Observe option and the Message ID are set
+    /// by the client.
+    pub fn observe_request<H: FnMut(Packet) + Send + 'static>(
+        &mut self,
+        request: &CoapRequest<SocketAddr>,
         mut handler: H,
         timeout: Duration,
     ) -> Result<()> {
         // TODO: support observe multi resources at the same time
-        let mut message_id = self.message_id;
-        let mut register_packet = CoapRequest::new();
+        let message_ids = self.message_ids.clone();
+        let mut register_packet = request.clone();
         register_packet.set_observe_flag(ObserveOption::Register);
-        register_packet.message.header.message_id = Self::gen_message_id(&mut message_id);
-        register_packet.set_path(resource_path);
+        register_packet.message.header.message_id = self.next_message_id();
 
         self.send(&register_packet)?;
 
@@ -226,51 +590,105 @@ impl CoAPClient {
         }
         let peer_addr = self.peer_addr.clone();
         let (observe_sender, observe_receiver) = mpsc::channel();
-        let observe_path = String::from(resource_path);
 
-        let observe_thread = thread::spawn(move || loop {
-            match Self::receive_from_socket(&socket) {
-                Ok((packet, _src)) => {
-                    let receive_packet = CoapRequest::from_packet(packet, &peer_addr);
-
-                    handler(receive_packet.message);
+        let keepalive = self.keepalive;
+        let keepalive_failure = self.keepalive_failure.clone();
+        let keepalive_failed = move |error: &Error| {
+            warn!("observe keepalive failed: {}", error);
+            if let Some(ref handler) = keepalive_failure {
+                (handler.lock().unwrap())(error);
+            }
+        };
 
-                    if let Some(response) = receive_packet.response {
-                        let mut packet = Packet::new();
-                        packet.header.set_type(response.message.header.get_type());
-                        packet.header.message_id = response.message.header.message_id;
-                        packet.set_token(response.message.get_token().into());
+        let observe_thread = thread::spawn(move || {
+            let mut last_received = Instant::now();
+            let mut keepalive_sent: Option<Instant> = None;
+            loop {
+                match Self::receive_from_socket(&*socket) {
+                    Ok((packet, _src)) => {
+                        last_received = Instant::now();
+                        keepalive_sent = None;
+                        let receive_packet = CoapRequest::from_packet(packet, &peer_addr);
+
+                        // empty messages answer pings and are not notifications
+                        if receive_packet.message.header.code != MessageClass::Empty {
+                            handler(receive_packet.message);
+                        }
 
-                        match Self::send_with_socket(&socket, &peer_addr, &packet) {
-                            Ok(_) => (),
-                            Err(e) => {
-                                warn!("reply ack failed {}", e)
+                        if let Some(response) = receive_packet.response {
+                            let mut packet = Packet::new();
+                            packet.header.set_type(response.message.header.get_type());
+                            packet.header.message_id = response.message.header.message_id;
+                            packet.set_token(response.message.get_token().into());
+
+                            match Self::send_with_socket(&*socket, &peer_addr, &packet) {
+                                Ok(_) => (),
+                                Err(e) => {
+                                    warn!("reply ack failed {}", e)
+                                }
+                            }
+                        }
+                    }
+                    Err(e) => match e.kind() {
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                            info!("Observe timeout");
+                        }
+                        _ => warn!("observe failed {:?}", e),
+                    },
+                };
+
+                if let Some((interval, mode)) = keepalive {
+                    match keepalive_sent {
+                        Some(sent) if sent.elapsed() >= timeout => {
+                            keepalive_sent = None;
+                            last_received = Instant::now();
+                            keepalive_failed(&Error::new(
+                                ErrorKind::TimedOut,
+                                "keepalive not answered",
+                            ));
+                        }
+                        None if last_received.elapsed() >= interval => {
+                            let mut packet = match mode {
+                                KeepaliveMode::Ping => {
+                                    let mut ping = Packet::new();
+                                    ping.header.code = MessageClass::Empty;
+                                    ping.header.set_type(MessageType::Confirmable);
+                                    ping
+                                }
+                                KeepaliveMode::Reregister => register_packet.message.clone(),
+                            };
+                            packet.header.message_id =
+                                message_ids.lock().unwrap().next(peer_addr);
+                            match Self::send_with_socket(&*socket, &peer_addr, &packet) {
+                                Ok(_) => keepalive_sent = Some(Instant::now()),
+                                Err(e) => {
+                                    last_received = Instant::now();
+                                    keepalive_failed(&e);
+                                }
                             }
                         }
+                        _ => {}
                     }
                 }
-                Err(e) => match e.kind() {
-                    ErrorKind::WouldBlock => {
-                        info!("Observe timeout");
+
+                match observe_receiver.try_recv() {
+                    Ok(ObserveMessage::Terminate) => {
+                        let mut deregister_packet = register_packet.clone();
+                        deregister_packet.message.header.message_id =
+                            message_ids.lock().unwrap().next(peer_addr);
+                        deregister_packet.set_observe_flag(ObserveOption::Deregister);
+
+                        // a server that is gone forgets the observation anyway
+                        let deregistered =
+                            Self::send_with_socket(&*socket, &peer_addr, &deregister_packet.message)
+                                .and_then(|_| Self::receive_from_socket(&*socket));
+                        if let Err(e) = deregistered {
+                            warn!("deregister failed {}", e);
+                        }
+                        break;
                     }
-                    _ => warn!("observe failed {:?}", e),
-                },
-            };
-
-            match observe_receiver.try_recv() {
-                Ok(ObserveMessage::Terminate) => {
-                    let mut deregister_packet = CoapRequest::<SocketAddr>::new();
-                    deregister_packet.message.header.message_id =
-                        Self::gen_message_id(&mut message_id);
-                    deregister_packet.set_observe_flag(ObserveOption::Deregister);
-                    deregister_packet.set_path(observe_path.as_str());
-
-                    Self::send_with_socket(&socket, &peer_addr, &deregister_packet.message)
-                        .unwrap();
-                    Self::receive_from_socket(&socket).unwrap();
-                    break;
+                    _ => continue,
                 }
-                _ => continue,
             }
         });
         self.observe_sender = Some(observe_sender);
@@ -291,9 +709,118 @@ impl CoAPClient {
         }
     }
 
+    /// Create a topic described by `link`, e.g. `<temperature>;ct=0`, in
+    /// the topic collection at `collection` of a broker and return its path.
+    /// The topic is removed after `lifetime` without publications.
+    pub fn create_topic(
+        &mut self,
+        collection: &str,
+        link: &Link,
+        lifetime: Option<Duration>,
+    ) -> Result<String> {
+        let mut request = self.topic_request(collection, Method::Post);
+        request.message.payload = link.to_string().into_bytes();
+        payload::set_content_format(&mut request.message, link_format::CONTENT_FORMAT);
+        if let Some(lifetime) = lifetime {
+            let seconds = u32::try_from(lifetime.as_secs()).unwrap_or(u32::MAX);
+            request
+                .message
+                .add_option_as(CoapOption::MaxAge, OptionValueU32(seconds));
+        }
+        let response = self.exchange(&mut request)?;
+        match *response.get_status() {
+            Status::Created => {}
+            Status::Forbidden => return Err(Error::new(ErrorKind::AlreadyExists, "topic exists")),
+            status => return Err(Self::topic_error(status)),
+        }
+        let location: Vec<String> = response
+            .message
+            .get_option(CoapOption::LocationPath)
+            .map(|segments| {
+                segments
+                    .iter()
+                    .map(|segment| String::from_utf8_lossy(segment).into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        Ok(location.join("/"))
+    }
+
+    /// Publish `data` with an optional Content-Format to the topic at
+    /// `topic` of a broker.
+    pub fn publish(
+        &mut self,
+        topic: &str,
+        data: Vec<u8>,
+        content_format: Option<u16>,
+    ) -> Result<()> {
+        let mut request = self.topic_request(topic, Method::Put);
+        request.message.payload = data;
+        if let Some(content_format) = content_format {
+            payload::set_content_format(&mut request.message, content_format);
+        }
+        let response = self.exchange(&mut request)?;
+        match *response.get_status() {
+            Status::Changed | Status::Created => Ok(()),
+            status => Err(Self::topic_error(status)),
+        }
+    }
+
+    /// Remove the topic at `topic` of a broker, with its subtopics.
+    pub fn remove_topic(&mut self, topic: &str) -> Result<()> {
+        let mut request = self.topic_request(topic, Method::Delete);
+        let response = self.exchange(&mut request)?;
+        match *response.get_status() {
+            Status::Deleted => Ok(()),
+            status => Err(Self::topic_error(status)),
+        }
+    }
+
+    /// Subscribe to the topic at `topic` of a broker. The stream yields the
+    /// last publication, then the following ones, and ends when the topic
+    /// is removed. Like [`observe`](Self::observe), a client has one
+    /// subscription at a time, cancelled with [`unobserve`](Self::unobserve).
+    pub fn subscribe(&mut self, topic: &str) -> Result<UnboundedReceiver<Packet>> {
+        let (sender, receiver) = unbounded();
+        let mut sender = Some(sender);
+        self.observe(topic, move |packet| {
+            let ends = u8::from(packet.header.code) >> 5 != 2;
+            if let Some(ref sender) = sender {
+                let _ = sender.unbounded_send(packet);
+            }
+            if ends {
+                sender = None;
+            }
+        })?;
+        Ok(receiver)
+    }
+
+    fn topic_request(&self, path: &str, method: Method) -> CoapRequest<SocketAddr> {
+        let mut request = CoapRequest::new();
+        request.set_method(method);
+        request.set_path(path);
+        request.message.header.set_type(self.default_message_type(&method));
+        request
+    }
+
+    fn topic_error(status: Status) -> Error {
+        match status {
+            Status::NotFound => Error::new(ErrorKind::NotFound, "topic not found"),
+            Status::UnsupportedContentFormat => {
+                Error::new(ErrorKind::InvalidInput, "content format not supported by topic")
+            }
+            status => Error::other(format!("unexpected response {:?}", status)),
+        }
+    }
+
     /// Execute a request.
     pub fn send(&self, request: &CoapRequest<SocketAddr>) -> Result<()> {
-        Self::send_with_socket(&self.socket, &self.peer_addr, &request.message)
+        // keep the ID of the caller from being allocated to another message
+        self.message_ids
+            .lock()
+            .unwrap()
+            .reserve(self.peer_addr, request.message.header.message_id);
+        Self::send_with_socket(&*self.socket, &self.peer_addr, &request.message)
     }
 
     /// Send a request to all CoAP devices.
@@ -325,7 +852,7 @@ impl CoAPClient {
 
         match request.message.to_bytes() {
             Ok(bytes) => {
-                let size = self.socket.send_to(&bytes[..], addr)?;
+                let size = self.socket.send_to(&bytes[..], &addr)?;
                 if size == bytes.len() {
                     Ok(())
                 } else {
@@ -338,14 +865,20 @@ impl CoAPClient {
 
     /// Receive a response.
     pub fn receive(&self) -> Result<CoapResponse> {
-        let (packet, _src) = Self::receive_from_socket(&self.socket)?;
+        let (packet, _src) = Self::receive_from_socket(&*self.socket)?;
         Ok(CoapResponse { message: packet })
     }
 
     /// Receive a response support block-wise.
     pub fn receive2(&mut self, request:&mut CoapRequest<SocketAddr>) -> Result<CoapResponse> {
+        let start = Instant::now();
+        let mut stats = ExchangeStats::default();
+        self.backed_off = false;
         loop {
-            let (packet, _src) = Self::receive_from_socket(&self.socket)?;
+            let packet = self.receive_response(request, &mut stats)?;
+            if packet.get_option(CoapOption::Block2).is_some() {
+                stats.blocks += 1;
+            }
             request.response = CoapResponse::new(&request.message);
             let response = request
             .response
@@ -365,12 +898,83 @@ impl CoAPClient {
                 }
             }
         }
+
+        stats.rtt = start.elapsed();
+        stats.blocks = stats.blocks.max(1);
+        self.last_stats = Some(stats);
         Ok(CoapResponse { message: request.response.as_ref().unwrap().message.clone() })
     }
 
+    /// Wait for the response matching the request, retransmitting a
+    /// confirmable request and acknowledging a separate response as needed.
+    fn receive_response(
+        &mut self,
+        request: &CoapRequest<SocketAddr>,
+        stats: &mut ExchangeStats,
+    ) -> Result<Packet> {
+        let timeout = self.socket.read_timeout()?;
+        let mut acknowledged = request.message.header.get_type() != MessageType::Confirmable;
+        let mut retransmissions = 0;
+
+        let result = loop {
+            match Self::receive_from_socket(&*self.socket) {
+                Ok((packet, _src)) => {
+                    let own_message_id =
+                        packet.header.message_id == request.message.header.message_id;
+                    if packet.header.code == MessageClass::Empty {
+                        match packet.header.get_type() {
+                            MessageType::Acknowledgement if own_message_id => acknowledged = true,
+                            MessageType::Reset if own_message_id => {
+                                break Err(Error::new(ErrorKind::ConnectionReset, "reset by peer"))
+                            }
+                            _ => {}
+                        }
+                        continue;
+                    }
+                    if packet.get_token() != request.message.get_token() {
+                        debug!("ignore response with unknown token {:?}", packet.get_token());
+                        continue;
+                    }
+
+                    stats.piggybacked = false;
+                    match packet.header.get_type() {
+                        MessageType::Acknowledgement => stats.piggybacked = own_message_id,
+                        MessageType::Confirmable => {
+                            let mut ack = Packet::new();
+                            ack.header.set_type(MessageType::Acknowledgement);
+                            ack.header.code = MessageClass::Empty;
+                            ack.header.message_id = packet.header.message_id;
+                            Self::send_with_socket(&*self.socket, &self.peer_addr, &ack)?;
+                        }
+                        _ => {}
+                    }
+                    break Ok(packet);
+                }
+                Err(e)
+                    if (e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut)
+                        && !acknowledged
+                        && retransmissions < self.max_retransmit =>
+                {
+                    retransmissions += 1;
+                    stats.retransmissions += 1;
+                    debug!("retransmit message {}", request.message.header.message_id);
+                    if let Some(timeout) = timeout {
+                        self.socket
+                            .set_read_timeout(Some(timeout * 2u32.pow(retransmissions)))?;
+                    }
+                    self.send(request)?;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.socket.set_read_timeout(timeout)?;
+        result
+    }
+
     /// Receive a response.
     pub fn receive_from(&self) -> Result<(CoapResponse, SocketAddr)> {
-        let (packet, src) = Self::receive_from_socket(&self.socket)?;
+        let (packet, src) = Self::receive_from_socket(&*self.socket)?;
         Ok((CoapResponse { message: packet }, src))
     }
 
@@ -380,7 +984,7 @@ impl CoAPClient {
     }
 
     fn send_with_socket(
-        socket: &UdpSocket,
+        socket: &dyn ClientTransport,
         peer_addr: &SocketAddr,
         message: &Packet,
     ) -> Result<()> {
@@ -397,7 +1001,7 @@ impl CoAPClient {
         }
     }
 
-    fn receive_from_socket(socket: &UdpSocket) -> Result<(Packet, SocketAddr)> {
+    fn receive_from_socket(socket: &dyn ClientTransport) -> Result<(Packet, SocketAddr)> {
         let mut buf = [0; 1500];
 
         let (nread, src) = socket.recv_from(&mut buf)?;
@@ -423,9 +1027,10 @@ impl CoAPClient {
             .replace(&host, "$1")
             .to_string();
 
-        let port = match url_params.port() {
-            Some(p) => p,
-            None => 5683,
+        let port = match (url_params.port(), url_params.scheme()) {
+            (Some(p), _) => p,
+            (None, "coaps") => DEFAULT_SECURE_PORT,
+            (None, _) => DEFAULT_PORT,
         };
 
         let path = url_params.path().to_string();
@@ -435,26 +1040,525 @@ impl CoAPClient {
         return Ok((host.to_string(), port, path, queries));
     }
 
-    fn gen_message_id(message_id: &mut u16) -> u16 {
-        (*message_id) += 1;
-        return *message_id;
+    fn request_typed<T: Serialize + ?Sized>(
+        url: &str,
+        method: Method,
+        value: &T,
+        format: Format,
+    ) -> Result<CoapResponse> {
+        let (mut client, mut request) = Self::url_request(url, method, None)?;
+        payload::write(&mut request.message, value, format)?;
+        client.perform_request(&mut request, Duration::new(DEFAULT_RECEIVE_TIMEOUT, 0))
+    }
+
+    /// Create a client for the host of a coap url together with a request
+    /// for its path and query.
+    fn url_request(
+        url: &str,
+        method: Method,
+        data: Option<Vec<u8>>,
+    ) -> Result<(CoAPClient, CoapRequest<SocketAddr>)> {
+        if url.get(..6).is_some_and(|scheme| scheme.eq_ignore_ascii_case("coaps:")) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "coaps urls need DTLS credentials, see request_dtls",
+            ));
+        }
+        Self::url_request_with(url, method, data, |addr| Self::new(addr))
+    }
+
+    /// Like `url_request`, connecting to the host with `connect`.
+    fn url_request_with<F: FnOnce((&str, u16)) -> Result<CoAPClient>>(
+        url: &str,
+        method: Method,
+        data: Option<Vec<u8>>,
+        connect: F,
+    ) -> Result<(CoAPClient, CoapRequest<SocketAddr>)> {
+        let (domain, port, path, queries) = Self::parse_coap_url(url)?;
+        let client = connect((domain.as_str(), port))?;
+        let mut request = Self::build_request(&path, method, data, queries, Some(domain));
+        let default_port = match url.get(..6) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("coaps:") => DEFAULT_SECURE_PORT,
+            _ => DEFAULT_PORT,
+        };
+        // the Uri-Port only matters next to a Uri-Host, for name-based virtual hosting
+        if port != default_port && request.message.get_option(CoapOption::UriHost).is_some() {
+            request
+                .message
+                .add_option_as(CoapOption::UriPort, OptionValueU16(port));
+        }
+        Ok((client, request))
+    }
+
+    fn build_request(
+        path: &str,
+        method: Method,
+        data: Option<Vec<u8>>,
+        queries: Option<Vec<u8>>,
+        domain: Option<String>,
+    ) -> CoapRequest<SocketAddr> {
+        let mut request = CoapRequest::new();
+        request.set_method(method);
+        request.set_path(path);
+        if let Some(q) = queries {
+            request.message.add_option(CoapOption::UriQuery, q);
+        }
+        // an IP literal is the default Uri-Host and must not be sent
+        if let Some(d) = domain.filter(|d| d.parse::<IpAddr>().is_err()) {
+            request.message.add_option(CoapOption::UriHost, d.as_bytes().to_vec());
+        }
+
+        if let Some(data) = data {
+            request.message.payload = data;
+        }
+        request
+    }
+
+    fn perform_request(
+        &mut self,
+        request: &mut CoapRequest<SocketAddr>,
+        timeout: Duration,
+    ) -> Result<CoapResponse> {
+        request.message.header.message_id = self.next_message_id();
+        // the token is the only way to match a response on reliable transports
+        if request.message.get_token().is_empty() {
+            self.token = self.token.wrapping_add(1);
+            request.message.set_token(self.token.to_be_bytes().to_vec());
+        }
+        for interceptor in self.interceptors.iter_mut() {
+            interceptor.on_request(request);
+        }
+        self.set_receive_timeout(Some(timeout))?;
+        let mut response = if self.q_block && !self.socket.is_reliable() {
+            self.exchange_q_block(request)?
+        } else {
+            if !self.socket.is_reliable() {
+                self.fit_path_mtu(request)?;
+            }
+            self.send(request)?;
+            self.receive2(request)?
+        };
+
+        for interceptor in self.interceptors.iter_mut() {
+            interceptor.on_response(request, &mut response);
+        }
+        Ok(response)
+    }
+
+    /// Keep the request and its response within the path MTU to the peer:
+    /// a payload that does not fit is sent block-wise (RFC 7959 Block1), and
+    /// with a configured path MTU a GET asks for blocks that fit.
+    fn fit_path_mtu(&mut self, request: &mut CoapRequest<SocketAddr>) -> Result<()> {
+        let max_message_size = self.path_mtu.max_message_size(self.peer_addr.ip());
+        let encode = |message: &Packet| {
+            message
+                .to_bytes()
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "packet error"))
+        };
+        // a block option takes up to 4 bytes, the payload marker one more
+        let overhead = encode(&request.message)?.len() - request.message.payload.len() + 5;
+
+        if *request.get_method() == Method::Get
+            && self.path_mtu.get(self.peer_addr.ip()).is_some()
+            && request.message.get_option(CoapOption::Block2).is_none()
+        {
+            // the response carries roughly the options of the request
+            if let Some(size) = mtu::block_size(max_message_size, overhead) {
+                let block2 = BlockValue::new(0, false, size)
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid block size"))?;
+                request.message.add_option_as(CoapOption::Block2, block2);
+            }
+        }
+
+        if encode(&request.message)?.len() <= max_message_size
+            || request.message.get_option(CoapOption::Block1).is_some()
+        {
+            return Ok(());
+        }
+        // Size1 announces the body, so that a server can turn it away early;
+        // it takes up to 6 bytes
+        let size = mtu::block_size(max_message_size, overhead + 6).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "options exceed the path MTU")
+        })?;
+        let payload = mem::take(&mut request.message.payload);
+        payload::set_size(&mut request.message, CoapOption::Size1, payload.len());
+        Self::set_block(request, CoapOption::Block1, &payload, 0, size)?;
+        self.block_states
+            .entry(request.deref().into())
+            .or_insert(BlockState::default())
+            .request_payload = Some(payload);
+        Ok(())
+    }
+
+    /// Exchange a request with Q-Block1 and Q-Block2: a payload that does
+    /// not fit the path MTU goes out in sets of blocks, and the response is
+    /// collected from the blocks the server sends.
+    fn exchange_q_block(&mut self, request: &mut CoapRequest<SocketAddr>) -> Result<CoapResponse> {
+        let start = Instant::now();
+        let mut stats = ExchangeStats::default();
+        request.message.header.set_type(MessageType::NonConfirmable);
+        let max_message_size = self.path_mtu.max_message_size(self.peer_addr.ip());
+        let len = request
+            .message
+            .to_bytes()
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "packet error"))?
+            .len();
+        // Q-Block1, Q-Block2, Size1 and a Request-Tag take up to 5, 3, 6 and
+        // 7 bytes, the payload marker one more
+        let overhead = len - request.message.payload.len() + 22;
+        let size = mtu::block_size(max_message_size, overhead).ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "options exceed the path MTU")
+        })?;
+        if request.message.get_option(CoapOption::Unknown(Q_BLOCK2)).is_none() {
+            let block2 = BlockValue::new(0, false, size)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid block size"))?;
+            request
+                .message
+                .add_option_as(CoapOption::Unknown(Q_BLOCK2), block2);
+        }
+
+        let first = if len + 3 > max_message_size {
+            self.send_q_block1(request, size, &mut stats)?
+        } else {
+            self.send(request)?;
+            self.receive_q_block(request)?
+                .ok_or_else(|| Error::new(ErrorKind::TimedOut, "no response"))?
+        };
+        let response = self.receive_q_block2(request, first, &mut stats)?;
+
+        stats.rtt = start.elapsed();
+        stats.blocks = stats.blocks.max(1);
+        self.last_stats = Some(stats);
+        Ok(response)
+    }
+
+    /// Send the payload of the request in sets of Q-Block1 blocks, the next
+    /// set once the server confirmed one with 2.31 Continue or did not
+    /// answer in time, and send again the blocks it reports missing.
+    /// Returns the final response.
+    fn send_q_block1(
+        &mut self,
+        request: &mut CoapRequest<SocketAddr>,
+        size: usize,
+        stats: &mut ExchangeStats,
+    ) -> Result<Packet> {
+        let payload = mem::take(&mut request.message.payload);
+        let count = payload.len().div_ceil(size);
+        payload::set_size(&mut request.message, CoapOption::Size1, payload.len());
+        self.token = self.token.wrapping_add(1);
+        request
+            .message
+            .add_option(CoapOption::Unknown(REQUEST_TAG), self.token.to_be_bytes().to_vec());
+
+        let mut next = count.min(MAX_PAYLOADS);
+        let mut pending: Vec<usize> = (0..next).collect();
+        let mut rounds = 0;
+        loop {
+            for &num in &pending {
+                Self::set_block(request, CoapOption::Unknown(Q_BLOCK1), &payload, num, size)?;
+                request.message.header.message_id = self.next_message_id();
+                self.send(request)?;
+            }
+            match self.receive_q_block(request)? {
+                Some(packet) if packet.header.code == MessageClass::Response(Status::Continue) => {
+                    rounds = 0;
+                    pending = (next..count.min(next + MAX_PAYLOADS)).collect();
+                    next += pending.len();
+                }
+                Some(packet)
+                    if packet.header.code
+                        == MessageClass::Response(Status::RequestEntityIncomplete)
+                        && rounds < MAX_RECOVERY_ROUNDS =>
+                {
+                    let missing = match qblock::decode_missing_blocks(&packet.payload) {
+                        Some(missing) => missing,
+                        None => return Ok(packet),
+                    };
+                    rounds += 1;
+                    stats.retransmissions += missing.len() as u32;
+                    pending = missing.into_iter().filter(|num| *num < count).collect();
+                }
+                Some(packet) => return Ok(packet),
+                // without an answer, the server may have missed the end of
+                // the set, so carry on
+                None if next < count => {
+                    pending = (next..count.min(next + MAX_PAYLOADS)).collect();
+                    next += pending.len();
+                }
+                None if rounds < MAX_RECOVERY_ROUNDS => {
+                    rounds += 1;
+                    stats.retransmissions += 1;
+                    pending = vec![count - 1];
+                }
+                None => return Err(Error::new(ErrorKind::TimedOut, "no response")),
+            }
+        }
+    }
+
+    /// Collect the blocks of a response sent with Q-Block2, starting with
+    /// `first`. The next set is asked for once one is complete, and missing
+    /// blocks once no more arrive within the receive timeout.
+    fn receive_q_block2(
+        &mut self,
+        request: &CoapRequest<SocketAddr>,
+        first: Packet,
+        stats: &mut ExchangeStats,
+    ) -> Result<CoapResponse> {
+        let size = match qblock::get(&first, Q_BLOCK2) {
+            Some(block) => block.size(),
+            None => return Ok(CoapResponse { message: first }),
+        };
+        let etag = first.get_option(CoapOption::ETag).cloned();
+        let mut blocks = BTreeMap::new();
+        let mut last = None;
+        let mut rounds = 0;
+        let mut received = Some(first.clone());
+        loop {
+            match received {
+                Some(packet) => {
+                    let block = match qblock::get(&packet, Q_BLOCK2) {
+                        Some(block) => block,
+                        None => return Ok(CoapResponse { message: packet }),
+                    };
+                    let changed = packet.get_option(CoapOption::ETag).cloned() != etag;
+                    if changed || block.size() != size {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            "body changed during transfer",
+                        ));
+                    }
+                    let num = usize::from(block.num);
+                    if !block.more {
+                        last = Some(num);
+                    }
+                    if blocks.insert(num, packet.payload).is_none() {
+                        stats.blocks += 1;
+                    }
+                    rounds = 0;
+                }
+                None if rounds < MAX_RECOVERY_ROUNDS => rounds += 1,
+                None => return Err(Error::new(ErrorKind::TimedOut, "missing blocks")),
+            }
+
+            let highest = blocks.keys().next_back().copied().unwrap_or(0);
+            let missing: Vec<usize> = (0..=last.unwrap_or(highest))
+                .filter(|num| !blocks.contains_key(num))
+                .collect();
+            if last.is_some() && missing.is_empty() {
+                break;
+            }
+            let set_complete = missing.is_empty() && (highest + 1) % MAX_PAYLOADS == 0;
+            if set_complete || rounds > 0 {
+                let next = last.is_none().then_some(highest + 1);
+                self.request_q_block2(request, &missing, next, size)?;
+            }
+            received = self.receive_q_block(request)?;
+        }
+
+        let mut response = first;
+        response.clear_option(CoapOption::Unknown(Q_BLOCK2));
+        response.payload = blocks.into_values().flatten().collect();
+        Ok(CoapResponse { message: response })
+    }
+
+    /// Ask for the `missing` blocks of a Q-Block2 response and, with
+    /// `next`, for the set starting at that block.
+    fn request_q_block2(
+        &mut self,
+        request: &CoapRequest<SocketAddr>,
+        missing: &[usize],
+        next: Option<usize>,
+        size: usize,
+    ) -> Result<()> {
+        let mut message = request.message.clone();
+        message.payload.clear();
+        message.clear_option(CoapOption::Unknown(Q_BLOCK1));
+        message.clear_option(CoapOption::Unknown(REQUEST_TAG));
+        message.clear_option(CoapOption::Unknown(Q_BLOCK2));
+        let requested = missing
+            .iter()
+            .map(|num| (*num, false))
+            .chain(next.map(|num| (num, true)));
+        for (num, more) in requested {
+            let block2 = BlockValue::new(num, more, size)
+                .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid block size"))?;
+            message.add_option_as(CoapOption::Unknown(Q_BLOCK2), block2);
+        }
+        message.header.message_id = self.next_message_id();
+        Self::send_with_socket(&*self.socket, &self.peer_addr, &message)
+    }
+
+    /// Wait up to the receive timeout for a response to the request, or
+    /// return `None`.
+    fn receive_q_block(&mut self, request: &CoapRequest<SocketAddr>) -> Result<Option<Packet>> {
+        loop {
+            match Self::receive_from_socket(&*self.socket) {
+                Ok((packet, _src)) => {
+                    if packet.header.code == MessageClass::Empty
+                        || packet.get_token() != request.message.get_token()
+                    {
+                        continue;
+                    }
+                    if packet.header.get_type() == MessageType::Confirmable {
+                        let mut ack = Packet::new();
+                        ack.header.set_type(MessageType::Acknowledgement);
+                        ack.header.code = MessageClass::Empty;
+                        ack.header.message_id = packet.header.message_id;
+                        Self::send_with_socket(&*self.socket, &self.peer_addr, &ack)?;
+                    }
+                    return Ok(Some(packet));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                    return Ok(None)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Put block `num` of `payload` into the request, with its block option
+    /// `option`.
+    fn set_block(
+        request: &mut CoapRequest<SocketAddr>,
+        option: CoapOption,
+        payload: &[u8],
+        num: usize,
+        size: usize,
+    ) -> Result<()> {
+        let start = num * size;
+        let end = payload.len().min(start + size);
+        let block = BlockValue::new(num, end < payload.len(), size)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "invalid block size"))?;
+        request.message.clear_option(option);
+        request.message.add_option_as(option, block);
+        request.message.payload = payload[start..end].to_vec();
+        Ok(())
+    }
+
+    fn next_message_id(&self) -> u16 {
+        self.message_ids.lock().unwrap().next(self.peer_addr)
     }
 
     fn intercept_response(&mut self, request: &mut CoapRequest<SocketAddr>) -> std::result::Result<bool, HandlingError> {
+        if self.maybe_handle_response_echo(request) {
+            return Ok(true);
+        }
+        if self.maybe_handle_response_too_many_requests(request) {
+            return Ok(true);
+        }
+
         let state = self
             .block_states
             .entry(request.deref().into())
             .or_insert(BlockState::default());
-        
+
+        if Self::maybe_handle_response_block1(request, state)? {
+            request.message.header.message_id = self.next_message_id();
+            return Ok(true);
+        }
+
         let block2_handled =
             Self::maybe_handle_response_block2(request, state)?;
         if block2_handled {
+            request.message.header.message_id = self.next_message_id();
             return Ok(true);
         }
 
         Ok(false)
     }
 
+    /// Answer a 4.01 Echo challenge (RFC 9175) by repeating the request with the
+    /// Echo value. A request that already carried an Echo value is not retried,
+    /// so a persistent rejection is returned to the caller.
+    fn maybe_handle_response_echo(&mut self, request: &mut CoapRequest<SocketAddr>) -> bool {
+        let response = request.response.as_ref().unwrap();
+        if *response.get_status() != Status::Unauthorized {
+            return false;
+        }
+
+        let echo = match response
+            .message
+            .get_option(CoapOption::Unknown(ECHO))
+            .and_then(|values| values.front())
+        {
+            Some(echo) => echo.clone(),
+            None => return false,
+        };
+
+        let already_echoed = request
+            .message
+            .get_option(CoapOption::Unknown(ECHO))
+            .is_some_and(|values| !values.is_empty());
+        if already_echoed {
+            return false;
+        }
+
+        debug!("retrying request with echo {:?}", echo);
+        request.message.add_option(CoapOption::Unknown(ECHO), echo);
+        request.message.header.message_id = self.next_message_id();
+        true
+    }
+
+    /// Repeat a request answered with 4.29 Too Many Requests once the
+    /// back-off the server asked for elapsed, unless it exceeds the maximum
+    /// back-off. A request is repeated only once, so a persistent rejection
+    /// is returned to the caller.
+    fn maybe_handle_response_too_many_requests(
+        &mut self,
+        request: &mut CoapRequest<SocketAddr>,
+    ) -> bool {
+        let response = request.response.as_ref().unwrap();
+        let retry_after = match throttle::retry_after(&response.message) {
+            Some(retry_after) => retry_after,
+            None => return false,
+        };
+        if self.backed_off || self.max_backoff.is_none_or(|max| retry_after > max) {
+            return false;
+        }
+
+        debug!("retrying request after {:?}", retry_after);
+        self.backed_off = true;
+        thread::sleep(retry_after);
+        request.message.header.message_id = self.next_message_id();
+        true
+    }
+
+    /// Send the next block of a block-wise request once the server asked
+    /// for it with 2.31 Continue, in the block size the server chose.
+    fn maybe_handle_response_block1(
+        request: &mut CoapRequest<SocketAddr>,
+        state: &mut BlockState,
+    ) -> std::result::Result<bool, HandlingError> {
+        let payload = match state.request_payload.take() {
+            Some(payload) => payload,
+            None => return Ok(false),
+        };
+        let response = request.response.as_ref().unwrap();
+        if *response.get_status() != Status::Continue {
+            return Ok(false);
+        }
+
+        let sent = request
+            .message
+            .get_first_option_as::<BlockValue>(CoapOption::Block1)
+            .and_then(|x| x.ok())
+            .ok_or_else(|| HandlingError::internal("missing block1 option"))?;
+        let size = response
+            .message
+            .get_first_option_as::<BlockValue>(CoapOption::Block1)
+            .and_then(|x| x.ok())
+            .map_or(sent.size(), |acked| acked.size().min(sent.size()));
+        let offset = usize::from(sent.num) * sent.size() + request.message.payload.len();
+        if offset >= payload.len() {
+            return Ok(false);
+        }
+
+        Self::set_block(request, CoapOption::Block1, &payload, offset / size, size)
+            .map_err(HandlingError::internal)?;
+        state.request_payload = Some(payload);
+        Ok(true)
+    }
+
     fn maybe_handle_response_block2(
         request: &mut CoapRequest<SocketAddr>,
         state: &mut BlockState,
@@ -506,6 +1610,7 @@ impl Drop for CoAPClient {
 #[derive(Debug, Clone, Default)]
 pub struct BlockState {
     cached_payload: Option<Vec<u8>>,
+    request_payload: Option<Vec<u8>>,
 }
 
 #[cfg(test)]
@@ -538,6 +1643,23 @@ mod test {
         None
     }
 
+    #[test]
+    fn test_uri_host_and_port() {
+        let (_, request) = CoAPClient::url_request("coap://localhost:5684/a", Method::Get, None).unwrap();
+        let host = request.message.get_option(CoapOption::UriHost).unwrap();
+        assert_eq!(host.front().unwrap(), b"localhost");
+        let port = request.message.get_first_option_as::<OptionValueU16>(CoapOption::UriPort);
+        assert_eq!(port.unwrap().unwrap(), OptionValueU16(5684));
+
+        let (_, request) = CoAPClient::url_request("coap://localhost/a", Method::Get, None).unwrap();
+        assert!(request.message.get_option(CoapOption::UriHost).is_some());
+        assert!(request.message.get_option(CoapOption::UriPort).is_none());
+
+        let (_, request) = CoAPClient::url_request("coap://127.0.0.1:5684/a", Method::Get, None).unwrap();
+        assert!(request.message.get_option(CoapOption::UriHost).is_none());
+        assert!(request.message.get_option(CoapOption::UriPort).is_none());
+    }
+
     #[test]
     fn test_parse_queries() {
         if let Ok((_, _, _, Some(queries))) =
@@ -636,6 +1758,341 @@ mod test {
         assert_eq!(resp.message.payload, b"DELETE OK".to_vec());
     }
 
+    async fn echo_challenge_handler(req: CoapRequest<SocketAddr>) -> Option<CoapResponse> {
+        let echo = req
+            .message
+            .get_option(CoapOption::Unknown(ECHO))
+            .and_then(|values| values.front().cloned());
+        let path = req.get_path();
+
+        match req.response {
+            Some(mut response) => {
+                match echo {
+                    Some(value) if value == b"fresh".to_vec() => {
+                        response.message.payload = b"unlocked".to_vec();
+                    }
+                    _ => {
+                        response.set_status(Status::Unauthorized);
+                        let challenge = if path == "stale" {
+                            b"stale".to_vec()
+                        } else {
+                            b"fresh".to_vec()
+                        };
+                        response
+                            .message
+                            .add_option(CoapOption::Unknown(ECHO), challenge);
+                    }
+                }
+                Some(response)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_echo_challenge() {
+        let server_port = server::test::spawn_server("127.0.0.1:0", echo_challenge_handler)
+            .recv()
+            .unwrap();
+
+        let resp = CoAPClient::get(&format!("coap://127.0.0.1:{}/lock", server_port)).unwrap();
+        assert_eq!(*resp.get_status(), Status::Content);
+        assert_eq!(resp.message.payload, b"unlocked".to_vec());
+
+        let resp = CoAPClient::get(&format!("coap://127.0.0.1:{}/stale", server_port)).unwrap();
+        assert_eq!(*resp.get_status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn test_too_many_requests() {
+        static REQUESTS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+        let server_port = server::test::spawn_server("127.0.0.1:0", |req| async move {
+            let path = req.get_path();
+            let mut response = req.response?;
+            let first = REQUESTS.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0;
+            if path == "overloaded" {
+                throttle::reject(&mut response, Duration::from_secs(120));
+            } else if first {
+                throttle::reject(&mut response, Duration::from_secs(1));
+            } else {
+                response.message.payload = b"done".to_vec();
+            }
+            Some(response)
+        })
+        .recv()
+        .unwrap();
+
+        let start = Instant::now();
+        let resp = CoAPClient::get(&format!("coap://127.0.0.1:{}/busy", server_port)).unwrap();
+        assert_eq!(*resp.get_status(), Status::Content);
+        assert_eq!(resp.message.payload, b"done".to_vec());
+        assert!(start.elapsed() >= Duration::from_secs(1));
+
+        let url = format!("coap://127.0.0.1:{}/overloaded", server_port);
+        let resp = CoAPClient::get(&url).unwrap();
+        assert_eq!(*resp.get_status(), Status::TooManyRequests);
+        assert_eq!(throttle::retry_after(&resp.message), Some(Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn test_pubsub() {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    server.set_broker_path(Some(pubsub::DEFAULT_PATH));
+                    server.add_link(Link::new("/ps").with_attribute("rt", pubsub::RESOURCE_TYPE));
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|req: CoapRequest<SocketAddr>| async { req.response })
+                        .await
+                        .unwrap();
+                })
+        });
+        let addr = rx.recv().unwrap();
+
+        let brokers = CoAPClient::discover_brokers(&format!("coap://{}", addr)).unwrap();
+        assert_eq!(brokers, [Link::new("/ps").with_attribute("rt", pubsub::RESOURCE_TYPE)]);
+
+        let mut publisher = CoAPClient::new(addr).unwrap();
+        let link = Link::new("temp").with_content_format(0);
+        let topic = publisher.create_topic("/ps", &link, Some(Duration::from_secs(600))).unwrap();
+        assert_eq!(topic, "ps/temp");
+        let error = publisher.create_topic("/ps", &link, None).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        publisher.publish(&topic, b"21.5".to_vec(), Some(0)).unwrap();
+        let error = publisher.publish(&topic, b"{}".to_vec(), Some(50)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        let mut subscriber = CoAPClient::new(addr).unwrap();
+        let publications = subscriber.subscribe(&topic).unwrap();
+        let mut publications = futures::executor::block_on_stream(publications);
+        assert_eq!(publications.next().unwrap().payload, b"21.5");
+        publisher.publish(&topic, b"22.0".to_vec(), Some(0)).unwrap();
+        assert_eq!(publications.next().unwrap().payload, b"22.0");
+
+        publisher.remove_topic(&topic).unwrap();
+        let removed = publications.next().unwrap();
+        assert_eq!(removed.header.code, MessageClass::Response(Status::NotFound));
+        assert!(publications.next().is_none());
+        let error = publisher.publish(&topic, b"22.5".to_vec(), None).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+
+    #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Reading {
+        sensor: String,
+        value: u32,
+    }
+
+    async fn typed_handler(req: CoapRequest<SocketAddr>) -> Option<CoapResponse> {
+        let mut response = req.response.clone()?;
+        match req.get_method() {
+            // echo the typed body back in the other format
+            &Method::Post => {
+                let reading: Reading = payload::read(&req.message).ok()?;
+                let format = match payload::content_format(&req.message) {
+                    Some(50) => Format::Cbor,
+                    _ => Format::Json,
+                };
+                payload::write(&mut response.message, &reading, format).unwrap();
+            }
+            _ => {
+                let reading = Reading {
+                    sensor: req.get_path(),
+                    value: 42,
+                };
+                payload::write(&mut response.message, &reading, Format::Cbor).unwrap();
+            }
+        }
+        Some(response)
+    }
+
+    #[test]
+    fn test_typed_payload() {
+        let server_port = server::test::spawn_server("127.0.0.1:0", typed_handler)
+            .recv()
+            .unwrap();
+        let url = format!("coap://127.0.0.1:{}/temp", server_port);
+
+        let reading: Reading = CoAPClient::get_as(&url).unwrap();
+        assert_eq!(
+            reading,
+            Reading {
+                sensor: "temp".to_string(),
+                value: 42
+            }
+        );
+
+        let resp = CoAPClient::post_json(&url, &reading).unwrap();
+        assert_eq!(payload::content_format(&resp.message), Some(60));
+        assert_eq!(payload::read::<Reading>(&resp.message).unwrap(), reading);
+
+        let resp = CoAPClient::post_cbor(&url, &reading).unwrap();
+        assert_eq!(payload::content_format(&resp.message), Some(50));
+        assert_eq!(payload::read::<Reading>(&resp.message).unwrap(), reading);
+
+        let error = CoAPClient::get_as::<String>(&url).unwrap_err();
+        assert!(matches!(error, PayloadError::Cbor(_)));
+    }
+
+    struct AuthInterceptor {
+        responses: std::sync::Arc<std::sync::Mutex<Vec<Status>>>,
+    }
+
+    impl Interceptor for AuthInterceptor {
+        fn on_request(&mut self, request: &mut CoapRequest<SocketAddr>) {
+            request.message.add_option(CoapOption::UriQuery, b"token=secret".to_vec());
+        }
+
+        fn on_response(&mut self, _request: &CoapRequest<SocketAddr>, response: &mut CoapResponse) {
+            self.responses.lock().unwrap().push(*response.get_status());
+            response.message.payload.extend_from_slice(b"!");
+        }
+    }
+
+    async fn auth_handler(req: CoapRequest<SocketAddr>) -> Option<CoapResponse> {
+        let authorized = req
+            .message
+            .get_option(CoapOption::UriQuery)
+            .is_some_and(|queries| queries.iter().any(|q| q == b"token=secret"));
+        let mut response = req.response?;
+        if authorized {
+            response.message.payload = b"welcome".to_vec();
+        } else {
+            response.set_status(Status::Forbidden);
+        }
+        Some(response)
+    }
+
+    #[test]
+    fn test_interceptors() {
+        let server_port = server::test::spawn_server("127.0.0.1:0", auth_handler)
+            .recv()
+            .unwrap();
+        let responses = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+
+        let mut client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let resp = client
+            .request_path("/door", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(*resp.get_status(), Status::Forbidden);
+
+        client.add_interceptor(AuthInterceptor {
+            responses: responses.clone(),
+        });
+        let resp = client
+            .request_path("/door", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(resp.message.payload, b"welcome!".to_vec());
+        assert_eq!(*responses.lock().unwrap(), vec![Status::Content]);
+    }
+
+    #[test]
+    fn test_exchange_stats() {
+        let server_port = server::test::spawn_server("127.0.0.1:0", auth_handler)
+            .recv()
+            .unwrap();
+
+        let mut client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        assert!(client.last_exchange_stats().is_none());
+        client
+            .request_path("/door", Method::Get, None, None, None)
+            .unwrap();
+        let stats = client.last_exchange_stats().unwrap();
+        assert!(stats.piggybacked);
+        assert_eq!(stats.retransmissions, 0);
+        assert_eq!(stats.blocks, 1);
+    }
+
+    #[test]
+    fn test_retransmission_and_separate_response() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 1500];
+            // drop the first transmission
+            server.recv_from(&mut buf).unwrap();
+            let (n, src) = server.recv_from(&mut buf).unwrap();
+            let request = Packet::from_bytes(&buf[..n]).unwrap();
+
+            let mut ack = Packet::new();
+            ack.header.set_type(MessageType::Acknowledgement);
+            ack.header.code = MessageClass::Empty;
+            ack.header.message_id = request.header.message_id;
+            server.send_to(&ack.to_bytes().unwrap(), src).unwrap();
+
+            let mut response = Packet::new();
+            response.header.set_type(MessageType::Confirmable);
+            response.header.code = MessageClass::Response(Status::Content);
+            response.header.message_id = 1000;
+            response.set_token(request.get_token().to_vec());
+            response.payload = b"separate".to_vec();
+            server.send_to(&response.to_bytes().unwrap(), src).unwrap();
+
+            let (n, _) = server.recv_from(&mut buf).unwrap();
+            let ack = Packet::from_bytes(&buf[..n]).unwrap();
+            assert_eq!(ack.header.get_type(), MessageType::Acknowledgement);
+            assert_eq!(ack.header.message_id, 1000);
+        });
+
+        let mut client = CoAPClient::new(server_addr).unwrap();
+        client.set_max_retransmit(2);
+        let resp = client
+            .request_path_with_timeout(
+                "/slow",
+                Method::Get,
+                None,
+                None,
+                None,
+                Duration::from_millis(200),
+            )
+            .unwrap();
+        assert_eq!(resp.message.payload, b"separate".to_vec());
+        let stats = client.last_exchange_stats().unwrap();
+        assert!(!stats.piggybacked);
+        assert_eq!(stats.retransmissions, 1);
+    }
+
+    #[test]
+    fn test_message_type() {
+        let seen = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen_by_server = seen.clone();
+        let server_port = server::test::spawn_server("127.0.0.1:0", move |req: CoapRequest<SocketAddr>| {
+            seen_by_server
+                .lock()
+                .unwrap()
+                .push((req.get_path(), req.message.header.get_type()));
+            async { req.response }
+        })
+        .recv()
+        .unwrap();
+
+        let mut client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        client
+            .request_path("/get", Method::Get, None, None, None)
+            .unwrap();
+        client.set_default_message_type(Method::Get, MessageType::NonConfirmable);
+        client
+            .request_path("/non", Method::Get, None, None, None)
+            .unwrap();
+        client
+            .request_path("/put", Method::Put, None, None, None)
+            .unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(
+            *seen,
+            vec![
+                ("get".to_string(), MessageType::Confirmable),
+                ("non".to_string(), MessageType::NonConfirmable),
+                ("put".to_string(), MessageType::Confirmable),
+            ]
+        );
+    }
+
     #[test]
     fn test_set_broadcast() {
         let client = CoAPClient::new(("127.0.0.1", 5683)).unwrap();
@@ -683,4 +2140,189 @@ mod test {
         let client = CoAPClient::new(("::1", 5683)).unwrap();
         assert!(client.send_all_coap(&request, 0x4).is_ok());
     }
+
+    #[test]
+    fn test_ping() {
+        let server_port = server::test::spawn_server("127.0.0.1:0", request_handler)
+            .recv()
+            .unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        client.ping().unwrap();
+
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = CoAPClient::new(silent.local_addr().unwrap()).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        assert!(client.ping().is_err());
+    }
+
+    #[test]
+    fn test_observe_keepalive() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let mut client = CoAPClient::new(server_addr).unwrap();
+        client.set_keepalive(Some(Duration::from_millis(200)), KeepaliveMode::Ping);
+        let (failure_tx, failure_rx) = mpsc::channel();
+        client.set_keepalive_failure_handler(move |e: &Error| {
+            let _ = failure_tx.send(e.kind());
+        });
+
+        let respond = |request: &Packet, code: MessageClass, message_type: MessageType, to| {
+            let mut response = Packet::new();
+            response.header.code = code;
+            response.header.set_type(message_type);
+            response.header.message_id = request.header.message_id;
+            response.set_token(request.get_token().to_vec());
+            server.send_to(&response.to_bytes().unwrap(), to).unwrap();
+        };
+        let mut buf = [0; 1500];
+        let observer = thread::scope(|scope| {
+            let observer = scope.spawn(|| {
+                client
+                    .observe_with_timeout("/sensor", |_| {}, Duration::from_millis(100))
+                    .map(|_| client)
+            });
+            let (n, client_addr) = server.recv_from(&mut buf).unwrap();
+            let register = Packet::from_bytes(&buf[..n]).unwrap();
+            respond(
+                &register,
+                MessageClass::Response(Status::Content),
+                MessageType::Acknowledgement,
+                client_addr,
+            );
+            observer.join().unwrap().unwrap()
+        });
+
+        // the first ping is answered with a reset, the second is ignored
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (n, client_addr) = server.recv_from(&mut buf).unwrap();
+        let ping = Packet::from_bytes(&buf[..n]).unwrap();
+        assert_eq!(ping.header.code, MessageClass::Empty);
+        assert_eq!(ping.header.get_type(), MessageType::Confirmable);
+        respond(&ping, MessageClass::Empty, MessageType::Reset, client_addr);
+        assert!(failure_rx.try_recv().is_err());
+
+        let (n, _) = server.recv_from(&mut buf).unwrap();
+        assert_eq!(
+            Packet::from_bytes(&buf[..n]).unwrap().header.code,
+            MessageClass::Empty
+        );
+        assert_eq!(
+            failure_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            ErrorKind::TimedOut
+        );
+
+        thread::scope(|scope| {
+            scope.spawn(move || drop(observer));
+            loop {
+                let (n, client_addr) = server.recv_from(&mut buf).unwrap();
+                let packet = Packet::from_bytes(&buf[..n]).unwrap();
+                if packet.header.code != MessageClass::Empty {
+                    respond(
+                        &packet,
+                        MessageClass::Response(Status::Content),
+                        MessageType::Acknowledgement,
+                        client_addr,
+                    );
+                    break;
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn test_block1_path_mtu() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let mut client = CoAPClient::new(server_addr).unwrap();
+        client.set_path_mtu(server_addr.ip(), Some(200));
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+
+        let mut buf = [0; 1500];
+        let response = thread::scope(|scope| {
+            let request = scope.spawn(|| {
+                client.request_path("/upload", Method::Put, Some(payload.clone()), None, None)
+            });
+            let mut received = Vec::new();
+            loop {
+                let (n, client_addr) = server.recv_from(&mut buf).unwrap();
+                assert!(n <= 172);
+                let packet = Packet::from_bytes(&buf[..n]).unwrap();
+                let block1 = packet
+                    .get_first_option_as::<BlockValue>(CoapOption::Block1)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(usize::from(block1.num) * block1.size(), received.len());
+                received.extend_from_slice(&packet.payload);
+
+                let mut response = Packet::new();
+                response.header.set_type(MessageType::Acknowledgement);
+                response.header.message_id = packet.header.message_id;
+                response.set_token(packet.get_token().to_vec());
+                if block1.more {
+                    // ask for smaller blocks from now on
+                    let acked = BlockValue::new(usize::from(block1.num), true, 64).unwrap();
+                    response.header.code = MessageClass::Response(Status::Continue);
+                    response.add_option_as(CoapOption::Block1, acked);
+                } else {
+                    response.header.code = MessageClass::Response(Status::Changed);
+                }
+                server.send_to(&response.to_bytes().unwrap(), client_addr).unwrap();
+                if !block1.more {
+                    break;
+                }
+            }
+            assert_eq!(received, payload);
+            request.join().unwrap().unwrap()
+        });
+        assert_eq!(*response.get_status(), Status::Changed);
+    }
+
+    #[test]
+    fn test_block2_message_ids() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = CoAPClient::new(server.local_addr().unwrap()).unwrap();
+        let body: Vec<u8> = (0..40).collect();
+
+        let mut buf = [0; 1500];
+        let response = thread::scope(|scope| {
+            let request =
+                scope.spawn(|| client.request_path("/log", Method::Get, None, None, None));
+            let mut message_ids = Vec::new();
+            loop {
+                let (n, client_addr) = server.recv_from(&mut buf).unwrap();
+                let packet = Packet::from_bytes(&buf[..n]).unwrap();
+                message_ids.push(packet.header.message_id);
+                let num = packet
+                    .get_first_option_as::<BlockValue>(CoapOption::Block2)
+                    .and_then(|block| block.ok())
+                    .map_or(0, |block| usize::from(block.num));
+                let end = body.len().min(num * 16 + 16);
+                let block = BlockValue::new(num, end < body.len(), 16).unwrap();
+
+                let mut response = Packet::new();
+                response.header.set_type(MessageType::Acknowledgement);
+                response.header.code = MessageClass::Response(Status::Content);
+                response.header.message_id = packet.header.message_id;
+                response.set_token(packet.get_token().to_vec());
+                response.add_option_as(CoapOption::Block2, block);
+                response.payload = body[num * 16..end].to_vec();
+                server.send_to(&response.to_bytes().unwrap(), client_addr).unwrap();
+                if end == body.len() {
+                    break;
+                }
+            }
+            // each block is a new exchange, which a server must not take
+            // for a duplicate
+            assert_eq!(message_ids.len(), 3);
+            message_ids.sort();
+            message_ids.dedup();
+            assert_eq!(message_ids.len(), 3);
+            request.join().unwrap().unwrap()
+        });
+        assert_eq!(response.message.payload, body);
+    }
 }
@@ -0,0 +1,345 @@
+//! Content-Formats of the IANA "CoAP Content-Formats" registry.
+//!
+//! [`ContentFormat`] names the registered formats and keeps any other number
+//! as [`ContentFormat::Other`], so that conversions from option values never
+//! fail. Unlike the enum of coap-lite, it covers SenML, COSE, OCF and
+//! LwM2M formats among others.
+use coap_lite::{option_value::OptionValueU16, CoapOption, Packet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+use super::payload;
+
+/// A Content-Format, as carried in the Content-Format and Accept options.
+///
+/// Formats are compared and hashed by their number, so e.g.
+/// `ContentFormat::Other(50)` equals `ContentFormat::Json`.
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub enum ContentFormat {
+    TextPlain,
+    CoseEncrypt0,
+    CoseMac0,
+    CoseSign1,
+    AceCbor,
+    ImageGif,
+    ImageJpeg,
+    ImagePng,
+    LinkFormat,
+    Xml,
+    OctetStream,
+    Exi,
+    Json,
+    JsonPatchJson,
+    MergePatchJson,
+    Cbor,
+    Cwt,
+    MultipartCore,
+    CborSeq,
+    CoseEncrypt,
+    CoseMac,
+    CoseSign,
+    CoseKey,
+    CoseKeySet,
+    SenmlJson,
+    SensmlJson,
+    SenmlCbor,
+    SensmlCbor,
+    SenmlExi,
+    SensmlExi,
+    CoapGroupJson,
+    ConciseProblemDetailsCbor,
+    MissingBlocksCborSeq,
+    SenmlXml,
+    SensmlXml,
+    SenmlEtchJson,
+    SenmlEtchCbor,
+    TdJson,
+    OcfCbor,
+    Oscore,
+    Lwm2mTlv,
+    Lwm2mJson,
+    Lwm2mCbor,
+    /// A format without a variant of its own.
+    Other(u16),
+}
+
+/// The registered formats with their numbers and media types.
+const REGISTRY: &[(ContentFormat, u16, &str)] = &[
+    (ContentFormat::TextPlain, 0, "text/plain; charset=utf-8"),
+    (
+        ContentFormat::CoseEncrypt0,
+        16,
+        "application/cose; cose-type=\"cose-encrypt0\"",
+    ),
+    (
+        ContentFormat::CoseMac0,
+        17,
+        "application/cose; cose-type=\"cose-mac0\"",
+    ),
+    (
+        ContentFormat::CoseSign1,
+        18,
+        "application/cose; cose-type=\"cose-sign1\"",
+    ),
+    (ContentFormat::AceCbor, 19, "application/ace+cbor"),
+    (ContentFormat::ImageGif, 21, "image/gif"),
+    (ContentFormat::ImageJpeg, 22, "image/jpeg"),
+    (ContentFormat::ImagePng, 23, "image/png"),
+    (ContentFormat::LinkFormat, 40, "application/link-format"),
+    (ContentFormat::Xml, 41, "application/xml"),
+    (ContentFormat::OctetStream, 42, "application/octet-stream"),
+    (ContentFormat::Exi, 47, "application/exi"),
+    (ContentFormat::Json, 50, "application/json"),
+    (
+        ContentFormat::JsonPatchJson,
+        51,
+        "application/json-patch+json",
+    ),
+    (
+        ContentFormat::MergePatchJson,
+        52,
+        "application/merge-patch+json",
+    ),
+    (ContentFormat::Cbor, 60, "application/cbor"),
+    (ContentFormat::Cwt, 61, "application/cwt"),
+    (
+        ContentFormat::MultipartCore,
+        62,
+        "application/multipart-core",
+    ),
+    (ContentFormat::CborSeq, 63, "application/cbor-seq"),
+    (
+        ContentFormat::CoseEncrypt,
+        96,
+        "application/cose; cose-type=\"cose-encrypt\"",
+    ),
+    (
+        ContentFormat::CoseMac,
+        97,
+        "application/cose; cose-type=\"cose-mac\"",
+    ),
+    (
+        ContentFormat::CoseSign,
+        98,
+        "application/cose; cose-type=\"cose-sign\"",
+    ),
+    (ContentFormat::CoseKey, 101, "application/cose-key"),
+    (ContentFormat::CoseKeySet, 102, "application/cose-key-set"),
+    (ContentFormat::SenmlJson, 110, "application/senml+json"),
+    (ContentFormat::SensmlJson, 111, "application/sensml+json"),
+    (ContentFormat::SenmlCbor, 112, "application/senml+cbor"),
+    (ContentFormat::SensmlCbor, 113, "application/sensml+cbor"),
+    (ContentFormat::SenmlExi, 114, "application/senml-exi"),
+    (ContentFormat::SensmlExi, 115, "application/sensml-exi"),
+    (
+        ContentFormat::CoapGroupJson,
+        256,
+        "application/coap-group+json",
+    ),
+    (
+        ContentFormat::ConciseProblemDetailsCbor,
+        257,
+        "application/concise-problem-details+cbor",
+    ),
+    (
+        ContentFormat::MissingBlocksCborSeq,
+        272,
+        "application/missing-blocks+cbor-seq",
+    ),
+    (ContentFormat::SenmlXml, 310, "application/senml+xml"),
+    (ContentFormat::SensmlXml, 311, "application/sensml+xml"),
+    (
+        ContentFormat::SenmlEtchJson,
+        320,
+        "application/senml-etch+json",
+    ),
+    (
+        ContentFormat::SenmlEtchCbor,
+        322,
+        "application/senml-etch+cbor",
+    ),
+    (ContentFormat::TdJson, 432, "application/td+json"),
+    (ContentFormat::OcfCbor, 10000, "application/vnd.ocf+cbor"),
+    (ContentFormat::Oscore, 10001, "application/oscore"),
+    (
+        ContentFormat::Lwm2mTlv,
+        11542,
+        "application/vnd.oma.lwm2m+tlv",
+    ),
+    (
+        ContentFormat::Lwm2mJson,
+        11543,
+        "application/vnd.oma.lwm2m+json",
+    ),
+    (
+        ContentFormat::Lwm2mCbor,
+        11544,
+        "application/vnd.oma.lwm2m+cbor",
+    ),
+];
+
+impl ContentFormat {
+    /// Return the media type with its parameters, or `None` for formats
+    /// that are not registered.
+    pub fn media_type(self) -> Option<&'static str> {
+        let number = self.number();
+        REGISTRY
+            .iter()
+            .find(|(_, registered, _)| *registered == number)
+            .map(|(_, _, media_type)| *media_type)
+    }
+
+    /// Look up a format by its media type, ignoring the case and the spaces
+    /// between parameters.
+    pub fn from_media_type(media_type: &str) -> Option<ContentFormat> {
+        let normalize = |media_type: &str| media_type.replace(' ', "").to_ascii_lowercase();
+        let media_type = normalize(media_type);
+        REGISTRY
+            .iter()
+            .find(|(_, _, registered)| normalize(registered) == media_type)
+            .map(|(format, _, _)| *format)
+    }
+
+    fn number(self) -> u16 {
+        match self {
+            ContentFormat::Other(number) => number,
+            // a named variant, found by its variant rather than by `==`
+            format => REGISTRY
+                .iter()
+                .find(|(registered, _, _)| {
+                    mem::discriminant(registered) == mem::discriminant(&format)
+                })
+                .map_or(0, |(_, number, _)| *number),
+        }
+    }
+}
+
+impl From<u16> for ContentFormat {
+    fn from(number: u16) -> ContentFormat {
+        REGISTRY
+            .iter()
+            .find(|(_, registered, _)| *registered == number)
+            .map_or(ContentFormat::Other(number), |(format, _, _)| *format)
+    }
+}
+
+impl From<ContentFormat> for u16 {
+    fn from(format: ContentFormat) -> u16 {
+        format.number()
+    }
+}
+
+impl PartialEq for ContentFormat {
+    fn eq(&self, other: &ContentFormat) -> bool {
+        self.number() == other.number()
+    }
+}
+
+impl Eq for ContentFormat {}
+
+impl Hash for ContentFormat {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.number().hash(state);
+    }
+}
+
+impl fmt::Display for ContentFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.media_type() {
+            Some(media_type) => f.write_str(media_type),
+            None => write!(f, "Content-Format {}", u16::from(*self)),
+        }
+    }
+}
+
+/// Return the Content-Format of a message, if any.
+pub fn get(message: &Packet) -> Option<ContentFormat> {
+    payload::content_format(message).map(ContentFormat::from)
+}
+
+/// Set the Content-Format of a message.
+pub fn set(message: &mut Packet, format: ContentFormat) {
+    payload::set_content_format(message, format.into());
+}
+
+/// Return the formats a request accepts, in the order of its Accept
+/// options.
+pub fn accept(message: &Packet) -> Vec<ContentFormat> {
+    message
+        .get_options_as::<OptionValueU16>(CoapOption::Accept)
+        .map(|values| {
+            values
+                .into_iter()
+                .filter_map(|value| value.ok())
+                .map(|value| ContentFormat::from(value.0))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Set the Accept option of a request to `format`.
+pub fn set_accept(message: &mut Packet, format: ContentFormat) {
+    message.clear_option(CoapOption::Accept);
+    message.add_option_as(CoapOption::Accept, OptionValueU16(format.into()));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_numbers() {
+        for (format, number, _) in REGISTRY {
+            assert_eq!(ContentFormat::from(*number), *format);
+            assert_eq!(u16::from(*format), *number);
+        }
+        assert_eq!(ContentFormat::from(65000), ContentFormat::Other(65000));
+        assert_eq!(u16::from(ContentFormat::Other(65000)), 65000);
+    }
+
+    #[test]
+    fn test_other_with_registered_number() {
+        let json = ContentFormat::Other(50);
+        assert_eq!(json, ContentFormat::Json);
+        assert_ne!(json, ContentFormat::Cbor);
+        assert_eq!(json.media_type(), Some("application/json"));
+        assert_eq!(json.to_string(), "application/json");
+
+        let formats: std::collections::HashSet<_> = [json, ContentFormat::Json].into();
+        assert_eq!(formats.len(), 1);
+        assert!(formats.contains(&ContentFormat::from(50)));
+    }
+
+    #[test]
+    fn test_media_types() {
+        assert_eq!(
+            ContentFormat::from_media_type("application/senml+cbor"),
+            Some(ContentFormat::SenmlCbor)
+        );
+        assert_eq!(
+            ContentFormat::from_media_type("text/plain;charset=UTF-8"),
+            Some(ContentFormat::TextPlain)
+        );
+        assert_eq!(ContentFormat::from_media_type("text/html"), None);
+        assert_eq!(ContentFormat::Cbor.to_string(), "application/cbor");
+        assert_eq!(
+            ContentFormat::Other(65000).to_string(),
+            "Content-Format 65000"
+        );
+    }
+
+    #[test]
+    fn test_options() {
+        let mut message = Packet::new();
+        assert_eq!(get(&message), None);
+        set(&mut message, ContentFormat::SenmlJson);
+        assert_eq!(get(&message), Some(ContentFormat::SenmlJson));
+        assert_eq!(payload::content_format(&message), Some(110));
+
+        assert!(accept(&message).is_empty());
+        set_accept(&mut message, ContentFormat::OcfCbor);
+        assert_eq!(accept(&message), vec![ContentFormat::OcfCbor]);
+    }
+}
@@ -0,0 +1,535 @@
+//! Echo and Request-Tag options
+//! ([RFC 9175](https://tools.ietf.org/html/rfc9175)).
+//!
+//! An [`EchoPolicy`] makes the server challenge requests to some resources:
+//! unless a request carries a suitable Echo value, it is answered with 4.01
+//! Unauthorized and a new value, which the client repeats in the request.
+//! A value issued moments ago shows that the request is fresh, i.e. it was
+//! not delayed or replayed by an attacker, which matters e.g. for
+//! actuators. Any value issued to an address shows that the client really
+//! receives at that address; such verified addresses are exempt from the
+//! amplification limit of the server.
+//!
+//! Echo values are random and remembered by the server together with the
+//! address they were sent to.
+//!
+//! A handler can also demand fresh requests by itself, without a policy
+//! naming its resources, when wrapped with [`require_fresh`].
+//!
+//! Independently of any policy, the server reassembles Block1 bodies by
+//! client, resource and Request-Tag, so that a client may upload several
+//! bodies to the same resource at once under different Request-Tag values.
+//! A block that continues no transfer under its Request-Tag is answered
+//! with 4.08 Request Entity Incomplete instead of being appended to the
+//! body of another transfer. The bodies are held within the memory budget
+//! set with
+//! [`Server::set_transfer_memory_budget`](crate::Server::set_transfer_memory_budget).
+use coap_lite::{
+    block_handler::BlockValue, CoapOption, CoapRequest, CoapResponse, RequestType as Method,
+    ResponseType as Status,
+};
+use lru_time_cache::LruCache;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use super::acl;
+use super::budget::BudgetedCache;
+use super::payload::{self, ResponseFuture};
+
+/// Option number of Echo.
+pub const ECHO: u16 = 252;
+
+/// Option number of Request-Tag.
+pub const REQUEST_TAG: u16 = 292;
+
+/// Default time for which an Echo value proves a request fresh.
+pub const DEFAULT_FRESHNESS: Duration = Duration::from_secs(10);
+
+/// Default time for which an address stays verified.
+pub const DEFAULT_ADDRESS_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// How many Echo values and verified addresses are remembered.
+const CAPACITY: usize = 4096;
+
+/// What requests to a resource must show with an Echo value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Requirement {
+    /// The client has returned a value sent to its address before.
+    VerifiedAddress,
+    /// The request carries a value issued at most the freshness window
+    /// ago.
+    Fresh,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    resource: String,
+    methods: Vec<Method>,
+    requirement: Requirement,
+}
+
+/// Resources that require Echo values, with the values issued so far.
+pub struct EchoPolicy {
+    rules: Vec<Rule>,
+    freshness: Duration,
+    address_lifetime: Duration,
+    /// Issued values by the address they were sent to.
+    issued: LruCache<(SocketAddr, Vec<u8>), Instant>,
+    /// Addresses by the time they were last verified.
+    verified: LruCache<SocketAddr, Instant>,
+    random: RandomState,
+    counter: u64,
+}
+
+impl Default for EchoPolicy {
+    fn default() -> EchoPolicy {
+        EchoPolicy::new()
+    }
+}
+
+impl EchoPolicy {
+    /// Create a policy that requires nothing.
+    pub fn new() -> EchoPolicy {
+        EchoPolicy {
+            rules: Vec::new(),
+            freshness: DEFAULT_FRESHNESS,
+            address_lifetime: DEFAULT_ADDRESS_LIFETIME,
+            issued: LruCache::with_capacity(CAPACITY),
+            verified: LruCache::with_capacity(CAPACITY),
+            random: RandomState::new(),
+            counter: 0,
+        }
+    }
+
+    /// Require `requirement` of requests with `methods` to the resources
+    /// matching the `resource` pattern, in which `*` stands for any
+    /// sequence of characters. If several rules match, the strictest
+    /// requirement applies.
+    pub fn require(&mut self, resource: &str, methods: &[Method], requirement: Requirement) {
+        self.rules.push(Rule {
+            resource: resource.trim_start_matches('/').to_string(),
+            methods: methods.to_vec(),
+            requirement,
+        });
+    }
+
+    /// Set the time for which an Echo value proves a request fresh.
+    pub fn set_freshness(&mut self, freshness: Duration) {
+        self.freshness = freshness;
+    }
+
+    /// Set the time for which an address stays verified after the client
+    /// last returned an Echo value from it.
+    pub fn set_address_lifetime(&mut self, lifetime: Duration) {
+        self.address_lifetime = lifetime;
+    }
+
+    /// Return whether the client at `addr` has shown that it receives at
+    /// that address.
+    pub fn is_verified(&self, addr: &SocketAddr) -> bool {
+        self.verified
+            .peek(addr)
+            .is_some_and(|verified| verified.elapsed() <= self.address_lifetime)
+    }
+
+    /// Check the request against the policy, and return the Echo value to
+    /// challenge the client with if it falls short.
+    pub(crate) fn check(
+        &mut self,
+        addr: SocketAddr,
+        request: &CoapRequest<SocketAddr>,
+        method: Method,
+    ) -> Result<(), Vec<u8>> {
+        let path = request.get_path();
+        let requirement = self
+            .rules
+            .iter()
+            .filter(|rule| rule.methods.contains(&method))
+            .filter(|rule| acl::matches_pattern(&rule.resource, &path))
+            .map(|rule| rule.requirement)
+            .max();
+        self.check_requirement(addr, request, requirement)
+    }
+
+    /// Check the request against `requirement`, and return the Echo value
+    /// to challenge the client with if it falls short.
+    fn check_requirement(
+        &mut self,
+        addr: SocketAddr,
+        request: &CoapRequest<SocketAddr>,
+        requirement: Option<Requirement>,
+    ) -> Result<(), Vec<u8>> {
+        let echo = request
+            .message
+            .get_option(CoapOption::Unknown(ECHO))
+            .and_then(|values| values.front());
+        let issued = echo.and_then(|echo| self.issued.peek(&(addr, echo.clone())).copied());
+        if issued.is_some() {
+            self.verified.insert(addr, Instant::now());
+        }
+
+        let satisfied = match requirement {
+            None => true,
+            Some(Requirement::VerifiedAddress) => self.is_verified(&addr),
+            Some(Requirement::Fresh) => {
+                issued.is_some_and(|issued| issued.elapsed() <= self.freshness)
+            }
+        };
+        if satisfied {
+            return Ok(());
+        }
+        Err(self.challenge(addr))
+    }
+
+    /// Issue a new Echo value to the client at `addr`.
+    pub(crate) fn challenge(&mut self, addr: SocketAddr) -> Vec<u8> {
+        self.counter += 1;
+        let value = self
+            .random
+            .hash_one((self.counter, addr))
+            .to_be_bytes()
+            .to_vec();
+        self.issued.insert((addr, value.clone()), Instant::now());
+        value
+    }
+}
+
+/// Turn `handler` into one that only gets requests shown fresh by an Echo
+/// value issued at most `freshness` ago, e.g. for a resource that unlocks a
+/// door. Other requests are answered with 4.01 Unauthorized and a new Echo
+/// value, which the client repeats in the request;
+/// [`CoAPClient`](crate::CoAPClient) does so by itself.
+pub fn require_fresh<F, R>(
+    freshness: Duration,
+    mut handler: F,
+) -> impl FnMut(CoapRequest<SocketAddr>) -> ResponseFuture
+where
+    F: FnMut(CoapRequest<SocketAddr>) -> R,
+    R: Future<Output = Option<CoapResponse>> + Send + 'static,
+{
+    let mut policy = EchoPolicy::new();
+    policy.set_freshness(freshness);
+    move |mut request: CoapRequest<SocketAddr>| {
+        // without its address, the client cannot be challenged
+        let result = match request.source {
+            Some(addr) => policy.check_requirement(addr, &request, Some(Requirement::Fresh)),
+            None => Err(Vec::new()),
+        };
+        match (result, request.response.as_mut()) {
+            (Ok(()), _) => Box::pin(handler(request)),
+            (Err(value), Some(response)) => {
+                response.set_status(Status::Unauthorized);
+                if !value.is_empty() {
+                    response
+                        .message
+                        .add_option(CoapOption::Unknown(ECHO), value);
+                }
+                Box::pin(async move { request.response })
+            }
+            (Err(_), None) => Box::pin(async { None }),
+        }
+    }
+}
+
+/// A Block1 transfer: the client, the resource and the Request-Tag.
+type Transfer = (SocketAddr, String, Option<Vec<u8>>);
+
+/// The bodies of the unfinished Block1 transfers.
+pub(crate) struct RequestTags(BudgetedCache<Transfer, Vec<u8>>);
+
+impl RequestTags {
+    pub(crate) fn new(lifetime: Duration) -> RequestTags {
+        RequestTags(BudgetedCache::new(lifetime, CAPACITY))
+    }
+
+    /// Hold the bodies within `budget` bytes, evicting the least recently
+    /// used transfers.
+    pub(crate) fn set_budget(&mut self, budget: Option<usize>) {
+        self.0.set_budget(budget);
+    }
+
+    /// Collect a block of a Block1 body, and return whether the request may
+    /// be processed: with the complete body in place of the block, or as is
+    /// if it carries no Block1 option. Otherwise the request is left with
+    /// the response to send, 2.31 Continue, 4.08 Request Entity Incomplete
+    /// or 4.13 Request Entity Too Large if the body exceeds the budget.
+    pub(crate) fn receive(
+        &mut self,
+        addr: SocketAddr,
+        request: &mut CoapRequest<SocketAddr>,
+    ) -> bool {
+        let block = match request
+            .message
+            .get_first_option_as::<BlockValue>(CoapOption::Block1)
+            .and_then(|block| block.ok())
+        {
+            Some(block) => block,
+            None => return true,
+        };
+        let tag = request
+            .message
+            .get_option(CoapOption::Unknown(REQUEST_TAG))
+            .and_then(|values| values.front().cloned());
+        let key = (addr, request.get_path(), tag);
+        let offset = usize::from(block.num) * block.size();
+        // a first block starts the transfer over, and a block sent again
+        // replaces the end of the body
+        let mut body = match self.0.remove(&key) {
+            _ if block.num == 0 => Vec::new(),
+            Some(body) if offset <= body.len() => body,
+            _ => {
+                if let Some(ref mut response) = request.response {
+                    response.set_status(Status::RequestEntityIncomplete);
+                }
+                return false;
+            }
+        };
+        body.truncate(offset);
+        body.append(&mut request.message.payload);
+
+        if !block.more {
+            request.message.payload = body;
+            request.message.clear_option(CoapOption::Block1);
+            return true;
+        }
+        let stored = self.0.insert(key, body).is_ok();
+        if let Some(ref mut response) = request.response {
+            if stored {
+                response.set_status(Status::Continue);
+                response.message.add_option_as(CoapOption::Block1, block);
+            } else if let Some(budget) = self.0.budget() {
+                // the body alone exceeds the budget
+                response.set_status(Status::RequestEntityTooLarge);
+                payload::set_size(&mut response.message, CoapOption::Size1, budget);
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use coap_lite::{CoapResponse, ResponseType as Status};
+    use std::time::Duration;
+
+    fn request(path: &str, echo: Option<&[u8]>) -> CoapRequest<SocketAddr> {
+        let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+        request.set_method(Method::Post);
+        request.set_path(path);
+        if let Some(echo) = echo {
+            request
+                .message
+                .add_option(CoapOption::Unknown(ECHO), echo.to_vec());
+        }
+        request
+    }
+
+    #[test]
+    fn test_policy() {
+        let client: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let spoofed: SocketAddr = "127.0.0.2:5683".parse().unwrap();
+        let mut policy = EchoPolicy::new();
+        policy.require("/actuators/*", &[Method::Post], Requirement::Fresh);
+        policy.require("/firmware", &[Method::Get], Requirement::VerifiedAddress);
+        policy.set_freshness(Duration::from_millis(200));
+
+        assert_eq!(
+            policy.check(client, &request("/status", None), Method::Post),
+            Ok(())
+        );
+        let echo = policy
+            .check(client, &request("/actuators/valve", None), Method::Post)
+            .unwrap_err();
+        assert!(!policy.is_verified(&client));
+        assert!(policy
+            .check(
+                spoofed,
+                &request("/actuators/valve", Some(&echo)),
+                Method::Post
+            )
+            .is_err());
+        assert_eq!(
+            policy.check(
+                client,
+                &request("/actuators/valve", Some(&echo)),
+                Method::Post
+            ),
+            Ok(())
+        );
+        assert!(policy.is_verified(&client));
+        assert!(!policy.is_verified(&spoofed));
+        assert_eq!(
+            policy.check(client, &request("/firmware", None), Method::Get),
+            Ok(())
+        );
+        assert!(policy
+            .check(spoofed, &request("/firmware", None), Method::Get)
+            .is_err());
+
+        std::thread::sleep(Duration::from_millis(300));
+        let fresh = policy
+            .check(
+                client,
+                &request("/actuators/valve", Some(&echo)),
+                Method::Post,
+            )
+            .unwrap_err();
+        assert_ne!(fresh, echo);
+    }
+
+    #[test]
+    fn test_request_tags() {
+        let client: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let block = |num: usize, more: bool, tag: &[u8]| {
+            let mut request = request("/upload", None);
+            request.message.add_option(
+                CoapOption::Block1,
+                BlockValue::new(num, more, 16).unwrap().into(),
+            );
+            request
+                .message
+                .add_option(CoapOption::Unknown(REQUEST_TAG), tag.to_vec());
+            request.message.payload = vec![tag[0]; 16];
+            request.response = Some(CoapResponse::new(&request.message).unwrap());
+            request
+        };
+        let status =
+            |request: &CoapRequest<SocketAddr>| *request.response.as_ref().unwrap().get_status();
+        let mut tags = RequestTags::new(Duration::from_secs(120));
+
+        // two uploads to the same resource at once
+        let mut a = block(0, true, b"a");
+        assert!(!tags.receive(client, &mut a));
+        assert_eq!(status(&a), Status::Continue);
+        assert!(!tags.receive(client, &mut block(0, true, b"b")));
+        assert!(!tags.receive(client, &mut block(1, true, b"a")));
+        assert!(!tags.receive(client, &mut block(1, true, b"b")));
+        let mut b = block(2, false, b"b");
+        assert!(tags.receive(client, &mut b));
+        assert_eq!(b.message.payload, [b'b'; 48]);
+        assert!(b
+            .message
+            .get_first_option_as::<BlockValue>(CoapOption::Block1)
+            .is_none());
+        let mut a = block(2, false, b"a");
+        assert!(tags.receive(client, &mut a));
+        assert_eq!(a.message.payload, [b'a'; 48]);
+
+        // a block of no transfer
+        let mut c = block(3, false, b"c");
+        assert!(!tags.receive(client, &mut c));
+        assert_eq!(status(&c), Status::RequestEntityIncomplete);
+
+        // the transfer used least recently makes room
+        tags.set_budget(Some(40));
+        assert!(!tags.receive(client, &mut block(0, true, b"a")));
+        assert!(!tags.receive(client, &mut block(0, true, b"b")));
+        assert!(!tags.receive(client, &mut block(1, true, b"a")));
+        let mut b = block(1, true, b"b");
+        assert!(!tags.receive(client, &mut b));
+        assert_eq!(status(&b), Status::RequestEntityIncomplete);
+        // a body beyond the budget
+        let mut a = block(2, true, b"a");
+        assert!(!tags.receive(client, &mut a));
+        assert_eq!(status(&a), Status::RequestEntityTooLarge);
+        assert_eq!(payload::size1(&a.response.unwrap().message), Some(40));
+    }
+
+    async fn handler(request: CoapRequest<SocketAddr>) -> Option<CoapResponse> {
+        let mut response = request.response?;
+        response.message.payload = b"done".to_vec();
+        Some(response)
+    }
+
+    #[test]
+    fn test_echo_challenge() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    let mut policy = EchoPolicy::new();
+                    policy.require("/lock", &[Method::Put], Requirement::Fresh);
+                    server.set_echo_policy(Some(policy));
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server.run(handler).await.unwrap();
+                });
+        });
+        let addr = rx.recv().unwrap();
+
+        // the client answers the challenge by itself
+        let url = format!("coap://{}/lock", addr);
+        let response = CoAPClient::put(&url, b"open".to_vec()).unwrap();
+        assert_eq!(response.message.payload, b"done".to_vec());
+
+        let client = CoAPClient::new(addr).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+        request.set_method(Method::Put);
+        request.set_path("/lock");
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::Unauthorized);
+        assert!(response
+            .message
+            .get_option(CoapOption::Unknown(ECHO))
+            .is_some());
+    }
+
+    #[test]
+    fn test_require_fresh() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    let handler = require_fresh(Duration::from_millis(500), handler);
+                    server.run(handler).await.unwrap();
+                });
+        });
+        let addr = rx.recv().unwrap();
+
+        let client = CoAPClient::new(addr).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+        request.set_method(Method::Post);
+        request.set_path("/unlock");
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::Unauthorized);
+        let echo = response
+            .message
+            .get_option(CoapOption::Unknown(ECHO))
+            .and_then(|values| values.front().cloned())
+            .unwrap();
+
+        request
+            .message
+            .add_option(CoapOption::Unknown(ECHO), echo.clone());
+        client.send(&request).unwrap();
+        assert_eq!(client.receive().unwrap().message.payload, b"done".to_vec());
+
+        // the value is no longer fresh
+        std::thread::sleep(Duration::from_millis(600));
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::Unauthorized);
+
+        // the client answers the challenge by itself
+        let url = format!("coap://{}/unlock", addr);
+        let response = CoAPClient::post(&url, b"now".to_vec()).unwrap();
+        assert_eq!(response.message.payload, b"done".to_vec());
+    }
+}
@@ -0,0 +1,170 @@
+//! Entity-tags ([RFC 7252](https://tools.ietf.org/html/rfc7252)
+//! section 5.10.6).
+//!
+//! A handler tags a 2.05 Content response with [`tag`]. When a GET or FETCH
+//! request lists the same ETag, the server answers 2.03 Valid instead,
+//! without the payload, so polling clients only fetch a representation
+//! when it changed.
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType as Method, ResponseType as Status};
+
+/// Return an 8 bytes entity-tag for `representation`, derived with FNV-1a so
+/// that it is stable across restarts of the server.
+pub fn generate(representation: &[u8]) -> Vec<u8> {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in representation {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash.to_be_bytes().to_vec()
+}
+
+/// Set the ETag of a response to one generated from its Content-Format and
+/// payload, and return it.
+pub fn tag(message: &mut Packet) -> Vec<u8> {
+    let format = message.get_option(CoapOption::ContentFormat);
+    let mut representation = match format.and_then(|values| values.front()) {
+        Some(format) => format.clone(),
+        None => vec![0xff],
+    };
+    representation.extend_from_slice(&message.payload);
+    let etag = generate(&representation);
+    set(message, etag.clone());
+    etag
+}
+
+/// Return the ETag of a response.
+pub fn get(message: &Packet) -> Option<&Vec<u8>> {
+    message.get_option(CoapOption::ETag)?.front()
+}
+
+/// Set the ETag of a response, replacing any other.
+pub fn set(message: &mut Packet, etag: Vec<u8>) {
+    message.clear_option(CoapOption::ETag);
+    message.add_option(CoapOption::ETag, etag);
+}
+
+/// Return whether `request` lists `etag` among its ETag options.
+pub fn matches(request: &Packet, etag: &[u8]) -> bool {
+    request
+        .get_option(CoapOption::ETag)
+        .is_some_and(|etags| etags.iter().any(|candidate| candidate == etag))
+}
+
+/// Turn a 2.05 Content response to a GET or FETCH request into 2.03 Valid
+/// if the request lists its ETag. Return whether it did.
+pub(crate) fn validate(request: &Packet, response: &mut Packet) -> bool {
+    match request.header.code {
+        MessageClass::Request(Method::Get) | MessageClass::Request(Method::Fetch) => {}
+        _ => return false,
+    }
+    if response.header.code != MessageClass::Response(Status::Content) {
+        return false;
+    }
+    match get(response) {
+        Some(etag) if matches(request, etag) => {}
+        _ => return false,
+    }
+    response.header.code = MessageClass::Response(Status::Valid);
+    response.payload.clear();
+    for option in [
+        CoapOption::ContentFormat,
+        CoapOption::Block2,
+        CoapOption::Size2,
+    ] {
+        response.clear_option(option);
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_tag() {
+        assert_eq!(generate(b""), 0xcbf2_9ce4_8422_2325u64.to_be_bytes());
+        assert_ne!(generate(b"21.5"), generate(b"21.6"));
+
+        let mut message = Packet::new();
+        message.payload = b"21.5".to_vec();
+        let plain = tag(&mut message);
+        payload::set_content_format(&mut message, 50);
+        let json = tag(&mut message);
+        assert_ne!(plain, json);
+        assert_eq!(get(&message), Some(&json));
+        assert_eq!(message.get_option(CoapOption::ETag).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut request = Packet::new();
+        request.header.code = MessageClass::Request(Method::Get);
+        request.add_option(CoapOption::ETag, vec![1]);
+        request.add_option(CoapOption::ETag, vec![2]);
+        let mut response = Packet::new();
+        response.header.code = MessageClass::Response(Status::Content);
+        response.payload = b"21.5".to_vec();
+        payload::set_content_format(&mut response, 0);
+
+        set(&mut response, vec![3]);
+        assert!(!validate(&request, &mut response));
+        set(&mut response, vec![2]);
+        assert!(validate(&request, &mut response));
+        assert_eq!(response.header.code, MessageClass::Response(Status::Valid));
+        assert!(response.payload.is_empty());
+        assert_eq!(payload::content_format(&response), None);
+        assert_eq!(get(&response), Some(&vec![2]));
+
+        request.header.code = MessageClass::Request(Method::Put);
+        response.header.code = MessageClass::Response(Status::Content);
+        assert!(!validate(&request, &mut response));
+    }
+
+    #[test]
+    fn test_valid_response() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|request: CoapRequest<SocketAddr>| async move {
+                            let mut response: CoapResponse = request.response?;
+                            response.message.payload = b"21.5".to_vec();
+                            tag(&mut response.message);
+                            Some(response)
+                        })
+                        .await
+                        .unwrap();
+                });
+        });
+        let client = CoAPClient::new(rx.recv().unwrap()).unwrap();
+        let send = |etag: Option<Vec<u8>>| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Get);
+            request.set_path("/temperature");
+            request.message.set_token(vec![1]);
+            if let Some(etag) = etag {
+                request.message.add_option(CoapOption::ETag, etag);
+            }
+            client.send(&request).unwrap();
+            client.receive().unwrap()
+        };
+
+        let response = send(None);
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.payload, b"21.5");
+        let etag = get(&response.message).unwrap().clone();
+        let response = send(Some(etag.clone()));
+        assert_eq!(*response.get_status(), Status::Valid);
+        assert!(response.message.payload.is_empty());
+        assert_eq!(get(&response.message), Some(&etag));
+        let response = send(Some(vec![0]));
+        assert_eq!(*response.get_status(), Status::Content);
+    }
+}
@@ -0,0 +1,204 @@
+//! Group communication ([RFC 7390](https://tools.ietf.org/html/rfc7390)).
+//!
+//! A server serves the multicast groups it joined, either with
+//! [`Server::join_multicast`](crate::Server::join_multicast) or through its
+//! group membership resource, enabled with
+//! [`Server::set_group_membership_path`](crate::Server::set_group_membership_path).
+//! Clients list the memberships with a GET on the resource, join a group by
+//! POSTing a [`Membership`] and leave it by deleting the membership. The
+//! resource should be protected with an access control list.
+//!
+//! Requests to a group are recognized when the transport receives the
+//! group on a dedicated socket, see
+//! [`UdpTransport::set_dedicated_multicast`](crate::transport::UdpTransport::set_dedicated_multicast).
+//! Responses to them follow the [`ResponsePolicy`] of the group.
+use coap_lite::MessageClass;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+
+use super::content_format::ContentFormat;
+
+/// Path of the group membership resource suggested by RFC 7390.
+pub const DEFAULT_MEMBERSHIP_PATH: &str = "coap-group";
+
+/// Content-Format of `application/coap-group+json`.
+pub const CONTENT_FORMAT: ContentFormat = ContentFormat::CoapGroupJson;
+
+/// A group membership, in the `application/coap-group+json` format.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Membership {
+    /// The name of the group, e.g. `all.bldg6.example.com`.
+    #[serde(rename = "n", default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The group address, optionally with a port, e.g. `[ff15::1]:5683`.
+    #[serde(rename = "a")]
+    pub address: String,
+}
+
+impl Membership {
+    /// Return the multicast address of the group and the port, if given.
+    pub fn group(&self) -> Option<(IpAddr, Option<u16>)> {
+        let (ip, port) = match self.address.parse::<SocketAddr>() {
+            Ok(addr) => (addr.ip(), Some(addr.port())),
+            Err(_) => {
+                let ip = self.address.trim_start_matches('[').trim_end_matches(']');
+                (ip.parse().ok()?, None)
+            }
+        };
+        ip.is_multicast().then_some((ip, port))
+    }
+}
+
+/// Which responses the server sends to requests to a group.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ResponsePolicy {
+    /// Respond like to requests sent to the server alone.
+    All,
+    /// Send no error responses, so that only the members that have the
+    /// resource answer, as RFC 7390 recommends.
+    #[default]
+    SuppressErrors,
+    /// Send no responses at all, e.g. for group actuation.
+    None,
+}
+
+impl ResponsePolicy {
+    /// Return whether the policy suppresses a response with `code`.
+    pub fn suppresses(self, code: MessageClass) -> bool {
+        match self {
+            ResponsePolicy::All => false,
+            ResponsePolicy::SuppressErrors => u8::from(code) >> 5 >= 4,
+            ResponsePolicy::None => true,
+        }
+    }
+}
+
+/// The memberships created through the membership resource and the
+/// response policies of the groups.
+#[derive(Debug, Default)]
+pub(crate) struct Groups {
+    pub(crate) path: Option<String>,
+    memberships: BTreeMap<u32, Membership>,
+    next_index: u32,
+    policies: HashMap<IpAddr, ResponsePolicy>,
+}
+
+impl Groups {
+    pub(crate) fn new() -> Groups {
+        Groups::default()
+    }
+
+    pub(crate) fn set_policy(&mut self, group: IpAddr, policy: ResponsePolicy) {
+        self.policies.insert(group, policy);
+    }
+
+    pub(crate) fn policy(&self, group: IpAddr) -> ResponsePolicy {
+        self.policies.get(&group).copied().unwrap_or_default()
+    }
+
+    /// Return the index of the membership addressed by `path`: `Some(None)`
+    /// for the collection, `None` for paths outside the resource.
+    pub(crate) fn resource<'a>(&self, path: &'a str) -> Option<Option<&'a str>> {
+        let base = self.path.as_deref()?;
+        let rest = path.strip_prefix(base)?;
+        if rest.is_empty() {
+            return Some(None);
+        }
+        rest.strip_prefix('/').map(Some)
+    }
+
+    pub(crate) fn memberships(&self) -> &BTreeMap<u32, Membership> {
+        &self.memberships
+    }
+
+    pub(crate) fn get(&self, index: &str) -> Option<&Membership> {
+        self.memberships.get(&index.parse().ok()?)
+    }
+
+    /// Add a membership and return its index.
+    pub(crate) fn add(&mut self, membership: Membership) -> u32 {
+        let index = self.next_index;
+        self.next_index += 1;
+        self.memberships.insert(index, membership);
+        index
+    }
+
+    pub(crate) fn remove(&mut self, index: &str) -> Option<Membership> {
+        self.memberships.remove(&index.parse().ok()?)
+    }
+
+    /// Return whether any membership is for `group`.
+    pub(crate) fn is_member(&self, group: IpAddr) -> bool {
+        self.memberships
+            .values()
+            .any(|membership| membership.group().map(|(ip, _)| ip) == Some(group))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use coap_lite::ResponseType as Status;
+
+    #[test]
+    fn test_membership() {
+        let membership: Membership =
+            serde_json::from_str(r#"{"n": "all.bldg6.example.com", "a": "[ff15::1]:5683"}"#)
+                .unwrap();
+        assert_eq!(
+            membership.group(),
+            Some(("ff15::1".parse().unwrap(), Some(5683)))
+        );
+        let membership = Membership {
+            name: None,
+            address: "224.0.1.187".to_string(),
+        };
+        assert_eq!(
+            membership.group(),
+            Some(("224.0.1.187".parse().unwrap(), None))
+        );
+        assert_eq!(
+            serde_json::to_string(&membership).unwrap(),
+            r#"{"a":"224.0.1.187"}"#
+        );
+        let membership = Membership {
+            name: None,
+            address: "192.0.2.1".to_string(),
+        };
+        assert_eq!(membership.group(), None);
+    }
+
+    #[test]
+    fn test_policy() {
+        let mut groups = Groups::new();
+        let group = "224.0.1.187".parse().unwrap();
+        let not_found = MessageClass::Response(Status::NotFound);
+        let content = MessageClass::Response(Status::Content);
+        assert!(groups.policy(group).suppresses(not_found));
+        assert!(!groups.policy(group).suppresses(content));
+        groups.set_policy(group, ResponsePolicy::None);
+        assert!(groups.policy(group).suppresses(content));
+        groups.set_policy(group, ResponsePolicy::All);
+        assert!(!groups.policy(group).suppresses(not_found));
+    }
+
+    #[test]
+    fn test_resource() {
+        let mut groups = Groups::new();
+        assert_eq!(groups.resource("coap-group"), None);
+        groups.path = Some(DEFAULT_MEMBERSHIP_PATH.to_string());
+        assert_eq!(groups.resource("coap-group"), Some(None));
+        assert_eq!(groups.resource("coap-group/3"), Some(Some("3")));
+        assert_eq!(groups.resource("coap-groups"), None);
+
+        let index = groups.add(Membership {
+            name: None,
+            address: "224.0.1.187".to_string(),
+        });
+        assert!(groups.is_member("224.0.1.187".parse().unwrap()));
+        assert!(groups.get(&index.to_string()).is_some());
+        assert!(groups.remove(&index.to_string()).is_some());
+        assert!(!groups.is_member("224.0.1.187".parse().unwrap()));
+    }
+}
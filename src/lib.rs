@@ -8,6 +8,8 @@
 //! - CoAP Observe option [RFC 7641](https://tools.ietf.org/rfc/rfc7641.txt)
 //! - *Too Many Requests* Response Code [RFC 8516](https://tools.ietf.org/html/rfc8516)
 //! - Block-Wise Transfers [RFC 7959](https://tools.ietf.org/html/rfc7959)
+//! - CoRE Link Format [RFC 6690](https://tools.ietf.org/html/rfc6690)
+//! - CoAP over TCP, TLS and WebSockets [RFC 8323](https://tools.ietf.org/html/rfc8323) (with the `tls` and `websocket` features)
 //!
 //! # Installation
 //!
@@ -84,9 +86,41 @@ extern crate alloc;
 extern crate quickcheck;
 
 pub use self::client::CoAPClient;
-pub use self::observer::Observer;
+pub use self::observer::{ObserveRegistry, Observer};
 pub use self::server::{CoAPServer, Server};
+#[cfg(feature = "ace")]
+pub mod ace;
+pub mod acl;
+pub mod audit;
+mod budget;
+pub mod cache;
 pub mod client;
+pub mod content_format;
+pub mod echo;
+pub mod etag;
+pub mod group;
+pub mod link_format;
+#[cfg(feature = "mdns")]
+pub mod mdns;
 pub mod message;
+pub mod message_id;
+pub mod mtu;
+pub mod no_response;
 mod observer;
-pub mod server;
\ No newline at end of file
+pub mod options;
+pub mod outbound;
+pub mod payload;
+pub mod problem;
+pub mod proxy;
+pub mod pubsub;
+pub mod qblock;
+#[cfg(unix)]
+pub mod reactor;
+pub mod resource_directory;
+pub mod router;
+pub mod runtime;
+pub mod senml;
+pub mod server;
+pub mod throttle;
+pub mod transport;
+pub mod vhost;
\ No newline at end of file
@@ -0,0 +1,503 @@
+//! CoRE Link Format ([RFC 6690](https://tools.ietf.org/html/rfc6690)) data model.
+//!
+//! The same `Link` type is produced by the client when discovering resources
+//! and consumed by the server when answering `/.well-known/core` requests.
+//! Attribute names are case-insensitive, and targets and anchors are URI
+//! references resolved with [`Link::target_uri`] and [`Link::context_uri`].
+use std::error;
+use std::fmt;
+use url::Url;
+
+/// Content-Format number of `application/link-format`.
+pub const CONTENT_FORMAT: u16 = 40;
+
+/// A target attribute of a link, e.g. `rt="temperature"` or the flag `obs`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attribute {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+impl Attribute {
+    /// Creates an attribute with a value.
+    pub fn new(name: &str, value: &str) -> Attribute {
+        Attribute {
+            name: name.to_string(),
+            value: Some(value.to_string()),
+        }
+    }
+
+    /// Creates a valueless attribute such as `obs`.
+    pub fn flag(name: &str) -> Attribute {
+        Attribute {
+            name: name.to_string(),
+            value: None,
+        }
+    }
+}
+
+/// A single link: a target URI reference with its attributes.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Link {
+    pub target: String,
+    pub attributes: Vec<Attribute>,
+}
+
+impl Link {
+    /// Creates a link to the given URI reference without attributes.
+    pub fn new(target: &str) -> Link {
+        Link {
+            target: target.to_string(),
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Adds an attribute with a value.
+    pub fn with_attribute(mut self, name: &str, value: &str) -> Link {
+        self.attributes.push(Attribute::new(name, value));
+        self
+    }
+
+    /// Adds a valueless attribute.
+    pub fn with_flag(mut self, name: &str) -> Link {
+        self.attributes.push(Attribute::flag(name));
+        self
+    }
+
+    /// Adds a Content-Format (`ct`) of the target. Several ones share a
+    /// single attribute, as RFC 7252 requires.
+    pub fn with_content_format(mut self, content_format: u16) -> Link {
+        let ct = self
+            .attributes
+            .iter_mut()
+            .find(|attr| attr.name.eq_ignore_ascii_case("ct"));
+        match ct.and_then(|attr| attr.value.as_mut()) {
+            Some(value) => *value = format!("{} {}", value, content_format),
+            None => return self.with_attribute("ct", &content_format.to_string()),
+        }
+        self
+    }
+
+    /// Sets the estimated size (`sz`) of the target in bytes.
+    pub fn with_size(self, size: u64) -> Link {
+        self.with_attribute("sz", &size.to_string())
+    }
+
+    /// Returns the value of the first attribute with the given name.
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|attr| attr.name.eq_ignore_ascii_case(name))
+            .and_then(|attr| attr.value.as_deref())
+    }
+
+    /// Returns whether an attribute with the given name is present.
+    pub fn has_attribute(&self, name: &str) -> bool {
+        self.attributes
+            .iter()
+            .any(|attr| attr.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns all values of an attribute. Repeated attributes and
+    /// space-separated values (as used by `rt`, `if` and `rel`) are flattened.
+    pub fn values(&self, name: &str) -> Vec<&str> {
+        self.attributes
+            .iter()
+            .filter(|attr| attr.name.eq_ignore_ascii_case(name))
+            .filter_map(|attr| attr.value.as_deref())
+            .flat_map(|value| value.split(' ').filter(|v| !v.is_empty()))
+            .collect()
+    }
+
+    /// Returns the relation types of the link (`rel`), `hosts` if none is
+    /// given.
+    pub fn relations(&self) -> Vec<&str> {
+        let relations = self.values("rel");
+        if relations.is_empty() {
+            return vec!["hosts"];
+        }
+        relations
+    }
+
+    /// Returns the resource types (`rt`) of the target.
+    pub fn resource_types(&self) -> Vec<&str> {
+        self.values("rt")
+    }
+
+    /// Returns the interface descriptions (`if`) of the target.
+    pub fn interfaces(&self) -> Vec<&str> {
+        self.values("if")
+    }
+
+    /// Returns the Content-Formats (`ct`) of the target, skipping values
+    /// that are no Content-Format numbers.
+    pub fn content_formats(&self) -> Vec<u16> {
+        self.values("ct")
+            .into_iter()
+            .filter_map(|ct| ct.parse().ok())
+            .collect()
+    }
+
+    /// Returns the estimated size (`sz`) of the target in bytes.
+    pub fn size(&self) -> Option<u64> {
+        self.attribute("sz")?.parse().ok()
+    }
+
+    /// Returns the target URI, resolved against the URI of the document
+    /// the link was found in.
+    pub fn target_uri(&self, base: &Url) -> Result<Url, url::ParseError> {
+        base.join(&self.target)
+    }
+
+    /// Returns the context URI of the link: the `anchor` resolved against
+    /// the URI of the document, or else the origin of the target.
+    pub fn context_uri(&self, base: &Url) -> Result<Url, url::ParseError> {
+        match self.attribute("anchor") {
+            Some(anchor) => base.join(anchor),
+            None => self.target_uri(base)?.join("/"),
+        }
+    }
+
+    /// Checks the link against a discovery query such as `rt=temp*`
+    /// (RFC 6690 section 4.1). A trailing `*` matches any suffix.
+    pub fn matches_query(&self, query: &str) -> bool {
+        let (name, pattern) = match query.split_once('=') {
+            Some((name, pattern)) => (name, pattern),
+            None => return self.has_attribute(query),
+        };
+
+        let matches = |value: &str| match pattern.strip_suffix('*') {
+            Some(prefix) => value.starts_with(prefix),
+            None => value == pattern,
+        };
+
+        if name.eq_ignore_ascii_case("href") {
+            return matches(&self.target);
+        }
+        self.attributes
+            .iter()
+            .filter(|attr| attr.name.eq_ignore_ascii_case(name))
+            .filter_map(|attr| attr.value.as_deref())
+            .any(|value| matches(value) || value.split(' ').any(matches))
+    }
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{}>", self.target)?;
+        for attr in &self.attributes {
+            write!(f, ";{}", attr.name)?;
+            if let Some(ref value) = attr.value {
+                if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+                    write!(f, "={}", value)?;
+                } else {
+                    write!(f, "=\"")?;
+                    for c in value.chars() {
+                        if c == '"' || c == '\\' {
+                            write!(f, "\\")?;
+                        }
+                        write!(f, "{}", c)?;
+                    }
+                    write!(f, "\"")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serializes links into a link-format document.
+pub fn serialize<'a, I: IntoIterator<Item = &'a Link>>(links: I) -> String {
+    links
+        .into_iter()
+        .map(|link| link.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Error returned when a link-format document is malformed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset in the input where parsing failed.
+    pub position: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.reason, self.position)
+    }
+}
+
+impl error::Error for ParseError {}
+
+/// Parses a link-format document.
+pub fn parse(input: &str) -> Result<Vec<Link>, ParseError> {
+    let mut parser = Parser {
+        input: input.as_bytes(),
+        pos: 0,
+    };
+    let mut links = Vec::new();
+
+    parser.skip_whitespace();
+    if parser.at_end() {
+        return Ok(links);
+    }
+
+    loop {
+        links.push(parser.link()?);
+        parser.skip_whitespace();
+        if parser.at_end() {
+            return Ok(links);
+        }
+        parser.expect(b',', "expected ','")?;
+        parser.skip_whitespace();
+    }
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn at_end(&self) -> bool {
+        self.pos >= self.input.len()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn error(&self, reason: &'static str) -> ParseError {
+        ParseError {
+            position: self.pos,
+            reason,
+        }
+    }
+
+    fn expect(&mut self, byte: u8, reason: &'static str) -> Result<(), ParseError> {
+        if self.peek() != Some(byte) {
+            return Err(self.error(reason));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\r') | Some(b'\n') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn take_while<F: Fn(u8) -> bool>(&mut self, predicate: F) -> Result<String, ParseError> {
+        let start = self.pos;
+        while self.peek().is_some_and(&predicate) {
+            self.pos += 1;
+        }
+        String::from_utf8(self.input[start..self.pos].to_vec())
+            .map_err(|_| ParseError {
+                position: start,
+                reason: "invalid utf-8",
+            })
+    }
+
+    fn link(&mut self) -> Result<Link, ParseError> {
+        self.expect(b'<', "expected '<'")?;
+        let target = self.take_while(is_uri_char)?;
+        if self.peek().is_some_and(|b| b != b'>') {
+            return Err(self.error("invalid character in URI reference"));
+        }
+        self.expect(b'>', "unterminated URI reference")?;
+
+        let mut link = Link::new(&target);
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b';') {
+                return Ok(link);
+            }
+            self.pos += 1;
+            self.skip_whitespace();
+            link.attributes.push(self.attribute()?);
+        }
+    }
+
+    fn attribute(&mut self) -> Result<Attribute, ParseError> {
+        let name = self.take_while(is_parmname_char)?;
+        if name.is_empty() {
+            return Err(self.error("expected attribute name"));
+        }
+
+        self.skip_whitespace();
+        if self.peek() != Some(b'=') {
+            return Ok(Attribute { name, value: None });
+        }
+        self.pos += 1;
+        self.skip_whitespace();
+
+        let value = if self.peek() == Some(b'"') {
+            self.quoted_string()?
+        } else {
+            let value = self.take_while(is_ptoken_char)?;
+            if value.is_empty() {
+                return Err(self.error("expected attribute value"));
+            }
+            value
+        };
+        Ok(Attribute {
+            name,
+            value: Some(value),
+        })
+    }
+
+    fn quoted_string(&mut self) -> Result<String, ParseError> {
+        self.expect(b'"', "expected '\"'")?;
+        let mut value = Vec::new();
+        loop {
+            match self.peek() {
+                None => return Err(self.error("unterminated quoted string")),
+                Some(b'"') => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(escaped) => value.push(escaped),
+                        None => return Err(self.error("unterminated quoted string")),
+                    }
+                    self.pos += 1;
+                }
+                Some(b) => {
+                    value.push(b);
+                    self.pos += 1;
+                }
+            }
+        }
+        String::from_utf8(value).map_err(|_| self.error("invalid utf-8"))
+    }
+}
+
+/// Characters of a URI reference (RFC 3986), percent-encoded or not.
+fn is_uri_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"-._~:/?#[]@!$&'()*+,;=%".contains(&b)
+}
+
+fn is_parmname_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$&+-.^_`|~*".contains(&b)
+}
+
+fn is_ptoken_char(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'()*+-./:<=>?@[]^_`{|}~".contains(&b)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let links = vec![
+            Link::new("/sensors/temp")
+                .with_attribute("rt", "temperature-c")
+                .with_attribute("if", "sensor")
+                .with_attribute("ct", "0")
+                .with_flag("obs"),
+            Link::new("/quote").with_attribute("title", "say \"hi\""),
+        ];
+        assert_eq!(
+            serialize(&links),
+            "</sensors/temp>;rt=\"temperature-c\";if=\"sensor\";ct=0;obs,</quote>;title=\"say \\\"hi\\\"\""
+        );
+    }
+
+    #[test]
+    fn test_parse_round_trip() {
+        let document = "</sensors/temp>;rt=\"temperature-c\";if=\"sensor\";ct=0;obs,</quote>;title=\"a, \\\"b\\\"; c\"";
+        let links = parse(document).unwrap();
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].target, "/sensors/temp");
+        assert_eq!(links[0].attribute("rt"), Some("temperature-c"));
+        assert_eq!(links[0].attribute("ct"), Some("0"));
+        assert!(links[0].has_attribute("obs"));
+        assert_eq!(links[1].attribute("title"), Some("a, \"b\"; c"));
+        assert_eq!(serialize(&links), document);
+    }
+
+    #[test]
+    fn test_parse_multiple_values() {
+        let links = parse("</a>;rt=\"one two\";rt=three , </b> ; ct = 40").unwrap();
+        assert_eq!(links[0].values("rt"), vec!["one", "two", "three"]);
+        assert_eq!(links[1].attribute("ct"), Some("40"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("").unwrap().is_empty());
+        assert!(parse("/a").is_err());
+        assert!(parse("</a").is_err());
+        assert!(parse("</a>;rt=\"x").is_err());
+        assert!(parse("</a>;=x").is_err());
+        assert!(parse("</a> </b>").is_err());
+        assert!(parse("</a b>").is_err());
+        assert!(parse("</a\"b>").is_err());
+    }
+
+    #[test]
+    fn test_typed_attributes() {
+        let links = parse(
+            "</sensors/temp>;RT=\"temperature-c\";if=sensor;ct=\"0 60\";sz=1280,\
+             </t>;anchor=\"/sensors/temp\";rel=\"describedby alternate\"",
+        )
+        .unwrap();
+        assert_eq!(links[0].resource_types(), vec!["temperature-c"]);
+        assert_eq!(links[0].interfaces(), vec!["sensor"]);
+        assert_eq!(links[0].content_formats(), vec![0, 60]);
+        assert_eq!(links[0].size(), Some(1280));
+        assert_eq!(links[0].relations(), vec!["hosts"]);
+        assert_eq!(links[1].relations(), vec!["describedby", "alternate"]);
+    }
+
+    #[test]
+    fn test_builders() {
+        let link = Link::new("/fw")
+            .with_content_format(42)
+            .with_content_format(60)
+            .with_size(65536);
+        assert_eq!(link.to_string(), "</fw>;ct=\"42 60\";sz=65536");
+        assert_eq!(parse(&link.to_string()).unwrap(), vec![link]);
+    }
+
+    #[test]
+    fn test_uris() {
+        let base = Url::parse("coap://[2001:db8::1]/.well-known/core").unwrap();
+        let links = parse("<sensors/temp>,</t>;anchor=\"/sensors/temp\",<coap://example.com/x>")
+            .unwrap();
+        assert_eq!(
+            links[0].target_uri(&base).unwrap().as_str(),
+            "coap://[2001:db8::1]/.well-known/sensors/temp"
+        );
+        assert_eq!(
+            links[0].context_uri(&base).unwrap().as_str(),
+            "coap://[2001:db8::1]/"
+        );
+        assert_eq!(
+            links[1].context_uri(&base).unwrap().as_str(),
+            "coap://[2001:db8::1]/sensors/temp"
+        );
+        assert_eq!(
+            links[2].context_uri(&base).unwrap().as_str(),
+            "coap://example.com/"
+        );
+    }
+
+    #[test]
+    fn test_matches_query() {
+        let link = Link::new("/sensors/temp").with_attribute("rt", "temperature-c outdoor");
+        assert!(link.matches_query("rt=outdoor"));
+        assert!(link.matches_query("rt=temp*"));
+        assert!(link.matches_query("href=/sensors/*"));
+        assert!(!link.matches_query("rt=humidity"));
+        assert!(!link.matches_query("if=sensor"));
+    }
+}
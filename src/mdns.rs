@@ -0,0 +1,704 @@
+//! Advertisement and discovery of CoAP services with multicast DNS
+//! ([RFC 6762](https://tools.ietf.org/html/rfc6762)) and DNS-based service
+//! discovery ([RFC 6763](https://tools.ietf.org/html/rfc6763)).
+//!
+//! A [`Responder`] announces a server as a `_coap._udp` service, with its
+//! port in the SRV record and an optional path in a `path=` TXT record, and
+//! answers the queries for it. [`browse`] finds the services on the local
+//! network. Only IPv4 multicast is used.
+//!
+//! ```no_run
+//! use coap::mdns::{self, Responder, ServiceInfo};
+//! use std::time::Duration;
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let service = ServiceInfo::new("Kitchen sensor", "kitchen.local", 5683)
+//!         .with_address("192.168.1.20".parse().unwrap())
+//!         .with_path("/sensors");
+//!     tokio::spawn(Responder::new(service).unwrap().run());
+//!
+//!     for service in mdns::browse(Duration::from_secs(1)).unwrap() {
+//!         println!("{}: {:?}", service.instance, service.url());
+//!     }
+//! }
+//! ```
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io::{ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// The service type of CoAP over UDP.
+pub const SERVICE_TYPE: &str = "_coap._udp.local";
+
+/// The IPv4 multicast group of mDNS.
+pub const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+/// The port of mDNS.
+pub const MDNS_PORT: u16 = 5353;
+
+const SERVICES: &str = "_services._dns-sd._udp.local";
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// The top bit of the class: unicast response in questions, cache flush in
+/// records.
+const CLASS_FLAG: u16 = 0x8000;
+
+/// Time to live of the records naming the host, as RFC 6762 recommends.
+const HOST_TTL: u32 = 120;
+/// Time to live of the other records.
+const OTHER_TTL: u32 = 4500;
+/// Time to live of the records in answers to legacy unicast queries.
+const LEGACY_TTL: u32 = 10;
+
+/// A CoAP service on the local network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceInfo {
+    /// The instance name, e.g. `Kitchen sensor`.
+    pub instance: String,
+    /// The host name, e.g. `kitchen.local`.
+    pub host: String,
+    /// The port of the server.
+    pub port: u16,
+    /// The addresses of the host.
+    pub addresses: Vec<IpAddr>,
+    /// The path of the resources, from the `path` TXT record.
+    pub path: Option<String>,
+}
+
+impl ServiceInfo {
+    /// Create a service on `host` at `port`, without addresses.
+    pub fn new(instance: &str, host: &str, port: u16) -> ServiceInfo {
+        ServiceInfo {
+            instance: instance.to_string(),
+            host: host.trim_end_matches('.').to_string(),
+            port,
+            addresses: Vec::new(),
+            path: None,
+        }
+    }
+
+    /// Add an address of the host.
+    pub fn with_address(mut self, address: IpAddr) -> ServiceInfo {
+        self.addresses.push(address);
+        self
+    }
+
+    /// Set the path of the resources.
+    pub fn with_path(mut self, path: &str) -> ServiceInfo {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Return the URL of the service at its first address, if it has one.
+    pub fn url(&self) -> Option<String> {
+        let address = SocketAddr::new(*self.addresses.first()?, self.port);
+        let path = self.path.as_deref().unwrap_or("");
+        Some(format!(
+            "coap://{}/{}",
+            address,
+            path.trim_start_matches('/')
+        ))
+    }
+
+    fn instance_name(&self) -> Vec<String> {
+        let mut name = vec![self.instance.clone()];
+        name.extend(labels(SERVICE_TYPE));
+        name
+    }
+
+    /// Return the records describing the service: the PTR record of the
+    /// service type first.
+    fn records(&self, ttl: Option<u32>) -> Vec<Record> {
+        let ttl = |default: u32| ttl.unwrap_or(default);
+        let instance = self.instance_name();
+        let host = labels(&self.host);
+        let mut txt = Vec::new();
+        if let Some(ref path) = self.path {
+            let entry = format!("path={}", path);
+            let entry = &entry.as_bytes()[..entry.len().min(255)];
+            txt.push(entry.len() as u8);
+            txt.extend(entry);
+        } else {
+            // an empty TXT record has a single empty string
+            txt.push(0);
+        }
+        let mut records = vec![
+            Record::new(
+                labels(SERVICE_TYPE),
+                TYPE_PTR,
+                ttl(OTHER_TTL),
+                RData::Name(instance.clone()),
+            ),
+            Record::new(
+                instance.clone(),
+                TYPE_SRV,
+                ttl(HOST_TTL),
+                RData::Srv(self.port, host.clone()),
+            ),
+            Record::new(instance, TYPE_TXT, ttl(OTHER_TTL), RData::Bytes(txt)),
+        ];
+        for address in &self.addresses {
+            let (kind, data) = match address {
+                IpAddr::V4(ip) => (TYPE_A, ip.octets().to_vec()),
+                IpAddr::V6(ip) => (TYPE_AAAA, ip.octets().to_vec()),
+            };
+            records.push(Record::new(
+                host.clone(),
+                kind,
+                ttl(HOST_TTL),
+                RData::Bytes(data),
+            ));
+        }
+        records
+    }
+
+    /// Return the answer to a query, or `None` if it asks for nothing about
+    /// the service. Legacy unicast queries, sent from another port than
+    /// 5353, get their ID and questions back.
+    fn answer(&self, query: &[u8], legacy: bool) -> Option<Vec<u8>> {
+        let message = Message::parse(query)?;
+        if message.response {
+            return None;
+        }
+        let ttl = legacy.then_some(LEGACY_TTL);
+        let records = self.records(ttl);
+        let mut answers: Vec<Record> = Vec::new();
+        for question in &message.questions {
+            let matches = |record: &Record| {
+                same_name(&record.name, &question.name)
+                    && (question.kind == TYPE_ANY || question.kind == record.kind)
+            };
+            if same_name(&question.name, &labels(SERVICES))
+                && matches!(question.kind, TYPE_PTR | TYPE_ANY)
+            {
+                let service_type = RData::Name(labels(SERVICE_TYPE));
+                answers.push(Record::new(
+                    labels(SERVICES),
+                    TYPE_PTR,
+                    ttl.unwrap_or(OTHER_TTL),
+                    service_type,
+                ));
+            }
+            for record in records.iter().filter(|record| matches(record)) {
+                if !answers.contains(record) {
+                    answers.push(record.clone());
+                }
+            }
+        }
+        if answers.is_empty() {
+            return None;
+        }
+        // the other records of the service spare the querier further queries
+        let additionals = records
+            .into_iter()
+            .filter(|record| !answers.contains(record) && record.kind != TYPE_PTR)
+            .collect();
+        let response = Message {
+            id: if legacy { message.id } else { 0 },
+            response: true,
+            questions: if legacy {
+                message.questions
+            } else {
+                Vec::new()
+            },
+            answers,
+            additionals,
+        };
+        Some(response.encode())
+    }
+}
+
+/// Announces a service and answers the queries for it.
+pub struct Responder {
+    service: ServiceInfo,
+    socket: UdpSocket,
+}
+
+impl Responder {
+    /// Join the mDNS group on port 5353 to answer for `service`.
+    pub fn new(service: ServiceInfo) -> Result<Responder> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), MDNS_PORT).into())?;
+        socket.join_multicast_v4(&MDNS_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_nonblocking(true)?;
+        Ok(Responder {
+            service,
+            socket: UdpSocket::from_std(socket.into())?,
+        })
+    }
+
+    /// Announce the service, then answer queries until an error occurs.
+    pub async fn run(self) -> Result<()> {
+        let group = SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT));
+        let announcement = Message {
+            id: 0,
+            response: true,
+            questions: Vec::new(),
+            answers: self.service.records(None),
+            additionals: Vec::new(),
+        };
+        self.socket.send_to(&announcement.encode(), group).await?;
+
+        let mut buf = [0; 9000];
+        loop {
+            let (len, source) = self.socket.recv_from(&mut buf).await?;
+            let legacy = source.port() != MDNS_PORT;
+            let unicast = legacy
+                || Message::parse(&buf[..len]).is_some_and(|message| {
+                    message
+                        .questions
+                        .iter()
+                        .any(|question| question.class & CLASS_FLAG != 0)
+                });
+            if let Some(answer) = self.service.answer(&buf[..len], legacy) {
+                let destination = if unicast { source } else { group };
+                self.socket.send_to(&answer, destination).await?;
+            }
+        }
+    }
+}
+
+/// Find the CoAP services on the local network, collecting answers for
+/// `timeout`.
+pub fn browse(timeout: Duration) -> Result<Vec<ServiceInfo>> {
+    browse_at(
+        SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT)),
+        timeout,
+    )
+}
+
+/// Send a browsing query to `destination` and collect the services
+/// described by the answers.
+fn browse_at(destination: SocketAddr, timeout: Duration) -> Result<Vec<ServiceInfo>> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let query = Message {
+        id: 0,
+        response: false,
+        questions: vec![Question {
+            name: labels(SERVICE_TYPE),
+            kind: TYPE_PTR,
+            class: CLASS_IN,
+        }],
+        answers: Vec::new(),
+        additionals: Vec::new(),
+    };
+    socket.send_to(&query.encode(), destination)?;
+
+    let mut records = Vec::new();
+    let mut sources = Vec::new();
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0; 9000];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+        match socket.recv_from(&mut buf) {
+            Ok((len, source)) => {
+                if let Some(message) = Message::parse(&buf[..len]).filter(|m| m.response) {
+                    let count = message.answers.len() + message.additionals.len();
+                    records.extend(message.answers.into_iter().chain(message.additionals));
+                    sources.extend(std::iter::repeat_n(source.ip(), count));
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(services(&records, &sources))
+}
+
+/// Assemble the services named by the PTR records of the service type.
+/// Hosts without address records are reached at the address that sent
+/// their records.
+fn services(records: &[Record], sources: &[IpAddr]) -> Vec<ServiceInfo> {
+    let mut services: Vec<ServiceInfo> = Vec::new();
+    let service_type = labels(SERVICE_TYPE);
+    for (index, record) in records.iter().enumerate() {
+        let instance = match (&record.data, record.kind) {
+            (RData::Name(name), TYPE_PTR) if same_name(&record.name, &service_type) => name,
+            _ => continue,
+        };
+        let Some((port, host)) = records.iter().find_map(|record| match &record.data {
+            RData::Srv(port, host) if same_name(&record.name, instance) => Some((*port, host)),
+            _ => None,
+        }) else {
+            continue;
+        };
+        let mut service = ServiceInfo::new(&instance[0], &host.join("."), port);
+        service.path = records
+            .iter()
+            .filter(|record| record.kind == TYPE_TXT && same_name(&record.name, instance))
+            .find_map(|record| match &record.data {
+                RData::Bytes(data) => txt_value(data, "path"),
+                _ => None,
+            });
+        for record in records
+            .iter()
+            .filter(|record| same_name(&record.name, host))
+        {
+            let address = match (&record.data, record.kind) {
+                (RData::Bytes(data), TYPE_A) => {
+                    <[u8; 4]>::try_from(data.as_slice()).ok().map(IpAddr::from)
+                }
+                (RData::Bytes(data), TYPE_AAAA) => {
+                    <[u8; 16]>::try_from(data.as_slice()).ok().map(IpAddr::from)
+                }
+                _ => None,
+            };
+            if let Some(address) = address.filter(|address| !service.addresses.contains(address)) {
+                service.addresses.push(address);
+            }
+        }
+        if service.addresses.is_empty() {
+            service.addresses.push(sources[index]);
+        }
+        if !services
+            .iter()
+            .any(|known| known.instance == service.instance)
+        {
+            services.push(service);
+        }
+    }
+    services
+}
+
+/// Return the value of `key` in the strings of a TXT record.
+fn txt_value(data: &[u8], key: &str) -> Option<String> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let len = data[pos] as usize;
+        let entry = data.get(pos + 1..pos + 1 + len)?;
+        pos += 1 + len;
+        let entry = String::from_utf8_lossy(entry);
+        if let Some((name, value)) = entry.split_once('=') {
+            if name.eq_ignore_ascii_case(key) {
+                return Some(value.to_string());
+            }
+        }
+    }
+    None
+}
+
+fn labels(name: &str) -> Vec<String> {
+    name.trim_end_matches('.')
+        .split('.')
+        .map(str::to_string)
+        .collect()
+}
+
+fn same_name(a: &[String], b: &[String]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Question {
+    name: Vec<String>,
+    kind: u16,
+    class: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RData {
+    Name(Vec<String>),
+    Srv(u16, Vec<String>),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    name: Vec<String>,
+    kind: u16,
+    ttl: u32,
+    data: RData,
+}
+
+impl Record {
+    fn new(name: Vec<String>, kind: u16, ttl: u32, data: RData) -> Record {
+        Record {
+            name,
+            kind,
+            ttl,
+            data,
+        }
+    }
+}
+
+/// A DNS message, without the authority section, which mDNS uses only for
+/// probing.
+#[derive(Debug, Default)]
+struct Message {
+    id: u16,
+    response: bool,
+    questions: Vec<Question>,
+    answers: Vec<Record>,
+    additionals: Vec<Record>,
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(self.id.to_be_bytes());
+        // a response is authoritative
+        let flags: u16 = if self.response { 0x8400 } else { 0 };
+        out.extend(flags.to_be_bytes());
+        for count in [
+            self.questions.len(),
+            self.answers.len(),
+            0,
+            self.additionals.len(),
+        ] {
+            out.extend((count as u16).to_be_bytes());
+        }
+        for question in &self.questions {
+            encode_name(&mut out, &question.name);
+            out.extend(question.kind.to_be_bytes());
+            out.extend(question.class.to_be_bytes());
+        }
+        for record in self.answers.iter().chain(&self.additionals) {
+            encode_name(&mut out, &record.name);
+            out.extend(record.kind.to_be_bytes());
+            // the records of a service are unique, except for the PTR ones
+            let class = if record.kind == TYPE_PTR {
+                CLASS_IN
+            } else {
+                CLASS_IN | CLASS_FLAG
+            };
+            out.extend(class.to_be_bytes());
+            out.extend(record.ttl.to_be_bytes());
+            let mut data = Vec::new();
+            match &record.data {
+                RData::Name(name) => encode_name(&mut data, name),
+                RData::Srv(port, target) => {
+                    // priority and weight
+                    data.extend([0, 0, 0, 0]);
+                    data.extend(port.to_be_bytes());
+                    encode_name(&mut data, target);
+                }
+                RData::Bytes(bytes) => data.extend(bytes),
+            }
+            out.extend((data.len() as u16).to_be_bytes());
+            out.extend(data);
+        }
+        out
+    }
+
+    fn parse(packet: &[u8]) -> Option<Message> {
+        let mut reader = Reader { packet, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let questions = reader.u16()?;
+        let answers = reader.u16()?;
+        let authorities = reader.u16()?;
+        let additionals = reader.u16()?;
+        let mut message = Message {
+            id,
+            response: flags & 0x8000 != 0,
+            ..Message::default()
+        };
+        for _ in 0..questions {
+            message.questions.push(Question {
+                name: reader.name()?,
+                kind: reader.u16()?,
+                class: reader.u16()?,
+            });
+        }
+        for index in 0..answers + authorities + additionals {
+            let record = reader.record()?;
+            if index < answers {
+                message.answers.push(record);
+            } else if index >= answers + authorities {
+                message.additionals.push(record);
+            }
+        }
+        Some(message)
+    }
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &[String]) {
+    for label in name {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend(label);
+    }
+    out.push(0);
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.packet.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.bytes(4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Read a name, following compression pointers.
+    fn name(&mut self) -> Option<Vec<String>> {
+        let mut name = Vec::new();
+        let mut pos = self.pos;
+        let mut end = None;
+        // every pointer must go backwards, so that they cannot loop
+        let mut limit = pos;
+        loop {
+            let len = *self.packet.get(pos)? as usize;
+            match len {
+                0 => {
+                    pos += 1;
+                    break;
+                }
+                len if len & 0xc0 == 0xc0 => {
+                    let target = ((len & 0x3f) << 8) | *self.packet.get(pos + 1)? as usize;
+                    if target >= limit {
+                        return None;
+                    }
+                    end.get_or_insert(pos + 2);
+                    limit = target;
+                    pos = target;
+                }
+                len if len < 64 => {
+                    let label = self.packet.get(pos + 1..pos + 1 + len)?;
+                    name.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + len;
+                }
+                _ => return None,
+            }
+        }
+        self.pos = end.unwrap_or(pos);
+        Some(name)
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let name = self.name()?;
+        let kind = self.u16()?;
+        let _class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = self.u16()? as usize;
+        let start = self.pos;
+        let data = match kind {
+            TYPE_PTR => RData::Name(self.name()?),
+            TYPE_SRV => {
+                self.bytes(4)?;
+                let port = self.u16()?;
+                RData::Srv(port, self.name()?)
+            }
+            _ => RData::Bytes(self.bytes(len)?.to_vec()),
+        };
+        // skip whatever the data holds beyond what was read
+        self.pos = start
+            .checked_add(len)
+            .filter(|end| *end <= self.packet.len())?;
+        Some(Record::new(name, kind, ttl, data))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn service() -> ServiceInfo {
+        ServiceInfo::new("Kitchen sensor", "kitchen.local", 5683)
+            .with_address("192.0.2.20".parse().unwrap())
+            .with_path("/sensors")
+    }
+
+    #[test]
+    fn test_answer() {
+        let service = service();
+        let query = Message {
+            id: 7,
+            questions: vec![Question {
+                name: labels(SERVICE_TYPE),
+                kind: TYPE_PTR,
+                class: CLASS_IN,
+            }],
+            ..Message::default()
+        };
+        let answer = Message::parse(&service.answer(&query.encode(), true).unwrap()).unwrap();
+        assert_eq!(answer.id, 7);
+        assert!(answer.response);
+        assert_eq!(answer.questions, query.questions);
+        assert_eq!(answer.answers.len(), 1);
+        assert_eq!(answer.additionals.len(), 3);
+        assert!(answer.answers.iter().all(|record| record.ttl == LEGACY_TTL));
+
+        let sources = vec!["192.0.2.1".parse().unwrap(); 4];
+        let records: Vec<Record> = answer
+            .answers
+            .into_iter()
+            .chain(answer.additionals)
+            .collect();
+        assert_eq!(services(&records, &sources), std::slice::from_ref(&service));
+        assert_eq!(service.url().unwrap(), "coap://192.0.2.20:5683/sensors");
+
+        let query = Message {
+            questions: vec![Question {
+                name: labels("other.local"),
+                kind: TYPE_A,
+                class: CLASS_IN,
+            }],
+            ..Message::default()
+        };
+        assert_eq!(service.answer(&query.encode(), false), None);
+    }
+
+    #[test]
+    fn test_compression() {
+        // a PTR answer whose data points back to the question name
+        let mut packet = vec![0, 0, 0x84, 0, 0, 1, 0, 1, 0, 0, 0, 0];
+        encode_name(&mut packet, &labels(SERVICE_TYPE));
+        packet.extend([0, 12, 0, 1]);
+        packet.extend([0xc0, 12, 0, 12, 0, 1, 0, 0, 0x11, 0x94, 0, 7]);
+        packet.extend([4, b'n', b'o', b'd', b'e', 0xc0, 12]);
+        let message = Message::parse(&packet).unwrap();
+        assert_eq!(message.answers[0].name, labels(SERVICE_TYPE));
+        let mut instance = vec!["node".to_string()];
+        instance.extend(labels(SERVICE_TYPE));
+        assert_eq!(message.answers[0].data, RData::Name(instance));
+
+        // a pointer to itself
+        let packet = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 12, 0, 1];
+        assert!(Message::parse(&packet).is_none());
+    }
+
+    #[test]
+    fn test_browse() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            tx.send(socket.local_addr().unwrap()).unwrap();
+            let mut buf = [0; 1500];
+            let (len, source) = socket.recv_from(&mut buf).unwrap();
+            let answer = service().answer(&buf[..len], true).unwrap();
+            socket.send_to(&answer, source).unwrap();
+        });
+        let services = browse_at(rx.recv().unwrap(), Duration::from_millis(500)).unwrap();
+        assert_eq!(services, [service()]);
+    }
+}
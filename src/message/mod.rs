@@ -1,9 +1,70 @@
-use bytes::BytesMut;
+use bytes::{BufMut, BytesMut};
 use tokio::io;
 
 use tokio_util::codec::{Decoder, Encoder};
 
-use coap_lite::Packet;
+use coap_lite::{CoapOption, MessageClass, MessageType, Packet};
+use smallvec::SmallVec;
+use std::ops::Range;
+
+pub mod pool;
+
+/// Signaling messages (code class 7) of reliable transports
+/// ([RFC 8323](https://tools.ietf.org/html/rfc8323) section 5). They manage
+/// the connection and are never requests or responses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// Capabilities and Settings Message, 7.01.
+    Csm,
+    /// 7.02.
+    Ping,
+    /// 7.03.
+    Pong,
+    /// 7.04.
+    Release,
+    /// 7.05.
+    Abort,
+    /// Any other code of class 7.
+    Unknown(u8),
+}
+
+impl Signal {
+    /// Classify a packet by its code, returning `None` for anything but a
+    /// signaling message.
+    pub fn from_packet(packet: &Packet) -> Option<Signal> {
+        Signal::from_code(u8::from(packet.header.code))
+    }
+
+    /// Classify a raw message code.
+    pub fn from_code(code: u8) -> Option<Signal> {
+        match code {
+            0xe1 => Some(Signal::Csm),
+            0xe2 => Some(Signal::Ping),
+            0xe3 => Some(Signal::Pong),
+            0xe4 => Some(Signal::Release),
+            0xe5 => Some(Signal::Abort),
+            code if code >> 5 == 7 => Some(Signal::Unknown(code)),
+            _ => None,
+        }
+    }
+
+    /// Return the message code.
+    pub fn code(self) -> u8 {
+        match self {
+            Signal::Csm => 0xe1,
+            Signal::Ping => 0xe2,
+            Signal::Pong => 0xe3,
+            Signal::Release => 0xe4,
+            Signal::Abort => 0xe5,
+            Signal::Unknown(code) => code,
+        }
+    }
+
+    /// Return the message class to put in a packet header.
+    pub fn message_class(self) -> MessageClass {
+        MessageClass::from(self.code())
+    }
+}
 
 pub struct Codec {}
 
@@ -21,23 +82,445 @@ impl Decoder for Codec {
         if buf.len() == 0 {
             return Ok(None);
         }
-        let result = (|| {
-            let packet = Ok(Some(Packet::from_bytes(buf).map_err(|cause| {
-                io::Error::new(io::ErrorKind::InvalidData, cause.to_string())
-            })?));
-            packet
-        })();
+        let result = decode_datagram(buf).map(Some);
         buf.clear();
         result
     }
 }
 
+/// Decode a message received over an unreliable transport.
+pub(crate) fn decode_datagram(bytes: &[u8]) -> Result<Packet, io::Error> {
+    let packet = decode_packet(bytes)?;
+    // signaling is only defined for reliable transports
+    if Signal::from_packet(&packet).is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "signaling message over an unreliable transport",
+        ));
+    }
+    Ok(packet)
+}
+
 impl Encoder<Packet> for Codec {
     type Error = io::Error;
 
     fn encode(&mut self, my_packet: Packet, buf: &mut BytesMut) -> Result<(), io::Error> {
-        buf.extend_from_slice(&my_packet.to_bytes()
-        .map_err(|cause| io::Error::new(io::ErrorKind::InvalidData, cause.to_string()))?[..]);
-        Ok(())
+        buf.reserve(encoded_len(&my_packet));
+        write_packet(&my_packet, buf)
+    }
+}
+
+/// Serialize a message in the UDP message format into `buf`.
+pub(crate) fn write_packet<B: BufMut>(packet: &Packet, buf: &mut B) -> Result<(), io::Error> {
+    let tkl = token_length(packet)?;
+    let message_type = match packet.header.get_type() {
+        MessageType::Confirmable => 0,
+        MessageType::NonConfirmable => 1,
+        MessageType::Acknowledgement => 2,
+        MessageType::Reset => 3,
+    };
+    buf.put_u8(packet.header.get_version() << 6 | message_type << 4 | tkl);
+    buf.put_u8(packet.header.code.into());
+    buf.put_u16(packet.header.message_id);
+    write_body(packet, buf)
+}
+
+/// Serialize the token, options and payload of a message, the part shared
+/// by the UDP and the reliable message formats. Only the header around
+/// them differs.
+pub(crate) fn write_body<B: BufMut>(packet: &Packet, buf: &mut B) -> Result<(), io::Error> {
+    token_length(packet)?;
+    buf.put_slice(packet.get_token());
+    let mut last = 0;
+    for (&number, values) in packet.options() {
+        for value in values {
+            if value.len() > 65804 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "option too long"));
+            }
+            let (header, len) = option_header(usize::from(number - last), value.len());
+            last = number;
+            buf.put_slice(&header[..len]);
+            buf.put_slice(value);
+        }
+    }
+    if !packet.payload.is_empty() {
+        buf.put_u8(0xff);
+        buf.put_slice(&packet.payload);
+    }
+    Ok(())
+}
+
+/// Return the size of a message in the UDP message format.
+pub(crate) fn encoded_len(packet: &Packet) -> usize {
+    4 + body_len(packet)
+}
+
+/// Return the size of the part written by `write_body`.
+fn body_len(packet: &Packet) -> usize {
+    let mut len = packet.get_token().len();
+    let mut last = 0;
+    for (&number, values) in packet.options() {
+        for value in values {
+            let delta = usize::from(number - last);
+            last = number;
+            len += 1 + extended_len(delta) + extended_len(value.len()) + value.len();
+        }
+    }
+    if !packet.payload.is_empty() {
+        len += 1 + packet.payload.len();
+    }
+    len
+}
+
+// TODO: extended token lengths (RFC 8974) need a coap-lite change first.
+// Its Header packs the token length into the 4 bits below the version and
+// type, so Packet::set_token cannot hold tokens of 16 bytes or more.
+fn token_length(packet: &Packet) -> Result<u8, io::Error> {
+    match packet.get_token().len() {
+        len @ 0..=8 => Ok(len as u8),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "token too long")),
+    }
+}
+
+/// The value an extended option delta or length of 0, 1 or 2 bytes is
+/// relative to.
+const EXTENDED_BASE: [usize; 3] = [0, 13, 269];
+
+/// Return the length of the extended field of an option delta or length,
+/// which must be at most 65804.
+#[inline]
+fn extended_len(n: usize) -> usize {
+    usize::from(n > 12) + usize::from(n > 268)
+}
+
+/// Return the header of an option, the byte of the 4-bit delta and length
+/// fields followed by their extended fields, and its length. The fields are
+/// looked up rather than matched, so that the header of the options of a
+/// small message is written without branching on their sizes.
+#[inline]
+fn option_header(delta: usize, len: usize) -> ([u8; 5], usize) {
+    let (delta_len, len_len) = (extended_len(delta), extended_len(len));
+    let mut header = [0; 5];
+    header[0] = [delta as u8, 13, 14][delta_len] << 4 | [len as u8, 13, 14][len_len];
+    let delta = ((delta - EXTENDED_BASE[delta_len]) as u16).to_be_bytes();
+    header[1..1 + delta_len].copy_from_slice(&delta[2 - delta_len..]);
+    let at = 1 + delta_len;
+    let len = ((len - EXTENDED_BASE[len_len]) as u16).to_be_bytes();
+    header[at..at + len_len].copy_from_slice(&len[2 - len_len..]);
+    (header, at + len_len)
+}
+
+/// Parse a message in the UDP message format.
+fn decode_packet(bytes: &[u8]) -> Result<Packet, io::Error> {
+    if bytes.len() < 4 {
+        return Err(invalid("message too short"));
+    }
+    let mut packet = read_body(bytes[0] & 0x0f, bytes[1], &bytes[4..])?;
+    packet.header.set_version(bytes[0] >> 6);
+    packet.header.set_type(match (bytes[0] >> 4) & 0x03 {
+        0 => MessageType::Confirmable,
+        1 => MessageType::NonConfirmable,
+        2 => MessageType::Acknowledgement,
+        _ => MessageType::Reset,
+    });
+    packet.header.message_id = u16::from_be_bytes([bytes[2], bytes[3]]);
+    Ok(packet)
+}
+
+/// Parse a message from its token length, code and the part written by
+/// `write_body`. The header fields of the UDP message format that reliable
+/// transports lack are left at their defaults.
+pub(crate) fn join_header(tkl: u8, code: u8, rest: &[u8]) -> Result<Packet, io::Error> {
+    read_body(tkl, code, rest)
+}
+
+/// The options a message may carry before decoding it allocates for them.
+const INLINE_OPTIONS: usize = 16;
+
+/// Parse the token, options and payload of a message, the inverse of
+/// `write_body`. The options are located first, so that their values are
+/// copied once each into the lists of the message.
+fn read_body(tkl: u8, code: u8, bytes: &[u8]) -> Result<Packet, io::Error> {
+    let tkl = usize::from(tkl);
+    if tkl > 8 || bytes.len() < tkl {
+        return Err(invalid("invalid token length"));
+    }
+    let mut options: SmallVec<[(u16, Range<usize>); INLINE_OPTIONS]> = SmallVec::new();
+    let mut at = tkl;
+    let mut number = 0u16;
+    while at < bytes.len() && bytes[at] != 0xff {
+        let byte = bytes[at];
+        at += 1;
+        let delta = read_extended(bytes, &mut at, byte >> 4, "invalid option delta")?;
+        let len = read_extended(bytes, &mut at, byte & 0x0f, "invalid option length")?;
+        number = u16::try_from(delta)
+            .ok()
+            .and_then(|delta| number.checked_add(delta))
+            .ok_or_else(|| invalid("invalid option delta"))?;
+        if bytes.len() - at < len {
+            return Err(invalid("invalid option length"));
+        }
+        options.push((number, at..at + len));
+        at += len;
+    }
+
+    let mut packet = Packet::new();
+    packet.header.code = MessageClass::from(code);
+    packet.set_token(bytes[..tkl].to_vec());
+    // the numbers are in order, so the values of an option are adjacent
+    let mut rest = &options[..];
+    while let Some(&(number, _)) = rest.first() {
+        let count = rest.iter().take_while(|(n, _)| *n == number).count();
+        let values = rest[..count]
+            .iter()
+            .map(|(_, range)| bytes[range.clone()].to_vec())
+            .collect();
+        packet.set_option(CoapOption::from(number), values);
+        rest = &rest[count..];
+    }
+    // TODO: a copy, as long as coap-lite's Packet owns its payload as a
+    // Vec<u8>. Sharing the received buffer needs a Packet holding Bytes.
+    if at < bytes.len() {
+        packet.payload = bytes[at + 1..].to_vec();
+    }
+    Ok(packet)
+}
+
+/// Read the option delta or length given by the 4-bit field `nibble` and
+/// the extended field at `at`, if any.
+fn read_extended(bytes: &[u8], at: &mut usize, nibble: u8, error: &str) -> io::Result<usize> {
+    let (len, base) = match nibble {
+        0..=12 => return Ok(usize::from(nibble)),
+        13 => (1, 13),
+        14 => (2, 269),
+        _ => return Err(invalid(error)),
+    };
+    let field = bytes.get(*at..*at + len).ok_or_else(|| invalid(error))?;
+    *at += len;
+    Ok(field.iter().fold(0, |n, &byte| n << 8 | usize::from(byte)) + base)
+}
+
+fn invalid(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// Codec for the message format of CoAP over TCP and TLS
+/// ([RFC 8323](https://tools.ietf.org/html/rfc8323) section 3.2): the UDP
+/// header is replaced by a length prefix with extended length fields, and
+/// there is no Message ID or message type.
+pub struct TcpCodec {
+    max_message_size: u32,
+}
+
+impl TcpCodec {
+    /// Create a codec that rejects incoming messages larger than
+    /// `max_message_size` bytes.
+    pub fn new(max_message_size: u32) -> TcpCodec {
+        TcpCodec { max_message_size }
+    }
+}
+
+impl Decoder for TcpCodec {
+    type Item = Packet;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Packet>, io::Error> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let nibble = buf[0] >> 4;
+        let tkl = buf[0] & 0x0f;
+        let extended = match nibble {
+            13 => 1,
+            14 => 2,
+            15 => 4,
+            _ => 0,
+        };
+        if buf.len() < 1 + extended {
+            return Ok(None);
+        }
+        let length = match nibble {
+            13 => buf[1] as usize + 13,
+            14 => u16::from_be_bytes([buf[1], buf[2]]) as usize + 269,
+            15 => u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]) as usize + 65805,
+            n => n as usize,
+        };
+        if tkl > 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "token too long"));
+        }
+        if length + tkl as usize + 1 > self.max_message_size as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "message too large"));
+        }
+
+        let header = 1 + extended;
+        let total = header + 1 + tkl as usize + length;
+        if buf.len() < total {
+            buf.reserve(total - buf.len());
+            return Ok(None);
+        }
+
+        let frame = buf.split_to(total);
+        join_header(tkl, frame[header], &frame[header + 1..]).map(Some)
+    }
+}
+
+impl Encoder<Packet> for TcpCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, packet: Packet, buf: &mut BytesMut) -> Result<(), io::Error> {
+        let tkl = token_length(&packet)?;
+        let body = body_len(&packet);
+        // the length covers options and payload, but not the token
+        let length = body - tkl as usize;
+
+        buf.reserve(6 + body);
+        match length {
+            0..=12 => buf.put_u8((length as u8) << 4 | tkl),
+            13..=268 => {
+                buf.put_u8(13 << 4 | tkl);
+                buf.put_u8((length - 13) as u8);
+            }
+            269..=65804 => {
+                buf.put_u8(14 << 4 | tkl);
+                buf.put_u16((length - 269) as u16);
+            }
+            _ => {
+                buf.put_u8(15 << 4 | tkl);
+                buf.put_u32((length - 65805) as u32);
+            }
+        }
+        buf.put_u8(packet.header.code.into());
+        write_body(&packet, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use coap_lite::{CoapOption, RequestType as Method};
+
+    #[test]
+    fn test_signal() {
+        assert_eq!(Signal::from_code(0xe2), Some(Signal::Ping));
+        assert_eq!(Signal::from_code(0xe7), Some(Signal::Unknown(0xe7)));
+        assert_eq!(Signal::from_code(0x45), None);
+        for signal in [Signal::Csm, Signal::Pong, Signal::Release, Signal::Abort] {
+            assert_eq!(Signal::from_code(signal.code()), Some(signal));
+        }
+
+        let mut codec = Codec::new();
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(Method::Get);
+        let mut buf = BytesMut::from(&packet.to_bytes().unwrap()[..]);
+        assert!(codec.decode(&mut buf).unwrap().is_some());
+
+        packet.header.code = Signal::Ping.message_class();
+        let mut buf = BytesMut::from(&packet.to_bytes().unwrap()[..]);
+        assert!(codec.decode(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_write_packet() {
+        let mut packet = Packet::new();
+        packet.header.message_id = 0x1234;
+        packet.header.set_type(MessageType::NonConfirmable);
+        packet.header.code = MessageClass::Request(Method::Put);
+        packet.set_token(vec![1, 2, 3, 4]);
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::UriPath, vec![b'b'; 20]);
+        packet.add_option(CoapOption::Size1, vec![0; 300]);
+        packet.add_option(CoapOption::Unknown(2000), vec![]);
+        packet.add_option(CoapOption::Unknown(65000), vec![7]);
+        for payload in [vec![], vec![0xff; 100]] {
+            packet.payload = payload;
+            let mut bytes = BytesMut::new();
+            Codec::new().encode(packet.clone(), &mut bytes).unwrap();
+            assert_eq!(bytes[..], packet.to_bytes().unwrap()[..]);
+            assert_eq!(encoded_len(&packet), bytes.len());
+        }
+
+        packet.add_option(CoapOption::UriQuery, vec![0; 65805]);
+        assert!(write_packet(&packet, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_option_header() {
+        let sizes = [0, 1, 12, 13, 14, 268, 269, 270, 1000, 65535, 65804];
+        for delta in sizes.into_iter().filter(|delta| *delta <= 65535) {
+            for len in sizes {
+                let mut packet = Packet::new();
+                packet.add_option(CoapOption::from(delta as u16), vec![0; len]);
+                let bytes = packet.to_bytes().unwrap();
+                let (header, header_len) = option_header(delta, len);
+                assert_eq!(header[..header_len], bytes[4..4 + header_len]);
+                assert_eq!(encoded_len(&packet), bytes.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_packet() {
+        let mut packet = Packet::new();
+        packet.header.message_id = 0x1234;
+        packet.header.set_type(MessageType::Acknowledgement);
+        packet.header.code = MessageClass::Request(Method::Get);
+        packet.set_token(vec![9; 8]);
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.add_option(CoapOption::UriPath, vec![b'b'; 20]);
+        packet.add_option(CoapOption::Size1, vec![0; 300]);
+        packet.add_option(CoapOption::Unknown(65000), vec![7]);
+        for payload in [vec![], vec![0xff; 100]] {
+            packet.payload = payload;
+            let bytes = packet.to_bytes().unwrap();
+            let decoded = decode_packet(&bytes).unwrap();
+            assert_eq!(decoded.to_bytes().unwrap(), bytes);
+            assert_eq!(decoded.header.message_id, 0x1234);
+            assert_eq!(decoded.header.get_type(), MessageType::Acknowledgement);
+        }
+
+        // no payload after the marker is none at all
+        assert!(decode_packet(&[0x40, 0x01, 0, 0, 0xff]).unwrap().payload.is_empty());
+        for malformed in [
+            &[0x40, 0x01, 0][..],
+            // token longer than 8 bytes or than the message
+            &[0x49, 0x01, 0, 0],
+            &[0x42, 0x01, 0, 0, 1],
+            // reserved delta and length
+            &[0x40, 0x01, 0, 0, 0xf0],
+            &[0x40, 0x01, 0, 0, 0x1f],
+            // missing extended field and value
+            &[0x40, 0x01, 0, 0, 0xe0, 1],
+            &[0x40, 0x01, 0, 0, 0x12, 1],
+            // option number beyond 65535
+            &[0x40, 0x01, 0, 0, 0xe0, 0xff, 0xff, 0xe0, 0xff, 0xff],
+        ] {
+            assert!(decode_packet(malformed).is_err(), "{:?}", malformed);
+        }
+    }
+
+    #[test]
+    fn test_codec_round_trip() {
+        let mut codec = TcpCodec::new(u32::MAX);
+        for size in [0, 5, 100, 1000, 70000] {
+            let mut packet = Packet::new();
+            packet.header.code = MessageClass::Request(Method::Post);
+            packet.set_token(vec![1, 2, 3]);
+            packet.add_option(CoapOption::UriPath, b"test".to_vec());
+            packet.payload = vec![0x55; size];
+
+            let mut buf = BytesMut::new();
+            codec.encode(packet.clone(), &mut buf).unwrap();
+            let partial = buf.split_to(buf.len() - 1);
+            let mut partial_buf = partial.clone();
+            assert!(codec.decode(&mut partial_buf).unwrap().is_none());
+
+            let mut full = partial;
+            full.unsplit(buf);
+            let decoded = codec.decode(&mut full).unwrap().unwrap();
+            assert!(full.is_empty());
+            assert_eq!(decoded.get_token(), packet.get_token());
+            assert_eq!(decoded.payload, packet.payload);
+            assert_eq!(decoded.header.code, packet.header.code);
+        }
     }
 }
@@ -0,0 +1,181 @@
+//! Reusable buffers for encoding and decoding messages.
+//!
+//! Transports that hand a message to the socket as bytes need a buffer for
+//! each one. Taking it from a [`BufferPool`] reuses the buffers of earlier
+//! messages instead of allocating a new one per packet; the codecs and the
+//! datagram transports use [`BufferPool::global`].
+//!
+//! Buffers are allocated with the capacity of the largest expected message,
+//! by default [`DEFAULT_MAX_MESSAGE_SIZE`]. A buffer that had to grow beyond
+//! it for a larger message is dropped when it is returned, so that a few
+//! large messages do not pin their memory in the pool.
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+pub use crate::mtu::DEFAULT_MAX_MESSAGE_SIZE;
+
+/// How many idle buffers a pool keeps by default.
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// Counters of a [`BufferPool`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers allocated because no idle one was available.
+    pub allocated: u64,
+    /// Buffers taken from the idle ones.
+    pub reused: u64,
+    /// Returned buffers that were dropped, because they had grown beyond
+    /// the buffer size or the pool was full.
+    pub discarded: u64,
+    /// Buffers currently idle in the pool.
+    pub idle: usize,
+}
+
+/// A pool of buffers for serialized messages.
+pub struct BufferPool {
+    idle: Mutex<Vec<Vec<u8>>>,
+    buffer_size: AtomicUsize,
+    capacity: AtomicUsize,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl BufferPool {
+    /// Create a pool of buffers of `buffer_size` bytes, keeping at most
+    /// `capacity` idle ones.
+    pub fn new(buffer_size: usize, capacity: usize) -> BufferPool {
+        BufferPool {
+            idle: Mutex::new(Vec::new()),
+            buffer_size: AtomicUsize::new(buffer_size),
+            capacity: AtomicUsize::new(capacity),
+            allocated: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+        }
+    }
+
+    /// Return the pool shared by the codecs and transports of this crate.
+    pub fn global() -> &'static BufferPool {
+        static GLOBAL: OnceLock<BufferPool> = OnceLock::new();
+        GLOBAL.get_or_init(|| BufferPool::new(DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_CAPACITY))
+    }
+
+    /// Size new buffers for messages of up to `size` bytes, e.g. the
+    /// configured maximum message size. The idle buffers of the previous
+    /// size are dropped.
+    pub fn set_buffer_size(&self, size: usize) {
+        self.buffer_size.store(size, Ordering::Relaxed);
+        self.idle.lock().unwrap().clear();
+    }
+
+    /// Keep at most `capacity` idle buffers.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        self.idle.lock().unwrap().truncate(capacity);
+    }
+
+    /// Take an empty buffer, which returns to the pool when dropped.
+    pub fn take(&self) -> PooledBuffer<'_> {
+        let buffer = match self.idle.lock().unwrap().pop() {
+            Some(buffer) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(self.buffer_size.load(Ordering::Relaxed))
+            }
+        };
+        PooledBuffer { buffer, pool: self }
+    }
+
+    /// Return the counters of the pool.
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            allocated: self.allocated.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            discarded: self.discarded.load(Ordering::Relaxed),
+            idle: self.idle.lock().unwrap().len(),
+        }
+    }
+
+    fn give(&self, mut buffer: Vec<u8>) {
+        let mut idle = self.idle.lock().unwrap();
+        if buffer.capacity() > self.buffer_size.load(Ordering::Relaxed)
+            || idle.len() >= self.capacity.load(Ordering::Relaxed)
+        {
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        buffer.clear();
+        idle.push(buffer);
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("buffer_size", &self.buffer_size.load(Ordering::Relaxed))
+            .field("capacity", &self.capacity.load(Ordering::Relaxed))
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+/// A buffer taken from a [`BufferPool`].
+#[derive(Debug)]
+pub struct PooledBuffer<'a> {
+    buffer: Vec<u8>,
+    pool: &'a BufferPool,
+}
+
+impl Deref for PooledBuffer<'_> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer<'_> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.give(std::mem::take(&mut self.buffer));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pool() {
+        let pool = BufferPool::new(16, 1);
+        let mut first = pool.take();
+        first.extend_from_slice(b"hello");
+        let second = pool.take();
+        assert_eq!(pool.stats().allocated, 2);
+        drop(first);
+        // the pool is full
+        drop(second);
+        let stats = pool.stats();
+        assert_eq!((stats.discarded, stats.idle), (1, 1));
+
+        let mut buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!(pool.stats().reused, 1);
+        // grown beyond the buffer size
+        buffer.extend_from_slice(&[0; 17]);
+        drop(buffer);
+        let stats = pool.stats();
+        assert_eq!((stats.discarded, stats.idle), (2, 0));
+    }
+}
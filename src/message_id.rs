@@ -0,0 +1,171 @@
+//! Message ID allocation ([RFC 7252](https://tools.ietf.org/html/rfc7252)
+//! section 4.4).
+//!
+//! A Message ID must not be reused towards the same endpoint within
+//! `EXCHANGE_LIFETIME`, or the endpoint takes the message for a duplicate
+//! and a late acknowledgement for a new message. [`MessageIds`] allocates
+//! them per endpoint, starting at an unpredictable value and wrapping
+//! around, and skips the IDs still in use.
+use log::warn;
+use lru_time_cache::LruCache;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashSet, VecDeque};
+use std::hash::BuildHasher;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// How long a Message ID stays in use, with the default transmission
+/// parameters.
+pub const EXCHANGE_LIFETIME: Duration = Duration::from_secs(247);
+
+/// The IDs in use towards one endpoint, oldest first.
+#[derive(Debug)]
+struct Peer {
+    next: u16,
+    used: VecDeque<(u16, Instant)>,
+    in_use: HashSet<u16>,
+}
+
+impl Peer {
+    fn new(next: u16) -> Peer {
+        Peer {
+            next,
+            used: VecDeque::new(),
+            in_use: HashSet::new(),
+        }
+    }
+
+    fn expire(&mut self, now: Instant, lifetime: Duration) {
+        while let Some(&(id, allocated)) = self.used.front() {
+            if now.duration_since(allocated) < lifetime {
+                break;
+            }
+            self.used.pop_front();
+            self.in_use.remove(&id);
+        }
+    }
+
+    fn insert(&mut self, id: u16, now: Instant) {
+        if self.in_use.insert(id) {
+            self.used.push_back((id, now));
+        }
+    }
+}
+
+/// Allocates Message IDs per endpoint.
+pub struct MessageIds {
+    peers: LruCache<SocketAddr, Peer>,
+    lifetime: Duration,
+    seed: RandomState,
+}
+
+impl MessageIds {
+    /// Create an allocator keeping IDs in use for [`EXCHANGE_LIFETIME`].
+    pub fn new() -> MessageIds {
+        MessageIds::with_lifetime(EXCHANGE_LIFETIME)
+    }
+
+    /// Create an allocator keeping IDs in use for `lifetime`, e.g. the
+    /// exchange lifetime of custom transmission parameters.
+    pub fn with_lifetime(lifetime: Duration) -> MessageIds {
+        MessageIds {
+            // an endpoint unused for the lifetime has no IDs in use
+            peers: LruCache::with_expiry_duration(lifetime),
+            lifetime,
+            seed: RandomState::new(),
+        }
+    }
+
+    /// Allocate the next Message ID towards `peer`. If all IDs are in use,
+    /// the oldest one is reused.
+    pub fn next(&mut self, peer: SocketAddr) -> u16 {
+        let now = Instant::now();
+        let peer_ids = self.peer(peer, now);
+        if peer_ids.used.len() > usize::from(u16::MAX) {
+            warn!("all message IDs towards {} are in use", peer);
+            if let Some((id, _)) = peer_ids.used.pop_front() {
+                peer_ids.in_use.remove(&id);
+            }
+        }
+        let mut id = peer_ids.next;
+        while peer_ids.in_use.contains(&id) {
+            id = id.wrapping_add(1);
+        }
+        peer_ids.next = id.wrapping_add(1);
+        peer_ids.insert(id, now);
+        id
+    }
+
+    /// Mark `id` as in use towards `peer`, e.g. for a message whose ID was
+    /// chosen by the application.
+    pub fn reserve(&mut self, peer: SocketAddr, id: u16) {
+        let now = Instant::now();
+        self.peer(peer, now).insert(id, now);
+    }
+
+    /// Return the IDs towards `peer` without the expired ones.
+    fn peer(&mut self, peer: SocketAddr, now: Instant) -> &mut Peer {
+        if !self.peers.contains_key(&peer) {
+            // an unpredictable start, as RFC 7252 recommends
+            let start = self.seed.hash_one(peer) as u16;
+            self.peers.insert(peer, Peer::new(start));
+        }
+        let lifetime = self.lifetime;
+        let peer_ids = self.peers.get_mut(&peer).unwrap();
+        peer_ids.expire(now, lifetime);
+        peer_ids
+    }
+}
+
+impl Default for MessageIds {
+    fn default() -> MessageIds {
+        MessageIds::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_per_peer() {
+        let mut ids = MessageIds::new();
+        let a: SocketAddr = "192.0.2.1:5683".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:5683".parse().unwrap();
+        let first = ids.next(a);
+        assert_eq!(ids.next(a), first.wrapping_add(1));
+        // other endpoints have sequences of their own
+        let other = ids.next(b);
+        assert_eq!(ids.next(b), other.wrapping_add(1));
+        assert_eq!(ids.next(a), first.wrapping_add(2));
+
+        // reserved IDs are skipped
+        ids.reserve(a, first.wrapping_add(3));
+        assert_eq!(ids.next(a), first.wrapping_add(4));
+    }
+
+    #[test]
+    fn test_rollover() {
+        let mut ids = MessageIds::new();
+        let peer: SocketAddr = "192.0.2.1:5683".parse().unwrap();
+        let mut seen = HashSet::new();
+        for _ in 0..=u16::MAX {
+            // no ID is reused while others are free
+            assert!(seen.insert(ids.next(peer)));
+        }
+        // all in use: the oldest is reused
+        let oldest = ids.peers.get(&peer).unwrap().used.front().unwrap().0;
+        assert_eq!(ids.next(peer), oldest);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut ids = MessageIds::with_lifetime(Duration::from_millis(50));
+        let peer: SocketAddr = "192.0.2.1:5683".parse().unwrap();
+        let first = ids.next(peer);
+        assert_eq!(ids.next(peer), first.wrapping_add(1));
+        std::thread::sleep(Duration::from_millis(60));
+        // the IDs are free again and the sequence starts over
+        assert_eq!(ids.next(peer), first);
+    }
+}
@@ -0,0 +1,112 @@
+//! Path MTU assumptions used to size messages.
+//!
+//! A CoAP message should fit into a single IP packet: fragmented datagrams
+//! are lost more often, and on 6LoWPAN links every fragment costs a radio
+//! frame. When the path MTU to a destination is unknown, 1280 bytes are
+//! assumed (the IPv6 minimum) and messages are kept to 1152 bytes (RFC 7252
+//! section 4.6), leaving room for IP options and tunnels. Larger payloads
+//! are moved into block-wise transfers.
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Path MTU assumed for destinations without a configured value.
+pub const DEFAULT_PATH_MTU: usize = 1280;
+
+/// Largest message sent to destinations without a configured path MTU.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1152;
+
+/// Size of the UDP header.
+const UDP_HEADER_SIZE: usize = 8;
+
+/// Smallest and largest block size of block-wise transfers (RFC 7959).
+pub(crate) const MIN_BLOCK_SIZE: usize = 16;
+const MAX_BLOCK_SIZE: usize = 1024;
+
+/// Path MTUs of destinations.
+#[derive(Debug, Clone, Default)]
+pub struct PathMtu {
+    default: Option<usize>,
+    destinations: HashMap<IpAddr, usize>,
+}
+
+impl PathMtu {
+    pub fn new() -> PathMtu {
+        PathMtu::default()
+    }
+
+    /// Set the path MTU of destinations without their own value. `None`
+    /// restores the default assumption.
+    pub fn set_default(&mut self, mtu: Option<usize>) {
+        self.default = mtu;
+    }
+
+    /// Set the path MTU to a destination.
+    pub fn set(&mut self, addr: IpAddr, mtu: usize) {
+        self.destinations.insert(addr, mtu);
+    }
+
+    /// Forget the path MTU of a destination.
+    pub fn remove(&mut self, addr: IpAddr) {
+        self.destinations.remove(&addr);
+    }
+
+    /// Return the configured path MTU to a destination, if any.
+    pub fn get(&self, addr: IpAddr) -> Option<usize> {
+        self.destinations.get(&addr).copied().or(self.default)
+    }
+
+    /// Return the largest CoAP message that reaches the destination without
+    /// IP fragmentation.
+    pub fn max_message_size(&self, addr: IpAddr) -> usize {
+        let ip_header_size = match addr {
+            IpAddr::V4(_) => 20,
+            IpAddr::V6(_) => 40,
+        };
+        match self.get(addr) {
+            Some(mtu) => mtu.saturating_sub(ip_header_size + UDP_HEADER_SIZE),
+            None => DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+/// Return the largest block size for which a message with `overhead` bytes
+/// of header and options stays within `max_message_size`, or `None` if not
+/// even the smallest block fits.
+pub fn block_size(max_message_size: usize, overhead: usize) -> Option<usize> {
+    let mut size = MAX_BLOCK_SIZE;
+    while overhead + size > max_message_size {
+        if size == MIN_BLOCK_SIZE {
+            return None;
+        }
+        size /= 2;
+    }
+    Some(size)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_max_message_size() {
+        let mut path_mtu = PathMtu::new();
+        let v4: IpAddr = "192.0.2.1".parse().unwrap();
+        let v6: IpAddr = "2001:db8::1".parse().unwrap();
+        assert_eq!(path_mtu.max_message_size(v6), DEFAULT_MAX_MESSAGE_SIZE);
+
+        path_mtu.set_default(Some(1500));
+        assert_eq!(path_mtu.max_message_size(v4), 1472);
+        path_mtu.set(v6, 127);
+        assert_eq!(path_mtu.max_message_size(v6), 79);
+        path_mtu.remove(v6);
+        assert_eq!(path_mtu.max_message_size(v6), 1452);
+    }
+
+    #[test]
+    fn test_block_size() {
+        assert_eq!(block_size(1152, 20), Some(1024));
+        assert_eq!(block_size(1000, 20), Some(512));
+        assert_eq!(block_size(79, 20), Some(32));
+        assert_eq!(block_size(30, 20), None);
+    }
+}
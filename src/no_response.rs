@@ -0,0 +1,130 @@
+//! No-Response option
+//! ([RFC 7967](https://tools.ietf.org/html/rfc7967)).
+//!
+//! A client that is not interested in some responses, e.g. a sensor posting
+//! readings, lists the response classes to suppress in a No-Response option.
+//! The server then sends no such response, but still acknowledges a
+//! confirmable request. Handlers may check [`suppresses`] to skip building a
+//! payload that would be discarded anyway.
+use coap_lite::{
+    option_value::OptionValueU8, CoapOption, MessageClass, MessageType, Packet,
+    ResponseType as Status,
+};
+
+/// Option number of No-Response.
+pub const NO_RESPONSE: u16 = 258;
+
+/// Suppresses 2.xx responses.
+pub const SUCCESS: u8 = 2;
+
+/// Suppresses 4.xx responses.
+pub const CLIENT_ERROR: u8 = 8;
+
+/// Suppresses 5.xx responses.
+pub const SERVER_ERROR: u8 = 16;
+
+/// Return whether the No-Response option of `request` suppresses responses
+/// with `status`.
+pub fn suppresses(request: &Packet, status: Status) -> bool {
+    suppresses_code(request, MessageClass::Response(status))
+}
+
+fn suppresses_code(request: &Packet, code: MessageClass) -> bool {
+    let value = match request
+        .get_first_option_as::<OptionValueU8>(CoapOption::Unknown(NO_RESPONSE))
+        .and_then(|value| value.ok())
+    {
+        Some(value) => value.0,
+        None => return false,
+    };
+    let bit = match u8::from(code) >> 5 {
+        2 => SUCCESS,
+        4 => CLIENT_ERROR,
+        5 => SERVER_ERROR,
+        _ => return false,
+    };
+    value & bit != 0
+}
+
+/// Return the message to send in place of `response` to `request`: the
+/// response itself, an empty acknowledgement if it is suppressed but
+/// piggybacked, or `None`.
+pub(crate) fn filter(request: &Packet, response: Packet) -> Option<Packet> {
+    if !suppresses_code(request, response.header.code) {
+        return Some(response);
+    }
+    if response.header.get_type() != MessageType::Acknowledgement {
+        return None;
+    }
+    let mut ack = Packet::new();
+    ack.header.set_type(MessageType::Acknowledgement);
+    ack.header.code = MessageClass::Empty;
+    ack.header.message_id = response.header.message_id;
+    Some(ack)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_suppresses() {
+        let mut request = Packet::new();
+        assert!(!suppresses(&request, Status::Content));
+        request.add_option_as(
+            CoapOption::Unknown(NO_RESPONSE),
+            OptionValueU8(SUCCESS | SERVER_ERROR),
+        );
+        assert!(suppresses(&request, Status::Changed));
+        assert!(!suppresses(&request, Status::NotFound));
+        assert!(suppresses(&request, Status::InternalServerError));
+    }
+
+    #[test]
+    fn test_no_response() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|request: CoapRequest<SocketAddr>| async move {
+                            let mut response: CoapResponse = request.response?;
+                            response.set_status(Status::Changed);
+                            Some(response)
+                        })
+                        .await
+                        .unwrap();
+                });
+        });
+        let client = CoAPClient::new(rx.recv().unwrap()).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let send = |message_type: MessageType, no_response: u8| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Post);
+            request.set_path("/readings");
+            request.message.header.set_type(message_type);
+            request.message.set_token(vec![1]);
+            request
+                .message
+                .add_option_as(CoapOption::Unknown(NO_RESPONSE), OptionValueU8(no_response));
+            client.send(&request).unwrap();
+            client.receive()
+        };
+
+        let response = send(MessageType::NonConfirmable, CLIENT_ERROR).unwrap();
+        assert_eq!(*response.get_status(), Status::Changed);
+        assert!(send(MessageType::NonConfirmable, SUCCESS).is_err());
+        let ack = send(MessageType::Confirmable, SUCCESS).unwrap();
+        assert_eq!(ack.message.header.get_type(), MessageType::Acknowledgement);
+        assert_eq!(ack.message.header.code, MessageClass::Empty);
+    }
+}
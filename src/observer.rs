@@ -3,79 +3,190 @@ use coap_lite::{
     ResponseType as Status,
 };
 use futures::{
-    stream::{Fuse, SelectNextSome},
-    StreamExt,
+    future,
+    stream::{BoxStream, Fuse, SelectNextSome},
+    Future, StreamExt,
 };
 use log::{debug, warn};
 use std::{
-    collections::{hash_map::Entry, HashMap, HashSet},
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    hash::BuildHasher,
     net::SocketAddr,
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
 };
-use tokio::time::interval;
-use tokio_stream::wrappers::IntervalStream;
 
-use super::server::MessageSender;
+use super::message_id::MessageIds;
+use super::runtime::{Runtime, TokioRuntime};
 
 const DEFAULT_UNACKNOWLEDGE_MESSAGE_TRY_TIMES: usize = 10;
 
+/// How many notifications wait to be sent by default.
+pub const DEFAULT_NOTIFICATION_QUEUE_SIZE: usize = 1024;
+
+/// Number of shards the resources are split into by path.
+const SHARDS: usize = 16;
+
 pub struct Observer {
-    registers: HashMap<String, RegisterItem>,
-    resources: HashMap<String, ResourceItem>,
-    register_resources: HashMap<String, RegisterResourceItem>,
-    unacknowledge_messages: HashMap<u16, UnacknowledgeMessageItem>,
-    tx_sender: MessageSender,
-    current_message_id: u16,
-    timer: Fuse<IntervalStream>,
+    registry: Arc<ObserveRegistry>,
+    timer: Fuse<BoxStream<'static, ()>>,
 }
 
+/// The observed resources and their observers. The resources are split
+/// into shards that are locked independently, so handlers running on
+/// tasks of their own can notify the observers of a resource while the
+/// server registers others.
+pub struct ObserveRegistry {
+    shards: Vec<Mutex<Shard>>,
+    seed: RandomState,
+    message_ids: Mutex<MessageIds>,
+    queue: Mutex<NotificationQueue>,
+    /// The minimum interval between notifications, in milliseconds.
+    min_interval: AtomicU64,
+}
+
+/// The messages to observers that wait to be sent, oldest first.
 #[derive(Debug)]
-struct RegisterItem {
-    register_resources: HashSet<String>,
+struct NotificationQueue {
+    messages: VecDeque<(Packet, SocketAddr)>,
+    capacity: usize,
+    waker: Option<Waker>,
+}
+
+/// A notification by the address it was sent to and its message ID.
+type MessageKey = (SocketAddr, u16);
+
+#[derive(Debug, Default)]
+struct Shard {
+    resources: HashMap<String, ResourceItem>,
+    unacknowledge_messages: HashMap<MessageKey, UnacknowledgeMessageItem>,
 }
 
 #[derive(Debug)]
 struct ResourceItem {
     payload: Vec<u8>,
-    register_resources: HashSet<String>,
     sequence: u32,
+    registrations: HashMap<SocketAddr, Registration>,
 }
 
 #[derive(Debug)]
-struct RegisterResourceItem {
-    register: String,
-    resource: String,
+struct Registration {
     token: Vec<u8>,
-    unacknowledge_message: Option<u16>,
+    unacknowledge_message: Option<MessageKey>,
+    last_notified: Option<Instant>,
+    /// Whether a change is yet to be notified.
+    pending: bool,
 }
 
 #[derive(Debug)]
 struct UnacknowledgeMessageItem {
-    register_resource: String,
+    resource: String,
     try_times: usize,
 }
 
 impl Observer {
-    /// Creates an observer with channel to send message.
-    pub fn new(tx_sender: MessageSender) -> Observer {
+    /// Creates an observer.
+    pub fn new() -> Observer {
+        Observer::with_runtime(&TokioRuntime)
+    }
+
+    /// Creates an observer whose timer is provided by the given runtime.
+    pub fn with_runtime(runtime: &dyn Runtime) -> Observer {
         Observer {
-            registers: HashMap::new(),
-            resources: HashMap::new(),
-            register_resources: HashMap::new(),
-            unacknowledge_messages: HashMap::new(),
-            tx_sender: tx_sender,
-            current_message_id: 0,
-            timer: IntervalStream::new(interval(Duration::from_secs(1))).fuse(),
+            registry: Arc::new(ObserveRegistry::new()),
+            timer: runtime.interval(Duration::from_secs(1)).fuse(),
         }
     }
 
+    /// Return the registry of the observed resources, to be shared with
+    /// the tasks that change them.
+    pub fn registry(&self) -> Arc<ObserveRegistry> {
+        self.registry.clone()
+    }
+
     /// poll the observer's timer.
-    pub fn select_next_some(&mut self) -> SelectNextSome<Fuse<IntervalStream>> {
+    pub fn select_next_some(&mut self) -> SelectNextSome<'_, Fuse<BoxStream<'static, ()>>> {
         self.timer.select_next_some()
     }
 
     /// filter the requests belong to the observer.
     pub async fn request_handler(&mut self, request: &CoapRequest<SocketAddr>) -> bool {
+        self.registry.request_handler(request)
+    }
+
+    /// trigger send the unacknowledge messages.
+    pub async fn timer_handler(&mut self) {
+        self.registry.retransmit();
+        self.registry.flush_coalesced();
+    }
+
+    /// notify the registers of a removed resource with 4.04 Not Found and
+    /// forget the resource.
+    pub async fn resource_removed(&mut self, path: &str) {
+        self.registry.resource_removed(path);
+    }
+}
+
+impl Default for Observer {
+    fn default() -> Observer {
+        Observer::new()
+    }
+}
+
+impl ObserveRegistry {
+    /// Creates a registry queueing up to
+    /// [`DEFAULT_NOTIFICATION_QUEUE_SIZE`] notifications.
+    pub fn new() -> ObserveRegistry {
+        ObserveRegistry {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            seed: RandomState::new(),
+            message_ids: Mutex::new(MessageIds::new()),
+            queue: Mutex::new(NotificationQueue {
+                messages: VecDeque::new(),
+                capacity: DEFAULT_NOTIFICATION_QUEUE_SIZE,
+                waker: None,
+            }),
+            min_interval: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue up to `size` notifications. When the server falls behind
+    /// further, a new notification replaces a queued one to the same
+    /// observation, which it supersedes, or else the oldest one is dropped.
+    pub fn set_queue_size(&self, size: usize) {
+        self.queue.lock().unwrap().capacity = size.max(1);
+    }
+
+    /// Wait for notifications and take all queued ones, grouped by the
+    /// peer they go to, each group in the order they were queued.
+    pub fn notifications(&self) -> impl Future<Output = Vec<(SocketAddr, Vec<Packet>)>> + '_ {
+        future::poll_fn(move |cx| self.poll_notifications(cx))
+    }
+
+    fn poll_notifications(&self, cx: &mut Context<'_>) -> Poll<Vec<(SocketAddr, Vec<Packet>)>> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.messages.is_empty() {
+            queue.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let mut batches: Vec<(SocketAddr, Vec<Packet>)> = Vec::new();
+        let mut peers = HashMap::new();
+        for (message, address) in queue.messages.drain(..) {
+            let index = *peers.entry(address).or_insert_with(|| {
+                batches.push((address, Vec::new()));
+                batches.len() - 1
+            });
+            batches[index].1.push(message);
+        }
+        Poll::Ready(batches)
+    }
+
+    /// filter the requests belong to the observer.
+    pub fn request_handler(&self, request: &CoapRequest<SocketAddr>) -> bool {
         if request.message.header.get_type() == MessageType::Acknowledgement {
             self.acknowledge(request);
             return false;
@@ -84,329 +195,355 @@ impl Observer {
         match (request.get_method(), request.get_observe_flag()) {
             (&Method::Get, Some(observe_option)) => match observe_option {
                 Ok(ObserveOption::Register) => {
-                    self.register(request).await;
-                    return false;
+                    self.register(request);
+                    false
                 }
                 Ok(ObserveOption::Deregister) => {
                     self.deregister(request);
-                    return true;
+                    true
                 }
-                _ => return true,
+                _ => true,
             },
             (&Method::Put, _) => {
-                self.resource_changed(request).await;
-                return true;
+                self.resource_changed(&request.get_path(), request.message.payload.clone());
+                true
             }
-            _ => return true,
+            _ => true,
         }
     }
 
-    /// trigger send the unacknowledge messages.
-    pub async fn timer_handler(&mut self) {
-        let register_resource_keys: Vec<String>;
-        {
-            register_resource_keys = self
-                .unacknowledge_messages
-                .iter()
-                .map(|(_, msg)| msg.register_resource.clone())
-                .collect();
-        }
-
-        for register_resource_key in register_resource_keys {
-            if self.try_unacknowledge_message(&register_resource_key) {
-                self.notify_register_with_newest_resource(&register_resource_key)
-                    .await;
+    /// Set the representation of the resource at `path` and notify its
+    /// observers.
+    pub fn resource_changed(&self, path: &str, payload: Vec<u8>) {
+        debug!("resource_changed {} {:?}", path, payload);
+
+        let mut shard = self.shard(path);
+        let Shard {
+            resources,
+            unacknowledge_messages,
+        } = &mut *shard;
+        let resource = resources
+            .entry(path.to_string())
+            .and_modify(|resource| resource.sequence += 1)
+            .or_insert_with(|| ResourceItem {
+                payload: Vec::new(),
+                sequence: 0,
+                registrations: HashMap::new(),
+            });
+        resource.payload = payload;
+
+        let min_interval = self.min_interval();
+        for (address, registration) in &mut resource.registrations {
+            let recent = registration
+                .last_notified
+                .is_some_and(|last| last.elapsed() < min_interval);
+            if recent {
+                // the observer gets the state current once the interval is over
+                registration.pending = true;
+                continue;
             }
+            let state = (&resource.payload[..], resource.sequence);
+            self.notify_change(path, address, registration, state, unacknowledge_messages);
         }
     }
 
-    async fn register(&mut self, request: &CoapRequest<SocketAddr>) {
-        let register_address = request.source.unwrap();
-        let resource_path = request.get_path();
+    /// Notify observers at most once per `interval`, by default as often as
+    /// the resource changes. Changes within the interval are coalesced:
+    /// once it is over, the observer is notified of the latest state with
+    /// the next tick of the one-second timer of the observer.
+    pub fn set_min_interval(&self, interval: Duration) {
+        let millis = u64::try_from(interval.as_millis()).unwrap_or(u64::MAX);
+        self.min_interval.store(millis, Ordering::Relaxed);
+    }
 
-        debug!("register {} {}", register_address, resource_path);
+    fn min_interval(&self) -> Duration {
+        Duration::from_millis(self.min_interval.load(Ordering::Relaxed))
+    }
 
-        // reply NotFound if resource doesn't exist
-        if !self.resources.contains_key(&resource_path) {
-            if let Some(ref response) = request.response {
-                let mut response2 = response.clone();
-                response2.set_status(Status::NotFound);
-                self.send_message(&register_address, &response2.message)
-                    .await;
-            }
-            return;
+    /// Send a confirmable notification of the state of the resource, which
+    /// supersedes any unacknowledged one.
+    fn notify_change(
+        &self,
+        path: &str,
+        address: &SocketAddr,
+        registration: &mut Registration,
+        (payload, sequence): (&[u8], u32),
+        unacknowledge_messages: &mut HashMap<MessageKey, UnacknowledgeMessageItem>,
+    ) {
+        let message_id = self.message_ids.lock().unwrap().next(*address);
+        if let Some(old_message_id) = registration.unacknowledge_message {
+            unacknowledge_messages.remove(&old_message_id);
         }
-
-        self.record_register_resource(
-            &register_address,
-            &resource_path,
-            &request.message.get_token(),
+        registration.unacknowledge_message = Some((*address, message_id));
+        registration.last_notified = Some(Instant::now());
+        registration.pending = false;
+        unacknowledge_messages.insert(
+            (*address, message_id),
+            UnacknowledgeMessageItem {
+                resource: path.to_string(),
+                try_times: 1,
+            },
         );
+        self.notify(address, registration, payload, sequence, message_id);
+    }
 
-        let resource = self.resources.get(&resource_path).unwrap();
-
-        if let Some(ref response) = request.response {
-            let mut response2 = response.clone();
-            response2.message.payload = resource.payload.clone();
-            response2.message.set_observe_value(resource.sequence);
-            response2.message.header.set_type(MessageType::NonConfirmable);
-            self.send_message(&register_address, &response2.message)
-                .await;
+    /// Notify the observers whose notifications were held back by the
+    /// minimum interval and whose interval is over.
+    fn flush_coalesced(&self) {
+        let min_interval = self.min_interval();
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let Shard {
+                resources,
+                unacknowledge_messages,
+            } = &mut *shard;
+            for (path, resource) in resources.iter_mut() {
+                for (address, registration) in &mut resource.registrations {
+                    let due = registration.pending
+                        && registration
+                            .last_notified
+                            .is_none_or(|last| last.elapsed() >= min_interval);
+                    if due {
+                        let state = (&resource.payload[..], resource.sequence);
+                        self.notify_change(
+                            path,
+                            address,
+                            registration,
+                            state,
+                            unacknowledge_messages,
+                        );
+                    }
+                }
+            }
         }
     }
 
-    fn deregister(&mut self, request: &CoapRequest<SocketAddr>) {
-        let register_address = request.source.unwrap();
-        let resource_path = request.get_path();
-
-        debug!("deregister {} {}", register_address, resource_path);
-
-        self.remove_register_resource(
-            &register_address,
-            &resource_path,
-            &request.message.get_token(),
-        );
-    }
+    /// notify the registers of a removed resource with 4.04 Not Found and
+    /// forget the resource.
+    pub fn resource_removed(&self, path: &str) {
+        let mut shard = self.shard(path);
+        let resource = match shard.resources.remove(path) {
+            Some(resource) => resource,
+            None => return,
+        };
 
-    async fn resource_changed(&mut self, request: &CoapRequest<SocketAddr>) {
-        let resource_path = request.get_path();
-        let ref resource_payload = request.message.payload;
+        debug!("resource_removed {}", path);
 
-        debug!("resource_changed {} {:?}", resource_path, resource_payload);
+        for (address, registration) in resource.registrations {
+            if let Some(message_id) = registration.unacknowledge_message {
+                shard.unacknowledge_messages.remove(&message_id);
+            }
 
-        let register_resource_keys: Vec<String>;
-        {
-            let resource = self.record_resource(&resource_path, &resource_payload);
-            register_resource_keys = resource
-                .register_resources
-                .iter()
-                .map(|k| k.clone())
-                .collect();
+            let mut message = Packet::new();
+            message.header.set_type(MessageType::NonConfirmable);
+            message.header.code = MessageClass::Response(Status::NotFound);
+            message.header.message_id = self.message_ids.lock().unwrap().next(address);
+            message.set_token(registration.token);
+            self.send_message(&address, &message);
         }
+    }
 
-        for register_resource_key in register_resource_keys {
-            self.gen_message_id();
-            self.notify_register_with_newest_resource(&register_resource_key)
-                .await;
-            self.record_unacknowledge_message(&register_resource_key);
+    /// Resend the notifications that were not acknowledged.
+    fn retransmit(&self) {
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let Shard {
+                resources,
+                unacknowledge_messages,
+            } = &mut *shard;
+            unacknowledge_messages.retain(|&(address, message_id), message| {
+                let resource = match resources.get_mut(&message.resource) {
+                    Some(resource) => resource,
+                    None => return false,
+                };
+                let registration = match resource.registrations.get_mut(&address) {
+                    Some(registration) => registration,
+                    None => return false,
+                };
+                if message.try_times > DEFAULT_UNACKNOWLEDGE_MESSAGE_TRY_TIMES {
+                    warn!(
+                        "unacknowledge_message try times exceeded  {}${}",
+                        address, message.resource
+                    );
+                    registration.unacknowledge_message = None;
+                    return false;
+                }
+                message.try_times += 1;
+                // retransmissions keep the message ID of the notification
+                let (payload, sequence) = (&resource.payload, resource.sequence);
+                self.notify(&address, registration, payload, sequence, message_id);
+                true
+            });
         }
     }
 
-    fn acknowledge(&mut self, request: &CoapRequest<SocketAddr>) {
-        self.remove_unacknowledge_message(
-            &request.message.header.message_id,
-            &request.message.get_token(),
-        );
+    fn register(&self, request: &CoapRequest<SocketAddr>) {
+        self.register_at(&request.get_path(), request);
     }
 
-    fn record_register_resource(&mut self, address: &SocketAddr, path: &String, token: &[u8]) {
-        let resource = self.resources.get_mut(path).unwrap();
-        let register_key = Self::format_register(&address);
-        let register_resource_key = Self::format_register_resource(&address, path);
+    /// Register the source of `request` as an observer of the resource at
+    /// `resource_path` and send it the current state, e.g. for a proxy
+    /// keeping the state of a target under its URI.
+    pub(crate) fn register_at(&self, resource_path: &str, request: &CoapRequest<SocketAddr>) {
+        let register_address = request.source.unwrap();
 
-        self.register_resources
-            .entry(register_resource_key.clone())
-            .or_insert(RegisterResourceItem {
-                register: register_key.clone(),
-                resource: path.clone(),
-                token: token.into(),
-                unacknowledge_message: None,
-            });
-        resource
-            .register_resources
-            .replace(register_resource_key.clone());
-        match self.registers.entry(register_key) {
-            Entry::Occupied(register) => {
-                register
-                    .into_mut()
-                    .register_resources
-                    .replace(register_resource_key);
-            }
-            Entry::Vacant(v) => {
-                let mut register = RegisterItem {
-                    register_resources: HashSet::new(),
-                };
-                register.register_resources.insert(register_resource_key);
+        debug!("register {} {}", register_address, resource_path);
 
-                v.insert(register);
+        let mut shard = self.shard(resource_path);
+        let resource = match shard.resources.get_mut(resource_path) {
+            Some(resource) => resource,
+            // reply NotFound if resource doesn't exist
+            None => {
+                if let Some(ref response) = request.response {
+                    let mut response2 = response.clone();
+                    response2.set_status(Status::NotFound);
+                    self.send_message(&register_address, &response2.message);
+                }
+                return;
             }
         };
-    }
 
-    fn remove_register_resource(
-        &mut self,
-        address: &SocketAddr,
-        path: &String,
-        token: &[u8],
-    ) -> bool {
-        let register_resource_key = Self::format_register_resource(&address, path);
-
-        if let Some(register_resource) = self.register_resources.get(&register_resource_key) {
-            if register_resource.token != *token {
-                return false;
-            }
-
-            if let Some(unacknowledge_message) = register_resource.unacknowledge_message {
-                self.unacknowledge_messages
-                    .remove(&unacknowledge_message)
-                    .unwrap();
-            }
-
-            assert_eq!(
-                self.resources
-                    .get_mut(path)
-                    .unwrap()
-                    .register_resources
-                    .remove(&register_resource_key),
-                true
-            );
-
-            let remove_register;
-            {
-                let register = self.registers.get_mut(&register_resource.register).unwrap();
-                assert_eq!(
-                    register.register_resources.remove(&register_resource_key),
-                    true
-                );
-                remove_register = register.register_resources.len() == 0;
-            }
+        resource
+            .registrations
+            .entry(register_address)
+            .or_insert_with(|| Registration {
+                token: request.message.get_token().to_vec(),
+                unacknowledge_message: None,
+                last_notified: None,
+                pending: false,
+            });
 
-            if remove_register {
-                self.registers.remove(&register_resource.register);
-            }
+        if let Some(ref response) = request.response {
+            let mut response2 = response.clone();
+            response2.message.payload = resource.payload.clone();
+            response2.message.set_observe_value(resource.sequence);
+            response2
+                .message
+                .header
+                .set_type(MessageType::NonConfirmable);
+            self.send_message(&register_address, &response2.message);
         }
-
-        self.register_resources.remove(&register_resource_key);
-        return true;
     }
 
-    fn record_resource(&mut self, path: &String, payload: &Vec<u8>) -> &ResourceItem {
-        match self.resources.entry(path.clone()) {
-            Entry::Occupied(resource) => {
-                let mut r = resource.into_mut();
-                r.sequence += 1;
-                r.payload = payload.clone();
-                return r;
-            }
-            Entry::Vacant(v) => {
-                return v.insert(ResourceItem {
-                    payload: payload.clone(),
-                    register_resources: HashSet::new(),
-                    sequence: 0,
-                });
-            }
-        }
+    fn deregister(&self, request: &CoapRequest<SocketAddr>) {
+        self.deregister_at(&request.get_path(), request);
     }
 
-    fn record_unacknowledge_message(&mut self, register_resource_key: &String) {
-        let message_id = self.current_message_id;
-
-        let register_resource = self
-            .register_resources
-            .get_mut(register_resource_key)
-            .unwrap();
-        if let Some(old_message_id) = register_resource.unacknowledge_message {
-            self.unacknowledge_messages.remove(&old_message_id);
-        }
-
-        register_resource.unacknowledge_message = Some(message_id);
-        self.unacknowledge_messages.insert(
-            message_id,
-            UnacknowledgeMessageItem {
-                register_resource: register_resource_key.clone(),
-                try_times: 1,
-            },
-        );
-    }
+    /// Deregister the source of `request` as an observer of the resource
+    /// at `resource_path`.
+    pub(crate) fn deregister_at(&self, resource_path: &str, request: &CoapRequest<SocketAddr>) {
+        let register_address = request.source.unwrap();
 
-    fn try_unacknowledge_message(&mut self, register_resource_key: &String) -> bool {
-        let register_resource = self
-            .register_resources
-            .get_mut(register_resource_key)
-            .unwrap();
-        let ref message_id = register_resource.unacknowledge_message.unwrap();
+        debug!("deregister {} {}", register_address, resource_path);
 
-        let try_again;
+        let mut shard = self.shard(resource_path);
+        let Shard {
+            resources,
+            unacknowledge_messages,
+        } = &mut *shard;
+        let registrations = match resources.get_mut(resource_path) {
+            Some(resource) => &mut resource.registrations,
+            None => return,
+        };
+        let token = request.message.get_token();
+        if registrations
+            .get(&register_address)
+            .is_some_and(|registration| registration.token == token)
         {
-            let unacknowledge_message = self.unacknowledge_messages.get_mut(message_id).unwrap();
-            if unacknowledge_message.try_times > DEFAULT_UNACKNOWLEDGE_MESSAGE_TRY_TIMES {
-                try_again = false;
-            } else {
-                unacknowledge_message.try_times += 1;
-                try_again = true;
+            let registration = registrations.remove(&register_address).unwrap();
+            if let Some(message_id) = registration.unacknowledge_message {
+                unacknowledge_messages.remove(&message_id);
             }
         }
+    }
 
-        if !try_again {
-            warn!(
-                "unacknowledge_message try times exceeded  {}",
-                register_resource_key
-            );
-
-            register_resource.unacknowledge_message = None;
-            self.unacknowledge_messages.remove(message_id);
-        }
-
-        return try_again;
+    /// Return the number of observers of the resource at `path`, or `None`
+    /// if there is no such resource.
+    pub(crate) fn observers(&self, path: &str) -> Option<usize> {
+        let shard = self.shard(path);
+        let resource = shard.resources.get(path)?;
+        Some(resource.registrations.len())
     }
 
-    fn remove_unacknowledge_message(&mut self, message_id: &u16, token: &[u8]) {
-        if let Some(message) = self.unacknowledge_messages.get_mut(message_id) {
-            let register_resource = self
-                .register_resources
-                .get_mut(&message.register_resource)
-                .unwrap();
-            if register_resource.token != *token {
-                return;
+    fn acknowledge(&self, request: &CoapRequest<SocketAddr>) {
+        let address = request.source.unwrap();
+        let message_id = (address, request.message.header.message_id);
+        for shard in &self.shards {
+            let mut shard = shard.lock().unwrap();
+            let Shard {
+                resources,
+                unacknowledge_messages,
+            } = &mut *shard;
+            let message = match unacknowledge_messages.get(&message_id) {
+                Some(message) => message,
+                None => continue,
+            };
+            let registration = resources
+                .get_mut(&message.resource)
+                .and_then(|resource| resource.registrations.get_mut(&address));
+            if let Some(registration) = registration {
+                if registration.token != request.message.get_token() {
+                    return;
+                }
+                registration.unacknowledge_message = None;
             }
-
-            register_resource.unacknowledge_message = None;
+            unacknowledge_messages.remove(&message_id);
+            return;
         }
-
-        self.unacknowledge_messages.remove(message_id);
     }
 
-    async fn notify_register_with_newest_resource(&mut self, register_resource_key: &String) {
-        let message_id = self.current_message_id;
-
-        debug!("notify {} {}", register_resource_key, message_id);
+    /// Return the shard holding the resource at `path`.
+    fn shard(&self, path: &str) -> MutexGuard<'_, Shard> {
+        let index = self.seed.hash_one(path) as usize % self.shards.len();
+        self.shards[index].lock().unwrap()
+    }
 
-        let ref mut message = Packet::new();
+    fn notify(
+        &self,
+        address: &SocketAddr,
+        registration: &Registration,
+        payload: &[u8],
+        sequence: u32,
+        message_id: u16,
+    ) {
+        debug!("notify {}${:?} {}", address, registration.token, message_id);
+
+        let mut message = Packet::new();
         message.header.set_type(MessageType::Confirmable);
         message.header.code = MessageClass::Response(Status::Content);
+        message.set_token(registration.token.clone());
+        message.set_observe_value(sequence);
+        message.header.message_id = message_id;
+        message.payload = payload.to_vec();
 
-        let address: SocketAddr;
-        {
-            let register_resource = self.register_resources.get(register_resource_key).unwrap();
-            let resource = self.resources.get(&register_resource.resource).unwrap();
-
-            message.set_token(register_resource.token.clone());
-            message.set_observe_value(resource.sequence);
-            message.header.message_id = message_id;
-            message.payload = resource.payload.clone();
-
-            address = register_resource.register.parse().unwrap();
-        }
-
-        self.send_message(&address, &message).await;
+        self.send_message(address, &message);
     }
 
-    async fn send_message(&mut self, address: &SocketAddr, message: &Packet) {
+    fn send_message(&self, address: &SocketAddr, message: &Packet) {
         debug!("send_message {:?} {:?}", address, message);
-        self.tx_sender.send((message.clone(), *address)).unwrap();
-    }
-
-    fn gen_message_id(&mut self) -> u16 {
-        self.current_message_id += 1;
-        return self.current_message_id;
-    }
-
-    fn format_register(address: &SocketAddr) -> String {
-        format!("{}", address)
+        let mut queue = self.queue.lock().unwrap();
+        if queue.messages.len() >= queue.capacity {
+            // the token tells the observation the message belongs to
+            let superseded = queue.messages.iter_mut().find(|(queued, queued_address)| {
+                queued_address == address && queued.get_token() == message.get_token()
+            });
+            if let Some(queued) = superseded {
+                *queued = (message.clone(), *address);
+                return;
+            }
+            warn!("notification queue full, drop the oldest notification");
+            queue.messages.pop_front();
+        }
+        queue.messages.push_back((message.clone(), *address));
+        if let Some(waker) = queue.waker.take() {
+            waker.wake();
+        }
     }
+}
 
-    fn format_register_resource(address: &SocketAddr, path: &String) -> String {
-        format!("{}${}", address, path)
+impl Default for ObserveRegistry {
+    fn default() -> ObserveRegistry {
+        ObserveRegistry::new()
     }
 }
 
@@ -531,6 +668,108 @@ mod test {
         client3.receive().unwrap();
     }
 
+    fn register(registry: &ObserveRegistry, path: &str, port: u16) {
+        let mut packet = Packet::new();
+        packet.set_token(vec![port as u8]);
+        let mut request = CoapRequest::from_packet(packet, ([192, 0, 2, 1], port).into());
+        request.set_path(path);
+        request.set_observe_flag(ObserveOption::Register);
+        assert!(!registry.request_handler(&request));
+    }
+
+    #[test]
+    fn test_concurrent_registry() {
+        let registry = Arc::new(ObserveRegistry::new());
+        let paths = ["a", "b", "c", "d"];
+        for (port, path) in (5000..).zip(paths) {
+            registry.resource_changed(path, vec![0]);
+            register(&registry, path, port);
+        }
+
+        std::thread::scope(|scope| {
+            for path in paths {
+                let registry = &registry;
+                scope.spawn(move || {
+                    for i in 1..=10u8 {
+                        registry.resource_changed(path, vec![i]);
+                    }
+                });
+            }
+        });
+
+        let mut last = HashMap::new();
+        let batches = futures::executor::block_on(registry.notifications());
+        for (address, messages) in batches {
+            for message in messages {
+                let sequence = message.get_observe_value().unwrap().unwrap();
+                assert_eq!(message.get_token(), [address.port() as u8]);
+                assert_eq!(message.payload, [sequence as u8]);
+                // the notifications of a resource are in order
+                let previous = last.insert(address, sequence);
+                assert!(previous < Some(sequence));
+            }
+        }
+        assert_eq!(last.len(), paths.len());
+        assert!(last.values().all(|&sequence| sequence == 10));
+    }
+
+    #[test]
+    fn test_notification_queue() {
+        let registry = ObserveRegistry::new();
+        registry.set_queue_size(2);
+        registry.resource_changed("a", vec![0]);
+        registry.resource_changed("b", vec![0]);
+        register(&registry, "a", 5000);
+        register(&registry, "b", 5001);
+        // the queue is full, newer notifications supersede queued ones
+        for i in 1..=3 {
+            registry.resource_changed("a", vec![i]);
+        }
+        let batches = futures::executor::block_on(registry.notifications());
+        assert_eq!(batches.len(), 2);
+        let (address, messages) = &batches[0];
+        assert_eq!(address.port(), 5000);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].payload, [3]);
+        assert_eq!(batches[1].1[0].payload, [0]);
+
+        // a message that supersedes none pushes out the oldest
+        registry.resource_changed("b", vec![1]);
+        register(&registry, "a", 5002);
+        register(&registry, "b", 5003);
+        let batches = futures::executor::block_on(registry.notifications());
+        let ports: Vec<_> = batches.iter().map(|(address, _)| address.port()).collect();
+        assert_eq!(ports, [5002, 5003]);
+    }
+
+    #[test]
+    fn test_coalescing() {
+        let registry = ObserveRegistry::new();
+        registry.set_min_interval(Duration::from_secs(3600));
+        registry.resource_changed("a", vec![0]);
+        register(&registry, "a", 5000);
+        for i in 1..=3 {
+            registry.resource_changed("a", vec![i]);
+        }
+        // the registration response and the first change
+        let batches = futures::executor::block_on(registry.notifications());
+        let payloads: Vec<_> = batches[0]
+            .1
+            .iter()
+            .map(|message| message.payload[0])
+            .collect();
+        assert_eq!(payloads, [0, 1]);
+
+        registry.flush_coalesced();
+        assert!(registry.queue.lock().unwrap().messages.is_empty());
+        registry.set_min_interval(Duration::ZERO);
+        registry.flush_coalesced();
+        let batches = futures::executor::block_on(registry.notifications());
+        let notification = &batches[0].1[0];
+        assert_eq!(notification.payload, [3]);
+        assert_eq!(notification.get_observe_value().unwrap().unwrap(), 3);
+    }
+
     #[test]
     fn test_observe_without_resource() {
         let path = "/test";
@@ -0,0 +1,284 @@
+//! Application-defined options.
+//!
+//! The properties that matter to intermediaries follow from the option
+//! number ([RFC 7252](https://tools.ietf.org/html/rfc7252) section 5.4.6):
+//! see [`is_critical`], [`is_unsafe`] and [`is_no_cache_key`]. What the
+//! number does not tell, i.e. the name, the value format, the length of the
+//! value and whether the option may be repeated, is declared with an
+//! [`OptionDefinition`] registered with
+//! [`Server::register_option`](crate::Server::register_option).
+//!
+//! The server answers confirmable requests with a critical option it does
+//! not recognize, or with an invalid value or repetition of a registered
+//! critical option, with 4.02 Bad Option, and drops such non-confirmable
+//! requests. Invalid elective options are removed before the request
+//! reaches the handler. Requests that a proxy handler forwards are only
+//! checked for unsafe options, see [`proxy`](crate::proxy).
+use coap_lite::{CoapOption, Packet};
+use smallvec::SmallVec;
+use std::collections::BTreeMap;
+
+use super::echo;
+use super::no_response;
+use super::qblock;
+
+/// Options unknown to coap-lite that the server processes itself.
+const PROCESSED_OPTIONS: [u16; 5] = [
+    qblock::Q_BLOCK1,
+    qblock::Q_BLOCK2,
+    echo::ECHO,
+    echo::REQUEST_TAG,
+    no_response::NO_RESPONSE,
+];
+
+/// Return whether a recipient must reject a message with the option if it
+/// does not recognize it.
+pub fn is_critical(number: u16) -> bool {
+    number & 0x01 != 0
+}
+
+/// Return whether a proxy must not forward the option unless it
+/// recognizes it.
+pub fn is_unsafe(number: u16) -> bool {
+    number & 0x02 != 0
+}
+
+/// Return whether the option is not part of the cache key of a request.
+pub fn is_no_cache_key(number: u16) -> bool {
+    number & 0x1e == 0x1c
+}
+
+/// Format of an option value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// No value.
+    Empty,
+    /// An opaque sequence of bytes.
+    Opaque,
+    /// An unsigned integer in network byte order.
+    Uint,
+    /// A UTF-8 string.
+    String,
+}
+
+/// The properties of an option that do not follow from its number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OptionDefinition {
+    name: String,
+    format: Format,
+    min_length: usize,
+    max_length: usize,
+    repeatable: bool,
+}
+
+impl OptionDefinition {
+    /// Define a non-repeatable option with values of `format` of any
+    /// length the format allows.
+    pub fn new(name: &str, format: Format) -> OptionDefinition {
+        let max_length = match format {
+            Format::Empty => 0,
+            Format::Uint => 8,
+            Format::Opaque | Format::String => usize::MAX,
+        };
+        OptionDefinition {
+            name: name.to_string(),
+            format,
+            min_length: 0,
+            max_length,
+            repeatable: false,
+        }
+    }
+
+    /// Limit the length of values to `min..=max` bytes.
+    pub fn with_length(mut self, min: usize, max: usize) -> OptionDefinition {
+        self.min_length = min;
+        self.max_length = max;
+        self
+    }
+
+    /// Allow the option to occur several times in a message.
+    pub fn repeatable(mut self) -> OptionDefinition {
+        self.repeatable = true;
+        self
+    }
+
+    /// Return the name of the option, for diagnostics.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the format of the values.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Return whether the option may occur several times in a message.
+    pub fn is_repeatable(&self) -> bool {
+        self.repeatable
+    }
+
+    /// Return whether `value` is a valid value of the option.
+    pub fn accepts(&self, value: &[u8]) -> bool {
+        (self.min_length..=self.max_length).contains(&value.len())
+            && (self.format != Format::String || std::str::from_utf8(value).is_ok())
+    }
+}
+
+/// The options an application defined, by number.
+#[derive(Clone, Debug, Default)]
+pub struct OptionRegistry {
+    options: BTreeMap<u16, OptionDefinition>,
+}
+
+impl OptionRegistry {
+    /// Create a registry without any options.
+    pub fn new() -> OptionRegistry {
+        OptionRegistry::default()
+    }
+
+    /// Define the option `number`, replacing any earlier definition.
+    pub fn register(&mut self, number: u16, definition: OptionDefinition) {
+        self.options.insert(number, definition);
+    }
+
+    /// Return the definition of the option `number`, if it was registered.
+    pub fn get(&self, number: u16) -> Option<&OptionDefinition> {
+        self.options.get(&number)
+    }
+
+    /// Return whether the option `number` is known to coap-lite, processed
+    /// by the server or registered. OSCORE is not supported, so its option
+    /// is not recognized.
+    pub fn is_recognized(&self, number: u16) -> bool {
+        match CoapOption::from(number) {
+            CoapOption::Unknown(_) | CoapOption::Oscore => {
+                PROCESSED_OPTIONS.contains(&number) || self.options.contains_key(&number)
+            }
+            _ => true,
+        }
+    }
+
+    /// Check the options of a request, removing invalid elective ones, and
+    /// return the diagnostic for the first unrecognized or invalid critical
+    /// option.
+    pub(crate) fn check(&self, message: &mut Packet) -> Result<(), String> {
+        // checked in place, as most requests have no invalid options
+        let mut invalid: SmallVec<[u16; 4]> = SmallVec::new();
+        for (&number, values) in message.options() {
+            if values.is_empty() {
+                continue;
+            }
+            if !self.is_recognized(number) {
+                if is_critical(number) {
+                    return Err(format!("Unrecognized critical option {}", number));
+                }
+                continue;
+            }
+            let definition = match self.options.get(&number) {
+                Some(definition) => definition,
+                None => continue,
+            };
+            let valid = values.iter().all(|value| definition.accepts(value))
+                && (definition.repeatable || values.len() <= 1);
+            if valid {
+                continue;
+            }
+            if is_critical(number) {
+                return Err(format!(
+                    "Invalid critical option {} ({})",
+                    number, definition.name
+                ));
+            }
+            invalid.push(number);
+        }
+        for number in invalid {
+            message.clear_option(CoapOption::Unknown(number));
+        }
+        Ok(())
+    }
+
+    /// Return the diagnostic for the first option of a request to be
+    /// forwarded that is unsafe to forward and not recognized. A proxy
+    /// passes on the other options, whether it recognizes them or not.
+    pub(crate) fn check_forwarded(&self, message: &Packet) -> Result<(), String> {
+        let unrecognized = message
+            .options()
+            .filter(|(_, values)| !values.is_empty())
+            .map(|(number, _)| *number)
+            .find(|number| is_unsafe(*number) && !self.is_recognized(*number));
+        match unrecognized {
+            Some(number) => Err(format!("Unrecognized unsafe option {}", number)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_properties() {
+        // Uri-Host, ETag, Size1
+        assert!(is_critical(3) && is_unsafe(3) && !is_no_cache_key(3));
+        assert!(!is_critical(4) && !is_unsafe(4) && !is_no_cache_key(4));
+        assert!(!is_critical(60) && !is_unsafe(60) && is_no_cache_key(60));
+    }
+
+    #[test]
+    fn test_check() {
+        let mut registry = OptionRegistry::new();
+        registry.register(
+            65001,
+            OptionDefinition::new("Tenant", Format::String).with_length(1, 16),
+        );
+        registry.register(
+            65004,
+            OptionDefinition::new("Trace", Format::Opaque).repeatable(),
+        );
+        registry.register(65008, OptionDefinition::new("Priority", Format::Uint));
+
+        let mut message = Packet::new();
+        message.add_option(CoapOption::Unknown(65001), b"acme".to_vec());
+        message.add_option(CoapOption::Unknown(65004), vec![1]);
+        message.add_option(CoapOption::Unknown(65004), vec![2]);
+        message.add_option(CoapOption::Unknown(65008), vec![1]);
+        message.add_option(CoapOption::Unknown(65008), vec![2]);
+        message.add_option(CoapOption::Unknown(65010), vec![]);
+        assert_eq!(registry.check(&mut message), Ok(()));
+        // the repeated elective option is removed, the unknown one kept
+        assert!(message
+            .get_option(CoapOption::Unknown(65008))
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            message
+                .get_option(CoapOption::Unknown(65004))
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(message.get_option(CoapOption::Unknown(65010)).is_some());
+
+        message.add_option(CoapOption::Unknown(65001), b"other".to_vec());
+        assert_eq!(
+            registry.check(&mut message),
+            Err("Invalid critical option 65001 (Tenant)".to_string())
+        );
+        let mut message = Packet::new();
+        message.add_option(CoapOption::Unknown(65003), vec![]);
+        assert_eq!(
+            registry.check(&mut message),
+            Err("Unrecognized critical option 65003".to_string())
+        );
+        // a proxy forwards unrecognized options that are safe to forward
+        let mut message = Packet::new();
+        message.add_option(CoapOption::Unknown(65005), vec![]);
+        assert_eq!(registry.check_forwarded(&message), Ok(()));
+        message.add_option(CoapOption::Unknown(65006), vec![]);
+        assert_eq!(
+            registry.check_forwarded(&message),
+            Err("Unrecognized unsafe option 65006".to_string())
+        );
+    }
+}
@@ -0,0 +1,254 @@
+//! Outbound queues of the server.
+//!
+//! The server does not wait for its transports to take the messages it
+//! sends. Each peer has a queue of its own, and the queues take turns
+//! whenever the server is polled and the transport of a peer is ready, so
+//! that a peer, a transport or a multicast interface slow to send to holds
+//! up its own messages only. What happens when the queue of a peer is full
+//! is up to the [`OverflowPolicy`], and [`OutboundStats`] count what became
+//! of the messages.
+use coap_lite::Packet;
+use futures::{Sink, SinkExt};
+use log::{debug, error};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+
+/// Default number of messages waiting for each peer.
+pub const DEFAULT_OUTBOUND_QUEUE_SIZE: usize = 64;
+
+/// What happens to a message for a peer whose queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Drop the oldest message waiting, the default. Confirmable messages
+    /// are retransmitted by their sender anyway, and the latest are the
+    /// most useful.
+    #[default]
+    DropOldest,
+    /// Drop the new message.
+    DropNewest,
+    /// Stop dispatching until the queue has room, which slows down all
+    /// peers to the pace of the slowest. Messages to peers of group
+    /// requests still drop the oldest instead.
+    Block,
+}
+
+/// What became of the messages the server sent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct OutboundStats {
+    /// Messages waiting in the queues.
+    pub queued: usize,
+    /// Peers with messages waiting.
+    pub peers: usize,
+    /// Messages taken by their transport.
+    pub sent: u64,
+    /// Messages dropped because the queue of their peer was full.
+    pub dropped: u64,
+    /// Messages their transport failed to send.
+    pub failed: u64,
+}
+
+/// The queues of the peers, with the transport to send each message on.
+pub(crate) struct Outbound {
+    queues: HashMap<SocketAddr, VecDeque<(usize, Packet)>>,
+    /// The peers with messages waiting, in the order they take turns.
+    turns: VecDeque<SocketAddr>,
+    /// The transports that messages were passed to but not flushed.
+    unflushed: Vec<usize>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    stats: OutboundStats,
+}
+
+impl Outbound {
+    pub(crate) fn new() -> Outbound {
+        Outbound {
+            queues: HashMap::new(),
+            turns: VecDeque::new(),
+            unflushed: Vec::new(),
+            capacity: DEFAULT_OUTBOUND_QUEUE_SIZE,
+            policy: OverflowPolicy::default(),
+            stats: OutboundStats::default(),
+        }
+    }
+
+    /// Hold up to `capacity` messages for each peer, at least one.
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        for queue in self.queues.values_mut() {
+            while queue.len() > self.capacity {
+                queue.pop_front();
+                self.stats.queued -= 1;
+                self.stats.dropped += 1;
+            }
+        }
+    }
+
+    pub(crate) fn set_policy(&mut self, policy: OverflowPolicy) {
+        self.policy = policy;
+    }
+
+    pub(crate) fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    pub(crate) fn stats(&self) -> OutboundStats {
+        OutboundStats {
+            peers: self.queues.len(),
+            ..self.stats
+        }
+    }
+
+    /// Return whether the queue of the peer has room for another message.
+    pub(crate) fn has_room(&self, addr: &SocketAddr) -> bool {
+        self.queues
+            .get(addr)
+            .is_none_or(|queue| queue.len() < self.capacity)
+    }
+
+    /// Queue a message to send to the peer on the transport `index`. When
+    /// the queue is full, a message is dropped as the policy says, the
+    /// oldest one if it is to block.
+    pub(crate) fn push(&mut self, index: usize, packet: Packet, addr: SocketAddr) {
+        let queue = self.queues.entry(addr).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(addr);
+        }
+        if queue.len() >= self.capacity {
+            debug!("outbound queue of {} full", addr);
+            self.stats.dropped += 1;
+            match self.policy {
+                OverflowPolicy::DropNewest => return,
+                OverflowPolicy::DropOldest | OverflowPolicy::Block => {
+                    queue.pop_front();
+                    self.stats.queued -= 1;
+                }
+            }
+        }
+        queue.push_back((index, packet));
+        self.stats.queued += 1;
+    }
+
+    /// Pass the messages waiting to their transports, a message of each
+    /// peer in turn, as far as the transports are ready for them, and flush
+    /// the transports. A message that fails is dropped.
+    pub(crate) fn poll_send<T>(&mut self, cx: &mut Context<'_>, transports: &mut [T])
+    where
+        T: Sink<(Packet, SocketAddr), Error = io::Error> + Unpin,
+    {
+        // the transports not ready during this poll
+        let mut blocked = Vec::new();
+        let mut skipped = 0;
+        while skipped < self.turns.len() {
+            let addr = self.turns.pop_front().unwrap();
+            let queue = self.queues.get_mut(&addr).unwrap();
+            let index = queue.front().unwrap().0;
+            let ready = match blocked.contains(&index) {
+                true => Poll::Pending,
+                false => transports[index].poll_ready_unpin(cx),
+            };
+            let ready = match ready {
+                Poll::Ready(ready) => ready,
+                Poll::Pending => {
+                    if !blocked.contains(&index) {
+                        blocked.push(index);
+                    }
+                    self.turns.push_back(addr);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            skipped = 0;
+            let (index, packet) = queue.pop_front().unwrap();
+            self.stats.queued -= 1;
+            match queue.is_empty() {
+                true => {
+                    self.queues.remove(&addr);
+                }
+                false => self.turns.push_back(addr),
+            }
+            match ready.and_then(|()| transports[index].start_send_unpin((packet, addr))) {
+                Ok(()) => {
+                    self.stats.sent += 1;
+                    if !self.unflushed.contains(&index) {
+                        self.unflushed.push(index);
+                    }
+                }
+                Err(e) => {
+                    error!("send to {} error: {}", addr, e);
+                    self.stats.failed += 1;
+                }
+            }
+        }
+        self.unflushed
+            .retain(|&index| match transports[index].poll_flush_unpin(cx) {
+                Poll::Ready(Err(e)) => {
+                    error!("flush error: {}", e);
+                    false
+                }
+                Poll::Ready(Ok(())) => false,
+                Poll::Pending => true,
+            });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::transport::{ClientTransport, MemoryTransport};
+    use futures::task::noop_waker;
+
+    #[test]
+    fn test_outbound() {
+        let transport = MemoryTransport::new("10.0.0.1:5683".parse().unwrap());
+        let addrs: Vec<SocketAddr> = vec![
+            "10.0.0.2:5683".parse().unwrap(),
+            "10.0.0.3:5683".parse().unwrap(),
+        ];
+        let peers: Vec<_> = addrs.iter().map(|addr| transport.connect(*addr)).collect();
+        let mut transports = [transport];
+        let message = |id| {
+            let mut packet = Packet::new();
+            packet.header.message_id = id;
+            packet
+        };
+
+        let mut outbound = Outbound::new();
+        outbound.set_capacity(2);
+        for id in 0..3 {
+            outbound.push(0, message(id), addrs[0]);
+        }
+        outbound.push(0, message(10), addrs[1]);
+        assert!(!outbound.has_room(&addrs[0]));
+        assert!(outbound.has_room(&addrs[1]));
+        let stats = outbound.stats();
+        assert_eq!((stats.queued, stats.peers, stats.dropped), (3, 2, 1));
+
+        let waker = noop_waker();
+        outbound.poll_send(&mut Context::from_waker(&waker), &mut transports);
+        let stats = outbound.stats();
+        assert_eq!((stats.queued, stats.peers, stats.sent), (0, 0, 3));
+        let mut buf = [0; 64];
+        for (peer, ids) in peers.iter().zip([[1, 2].as_slice(), &[10]]) {
+            for id in ids {
+                let (n, _) = peer.recv_from(&mut buf).unwrap();
+                assert_eq!(
+                    Packet::from_bytes(&buf[..n]).unwrap().header.message_id,
+                    *id
+                );
+            }
+        }
+
+        outbound.set_policy(OverflowPolicy::DropNewest);
+        for id in 0..3 {
+            outbound.push(0, message(id), addrs[0]);
+        }
+        outbound.poll_send(&mut Context::from_waker(&waker), &mut transports);
+        for id in [0, 1] {
+            let (n, _) = peers[0].recv_from(&mut buf).unwrap();
+            assert_eq!(Packet::from_bytes(&buf[..n]).unwrap().header.message_id, id);
+        }
+        assert_eq!(outbound.stats().dropped, 2);
+    }
+}
@@ -0,0 +1,386 @@
+//! Serde based payload encoding, selected by the Content-Format option, and
+//! the size options of a body. Handlers of typed bodies are wrapped with
+//! [`typed`].
+use coap_lite::{
+    option_value::{OptionValueU16, OptionValueU32},
+    CoapOption, CoapRequest, CoapResponse, Packet, RequestType as Method,
+    ResponseType as Status,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use super::content_format::ContentFormat;
+
+/// Payload formats supported by the typed helpers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `application/json` (Content-Format 50)
+    Json,
+    /// `application/cbor` (Content-Format 60)
+    Cbor,
+}
+
+impl Format {
+    /// Returns the Content-Format number of the format.
+    pub fn content_format(&self) -> u16 {
+        match self {
+            Format::Json => ContentFormat::Json,
+            Format::Cbor => ContentFormat::Cbor,
+        }
+        .into()
+    }
+
+    /// Looks up the format for a Content-Format number.
+    pub fn from_content_format(content_format: u16) -> Option<Format> {
+        match ContentFormat::from(content_format) {
+            ContentFormat::Json => Some(Format::Json),
+            ContentFormat::Cbor => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PayloadError {
+    /// The exchange with the peer failed.
+    Request(io::Error),
+    /// The peer answered with an error status.
+    UnexpectedStatus(Status),
+    /// The Content-Format is missing or not supported by the typed helpers.
+    UnsupportedContentFormat(Option<u16>),
+    Json(serde_json::Error),
+    Cbor(String),
+    /// The payload decoded but breaks the rules of its format.
+    Invalid(String),
+}
+
+impl fmt::Display for PayloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PayloadError::Request(e) => write!(f, "request failed: {}", e),
+            PayloadError::UnexpectedStatus(status) => write!(f, "unexpected status {:?}", status),
+            PayloadError::UnsupportedContentFormat(Some(cf)) => {
+                write!(f, "unsupported content format {}", cf)
+            }
+            PayloadError::UnsupportedContentFormat(None) => write!(f, "missing content format"),
+            PayloadError::Json(e) => write!(f, "json error: {}", e),
+            PayloadError::Cbor(e) => write!(f, "cbor error: {}", e),
+            PayloadError::Invalid(e) => write!(f, "invalid payload: {}", e),
+        }
+    }
+}
+
+impl error::Error for PayloadError {}
+
+impl From<io::Error> for PayloadError {
+    fn from(e: io::Error) -> PayloadError {
+        PayloadError::Request(e)
+    }
+}
+
+impl From<PayloadError> for io::Error {
+    fn from(e: PayloadError) -> io::Error {
+        match e {
+            PayloadError::Request(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+/// Serializes a value in the given format.
+pub fn encode<T: Serialize + ?Sized>(value: &T, format: Format) -> Result<Vec<u8>, PayloadError> {
+    match format {
+        Format::Json => serde_json::to_vec(value).map_err(PayloadError::Json),
+        Format::Cbor => {
+            let mut buf = Vec::new();
+            ciborium::ser::into_writer(value, &mut buf)
+                .map_err(|e| PayloadError::Cbor(e.to_string()))?;
+            Ok(buf)
+        }
+    }
+}
+
+/// Deserializes a payload in the given format.
+pub fn decode<T: DeserializeOwned>(payload: &[u8], format: Format) -> Result<T, PayloadError> {
+    match format {
+        Format::Json => serde_json::from_slice(payload).map_err(PayloadError::Json),
+        Format::Cbor => {
+            ciborium::de::from_reader(payload).map_err(|e| PayloadError::Cbor(e.to_string()))
+        }
+    }
+}
+
+/// Returns the Content-Format number of a message, if any.
+pub fn content_format(message: &Packet) -> Option<u16> {
+    message
+        .get_first_option_as::<OptionValueU16>(CoapOption::ContentFormat)
+        .and_then(|cf| cf.ok())
+        .map(|cf| cf.0)
+}
+
+/// Sets the Content-Format option of a message.
+pub fn set_content_format(message: &mut Packet, content_format: u16) {
+    message.clear_option(CoapOption::ContentFormat);
+    message.add_option_as(CoapOption::ContentFormat, OptionValueU16(content_format));
+}
+
+/// Returns the Size1 option of a message: in a request, the size of the
+/// body the client is about to send block-wise; in a 4.13 response, the
+/// largest body the server accepts.
+pub fn size1(message: &Packet) -> Option<usize> {
+    size_option(message, CoapOption::Size1)
+}
+
+/// Returns the Size2 option of a message: in a response, the size of the
+/// whole body; in a request, 0 asks for it.
+pub fn size2(message: &Packet) -> Option<usize> {
+    size_option(message, CoapOption::Size2)
+}
+
+/// Sets a Size1 or Size2 option of a message.
+pub fn set_size(message: &mut Packet, option: CoapOption, size: usize) {
+    message.clear_option(option);
+    let size = OptionValueU32(u32::try_from(size).unwrap_or(u32::MAX));
+    message.add_option_as(option, size);
+}
+
+fn size_option(message: &Packet, option: CoapOption) -> Option<usize> {
+    message
+        .get_first_option_as::<OptionValueU32>(option)
+        .and_then(|size| size.ok())
+        .map(|size| size.0 as usize)
+}
+
+/// Serializes a value into the payload of a message and sets its Content-Format.
+pub fn write<T: Serialize + ?Sized>(
+    message: &mut Packet,
+    value: &T,
+    format: Format,
+) -> Result<(), PayloadError> {
+    message.payload = encode(value, format)?;
+    set_content_format(message, format.content_format());
+    Ok(())
+}
+
+/// Deserializes the payload of a message according to its Content-Format.
+pub fn read<T: DeserializeOwned>(message: &Packet) -> Result<T, PayloadError> {
+    let cf = content_format(message);
+    let format = cf
+        .and_then(Format::from_content_format)
+        .ok_or(PayloadError::UnsupportedContentFormat(cf))?;
+    decode(&message.payload, format)
+}
+
+/// The future of a response of a [`typed`] handler.
+pub type ResponseFuture = Pin<Box<dyn Future<Output = Option<CoapResponse>> + Send>>;
+
+/// Wrap a handler of requests with a body of type `T` and responses with
+/// a body of type `R` into a handler for [`Server::run`](crate::Server::run).
+///
+/// The body is deserialized according to its Content-Format; a request
+/// without payload and Content-Format has the body JSON `null`, which
+/// deserializes into `()` or `None`. Other formats are answered with 4.15
+/// Unsupported Content-Format, bodies that do not deserialize with 4.00 Bad
+/// Request. The value returned by the handler is serialized in the format
+/// of the Accept option, or else of the request, with status 2.05 Content
+/// for GET and FETCH and 2.04 Changed for the other methods. An unsupported
+/// Accept option is answered with 4.06 Not Acceptable, and a handler
+/// returning an error status gets a response with that status and no
+/// payload.
+pub fn typed<T, R, F, Fut>(mut handler: F) -> impl FnMut(CoapRequest<SocketAddr>) -> ResponseFuture
+where
+    T: DeserializeOwned,
+    R: Serialize,
+    F: FnMut(CoapRequest<SocketAddr>, T) -> Fut,
+    Fut: Future<Output = Result<R, Status>> + Send + 'static,
+{
+    move |request: CoapRequest<SocketAddr>| {
+        let mut response = match request.response.clone() {
+            Some(response) => response,
+            None => return Box::pin(async { None }) as ResponseFuture,
+        };
+        let format = match response_format(&request.message) {
+            Ok(format) => format,
+            Err(status) => {
+                response.set_status(status);
+                return Box::pin(async { Some(response) });
+            }
+        };
+        let body = match read_body::<T>(&request.message) {
+            Ok(body) => body,
+            Err(error) => {
+                response.set_status(match error {
+                    PayloadError::UnsupportedContentFormat(_) => Status::UnsupportedContentFormat,
+                    _ => Status::BadRequest,
+                });
+                response.message.payload = error.to_string().into_bytes();
+                return Box::pin(async { Some(response) });
+            }
+        };
+        let status = match request.get_method() {
+            Method::Get | Method::Fetch => Status::Content,
+            _ => Status::Changed,
+        };
+        let value = handler(request, body);
+        Box::pin(async move {
+            match value.await.map(|value| encode(&value, format)) {
+                Ok(Ok(payload)) => {
+                    response.set_status(status);
+                    response.message.payload = payload;
+                    set_content_format(&mut response.message, format.content_format());
+                }
+                Ok(Err(_)) => response.set_status(Status::InternalServerError),
+                Err(status) => response.set_status(status),
+            }
+            Some(response)
+        })
+    }
+}
+
+/// Pick the format of the response to a typed request.
+fn response_format(request: &Packet) -> Result<Format, Status> {
+    let accept = request
+        .get_first_option_as::<OptionValueU16>(CoapOption::Accept)
+        .and_then(|accept| accept.ok());
+    match accept {
+        Some(accept) => Format::from_content_format(accept.0).ok_or(Status::NotAcceptable),
+        None => Ok(content_format(request)
+            .and_then(Format::from_content_format)
+            .unwrap_or(Format::Json)),
+    }
+}
+
+fn read_body<T: DeserializeOwned>(request: &Packet) -> Result<T, PayloadError> {
+    if request.payload.is_empty() && content_format(request).is_none() {
+        return decode(b"null", Format::Json);
+    }
+    read(request)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: String,
+        value: f64,
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let reading = Reading {
+            sensor: "temp".to_string(),
+            value: 21.5,
+        };
+        for format in [Format::Json, Format::Cbor] {
+            let mut message = Packet::new();
+            write(&mut message, &reading, format).unwrap();
+            assert_eq!(content_format(&message), Some(format.content_format()));
+            assert_eq!(read::<Reading>(&message).unwrap(), reading);
+        }
+    }
+
+    #[test]
+    fn test_format_mismatch() {
+        let mut message = Packet::new();
+        message.payload = b"{}".to_vec();
+        assert!(matches!(
+            read::<Reading>(&message),
+            Err(PayloadError::UnsupportedContentFormat(None))
+        ));
+
+        set_content_format(&mut message, 0);
+        assert!(matches!(
+            read::<Reading>(&message),
+            Err(PayloadError::UnsupportedContentFormat(Some(0)))
+        ));
+
+        set_content_format(&mut message, Format::Cbor.content_format());
+        assert!(matches!(read::<Reading>(&message), Err(PayloadError::Cbor(_))));
+    }
+
+    #[test]
+    fn test_typed_handler() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    let handler = typed(|_, reading: Option<Reading>| async move {
+                        match reading {
+                            Some(reading) if reading.value < 0.0 => Err(Status::Forbidden),
+                            Some(reading) => Ok(vec![reading]),
+                            None => Ok(vec![]),
+                        }
+                    });
+                    server.run(handler).await.unwrap();
+                });
+        });
+        let client = CoAPClient::new(rx.recv().unwrap()).unwrap();
+        let send = |method: Method, body: Option<(&Reading, Format)>, accept: Option<u16>| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(method);
+            request.set_path("/readings");
+            if let Some((reading, format)) = body {
+                write(&mut request.message, reading, format).unwrap();
+            }
+            if let Some(accept) = accept {
+                request
+                    .message
+                    .add_option_as(CoapOption::Accept, OptionValueU16(accept));
+            }
+            client.send(&request).unwrap();
+            client.receive().unwrap()
+        };
+        let reading = Reading {
+            sensor: "temp".to_string(),
+            value: 21.5,
+        };
+
+        let response = send(Method::Get, None, None);
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.payload, b"[]");
+        for format in [Format::Json, Format::Cbor] {
+            let response = send(Method::Post, Some((&reading, format)), None);
+            assert_eq!(*response.get_status(), Status::Changed);
+            assert_eq!(content_format(&response.message), Some(format.content_format()));
+            let readings = read::<Vec<Reading>>(&response.message).unwrap();
+            assert_eq!(readings, std::slice::from_ref(&reading));
+        }
+        let response = send(Method::Post, Some((&reading, Format::Cbor)), Some(50));
+        assert_eq!(content_format(&response.message), Some(50));
+        let response = send(Method::Post, Some((&reading, Format::Cbor)), Some(0));
+        assert_eq!(*response.get_status(), Status::NotAcceptable);
+
+        let negative = Reading {
+            sensor: "temp".to_string(),
+            value: -1.0,
+        };
+        let response = send(Method::Post, Some((&negative, Format::Json)), None);
+        assert_eq!(*response.get_status(), Status::Forbidden);
+        let send_raw = |payload: &[u8], format: u16| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Post);
+            request.set_path("/readings");
+            request.message.payload = payload.to_vec();
+            set_content_format(&mut request.message, format);
+            client.send(&request).unwrap();
+            client.receive().unwrap()
+        };
+        let response = send_raw(b"\"temp\"", Format::Json.content_format());
+        assert_eq!(*response.get_status(), Status::BadRequest);
+        assert!(!response.message.payload.is_empty());
+        let response = send_raw(b"temp", 0);
+        assert_eq!(*response.get_status(), Status::UnsupportedContentFormat);
+    }
+}
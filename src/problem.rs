@@ -0,0 +1,208 @@
+//! Concise Problem Details
+//! ([RFC 9290](https://tools.ietf.org/html/rfc9290)).
+//!
+//! A CBOR map in a 4.xx or 5.xx response tells the client what went wrong
+//! beyond the response code. The server attaches it with [`set`], the client
+//! reads it with [`read`]. Custom problem details, registered by number or
+//! named by a URI, go into [`ProblemDetails::extensions`].
+use ciborium::value::Value;
+use coap_lite::{CoapResponse, Packet, ResponseType as Status};
+
+use super::content_format::{self, ContentFormat};
+use super::payload::{self, Format, PayloadError};
+
+/// Content-Format of `application/concise-problem-details+cbor`.
+pub const CONTENT_FORMAT: ContentFormat = ContentFormat::ConciseProblemDetailsCbor;
+
+const TITLE: i64 = -1;
+const DETAIL: i64 = -2;
+const INSTANCE: i64 = -3;
+const RESPONSE_CODE: i64 = -4;
+const BASE_URI: i64 = -5;
+const BASE_LANG: i64 = -6;
+const BASE_RTL: i64 = -7;
+
+/// The standard problem details with any custom ones.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProblemDetails {
+    /// A short summary of the problem type.
+    pub title: Option<String>,
+    /// An explanation specific to this occurrence of the problem.
+    pub detail: Option<String>,
+    /// A URI reference identifying this occurrence of the problem.
+    pub instance: Option<String>,
+    /// The response code, e.g. 132 for 4.04.
+    pub response_code: Option<u8>,
+    /// The base URI for relative URI references.
+    pub base_uri: Option<String>,
+    /// The language tag of the texts.
+    pub base_lang: Option<String>,
+    /// The base direction of the texts.
+    pub base_rtl: Option<String>,
+    /// Custom problem details by key: an unsigned integer or a URI.
+    pub extensions: Vec<(Value, Value)>,
+}
+
+impl ProblemDetails {
+    /// Create problem details with a title.
+    pub fn new(title: &str) -> ProblemDetails {
+        ProblemDetails {
+            title: Some(title.to_string()),
+            ..ProblemDetails::default()
+        }
+    }
+
+    /// Set the explanation of this occurrence of the problem.
+    pub fn with_detail(mut self, detail: &str) -> ProblemDetails {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    /// Set the URI reference of this occurrence of the problem.
+    pub fn with_instance(mut self, instance: &str) -> ProblemDetails {
+        self.instance = Some(instance.to_string());
+        self
+    }
+
+    /// Add a custom problem detail.
+    pub fn with_extension(mut self, key: Value, value: Value) -> ProblemDetails {
+        self.extensions.push((key, value));
+        self
+    }
+
+    /// Return the custom problem detail with `key`.
+    pub fn extension(&self, key: &Value) -> Option<&Value> {
+        self.extensions
+            .iter()
+            .find(|(known, _)| known == key)
+            .map(|(_, value)| value)
+    }
+
+    /// Encode the problem details as a CBOR map.
+    pub fn to_cbor(&self) -> Result<Vec<u8>, PayloadError> {
+        let mut map = Vec::new();
+        let texts = [
+            (TITLE, &self.title),
+            (DETAIL, &self.detail),
+            (INSTANCE, &self.instance),
+            (BASE_URI, &self.base_uri),
+            (BASE_LANG, &self.base_lang),
+            (BASE_RTL, &self.base_rtl),
+        ];
+        for (key, text) in texts {
+            if let Some(text) = text {
+                map.push((Value::from(key), Value::Text(text.clone())));
+            }
+        }
+        if let Some(code) = self.response_code {
+            map.push((Value::from(RESPONSE_CODE), Value::from(code)));
+        }
+        map.extend(self.extensions.iter().cloned());
+        payload::encode(&Value::Map(map), Format::Cbor)
+    }
+
+    /// Decode problem details from a CBOR map. Entries with unknown
+    /// negative keys are ignored.
+    pub fn from_cbor(data: &[u8]) -> Result<ProblemDetails, PayloadError> {
+        let invalid =
+            |reason: &str| PayloadError::Invalid(format!("{} in problem details", reason));
+        let map = match payload::decode::<Value>(data, Format::Cbor)? {
+            Value::Map(map) => map,
+            _ => return Err(invalid("no map")),
+        };
+        let mut problem = ProblemDetails::default();
+        for (key, value) in map {
+            let number = key.as_integer().map(i128::from);
+            if number.is_none_or(|number| number >= 0) {
+                problem.extensions.push((key, value));
+                continue;
+            }
+            let text = || match &value {
+                Value::Text(text) => Ok(Some(text.clone())),
+                _ => Err(invalid("invalid text")),
+            };
+            match number.and_then(|number| i64::try_from(number).ok()) {
+                Some(TITLE) => problem.title = text()?,
+                Some(DETAIL) => problem.detail = text()?,
+                Some(INSTANCE) => problem.instance = text()?,
+                Some(BASE_URI) => problem.base_uri = text()?,
+                Some(BASE_LANG) => problem.base_lang = text()?,
+                Some(BASE_RTL) => problem.base_rtl = text()?,
+                Some(RESPONSE_CODE) => {
+                    let code = value
+                        .as_integer()
+                        .and_then(|code| u8::try_from(code).ok())
+                        .ok_or_else(|| invalid("invalid response code"))?;
+                    problem.response_code = Some(code);
+                }
+                _ => {}
+            }
+        }
+        Ok(problem)
+    }
+}
+
+/// Turn `response` into an error response with `status`, describing the
+/// problem in its payload. The response code is added to the problem
+/// details.
+pub fn set(
+    response: &mut CoapResponse,
+    status: Status,
+    problem: &ProblemDetails,
+) -> Result<(), PayloadError> {
+    response.set_status(status);
+    let problem = ProblemDetails {
+        response_code: Some(u8::from(response.message.header.code)),
+        ..problem.clone()
+    };
+    response.message.payload = problem.to_cbor()?;
+    content_format::set(&mut response.message, CONTENT_FORMAT);
+    Ok(())
+}
+
+/// Return the problem details of a response, or `None` if its payload has
+/// another Content-Format.
+pub fn read(message: &Packet) -> Result<Option<ProblemDetails>, PayloadError> {
+    if content_format::get(message) != Some(CONTENT_FORMAT) {
+        return Ok(None);
+    }
+    ProblemDetails::from_cbor(&message.payload).map(Some)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let problem = ProblemDetails::new("Low battery")
+            .with_detail("The device runs on 3% battery")
+            .with_instance("/battery")
+            .with_extension(Value::from(4711), Value::from(3))
+            .with_extension(
+                Value::Text("tag:example.com,2023:battery".to_string()),
+                Value::Bool(true),
+            );
+        let decoded = ProblemDetails::from_cbor(&problem.to_cbor().unwrap()).unwrap();
+        assert_eq!(decoded, problem);
+        assert_eq!(decoded.extension(&Value::from(4711)), Some(&Value::from(3)));
+
+        // title "Bad", an unknown standard key
+        let data = [0xa2, 0x20, 0x63, b'B', b'a', b'd', 0x38, 0x63, 0x00];
+        let decoded = ProblemDetails::from_cbor(&data).unwrap();
+        assert_eq!(decoded, ProblemDetails::new("Bad"));
+        assert!(ProblemDetails::from_cbor(&[0x80]).is_err());
+    }
+
+    #[test]
+    fn test_response() {
+        let mut response = CoapResponse::new(&Packet::new()).unwrap();
+        assert_eq!(read(&response.message).unwrap(), None);
+        let problem = ProblemDetails::new("No such sensor");
+        set(&mut response, Status::NotFound, &problem).unwrap();
+        assert_eq!(*response.get_status(), Status::NotFound);
+        let read = read(&response.message).unwrap().unwrap();
+        assert_eq!(read.title.as_deref(), Some("No such sensor"));
+        assert_eq!(read.response_code, Some(0x84));
+    }
+}
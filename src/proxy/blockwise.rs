@@ -0,0 +1,202 @@
+//! Block-wise transfers between the legs of a forward proxy
+//! ([RFC 7959](https://tools.ietf.org/html/rfc7959)).
+//!
+//! The proxy collects the blocks of a representation from the target in
+//! the block size of the target leg before it relays the representation,
+//! and cuts it into the blocks of the client leg itself, which may be
+//! smaller: the block size the client asks for, or the one the proxy is
+//! set to. The representation is kept for the client until it fetched the
+//! last block or the transfer lifetime is over, so that the target is not
+//! asked again for every block.
+use coap_lite::{
+    block_handler::BlockValue, CoapOption, MessageClass, Packet, RequestType as Method,
+    ResponseType as Status,
+};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::budget::{BudgetedCache, Footprint};
+use crate::message;
+use crate::payload;
+
+/// How long a representation is kept for a client between two blocks.
+pub const DEFAULT_TRANSFER_LIFETIME: Duration = Duration::from_secs(120);
+
+/// How many representations are kept.
+const CAPACITY: usize = 1024;
+
+/// A representation being sent in blocks.
+struct Download {
+    response: Packet,
+}
+
+impl Footprint for Download {
+    fn footprint(&self) -> usize {
+        message::encoded_len(&self.response)
+    }
+}
+
+/// The representations a proxy sends in blocks, by client and target URI.
+pub(super) struct Downloads {
+    entries: BudgetedCache<(SocketAddr, String), Download>,
+}
+
+impl Downloads {
+    pub(super) fn new() -> Downloads {
+        Downloads {
+            entries: BudgetedCache::new(DEFAULT_TRANSFER_LIFETIME, CAPACITY),
+        }
+    }
+
+    /// Return the representation kept for a request of `client` for a
+    /// further block of the target at `uri`.
+    pub(super) fn get(
+        &mut self,
+        client: SocketAddr,
+        uri: &str,
+        request: &Packet,
+    ) -> Option<Packet> {
+        match requested(request) {
+            Some(block) if block.num > 0 => {}
+            _ => return None,
+        }
+        let key = (client, uri.to_string());
+        self.entries
+            .get(&key)
+            .map(|download| download.response.clone())
+    }
+
+    /// Cut the block `request` asks for from `response`, of at most
+    /// `block_size` bytes, keeping the representation for the next blocks.
+    /// A response that fits is left as it is.
+    pub(super) fn cut(
+        &mut self,
+        client: SocketAddr,
+        uri: &str,
+        request: &Packet,
+        response: &mut Packet,
+        block_size: Option<usize>,
+    ) {
+        if request.header.code != MessageClass::Request(Method::Get)
+            || response.header.code != MessageClass::Response(Status::Content)
+        {
+            return;
+        }
+        let requested = requested(request);
+        let size = match (block_size, &requested) {
+            (Some(size), Some(block)) => size.min(block.size()),
+            (None, Some(block)) => block.size(),
+            (Some(size), None) if response.payload.len() > size => size,
+            _ => return,
+        };
+        let key = (client, uri.to_string());
+        let body = response.payload.len();
+        let start = requested
+            .as_ref()
+            .map_or(0, |block| usize::from(block.num) * block.size());
+        if start > 0 && start >= body {
+            self.entries.remove(&key);
+            response.header.code = MessageClass::Response(Status::BadOption);
+            response.clear_option(CoapOption::ETag);
+            response.payload = b"Block out of range".to_vec();
+            return;
+        }
+        let end = body.min(start + size);
+        let block = match BlockValue::new(start / size, end < body, size) {
+            Ok(block) => block,
+            Err(_) => return,
+        };
+        if end < body {
+            let download = Download {
+                response: response.clone(),
+            };
+            // a representation beyond the budget is fetched again
+            let _ = self.entries.insert(key, download);
+        } else {
+            self.entries.remove(&key);
+        }
+        response.payload = response.payload[start..end].to_vec();
+        response.clear_option(CoapOption::Block2);
+        response.add_option_as(CoapOption::Block2, block);
+        if start == 0 || payload::size2(request).is_some() {
+            payload::set_size(response, CoapOption::Size2, body);
+        }
+    }
+}
+
+/// Return the Block2 option of a request.
+fn requested(request: &Packet) -> Option<BlockValue> {
+    request
+        .get_first_option_as::<BlockValue>(CoapOption::Block2)
+        .and_then(|block| block.ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cut() {
+        let mut downloads = Downloads::new();
+        let client: SocketAddr = "192.0.2.1:5683".parse().unwrap();
+        let uri = "coap://sensor.example/log";
+        let body: Vec<u8> = (0..100).collect();
+        let mut representation = Packet::new();
+        representation.header.code = MessageClass::Response(Status::Content);
+        representation.payload = body.clone();
+        let request = |block: Option<BlockValue>| {
+            let mut request = Packet::new();
+            request.header.code = MessageClass::Request(Method::Get);
+            if let Some(block) = block {
+                request.add_option_as(CoapOption::Block2, block);
+            }
+            request
+        };
+        let block2 = |response: &Packet| {
+            let block = response.get_first_option_as::<BlockValue>(CoapOption::Block2);
+            let block = block.unwrap().unwrap();
+            (block.num, block.more, block.size())
+        };
+
+        // a response that fits is relayed as it is
+        let mut response = representation.clone();
+        downloads.cut(client, uri, &request(None), &mut response, Some(128));
+        assert_eq!(response.payload, body);
+        assert!(response.get_option(CoapOption::Block2).is_none());
+
+        // cut in the block size of the proxy
+        let first = request(None);
+        let mut response = representation.clone();
+        downloads.cut(client, uri, &first, &mut response, Some(32));
+        assert_eq!(block2(&response), (0, true, 32));
+        assert_eq!(response.payload, body[..32]);
+        assert_eq!(payload::size2(&response), Some(100));
+
+        // further blocks come from the kept representation, in the smaller
+        // of the sizes of the client and the proxy
+        let second = request(Some(BlockValue::new(2, false, 16).unwrap()));
+        let mut response = downloads.get(client, uri, &second).unwrap();
+        downloads.cut(client, uri, &second, &mut response, Some(32));
+        assert_eq!(block2(&response), (2, true, 16));
+        assert_eq!(response.payload, body[32..48]);
+        assert!(downloads.get(client, uri, &request(None)).is_none());
+        let other: SocketAddr = "192.0.2.2:5683".parse().unwrap();
+        assert!(downloads.get(other, uri, &second).is_none());
+
+        // the last block ends the transfer
+        let last = request(Some(BlockValue::new(3, false, 32).unwrap()));
+        let mut response = downloads.get(client, uri, &last).unwrap();
+        downloads.cut(client, uri, &last, &mut response, Some(32));
+        assert_eq!(block2(&response), (3, false, 32));
+        assert_eq!(response.payload, body[96..]);
+        assert!(downloads.get(client, uri, &last).is_none());
+
+        let beyond = request(Some(BlockValue::new(7, false, 16).unwrap()));
+        let mut response = representation.clone();
+        downloads.cut(client, uri, &beyond, &mut response, None);
+        assert_eq!(
+            response.header.code,
+            MessageClass::Response(Status::BadOption)
+        );
+    }
+}
@@ -0,0 +1,808 @@
+//! Cross-proxying between HTTP and CoAP
+//! ([RFC 8075](https://tools.ietf.org/html/rfc8075), and
+//! [RFC 7252](https://tools.ietf.org/html/rfc7252) section 10.1 the other
+//! way).
+//!
+//! [`HttpProxy`] listens for HTTP/1.1 requests and forwards them to CoAP
+//! servers, so that HTTP clients can reach devices that speak CoAP only.
+//! The CoAP URI of the target follows a prefix of the path, `/hc/` by
+//! default, e.g. `http://gateway/hc/coap://sensor.example/temp`; it may be
+//! percent-encoded as a whole.
+//!
+//! The methods GET, POST, PUT, DELETE and PATCH are mapped to CoAP, others
+//! are answered with 501 Not Implemented. Content-Type and Accept are
+//! mapped to Content-Format and Accept, and a media type without a
+//! Content-Format is answered with 415 Unsupported Media Type. ETags, the
+//! conditional headers, Max-Age and the location of created resources are
+//! mapped as well, and the response codes as section 7 of the RFC says.
+//!
+//! The other way round, a [`ForwardProxy`] sends CoAP requests for `http`
+//! and `https` resources as HTTP/1.1 requests, over a connection of their
+//! own, and maps the HTTP responses back: the status to a response code
+//! depending on the method, Content-Type to Content-Format, ETag and
+//! Cache-Control max-age to the ETag and Max-Age options and Location to
+//! Location-Path and Location-Query. A media type without a Content-Format
+//! is answered with 5.02 Bad Gateway, as the client could not interpret
+//! the payload.
+use super::{percent_decode, ForwardProxy};
+use crate::content_format::{self, ContentFormat};
+use bytes::Bytes;
+use coap_lite::{
+    option_value::OptionValueU32, CoapOption, MessageClass, Packet, RequestType as Method,
+    ResponseType as Status,
+};
+use futures::future;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Body;
+use hyper::client::conn::http1 as client;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::http::response::Parts;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_util::rt::TokioIo;
+use log::debug;
+use std::convert::Infallible;
+use std::error::Error;
+use std::io;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(feature = "tls")]
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
+use url::{Position, Url};
+
+/// Default prefix of the paths that carry the URI of the target.
+pub const DEFAULT_URI_PREFIX: &str = "/hc/";
+
+/// Largest request body the proxy forwards.
+pub const MAX_BODY_SIZE: usize = 1 << 20;
+
+/// Max-Age of a response without the option.
+const DEFAULT_MAX_AGE: u32 = 60;
+
+/// An HTTP-to-CoAP proxy.
+#[derive(Clone)]
+pub struct HttpProxy {
+    prefix: String,
+    upstream: ForwardProxy,
+}
+
+impl Default for HttpProxy {
+    fn default() -> Self {
+        HttpProxy::new()
+    }
+}
+
+impl HttpProxy {
+    pub fn new() -> HttpProxy {
+        HttpProxy {
+            prefix: DEFAULT_URI_PREFIX.to_string(),
+            upstream: ForwardProxy::new(),
+        }
+    }
+
+    /// Take the URI of the target from the paths starting with `prefix`;
+    /// requests for other paths are answered with 404 Not Found.
+    pub fn set_uri_prefix(&mut self, prefix: &str) {
+        self.prefix = prefix.to_string();
+    }
+
+    /// Send the requests to their targets with `upstream`, e.g. to set
+    /// its timeouts or DTLS credentials.
+    pub fn set_upstream(&mut self, upstream: ForwardProxy) {
+        self.upstream = upstream;
+    }
+
+    /// Serve the HTTP connections accepted by `listener`, each on a task of
+    /// its own. Only returns if accepting a connection fails.
+    pub async fn run(self, listener: TcpListener) -> io::Result<()> {
+        let proxy = Arc::new(self);
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let proxy = proxy.clone();
+            tokio::spawn(async move {
+                let service = service_fn(|request| {
+                    let proxy = proxy.clone();
+                    async move { Ok::<_, Infallible>(proxy.handle(request).await) }
+                });
+                let connection =
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service);
+                if let Err(e) = connection.await {
+                    debug!("HTTP connection from {} failed: {}", peer, e);
+                }
+            });
+        }
+    }
+
+    /// Forward an HTTP request to its target and return the response to
+    /// relay, e.g. from a service of an HTTP server of the application.
+    pub async fn handle<B>(&self, request: Request<B>) -> Response<Full<Bytes>>
+    where
+        B: Body,
+        B::Error: Into<Box<dyn Error + Send + Sync>>,
+    {
+        let (parts, body) = request.into_parts();
+        let url = match self.target(&parts.uri) {
+            Some(url) => url,
+            None => return error(StatusCode::NOT_FOUND, "no CoAP URI"),
+        };
+        let method = match method(&parts.method) {
+            Some(method) => method,
+            None => return error(StatusCode::NOT_IMPLEMENTED, "method not supported"),
+        };
+        let body = match Limited::new(body, MAX_BODY_SIZE).collect().await {
+            Ok(body) => body.to_bytes(),
+            Err(_) => return error(StatusCode::PAYLOAD_TOO_LARGE, "body too large"),
+        };
+
+        let mut message = Packet::new();
+        message.header.code = MessageClass::Request(method);
+        message.add_option(CoapOption::ProxyUri, url.as_str().as_bytes().to_vec());
+        let (mut message, url) = match self.upstream.prepare(&mut message) {
+            Ok(prepared) => prepared,
+            Err(status) => return error(http_status(status), "invalid CoAP URI"),
+        };
+        if let Err(status) = map_headers(&parts.headers, &mut message) {
+            return error(status, "media type not supported");
+        }
+        message.payload = body.to_vec();
+        match self.upstream.send(message, url.clone()).await {
+            Ok(response) => self.response(&url, &response),
+            Err(status) => error(http_status(status), "CoAP server not reachable"),
+        }
+    }
+
+    /// Return the CoAP URI of the target of a request.
+    fn target(&self, uri: &Uri) -> Option<Url> {
+        let path = uri.path_and_query()?.as_str();
+        let target = path.strip_prefix(&self.prefix)?;
+        let url = match Url::parse(target) {
+            Ok(url) => url,
+            Err(_) => Url::parse(&percent_decode(target)?).ok()?,
+        };
+        url.scheme().starts_with("coap").then_some(url)
+    }
+
+    /// Return the HTTP response for the CoAP response of the target at
+    /// `url`.
+    fn response(&self, url: &Url, message: &Packet) -> Response<Full<Bytes>> {
+        let status = match message.header.code {
+            MessageClass::Response(status) => http_status(status),
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        let mut response = Response::new(Full::new(Bytes::from(message.payload.clone())));
+        *response.status_mut() = status;
+        let headers = response.headers_mut();
+        match content_format::get(message) {
+            Some(format) => {
+                if let Some(value) = format
+                    .media_type()
+                    .and_then(|media_type| HeaderValue::from_str(media_type).ok())
+                {
+                    headers.insert(header::CONTENT_TYPE, value);
+                }
+            }
+            // the payload of an error response is a diagnostic message
+            None if !status.is_success() && !message.payload.is_empty() => {
+                let value = HeaderValue::from_static("text/plain; charset=utf-8");
+                headers.insert(header::CONTENT_TYPE, value);
+            }
+            None => {}
+        }
+        if let Some(etag) = message
+            .get_option(CoapOption::ETag)
+            .and_then(|values| values.front())
+        {
+            if let Ok(value) = HeaderValue::from_str(&format!("\"{}\"", hex(etag))) {
+                headers.insert(header::ETAG, value);
+            }
+        }
+        if status.is_success() || status == StatusCode::NOT_MODIFIED {
+            let max_age = message
+                .get_first_option_as::<OptionValueU32>(CoapOption::MaxAge)
+                .and_then(|value| value.ok())
+                .map_or(DEFAULT_MAX_AGE, |value| value.0);
+            if let Ok(value) = HeaderValue::from_str(&format!("max-age={}", max_age)) {
+                headers.insert(header::CACHE_CONTROL, value);
+            }
+        }
+        if let Some(location) = self.location(url, message) {
+            headers.insert(header::LOCATION, location);
+        }
+        response
+    }
+
+    /// Return the Location of a resource created at the Location-Path and
+    /// Location-Query of the response.
+    fn location(&self, url: &Url, message: &Packet) -> Option<HeaderValue> {
+        let option = |option| -> Vec<String> {
+            message
+                .get_option(option)
+                .into_iter()
+                .flatten()
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+                .collect()
+        };
+        let path = option(CoapOption::LocationPath);
+        let query = option(CoapOption::LocationQuery);
+        if path.is_empty() && query.is_empty() {
+            return None;
+        }
+        let mut location = url.clone();
+        location.path_segments_mut().ok()?.clear().extend(path);
+        location.set_query(None);
+        if !query.is_empty() {
+            location.set_query(Some(&query.join("&")));
+        }
+        HeaderValue::from_str(&format!("{}{}", self.prefix, location)).ok()
+    }
+}
+
+/// Return the CoAP method of an HTTP method.
+fn method(method: &hyper::Method) -> Option<Method> {
+    Some(match *method {
+        hyper::Method::GET => Method::Get,
+        hyper::Method::POST => Method::Post,
+        hyper::Method::PUT => Method::Put,
+        hyper::Method::DELETE => Method::Delete,
+        hyper::Method::PATCH => Method::Patch,
+        _ => return None,
+    })
+}
+
+/// Map the headers of an HTTP request to options of the CoAP request. An
+/// error is the status to answer the request with.
+fn map_headers(headers: &HeaderMap, message: &mut Packet) -> Result<(), StatusCode> {
+    let text = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    if let Some(media_type) = text(header::CONTENT_TYPE) {
+        let format =
+            ContentFormat::from_media_type(media_type).ok_or(StatusCode::UNSUPPORTED_MEDIA_TYPE)?;
+        content_format::set(message, format);
+    }
+    // only a single media type maps to the Accept option
+    if let Some(format) = text(header::ACCEPT)
+        .filter(|accept| !accept.contains(',') && !accept.contains('*'))
+        .and_then(ContentFormat::from_media_type)
+    {
+        content_format::set_accept(message, format);
+    }
+    if let Some(etags) = text(header::IF_MATCH) {
+        for etag in parse_etags(etags).ok_or(StatusCode::BAD_REQUEST)? {
+            message.add_option(CoapOption::IfMatch, etag);
+        }
+    }
+    if let Some(etags) = text(header::IF_NONE_MATCH) {
+        match etags.trim() {
+            "*" => message.add_option(CoapOption::IfNoneMatch, Vec::new()),
+            // validation of representations the client has
+            etags => {
+                for etag in parse_etags(etags).ok_or(StatusCode::BAD_REQUEST)? {
+                    message.add_option(CoapOption::ETag, etag);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Parse a list of ETags of the form the proxy sends, the hexadecimal
+/// CoAP ETag in quotes. `*` is the empty ETag, which matches any.
+fn parse_etags(etags: &str) -> Option<Vec<Vec<u8>>> {
+    etags
+        .split(',')
+        .map(|etag| {
+            let etag = etag.trim().trim_start_matches("W/");
+            if etag == "*" {
+                return Some(Vec::new());
+            }
+            let etag = etag.strip_prefix('"')?.strip_suffix('"')?;
+            if etag.len() % 2 != 0 || etag.len() > 16 {
+                return None;
+            }
+            (0..etag.len())
+                .step_by(2)
+                .map(|at| u8::from_str_radix(etag.get(at..at + 2)?, 16).ok())
+                .collect()
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Return the HTTP status of a CoAP response code.
+fn http_status(status: Status) -> StatusCode {
+    match status {
+        Status::Created => StatusCode::CREATED,
+        Status::Deleted | Status::Changed | Status::Content => StatusCode::OK,
+        Status::Valid => StatusCode::NOT_MODIFIED,
+        Status::BadRequest
+        | Status::BadOption
+        | Status::MethodNotAllowed
+        | Status::RequestEntityIncomplete => StatusCode::BAD_REQUEST,
+        Status::Unauthorized | Status::Forbidden => StatusCode::FORBIDDEN,
+        Status::NotFound => StatusCode::NOT_FOUND,
+        Status::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+        Status::Conflict => StatusCode::CONFLICT,
+        Status::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+        Status::RequestEntityTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+        Status::UnsupportedContentFormat => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        Status::UnprocessableEntity => StatusCode::UNPROCESSABLE_ENTITY,
+        Status::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
+        Status::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
+        Status::NotImplemented => StatusCode::NOT_IMPLEMENTED,
+        Status::ServiceUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        Status::GatewayTimeout => StatusCode::GATEWAY_TIMEOUT,
+        Status::HopLimitReached => StatusCode::LOOP_DETECTED,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// Return a response with the status and a diagnostic message.
+fn error(status: StatusCode, diagnostic: &'static str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from_static(diagnostic.as_bytes())));
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+/// Exchange a CoAP request with the HTTP server at `url` and return the
+/// CoAP response to relay, or the status to answer the request with.
+pub(super) async fn exchange(
+    proxy: &ForwardProxy,
+    message: &Packet,
+    url: &Url,
+) -> Result<Packet, Status> {
+    let method = match message.header.code {
+        MessageClass::Request(method) => method,
+        _ => return Err(Status::BadRequest),
+    };
+    let request = http_request(method, message, url)?;
+    let host = url.host_str().ok_or(Status::BadRequest)?;
+    let port = url.port_or_known_default().ok_or(Status::BadRequest)?;
+    let response = tokio::time::timeout(proxy.patience(), async {
+        let stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
+        match url.scheme() {
+            #[cfg(feature = "tls")]
+            "https" => {
+                let config = proxy.https.clone().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::Unsupported, "no TLS configuration")
+                })?;
+                let name = ServerName::try_from(host.trim_matches(['[', ']']).to_string())
+                    .map_err(io::Error::other)?;
+                let stream = TlsConnector::from(config).connect(name, stream).await?;
+                send(TokioIo::new(stream), request).await
+            }
+            _ => send(TokioIo::new(stream), request).await,
+        }
+    })
+    .await;
+    match response {
+        Ok(Ok((parts, body))) => coap_response(method, &parts, body),
+        Ok(Err(e)) => {
+            debug!("forwarding to {} failed: {}", url, e);
+            Err(Status::BadGateway)
+        }
+        Err(_) => Err(Status::GatewayTimeout),
+    }
+}
+
+/// Send an HTTP request over a new connection and return the response.
+async fn send<I>(io: I, request: Request<Full<Bytes>>) -> io::Result<(Parts, Bytes)>
+where
+    I: hyper::rt::Read + hyper::rt::Write + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = client::handshake(io).await.map_err(io::Error::other)?;
+    let exchange = async {
+        let response = sender.send_request(request).await?;
+        let (parts, body) = response.into_parts();
+        let body = Limited::new(body, MAX_BODY_SIZE).collect().await?;
+        Ok::<_, Box<dyn Error + Send + Sync>>((parts, body.to_bytes()))
+    };
+    // the connection is driven along and closes after the response
+    let (response, _) = future::join(exchange, connection).await;
+    response.map_err(io::Error::other)
+}
+
+/// Return the HTTP request for a CoAP request to the resource at `url`.
+fn http_request(
+    method: Method,
+    message: &Packet,
+    url: &Url,
+) -> Result<Request<Full<Bytes>>, Status> {
+    let method = match method {
+        Method::Get => hyper::Method::GET,
+        Method::Post => hyper::Method::POST,
+        Method::Put => hyper::Method::PUT,
+        Method::Delete => hyper::Method::DELETE,
+        Method::Patch => hyper::Method::PATCH,
+        _ => return Err(Status::NotImplemented),
+    };
+    let mut request = Request::builder()
+        .method(method)
+        .uri(&url[Position::BeforePath..])
+        .header(
+            header::HOST,
+            &url[Position::BeforeHost..Position::AfterPort],
+        )
+        .header(header::CONNECTION, "close");
+    if let Some(format) = content_format::get(message) {
+        let media_type = format
+            .media_type()
+            .ok_or(Status::UnsupportedContentFormat)?;
+        request = request.header(header::CONTENT_TYPE, media_type);
+    }
+    let accept: Vec<_> = content_format::accept(message)
+        .into_iter()
+        .filter_map(ContentFormat::media_type)
+        .collect();
+    if !accept.is_empty() {
+        request = request.header(header::ACCEPT, accept.join(", "));
+    }
+    let etags = |option| {
+        let etags = message.get_option(option).into_iter().flatten();
+        let etags: Vec<_> = etags
+            .map(|etag| match etag.is_empty() {
+                true => "*".to_string(),
+                false => format!("\"{}\"", hex(etag)),
+            })
+            .collect();
+        (!etags.is_empty()).then(|| etags.join(", "))
+    };
+    if let Some(etags) = etags(CoapOption::IfMatch) {
+        request = request.header(header::IF_MATCH, etags);
+    }
+    let if_none_match = message
+        .get_option(CoapOption::IfNoneMatch)
+        .is_some_and(|values| !values.is_empty());
+    if if_none_match {
+        request = request.header(header::IF_NONE_MATCH, "*");
+    } else if let Some(etags) = etags(CoapOption::ETag) {
+        // validation of representations the client has
+        request = request.header(header::IF_NONE_MATCH, etags);
+    }
+    request
+        .body(Full::new(Bytes::from(message.payload.clone())))
+        .map_err(|_| Status::BadRequest)
+}
+
+/// Return the CoAP response for the HTTP response to a request with
+/// `method`.
+fn coap_response(method: Method, parts: &Parts, body: Bytes) -> Result<Packet, Status> {
+    let mut message = Packet::new();
+    message.header.code = MessageClass::Response(coap_status(method, parts.status));
+    let text = |name| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    if let Some(media_type) = text(header::CONTENT_TYPE) {
+        let format = ContentFormat::from_media_type(media_type).or_else(|| {
+            // a parameter the Content-Formats do not distinguish
+            ContentFormat::from_media_type(media_type.split(';').next()?.trim())
+        });
+        match format {
+            Some(format) => content_format::set(&mut message, format),
+            None if !body.is_empty() => {
+                debug!("no Content-Format for {}", media_type);
+                return Err(Status::BadGateway);
+            }
+            None => {}
+        }
+    }
+    if let Some(etag) = text(header::ETAG).and_then(coap_etag) {
+        message.add_option(CoapOption::ETag, etag);
+    }
+    if let Some(max_age) = text(header::CACHE_CONTROL).and_then(max_age) {
+        message.add_option_as(CoapOption::MaxAge, OptionValueU32(max_age));
+    }
+    if let Some(location) = text(header::LOCATION) {
+        // only a location on the same server has a path to give
+        let path = location
+            .starts_with('/')
+            .then_some(location)
+            .and_then(|location| location.split('#').next());
+        if let Some(path) = path {
+            let (path, query) = path.split_once('?').unwrap_or((path, ""));
+            for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+                let segment = percent_decode(segment).ok_or(Status::BadGateway)?;
+                message.add_option(CoapOption::LocationPath, segment.into_bytes());
+            }
+            for argument in query.split('&').filter(|argument| !argument.is_empty()) {
+                let argument = percent_decode(argument).ok_or(Status::BadGateway)?;
+                message.add_option(CoapOption::LocationQuery, argument.into_bytes());
+            }
+        }
+    }
+    message.payload = body.to_vec();
+    Ok(message)
+}
+
+/// Return the CoAP ETag for an HTTP entity tag: the ETag it was made from,
+/// or else the tag itself if it fits. A weak tag has no CoAP ETag.
+fn coap_etag(etag: &str) -> Option<Vec<u8>> {
+    if etag.starts_with("W/") {
+        return None;
+    }
+    match parse_etags(etag) {
+        Some(etags) if etags.len() == 1 && !etags[0].is_empty() => etags.into_iter().next(),
+        _ => {
+            let etag = etag.trim().trim_matches('"').as_bytes();
+            (1..=8).contains(&etag.len()).then(|| etag.to_vec())
+        }
+    }
+}
+
+/// Return the max-age of a Cache-Control header, 0 if the response is not
+/// to be reused.
+fn max_age(cache_control: &str) -> Option<u32> {
+    cache_control.split(',').find_map(|directive| {
+        let directive = directive.trim().to_ascii_lowercase();
+        match directive.split_once('=') {
+            Some(("max-age", seconds)) => seconds.trim_matches('"').parse().ok(),
+            None if directive == "no-store" || directive == "no-cache" => Some(0),
+            _ => None,
+        }
+    })
+}
+
+/// Return the CoAP response code of an HTTP status to a request with
+/// `method`.
+fn coap_status(method: Method, status: StatusCode) -> Status {
+    match status.as_u16() {
+        201 => Status::Created,
+        204 if method == Method::Delete => Status::Deleted,
+        204 => Status::Changed,
+        304 => Status::Valid,
+        400 => Status::BadRequest,
+        401 => Status::Unauthorized,
+        403 => Status::Forbidden,
+        404 | 410 => Status::NotFound,
+        405 => Status::MethodNotAllowed,
+        406 => Status::NotAcceptable,
+        409 => Status::Conflict,
+        412 => Status::PreconditionFailed,
+        413 => Status::RequestEntityTooLarge,
+        415 => Status::UnsupportedContentFormat,
+        422 => Status::UnprocessableEntity,
+        429 => Status::TooManyRequests,
+        500 => Status::InternalServerError,
+        501 => Status::NotImplemented,
+        503 => Status::ServiceUnavailable,
+        504 => Status::GatewayTimeout,
+        _ if status.is_success() => match method {
+            Method::Get | Method::Fetch => Status::Content,
+            Method::Delete => Status::Deleted,
+            _ => Status::Changed,
+        },
+        _ if status.is_client_error() => Status::BadRequest,
+        _ => Status::BadGateway,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::server::test::spawn_server;
+    use coap_lite::{CoapRequest, CoapResponse};
+    use std::io::{Read, Write};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_mapping() {
+        let proxy = HttpProxy::new();
+        let uri: Uri = "/hc/coap://sensor.example/temp?unit=C".parse().unwrap();
+        let url = proxy.target(&uri).unwrap();
+        assert_eq!(url.as_str(), "coap://sensor.example/temp?unit=C");
+        let uri: Uri = "/hc/coap%3A%2F%2F%5B2001%3Adb8%3A%3A1%5D%2Ftemp"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            proxy.target(&uri).unwrap().as_str(),
+            "coap://[2001:db8::1]/temp"
+        );
+        assert_eq!(
+            proxy.target(&"/hc/http://example.com/".parse().unwrap()),
+            None
+        );
+        assert_eq!(proxy.target(&"/other".parse().unwrap()), None);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        headers.insert(header::ACCEPT, HeaderValue::from_static("application/cbor"));
+        headers.insert(
+            header::IF_NONE_MATCH,
+            HeaderValue::from_static("\"0a0b\", \"01\""),
+        );
+        let mut message = Packet::new();
+        map_headers(&headers, &mut message).unwrap();
+        assert_eq!(content_format::get(&message), Some(ContentFormat::Json));
+        assert_eq!(content_format::accept(&message), [ContentFormat::Cbor]);
+        let etags: Vec<_> = message
+            .get_option(CoapOption::ETag)
+            .unwrap()
+            .iter()
+            .collect();
+        assert_eq!(etags, [&vec![0x0a, 0x0b], &vec![0x01]]);
+
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/x-unknown"),
+        );
+        assert_eq!(
+            map_headers(&headers, &mut Packet::new()),
+            Err(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        );
+        assert_eq!(parse_etags("W/\"xyz\""), None);
+        assert_eq!(http_status(Status::Changed), StatusCode::OK);
+        assert_eq!(
+            http_status(Status::ProxyingNotSupported),
+            StatusCode::BAD_GATEWAY
+        );
+    }
+
+    #[test]
+    fn test_http_proxy() {
+        async fn handler(request: CoapRequest<SocketAddr>) -> Option<CoapResponse> {
+            let mut response = request.response?;
+            response.set_status(Status::Created);
+            response.message.payload = request.message.payload;
+            content_format::set(&mut response.message, ContentFormat::TextPlain);
+            response
+                .message
+                .add_option(CoapOption::LocationPath, b"items".to_vec());
+            response
+                .message
+                .add_option(CoapOption::LocationPath, b"1".to_vec());
+            Some(response)
+        }
+        let coap_port = spawn_server("127.0.0.1:0", handler).recv().unwrap();
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let http_addr = listener.local_addr().unwrap();
+        runtime.spawn(HttpProxy::new().run(listener));
+
+        let mut stream = std::net::TcpStream::connect(http_addr).unwrap();
+        write!(
+            stream,
+            "POST /hc/coap://127.0.0.1:{}/items HTTP/1.1\r\nHost: gateway\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Length: 5\r\n\
+             Connection: close\r\n\r\nhello",
+            coap_port
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 201 Created\r\n"),
+            "{}",
+            response
+        );
+        let location = format!("location: /hc/coap://127.0.0.1:{}/items/1\r\n", coap_port);
+        assert!(response.contains(&location), "{}", response);
+        assert!(response.contains("content-type: text/plain; charset=utf-8\r\n"));
+        assert!(response.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_response_mapping() {
+        assert_eq!(coap_status(Method::Get, StatusCode::OK), Status::Content);
+        assert_eq!(coap_status(Method::Put, StatusCode::OK), Status::Changed);
+        assert_eq!(
+            coap_status(Method::Delete, StatusCode::NO_CONTENT),
+            Status::Deleted
+        );
+        assert_eq!(coap_status(Method::Get, StatusCode::GONE), Status::NotFound);
+        assert_eq!(
+            coap_status(Method::Get, StatusCode::IM_A_TEAPOT),
+            Status::BadRequest
+        );
+        assert_eq!(
+            coap_status(Method::Get, StatusCode::BAD_GATEWAY),
+            Status::BadGateway
+        );
+        assert_eq!(coap_etag("\"0a0b\""), Some(vec![0x0a, 0x0b]));
+        assert_eq!(coap_etag("\"v1\""), Some(b"v1".to_vec()));
+        assert_eq!(coap_etag("\"a-very-long-tag\""), None);
+        assert_eq!(coap_etag("W/\"0a\""), None);
+        assert_eq!(max_age("public, max-age=30"), Some(30));
+        assert_eq!(max_age("no-store"), Some(0));
+        assert_eq!(max_age("public"), None);
+
+        let mut message = Packet::new();
+        message.header.code = MessageClass::Request(Method::Get);
+        content_format::set_accept(&mut message, ContentFormat::Json);
+        message.add_option(CoapOption::ETag, vec![1, 2]);
+        let url = Url::parse("http://example.com:8080/a/b?c=d").unwrap();
+        let request = http_request(Method::Get, &message, &url).unwrap();
+        assert_eq!(request.uri(), "/a/b?c=d");
+        assert_eq!(request.headers()[header::HOST], "example.com:8080");
+        assert_eq!(request.headers()[header::ACCEPT], "application/json");
+        assert_eq!(request.headers()[header::IF_NONE_MATCH], "\"0102\"");
+        assert_eq!(
+            http_request(Method::IPatch, &message, &url).err(),
+            Some(Status::NotImplemented)
+        );
+    }
+
+    #[test]
+    fn test_coap_to_http() {
+        let http = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let http_addr = http.local_addr().unwrap();
+        let (requests, received) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = http.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            requests.send(String::from_utf8(request).unwrap()).unwrap();
+            stream
+                .write_all(
+                    b"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                      ETag: \"0102\"\r\nCache-Control: max-age=30\r\n\
+                      Content-Length: 2\r\nConnection: close\r\n\r\n{}",
+                )
+                .unwrap();
+        });
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = crate::Server::new("127.0.0.1:0").unwrap();
+                    server.set_forward_proxy(ForwardProxy::new());
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|request: CoapRequest<SocketAddr>| async { request.response })
+                        .await
+                        .unwrap();
+                })
+        });
+
+        let client = crate::CoAPClient::new(rx.recv().unwrap()).unwrap();
+        let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+        request.set_method(Method::Get);
+        let uri = format!("http://{}/sensors/temp", http_addr);
+        request
+            .message
+            .add_option(CoapOption::ProxyUri, uri.into_bytes());
+        content_format::set_accept(&mut request.message, ContentFormat::Json);
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+
+        let received = received.recv().unwrap();
+        assert!(
+            received.starts_with("GET /sensors/temp HTTP/1.1\r\n"),
+            "{}",
+            received
+        );
+        assert!(
+            received.contains("accept: application/json\r\n"),
+            "{}",
+            received
+        );
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(
+            content_format::get(&response.message),
+            Some(ContentFormat::Json)
+        );
+        let etag = response.message.get_option(CoapOption::ETag).unwrap();
+        assert_eq!(etag.front().unwrap(), &[1, 2]);
+        let max_age = response
+            .message
+            .get_first_option_as::<OptionValueU32>(CoapOption::MaxAge);
+        assert_eq!(max_age.unwrap().unwrap().0, 30);
+        assert_eq!(response.message.payload, b"{}");
+    }
+}
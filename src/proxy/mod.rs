@@ -0,0 +1,756 @@
+//! Recognition of proxy requests
+//! ([RFC 7252](https://tools.ietf.org/html/rfc7252) section 5.7).
+//!
+//! A request with a Proxy-Uri or Proxy-Scheme option asks the server to
+//! forward it rather than to serve it. Such requests are passed to the
+//! handler set with
+//! [`Server::set_proxy_handler`](crate::Server::set_proxy_handler), which
+//! finds the resource to forward to with [`target`]; without one, the
+//! server answers them with 5.05 Proxying Not Supported.
+//!
+//! A proxy only needs to recognize the options that are unsafe to forward:
+//! the server answers proxy requests with unrecognized unsafe options with
+//! 5.02 Bad Gateway and passes on all others. That lets OSCORE-protected
+//! requests ([RFC 8613](https://tools.ietf.org/html/rfc8613)) through,
+//! whose OSCORE option is safe to forward. Their payload and inner options
+//! are encrypted end to end, so [`forward_request`] only rewrites their
+//! outer options and forwards the payload untouched.
+//!
+//! [`ForwardProxy`] is a complete forward proxy on top of these: set with
+//! [`Server::set_forward_proxy`](crate::Server::set_forward_proxy), it
+//! sends each proxy request on to its target with a [`CoAPClient`] and
+//! relays the response. [`ReverseProxy`] serves the resources of upstream
+//! servers under path prefixes of its own, see [`reverse`].
+//!
+//! With the `http` feature, [`HttpProxy`] forwards HTTP requests to CoAP
+//! servers, see [`http`].
+use super::cache::{self, ResponseCache};
+use super::client::CoAPClient;
+use super::etag;
+use super::mtu;
+use super::observer::ObserveRegistry;
+use super::payload::ResponseFuture;
+#[cfg(feature = "dtls")]
+use super::transport::dtls;
+#[cfg(all(feature = "http", feature = "tls"))]
+use super::transport::tls;
+use coap_lite::{
+    block_handler::BlockValue, option_value::OptionValueU16, CoapOption, CoapRequest, MessageClass,
+    MessageType, Packet, RequestType as Method, ResponseType as Status,
+};
+use futures::channel::oneshot;
+use log::debug;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use url::{Host, Url};
+
+use self::blockwise::Downloads;
+use self::observe::Observations;
+
+pub mod blockwise;
+#[cfg(feature = "http")]
+pub mod http;
+mod observe;
+pub mod reverse;
+
+#[cfg(feature = "http")]
+pub use self::http::HttpProxy;
+pub use self::reverse::{Balance, ReverseProxy};
+
+/// Number of the Hop-Limit option
+/// ([RFC 8768](https://tools.ietf.org/html/rfc8768)).
+pub const HOP_LIMIT: u16 = 16;
+
+/// Hop-Limit a proxy inserts into requests without one.
+pub const DEFAULT_HOP_LIMIT: u8 = 16;
+
+/// Default time to wait for the target to answer before retransmitting.
+pub const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Default number of retransmissions of a request to the target.
+pub const DEFAULT_UPSTREAM_RETRANSMIT: u32 = 2;
+
+/// Default time without word from an observed target after which the proxy
+/// registers with it again: the default Max-Age of a notification.
+pub const DEFAULT_REREGISTER_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Return whether the request is to be forwarded by a proxy.
+pub fn is_proxy_request(message: &Packet) -> bool {
+    [CoapOption::ProxyUri, CoapOption::ProxyScheme]
+        .into_iter()
+        .any(|option| {
+            message
+                .get_option(option)
+                .is_some_and(|values| !values.is_empty())
+        })
+}
+
+/// Return the URI of the resource a proxy request is to be forwarded to:
+/// the Proxy-Uri, or else the URI composed of the Proxy-Scheme and the
+/// Uri-Host, Uri-Port, Uri-Path and Uri-Query options. `None` if the
+/// request is no proxy request or the URI is invalid. A Proxy-Scheme
+/// request without Uri-Host has no target, as the server does not know
+/// the address the request was sent to.
+pub fn target(message: &Packet) -> Option<Url> {
+    let text = |option| {
+        message
+            .get_option(option)
+            .into_iter()
+            .flatten()
+            .map(|value| String::from_utf8(value.clone()).ok())
+            .collect::<Option<Vec<String>>>()
+    };
+    if let Some(uri) = text(CoapOption::ProxyUri)?.first() {
+        // Proxy-Uri takes precedence over the Uri-* options
+        return Url::parse(uri).ok();
+    }
+    let scheme = text(CoapOption::ProxyScheme)?.first()?.to_lowercase();
+    let host = text(CoapOption::UriHost)?.first()?.clone();
+    let mut url = Url::parse(&format!("{}://{}", scheme, host)).ok()?;
+    if let Some(port) = message
+        .get_option(CoapOption::UriPort)
+        .and_then(|values| values.front())
+    {
+        let port = port
+            .iter()
+            .fold(0u32, |port, byte| port << 8 | u32::from(*byte));
+        url.set_port(Some(u16::try_from(port).ok()?)).ok()?;
+    }
+    url.path_segments_mut()
+        .ok()?
+        .clear()
+        .extend(text(CoapOption::UriPath)?);
+    let query = text(CoapOption::UriQuery)?;
+    if !query.is_empty() {
+        url.set_query(Some(&query.join("&")));
+    }
+    Some(url)
+}
+
+/// Return whether the message is protected with OSCORE.
+pub fn is_oscore(message: &Packet) -> bool {
+    message
+        .get_option(CoapOption::Oscore)
+        .is_some_and(|values| !values.is_empty())
+}
+
+/// Return the request to send to the next hop for a proxy request: without
+/// Proxy-Uri and Proxy-Scheme, with a Proxy-Uri decomposed into Uri-Host,
+/// Uri-Port, Uri-Path and Uri-Query options, and otherwise as it is. An
+/// error is the status to answer the request with: 4.00 Bad Request if it
+/// has no valid target.
+///
+/// The Uri-Path and Uri-Query options of an OSCORE-protected request are
+/// encrypted, so its Proxy-Uri may only name the scheme, host and port; one
+/// with a path or query is answered with 4.02 Bad Option.
+pub fn forward_request(message: &Packet) -> Result<Packet, Status> {
+    let url = target(message).ok_or(Status::BadRequest)?;
+    let mut forwarded = message.clone();
+    forwarded.clear_option(CoapOption::ProxyScheme);
+    let proxy_uri = message
+        .get_option(CoapOption::ProxyUri)
+        .is_some_and(|values| !values.is_empty());
+    if !proxy_uri {
+        // already decomposed by the client
+        return Ok(forwarded);
+    }
+    let has_path = !matches!(url.path(), "" | "/");
+    if is_oscore(message) && (has_path || url.query().is_some()) {
+        return Err(Status::BadOption);
+    }
+    forwarded.clear_option(CoapOption::ProxyUri);
+    for option in [
+        CoapOption::UriHost,
+        CoapOption::UriPort,
+        CoapOption::UriPath,
+        CoapOption::UriQuery,
+    ] {
+        forwarded.clear_option(option);
+    }
+
+    if let Some(host) = uri_host(&url) {
+        forwarded.add_option(CoapOption::UriHost, host.as_bytes().to_vec());
+    }
+    if let Some(port) = url
+        .port()
+        .filter(|port| Some(*port) != default_port(url.scheme()))
+    {
+        forwarded.add_option_as(CoapOption::UriPort, OptionValueU16(port));
+    }
+    let segments = url.path_segments().into_iter().flatten();
+    for segment in segments.filter(|_| has_path) {
+        let segment = percent_decode(segment).ok_or(Status::BadRequest)?;
+        forwarded.add_option(CoapOption::UriPath, segment.into_bytes());
+    }
+    for argument in url.query().into_iter().flat_map(|query| query.split('&')) {
+        let argument = percent_decode(argument).ok_or(Status::BadRequest)?;
+        forwarded.add_option(CoapOption::UriQuery, argument.into_bytes());
+    }
+    Ok(forwarded)
+}
+
+/// Decrement the Hop-Limit of a request to forward, or give it a
+/// Hop-Limit of `initial` if it has none, so that a request going round a
+/// loop of proxies is dropped in the end. An error is the status to answer
+/// the request with: 5.08 Hop Limit Reached if no hops are left, or 4.00
+/// Bad Request if the Hop-Limit is invalid.
+pub fn limit_hops(message: &mut Packet, initial: u8) -> Result<(), Status> {
+    let option = CoapOption::Unknown(HOP_LIMIT);
+    let limit = match message.get_option(option).and_then(|values| values.front()) {
+        Some(value) => match value[..] {
+            [limit @ 1..=255] => limit,
+            _ => return Err(Status::BadRequest),
+        },
+        None => {
+            message.add_option(option, vec![initial.max(1)]);
+            return Ok(());
+        }
+    };
+    if limit == 1 {
+        return Err(Status::HopLimitReached);
+    }
+    message.clear_option(option);
+    message.add_option(option, vec![limit - 1]);
+    Ok(())
+}
+
+/// A forward proxy: sends proxy requests on to their targets and relays
+/// the responses.
+///
+/// Each request goes out from a client of its own, on a thread of its own,
+/// with a fresh token and Message ID; the proxy only answers the request
+/// it was sent once the target answered. Forwarded requests carry a
+/// Hop-Limit, see [`limit_hops`], which ends forwarding loops between
+/// proxies with 5.08 Hop Limit Reached. A body sent block-wise is
+/// reassembled before it is forwarded, and a response sent block-wise by
+/// the target is collected before it is relayed, so that both legs choose
+/// their block sizes independently, see [`blockwise`].
+///
+/// Observe requests get the current state only, unless the proxy has the
+/// registry of the observers of its server, see
+/// [`set_observe_registry`](Self::set_observe_registry): then it observes
+/// each target once for all of its clients and notifies them itself.
+///
+/// The `coap` and `coap+tcp` schemes are supported, `coap+ws` with the
+/// `websocket` feature and `coaps` with the `dtls` feature once credentials
+/// are set. With the `http` feature, requests are also forwarded to `http`
+/// targets, and to `https` ones with the `tls` feature once a TLS
+/// configuration is set, see [`http`]. Requests for other schemes are
+/// answered with 5.05 Proxying Not Supported. A target that cannot be
+/// reached is answered with 5.02 Bad Gateway, and one that does not answer
+/// in time with 5.04 Gateway Timeout.
+///
+/// With a cache, fresh responses to GET requests are served without asking
+/// the target, and stale ones with an ETag are validated with it; see
+/// [`cache`](crate::cache). The cache is shared by the clones of a proxy.
+#[derive(Clone)]
+pub struct ForwardProxy {
+    timeout: Duration,
+    max_retransmit: u32,
+    cache: Option<Arc<Mutex<ResponseCache>>>,
+    observations: Option<Arc<Observations>>,
+    reregister: Duration,
+    hop_limit: u8,
+    downloads: Arc<Mutex<Downloads>>,
+    block_size: Option<usize>,
+    upstream_block_size: Option<usize>,
+    #[cfg(feature = "dtls")]
+    dtls: Option<dtls::PskConfig>,
+    #[cfg(all(feature = "http", feature = "tls"))]
+    https: Option<Arc<tls::rustls::ClientConfig>>,
+}
+
+impl Default for ForwardProxy {
+    fn default() -> Self {
+        ForwardProxy::new()
+    }
+}
+
+impl ForwardProxy {
+    pub fn new() -> ForwardProxy {
+        ForwardProxy {
+            timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            max_retransmit: DEFAULT_UPSTREAM_RETRANSMIT,
+            cache: None,
+            observations: None,
+            reregister: DEFAULT_REREGISTER_INTERVAL,
+            hop_limit: DEFAULT_HOP_LIMIT,
+            downloads: Arc::new(Mutex::new(Downloads::new())),
+            block_size: None,
+            upstream_block_size: None,
+            #[cfg(feature = "dtls")]
+            dtls: None,
+            #[cfg(all(feature = "http", feature = "tls"))]
+            https: None,
+        }
+    }
+
+    /// Set how long to wait for the target before retransmitting a
+    /// request, doubled with each retransmission.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Set how often a request is retransmitted before the proxy gives up
+    /// with 5.04 Gateway Timeout.
+    pub fn set_max_retransmit(&mut self, max_retransmit: u32) {
+        self.max_retransmit = max_retransmit;
+    }
+
+    /// Return how long the target has to answer in all, counting the
+    /// retransmissions.
+    #[cfg(feature = "http")]
+    fn patience(&self) -> Duration {
+        (0..=self.max_retransmit)
+            .map(|n| self.timeout * 2u32.saturating_pow(n))
+            .sum()
+    }
+
+    /// Keep the responses of the targets in `cache`, or stop caching them
+    /// with `None`, the default.
+    pub fn set_cache(&mut self, cache: Option<ResponseCache>) {
+        self.cache = cache.map(|cache| Arc::new(Mutex::new(cache)));
+    }
+
+    /// Observe the targets observed through the proxy once for all clients,
+    /// notifying the clients through `registry`, the registry of the server
+    /// the proxy serves, or stop with `None`, the default.
+    /// [`Server::set_forward_proxy`](crate::Server::set_forward_proxy) sets
+    /// it.
+    pub fn set_observe_registry(&mut self, registry: Option<Arc<ObserveRegistry>>) {
+        self.observations = registry.map(|registry| Arc::new(Observations::new(registry)));
+    }
+
+    /// Register again with an observed target when nothing was heard of it
+    /// for `interval`, by default [`DEFAULT_REREGISTER_INTERVAL`].
+    pub fn set_reregister_interval(&mut self, interval: Duration) {
+        self.reregister = interval;
+    }
+
+    /// Give requests without a Hop-Limit one of `limit` when forwarding
+    /// them, by default [`DEFAULT_HOP_LIMIT`].
+    pub fn set_hop_limit(&mut self, limit: u8) {
+        self.hop_limit = limit.max(1);
+    }
+
+    /// Send representations to clients in blocks of at most `size` bytes,
+    /// rounded down to a block size, or with `None`, the default, in the
+    /// blocks the clients ask for and otherwise as the server fits them to
+    /// the path MTU.
+    pub fn set_block_size(&mut self, size: Option<usize>) {
+        self.block_size = size.map(valid_block_size);
+    }
+
+    /// Ask targets for blocks of `size` bytes, rounded down to a block
+    /// size, or with `None`, the default, for the blocks they choose.
+    pub fn set_upstream_block_size(&mut self, size: Option<usize>) {
+        self.upstream_block_size = size.map(valid_block_size);
+    }
+
+    /// Authenticate to `coaps` targets with a pre-shared key, or stop
+    /// forwarding to them with `None`, the default.
+    #[cfg(feature = "dtls")]
+    pub fn set_dtls(&mut self, config: Option<dtls::PskConfig>) {
+        self.dtls = config;
+    }
+
+    /// Connect to `https` targets with a TLS configuration, or stop
+    /// forwarding to them with `None`, the default.
+    #[cfg(all(feature = "http", feature = "tls"))]
+    pub fn set_https(&mut self, config: Option<Arc<tls::rustls::ClientConfig>>) {
+        self.https = config;
+    }
+
+    /// Forward a proxy request and return the future of the response to
+    /// relay.
+    pub fn forward(&self, mut request: CoapRequest<SocketAddr>) -> ResponseFuture {
+        let proxy = self.clone();
+        Box::pin(async move {
+            let mut response = request.response.take()?;
+            let prepared = proxy.prepare(&mut request.message);
+            // the blocks of a representation are kept by the URI of the target
+            let target = prepared.as_ref().ok().map(|(_, url)| url.to_string());
+            let relayed = match (prepared, &proxy.observations) {
+                (Ok((message, url)), Some(observations)) if observe::is_registration(&request) => {
+                    request.response = Some(response.clone());
+                    match observations.register(&proxy, &request, message, url).await {
+                        // the registry answered the request
+                        Ok(None) => return None,
+                        Ok(Some(relayed)) => Ok(relayed),
+                        Err(status) => Err(status),
+                    }
+                }
+                (Ok((message, url)), Some(observations))
+                    if observe::is_deregistration(&request) =>
+                {
+                    observations.deregister(&request, &url);
+                    proxy.send(message, url).await
+                }
+                (Ok((message, url)), _) => proxy.download(&request, message, url).await,
+                (Err(status), _) => Err(status),
+            };
+            match relayed {
+                Ok(relayed) => {
+                    relay(&relayed, &mut response.message);
+                    if let (Some(client), Some(target)) = (request.source, target) {
+                        proxy.downloads.lock().unwrap().cut(
+                            client,
+                            &target,
+                            &request.message,
+                            &mut response.message,
+                            proxy.block_size,
+                        );
+                    }
+                }
+                Err(status) => {
+                    debug!("proxy request answered with {:?}", status);
+                    response.set_status(status);
+                }
+            }
+            Some(response)
+        })
+    }
+
+    /// Return the request to send to the target and its URI.
+    fn prepare(&self, message: &mut Packet) -> Result<(Packet, Url), Status> {
+        let url = target(message).ok_or(Status::BadRequest)?;
+        if !self.supports(url.scheme()) {
+            return Err(Status::ProxyingNotSupported);
+        }
+        limit_hops(message, self.hop_limit)?;
+        let mut forwarded = forward_request(message)?;
+        for option in [CoapOption::Block1, CoapOption::Block2, CoapOption::Observe] {
+            forwarded.clear_option(option);
+        }
+        let block2 = self
+            .upstream_block_size
+            .filter(|_| forwarded.header.code == MessageClass::Request(Method::Get))
+            .and_then(|size| BlockValue::new(0, false, size).ok());
+        if let Some(block2) = block2 {
+            forwarded.add_option_as(CoapOption::Block2, block2);
+        }
+        // the client picks a token and Message ID of its own
+        forwarded.set_token(Vec::new());
+        forwarded.header.set_type(MessageType::Confirmable);
+        Ok((forwarded, url))
+    }
+
+    /// Return whether requests can be forwarded to URIs with `scheme`.
+    fn supports(&self, scheme: &str) -> bool {
+        match scheme {
+            #[cfg(feature = "dtls")]
+            "coaps" => self.dtls.is_some(),
+            #[cfg(all(feature = "http", feature = "tls"))]
+            "https" => self.https.is_some(),
+            "coap+ws" => cfg!(feature = "websocket"),
+            "http" => cfg!(feature = "http"),
+            _ => matches!(scheme, "coap" | "coap+tcp"),
+        }
+    }
+
+    /// Return the representation a request asks for a further block of,
+    /// from those kept for the client if there is one, or else from the
+    /// target.
+    async fn download(
+        &self,
+        request: &CoapRequest<SocketAddr>,
+        message: Packet,
+        url: Url,
+    ) -> Result<Packet, Status> {
+        let kept = request.source.and_then(|client| {
+            let mut downloads = self.downloads.lock().unwrap();
+            downloads.get(client, url.as_str(), &request.message)
+        });
+        match kept {
+            Some(kept) => Ok(kept),
+            None => self.send(message, url).await,
+        }
+    }
+
+    /// Return the response to the request to the target at `url`, from the
+    /// cache if there is a fresh one, or else from the target, or the status
+    /// to answer the request with.
+    async fn send(&self, mut message: Packet, url: Url) -> Result<Packet, Status> {
+        let cache = match self.cache {
+            Some(ref cache) => cache,
+            None => return self.fetch(message, url).await,
+        };
+        if cache::changes(&message) {
+            let response = self.fetch(message, url.clone()).await;
+            if response.is_ok() {
+                cache.lock().unwrap().invalidate_target(url.as_str());
+            }
+            return response;
+        }
+        let key = match cache::target_key(url.as_str(), &message) {
+            Some(key) => key,
+            None => return self.fetch(message, url).await,
+        };
+        let stored = cache.lock().unwrap().lookup(&key);
+        let etag = match stored {
+            Some((stored, true)) => return Ok(stored),
+            Some((stored, false)) => etag::get(&stored).cloned(),
+            None => None,
+        };
+        if let Some(ref etag) = etag {
+            // the ETags of the client are validated with the stored response
+            etag::set(&mut message, etag.clone());
+        }
+        let response = self.fetch(message, url).await?;
+        let mut cache = cache.lock().unwrap();
+        match response.header.code {
+            MessageClass::Response(Status::Valid) if etag.is_some() => {
+                cache.refresh(key, &response).ok_or(Status::BadGateway)
+            }
+            _ => {
+                cache.store(key, &response);
+                Ok(response)
+            }
+        }
+    }
+
+    /// Send the request to the target at `url` and return its response, or
+    /// the status to answer the request with.
+    async fn fetch(&self, message: Packet, url: Url) -> Result<Packet, Status> {
+        #[cfg(feature = "http")]
+        if matches!(url.scheme(), "http" | "https") {
+            return http::exchange(self, &message, &url).await;
+        }
+        let (tx, rx) = oneshot::channel();
+        let proxy = self.clone();
+        // the client blocks, and the server must not
+        thread::spawn(move || tx.send(proxy.exchange(message, &url)));
+        rx.await.unwrap_or(Err(Status::InternalServerError))
+    }
+
+    /// Exchange the request with the target at `url`.
+    fn exchange(&self, message: Packet, url: &Url) -> Result<Packet, Status> {
+        let mut request = CoapRequest::new();
+        request.message = message;
+        let result = self.connect(url).and_then(|mut client| {
+            client.set_receive_timeout(Some(self.timeout))?;
+            client.set_max_retransmit(self.max_retransmit);
+            client.exchange(&mut request)
+        });
+        match result {
+            Ok(response) => Ok(response.message),
+            Err(e) => {
+                debug!("forwarding to {} failed: {}", url, e);
+                Err(match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => Status::GatewayTimeout,
+                    _ => Status::BadGateway,
+                })
+            }
+        }
+    }
+
+    /// Return a client talking to the target at `url`.
+    fn connect(&self, url: &Url) -> io::Result<CoAPClient> {
+        let addr = url
+            .socket_addrs(|| default_port(url.scheme()))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "no address"))?;
+        match url.scheme() {
+            "coap" => CoAPClient::new(addr),
+            "coap+tcp" => CoAPClient::new_tcp(addr),
+            #[cfg(feature = "websocket")]
+            "coap+ws" => CoAPClient::new_ws(addr),
+            #[cfg(feature = "dtls")]
+            "coaps" => match self.dtls {
+                Some(ref config) => CoAPClient::new_dtls(addr, config),
+                None => Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "no DTLS credentials",
+                )),
+            },
+            scheme => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("unsupported scheme {}", scheme),
+            )),
+        }
+    }
+}
+
+/// Return the largest block size of at most `size` bytes, but at least the
+/// smallest one.
+fn valid_block_size(size: usize) -> usize {
+    mtu::block_size(size, 0).unwrap_or(mtu::MIN_BLOCK_SIZE)
+}
+
+/// Copy the code, options and payload of the response of the target into
+/// the response to relay it with. The blocks of the response have been
+/// collected already.
+fn relay(relayed: &Packet, response: &mut Packet) {
+    response.header.code = relayed.header.code;
+    for (&number, values) in relayed.options() {
+        match CoapOption::from(number) {
+            CoapOption::Block1 | CoapOption::Block2 | CoapOption::Observe => {}
+            option => response.set_option(option, values.clone()),
+        }
+    }
+    response.payload = relayed.payload.clone();
+}
+
+/// Return the host of `url` to put in a Uri-Host option: none for an IP
+/// literal, which is the address the request goes to anyway.
+fn uri_host(url: &Url) -> Option<&str> {
+    match url.host()? {
+        // URIs of schemes other than http(s) leave IPv4 addresses unparsed
+        Host::Domain(host) if host.parse::<Ipv4Addr>().is_err() => Some(host),
+        _ => None,
+    }
+}
+
+/// Return the port that URIs with `scheme` imply.
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "coap" | "coap+tcp" | "coap+ws" => Some(5683),
+        "coaps" | "coaps+tcp" | "coaps+ws" => Some(5684),
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+/// Decode the percent-encoded octets of a URI component.
+fn percent_decode(text: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'%' {
+            decoded.push(byte);
+            continue;
+        }
+        let hex = [bytes.next()?, bytes.next()?];
+        decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_target() {
+        let mut message = Packet::new();
+        assert!(!is_proxy_request(&message));
+        assert_eq!(target(&message), None);
+
+        message.add_option(CoapOption::UriPath, b"local".to_vec());
+        message.add_option(CoapOption::ProxyScheme, b"coap".to_vec());
+        // no host to forward to
+        assert!(is_proxy_request(&message));
+        assert_eq!(target(&message), None);
+
+        message.add_option(CoapOption::UriHost, b"example.com".to_vec());
+        message.add_option(CoapOption::UriPort, vec![0x16, 0x34]);
+        message.add_option(CoapOption::UriPath, b"a b".to_vec());
+        message.add_option(CoapOption::UriQuery, b"rt=temp".to_vec());
+        message.add_option(CoapOption::UriQuery, b"if=sensor".to_vec());
+        let url = target(&message).unwrap();
+        assert_eq!(
+            url.as_str(),
+            "coap://example.com:5684/local/a%20b?rt=temp&if=sensor"
+        );
+
+        message.add_option(CoapOption::ProxyUri, b"coaps://[2001:db8::1]/temp".to_vec());
+        assert_eq!(
+            target(&message).unwrap().as_str(),
+            "coaps://[2001:db8::1]/temp"
+        );
+    }
+
+    #[test]
+    fn test_forward_request() {
+        let option = |message: &Packet, option| {
+            message
+                .get_option(option)
+                .into_iter()
+                .flatten()
+                .map(|value| String::from_utf8_lossy(value).into_owned())
+                .collect::<Vec<_>>()
+        };
+        let mut message = Packet::new();
+        message.add_option(
+            CoapOption::ProxyUri,
+            b"coap://example.com:61616/a%2Fb/c?x=1&y=%26".to_vec(),
+        );
+        message.payload = b"data".to_vec();
+        let forwarded = forward_request(&message).unwrap();
+        assert!(!is_proxy_request(&forwarded));
+        assert_eq!(option(&forwarded, CoapOption::UriHost), ["example.com"]);
+        assert_eq!(option(&forwarded, CoapOption::UriPath), ["a/b", "c"]);
+        assert_eq!(option(&forwarded, CoapOption::UriQuery), ["x=1", "y=&"]);
+        let port = forwarded.get_first_option_as::<OptionValueU16>(CoapOption::UriPort);
+        assert_eq!(port.unwrap().unwrap().0, 61616);
+        assert_eq!(forwarded.payload, b"data");
+
+        // OSCORE: the path and query are inner options
+        let mut protected = Packet::new();
+        protected.add_option(CoapOption::Oscore, vec![0x09, 0x14]);
+        protected.add_option(CoapOption::ProxyUri, b"coap://[2001:db8::1]/".to_vec());
+        protected.payload = vec![0x5c, 0x94, 0xc1];
+        let forwarded = forward_request(&protected).unwrap();
+        assert!(is_oscore(&forwarded));
+        assert!(!is_proxy_request(&forwarded));
+        assert!(option(&forwarded, CoapOption::UriHost).is_empty());
+        assert!(option(&forwarded, CoapOption::UriPath).is_empty());
+        assert_eq!(forwarded.payload, protected.payload);
+
+        protected.clear_option(CoapOption::ProxyUri);
+        protected.add_option(CoapOption::ProxyUri, b"coap://example.com/temp".to_vec());
+        assert_eq!(forward_request(&protected), Err(Status::BadOption));
+
+        // the client decomposed the URI itself
+        protected.clear_option(CoapOption::ProxyUri);
+        protected.add_option(CoapOption::ProxyScheme, b"coap".to_vec());
+        protected.add_option(CoapOption::UriHost, b"example.com".to_vec());
+        let forwarded = forward_request(&protected).unwrap();
+        assert!(!is_proxy_request(&forwarded));
+        assert_eq!(option(&forwarded, CoapOption::UriHost), ["example.com"]);
+        assert_eq!(forwarded.payload, protected.payload);
+    }
+
+    #[test]
+    fn test_hop_limit() {
+        let option = CoapOption::Unknown(HOP_LIMIT);
+        let mut message = Packet::new();
+        assert_eq!(limit_hops(&mut message, DEFAULT_HOP_LIMIT), Ok(()));
+        assert_eq!(message.get_option(option).unwrap().front().unwrap(), &[16]);
+
+        message.clear_option(option);
+        message.add_option(option, vec![2]);
+        assert_eq!(limit_hops(&mut message, DEFAULT_HOP_LIMIT), Ok(()));
+        assert_eq!(message.get_option(option).unwrap().front().unwrap(), &[1]);
+        assert_eq!(
+            limit_hops(&mut message, DEFAULT_HOP_LIMIT),
+            Err(Status::HopLimitReached)
+        );
+
+        message.clear_option(option);
+        message.add_option(option, vec![0]);
+        assert_eq!(
+            limit_hops(&mut message, DEFAULT_HOP_LIMIT),
+            Err(Status::BadRequest)
+        );
+    }
+
+    #[test]
+    fn test_upstream_block_size() {
+        let mut proxy = ForwardProxy::new();
+        proxy.set_upstream_block_size(Some(300));
+        let mut message = Packet::new();
+        message.header.code = MessageClass::Request(Method::Get);
+        message.add_option(CoapOption::ProxyUri, b"coap://sensor.example/log".to_vec());
+        message.add_option_as(CoapOption::Block2, BlockValue::new(1, false, 16).unwrap());
+        let (forwarded, _) = proxy.prepare(&mut message).unwrap();
+        let block = forwarded.get_first_option_as::<BlockValue>(CoapOption::Block2);
+        let block = block.unwrap().unwrap();
+        assert_eq!((block.num, block.size()), (0, 256));
+    }
+}
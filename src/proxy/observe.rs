@@ -0,0 +1,159 @@
+//! Aggregation of the observations of a forward proxy
+//! ([RFC 7641](https://tools.ietf.org/html/rfc7641) section 5).
+//!
+//! However many clients observe a target through the proxy, the proxy
+//! observes it only once. It keeps the state of the target in an
+//! [`ObserveRegistry`] under the URI of the target, which notifies the
+//! clients like the observers of a resource of the server itself, and
+//! stops observing the target once the last client deregisters.
+//!
+//! When nothing was heard of the target for the re-registration interval,
+//! the proxy registers again, which re-establishes an observation the
+//! target forgot, e.g. after a reboot. A notification with an error ends
+//! the observations of the clients with 4.04 Not Found.
+use super::ForwardProxy;
+use crate::client::{CoAPClient, KeepaliveMode};
+use crate::observer::ObserveRegistry;
+use coap_lite::{
+    CoapRequest, MessageClass, ObserveOption, Packet, RequestType as Method, ResponseType as Status,
+};
+use futures::channel::oneshot;
+use log::debug;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use url::Url;
+
+/// The targets a proxy observes, by URI.
+pub(super) struct Observations {
+    registry: Arc<ObserveRegistry>,
+    upstream: Mutex<HashMap<String, CoAPClient>>,
+}
+
+impl Observations {
+    pub(super) fn new(registry: Arc<ObserveRegistry>) -> Observations {
+        Observations {
+            registry,
+            upstream: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Register the source of `request` as an observer of the target at
+    /// `url`, observing the target with `message` unless it is observed
+    /// already. Returns `None` once the registry answered the request, or
+    /// else the response to relay: that of a target that cannot be
+    /// observed.
+    pub(super) async fn register(
+        &self,
+        proxy: &ForwardProxy,
+        request: &CoapRequest<SocketAddr>,
+        message: Packet,
+        url: Url,
+    ) -> Result<Option<Packet>, Status> {
+        let key = url.as_str();
+        let observed = self.upstream.lock().unwrap().contains_key(key);
+        if observed && self.registry.observers(key).is_some() {
+            self.registry.register_at(key, request);
+            return Ok(None);
+        }
+
+        let (tx, rx) = oneshot::channel();
+        let registry = self.registry.clone();
+        let upstream = proxy.clone();
+        let (forwarded, target) = (message.clone(), url.clone());
+        thread::spawn(move || {
+            let _ = tx.send(observe(&upstream, registry, forwarded, &target));
+        });
+        match rx.await.map_err(|_| Status::InternalServerError)? {
+            Ok((client, first)) if first.get_observe_value().is_some() => {
+                self.registry.register_at(key, request);
+                let replaced = self
+                    .upstream
+                    .lock()
+                    .unwrap()
+                    .insert(key.to_string(), client);
+                // dropping a client deregisters it, which waits for the target
+                if let Some(replaced) = replaced {
+                    thread::spawn(move || drop(replaced));
+                }
+                Ok(None)
+            }
+            Ok((client, first)) => {
+                thread::spawn(move || drop(client));
+                Ok(Some(first))
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                Err(Status::GatewayTimeout)
+            }
+            Err(e) => {
+                debug!("observing {} failed: {}", url, e);
+                // the target may answer the request as it is
+                proxy.send(message, url).await.map(Some)
+            }
+        }
+    }
+
+    /// Deregister the source of `request` as an observer of the target at
+    /// `url`, and stop observing the target if it was the last one.
+    pub(super) fn deregister(&self, request: &CoapRequest<SocketAddr>, url: &Url) {
+        let key = url.as_str();
+        self.registry.deregister_at(key, request);
+        if self.registry.observers(key) == Some(0) {
+            self.registry.resource_removed(key);
+            if let Some(client) = self.upstream.lock().unwrap().remove(key) {
+                thread::spawn(move || drop(client));
+            }
+        }
+    }
+}
+
+/// Return whether `request` registers an observation.
+pub(super) fn is_registration(request: &CoapRequest<SocketAddr>) -> bool {
+    *request.get_method() == Method::Get
+        && matches!(
+            request.get_observe_flag(),
+            Some(Ok(ObserveOption::Register))
+        )
+}
+
+/// Return whether `request` deregisters an observation.
+pub(super) fn is_deregistration(request: &CoapRequest<SocketAddr>) -> bool {
+    *request.get_method() == Method::Get
+        && matches!(
+            request.get_observe_flag(),
+            Some(Ok(ObserveOption::Deregister))
+        )
+}
+
+/// Observe the target at `url` with `message`, keeping its state in
+/// `registry`, and return the client observing it and the first response.
+fn observe(
+    proxy: &ForwardProxy,
+    registry: Arc<ObserveRegistry>,
+    message: Packet,
+    url: &Url,
+) -> io::Result<(CoAPClient, Packet)> {
+    let mut client = proxy.connect(url)?;
+    client.set_keepalive(Some(proxy.reregister), KeepaliveMode::Reregister);
+    let key = url.to_string();
+    let (tx, rx) = mpsc::channel();
+    let mut first = Some(tx);
+    let handler = move |packet: Packet| {
+        let content = packet.header.code == MessageClass::Response(Status::Content);
+        if content && packet.get_observe_value().is_some() {
+            registry.resource_changed(&key, packet.payload.clone());
+        } else {
+            registry.resource_removed(&key);
+        }
+        if let Some(first) = first.take() {
+            let _ = first.send(packet);
+        }
+    };
+    let mut request = CoapRequest::new();
+    request.message = message;
+    client.observe_request(&request, handler, proxy.timeout)?;
+    let first = rx.recv().map_err(|_| io::Error::other("no response"))?;
+    Ok((client, first))
+}
@@ -0,0 +1,549 @@
+//! Reverse proxying by path prefix.
+//!
+//! [`ReverseProxy`] serves the resources of upstream servers as if they
+//! were its own: a request for a path under one of its prefixes is sent on
+//! to the upstream server of the prefix, with the prefix replaced by the
+//! path of the upstream URI, e.g. `/kitchen/temp` to `/temp` of
+//! `coap://[2001:db8::1]` for the prefix `/kitchen`. That puts many devices
+//! behind one address.
+//!
+//! The proxy acts as the origin server towards its clients. The options it
+//! processes itself, i.e. the Uri-* options, block-wise transfer, Observe,
+//! Echo and Request-Tag, are not passed on; of the others, options unsafe
+//! to forward are only passed on if they are recognized, and requests with
+//! other unsafe options are answered with 5.02 Bad Gateway.
+//!
+//! A route may also lead to a pool of upstream servers serving the same
+//! resources, of which each request goes to one, picked as set by
+//! [`Balance`]. With health checks, the proxy pings the servers of the
+//! pools at an interval and leaves out those that failed to answer too
+//! many times in a row, until they answer again. Requests for a route
+//! without a server left are answered with 5.03 Service Unavailable.
+use super::{default_port, limit_hops, percent_decode, relay, uri_host, ForwardProxy};
+use crate::echo;
+use crate::options::OptionRegistry;
+use crate::payload::ResponseFuture;
+use crate::qblock;
+use coap_lite::{
+    option_value::OptionValueU16, CoapOption, CoapRequest, MessageType, Packet,
+    ResponseType as Status,
+};
+use log::{debug, warn};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+/// How often the upstream servers are pinged.
+pub const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many pings in a row an upstream server may fail to answer before it
+/// is left out of its pool.
+pub const DEFAULT_MAX_FAILURES: u32 = 3;
+
+/// The options of a request the proxy processes itself.
+const PROCESSED_OPTIONS: [CoapOption; 11] = [
+    CoapOption::UriHost,
+    CoapOption::UriPort,
+    CoapOption::UriPath,
+    CoapOption::Block1,
+    CoapOption::Block2,
+    CoapOption::Observe,
+    CoapOption::Unknown(echo::ECHO),
+    CoapOption::Unknown(echo::REQUEST_TAG),
+    CoapOption::Unknown(qblock::Q_BLOCK1),
+    CoapOption::Unknown(qblock::Q_BLOCK2),
+    CoapOption::Size2,
+];
+
+/// How a route picks one of its upstream servers for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Balance {
+    /// Each server in turn.
+    #[default]
+    RoundRobin,
+    /// The server with the fewest requests waiting for an answer.
+    LeastOutstanding,
+}
+
+/// An upstream server of a route.
+struct Backend {
+    url: Url,
+    /// The path of `url`, which replaces the prefix.
+    path: Vec<Vec<u8>>,
+    /// The requests forwarded to the server and not answered yet.
+    outstanding: AtomicUsize,
+    /// The pings the server failed to answer in a row.
+    failures: AtomicU32,
+}
+
+/// Counts a request as outstanding at a server until it is dropped.
+struct Outstanding(Arc<Backend>);
+
+impl Outstanding {
+    fn new(backend: Arc<Backend>) -> Outstanding {
+        backend.outstanding.fetch_add(1, Ordering::Relaxed);
+        Outstanding(backend)
+    }
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        self.0.outstanding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Upstream servers and the prefix of the paths they serve.
+struct Route {
+    prefix: Vec<Vec<u8>>,
+    pool: Vec<Arc<Backend>>,
+    balance: Balance,
+    /// The requests routed so far, where the next turn starts.
+    turn: AtomicUsize,
+}
+
+/// Upstream servers by path prefix.
+pub struct ReverseProxy {
+    /// Longest prefixes first.
+    routes: Vec<Route>,
+    options: OptionRegistry,
+    upstream: ForwardProxy,
+    /// The servers of all routes, for the health checks.
+    backends: Arc<Mutex<Vec<Arc<Backend>>>>,
+    health_check_interval: Option<Duration>,
+    max_failures: u32,
+    health_checks: OnceLock<()>,
+}
+
+impl Default for ReverseProxy {
+    fn default() -> ReverseProxy {
+        ReverseProxy {
+            routes: Vec::new(),
+            options: OptionRegistry::default(),
+            upstream: ForwardProxy::default(),
+            backends: Arc::default(),
+            health_check_interval: None,
+            max_failures: DEFAULT_MAX_FAILURES,
+            health_checks: OnceLock::new(),
+        }
+    }
+}
+
+impl ReverseProxy {
+    /// Create a proxy without any routes.
+    pub fn new() -> ReverseProxy {
+        ReverseProxy::default()
+    }
+
+    /// Forward requests for `prefix` and the paths under it to `upstream`,
+    /// a `coap`, `coap+tcp`, `coap+ws` or `coaps` URI. The longest prefix
+    /// that matches a request wins, and `/` matches all requests.
+    pub fn with_route(self, prefix: &str, upstream: Url) -> ReverseProxy {
+        self.with_pool(prefix, vec![upstream], Balance::default())
+    }
+
+    /// Forward requests for `prefix` and the paths under it to one of the
+    /// `upstreams`, picked by `balance`, like [`with_route`](Self::with_route)
+    /// does to a single upstream server.
+    pub fn with_pool(
+        mut self,
+        prefix: &str,
+        upstreams: Vec<Url>,
+        balance: Balance,
+    ) -> ReverseProxy {
+        let prefix = segments(prefix.split('/'));
+        let pool: Vec<Arc<Backend>> = upstreams
+            .into_iter()
+            .map(|url| {
+                Arc::new(Backend {
+                    path: segments(url.path_segments().into_iter().flatten()),
+                    url,
+                    outstanding: AtomicUsize::new(0),
+                    failures: AtomicU32::new(0),
+                })
+            })
+            .collect();
+        self.backends.lock().unwrap().extend(pool.iter().cloned());
+        let at = self
+            .routes
+            .partition_point(|route| route.prefix.len() >= prefix.len());
+        self.routes.insert(
+            at,
+            Route {
+                prefix,
+                pool,
+                balance,
+                turn: AtomicUsize::new(0),
+            },
+        );
+        self
+    }
+
+    /// Ping the upstream servers every `interval`, and leave a server out
+    /// of its pool once it failed to answer `max_failures` pings in a row,
+    /// until it answers again, e.g. every [`DEFAULT_HEALTH_CHECK_INTERVAL`]
+    /// after [`DEFAULT_MAX_FAILURES`]. The pings wait for the timeout of
+    /// the proxy, and start with the first request handled.
+    pub fn with_health_checks(mut self, interval: Duration, max_failures: u32) -> ReverseProxy {
+        self.health_check_interval = Some(interval);
+        self.max_failures = max_failures.max(1);
+        self
+    }
+
+    /// Pass on the unsafe options registered in `options`, besides those
+    /// known to coap-lite.
+    pub fn with_options(mut self, options: OptionRegistry) -> ReverseProxy {
+        self.options = options;
+        self
+    }
+
+    /// Wait `timeout` for an upstream server before retransmitting a
+    /// request, and retransmit it up to `max_retransmit` times.
+    pub fn with_timeout(mut self, timeout: Duration, max_retransmit: u32) -> ReverseProxy {
+        self.upstream.set_timeout(timeout);
+        self.upstream.set_max_retransmit(max_retransmit);
+        self
+    }
+
+    /// Give requests without a Hop-Limit one of `limit` when forwarding
+    /// them, by default [`DEFAULT_HOP_LIMIT`](super::DEFAULT_HOP_LIMIT).
+    pub fn with_hop_limit(mut self, limit: u8) -> ReverseProxy {
+        self.upstream.set_hop_limit(limit);
+        self
+    }
+
+    /// Authenticate to `coaps` upstream servers with a pre-shared key.
+    #[cfg(feature = "dtls")]
+    pub fn with_dtls(mut self, config: crate::transport::dtls::PskConfig) -> ReverseProxy {
+        self.upstream.set_dtls(Some(config));
+        self
+    }
+
+    /// Forward `request` to the upstream server of its path, to be called
+    /// from the handler given to [`Server::run`](crate::Server::run).
+    /// Requests for paths without a route are answered with 4.04 Not
+    /// Found, and those for a route without a healthy upstream server with
+    /// 5.03 Service Unavailable.
+    pub fn handle(&self, mut request: CoapRequest<SocketAddr>) -> ResponseFuture {
+        if let Some(interval) = self.health_check_interval {
+            self.health_checks
+                .get_or_init(|| self.spawn_health_checks(interval));
+        }
+        let forwarded = self
+            .route(&request.message)
+            .ok_or(Status::NotFound)
+            .and_then(|route| {
+                let backend = self.select(route).ok_or(Status::ServiceUnavailable)?;
+                let forwarded = self.upstream_request(route, &backend, &mut request.message)?;
+                // what is needed of the route to rewrite the response
+                let prefix = route.prefix.clone();
+                Ok((forwarded, prefix, Outstanding::new(backend)))
+            });
+        let upstream = self.upstream.clone();
+        Box::pin(async move {
+            let mut response = request.response.take()?;
+            let relayed = match forwarded {
+                Ok(((message, url), prefix, outstanding)) => upstream
+                    .send(message, url)
+                    .await
+                    .map(|relayed| (relayed, prefix, outstanding)),
+                Err(status) => Err(status),
+            };
+            match relayed {
+                Ok((mut relayed, prefix, outstanding)) => {
+                    rewrite_location(&mut relayed, &outstanding.0.path, &prefix);
+                    relay(&relayed, &mut response.message);
+                }
+                Err(status) => {
+                    debug!("reverse proxy request answered with {:?}", status);
+                    response.set_status(status);
+                }
+            }
+            Some(response)
+        })
+    }
+
+    /// Return the route of the longest prefix of the path of the request.
+    fn route(&self, message: &Packet) -> Option<&Route> {
+        let path: Vec<&Vec<u8>> = message
+            .get_option(CoapOption::UriPath)
+            .into_iter()
+            .flatten()
+            .collect();
+        self.routes.iter().find(|route| {
+            route.prefix.len() <= path.len() && route.prefix.iter().zip(&path).all(|(a, b)| a == *b)
+        })
+    }
+
+    /// Pick the upstream server of `route` for a request, of those not
+    /// left out by the health checks.
+    fn select(&self, route: &Route) -> Option<Arc<Backend>> {
+        let healthy: Vec<&Arc<Backend>> = route
+            .pool
+            .iter()
+            .filter(|backend| backend.failures.load(Ordering::Relaxed) < self.max_failures)
+            .collect();
+        if healthy.is_empty() {
+            return None;
+        }
+        // starting at the next in turn spreads ties over the servers
+        let start = route.turn.fetch_add(1, Ordering::Relaxed) % healthy.len();
+        let mut turn = healthy[start..].iter().chain(&healthy[..start]).copied();
+        let backend = match route.balance {
+            Balance::RoundRobin => turn.next(),
+            Balance::LeastOutstanding => {
+                turn.min_by_key(|backend| backend.outstanding.load(Ordering::Relaxed))
+            }
+        };
+        backend.cloned()
+    }
+
+    /// Ping the upstream servers in a thread every `interval`, until the
+    /// proxy is dropped.
+    fn spawn_health_checks(&self, interval: Duration) {
+        let backends = Arc::downgrade(&self.backends);
+        let upstream = self.upstream.clone();
+        let max_failures = self.max_failures;
+        thread::spawn(move || check_health(backends, upstream, interval, max_failures));
+    }
+
+    /// Return the request to send to the upstream server `backend` of
+    /// `route` and its URI.
+    fn upstream_request(
+        &self,
+        route: &Route,
+        backend: &Backend,
+        message: &mut Packet,
+    ) -> Result<(Packet, Url), Status> {
+        if let Err(diagnostic) = self.options.check_forwarded(message) {
+            debug!("{}", diagnostic);
+            return Err(Status::BadGateway);
+        }
+        limit_hops(message, self.upstream.hop_limit)?;
+        let mut forwarded = message.clone();
+        for option in PROCESSED_OPTIONS {
+            forwarded.clear_option(option);
+        }
+        let url = &backend.url;
+        if let Some(host) = uri_host(url) {
+            forwarded.add_option(CoapOption::UriHost, host.as_bytes().to_vec());
+        }
+        if let Some(port) = url
+            .port()
+            .filter(|port| Some(*port) != default_port(url.scheme()))
+        {
+            forwarded.add_option_as(CoapOption::UriPort, OptionValueU16(port));
+        }
+        let path = message
+            .get_option(CoapOption::UriPath)
+            .into_iter()
+            .flatten();
+        for segment in backend.path.iter().chain(path.skip(route.prefix.len())) {
+            forwarded.add_option(CoapOption::UriPath, segment.clone());
+        }
+        // the client picks a token and Message ID of its own
+        forwarded.set_token(Vec::new());
+        forwarded.header.set_type(MessageType::Confirmable);
+        Ok((forwarded, url.clone()))
+    }
+}
+
+/// Ping each of the `backends` every `interval` until they are dropped,
+/// counting the pings they failed to answer in a row.
+fn check_health(
+    backends: Weak<Mutex<Vec<Arc<Backend>>>>,
+    upstream: ForwardProxy,
+    interval: Duration,
+    max_failures: u32,
+) {
+    while let Some(backends) = backends.upgrade() {
+        let pool = backends.lock().unwrap().clone();
+        drop(backends);
+        for backend in pool {
+            let answered = upstream.connect(&backend.url).and_then(|client| {
+                client.set_receive_timeout(Some(upstream.timeout))?;
+                client.ping()
+            });
+            match answered {
+                Ok(()) => {
+                    if backend.failures.swap(0, Ordering::Relaxed) >= max_failures {
+                        warn!("upstream server {} is back", backend.url);
+                    }
+                }
+                Err(e) => {
+                    let failures = backend.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                    if failures == max_failures {
+                        warn!("upstream server {} left out: {}", backend.url, e);
+                    }
+                }
+            }
+        }
+        thread::sleep(interval);
+    }
+}
+
+/// Return the non-empty, percent-decoded segments of a path.
+fn segments<'a>(path: impl Iterator<Item = &'a str>) -> Vec<Vec<u8>> {
+    path.filter(|segment| !segment.is_empty())
+        .map(|segment| {
+            percent_decode(segment)
+                .unwrap_or_else(|| segment.to_string())
+                .into_bytes()
+        })
+        .collect()
+}
+
+/// Replace the upstream `path` a Location-Path of the response starts with
+/// by the `prefix` it is served under.
+fn rewrite_location(response: &mut Packet, path: &[Vec<u8>], prefix: &[Vec<u8>]) {
+    let location: Vec<Vec<u8>> = match response.get_option(CoapOption::LocationPath) {
+        Some(values) if values.len() >= path.len() => values.iter().cloned().collect(),
+        _ => return,
+    };
+    if !location.starts_with(path) {
+        return;
+    }
+    let location = prefix.iter().chain(&location[path.len()..]).cloned();
+    response.set_option(CoapOption::LocationPath, location.collect());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_upstream_request() {
+        let proxy = ReverseProxy::new()
+            .with_route("/", Url::parse("coap://[2001:db8::1]/").unwrap())
+            .with_route(
+                "/kitchen",
+                Url::parse("coap://sensor.example:61616/api").unwrap(),
+            )
+            .with_route("/kitchen/light", Url::parse("coap://10.0.0.2").unwrap());
+        let path = |message: &Packet| {
+            message
+                .get_option(CoapOption::UriPath)
+                .into_iter()
+                .flatten()
+                .map(|segment| String::from_utf8_lossy(segment).into_owned())
+                .collect::<Vec<_>>()
+        };
+        let request = |segments: &[&str]| {
+            let mut message = Packet::new();
+            for segment in segments {
+                message.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+            }
+            message.add_option(CoapOption::UriHost, b"proxy.example".to_vec());
+            message.add_option(CoapOption::ETag, vec![1]);
+            message.add_option(CoapOption::Block2, vec![0x02]);
+            message.set_token(vec![7]);
+            message
+        };
+        let forward = |message: &mut Packet| {
+            let route = proxy.route(message).unwrap();
+            let backend = proxy.select(route).unwrap();
+            proxy.upstream_request(route, &backend, message)
+        };
+
+        let (forwarded, url) = forward(&mut request(&["kitchen", "temp"])).unwrap();
+        assert_eq!(url.host_str(), Some("sensor.example"));
+        assert_eq!(path(&forwarded), ["api", "temp"]);
+        let host = forwarded.get_option(CoapOption::UriHost).unwrap();
+        assert_eq!(host.iter().collect::<Vec<_>>(), [b"sensor.example"]);
+        let port = forwarded.get_first_option_as::<OptionValueU16>(CoapOption::UriPort);
+        assert_eq!(port.unwrap().unwrap().0, 61616);
+        assert!(forwarded.get_token().is_empty());
+        let hop_limit = forwarded.get_option(CoapOption::Unknown(crate::proxy::HOP_LIMIT));
+        assert_eq!(hop_limit.unwrap().front().unwrap(), &[16]);
+        // end-to-end options are passed on, those of the hop are not
+        assert!(forwarded.get_option(CoapOption::ETag).is_some());
+        assert!(forwarded
+            .get_option(CoapOption::Block2)
+            .is_none_or(|values| values.is_empty()));
+
+        let (forwarded, url) = forward(&mut request(&["kitchen", "light", "1"])).unwrap();
+        assert_eq!(url.host_str(), Some("10.0.0.2"));
+        assert_eq!(path(&forwarded), ["1"]);
+        assert!(forwarded
+            .get_option(CoapOption::UriHost)
+            .is_none_or(|values| values.is_empty()));
+        let (forwarded, _) = forward(&mut request(&["kitchenette"])).unwrap();
+        assert_eq!(path(&forwarded), ["kitchenette"]);
+
+        let mut message = request(&["kitchen"]);
+        message.add_option(CoapOption::Unknown(65006), vec![]);
+        assert_eq!(forward(&mut message), Err(Status::BadGateway));
+        let mut message = request(&["kitchen"]);
+        message.add_option(CoapOption::Unknown(65004), vec![]);
+        assert!(forward(&mut message).is_ok());
+    }
+
+    #[test]
+    fn test_select() {
+        let urls: Vec<Url> = ["coap://10.0.0.1", "coap://10.0.0.2", "coap://10.0.0.3"]
+            .iter()
+            .map(|url| Url::parse(url).unwrap())
+            .collect();
+        let proxy = ReverseProxy::new()
+            .with_pool("/a", urls.clone(), Balance::RoundRobin)
+            .with_pool("/b", urls, Balance::LeastOutstanding);
+        let host = |backend: Option<Arc<Backend>>| {
+            backend.map_or(String::new(), |backend| {
+                backend.url.host_str().unwrap().to_string()
+            })
+        };
+        let (a, b) = (&proxy.routes[0], &proxy.routes[1]);
+
+        let hosts: Vec<_> = (0..4).map(|_| host(proxy.select(a))).collect();
+        assert_eq!(hosts, ["10.0.0.1", "10.0.0.2", "10.0.0.3", "10.0.0.1"]);
+        // a server failing the health checks is left out
+        a.pool[1]
+            .failures
+            .store(DEFAULT_MAX_FAILURES, Ordering::Relaxed);
+        let hosts: Vec<_> = (0..4).map(|_| host(proxy.select(a))).collect();
+        assert!(!hosts.contains(&"10.0.0.2".to_string()));
+        for backend in &a.pool {
+            backend
+                .failures
+                .store(DEFAULT_MAX_FAILURES, Ordering::Relaxed);
+        }
+        assert!(proxy.select(a).is_none());
+
+        // the least busy server, whichever is in turn
+        let busy = [&b.pool[0], &b.pool[2]].map(|backend| Outstanding::new(backend.clone()));
+        for _ in 0..3 {
+            assert_eq!(host(proxy.select(b)), "10.0.0.2");
+        }
+        let busier = [&b.pool[1], &b.pool[1]].map(|backend| Outstanding::new(backend.clone()));
+        drop(busy);
+        assert_ne!(host(proxy.select(b)), "10.0.0.2");
+        assert_eq!(b.pool[0].outstanding.load(Ordering::Relaxed), 0);
+        drop(busier);
+    }
+
+    #[test]
+    fn test_rewrite_location() {
+        let path = [b"api".to_vec()];
+        let prefix = [b"kitchen".to_vec()];
+        let mut response = Packet::new();
+        response.add_option(CoapOption::LocationPath, b"api".to_vec());
+        response.add_option(CoapOption::LocationPath, b"42".to_vec());
+        rewrite_location(&mut response, &path, &prefix);
+        let location: Vec<_> = response
+            .get_option(CoapOption::LocationPath)
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        assert_eq!(location, [b"kitchen".to_vec(), b"42".to_vec()]);
+
+        // outside the path the prefix is served from
+        rewrite_location(&mut response, &[b"other".to_vec()], &prefix);
+        assert_eq!(
+            response.get_option(CoapOption::LocationPath).unwrap().len(),
+            2
+        );
+    }
+}
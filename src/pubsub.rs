@@ -0,0 +1,394 @@
+//! A publish-subscribe broker
+//! ([draft-ietf-core-pubsub](https://tools.ietf.org/html/draft-ietf-core-pubsub-09)).
+//!
+//! The broker is enabled with
+//! [`Server::set_broker_path`](crate::Server::set_broker_path) and serves
+//! topics below a topic collection:
+//!
+//! - a POST of a link such as `<temperature>;ct=0` to a collection creates
+//!   a topic, or a nested collection if the link has `ct=40`. A Max-Age
+//!   option gives the topic a lifetime, which each publication refreshes;
+//! - a GET of a collection lists its topics, filtered like discovery;
+//! - a PUT to a topic publishes to it;
+//! - a GET of a topic reads the last publication, and with Observe
+//!   subscribes to the following ones;
+//! - a DELETE of a topic removes it with its subtopics, and tells its
+//!   subscribers with a 4.04 notification.
+//!
+//! Clients find brokers with
+//! [`CoAPClient::discover_brokers`](crate::CoAPClient::discover_brokers) and
+//! use topics with [`create_topic`](crate::CoAPClient::create_topic),
+//! [`publish`](crate::CoAPClient::publish) and
+//! [`subscribe`](crate::CoAPClient::subscribe).
+use super::link_format::{self, Link};
+use super::payload;
+use coap_lite::{
+    option_value::OptionValueU32, CoapOption, CoapRequest, ObserveOption, RequestType as Method,
+    ResponseType as Status,
+};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Path of the topic collection suggested by the draft.
+pub const DEFAULT_PATH: &str = "ps";
+
+/// Resource type of a broker's topic collection, to be advertised in
+/// `/.well-known/core`.
+pub const RESOURCE_TYPE: &str = "core.ps";
+
+#[derive(Debug)]
+struct Topic {
+    /// The attributes the topic was created with.
+    attributes: Vec<link_format::Attribute>,
+    content_formats: Vec<u16>,
+    collection: bool,
+    /// The last publication and its Content-Format.
+    data: Option<(Vec<u8>, Option<u16>)>,
+    lifetime: Option<Duration>,
+    expires: Option<Instant>,
+}
+
+impl Topic {
+    fn refresh(&mut self, now: Instant) {
+        self.expires = self.lifetime.map(|lifetime| now + lifetime);
+    }
+}
+
+/// What the server does with a request after the broker.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Handling {
+    /// Send the response.
+    Respond,
+    /// Pass the request to the observer, to notify the subscribers of a
+    /// publication or to forget a subscription, then send the response.
+    Observe,
+    /// Pass the request to the observer, which answers the subscription.
+    Subscribe,
+    /// Send the response and notify the subscribers of the removed topics.
+    Remove(Vec<String>),
+}
+
+/// The topics of the broker by path, without the collection at its root.
+#[derive(Debug, Default)]
+pub(crate) struct Broker {
+    pub(crate) path: Option<String>,
+    topics: BTreeMap<String, Topic>,
+}
+
+impl Broker {
+    pub(crate) fn new() -> Broker {
+        Broker::default()
+    }
+
+    /// Answer `request` if it is for a topic of the broker.
+    pub(crate) fn handle(&mut self, request: &mut CoapRequest<SocketAddr>) -> Option<Handling> {
+        let root = self.path.as_deref()?;
+        let path = request.get_path();
+        let is_root = path == root;
+        if !is_root && !self.topics.contains_key(&path) {
+            let below_root = path
+                .strip_prefix(root)
+                .is_some_and(|rest| rest.starts_with('/'));
+            if !below_root {
+                return None;
+            }
+        }
+        let collection = is_root || self.topics.get(&path).is_some_and(|topic| topic.collection);
+        let method = *request.get_method();
+        let observe = request.get_observe_flag().and_then(|flag| flag.ok());
+        let now = Instant::now();
+
+        let mut handling = Handling::Respond;
+        let mut content_format = None;
+        let (status, body) = if collection {
+            match method {
+                Method::Get => {
+                    let links = self.topics(&path, &queries(request));
+                    content_format = Some(link_format::CONTENT_FORMAT);
+                    (
+                        Status::Content,
+                        Some(link_format::serialize(&links).into_bytes()),
+                    )
+                }
+                Method::Post => match self.create(&path, request, now) {
+                    Ok(topic) => {
+                        if let Some(ref mut response) = request.response {
+                            for segment in topic.split('/') {
+                                response.message.add_option(
+                                    CoapOption::LocationPath,
+                                    segment.as_bytes().to_vec(),
+                                );
+                            }
+                        }
+                        (Status::Created, None)
+                    }
+                    Err(error) => error,
+                },
+                Method::Delete if !is_root => {
+                    handling = Handling::Remove(self.remove(&path));
+                    (Status::Deleted, None)
+                }
+                _ => (Status::MethodNotAllowed, None),
+            }
+        } else {
+            match (self.topics.get_mut(&path), method) {
+                (None, _) => (Status::NotFound, None),
+                (Some(topic), Method::Put) => {
+                    let format = payload::content_format(&request.message);
+                    let formats = &topic.content_formats;
+                    if format
+                        .is_some_and(|format| !formats.is_empty() && !formats.contains(&format))
+                    {
+                        (Status::UnsupportedContentFormat, None)
+                    } else {
+                        topic.data = Some((request.message.payload.clone(), format));
+                        topic.refresh(now);
+                        handling = Handling::Observe;
+                        (Status::Changed, None)
+                    }
+                }
+                (Some(topic), Method::Get) => match topic.data {
+                    Some(_) if observe == Some(ObserveOption::Register) => {
+                        return Some(Handling::Subscribe);
+                    }
+                    Some((ref data, format)) => {
+                        if observe == Some(ObserveOption::Deregister) {
+                            handling = Handling::Observe;
+                        }
+                        content_format = format;
+                        (Status::Content, Some(data.clone()))
+                    }
+                    None => (Status::NotFound, Some(b"No data published".to_vec())),
+                },
+                (Some(_), Method::Delete) => {
+                    handling = Handling::Remove(self.remove(&path));
+                    (Status::Deleted, None)
+                }
+                (Some(_), _) => (Status::MethodNotAllowed, None),
+            }
+        };
+
+        if let Some(ref mut response) = request.response {
+            response.set_status(status);
+            if let Some(format) = content_format {
+                payload::set_content_format(&mut response.message, format);
+            }
+            if let Some(body) = body {
+                response.message.payload = body;
+            }
+        }
+        Some(handling)
+    }
+
+    /// Create the topic described by the payload of `request` in the
+    /// collection at `collection` and return its path.
+    fn create(
+        &mut self,
+        collection: &str,
+        request: &CoapRequest<SocketAddr>,
+        now: Instant,
+    ) -> Result<String, (Status, Option<Vec<u8>>)> {
+        let bad_request =
+            |diagnostic: &str| (Status::BadRequest, Some(diagnostic.as_bytes().to_vec()));
+        match payload::content_format(&request.message) {
+            Some(link_format::CONTENT_FORMAT) | None => {}
+            Some(_) => return Err((Status::UnsupportedContentFormat, None)),
+        }
+        let links = std::str::from_utf8(&request.message.payload)
+            .ok()
+            .and_then(|links| link_format::parse(links).ok())
+            .unwrap_or_default();
+        let link = match links.as_slice() {
+            [link] => link,
+            _ => return Err(bad_request("Expected one link")),
+        };
+        let name = link.target.trim_matches('/');
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(bad_request("Invalid topic name"));
+        }
+        let path = format!("{}/{}", collection, name);
+        if self.topics.contains_key(&path) {
+            return Err((Status::Forbidden, Some(b"Topic exists".to_vec())));
+        }
+        let lifetime = request
+            .message
+            .get_first_option_as::<OptionValueU32>(CoapOption::MaxAge)
+            .and_then(|value| value.ok())
+            .map(|value| Duration::from_secs(value.0.into()));
+        let mut topic = Topic {
+            content_formats: link.content_formats(),
+            collection: link.content_formats() == [link_format::CONTENT_FORMAT],
+            attributes: link.attributes.clone(),
+            data: None,
+            lifetime,
+            expires: None,
+        };
+        topic.refresh(now);
+        self.topics.insert(path.clone(), topic);
+        Ok(path)
+    }
+
+    /// Return the links to the topics directly in `collection` that match
+    /// `queries`.
+    fn topics(&self, collection: &str, queries: &[String]) -> Vec<Link> {
+        self.topics
+            .iter()
+            .filter(|(path, _)| {
+                path.strip_prefix(collection)
+                    .and_then(|rest| rest.strip_prefix('/'))
+                    .is_some_and(|name| !name.contains('/'))
+            })
+            .map(|(path, topic)| Link {
+                target: format!("/{}", path),
+                attributes: topic.attributes.clone(),
+            })
+            .filter(|link| queries.iter().all(|query| link.matches_query(query)))
+            .collect()
+    }
+
+    /// Remove the topic at `path` with its subtopics and return their paths.
+    fn remove(&mut self, path: &str) -> Vec<String> {
+        let prefix = format!("{}/", path);
+        let removed: Vec<String> = self
+            .topics
+            .keys()
+            .filter(|topic| *topic == path || topic.starts_with(&prefix))
+            .cloned()
+            .collect();
+        for topic in &removed {
+            self.topics.remove(topic);
+        }
+        removed
+    }
+
+    /// Remove the topics whose lifetime passed before `now` and return the
+    /// paths of the removed topics.
+    pub(crate) fn remove_expired(&mut self, now: Instant) -> Vec<String> {
+        let expired: Vec<String> = self
+            .topics
+            .iter()
+            .filter(|(_, topic)| topic.expires.is_some_and(|expires| expires <= now))
+            .map(|(path, _)| path.clone())
+            .collect();
+        expired.iter().flat_map(|path| self.remove(path)).collect()
+    }
+}
+
+fn queries(request: &CoapRequest<SocketAddr>) -> Vec<String> {
+    request
+        .message
+        .get_option(CoapOption::UriQuery)
+        .map(|queries| {
+            queries
+                .iter()
+                .map(|query| String::from_utf8_lossy(query).into_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use coap_lite::{MessageClass, Packet};
+
+    fn request(method: Method, path: &str, payload: &str) -> CoapRequest<SocketAddr> {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(method);
+        let mut request = CoapRequest::from_packet(packet, "127.0.0.1:5683".parse().unwrap());
+        request.set_path(path);
+        request.message.payload = payload.as_bytes().to_vec();
+        request
+    }
+
+    fn status(broker: &mut Broker, mut request: CoapRequest<SocketAddr>) -> Status {
+        broker.handle(&mut request).unwrap();
+        *request.response.unwrap().get_status()
+    }
+
+    #[test]
+    fn test_topics() {
+        let mut broker = Broker::new();
+        assert_eq!(broker.handle(&mut request(Method::Get, "ps", "")), None);
+        broker.path = Some(DEFAULT_PATH.to_string());
+        assert_eq!(broker.handle(&mut request(Method::Get, "other", "")), None);
+
+        let create = request(Method::Post, "ps", "<sensors>;ct=40");
+        assert_eq!(status(&mut broker, create), Status::Created);
+        let mut create = request(Method::Post, "ps/sensors", "<temp>;ct=0;rt=\"temperature\"");
+        assert_eq!(broker.handle(&mut create), Some(Handling::Respond));
+        let response = create.response.unwrap();
+        assert_eq!(*response.get_status(), Status::Created);
+        let location: Vec<Vec<u8>> = response
+            .message
+            .get_option(CoapOption::LocationPath)
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect();
+        assert_eq!(
+            location,
+            [b"ps".to_vec(), b"sensors".to_vec(), b"temp".to_vec()]
+        );
+        let create = request(Method::Post, "ps/sensors", "<temp>;ct=0");
+        assert_eq!(status(&mut broker, create), Status::Forbidden);
+        let create = request(Method::Post, "ps", "<a/b>");
+        assert_eq!(status(&mut broker, create), Status::BadRequest);
+
+        let mut list = request(Method::Get, "ps/sensors", "");
+        list.message
+            .add_option(CoapOption::UriQuery, b"rt=temp*".to_vec());
+        broker.handle(&mut list);
+        let payload = list.response.unwrap().message.payload;
+        assert_eq!(payload, b"</ps/sensors/temp>;ct=0;rt=\"temperature\"");
+
+        let read = request(Method::Get, "ps/sensors/temp", "");
+        assert_eq!(status(&mut broker, read), Status::NotFound);
+        let mut publish = request(Method::Put, "ps/sensors/temp", "21.5");
+        assert_eq!(broker.handle(&mut publish), Some(Handling::Observe));
+        let mut publish = request(Method::Put, "ps/sensors/temp", "{}");
+        payload::set_content_format(&mut publish.message, 50);
+        assert_eq!(
+            status(&mut broker, publish),
+            Status::UnsupportedContentFormat
+        );
+        let mut read = request(Method::Get, "ps/sensors/temp", "");
+        broker.handle(&mut read);
+        assert_eq!(read.response.unwrap().message.payload, b"21.5");
+        let mut subscribe = request(Method::Get, "ps/sensors/temp", "");
+        subscribe.set_observe_flag(ObserveOption::Register);
+        assert_eq!(broker.handle(&mut subscribe), Some(Handling::Subscribe));
+        let publish = request(Method::Put, "ps/sensors/humidity", "40");
+        assert_eq!(status(&mut broker, publish), Status::NotFound);
+
+        let mut delete = request(Method::Delete, "ps/sensors", "");
+        assert_eq!(
+            broker.handle(&mut delete),
+            Some(Handling::Remove(vec![
+                "ps/sensors".to_string(),
+                "ps/sensors/temp".to_string()
+            ]))
+        );
+        let delete = request(Method::Delete, "ps", "");
+        assert_eq!(status(&mut broker, delete), Status::MethodNotAllowed);
+    }
+
+    #[test]
+    fn test_lifetime() {
+        let mut broker = Broker::new();
+        broker.path = Some(DEFAULT_PATH.to_string());
+        let mut create = request(Method::Post, "ps", "<temp>");
+        create
+            .message
+            .add_option_as(CoapOption::MaxAge, OptionValueU32(60));
+        broker.handle(&mut create);
+        broker.handle(&mut request(Method::Post, "ps", "<light>"));
+
+        let now = Instant::now();
+        assert!(broker.remove_expired(now).is_empty());
+        let later = now + Duration::from_secs(61);
+        assert_eq!(broker.remove_expired(later), ["ps/temp".to_string()]);
+        assert!(broker.remove_expired(later).is_empty());
+    }
+}
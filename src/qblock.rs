@@ -0,0 +1,544 @@
+//! Robust block-wise transfers with Q-Block1 and Q-Block2
+//! ([RFC 9177](https://tools.ietf.org/html/rfc9177)).
+//!
+//! Unlike Block1 and Block2, which take a round trip per block, the Q-Block
+//! options let a body go out in sets of up to [`MAX_PAYLOADS`]
+//! non-confirmable blocks, and the receiver asks for the blocks it misses:
+//! the server answers an incomplete Q-Block1 body with 4.08 Request Entity
+//! Incomplete and the numbers of the missing blocks, and the client repeats
+//! a request with the numbers of the missing Q-Block2 blocks. This suits
+//! lossy paths with long round trips.
+//!
+//! Pacing follows from the sets: the server confirms every complete set of
+//! a Q-Block1 body with 2.31 Continue before the client sends the next one,
+//! and sends the next set of a Q-Block2 body only when the client asks for
+//! it, so that no more than `MAX_PAYLOADS` blocks are in flight.
+//!
+//! The server handles both options by itself and passes complete bodies to
+//! the handler. The client uses them once enabled with
+//! [`CoAPClient::set_q_block`](crate::CoAPClient::set_q_block).
+//!
+//! The bodies in either direction are held within the memory budget set
+//! with
+//! [`Server::set_transfer_memory_budget`](crate::Server::set_transfer_memory_budget):
+//! a Q-Block1 body that exceeds it is answered with 4.13 Request Entity Too
+//! Large, and a Q-Block2 body that exceeds it is not remembered, so that the
+//! handler is asked for it again.
+use coap_lite::{
+    block_handler::BlockValue, option_value::OptionValueU16, option_value::OptionValueU32,
+    CoapOption, CoapRequest, CoapResponse, MessageClass, MessageType, Packet,
+    ResponseType as Status,
+};
+use log::debug;
+use std::collections::hash_map::RandomState;
+use std::collections::BTreeMap;
+use std::hash::BuildHasher;
+use std::mem;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use super::budget::{BudgetedCache, Footprint};
+use super::echo::REQUEST_TAG;
+use super::message;
+use super::mtu;
+use super::payload;
+
+/// Option number of Q-Block1.
+pub const Q_BLOCK1: u16 = 19;
+
+/// Option number of Q-Block2.
+pub const Q_BLOCK2: u16 = 31;
+
+/// How many blocks are sent before waiting for the peer.
+pub const MAX_PAYLOADS: usize = 10;
+
+/// How often missing blocks are asked for or sent again before a transfer
+/// fails.
+pub const MAX_RECOVERY_ROUNDS: u32 = 4;
+
+/// Content-Format of a list of missing blocks,
+/// application/missing-blocks+cbor-seq.
+pub const MISSING_BLOCKS_FORMAT: u16 = 272;
+
+/// How many transfers are remembered in either direction.
+const CAPACITY: usize = 1024;
+
+/// Return the first value of the Q-Block option `number`.
+pub fn get(message: &Packet, number: u16) -> Option<BlockValue> {
+    message
+        .get_first_option_as::<BlockValue>(CoapOption::Unknown(number))
+        .and_then(|block| block.ok())
+}
+
+/// Return all values of the Q-Block option `number`.
+pub fn get_all(message: &Packet, number: u16) -> Vec<BlockValue> {
+    message
+        .get_options_as::<BlockValue>(CoapOption::Unknown(number))
+        .map(|blocks| blocks.into_iter().filter_map(|block| block.ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Encode the numbers of missing blocks as a CBOR sequence of unsigned
+/// integers.
+pub fn encode_missing_blocks(blocks: &[usize]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for &num in blocks {
+        let num = num as u64;
+        match num {
+            0..=23 => data.push(num as u8),
+            24..=0xff => data.extend([24, num as u8]),
+            0x100..=0xffff => {
+                data.push(25);
+                data.extend((num as u16).to_be_bytes());
+            }
+            0x1_0000..=0xffff_ffff => {
+                data.push(26);
+                data.extend((num as u32).to_be_bytes());
+            }
+            _ => {
+                data.push(27);
+                data.extend(num.to_be_bytes());
+            }
+        }
+    }
+    data
+}
+
+/// Decode a list of missing blocks, or return `None` if it is no CBOR
+/// sequence of unsigned integers.
+pub fn decode_missing_blocks(mut data: &[u8]) -> Option<Vec<usize>> {
+    let mut blocks = Vec::new();
+    while let Some((&initial, rest)) = data.split_first() {
+        let len = match initial {
+            0..=23 => 0,
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => return None,
+        };
+        let (bytes, rest) = (rest.get(..len)?, &rest[len..]);
+        let num = match len {
+            0 => u64::from(initial),
+            _ => bytes.iter().fold(0, |num, &b| num << 8 | u64::from(b)),
+        };
+        blocks.push(usize::try_from(num).ok()?);
+        data = rest;
+    }
+    Some(blocks)
+}
+
+/// A Q-Block1 body being received.
+struct Upload {
+    size: usize,
+    blocks: BTreeMap<usize, Vec<u8>>,
+    last: Option<usize>,
+    /// The missing blocks last reported to the client.
+    reported: Vec<usize>,
+}
+
+impl Footprint for Upload {
+    fn footprint(&self) -> usize {
+        self.blocks.values().map(Vec::capacity).sum()
+    }
+}
+
+/// A Q-Block2 body being sent.
+struct Download {
+    /// The response without its payload.
+    response: Packet,
+    body: Vec<u8>,
+    size: usize,
+}
+
+impl Footprint for Download {
+    fn footprint(&self) -> usize {
+        message::encoded_len(&self.response) + self.body.capacity()
+    }
+}
+
+/// The Q-Block transfers of a server in either direction.
+pub(crate) struct Transfers {
+    /// Bodies being received, by client, resource and Request-Tag.
+    uploads: BudgetedCache<(SocketAddr, String, Option<Vec<u8>>), Upload>,
+    /// Bodies being sent, by client and resource.
+    downloads: BudgetedCache<(SocketAddr, String), Download>,
+    random: RandomState,
+    message_id: u16,
+}
+
+impl Transfers {
+    pub(crate) fn new(lifetime: Duration) -> Transfers {
+        let random = RandomState::new();
+        Transfers {
+            uploads: BudgetedCache::new(lifetime, CAPACITY),
+            downloads: BudgetedCache::new(lifetime, CAPACITY),
+            message_id: random.hash_one(0) as u16,
+            random,
+        }
+    }
+
+    /// Hold the bodies in either direction within `budget` bytes each,
+    /// evicting the least recently used transfers.
+    pub(crate) fn set_budget(&mut self, budget: Option<usize>) {
+        self.uploads.set_budget(budget);
+        self.downloads.set_budget(budget);
+    }
+
+    /// Collect a block of a Q-Block1 body, and return whether the request
+    /// may be processed: with the complete body in place of the block, or
+    /// as is if it carries no Q-Block1 option. Otherwise the request is
+    /// left with the response to send, if any.
+    pub(crate) fn receive(
+        &mut self,
+        addr: SocketAddr,
+        request: &mut CoapRequest<SocketAddr>,
+    ) -> bool {
+        let block = match get(&request.message, Q_BLOCK1) {
+            Some(block) => block,
+            None => return true,
+        };
+        let tag = request
+            .message
+            .get_option(CoapOption::Unknown(REQUEST_TAG))
+            .and_then(|values| values.front().cloned());
+        let key = (addr, request.get_path(), tag);
+        let num = usize::from(block.num);
+        let mut upload = match self.uploads.remove(&key) {
+            Some(upload) if upload.size == block.size() => upload,
+            _ => Upload {
+                size: block.size(),
+                blocks: BTreeMap::new(),
+                last: None,
+                reported: Vec::new(),
+            },
+        };
+        upload
+            .blocks
+            .insert(num, mem::take(&mut request.message.payload));
+        if !block.more {
+            upload.last = Some(num);
+        }
+        let end = upload
+            .last
+            .or_else(|| upload.blocks.keys().next_back().copied())
+            .unwrap_or(num);
+        let missing: Vec<usize> = (0..=end)
+            .filter(|num| !upload.blocks.contains_key(num))
+            .collect();
+
+        if upload.last.is_some() && missing.is_empty() {
+            request.message.payload = upload.blocks.into_values().flatten().collect();
+            request.message.clear_option(CoapOption::Unknown(Q_BLOCK1));
+            return true;
+        }
+        // the client waits for an answer at the end of a set and after
+        // the last block, including blocks sent again
+        let end_of_set = block.more && (num + 1) % MAX_PAYLOADS == 0;
+        if !(end_of_set || !block.more || upload.reported.last() == Some(&num)) {
+            let acknowledge = request.message.header.get_type() == MessageType::Confirmable;
+            match request.response {
+                Some(ref mut response) if acknowledge => {
+                    response.message.header.code = MessageClass::Empty;
+                    response.message.set_token(Vec::new());
+                }
+                _ => request.response = None,
+            }
+            if self.uploads.insert(key, upload).is_err() {
+                self.reject(request);
+            }
+            return false;
+        }
+        if let Some(ref mut response) = request.response {
+            if missing.is_empty() {
+                response.set_status(Status::Continue);
+                if let Ok(block) = BlockValue::new(end, true, upload.size) {
+                    response
+                        .message
+                        .add_option_as(CoapOption::Unknown(Q_BLOCK1), block);
+                }
+            } else {
+                response.set_status(Status::RequestEntityIncomplete);
+                response.message.add_option_as(
+                    CoapOption::ContentFormat,
+                    OptionValueU16(MISSING_BLOCKS_FORMAT),
+                );
+                response.message.payload = encode_missing_blocks(&missing);
+            }
+        }
+        upload.reported = missing;
+        if self.uploads.insert(key, upload).is_err() {
+            self.reject(request);
+        }
+        false
+    }
+
+    /// Answer a block of a Q-Block1 body that exceeds the budget.
+    fn reject(&self, request: &mut CoapRequest<SocketAddr>) {
+        debug!("Q-Block1 body of {} exceeds the budget", request.get_path());
+        // in place of the acknowledgement or the report of a set
+        request.response = CoapResponse::new(&request.message);
+        if let (Some(budget), Some(response)) = (self.uploads.budget(), request.response.as_mut()) {
+            response.set_status(Status::RequestEntityTooLarge);
+            payload::set_size(&mut response.message, CoapOption::Size1, budget);
+        }
+    }
+
+    /// Answer a request for further blocks of a body sent with Q-Block2,
+    /// or return `None` if it asks for the first block only or the body is
+    /// no longer remembered, so that the handler produces the body again.
+    pub(crate) fn resend(
+        &mut self,
+        addr: SocketAddr,
+        request: &CoapRequest<SocketAddr>,
+    ) -> Option<Vec<Packet>> {
+        let requested = get_all(&request.message, Q_BLOCK2);
+        if requested.iter().all(|block| block.num == 0 && !block.more) {
+            return None;
+        }
+        let download = self.downloads.get(&(addr, request.get_path()))?;
+        Some(blocks(
+            download,
+            &request.message,
+            &requested,
+            &mut self.message_id,
+        ))
+    }
+
+    /// Split the response to a request with a Q-Block2 option into blocks
+    /// that fit `max_message_size`, remember the body for requests of
+    /// further blocks and return the first set. Returns `None` for
+    /// responses that fit into a single message.
+    pub(crate) fn split(
+        &mut self,
+        addr: SocketAddr,
+        request: &CoapRequest<SocketAddr>,
+        response: &Packet,
+        max_message_size: usize,
+    ) -> Option<Vec<Packet>> {
+        let requested = get(&request.message, Q_BLOCK2)?;
+        let len = message::encoded_len(response);
+        if len <= max_message_size {
+            return None;
+        }
+        // Q-Block2, ETag and Size2 take up to 5, 9 and 5 bytes, the
+        // payload marker one more
+        let overhead = len - response.payload.len() + 20;
+        let size = mtu::block_size(max_message_size, overhead)?.min(requested.size());
+
+        let mut template = response.clone();
+        let body = mem::take(&mut template.payload);
+        if template.get_option(CoapOption::ETag).is_none() {
+            let etag = self.random.hash_one(&body).to_be_bytes().to_vec();
+            template.add_option(CoapOption::ETag, etag);
+        }
+        let total = OptionValueU32(u32::try_from(body.len()).unwrap_or(u32::MAX));
+        template.add_option_as(CoapOption::Size2, total);
+        let download = Download {
+            response: template,
+            body,
+            size,
+        };
+        let first_set = BlockValue {
+            more: true,
+            ..requested
+        };
+        let blocks = blocks(
+            &download,
+            &request.message,
+            &[first_set],
+            &mut self.message_id,
+        );
+        let key = (addr, request.get_path());
+        if self.downloads.insert(key, download).is_err() {
+            debug!("Q-Block2 body of {} exceeds the budget", request.get_path());
+        }
+        Some(blocks)
+    }
+}
+
+/// Return the `requested` blocks of a body, a whole set for a value with
+/// the M bit set. The first block answers the request, the others follow as
+/// non-confirmable responses.
+fn blocks(
+    download: &Download,
+    request: &Packet,
+    requested: &[BlockValue],
+    message_id: &mut u16,
+) -> Vec<Packet> {
+    let count = download.body.len().div_ceil(download.size);
+    let mut nums = Vec::new();
+    for block in requested {
+        let num = usize::from(block.num);
+        let end = match block.more {
+            true => count.min(num + MAX_PAYLOADS),
+            false => count.min(num + 1),
+        };
+        for num in num..end {
+            if !nums.contains(&num) {
+                nums.push(num);
+            }
+        }
+    }
+
+    let mut packets = Vec::new();
+    for num in nums {
+        let start = num * download.size;
+        let end = download.body.len().min(start + download.size);
+        let block = match BlockValue::new(num, end < download.body.len(), download.size) {
+            Ok(block) => block,
+            Err(_) => continue,
+        };
+        let mut packet = download.response.clone();
+        packet.set_token(request.get_token().to_vec());
+        if packets.is_empty() {
+            if request.header.get_type() == MessageType::Confirmable {
+                packet.header.set_type(MessageType::Acknowledgement);
+            } else {
+                packet.header.set_type(MessageType::NonConfirmable);
+            }
+            packet.header.message_id = request.header.message_id;
+        } else {
+            *message_id = message_id.wrapping_add(1);
+            packet.header.set_type(MessageType::NonConfirmable);
+            packet.header.message_id = *message_id;
+        }
+        packet.add_option_as(CoapOption::Unknown(Q_BLOCK2), block);
+        packet.payload = download.body[start..end].to_vec();
+        packets.push(packet);
+    }
+    packets
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use coap_lite::{CoapResponse, RequestType as Method};
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    #[test]
+    fn test_missing_blocks() {
+        let blocks = vec![0, 23, 24, 255, 256, 70000];
+        let data = encode_missing_blocks(&blocks);
+        assert_eq!(data[..4], [0x00, 0x17, 0x18, 0x18]);
+        assert_eq!(decode_missing_blocks(&data), Some(blocks));
+        assert_eq!(decode_missing_blocks(&[0x19, 0x01]), None);
+        assert_eq!(decode_missing_blocks(&[0x40]), None);
+    }
+
+    fn spawn_echo_server() -> SocketAddr {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|request: CoapRequest<SocketAddr>| async move {
+                            let mut response: CoapResponse = request.response?;
+                            response.message.payload = request.message.payload;
+                            Some(response)
+                        })
+                        .await
+                        .unwrap();
+                });
+        });
+        rx.recv().unwrap()
+    }
+
+    #[test]
+    fn test_missing_q_block1() {
+        let addr = spawn_echo_server();
+        let client = CoAPClient::new(addr).unwrap();
+        let body: Vec<u8> = (0..40).collect();
+        let send = |num: usize| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Put);
+            request.set_path("/upload");
+            request.message.header.set_type(MessageType::NonConfirmable);
+            request.message.header.message_id = num as u16;
+            let end = body.len().min(num * 16 + 16);
+            request.message.add_option_as(
+                CoapOption::Unknown(Q_BLOCK1),
+                BlockValue::new(num, end < body.len(), 16).unwrap(),
+            );
+            request.message.payload = body[num * 16..end].to_vec();
+            client.send(&request).unwrap();
+        };
+
+        send(0);
+        send(2);
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::RequestEntityIncomplete);
+        assert_eq!(
+            decode_missing_blocks(&response.message.payload),
+            Some(vec![1])
+        );
+        send(1);
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.payload, body);
+    }
+
+    #[test]
+    fn test_q_block_exchange() {
+        let addr = spawn_echo_server();
+        let mut client = CoAPClient::new(addr).unwrap();
+        client.set_q_block(true);
+        // 12 blocks in each direction, i.e. two sets
+        let body: Vec<u8> = (0..12000).map(|i| i as u8).collect();
+        let response = client
+            .request_path("/upload", Method::Put, Some(body.clone()), None, None)
+            .unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.payload, body);
+        assert!(get(&response.message, Q_BLOCK2).is_none());
+        assert_eq!(client.last_exchange_stats().unwrap().blocks, 12);
+    }
+
+    #[test]
+    fn test_missing_q_block2() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let body: Vec<u8> = (0..40).collect();
+        let server = std::thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let mut answer = |nums: &[usize]| {
+                let (len, peer) = socket.recv_from(&mut buf).unwrap();
+                let request = Packet::from_bytes(&buf[..len]).unwrap();
+                for &num in nums {
+                    let mut response = CoapResponse::new(&request).unwrap();
+                    response
+                        .message
+                        .header
+                        .set_type(MessageType::NonConfirmable);
+                    let end = body.len().min(num * 16 + 16);
+                    response.message.add_option_as(
+                        CoapOption::Unknown(Q_BLOCK2),
+                        BlockValue::new(num, end < body.len(), 16).unwrap(),
+                    );
+                    response.message.payload = body[num * 16..end].to_vec();
+                    let bytes = response.message.to_bytes().unwrap();
+                    socket.send_to(&bytes, peer).unwrap();
+                }
+                get_all(&request, Q_BLOCK2)
+            };
+            // the second block gets lost
+            answer(&[0, 2]);
+            answer(&[1])
+        });
+
+        let mut client = CoAPClient::new(addr).unwrap();
+        client.set_q_block(true);
+        let timeout = Duration::from_millis(200);
+        let response = client
+            .request_path_with_timeout("/", Method::Get, None, None, None, timeout)
+            .unwrap();
+        assert_eq!(response.message.payload, (0..40).collect::<Vec<u8>>());
+        let requested = server.join().unwrap();
+        assert_eq!(requested, vec![BlockValue::new(1, false, 16).unwrap()]);
+    }
+}
@@ -0,0 +1,249 @@
+//! Running one server per core.
+//!
+//! A [`Server`] runs on a single task, so a single server uses one core at
+//! most. A [`MultiReactor`] runs several servers, each with a
+//! single-threaded Tokio runtime on a thread of its own and a UDP socket of
+//! its own bound to the same address with `SO_REUSEPORT`. The kernel
+//! spreads the datagrams over the sockets by a hash of their source and
+//! destination, so each peer keeps talking to the same server, which holds
+//! the deduplication, observe and block transfer state of its peers alone.
+//!
+//! With the `affinity` feature on Linux, the threads can be pinned to one
+//! CPU each, so that a reactor does not migrate between cores and keeps its
+//! caches warm.
+//!
+//! `SO_REUSEPORT` is not available on Windows, so neither is this module.
+use coap_lite::{CoapRequest, CoapResponse};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tokio::net::UdpSocket;
+
+use super::server::Server;
+use super::transport::UdpTransport;
+
+/// Builder of a group of servers sharing one address, one per thread.
+#[derive(Debug, Clone)]
+pub struct MultiReactor {
+    addr: SocketAddr,
+    reactors: usize,
+    #[cfg(all(target_os = "linux", feature = "affinity"))]
+    pin_threads: bool,
+}
+
+impl MultiReactor {
+    /// Create a builder for servers on the given address, by default one
+    /// per available core.
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<MultiReactor> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no addresses to bind to"))?;
+        Ok(MultiReactor {
+            addr,
+            reactors: thread::available_parallelism().map_or(1, usize::from),
+            #[cfg(all(target_os = "linux", feature = "affinity"))]
+            pin_threads: false,
+        })
+    }
+
+    /// Run `reactors` servers instead of one per core.
+    pub fn reactors(mut self, reactors: usize) -> MultiReactor {
+        self.reactors = reactors.max(1);
+        self
+    }
+
+    /// Pin the thread of each server to a CPU of its own, the first one to
+    /// the first CPU the process may run on and so on, wrapping around if
+    /// there are more servers than CPUs.
+    #[cfg(all(target_os = "linux", feature = "affinity"))]
+    pub fn pin_threads(mut self, pin: bool) -> MultiReactor {
+        self.pin_threads = pin;
+        self
+    }
+
+    /// Bind the sockets and start the servers, each answering requests with
+    /// a clone of `handler`.
+    pub fn spawn<F, HandlerRet>(self, handler: F) -> Result<Reactors>
+    where
+        F: FnMut(CoapRequest<SocketAddr>) -> HandlerRet + Clone + Send + 'static,
+        HandlerRet: Future<Output = Option<CoapResponse>> + 'static,
+    {
+        self.spawn_with(|_| {}, handler)
+    }
+
+    /// Bind the sockets and start the servers like [`spawn`](Self::spawn),
+    /// calling `configure` with each server before it runs, e.g. to set its
+    /// options or to add its resource links.
+    pub fn spawn_with<C, F, HandlerRet>(self, configure: C, handler: F) -> Result<Reactors>
+    where
+        C: Fn(&mut Server<'static, HandlerRet>) + Send + Sync + 'static,
+        F: FnMut(CoapRequest<SocketAddr>) -> HandlerRet + Clone + Send + 'static,
+        HandlerRet: Future<Output = Option<CoapResponse>> + 'static,
+    {
+        // bound here, so that binding errors are returned and the other
+        // sockets get the port of the first if it was chosen by the system
+        let first = reuse_port_socket(self.addr)?;
+        let local_addr = first
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "not an IP socket"))?;
+        let mut sockets = vec![first];
+        for _ in 1..self.reactors {
+            sockets.push(reuse_port_socket(local_addr)?);
+        }
+        #[cfg(all(target_os = "linux", feature = "affinity"))]
+        let cpus = match self.pin_threads {
+            true => allowed_cpus()?,
+            false => Vec::new(),
+        };
+
+        let configure = Arc::new(configure);
+        let mut threads = Vec::with_capacity(sockets.len());
+        for (index, socket) in sockets.into_iter().enumerate() {
+            let configure = configure.clone();
+            let handler = handler.clone();
+            #[cfg(all(target_os = "linux", feature = "affinity"))]
+            let cpu = (!cpus.is_empty()).then(|| cpus[index % cpus.len()]);
+            let thread = thread::Builder::new()
+                .name(format!("coap-reactor-{}", index))
+                .spawn(move || {
+                    #[cfg(all(target_os = "linux", feature = "affinity"))]
+                    if let Some(cpu) = cpu {
+                        pin_current_thread(cpu)?;
+                    }
+                    let runtime = tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?;
+                    runtime.block_on(async move {
+                        let socket = UdpSocket::from_std(socket.into())?;
+                        let mut server = Server::from_transport(UdpTransport::from_socket(socket));
+                        configure(&mut server);
+                        server.run(handler).await
+                    })
+                })?;
+            threads.push(thread);
+        }
+        Ok(Reactors {
+            local_addr,
+            threads,
+        })
+    }
+}
+
+/// The servers started by a [`MultiReactor`].
+#[derive(Debug)]
+pub struct Reactors {
+    local_addr: SocketAddr,
+    threads: Vec<JoinHandle<Result<()>>>,
+}
+
+impl Reactors {
+    /// Return the address the servers are bound to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Return the number of servers.
+    pub fn len(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Return whether there are no servers, which is never the case.
+    pub fn is_empty(&self) -> bool {
+        self.threads.is_empty()
+    }
+
+    /// Wait for all servers to stop, returning the first error of one.
+    pub fn join(self) -> Result<()> {
+        let mut result = Ok(());
+        for thread in self.threads {
+            let stopped = thread
+                .join()
+                .unwrap_or_else(|_| Err(Error::other("reactor thread panicked")));
+            if result.is_ok() {
+                result = stopped;
+            }
+        }
+        result
+    }
+}
+
+/// Bind a non-blocking UDP socket that other sockets can bind to as well.
+fn reuse_port_socket(addr: SocketAddr) -> Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Return the CPUs the process may run on.
+#[cfg(all(target_os = "linux", feature = "affinity"))]
+fn allowed_cpus() -> Result<Vec<usize>> {
+    // SAFETY: the set is a plain bit mask and sized correctly
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set) != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok((0..libc::CPU_SETSIZE as usize)
+            .filter(|cpu| libc::CPU_ISSET(*cpu, &set))
+            .collect())
+    }
+}
+
+/// Restrict the calling thread to `cpu`.
+#[cfg(all(target_os = "linux", feature = "affinity"))]
+fn pin_current_thread(cpu: usize) -> Result<()> {
+    // SAFETY: the set is a plain bit mask and sized correctly
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::CoAPClient;
+    use coap_lite::RequestType as Method;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_reactors() {
+        let reactors = MultiReactor::new("127.0.0.1:0").unwrap().reactors(2);
+        #[cfg(all(target_os = "linux", feature = "affinity"))]
+        let reactors = reactors.pin_threads(true);
+        let reactors = reactors
+            .spawn(|req: CoapRequest<SocketAddr>| async move {
+                let name = thread::current().name().unwrap().to_string();
+                let mut response = req.response?;
+                response.message.payload = name.into_bytes();
+                Some(response)
+            })
+            .unwrap();
+        assert_eq!(reactors.len(), 2);
+
+        let mut used = HashSet::new();
+        for _ in 0..16 {
+            let mut client = CoAPClient::new(reactors.local_addr()).unwrap();
+            let mut names = HashSet::new();
+            for _ in 0..3 {
+                let request = client.request_path("/", Method::Get, None, None, None);
+                names.insert(request.unwrap().message.payload);
+            }
+            // a peer always reaches the same server
+            assert_eq!(names.len(), 1);
+            used.extend(names);
+        }
+        assert_eq!(used.len(), 2);
+    }
+}
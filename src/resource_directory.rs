@@ -0,0 +1,563 @@
+//! A CoRE Resource Directory ([RFC 9176](https://tools.ietf.org/html/rfc9176)).
+//!
+//! [`ResourceDirectory`] serves the registration interface, where endpoints
+//! register their links with a POST to `/rd`, refresh them with a POST to
+//! their registration resource and remove them with a DELETE, and the lookup
+//! interfaces `/rd-lookup/ep` and `/rd-lookup/res`. Lookups filter by any
+//! endpoint or link attribute, like discovery queries, and are paged with
+//! `page` and `count`. Registrations expire when their lifetime passes
+//! without an update.
+//!
+//! The directory is called from the handler given to
+//! [`Server::run`](crate::Server::run); its [`links`](ResourceDirectory::links)
+//! can be advertised with [`Server::add_link`](crate::Server::add_link):
+//!
+//! ```no_run
+//! use coap::resource_directory::ResourceDirectory;
+//! use coap::Server;
+//!
+//! #[tokio::main]
+//! async fn main() {
+//!     let mut directory = ResourceDirectory::new();
+//!     let mut server = Server::new("[::]:5683").unwrap();
+//!     for link in directory.links() {
+//!         server.add_link(link);
+//!     }
+//!     server
+//!         .run(move |mut request| {
+//!             directory.handle(&mut request);
+//!             async { request.response }
+//!         })
+//!         .await
+//!         .unwrap();
+//! }
+//! ```
+use super::link_format::{self, Link};
+use super::payload;
+use coap_lite::{CoapOption, CoapRequest, RequestType as Method, ResponseType as Status};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use url::Url;
+
+/// Path of the registration interface.
+pub const REGISTRATION_PATH: &str = "rd";
+
+/// Path of the endpoint lookup interface.
+pub const ENDPOINT_LOOKUP_PATH: &str = "rd-lookup/ep";
+
+/// Path of the resource lookup interface.
+pub const RESOURCE_LOOKUP_PATH: &str = "rd-lookup/res";
+
+/// The lifetime of registrations that do not give one.
+pub const DEFAULT_LIFETIME: Duration = Duration::from_secs(90000);
+
+/// The shortest lifetime a registration may ask for.
+pub const MIN_LIFETIME: Duration = Duration::from_secs(60);
+
+/// The lookup parameters that page the results instead of filtering them.
+const PAGING: [&str; 2] = ["page", "count"];
+
+/// A registered endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Registration {
+    /// The endpoint name (`ep`).
+    pub endpoint: String,
+    /// The sector of the endpoint (`d`).
+    pub sector: Option<String>,
+    /// The base URI of the links, given in `base` or else taken from the
+    /// address the registration came from.
+    pub base: String,
+    /// The lifetime of the registration (`lt`).
+    pub lifetime: Duration,
+    /// Further endpoint attributes such as the endpoint type (`et`).
+    pub attributes: Vec<link_format::Attribute>,
+    /// The registered links, as given by the endpoint.
+    pub links: Vec<Link>,
+    expires: Instant,
+}
+
+impl Registration {
+    /// Return the link to the registration resource at `path`, as returned
+    /// by an endpoint lookup.
+    fn endpoint_link(&self, path: &str) -> Link {
+        let mut link = Link::new(&format!("/{}", path)).with_attribute("ep", &self.endpoint);
+        if let Some(ref sector) = self.sector {
+            link = link.with_attribute("d", sector);
+        }
+        link = link
+            .with_attribute("base", &self.base)
+            .with_attribute("lt", &self.lifetime.as_secs().to_string());
+        link.attributes.extend(self.attributes.iter().cloned());
+        link
+    }
+
+    /// Return the registered links with absolute targets and anchors, as
+    /// returned by a resource lookup.
+    fn resource_links(&self) -> Vec<Link> {
+        let base = match Url::parse(&self.base) {
+            Ok(base) => base,
+            Err(_) => return self.links.clone(),
+        };
+        self.links
+            .iter()
+            .map(|link| {
+                let mut resolved = link.clone();
+                if let Ok(target) = link.target_uri(&base) {
+                    resolved.target = target.to_string();
+                }
+                let anchor = match link.attribute("anchor") {
+                    Some(anchor) => base.join(anchor).map(String::from).ok(),
+                    None => Some(self.base.clone()),
+                };
+                resolved
+                    .attributes
+                    .retain(|attr| !attr.name.eq_ignore_ascii_case("anchor"));
+                if let Some(anchor) = anchor {
+                    resolved = resolved.with_attribute("anchor", &anchor);
+                }
+                resolved
+            })
+            .collect()
+    }
+}
+
+/// The registrations of a resource directory, with the interfaces to
+/// manage and look them up.
+#[derive(Debug, Default)]
+pub struct ResourceDirectory {
+    registrations: BTreeMap<u32, Registration>,
+    next_index: u32,
+}
+
+impl ResourceDirectory {
+    /// Create an empty directory.
+    pub fn new() -> ResourceDirectory {
+        ResourceDirectory::default()
+    }
+
+    /// Return the links to the interfaces of the directory, to be
+    /// advertised in `/.well-known/core`.
+    pub fn links(&self) -> Vec<Link> {
+        vec![
+            Link::new(&format!("/{}", REGISTRATION_PATH))
+                .with_attribute("rt", "core.rd")
+                .with_content_format(link_format::CONTENT_FORMAT),
+            Link::new(&format!("/{}", ENDPOINT_LOOKUP_PATH))
+                .with_attribute("rt", "core.rd-lookup-ep")
+                .with_content_format(link_format::CONTENT_FORMAT),
+            Link::new(&format!("/{}", RESOURCE_LOOKUP_PATH))
+                .with_attribute("rt", "core.rd-lookup-res")
+                .with_content_format(link_format::CONTENT_FORMAT),
+        ]
+    }
+
+    /// Return the current registrations by the path of their resource.
+    pub fn registrations(&self) -> impl Iterator<Item = (String, &Registration)> {
+        self.registrations
+            .iter()
+            .map(|(index, registration)| (registration_path(*index), registration))
+    }
+
+    /// Answer `request` if it is for one of the interfaces of the
+    /// directory, and return whether it was.
+    pub fn handle(&mut self, request: &mut CoapRequest<SocketAddr>) -> bool {
+        self.remove_expired(Instant::now());
+        let path = request.get_path();
+        let queries = queries(request);
+        let method = *request.get_method();
+        let source = request.source;
+        let (status, body) = if path == REGISTRATION_PATH {
+            match method {
+                Method::Post => match self.register(&queries, &request.message, source) {
+                    Ok(index) => {
+                        if let Some(ref mut response) = request.response {
+                            for segment in registration_path(index).split('/') {
+                                response.message.add_option(
+                                    CoapOption::LocationPath,
+                                    segment.as_bytes().to_vec(),
+                                );
+                            }
+                        }
+                        (Status::Created, None)
+                    }
+                    Err(error) => error,
+                },
+                _ => (Status::MethodNotAllowed, None),
+            }
+        } else if let Some(index) = path
+            .strip_prefix(REGISTRATION_PATH)
+            .and_then(|rest| rest.strip_prefix('/'))
+        {
+            let index: Option<u32> = index.parse().ok();
+            match (
+                index.and_then(|index| self.registrations.get_mut(&index)),
+                method,
+            ) {
+                (None, _) => (Status::NotFound, None),
+                (Some(registration), Method::Get) => (
+                    Status::Content,
+                    Some(link_format::serialize(&registration.links)),
+                ),
+                (Some(registration), Method::Post) => match update(registration, &queries) {
+                    Ok(()) => (Status::Changed, None),
+                    Err(error) => error,
+                },
+                (Some(_), Method::Delete) => {
+                    self.registrations.remove(&index.unwrap_or_default());
+                    (Status::Deleted, None)
+                }
+                _ => (Status::MethodNotAllowed, None),
+            }
+        } else if path == ENDPOINT_LOOKUP_PATH || path == RESOURCE_LOOKUP_PATH {
+            match method {
+                Method::Get => match self.lookup(&path, &queries) {
+                    Ok(links) => (Status::Content, Some(link_format::serialize(&links))),
+                    Err(error) => error,
+                },
+                _ => (Status::MethodNotAllowed, None),
+            }
+        } else {
+            return false;
+        };
+
+        let response = match request.response {
+            Some(ref mut response) => response,
+            None => return true,
+        };
+        response.set_status(status);
+        if let Some(body) = body {
+            if status == Status::Content {
+                payload::set_content_format(&mut response.message, link_format::CONTENT_FORMAT);
+            }
+            response.message.payload = body.into_bytes();
+        }
+        true
+    }
+
+    /// Add or replace the registration of an endpoint and return its index.
+    fn register(
+        &mut self,
+        queries: &[(String, Option<String>)],
+        message: &coap_lite::Packet,
+        source: Option<SocketAddr>,
+    ) -> Result<u32, (Status, Option<String>)> {
+        let bad_request = |diagnostic: &str| (Status::BadRequest, Some(diagnostic.to_string()));
+        match payload::content_format(message) {
+            Some(link_format::CONTENT_FORMAT) | None => {}
+            Some(_) => return Err((Status::UnsupportedContentFormat, None)),
+        }
+        let payload =
+            std::str::from_utf8(&message.payload).map_err(|_| bad_request("Invalid links"))?;
+        let links = link_format::parse(payload).map_err(|_| bad_request("Invalid links"))?;
+        let endpoint = query(queries, "ep").ok_or_else(|| bad_request("Missing endpoint name"))?;
+        let sector = query(queries, "d");
+        let base = match query(queries, "base") {
+            Some(base) => base,
+            None => match source {
+                Some(source) => format!("coap://{}", source),
+                None => return Err(bad_request("Missing base URI")),
+            },
+        };
+        let mut registration = Registration {
+            endpoint,
+            sector,
+            base,
+            lifetime: DEFAULT_LIFETIME,
+            attributes: Vec::new(),
+            links,
+            expires: Instant::now(),
+        };
+        update(&mut registration, queries)?;
+
+        let existing = self.registrations.iter().find(|(_, known)| {
+            known.endpoint == registration.endpoint && known.sector == registration.sector
+        });
+        let index = match existing {
+            Some((index, _)) => *index,
+            None => {
+                self.next_index += 1;
+                self.next_index
+            }
+        };
+        self.registrations.insert(index, registration);
+        Ok(index)
+    }
+
+    /// Return the endpoints or resources matching the lookup `queries`.
+    fn lookup(
+        &self,
+        path: &str,
+        queries: &[(String, Option<String>)],
+    ) -> Result<Vec<Link>, (Status, Option<String>)> {
+        let number = |name: &str| match query(queries, name) {
+            Some(value) => value
+                .parse::<usize>()
+                .map(Some)
+                .map_err(|_| (Status::BadRequest, Some(format!("Invalid {}", name)))),
+            None => Ok(None),
+        };
+        let page = number("page")?;
+        let count = number("count")?;
+        let filters: Vec<String> = queries
+            .iter()
+            .filter(|(name, _)| !PAGING.contains(&name.as_str()))
+            .map(|(name, value)| match value {
+                Some(value) => format!("{}={}", name, value),
+                None => name.clone(),
+            })
+            .collect();
+
+        let mut links = Vec::new();
+        for (index, registration) in &self.registrations {
+            let endpoint = registration.endpoint_link(&registration_path(*index));
+            let resources = registration.resource_links();
+            if path == ENDPOINT_LOOKUP_PATH {
+                let matches = filters.iter().all(|filter| {
+                    endpoint.matches_query(filter)
+                        || resources.iter().any(|link| link.matches_query(filter))
+                });
+                if matches {
+                    links.push(endpoint);
+                }
+            } else {
+                links.extend(resources.into_iter().filter(|link| {
+                    filters
+                        .iter()
+                        .all(|filter| link.matches_query(filter) || endpoint.matches_query(filter))
+                }));
+            }
+        }
+
+        let count = count.unwrap_or(links.len());
+        let skip = page.unwrap_or(0).saturating_mul(count);
+        Ok(links.into_iter().skip(skip).take(count).collect())
+    }
+
+    /// Drop the registrations whose lifetime passed before `now`.
+    fn remove_expired(&mut self, now: Instant) {
+        self.registrations
+            .retain(|_, registration| registration.expires > now);
+    }
+}
+
+/// Apply the lifetime, base URI and endpoint attributes in `queries` to a
+/// registration and refresh it.
+fn update(
+    registration: &mut Registration,
+    queries: &[(String, Option<String>)],
+) -> Result<(), (Status, Option<String>)> {
+    let mut attributes = registration.attributes.clone();
+    let mut lifetime = registration.lifetime;
+    for (name, value) in queries {
+        match (name.as_str(), value) {
+            ("ep" | "d", _) => {}
+            ("lt", Some(value)) => {
+                lifetime = value
+                    .parse()
+                    .ok()
+                    .map(Duration::from_secs)
+                    .filter(|lifetime| *lifetime >= MIN_LIFETIME)
+                    .ok_or((Status::BadRequest, Some("Invalid lifetime".to_string())))?;
+            }
+            ("base", Some(value)) => {
+                if Url::parse(value).is_err() {
+                    return Err((Status::BadRequest, Some("Invalid base URI".to_string())));
+                }
+                registration.base = value.clone();
+            }
+            (name, value) => {
+                attributes.retain(|attr| attr.name != name);
+                attributes.push(link_format::Attribute {
+                    name: name.to_string(),
+                    value: value.clone(),
+                });
+            }
+        }
+    }
+    registration.attributes = attributes;
+    registration.lifetime = lifetime;
+    registration.expires = Instant::now() + lifetime;
+    Ok(())
+}
+
+fn registration_path(index: u32) -> String {
+    format!("{}/{}", REGISTRATION_PATH, index)
+}
+
+/// Return the Uri-Query options of a request as names and values.
+fn queries(request: &CoapRequest<SocketAddr>) -> Vec<(String, Option<String>)> {
+    request
+        .message
+        .get_option(CoapOption::UriQuery)
+        .map(|queries| {
+            queries
+                .iter()
+                .map(|query| {
+                    let query = String::from_utf8_lossy(query);
+                    match query.split_once('=') {
+                        Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                        None => (query.into_owned(), None),
+                    }
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn query(queries: &[(String, Option<String>)], name: &str) -> Option<String> {
+    queries
+        .iter()
+        .find(|(known, _)| known == name)
+        .and_then(|(_, value)| value.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use coap_lite::{MessageClass, Packet};
+
+    fn request(
+        method: Method,
+        path: &str,
+        queries: &[&str],
+        payload: &str,
+    ) -> CoapRequest<SocketAddr> {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(method);
+        let mut request = CoapRequest::from_packet(packet, "[2001:db8::1]:61616".parse().unwrap());
+        request.set_path(path);
+        for query in queries {
+            request
+                .message
+                .add_option(CoapOption::UriQuery, query.as_bytes().to_vec());
+        }
+        request.message.payload = payload.as_bytes().to_vec();
+        request
+    }
+
+    fn send(
+        directory: &mut ResourceDirectory,
+        mut request: CoapRequest<SocketAddr>,
+    ) -> (Status, String, Vec<Vec<u8>>) {
+        assert!(directory.handle(&mut request));
+        let response = request.response.unwrap();
+        let location = response
+            .message
+            .get_option(CoapOption::LocationPath)
+            .map(|segments| segments.iter().cloned().collect())
+            .unwrap_or_default();
+        (
+            *response.get_status(),
+            String::from_utf8(response.message.payload).unwrap(),
+            location,
+        )
+    }
+
+    #[test]
+    fn test_registration() {
+        let mut directory = ResourceDirectory::new();
+        let links = "</sensors/temp>;rt=\"temperature\";ct=0,</sensors/light>;rt=\"light-lux\"";
+        let register = request(
+            Method::Post,
+            "rd",
+            &["ep=node1", "lt=600", "et=sensor"],
+            links,
+        );
+        let (status, _, location) = send(&mut directory, register);
+        assert_eq!(status, Status::Created);
+        assert_eq!(location, vec![b"rd".to_vec(), b"1".to_vec()]);
+
+        let (status, body, _) = send(&mut directory, request(Method::Get, "rd/1", &[], ""));
+        assert_eq!(status, Status::Content);
+        assert_eq!(link_format::parse(&body).unwrap().len(), 2);
+
+        let (status, body, _) = send(
+            &mut directory,
+            request(Method::Get, ENDPOINT_LOOKUP_PATH, &[], ""),
+        );
+        assert_eq!(status, Status::Content);
+        assert_eq!(
+            body,
+            "</rd/1>;ep=\"node1\";base=\"coap://[2001:db8::1]:61616\";lt=600;et=\"sensor\""
+        );
+
+        // re-registering the same endpoint replaces the registration
+        let register = request(Method::Post, "rd", &["ep=node1"], "</sensors/temp>");
+        let (_, _, location) = send(&mut directory, register);
+        assert_eq!(location, vec![b"rd".to_vec(), b"1".to_vec()]);
+        assert_eq!(directory.registrations().count(), 1);
+
+        let update = request(Method::Post, "rd/1", &["lt=120"], "");
+        assert_eq!(send(&mut directory, update).0, Status::Changed);
+        let update = request(Method::Post, "rd/1", &["lt=10"], "");
+        assert_eq!(send(&mut directory, update).0, Status::BadRequest);
+        assert_eq!(
+            directory.registrations().next().unwrap().1.lifetime,
+            Duration::from_secs(120)
+        );
+
+        let delete = request(Method::Delete, "rd/1", &[], "");
+        assert_eq!(send(&mut directory, delete).0, Status::Deleted);
+        let delete = request(Method::Delete, "rd/1", &[], "");
+        assert_eq!(send(&mut directory, delete).0, Status::NotFound);
+
+        let register = request(Method::Post, "rd", &[], "</sensors/temp>");
+        assert_eq!(send(&mut directory, register).0, Status::BadRequest);
+        let mut other = request(Method::Get, "sensors/temp", &[], "");
+        assert!(!directory.handle(&mut other));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let mut directory = ResourceDirectory::new();
+        let links = "</temp>;rt=\"temperature\",</light>;rt=\"light-lux\";anchor=\"/node\"";
+        let register = request(Method::Post, "rd", &["ep=node1", "d=floor1"], links);
+        send(&mut directory, register);
+        let register = request(
+            Method::Post,
+            "rd",
+            &["ep=node2", "base=coap://[2001:db8::2]"],
+            "</temp>;rt=\"temperature\"",
+        );
+        send(&mut directory, register);
+
+        let lookup = request(Method::Get, RESOURCE_LOOKUP_PATH, &["rt=temperature"], "");
+        let (_, body, _) = send(&mut directory, lookup);
+        assert_eq!(
+            body,
+            "<coap://[2001:db8::1]:61616/temp>;rt=\"temperature\";\
+             anchor=\"coap://[2001:db8::1]:61616\",\
+             <coap://[2001:db8::2]/temp>;rt=\"temperature\";anchor=\"coap://[2001:db8::2]\""
+        );
+        let lookup = request(
+            Method::Get,
+            RESOURCE_LOOKUP_PATH,
+            &["d=floor1", "rt=light*"],
+            "",
+        );
+        let (_, body, _) = send(&mut directory, lookup);
+        assert_eq!(
+            body,
+            "<coap://[2001:db8::1]:61616/light>;rt=\"light-lux\";\
+             anchor=\"coap://[2001:db8::1]:61616/node\""
+        );
+
+        let lookup = request(Method::Get, ENDPOINT_LOOKUP_PATH, &["rt=light-lux"], "");
+        let links = link_format::parse(&send(&mut directory, lookup).1).unwrap();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].attribute("ep"), Some("node1"));
+        let lookup = request(
+            Method::Get,
+            ENDPOINT_LOOKUP_PATH,
+            &["page=1", "count=1"],
+            "",
+        );
+        let links = link_format::parse(&send(&mut directory, lookup).1).unwrap();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].attribute("ep"), Some("node2"));
+
+        directory.remove_expired(Instant::now() + DEFAULT_LIFETIME);
+        assert_eq!(directory.registrations().count(), 0);
+    }
+}
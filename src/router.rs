@@ -0,0 +1,216 @@
+//! Routing of requests to handlers by path and method.
+//!
+//! A [`Router`] dispatches requests to the handler of the route matching
+//! their Uri-Path and method. Route paths consist of literal segments and
+//! parameters in braces, like `/sensors/{id}/value`; a parameter matches any
+//! single segment and is passed to the handler by name. Literal segments
+//! take precedence over parameters.
+//!
+//! The routes are kept in a trie with a node per path segment, so finding
+//! the route of a request takes time proportional to the length of its path
+//! rather than to the number of routes. Requests for paths without a route
+//! are answered with 4.04 Not Found, requests with a method the route has no
+//! handler for with 4.05 Method Not Allowed.
+use coap_lite::{CoapRequest, CoapResponse, RequestType as Method, ResponseType as Status};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+
+use super::payload::ResponseFuture;
+
+/// The values of the parameters of a route by their names.
+pub type Params = HashMap<String, String>;
+
+type Handler = Box<dyn FnMut(CoapRequest<SocketAddr>, Params) -> ResponseFuture + Send>;
+
+/// A route handler together with the names of the parameters of its path.
+struct Route {
+    params: Vec<String>,
+    handler: Handler,
+}
+
+/// A node of the trie, standing for the path up to a segment.
+#[derive(Default)]
+struct Node {
+    literals: HashMap<String, Node>,
+    param: Option<Box<Node>>,
+    /// The handlers of the routes ending here, as indices into the routes
+    /// of the router.
+    methods: Vec<(Method, usize)>,
+}
+
+impl Node {
+    /// Find the node of a path with a route, collecting the values of the
+    /// parameters on the way.
+    fn find(&self, segments: &[String], values: &mut Vec<String>) -> Option<&Node> {
+        let Some((segment, rest)) = segments.split_first() else {
+            return (!self.methods.is_empty()).then_some(self);
+        };
+        let literal = self.literals.get(segment);
+        if let Some(node) = literal.and_then(|node| node.find(rest, values)) {
+            return Some(node);
+        }
+        values.push(segment.clone());
+        let node = self.param.as_ref().and_then(|node| node.find(rest, values));
+        if node.is_none() {
+            values.pop();
+        }
+        node
+    }
+}
+
+/// Handlers by route.
+#[derive(Default)]
+pub struct Router {
+    root: Node,
+    routes: Vec<Route>,
+}
+
+impl Router {
+    /// Create a router without any routes.
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Serve requests with `method` for the paths matching `path` with
+    /// `handler`. A route added again for the same method replaces the
+    /// earlier one.
+    pub fn with_route<F, R>(mut self, method: Method, path: &str, mut handler: F) -> Router
+    where
+        F: FnMut(CoapRequest<SocketAddr>, Params) -> R + Send + 'static,
+        R: Future<Output = Option<CoapResponse>> + Send + 'static,
+    {
+        let mut node = &mut self.root;
+        let mut params = Vec::new();
+        for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+            node = match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name) => {
+                    params.push(name.to_string());
+                    node.param.get_or_insert_with(Box::default)
+                }
+                None => node.literals.entry(segment.to_string()).or_default(),
+            };
+        }
+        node.methods.retain(|(m, _)| *m != method);
+        node.methods.push((method, self.routes.len()));
+        self.routes.push(Route {
+            params,
+            handler: Box::new(move |request, params| Box::pin(handler(request, params))),
+        });
+        self
+    }
+
+    /// Pass `request` to the handler of its route, to be called from the
+    /// handler given to [`Server::run`](crate::Server::run).
+    pub fn handle(&mut self, mut request: CoapRequest<SocketAddr>) -> ResponseFuture {
+        let segments = request.get_path_as_vec().unwrap_or_default();
+        let mut values = Vec::new();
+        let status = match self.root.find(&segments, &mut values) {
+            Some(node) => {
+                let method = *request.get_method();
+                match node.methods.iter().find(|(m, _)| *m == method) {
+                    Some(&(_, index)) => {
+                        let route = &mut self.routes[index];
+                        let params = route.params.iter().cloned().zip(values).collect();
+                        return (route.handler)(request, params);
+                    }
+                    None => Status::MethodNotAllowed,
+                }
+            }
+            None => Status::NotFound,
+        };
+        if let Some(ref mut response) = request.response {
+            response.set_status(status);
+        }
+        Box::pin(async move { request.response })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use coap_lite::{MessageClass, Packet};
+
+    fn respond(
+        name: &'static str,
+    ) -> impl FnMut(CoapRequest<SocketAddr>, Params) -> ResponseFuture + Send + 'static {
+        move |request: CoapRequest<SocketAddr>, params: Params| {
+            Box::pin(async move {
+                let mut response = request.response?;
+                let mut params: Vec<_> = params.into_iter().collect();
+                params.sort();
+                let params: Vec<_> = params.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+                response.message.payload = format!("{} {}", name, params.join(",")).into_bytes();
+                Some(response)
+            })
+        }
+    }
+
+    fn send(router: &mut Router, method: Method, path: &str) -> (Status, String) {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(method);
+        let mut request = CoapRequest::from_packet(packet, "127.0.0.1:5683".parse().unwrap());
+        request.set_path(path);
+        let response = futures::executor::block_on(router.handle(request)).unwrap();
+        let payload = String::from_utf8(response.message.payload.clone()).unwrap();
+        (*response.get_status(), payload)
+    }
+
+    #[test]
+    fn test_routes() {
+        let mut router = Router::new()
+            .with_route(Method::Get, "/", respond("root"))
+            .with_route(Method::Get, "/sensors", respond("list"))
+            .with_route(Method::Get, "/sensors/{id}", respond("sensor"))
+            .with_route(Method::Put, "/sensors/{id}", respond("update"))
+            .with_route(Method::Get, "/sensors/all", respond("all"))
+            .with_route(Method::Get, "/sensors/all/count", respond("count"))
+            .with_route(Method::Get, "/sensors/{id}/{unit}", respond("value"));
+
+        let ok = |payload: &str| (Status::Content, payload.to_string());
+        assert_eq!(send(&mut router, Method::Get, "/"), ok("root "));
+        assert_eq!(send(&mut router, Method::Get, "/sensors"), ok("list "));
+        assert_eq!(
+            send(&mut router, Method::Get, "/sensors/7"),
+            ok("sensor id=7")
+        );
+        assert_eq!(
+            send(&mut router, Method::Put, "/sensors/7"),
+            ok("update id=7")
+        );
+        assert_eq!(send(&mut router, Method::Get, "/sensors/all"), ok("all "));
+        // the literal segment matches, but has no route for the rest
+        assert_eq!(
+            send(&mut router, Method::Get, "/sensors/all/celsius"),
+            ok("value id=all,unit=celsius")
+        );
+        assert_eq!(
+            send(&mut router, Method::Get, "/sensors/all/count"),
+            ok("count ")
+        );
+
+        assert_eq!(
+            send(&mut router, Method::Get, "/lights").0,
+            Status::NotFound
+        );
+        assert_eq!(
+            send(&mut router, Method::Get, "/sensors/7/c/x").0,
+            Status::NotFound
+        );
+        assert_eq!(
+            send(&mut router, Method::Delete, "/sensors/7").0,
+            Status::MethodNotAllowed
+        );
+    }
+
+    #[test]
+    fn test_replace_route() {
+        let mut router = Router::new()
+            .with_route(Method::Get, "/{a}", respond("old"))
+            .with_route(Method::Get, "/{b}", respond("new"));
+        assert_eq!(
+            send(&mut router, Method::Get, "/x"),
+            (Status::Content, "new b=x".to_string())
+        );
+    }
+}
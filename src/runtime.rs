@@ -0,0 +1,111 @@
+//! Runtime hooks of the server.
+//!
+//! Apart from its socket, the server only needs a periodic timer, which
+//! drives observe retransmissions; it never spawns tasks. Both come from a [`Runtime`], so the
+//! server can run on any executor: [`TokioRuntime`] is the default and
+//! [`StdRuntime`] is built on plain threads for executors other than Tokio,
+//! e.g. async-std or smol. Other runtimes can implement the trait
+//! themselves and pass it to [`Server::with_runtime`](crate::Server::with_runtime).
+use futures::{channel::mpsc, stream::BoxStream, StreamExt};
+use std::{io::Result, net::SocketAddr, thread, time::Duration};
+
+use super::transport::{ThreadedUdpTransport, Transport, UdpTransport};
+
+/// Timer and socket provider of a server.
+pub trait Runtime {
+    /// Return a stream yielding every `period`, starting after the first
+    /// period has elapsed.
+    fn interval(&self, period: Duration) -> BoxStream<'static, ()>;
+
+    /// Bind a UDP transport to the given address.
+    fn bind_udp(&self, addr: SocketAddr) -> Result<Box<dyn Transport>>;
+}
+
+/// The Tokio runtime. Servers using it must be created from within a Tokio
+/// runtime context.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRuntime;
+
+impl Runtime for TokioRuntime {
+    fn interval(&self, period: Duration) -> BoxStream<'static, ()> {
+        let start = tokio::time::Instant::now() + period;
+        let interval = tokio::time::interval_at(start, period);
+        tokio_stream::wrappers::IntervalStream::new(interval)
+            .map(|_| ())
+            .boxed()
+    }
+
+    fn bind_udp(&self, addr: SocketAddr) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(UdpTransport::bind(addr)?))
+    }
+}
+
+/// A runtime that does not depend on any executor. Timers run on their own
+/// thread and sockets are read by a blocking thread.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdRuntime;
+
+impl Runtime for StdRuntime {
+    fn interval(&self, period: Duration) -> BoxStream<'static, ()> {
+        let (tx, rx) = mpsc::unbounded();
+        thread::spawn(move || loop {
+            thread::sleep(period);
+            // the stream has been dropped
+            if tx.unbounded_send(()).is_err() {
+                break;
+            }
+        });
+        rx.boxed()
+    }
+
+    fn bind_udp(&self, addr: SocketAddr) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(ThreadedUdpTransport::bind(addr)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
+    use futures::executor::block_on;
+    use std::time::Instant;
+
+    #[test]
+    fn test_std_interval() {
+        let start = Instant::now();
+        let mut ticks = StdRuntime.interval(Duration::from_millis(50));
+        block_on(async {
+            ticks.next().await.unwrap();
+            ticks.next().await.unwrap();
+        });
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_std_runtime() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            // no Tokio runtime is running on this thread
+            block_on(async move {
+                let mut server = Server::with_runtime("127.0.0.1:0", StdRuntime).unwrap();
+                tx.send(server.socket_addr().unwrap()).unwrap();
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        let mut response = req.response?;
+                        response.message.payload = b"pong".to_vec();
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let mut client = CoAPClient::new(server_addr).unwrap();
+        let response = client
+            .request_path("/ping", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload, b"pong".to_vec());
+    }
+}
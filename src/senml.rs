@@ -0,0 +1,604 @@
+//! Sensor Measurement Lists
+//! ([RFC 8428](https://tools.ietf.org/html/rfc8428)).
+//!
+//! A SenML pack is a list of [`Record`]s, encoded in JSON with the labels of
+//! the RFC (`bn`, `v`, ...) and in CBOR with their integer keys. Base fields
+//! apply to the records that follow them; [`resolve`] folds them into every
+//! record. [`Pack`] builds the measurements of a response:
+//!
+//! ```
+//! use coap::payload::Format;
+//! use coap::senml::{self, Pack};
+//! use coap_lite::Packet;
+//!
+//! let mut response = Packet::new();
+//! let pack = Pack::new("urn:dev:ow:10e2073a01080063:")
+//!     .with_base_time(1_320_067_464.0)
+//!     .with_value("temp", "Cel", 23.1)
+//!     .with_value("humidity", "%RH", 42.0);
+//! pack.write(&mut response, Format::Json).unwrap();
+//! let records = senml::resolve(&senml::read(&response).unwrap()).unwrap();
+//! assert_eq!(records[1].name.as_deref(), Some("urn:dev:ow:10e2073a01080063:humidity"));
+//! ```
+use ciborium::value::Value as CborValue;
+use coap_lite::Packet;
+use serde_json::{Map, Value as JsonValue};
+
+use super::content_format::{self, ContentFormat};
+use super::payload::{self, Format, PayloadError};
+
+/// The SenML version this module implements.
+pub const VERSION: u64 = 10;
+
+/// Times below 2**28 are relative to the current time.
+pub const RELATIVE_TIME_LIMIT: f64 = 268_435_456.0;
+
+/// The value of a record.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Data(Vec<u8>),
+}
+
+/// A SenML record. All fields are optional; a resolved record has a name
+/// and no base fields.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Record {
+    pub base_name: Option<String>,
+    pub base_time: Option<f64>,
+    pub base_unit: Option<String>,
+    pub base_value: Option<f64>,
+    pub base_sum: Option<f64>,
+    pub base_version: Option<u64>,
+    pub name: Option<String>,
+    pub unit: Option<String>,
+    pub value: Option<Value>,
+    pub sum: Option<f64>,
+    pub time: Option<f64>,
+    pub update_time: Option<f64>,
+}
+
+impl Record {
+    /// Create a record of a numeric measurement.
+    pub fn measurement(name: &str, unit: &str, value: f64) -> Record {
+        Record {
+            name: Some(name.to_string()),
+            unit: Some(unit.to_string()),
+            value: Some(Value::Number(value)),
+            ..Record::default()
+        }
+    }
+
+    /// Return the time of a resolved record as seconds since the epoch,
+    /// resolving a relative time against `now`.
+    pub fn absolute_time(&self, now: f64) -> f64 {
+        match self.time {
+            Some(time) if time >= RELATIVE_TIME_LIMIT => time,
+            Some(time) => now + time,
+            None => now,
+        }
+    }
+}
+
+/// The value of a field of a record, whatever the encoding.
+enum Field {
+    Text(String),
+    Number(f64),
+    Uint(u64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+}
+
+/// The JSON labels and CBOR keys of the fields, by index.
+const LABELS: [(&str, i64); 15] = [
+    ("bn", -2),
+    ("bt", -3),
+    ("bu", -4),
+    ("bv", -5),
+    ("bs", -6),
+    ("bver", -1),
+    ("n", 0),
+    ("u", 1),
+    ("v", 2),
+    ("vs", 3),
+    ("vb", 4),
+    ("vd", 8),
+    ("s", 5),
+    ("t", 6),
+    ("ut", 7),
+];
+
+fn fields(record: &Record) -> Vec<(usize, Field)> {
+    let mut fields = Vec::new();
+    let text = |value: &Option<String>| value.clone().map(Field::Text);
+    let number = |value: &Option<f64>| value.map(Field::Number);
+    let value = |kind: usize| match (&record.value, kind) {
+        (Some(Value::Number(value)), 0) => Some(Field::Number(*value)),
+        (Some(Value::String(value)), 1) => Some(Field::Text(value.clone())),
+        (Some(Value::Bool(value)), 2) => Some(Field::Bool(*value)),
+        (Some(Value::Data(value)), 3) => Some(Field::Bytes(value.clone())),
+        _ => None,
+    };
+    let values = [
+        text(&record.base_name),
+        number(&record.base_time),
+        text(&record.base_unit),
+        number(&record.base_value),
+        number(&record.base_sum),
+        record.base_version.map(Field::Uint),
+        text(&record.name),
+        text(&record.unit),
+        value(0),
+        value(1),
+        value(2),
+        value(3),
+        number(&record.sum),
+        number(&record.time),
+        number(&record.update_time),
+    ];
+    for (index, field) in values.into_iter().enumerate() {
+        if let Some(field) = field {
+            fields.push((index, field));
+        }
+    }
+    fields
+}
+
+fn record(fields: Vec<(usize, Field)>) -> Result<Record, PayloadError> {
+    let mut record = Record::default();
+    for (index, field) in fields {
+        let label = LABELS[index].0;
+        let invalid = || PayloadError::Invalid(format!("invalid SenML field {}", label));
+        let text = |field: Field| match field {
+            Field::Text(text) => Ok(Some(text)),
+            _ => Err(invalid()),
+        };
+        let number = |field: Field| match field {
+            Field::Number(number) => Ok(Some(number)),
+            Field::Uint(number) => Ok(Some(number as f64)),
+            _ => Err(invalid()),
+        };
+        match index {
+            0 => record.base_name = text(field)?,
+            1 => record.base_time = number(field)?,
+            2 => record.base_unit = text(field)?,
+            3 => record.base_value = number(field)?,
+            4 => record.base_sum = number(field)?,
+            5 => match field {
+                Field::Uint(version) => record.base_version = Some(version),
+                _ => return Err(invalid()),
+            },
+            6 => record.name = text(field)?,
+            7 => record.unit = text(field)?,
+            8 => record.value = number(field)?.map(Value::Number),
+            9 => record.value = text(field)?.map(Value::String),
+            10 => match field {
+                Field::Bool(value) => record.value = Some(Value::Bool(value)),
+                _ => return Err(invalid()),
+            },
+            11 => match field {
+                Field::Bytes(value) => record.value = Some(Value::Data(value)),
+                _ => return Err(invalid()),
+            },
+            12 => record.sum = number(field)?,
+            13 => record.time = number(field)?,
+            _ => record.update_time = number(field)?,
+        }
+    }
+    Ok(record)
+}
+
+/// Encode a pack in SenML JSON.
+pub fn to_json(records: &[Record]) -> Result<Vec<u8>, PayloadError> {
+    let records: Vec<JsonValue> = records
+        .iter()
+        .map(|record| {
+            let mut map = Map::new();
+            for (index, field) in fields(record) {
+                let value = match field {
+                    Field::Text(text) => JsonValue::from(text),
+                    Field::Number(number) => JsonValue::from(number),
+                    Field::Uint(number) => JsonValue::from(number),
+                    Field::Bool(value) => JsonValue::from(value),
+                    Field::Bytes(data) => JsonValue::from(base64url_encode(&data)),
+                };
+                map.insert(LABELS[index].0.to_string(), value);
+            }
+            JsonValue::Object(map)
+        })
+        .collect();
+    serde_json::to_vec(&records).map_err(PayloadError::Json)
+}
+
+/// Decode a pack in SenML JSON. Unknown fields are ignored.
+pub fn from_json(payload: &[u8]) -> Result<Vec<Record>, PayloadError> {
+    let records: Vec<Map<String, JsonValue>> =
+        serde_json::from_slice(payload).map_err(PayloadError::Json)?;
+    records
+        .into_iter()
+        .map(|map| {
+            let mut fields = Vec::new();
+            for (label, value) in map {
+                let index = match LABELS.iter().position(|(known, _)| *known == label) {
+                    Some(index) => index,
+                    None => continue,
+                };
+                let field = match value {
+                    JsonValue::String(text) if label == "vd" => {
+                        Field::Bytes(base64url_decode(&text).ok_or_else(|| {
+                            PayloadError::Invalid("invalid SenML field vd".to_string())
+                        })?)
+                    }
+                    JsonValue::String(text) => Field::Text(text),
+                    JsonValue::Bool(value) => Field::Bool(value),
+                    JsonValue::Number(number) => match number.as_u64() {
+                        Some(number) if label == "bver" => Field::Uint(number),
+                        _ => Field::Number(number.as_f64().unwrap_or(f64::NAN)),
+                    },
+                    _ => {
+                        let error = format!("invalid SenML field {}", label);
+                        return Err(PayloadError::Invalid(error));
+                    }
+                };
+                fields.push((index, field));
+            }
+            record(fields)
+        })
+        .collect()
+}
+
+/// Encode a pack in SenML CBOR.
+pub fn to_cbor(records: &[Record]) -> Result<Vec<u8>, PayloadError> {
+    let records: Vec<CborValue> = records
+        .iter()
+        .map(|record| {
+            let map = fields(record)
+                .into_iter()
+                .map(|(index, field)| {
+                    let value = match field {
+                        Field::Text(text) => CborValue::Text(text),
+                        Field::Number(number) => CborValue::Float(number),
+                        Field::Uint(number) => CborValue::Integer(number.into()),
+                        Field::Bool(value) => CborValue::Bool(value),
+                        Field::Bytes(data) => CborValue::Bytes(data),
+                    };
+                    (CborValue::Integer(LABELS[index].1.into()), value)
+                })
+                .collect();
+            CborValue::Map(map)
+        })
+        .collect();
+    payload::encode(&CborValue::Array(records), Format::Cbor)
+}
+
+/// Decode a pack in SenML CBOR. Unknown fields are ignored.
+pub fn from_cbor(payload: &[u8]) -> Result<Vec<Record>, PayloadError> {
+    let invalid = || PayloadError::Invalid("invalid SenML pack".to_string());
+    let records = match payload::decode::<CborValue>(payload, Format::Cbor)? {
+        CborValue::Array(records) => records,
+        _ => return Err(invalid()),
+    };
+    records
+        .into_iter()
+        .map(|map| {
+            let map = match map {
+                CborValue::Map(map) => map,
+                _ => return Err(invalid()),
+            };
+            let mut fields = Vec::new();
+            for (key, value) in map {
+                let key = match key.as_integer() {
+                    Some(key) => i128::from(key),
+                    None => continue,
+                };
+                let index = match LABELS
+                    .iter()
+                    .position(|(_, known)| i128::from(*known) == key)
+                {
+                    Some(index) => index,
+                    None => continue,
+                };
+                let field = match value {
+                    CborValue::Text(text) => Field::Text(text),
+                    CborValue::Float(number) => Field::Number(number),
+                    CborValue::Integer(number) => match u64::try_from(number) {
+                        Ok(number) if index == 5 => Field::Uint(number),
+                        _ => Field::Number(i128::from(number) as f64),
+                    },
+                    CborValue::Bool(value) => Field::Bool(value),
+                    CborValue::Bytes(data) => Field::Bytes(data),
+                    _ => return Err(invalid()),
+                };
+                fields.push((index, field));
+            }
+            record(fields)
+        })
+        .collect()
+}
+
+/// Fold the base fields into the records that follow them, as in the
+/// resolved records of RFC 8428 section 4.6, and check the names and the
+/// version. Relative times stay relative, see [`Record::absolute_time`].
+pub fn resolve(records: &[Record]) -> Result<Vec<Record>, PayloadError> {
+    let mut base = Record::default();
+    let mut resolved = Vec::with_capacity(records.len());
+    for record in records {
+        if let Some(version) = record.base_version {
+            if version > VERSION {
+                let error = format!("unsupported SenML version {}", version);
+                return Err(PayloadError::Invalid(error));
+            }
+        }
+        if record.base_name.is_some() {
+            base.base_name = record.base_name.clone();
+        }
+        if record.base_time.is_some() {
+            base.base_time = record.base_time;
+        }
+        if record.base_unit.is_some() {
+            base.base_unit = record.base_unit.clone();
+        }
+        if record.base_value.is_some() {
+            base.base_value = record.base_value;
+        }
+        if record.base_sum.is_some() {
+            base.base_sum = record.base_sum;
+        }
+
+        let name = format!(
+            "{}{}",
+            base.base_name.as_deref().unwrap_or(""),
+            record.name.as_deref().unwrap_or("")
+        );
+        if !is_valid_name(&name) {
+            return Err(PayloadError::Invalid(format!(
+                "invalid SenML name {:?}",
+                name
+            )));
+        }
+        let value = match (&record.value, base.base_value) {
+            (Some(Value::Number(value)), Some(base)) => Some(Value::Number(base + value)),
+            (value, _) => value.clone(),
+        };
+        let sum = match (record.sum, base.base_sum) {
+            (Some(sum), Some(base)) => Some(base + sum),
+            (sum, _) => sum,
+        };
+        resolved.push(Record {
+            name: Some(name),
+            unit: record.unit.clone().or_else(|| base.base_unit.clone()),
+            value,
+            sum,
+            time: Some(base.base_time.unwrap_or(0.0) + record.time.unwrap_or(0.0)),
+            update_time: record.update_time,
+            ..Record::default()
+        });
+    }
+    Ok(resolved)
+}
+
+/// Names start with a letter or digit and consist of letters, digits and
+/// `-:./_`.
+fn is_valid_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-:./_".contains(c))
+}
+
+/// Encode a pack into the payload of a message in SenML JSON or CBOR and
+/// set its Content-Format.
+pub fn write(message: &mut Packet, records: &[Record], format: Format) -> Result<(), PayloadError> {
+    let (payload, content_format) = match format {
+        Format::Json => (to_json(records)?, ContentFormat::SenmlJson),
+        Format::Cbor => (to_cbor(records)?, ContentFormat::SenmlCbor),
+    };
+    message.payload = payload;
+    content_format::set(message, content_format);
+    Ok(())
+}
+
+/// Decode the pack in the payload of a message according to its
+/// Content-Format.
+pub fn read(message: &Packet) -> Result<Vec<Record>, PayloadError> {
+    match content_format::get(message) {
+        Some(ContentFormat::SenmlJson) => from_json(&message.payload),
+        Some(ContentFormat::SenmlCbor) => from_cbor(&message.payload),
+        format => Err(PayloadError::UnsupportedContentFormat(format.map(u16::from))),
+    }
+}
+
+/// Builds the records of a response, all named below a base name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Pack {
+    records: Vec<Record>,
+    base: Record,
+}
+
+impl Pack {
+    /// Create a pack whose record names are relative to `base_name`.
+    pub fn new(base_name: &str) -> Pack {
+        Pack {
+            records: Vec::new(),
+            base: Record {
+                base_name: Some(base_name.to_string()),
+                ..Record::default()
+            },
+        }
+    }
+
+    /// Set the base time, in seconds since the epoch, of the records.
+    pub fn with_base_time(mut self, time: f64) -> Pack {
+        self.base.base_time = Some(time);
+        self
+    }
+
+    /// Add a numeric measurement.
+    pub fn with_value(self, name: &str, unit: &str, value: f64) -> Pack {
+        self.with_record(Record::measurement(name, unit, value))
+    }
+
+    /// Add any record.
+    pub fn with_record(mut self, record: Record) -> Pack {
+        self.records.push(record);
+        self
+    }
+
+    /// Return the records, the base fields carried by the first one.
+    pub fn records(&self) -> Vec<Record> {
+        let mut records = self.records.clone();
+        match records.first_mut() {
+            Some(first) => {
+                first.base_name = self.base.base_name.clone();
+                first.base_time = self.base.base_time;
+            }
+            None => records.push(self.base.clone()),
+        }
+        records
+    }
+
+    /// Encode the pack into the payload of a message, see [`write`].
+    pub fn write(&self, message: &mut Packet, format: Format) -> Result<(), PayloadError> {
+        write(message, &self.records(), format)
+    }
+}
+
+const BASE64URL: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+/// Encode data in base64url without padding, as `vd` is in JSON.
+fn base64url_encode(data: &[u8]) -> String {
+    let mut text = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, byte)| {
+            bits | u32::from(*byte) << (16 - 8 * i)
+        });
+        for i in 0..=chunk.len() {
+            text.push(BASE64URL[(bits >> (18 - 6 * i)) as usize & 0x3f] as char);
+        }
+    }
+    text
+}
+
+fn base64url_decode(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=');
+    let mut data = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.as_bytes().chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut bits = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            let value = BASE64URL.iter().position(|known| known == c)? as u32;
+            bits |= value << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            data.push((bits >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // RFC 8428 section 5.1.2
+    const MULTIPLE: &str = r#"[
+        {"bn":"urn:dev:ow:10e2073a01080063:","n":"voltage","u":"V","v":120.1},
+        {"n":"current","u":"A","v":1.2}
+    ]"#;
+
+    #[test]
+    fn test_json() {
+        let records = from_json(MULTIPLE.as_bytes()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1], Record::measurement("current", "A", 1.2));
+        assert_eq!(from_json(&to_json(&records).unwrap()).unwrap(), records);
+
+        let data = Record {
+            name: Some("blob".to_string()),
+            value: Some(Value::Data(vec![0xfb, 0xff, 0x01, 0x02])),
+            ..Record::default()
+        };
+        let json = to_json(std::slice::from_ref(&data)).unwrap();
+        assert_eq!(json, br#"[{"n":"blob","vd":"-_8BAg"}]"#);
+        assert_eq!(from_json(&json).unwrap(), vec![data]);
+        assert!(from_json(br#"[{"n":"blob","vd":"A"}]"#).is_err());
+    }
+
+    #[test]
+    fn test_cbor() {
+        let records = from_json(MULTIPLE.as_bytes()).unwrap();
+        let cbor = to_cbor(&records).unwrap();
+        assert_eq!(from_cbor(&cbor).unwrap(), records);
+        // the first record maps -2 (bn) to a text string
+        assert_eq!(&cbor[..3], [0x82, 0xa4, 0x21]);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let records = vec![
+            Record {
+                base_name: Some("urn:dev:ow:10e2073a0108006:".to_string()),
+                base_time: Some(1.276020076001e9),
+                base_unit: Some("A".to_string()),
+                base_value: Some(1.0),
+                base_version: Some(5),
+                name: Some("current".to_string()),
+                time: Some(-5.0),
+                value: Some(Value::Number(0.2)),
+                ..Record::default()
+            },
+            Record {
+                name: Some("voltage".to_string()),
+                unit: Some("V".to_string()),
+                value: Some(Value::Number(120.1)),
+                ..Record::default()
+            },
+        ];
+        let resolved = resolve(&records).unwrap();
+        assert_eq!(
+            resolved[0],
+            Record {
+                name: Some("urn:dev:ow:10e2073a0108006:current".to_string()),
+                unit: Some("A".to_string()),
+                value: Some(Value::Number(1.2)),
+                time: Some(1.276020071001e9),
+                ..Record::default()
+            }
+        );
+        assert_eq!(resolved[1].unit.as_deref(), Some("V"));
+        assert_eq!(resolved[1].value, Some(Value::Number(121.1)));
+
+        let invalid = [Record::measurement("-temp", "Cel", 1.0)];
+        assert!(resolve(&invalid).is_err());
+        let future = [Record {
+            base_version: Some(11),
+            ..Record::measurement("temp", "Cel", 1.0)
+        }];
+        assert!(resolve(&future).is_err());
+    }
+
+    #[test]
+    fn test_pack() {
+        let pack = Pack::new("dev/").with_value("temp", "Cel", 23.5);
+        for format in [Format::Json, Format::Cbor] {
+            let mut message = Packet::new();
+            pack.write(&mut message, format).unwrap();
+            let records = read(&message).unwrap();
+            assert_eq!(records, pack.records());
+            assert_eq!(
+                resolve(&records).unwrap()[0].name.as_deref(),
+                Some("dev/temp")
+            );
+        }
+        let mut message = Packet::new();
+        payload::write(&mut message, &1, Format::Json).unwrap();
+        assert!(matches!(
+            read(&message),
+            Err(PayloadError::UnsupportedContentFormat(Some(50)))
+        ));
+    }
+}
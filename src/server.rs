@@ -1,26 +1,71 @@
-use coap_lite::{CoapRequest, CoapResponse, Packet, BlockHandler, BlockHandlerConfig, error::HandlingError};
-use futures::{select, stream::FusedStream, task::Poll, SinkExt, Stream, StreamExt};
+use coap_lite::{
+    CoapOption, CoapRequest, CoapResponse, ContentFormat, MessageType, Packet,
+    RequestType as Method, ResponseType as Status, BlockHandler, BlockHandlerConfig, MessageClass,
+    error::HandlingError, block_handler::BlockValue, option_value::OptionValueU32,
+};
+use futures::{
+    future::{self, Either},
+    select,
+    stream::{Fuse, FusedStream, FuturesUnordered},
+    task::Poll,
+    FutureExt, Stream, StreamExt,
+};
 use log::{debug, error};
+use lru_time_cache::LruCache;
 use std::{
     self,
+    collections::{BTreeMap, HashMap},
     future::Future,
-    net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    marker::PhantomData,
+    mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
     pin::Pin,
+    sync::Arc,
     task::Context,
+    time::{Duration, Instant},
 };
-use tokio::{
-    io,
-    net::UdpSocket,
-    sync::mpsc::{self},
-};
-use tokio_stream::wrappers::UnboundedReceiverStream;
-use tokio_util::udp::UdpFramed;
+use tokio::{io, task::futures::TaskLocalFuture};
+
+use super::acl::Acl;
+use super::audit::{self, SecurityEvent, SecurityEventHandler};
+use super::cache::ResponseCache;
+use super::content_format;
+use super::echo::{self, EchoPolicy, RequestTags};
+use super::etag;
+use super::group::{self, Groups, Membership, ResponsePolicy};
+use super::link_format::{self, Link};
+use super::message::{self, Signal};
+use super::mtu::{self, PathMtu, MIN_BLOCK_SIZE};
+use super::no_response;
+use super::observer::{ObserveRegistry, Observer};
+use super::options::{OptionDefinition, OptionRegistry};
+use super::outbound::{Outbound, OutboundStats, OverflowPolicy};
+use super::payload::{self, ResponseFuture};
+use super::proxy::{self, ForwardProxy};
+use super::pubsub::{Broker, Handling};
+use super::qblock::{self, Transfers};
+use super::runtime::{Runtime, TokioRuntime};
+use super::throttle;
+use super::transport::{tcp, PeerIdentity, Transport, UdpTransport};
+
+tokio::task_local! {
+    static INGRESS: usize;
+    static IDENTITY: PeerIdentity;
+}
 
-use super::message::Codec;
-use super::observer::Observer;
+/// Return the index of the transport the request being handled arrived on,
+/// as returned by [`Server::add_transport`]. Only available inside the
+/// handler passed to [`Server::run`].
+pub fn ingress() -> Option<usize> {
+    INGRESS.try_with(|ingress| *ingress).ok()
+}
 
-pub type MessageSender = mpsc::UnboundedSender<(Packet, SocketAddr)>;
-type MessageReceiver = UnboundedReceiverStream<(Packet, SocketAddr)>;
+/// Return the identity the client of the request being handled
+/// authenticated with, whatever transport it arrived on. Only available
+/// inside the handler passed to [`Server::run`].
+pub fn peer_identity() -> Option<PeerIdentity> {
+    IDENTITY.try_with(|identity| identity.clone()).ok()
+}
 
 #[derive(Debug)]
 pub enum CoAPServerError {
@@ -37,8 +82,10 @@ pub struct QueuedMessage {
 }
 
 pub enum Message {
-    NeedSend(Packet, SocketAddr),
     Received(Packet, SocketAddr),
+    /// A signaling message that the transport passed on instead of handling
+    /// it itself.
+    Signaling(Signal, Packet, SocketAddr),
 }
 
 pub struct Server<'a, HandlerRet>
@@ -47,8 +94,30 @@ where
 {
     server: CoAPServer,
     observer: Observer,
-    block_handler: BlockHandler<SocketAddr>,
-    handler: Option<Box<dyn FnMut(CoapRequest<SocketAddr>) -> HandlerRet + Send + 'a>>,
+    /// Block handlers by the maximum message size they produce, one for
+    /// every path MTU in use.
+    block_handlers: HashMap<usize, BlockHandler<SocketAddr>>,
+    block_transfer_lifetime: Duration,
+    transfer_memory_budget: Option<usize>,
+    max_request_size: Option<usize>,
+    max_concurrent_handlers: usize,
+    handler_overflow: HandlerOverflow,
+    path_mtu: PathMtu,
+    links: Vec<Link>,
+    /// The serialized `/.well-known/core` documents by the queries that
+    /// filtered them, dropped when a link is added.
+    discovery: LruCache<Vec<Vec<u8>>, Vec<u8>>,
+    options: OptionRegistry,
+    acl: Option<Acl>,
+    echo_policy: Option<EchoPolicy>,
+    request_tags: RequestTags,
+    q_blocks: Transfers,
+    groups: Groups,
+    broker: Broker,
+    proxy_handler: Option<Box<dyn FnMut(CoapRequest<SocketAddr>) -> ResponseFuture + Send + 'a>>,
+    response_cache: Option<ResponseCache>,
+    /// The future of the handler, which `run` takes as a type parameter.
+    handler: PhantomData<fn() -> HandlerRet>,
 }
 
 impl<'a, HandlerRet> Server<'a, HandlerRet>
@@ -57,40 +126,135 @@ where
 {
     /// Creates a CoAP server listening on the given address.
     pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Self, io::Error> {
-        let (tx, rx) = mpsc::unbounded_channel();
-        Ok(Server {
-            server: CoAPServer::new(addr, rx)?,
-            observer: Observer::new(tx),
-            block_handler: BlockHandler::new(BlockHandlerConfig::default()),
-            handler: None,
-        })
+        Ok(Self::from_transport(UdpTransport::bind(addr)?))
+    }
+
+    /// Creates a CoAP server on top of an arbitrary transport.
+    pub fn from_transport<T: Transport + 'static>(transport: T) -> Self {
+        Self::from_transport_with_runtime(transport, &TokioRuntime)
+    }
+
+    /// Creates a CoAP server listening on the given address, with the socket
+    /// and timers provided by `runtime` instead of Tokio.
+    pub fn with_runtime<A: ToSocketAddrs, R: Runtime>(
+        addr: A,
+        runtime: R,
+    ) -> Result<Self, io::Error> {
+        let mut last_error = None;
+        for addr in addr.to_socket_addrs()? {
+            match runtime.bind_udp(addr) {
+                Ok(transport) => return Ok(Self::from_boxed(transport, &runtime)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no addresses to bind to")
+        }))
+    }
+
+    /// Creates a CoAP server on top of an arbitrary transport, with timers
+    /// provided by `runtime`.
+    pub fn from_transport_with_runtime<T: Transport + 'static>(
+        transport: T,
+        runtime: &dyn Runtime,
+    ) -> Self {
+        Self::from_boxed(Box::new(transport), runtime)
+    }
+
+    fn from_boxed(transport: Box<dyn Transport>, runtime: &dyn Runtime) -> Self {
+        let mut server = Server {
+            server: CoAPServer::from_boxed(transport),
+            observer: Observer::with_runtime(runtime),
+            block_handlers: HashMap::new(),
+            block_transfer_lifetime: DEFAULT_BLOCK_TRANSFER_LIFETIME,
+            transfer_memory_budget: None,
+            max_request_size: Some(DEFAULT_MAX_REQUEST_SIZE),
+            max_concurrent_handlers: 1,
+            handler_overflow: HandlerOverflow::default(),
+            path_mtu: PathMtu::new(),
+            links: Vec::new(),
+            discovery: LruCache::with_capacity(DISCOVERY_CAPACITY),
+            options: OptionRegistry::new(),
+            acl: None,
+            echo_policy: None,
+            request_tags: RequestTags::new(DEFAULT_BLOCK_TRANSFER_LIFETIME),
+            q_blocks: Transfers::new(DEFAULT_BLOCK_TRANSFER_LIFETIME),
+            groups: Groups::new(),
+            broker: Broker::new(),
+            proxy_handler: None,
+            response_cache: None,
+            handler: PhantomData,
+        };
+        server.set_transfer_memory_budget(Some(DEFAULT_TRANSFER_MEMORY_BUDGET));
+        server
     }
 
     /// run the server.
+    ///
+    /// The handler gets requests with any of the methods of RFC 7252 and
+    /// RFC 8132, i.e. also FETCH, PATCH and iPATCH, and should answer 4.05
+    /// Method Not Allowed for those a resource does not support. Requests
+    /// with other method codes are answered with 4.05 by the server.
+    ///
+    /// A 2.05 Content response carrying an ETag, e.g. set with
+    /// [`etag::tag`](crate::etag::tag), is turned into 2.03 Valid without
+    /// payload when the GET or FETCH request listed that ETag.
+    ///
+    /// The handler is called without boxing or dynamic dispatch. Handlers
+    /// chosen at runtime can still be passed as a boxed trait object, as
+    /// `Box<dyn FnMut(CoapRequest<SocketAddr>) -> HandlerRet + Send>` is a
+    /// handler itself.
     pub async fn run<F: FnMut(CoapRequest<SocketAddr>) -> HandlerRet + Send + 'a>(
         &mut self,
-        handler: F,
+        mut handler: F,
     ) -> Result<(), io::Error> {
-        self.handler = Some(Box::new(handler));
-
+        let registry = self.observer.registry();
+        // the handlers running at once, polled along with the transports
+        let mut handling = FuturesUnordered::new();
         loop {
+            let full = handling.len() >= self.max_concurrent_handlers;
+            // while all handlers are busy, requests wait in the transports
+            let paused = full && self.handler_overflow == HandlerOverflow::Queue;
             select! {
-                message = self.server.select_next_some() => {
+                message = match paused {
+                    true => Either::Left(future::pending()),
+                    false => Either::Right(self.server.select_next_some()),
+                } => {
                     match message {
-                        Ok(Message::NeedSend(packet, addr)) => {
-                            self.send_msg(packet, addr).await?;
-                        }
                         Ok(Message::Received(packet, addr)) => {
-                            self.dispatch_msg(packet, addr).await?;
+                            let handled = self.dispatch_msg(&mut handler, packet, addr, full);
+                            if let Some((pending, response)) = handled.await? {
+                                // awaited in place if no other request may
+                                // run meanwhile anyway
+                                let inline = self.max_concurrent_handlers == 1
+                                    && self.handler_overflow == HandlerOverflow::Queue;
+                                if inline {
+                                    let response = response.await;
+                                    self.finish_request(pending, response).await?;
+                                } else {
+                                    handling.push(async move { (pending, response.await) });
+                                }
+                            }
+                        }
+                        Ok(Message::Signaling(signal, packet, addr)) => {
+                            self.handle_signaling(signal, packet, addr).await?;
                         }
                         Err(e) => {
                             error!("select error: {:?}", e);
                         }
                     }
                 }
+                (pending, response) = handling.select_next_some() => {
+                    self.finish_request(pending, response).await?;
+                }
                 _ = self.observer.select_next_some() => {
                     self.observer.timer_handler().await;
                 }
+                batches = registry.notifications().fuse() => {
+                    for (addr, packets) in batches {
+                        self.send_notifications(packets, addr).await?;
+                    }
+                }
                 complete => break,
             }
         }
@@ -103,75 +267,865 @@ where
         self.server.socket_addr()
     }
 
-    async fn send_msg(&mut self, packet: Packet, addr: SocketAddr) -> Result<(), io::Error> {
+    /// Advertise a resource in `/.well-known/core`. Once a link has been added
+    /// the server answers discovery requests itself instead of passing them
+    /// to the handler.
+    pub fn add_link(&mut self, link: Link) {
+        self.links.push(link);
+        // serialized here rather than for every discovery request
+        self.discovery.clear();
+        let document = link_format::serialize(&self.links).into_bytes();
+        self.discovery.insert(Vec::new(), document);
+    }
+
+    /// Declare the application-defined option `number`, so that requests
+    /// with it pass even if it is critical, and invalid values of it are
+    /// rejected. See [`options`](crate::options).
+    pub fn register_option(&mut self, number: u16, definition: OptionDefinition) {
+        self.options.register(number, definition);
+    }
+
+    /// Also accept requests on `transport`, e.g. on TCP next to UDP, sharing
+    /// the handler, the observers and the links. Returns the index that
+    /// [`ingress`] reports for requests arriving on it; the transport the
+    /// server was created with has index 0. Responses and notifications go
+    /// out on the transport the peer last sent a message on.
+    pub fn add_transport<T: Transport + 'static>(&mut self, transport: T) -> usize {
+        self.server.add_transport(transport)
+    }
+
+    /// Only pass on requests that `acl` allows, or all with `None`. Others
+    /// are answered with 4.01 Unauthorized or 4.03 Forbidden before they
+    /// reach the handler or a block-wise transfer starts.
+    pub fn set_acl(&mut self, acl: Option<Acl>) {
+        self.acl = acl;
+    }
+
+    /// Report failed handshakes, suspected replays and access control
+    /// denials to `handler`. See [`audit`](crate::audit).
+    pub fn set_security_event_handler<F>(&mut self, handler: F)
+    where
+        F: Fn(&SecurityEvent) + Send + Sync + 'static,
+    {
+        self.server.set_security_event_handler(Arc::new(handler));
+    }
+
+    /// Challenge requests with Echo values as `policy` requires, or none
+    /// with `None`. See [`echo`](crate::echo).
+    pub fn set_echo_policy(&mut self, policy: Option<EchoPolicy>) {
+        self.echo_policy = policy;
+    }
+
+    /// Send at most `factor` times the bytes received from a client to it
+    /// until its address is verified, or lift the limit with `None`, the
+    /// default. Addresses are verified by returning an Echo value, so with
+    /// an Echo policy set, responses that would exceed the limit are
+    /// replaced with a 4.01 Echo challenge. Clients of transports with a
+    /// handshake, like DTLS or TCP, are not limited.
+    pub fn set_amplification_limit(&mut self, factor: Option<usize>) {
+        self.server.set_amplification_limit(factor);
+    }
+
+    /// Hold up to `size` messages waiting to be sent for each client, by
+    /// default [`DEFAULT_OUTBOUND_QUEUE_SIZE`]. Messages are queued per
+    /// client rather than waited for, so a client or transport slow to send
+    /// to holds up no other. See [`outbound`](crate::outbound).
+    pub fn set_outbound_queue_size(&mut self, size: usize) {
+        self.server.set_outbound_queue_size(size);
+    }
+
+    /// Handle messages for a client whose outbound queue is full as
+    /// `policy` says, by default by dropping the oldest.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.server.set_overflow_policy(policy);
+    }
+
+    /// Return the counters of the outbound queues.
+    pub fn outbound_stats(&self) -> OutboundStats {
+        self.server.outbound_stats()
+    }
+
+    /// Answer GET requests from `cache` while it holds a fresh response,
+    /// or always call the handler with `None`, the default. See
+    /// [`cache`](crate::cache).
+    pub fn set_response_cache(&mut self, cache: Option<ResponseCache>) {
+        self.response_cache = cache;
+    }
+
+    /// Return the response cache, e.g. to drop the responses of a resource
+    /// that changed.
+    pub fn response_cache(&mut self) -> Option<&mut ResponseCache> {
+        self.response_cache.as_mut()
+    }
+
+    /// Return the Echo policy, e.g. to look up verified addresses.
+    pub fn echo_policy(&self) -> Option<&EchoPolicy> {
+        self.echo_policy.as_ref()
+    }
+
+    /// Return the links advertised in `/.well-known/core`.
+    pub fn links(&self) -> &[Link] {
+        &self.links
+    }
+
+    /// Return the registry of observed resources, through which handlers
+    /// running on other tasks notify the observers of a resource.
+    pub fn observe_registry(&self) -> Arc<ObserveRegistry> {
+        self.observer.registry()
+    }
+
+    /// Queue up to `size` notifications to observers while the server is
+    /// busy, by default [`DEFAULT_NOTIFICATION_QUEUE_SIZE`]. When more are
+    /// due, newer notifications replace the queued ones they supersede.
+    pub fn set_notification_queue_size(&mut self, size: usize) {
+        self.observer.registry().set_queue_size(size);
+    }
+
+    /// Notify each observer at most once per `interval`, coalescing the
+    /// changes of a resource in between into a notification of its latest
+    /// state. The interval is checked once a second.
+    pub fn set_min_notification_interval(&mut self, interval: Duration) {
+        self.observer.registry().set_min_interval(interval);
+    }
+
+    /// Answer requests whose body, once reassembled from its Block1 or
+    /// Q-Block1 blocks, would exceed `size` bytes with 4.13 Request Entity
+    /// Too Large and the limit in Size1, or accept bodies of any size with
+    /// `None`. A client that announces the size of its body in Size1 is
+    /// turned away with its first block. The default is
+    /// [`DEFAULT_MAX_REQUEST_SIZE`].
+    pub fn set_max_request_size(&mut self, size: Option<usize>) {
+        self.max_request_size = size;
+    }
+
+    /// Run the handler for up to `limit` requests at once, by default one.
+    /// The responses are awaited on the task of the server, so the handler
+    /// futures need not be `Send`, and its state such as the observers and
+    /// block-wise transfers is kept by the server alone. What happens to
+    /// requests beyond the limit is set with
+    /// [`set_handler_overflow`](Self::set_handler_overflow).
+    pub fn set_max_concurrent_handlers(&mut self, limit: usize) {
+        self.max_concurrent_handlers = limit.max(1);
+    }
+
+    /// Queue requests for the handler while it runs as often as allowed, or
+    /// reject them with 5.03 Service Unavailable.
+    pub fn set_handler_overflow(&mut self, overflow: HandlerOverflow) {
+        self.handler_overflow = overflow;
+    }
+
+    /// Forget block-wise transfers in either direction after `lifetime`
+    /// without a block of them. Transfers in progress are dropped.
+    pub fn set_block_transfer_lifetime(&mut self, lifetime: Duration) {
+        self.block_transfer_lifetime = lifetime;
+        self.block_handlers.clear();
+        self.q_blocks = Transfers::new(lifetime);
+        self.request_tags = RequestTags::new(lifetime);
+        self.set_transfer_memory_budget(self.transfer_memory_budget);
+    }
+
+    /// Hold the bodies of unfinished Block1, Q-Block1 and Q-Block2 transfers
+    /// within `budget` bytes for each kind, or lift the limit with `None`.
+    /// When a block would exceed it, the least recently used transfers are
+    /// dropped to make room, and a body larger than the whole budget is
+    /// answered with 4.13 Request Entity Too Large and the budget in Size1.
+    /// The default is [`DEFAULT_TRANSFER_MEMORY_BUDGET`].
+    pub fn set_transfer_memory_budget(&mut self, budget: Option<usize>) {
+        self.transfer_memory_budget = budget;
+        self.request_tags.set_budget(budget);
+        self.q_blocks.set_budget(budget);
+    }
+
+    /// Set the path MTU to a client, or forget it with `None`. Responses
+    /// that would exceed it are sent block-wise.
+    pub fn set_path_mtu(&mut self, addr: IpAddr, mtu: Option<usize>) {
+        match mtu {
+            Some(mtu) => self.path_mtu.set(addr, mtu),
+            None => self.path_mtu.remove(addr),
+        }
+    }
+
+    /// Set the path MTU of clients without their own value. `None` restores
+    /// the default assumption of 1280 bytes.
+    pub fn set_default_path_mtu(&mut self, mtu: Option<usize>) {
+        self.path_mtu.set_default(mtu);
+    }
+
+    fn block_handler(&mut self, addr: SocketAddr) -> &mut BlockHandler<SocketAddr> {
+        let max_total_message_size = self.path_mtu.max_message_size(addr.ip());
+        let cache_expiry_duration = self.block_transfer_lifetime;
+        self.block_handlers
+            .entry(max_total_message_size)
+            .or_insert_with(|| {
+                BlockHandler::new(BlockHandlerConfig {
+                    max_total_message_size,
+                    cache_expiry_duration,
+                })
+            })
+    }
+
+    /// Send the notifications of the observer to a peer in one batch.
+    async fn send_notifications(
+        &mut self,
+        packets: Vec<Packet>,
+        addr: SocketAddr,
+    ) -> Result<(), io::Error> {
+        let packets: Vec<_> = packets
+            .into_iter()
+            .filter_map(|packet| self.prepare_msg(packet, addr))
+            .collect();
+        if self.server.multicast_group(&addr).is_some() {
+            self.server.queue_group_sends(packets, addr).await;
+            return Ok(());
+        }
+        self.server.send_all(packets, addr).await
+    }
+
+    /// Return a message to send that was not a response to a request,
+    /// sliced into blocks if it does not fit the peer.
+    fn prepare_msg(&mut self, packet: Packet, addr: SocketAddr) -> Option<Packet> {
         let mut request = CoapRequest::from_packet(Packet::new(), addr);
         request.response = CoapResponse::new(&packet);
-        match self.block_handler.intercept_response(&mut request) {
+        match self.intercept_response(&mut request, addr) {
             Err(err) => {
                 if self.handle_coap_handing_error(&mut request, err) {
-                    return self.server.send((request.response.unwrap().message, addr)).await;
+                    return Some(request.response.unwrap().message);
                 }
+                None
+            }
+            Ok(true) => Some(request.response.unwrap().message),
+            _ => Some(packet),
+        }
+    }
+
+    /// Slice a response that does not fit the client into Block2 blocks, and
+    /// give the size of the whole body in Size2 with the first block or
+    /// when the request asked for it.
+    fn intercept_response(
+        &mut self,
+        request: &mut CoapRequest<SocketAddr>,
+        addr: SocketAddr,
+    ) -> Result<bool, HandlingError> {
+        let size = request.response.as_ref().map(|response| response.message.payload.len());
+        let asked = payload::size2(&request.message).is_some();
+        let result = self.block_handler(addr).intercept_response(request);
+        if let (Some(size), Some(response)) = (size, request.response.as_mut()) {
+            let first_block = response
+                .message
+                .get_first_option_as::<BlockValue>(CoapOption::Block2)
+                .and_then(|block| block.ok())
+                .is_some_and(|block| block.num == 0);
+            if (first_block || asked) && payload::size2(&response.message).is_none() {
+                payload::set_size(&mut response.message, CoapOption::Size2, size);
+            }
+        }
+        result
+    }
+
+    /// Send the response to a request, unless the No-Response option of the
+    /// request or the response policy of the group the request was sent to
+    /// suppresses it. A confirmable request is still acknowledged. Responses
+    /// to group requests are queued rather than waited for.
+    async fn respond(
+        &mut self,
+        request: &Packet,
+        response: Packet,
+        addr: SocketAddr,
+    ) -> Result<(), io::Error> {
+        let group = self.server.multicast_group(&addr);
+        if let Some(group) = group {
+            if self.groups.policy(group).suppresses(response.header.code) {
+                debug!("suppress response to {} for group {}", addr, group);
                 return Ok(());
             }
-            Ok(true) => {
-                return self.server.send((request.response.unwrap().message, addr)).await;
+        }
+        match no_response::filter(request, response) {
+            Some(packet) if group.is_some() => {
+                self.server.queue_group_sends(std::iter::once(packet), addr).await;
+                Ok(())
             }
+            Some(packet) => self.server.send((packet, addr)).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Signaling messages manage the connection and never reach the handler.
+    async fn handle_signaling(
+        &mut self,
+        signal: Signal,
+        packet: Packet,
+        addr: SocketAddr,
+    ) -> Result<(), io::Error> {
+        match signal {
+            Signal::Ping => self.server.send((tcp::pong(&packet), addr)).await,
             _ => {
-                return self.server.send((packet, addr)).await;
+                debug!("ignore signaling message {} from {}", packet.header.get_code(), addr);
+                Ok(())
             }
         }
     }
 
-    async fn dispatch_msg(&mut self, packet: Packet, addr: SocketAddr) -> Result<(), io::Error> {
+    /// Handle a request up to the handler, and return the future of the
+    /// handler if it is to be called, or else `None` once the response has
+    /// been sent. With `busy`, requests for the handler are rejected.
+    async fn dispatch_msg<F: FnMut(CoapRequest<SocketAddr>) -> HandlerRet>(
+        &mut self,
+        handler: &mut F,
+        packet: Packet,
+        addr: SocketAddr,
+        busy: bool,
+    ) -> Result<Option<(PendingRequest, HandlerFuture<HandlerRet>)>, io::Error> {
+        // a CoAP ping, an empty confirmable message, is answered with a reset
+        if packet.header.code == MessageClass::Empty
+            && packet.header.get_type() == MessageType::Confirmable
+        {
+            let mut reset = Packet::new();
+            reset.header.set_type(MessageType::Reset);
+            reset.header.message_id = packet.header.message_id;
+            self.server.send((reset, addr)).await?;
+            return Ok(None);
+        }
         let mut request = CoapRequest::from_packet(packet, addr);
 
-        match self.block_handler.intercept_request(&mut request) {
+        if !self.admit(&mut request, addr) {
+            if let Some(response) = request.response {
+                self.respond(&request.message, response.message, addr).await?;
+            }
+            return Ok(None);
+        }
+
+        // Q-Block1 and Block1 bodies reach the handler once complete, and
+        // further Q-Block2 blocks are sent from the remembered body
+        let block1 = request
+            .message
+            .get_first_option_as::<BlockValue>(CoapOption::Block1)
+            .and_then(|block| block.ok());
+        if !self.q_blocks.receive(addr, &mut request)
+            || !self.request_tags.receive(addr, &mut request)
+        {
+            if let Some(response) = request.response {
+                self.server.send((response.message, addr)).await?;
+            }
+            return Ok(None);
+        }
+        if let Some(blocks) = self.q_blocks.resend(addr, &request) {
+            self.server.send_all(blocks, addr).await?;
+            return Ok(None);
+        }
+
+        match self.block_handler(addr).intercept_request(&mut request) {
             Ok(true) => {
                 self.server.send((request.response.unwrap().message, addr)).await?;
-                return Ok(());
+                return Ok(None);
             }
             Err(err) => {
                 if self.handle_coap_handing_error(&mut request, err) {
-                    self.server.send((request.response.unwrap().message, addr)).await?;
+                    self.respond(&request.message, request.response.unwrap().message, addr).await?;
                 }
-                return Ok(());
+                return Ok(None);
             }
             Ok(false) => {}
         }
 
-        let filtered = !self.observer.request_handler(&request).await;
+        // proxy requests bypass the local resources
+        let proxied = proxy::is_proxy_request(&request.message);
+
+        if !proxied && self.handle_well_known_core(&mut request, addr) {
+            self.respond(&request.message, request.response.unwrap().message, addr).await?;
+            return Ok(None);
+        }
+
+        if !proxied && self.handle_group_membership(&mut request) {
+            self.respond(&request.message, request.response.unwrap().message, addr).await?;
+            return Ok(None);
+        }
+
+        if !proxied && self.handle_pubsub(&mut request).await {
+            if let Some(response) = request.response {
+                self.respond(&request.message, response.message, addr).await?;
+            }
+            return Ok(None);
+        }
+
+        let filtered = !proxied && !self.observer.request_handler(&request).await;
         if filtered {
-            return Ok(());
+            return Ok(None);
         }
 
-        if let Some(ref mut handler) = self.handler {
-            match handler(request.clone()).await {
-                Some(response) => {
-                    debug!("Response: {:?}", response);
+        let cache = self.response_cache.as_mut().filter(|_| !proxied);
+        if let Some(response) = cache.and_then(|cache| cache.get(&request)) {
+            debug!("cached response to {} for {}", request.get_path(), addr);
+            self.send_response(request, block1, Some(response), addr).await?;
+            return Ok(None);
+        }
+
+        if busy {
+            if let (HandlerOverflow::Reject(retry_after), Some(response)) =
+                (self.handler_overflow, request.response.as_mut())
+            {
+                debug!("handlers busy, reject request from {}", addr);
+                unavailable(response, retry_after);
+                self.respond(&request.message, request.response.unwrap().message, addr).await?;
+                return Ok(None);
+            }
+        }
+
+        let response = self.call_handler(handler, &mut request, proxied, addr);
+        let pending = PendingRequest {
+            request,
+            block1,
+            proxied,
+            addr,
+        };
+        Ok(Some((pending, response)))
+    }
+
+    /// Keep the response of the handler in the cache, if there is one, and
+    /// send it.
+    async fn finish_request(
+        &mut self,
+        pending: PendingRequest,
+        response: Option<CoapResponse>,
+    ) -> Result<(), io::Error> {
+        let PendingRequest {
+            request,
+            block1,
+            proxied,
+            addr,
+        } = pending;
+        if let Some(cache) = self.response_cache.as_mut().filter(|_| !proxied) {
+            let message = response.as_ref().map(|response| &response.message);
+            cache.update(&request.message, message);
+        }
+        self.send_response(request, block1, response, addr).await
+    }
+
+    /// Send the response to a request, in blocks if it does not fit, with
+    /// the Block1 option of the last block of the request body if any.
+    async fn send_response(
+        &mut self,
+        mut request: CoapRequest<SocketAddr>,
+        block1: Option<BlockValue>,
+        response: Option<CoapResponse>,
+        addr: SocketAddr,
+    ) -> Result<(), io::Error> {
+        match response {
+            Some(mut response) => {
+                debug!("Response: {:?}", response);
+                etag::validate(&request.message, &mut response.message);
+                // the response to the last block of a body acknowledges it
+                if let Some(block) = block1 {
+                    response.message.add_option_as(CoapOption::Block1, block);
+                }
+                if self.fits_piggybacked(&request.message, &response.message, addr) {
                     request.response = Some(response);
-                    match self.block_handler.intercept_response(&mut request) {
-                        Err(err) => {
-                            if self.handle_coap_handing_error(&mut request, err) {
-                                self.server.send((request.response.unwrap().message, addr)).await?;
+                    self.challenge_unverified(&mut request, addr);
+                    let response = request.response.unwrap().message;
+                    return self.respond(&request.message, response, addr).await;
+                }
+                let max_message_size = self.path_mtu.max_message_size(addr.ip());
+                let blocks =
+                    self.q_blocks.split(addr, &request, &response.message, max_message_size);
+                if let Some(blocks) = blocks {
+                    self.server.send_all(blocks, addr).await?;
+                    return Ok(());
+                }
+                request.response = Some(response);
+                if let Err(err) = self.intercept_response(&mut request, addr) {
+                    if self.handle_coap_handing_error(&mut request, err) {
+                        let response = request.response.unwrap().message;
+                        self.respond(&request.message, response, addr).await?;
+                    }
+                    return Ok(());
+                }
+                self.challenge_unverified(&mut request, addr);
+                self.respond(&request.message, request.response.unwrap().message, addr).await?;
+            }
+            None => {
+                debug!("No response");
+            }
+        }
+        Ok(())
+    }
+
+    /// Pass the request to the proxy handler if it is to be forwarded, or
+    /// else to the handler, and return the future of the response.
+    fn call_handler<F: FnMut(CoapRequest<SocketAddr>) -> HandlerRet>(
+        &mut self,
+        handler: &mut F,
+        request: &mut CoapRequest<SocketAddr>,
+        proxied: bool,
+        addr: SocketAddr,
+    ) -> HandlerFuture<HandlerRet> {
+        // the payload is not needed once the handler has it, so it is moved
+        // to the handler rather than copied
+        let payload = mem::take(&mut request.message.payload);
+        let mut handled = request.clone();
+        handled.message.payload = payload;
+        let ingress = self.server.ingress(&addr);
+        let identity = self.server.peer_identity(&addr);
+        let proxy_handler = self.proxy_handler.as_mut().filter(|_| proxied);
+        let response = INGRESS.sync_scope(ingress, || {
+            IDENTITY.sync_scope(identity.clone(), || match proxy_handler {
+                Some(proxy_handler) => Either::Left(proxy_handler(handled)),
+                None => Either::Right(handler(handled)),
+            })
+        });
+        INGRESS.scope(ingress, IDENTITY.scope(identity, response))
+    }
+
+    /// Return whether a response goes out as it is, bypassing the block-wise
+    /// transfers: a piggybacked response that fits the path MTU, with no
+    /// block or size option in it or the request.
+    fn fits_piggybacked(&self, request: &Packet, response: &Packet, addr: SocketAddr) -> bool {
+        let options = [
+            CoapOption::Block2,
+            CoapOption::Size2,
+            CoapOption::Unknown(qblock::Q_BLOCK2),
+        ];
+        response.header.get_type() == MessageType::Acknowledgement
+            && options.into_iter().all(|option| {
+                [request, response].into_iter().all(|message| {
+                    message.get_option(option).is_none_or(|values| values.is_empty())
+                })
+            })
+            && message::encoded_len(response) <= self.path_mtu.max_message_size(addr.ip())
+    }
+
+    /// Check the method and the critical options of the request, then the
+    /// request against the access control list, the size limit, the proxy
+    /// options and the Echo policy. Rejected
+    /// requests are left with the response to send.
+    fn admit(&mut self, request: &mut CoapRequest<SocketAddr>, addr: SocketAddr) -> bool {
+        let method = match request.message.header.code {
+            // codes of class 0 that are no known method
+            MessageClass::Request(Method::UnKnown) | MessageClass::Reserved(0x01..=0x1f) => {
+                debug!("unknown method {} from {}", request.message.header.get_code(), addr);
+                if let Some(ref mut response) = request.response {
+                    response.set_status(Status::MethodNotAllowed);
+                }
+                return false;
+            }
+            MessageClass::Request(method) => method,
+            _ => return true,
+        };
+        // a proxy passes on the options that are safe to forward
+        if self.proxy_handler.is_some() && proxy::is_proxy_request(&request.message) {
+            if let Err(diagnostic) = self.options.check_forwarded(&request.message) {
+                debug!("{} from {}", diagnostic, addr);
+                if let Some(ref mut response) = request.response {
+                    response.set_status(Status::BadGateway);
+                    response.message.payload = diagnostic.into_bytes();
+                }
+                return false;
+            }
+        } else if let Err(diagnostic) = self.options.check(&mut request.message) {
+            debug!("{} from {}", diagnostic, addr);
+            // a non-confirmable request is rejected silently
+            if request.message.header.get_type() != MessageType::Confirmable {
+                request.response = None;
+            } else if let Some(ref mut response) = request.response {
+                response.set_status(Status::BadOption);
+                response.message.payload = diagnostic.into_bytes();
+            }
+            return false;
+        }
+        let mut option = None;
+        let rejection = if let Some(status) = self.acl.as_ref().and_then(|acl| {
+            let identity = self.server.peer_identity(&addr);
+            let resource = request.get_path();
+            let status = acl.check(&identity, &resource, method).err()?;
+            self.server.emit_security_event(SecurityEvent::AccessDenied {
+                peer: addr,
+                identity,
+                resource,
+                method,
+            });
+            Some(status)
+        }) {
+            status
+        } else if let Some(limit) = self.oversized(request) {
+            let limit = OptionValueU32(u32::try_from(limit).unwrap_or(u32::MAX));
+            option = Some((CoapOption::Size1, limit.into()));
+            Status::RequestEntityTooLarge
+        } else if self.proxy_handler.is_none() && proxy::is_proxy_request(&request.message) {
+            Status::ProxyingNotSupported
+        } else if let Some(Err(value)) = self.check_echo(request, addr, method) {
+            option = Some((CoapOption::Unknown(echo::ECHO), value));
+            Status::Unauthorized
+        } else {
+            return true;
+        };
+
+        debug!(
+            "{:?} of {} by {} rejected with {:?}",
+            method,
+            request.get_path(),
+            addr,
+            rejection
+        );
+        if let Some(ref mut response) = request.response {
+            response.set_status(rejection);
+            if let Some((option, value)) = option {
+                response.message.add_option(option, value);
+            }
+        }
+        false
+    }
+
+    /// Return the size limit if the body of the request, as far as it was
+    /// received or as the client indicated in Size1, exceeds it.
+    fn oversized(&self, request: &CoapRequest<SocketAddr>) -> Option<usize> {
+        let limit = self.max_request_size?;
+        if payload::size1(&request.message).is_some_and(|size| size > limit) {
+            return Some(limit);
+        }
+        let offset = request
+            .message
+            .get_first_option_as::<BlockValue>(CoapOption::Block1)
+            .and_then(|block| block.ok())
+            .or_else(|| qblock::get(&request.message, qblock::Q_BLOCK1))
+            .map_or(0, |block| usize::from(block.num) * block.size());
+        (offset + request.message.payload.len() > limit).then_some(limit)
+    }
+
+    fn check_echo(
+        &mut self,
+        request: &CoapRequest<SocketAddr>,
+        addr: SocketAddr,
+        method: Method,
+    ) -> Option<Result<(), Vec<u8>>> {
+        let policy = self.echo_policy.as_mut()?;
+        let result = policy.check(addr, request, method);
+        self.server.set_address_verified(addr, policy.is_verified(&addr));
+        let echoed = request
+            .message
+            .get_option(CoapOption::Unknown(echo::ECHO))
+            .is_some();
+        if result.is_err() && echoed {
+            self.server.emit_security_event(SecurityEvent::ReplayDetected {
+                peer: addr,
+                resource: request.get_path(),
+            });
+        }
+        Some(result)
+    }
+
+    /// Replace a response that the amplification limit would hold back
+    /// with an Echo challenge, which lets the client verify its address.
+    fn challenge_unverified(&mut self, request: &mut CoapRequest<SocketAddr>, addr: SocketAddr) {
+        let policy = match self.echo_policy.as_mut() {
+            Some(policy) => policy,
+            None => return,
+        };
+        let len = request
+            .response
+            .as_ref()
+            .map_or(0, |response| message::encoded_len(&response.message));
+        if self.server.may_send(&addr, len) {
+            return;
+        }
+        if let Some(mut challenge) = CoapResponse::new(&request.message) {
+            challenge.set_status(Status::Unauthorized);
+            challenge
+                .message
+                .add_option(CoapOption::Unknown(echo::ECHO), policy.challenge(addr));
+            request.response = Some(challenge);
+        }
+    }
+
+    fn handle_well_known_core(
+        &mut self,
+        request: &mut CoapRequest<SocketAddr>,
+        addr: SocketAddr,
+    ) -> bool {
+        if self.links.is_empty()
+            || *request.get_method() != Method::Get
+            || request.get_path() != ".well-known/core"
+        {
+            return false;
+        }
+        let response = match request.response {
+            Some(ref mut response) => response,
+            None => return false,
+        };
+
+        let queries: Vec<Vec<u8>> = request
+            .message
+            .get_option(CoapOption::UriQuery)
+            .map(|queries| queries.iter().cloned().collect())
+            .unwrap_or_default();
+        if self.discovery.peek(&queries).is_none() {
+            let filters: Vec<_> = queries.iter().map(|q| String::from_utf8_lossy(q)).collect();
+            let links = self
+                .links
+                .iter()
+                .filter(|link| filters.iter().all(|q| link.matches_query(q)));
+            let document = link_format::serialize(links).into_bytes();
+            self.discovery.insert(queries.clone(), document);
+        }
+        let document = match self.discovery.get(&queries) {
+            Some(document) => document,
+            None => return false,
+        };
+
+        // blocks are cut from the document rather than left to the block
+        // handler, which would keep a copy of it for each client
+        response
+            .message
+            .set_content_format(ContentFormat::ApplicationLinkFormat);
+        let max_message_size = self.path_mtu.max_message_size(addr.ip());
+        let requested = request
+            .message
+            .get_first_option_as::<BlockValue>(CoapOption::Block2)
+            .and_then(|block| block.ok());
+        // Block2, Size2 and the payload marker take up to 9 bytes
+        let overhead = message::encoded_len(&response.message) + 9;
+        if requested.is_none() && overhead + document.len() <= max_message_size {
+            response.message.payload = document.clone();
+            return true;
+        }
+        let size = mtu::block_size(max_message_size, overhead)
+            .unwrap_or(MIN_BLOCK_SIZE)
+            .min(requested.as_ref().map_or(usize::MAX, |block| block.size()));
+        let start = requested.as_ref().map_or(0, |block| usize::from(block.num) * block.size());
+        if start > 0 && start >= document.len() {
+            response.set_status(Status::BadOption);
+            response.message.payload = b"Block out of range".to_vec();
+            return true;
+        }
+        let end = document.len().min(start + size);
+        let block = BlockValue::new(start / size, end < document.len(), size);
+        if let Ok(block) = block {
+            response.message.payload = document[start..end].to_vec();
+            response.message.add_option_as(CoapOption::Block2, block);
+            if start == 0 || payload::size2(&request.message).is_some() {
+                payload::set_size(&mut response.message, CoapOption::Size2, document.len());
+            }
+        }
+        true
+    }
+
+    /// Serve the group membership resource, joining and leaving groups as
+    /// memberships are created and deleted.
+    fn handle_group_membership(&mut self, request: &mut CoapRequest<SocketAddr>) -> bool {
+        let path = request.get_path();
+        let index = match self.groups.resource(&path) {
+            Some(index) => index.map(str::to_string),
+            None => return false,
+        };
+        let method = *request.get_method();
+        let response = match request.response {
+            Some(ref mut response) => response,
+            None => return false,
+        };
+        let (status, body) = match (index, method) {
+            (None, Method::Get) => {
+                let memberships: BTreeMap<String, &Membership> = self
+                    .groups
+                    .memberships()
+                    .iter()
+                    .map(|(index, membership)| (index.to_string(), membership))
+                    .collect();
+                (Status::Content, serde_json::to_vec(&memberships).ok())
+            }
+            (None, Method::Post) => {
+                let format = content_format::get(&request.message);
+                let membership = serde_json::from_slice::<Membership>(&request.message.payload);
+                let local_port = self.server.socket_addr().map(|addr| addr.port()).ok();
+                match (format, membership) {
+                    (Some(format), _) if format != group::CONTENT_FORMAT => {
+                        (Status::UnsupportedContentFormat, None)
+                    }
+                    (_, Ok(membership)) => match membership.group() {
+                        Some((_, Some(port))) if Some(port) != local_port => {
+                            (Status::BadRequest, Some(b"Port not served".to_vec()))
+                        }
+                        Some((group, _)) => {
+                            if !self.server.multicast_groups().contains(&group) {
+                                self.server.join_multicast(group);
+                            }
+                            if self.server.multicast_groups().contains(&group) {
+                                let index = self.groups.add(membership);
+                                for segment in path.split('/') {
+                                    response.message.add_option(
+                                        CoapOption::LocationPath,
+                                        segment.as_bytes().to_vec(),
+                                    );
+                                }
+                                response.message.add_option(
+                                    CoapOption::LocationPath,
+                                    index.to_string().into_bytes(),
+                                );
+                                (Status::Created, None)
+                            } else {
+                                let diagnostic = b"Cannot join group".to_vec();
+                                (Status::InternalServerError, Some(diagnostic))
                             }
-                            return Ok(());
                         }
-                        _ => {}
+                        None => (Status::BadRequest, Some(b"Not a multicast address".to_vec())),
+                    },
+                    (_, Err(_)) => (Status::BadRequest, Some(b"Invalid membership".to_vec())),
+                }
+            }
+            (Some(index), Method::Get) => match self.groups.get(&index) {
+                Some(membership) => (Status::Content, serde_json::to_vec(membership).ok()),
+                None => (Status::NotFound, None),
+            },
+            (Some(index), Method::Delete) => match self.groups.remove(&index) {
+                Some(membership) => {
+                    if let Some((group, _)) = membership.group() {
+                        if !self.groups.is_member(group) {
+                            self.server.leave_multicast(group);
+                        }
                     }
-                    self.server.send((request.response.unwrap().message, addr)).await?;
+                    (Status::Deleted, None)
                 }
-                None => {
-                    debug!("No response");
+                None => (Status::NotFound, None),
+            },
+            _ => (Status::MethodNotAllowed, None),
+        };
+        response.set_status(status);
+        if let Some(body) = body {
+            if status == Status::Content {
+                content_format::set(&mut response.message, group::CONTENT_FORMAT);
+            }
+            response.message.payload = body;
+        }
+        true
+    }
+
+    /// Serve the topics of the broker, passing publications and
+    /// subscriptions on to the observer.
+    async fn handle_pubsub(&mut self, request: &mut CoapRequest<SocketAddr>) -> bool {
+        for path in self.broker.remove_expired(Instant::now()) {
+            self.observer.resource_removed(&path).await;
+        }
+        match self.broker.handle(request) {
+            None => return false,
+            Some(Handling::Respond) => {}
+            Some(Handling::Observe) => {
+                self.observer.request_handler(request).await;
+            }
+            Some(Handling::Subscribe) => {
+                self.observer.request_handler(request).await;
+                request.response = None;
+            }
+            Some(Handling::Remove(paths)) => {
+                for path in paths {
+                    self.observer.resource_removed(&path).await;
                 }
             }
         }
-        Ok(())
+        true
     }
 
     fn handle_coap_handing_error(&mut self, request: &mut CoapRequest<SocketAddr>, err: HandlingError) -> bool {
         if request.apply_from_error(err) {
             // If the error happens to need block2 handling, let's do that here...
-            let _ = self.block_handler.intercept_response(request);
+            if let Some(addr) = request.source {
+                let _ = self.block_handler(addr).intercept_response(request);
+            }
             return true
         }
         false
@@ -188,8 +1142,7 @@ where
     /// For further details see method join_multicast
     pub fn enable_all_coap(&mut self, segment: u8) {
         assert!(segment <= 0xf);
-        let socket = self.server.socket.get_mut();
-        let m = match socket.local_addr().unwrap() {
+        let m = match self.server.socket_addr().unwrap() {
             SocketAddr::V4(_val) => IpAddr::V4(Ipv4Addr::new(224, 0, 1, 187)),
             SocketAddr::V6(_val) => IpAddr::V6(Ipv6Addr::new(
                 0xff00 + segment as u16,
@@ -237,32 +1190,263 @@ where
     pub fn leave_multicast(&mut self, addr: IpAddr) {
         self.server.leave_multicast(addr);
     }
+
+    /// Return the multicast groups the server currently serves.
+    pub fn multicast_groups(&self) -> Vec<IpAddr> {
+        self.server.multicast_groups()
+    }
+
+    /// Set which responses to requests to `group` are sent. By default,
+    /// error responses are suppressed.
+    pub fn set_group_response_policy(&mut self, group: IpAddr, policy: ResponsePolicy) {
+        self.groups.set_policy(group, policy);
+    }
+
+    /// Serve the group membership resource of RFC 7390 at `path`, e.g.
+    /// [`group::DEFAULT_MEMBERSHIP_PATH`], or stop serving it with `None`.
+    pub fn set_group_membership_path(&mut self, path: Option<&str>) {
+        self.groups.path = path.map(|path| path.trim_matches('/').to_string());
+    }
+
+    /// Act as a publish-subscribe broker with the topic collection at
+    /// `path`, e.g. [`pubsub::DEFAULT_PATH`](crate::pubsub::DEFAULT_PATH),
+    /// or stop with `None`. See [`pubsub`](crate::pubsub).
+    pub fn set_broker_path(&mut self, path: Option<&str>) {
+        self.broker.path = path.map(|path| path.trim_matches('/').to_string());
+    }
+
+    /// Pass requests with a Proxy-Uri or Proxy-Scheme option to `handler`
+    /// instead of serving them locally; see [`proxy`](crate::proxy).
+    /// Without a proxy handler they are answered with 5.05 Proxying Not
+    /// Supported.
+    pub fn set_proxy_handler<F>(&mut self, handler: F)
+    where
+        F: FnMut(CoapRequest<SocketAddr>) -> ResponseFuture + Send + 'a,
+    {
+        self.proxy_handler = Some(Box::new(handler));
+    }
+
+    /// Forward requests with a Proxy-Uri or Proxy-Scheme option to their
+    /// targets with `proxy` and relay the responses; see
+    /// [`ForwardProxy`](crate::proxy::ForwardProxy). The observers of the
+    /// server are notified of the targets observed through the proxy.
+    pub fn set_forward_proxy(&mut self, mut proxy: ForwardProxy) {
+        proxy.set_observe_registry(Some(self.observer.registry()));
+        self.set_proxy_handler(move |request| proxy.forward(request));
+    }
+}
+
+/// How many filtered `/.well-known/core` documents the server keeps.
+const DISCOVERY_CAPACITY: usize = 32;
+
+/// How many peers the server remembers the transport of.
+const ROUTE_CAPACITY: usize = 4096;
+
+/// How many times the bytes received from an unverified peer the server
+/// sends to it at most, as recommended by RFC 9175.
+pub const DEFAULT_AMPLIFICATION_FACTOR: usize = 3;
+
+/// Default limit of the size of a request body.
+pub const DEFAULT_MAX_REQUEST_SIZE: usize = 1024 * 1024;
+
+/// Default memory budget of each kind of block-wise transfer.
+pub const DEFAULT_TRANSFER_MEMORY_BUDGET: usize = 16 * 1024 * 1024;
+
+/// Default time after which an unfinished block-wise transfer is
+/// forgotten.
+pub const DEFAULT_BLOCK_TRANSFER_LIFETIME: Duration = Duration::from_secs(120);
+
+pub use super::observer::DEFAULT_NOTIFICATION_QUEUE_SIZE;
+pub use super::outbound::DEFAULT_OUTBOUND_QUEUE_SIZE;
+
+/// What happens to requests for the handler while it runs as many times at
+/// once as allowed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HandlerOverflow {
+    /// Leave them to wait in the transports until a handler is done, the
+    /// default.
+    #[default]
+    Queue,
+    /// Answer them with 5.03 Service Unavailable, asking the client to
+    /// repeat the request after the given time in Max-Age.
+    Reject(Duration),
+}
+
+/// The future of the response of the handler, run with the transport and
+/// the identity of the client at hand.
+type HandlerFuture<R> =
+    TaskLocalFuture<usize, TaskLocalFuture<PeerIdentity, Either<ResponseFuture, R>>>;
+
+/// A request being handled, with what is needed to send the response.
+struct PendingRequest {
+    request: CoapRequest<SocketAddr>,
+    block1: Option<BlockValue>,
+    proxied: bool,
+    addr: SocketAddr,
+}
+
+/// Turn `response` into 5.03 Service Unavailable, asking the client to wait
+/// `retry_after`, rounded up to whole seconds, before repeating the request.
+fn unavailable(response: &mut CoapResponse, retry_after: Duration) {
+    throttle::reject(response, retry_after);
+    response.set_status(Status::ServiceUnavailable);
+}
+
+/// The traffic of a peer, for the amplification limit.
+#[derive(Clone, Copy, Default)]
+struct Traffic {
+    received: usize,
+    sent: usize,
+    verified: bool,
+}
+
+/// Caps what the server sends to peers whose address is not verified, so
+/// that requests with a spoofed source address cannot make it flood the
+/// owner of that address.
+struct AmplificationLimit {
+    factor: usize,
+    traffic: LruCache<SocketAddr, Traffic>,
 }
 
 pub struct CoAPServer {
-    receiver: MessageReceiver,
     is_terminated: bool,
-    socket: UdpFramed<Codec>,
-    multicast_addresses: Vec<IpAddr>,
+    transports: Vec<Fuse<Box<dyn Transport>>>,
+    /// The transport each peer last sent a message on, if there are several.
+    routes: LruCache<SocketAddr, usize>,
+    next_transport: usize,
+    amplification_limit: Option<AmplificationLimit>,
+    security_event_handler: Option<SecurityEventHandler>,
+    outbound: Outbound,
 }
 
 impl CoAPServer {
     /// Creates a CoAP server listening on the given address.
-    pub fn new<A: ToSocketAddrs>(
-        addr: A,
-        rx: mpsc::UnboundedReceiver<(Packet, SocketAddr)>,
-    ) -> Result<CoAPServer, io::Error> {
-        let std_socket = net::UdpSocket::bind(addr).unwrap();
-        std_socket.set_nonblocking(true)?;
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<CoAPServer, io::Error> {
+        Ok(Self::from_transport(UdpTransport::bind(addr)?))
+    }
 
-        let socket = UdpSocket::from_std(std_socket)?;
+    /// Creates a CoAP server on top of an arbitrary transport.
+    pub fn from_transport<T: Transport + 'static>(transport: T) -> CoAPServer {
+        Self::from_boxed(Box::new(transport))
+    }
 
-        Ok(CoAPServer {
-            receiver: UnboundedReceiverStream::new(rx),
+    fn from_boxed(transport: Box<dyn Transport>) -> CoAPServer {
+        CoAPServer {
             is_terminated: false,
-            socket: UdpFramed::new(socket, Codec::new()),
-            multicast_addresses: Vec::new(),
-        })
+            transports: vec![transport.fuse()],
+            routes: LruCache::with_capacity(ROUTE_CAPACITY),
+            next_transport: 0,
+            amplification_limit: None,
+            security_event_handler: None,
+            outbound: Outbound::new(),
+        }
+    }
+
+    /// Also receive messages on `transport` and return its index. The
+    /// transport given at construction has index 0.
+    pub fn add_transport<T: Transport + 'static>(&mut self, transport: T) -> usize {
+        let mut boxed: Box<dyn Transport> = Box::new(transport);
+        if let Some(ref handler) = self.security_event_handler {
+            boxed.set_security_event_handler(handler.clone());
+        }
+        self.transports.push(boxed.fuse());
+        self.transports.len() - 1
+    }
+
+    /// Report security events of the server and its transports to
+    /// `handler`.
+    pub fn set_security_event_handler(&mut self, handler: SecurityEventHandler) {
+        for transport in self.transports.iter_mut() {
+            transport
+                .get_mut()
+                .set_security_event_handler(handler.clone());
+        }
+        self.security_event_handler = Some(handler);
+    }
+
+    /// Pass `event` to the security event handler, if there is one.
+    pub fn emit_security_event(&self, event: SecurityEvent) {
+        audit::emit(&self.security_event_handler, event);
+    }
+
+    /// Return the index of the transport the peer last sent a message on.
+    pub fn ingress(&self, addr: &SocketAddr) -> usize {
+        self.routes.peek(addr).copied().unwrap_or(0)
+    }
+
+    /// Return the identity the peer authenticated with on the transport it
+    /// last sent a message on.
+    pub fn peer_identity(&self, addr: &SocketAddr) -> PeerIdentity {
+        self.transports[self.ingress(addr)]
+            .get_ref()
+            .peer_identity(addr)
+    }
+
+    /// Return the multicast group the peer sent its last message to, if it
+    /// was a group request.
+    pub fn multicast_group(&self, addr: &SocketAddr) -> Option<IpAddr> {
+        self.transports[self.ingress(addr)]
+            .get_ref()
+            .multicast_group(addr)
+    }
+
+    /// Return the multicast groups the transports receive.
+    pub fn multicast_groups(&self) -> Vec<IpAddr> {
+        let mut groups = Vec::new();
+        for transport in self.transports.iter() {
+            for group in transport.get_ref().multicast_groups() {
+                if !groups.contains(&group) {
+                    groups.push(group);
+                }
+            }
+        }
+        groups
+    }
+
+    /// Send at most `factor` times the bytes received from a peer to it
+    /// until its address is verified, or lift the limit with `None`. Peers
+    /// of transports that verify addresses themselves are not limited.
+    pub fn set_amplification_limit(&mut self, factor: Option<usize>) {
+        self.amplification_limit = factor.map(|factor| AmplificationLimit {
+            factor,
+            traffic: LruCache::with_capacity(ROUTE_CAPACITY),
+        });
+    }
+
+    /// Mark the address of a peer as verified, e.g. after it returned an
+    /// Echo value, or as no longer verified.
+    pub fn set_address_verified(&mut self, addr: SocketAddr, verified: bool) {
+        if let Some(limit) = self.amplification_limit.as_mut() {
+            let traffic = limit.traffic.entry(addr).or_insert_with(Traffic::default);
+            traffic.verified = verified;
+        }
+    }
+
+    /// Return whether the amplification limit allows sending `len` more
+    /// bytes to the peer.
+    pub fn may_send(&self, addr: &SocketAddr, len: usize) -> bool {
+        let limit = match self.amplification_limit {
+            Some(ref limit) => limit,
+            None => return true,
+        };
+        if self.transports[self.ingress(addr)].get_ref().verifies_addresses() {
+            return true;
+        }
+        let traffic = limit.traffic.peek(addr).copied().unwrap_or_default();
+        traffic.verified || traffic.sent + len <= traffic.received * limit.factor
+    }
+
+    /// Account for `len` bytes received from or, with `sent`, sent to the
+    /// peer.
+    fn count_traffic(&mut self, addr: SocketAddr, len: usize, sent: bool) {
+        if let Some(limit) = self.amplification_limit.as_mut() {
+            let traffic = limit.traffic.entry(addr).or_insert_with(Traffic::default);
+            if sent {
+                traffic.sent += len;
+            } else {
+                traffic.received += len;
+            }
+        }
     }
 
     /// Stop the server.
@@ -271,81 +1455,143 @@ impl CoAPServer {
     }
 
     /// send the packet to the specific address.
+    /// With several transports, the packet goes out on the one the peer last
+    /// sent a message on.
     pub async fn send(&mut self, frame: (Packet, SocketAddr)) -> Result<(), io::Error> {
-        self.socket.send(frame).await
+        let addr = frame.1;
+        self.send_all(std::iter::once(frame.0), addr).await
+    }
+
+    /// Send several messages to `addr`, flushing the transport once, so
+    /// that a transport sending batches, like `MmsgTransport`, can send them
+    /// with a single system call.
+    ///
+    /// The messages go into the outbound queue of the peer and are sent as
+    /// soon as the transport takes them, which need not be before this
+    /// returns. Only with [`OverflowPolicy::Block`] does this wait, for room
+    /// in the queue.
+    pub async fn send_all<I: IntoIterator<Item = Packet>>(
+        &mut self,
+        packets: I,
+        addr: SocketAddr,
+    ) -> Result<(), io::Error> {
+        let index = self.route(&addr);
+        for packet in packets {
+            if self.limit_sending(index, &packet, addr) {
+                continue;
+            }
+            if self.outbound.policy() == OverflowPolicy::Block {
+                future::poll_fn(|cx| {
+                    self.outbound.poll_send(cx, &mut self.transports);
+                    match self.outbound.has_room(&addr) {
+                        true => Poll::Ready(()),
+                        false => Poll::Pending,
+                    }
+                })
+                .await;
+            }
+            self.outbound.push(index, packet, addr);
+        }
+        self.poll_outbound().await;
+        Ok(())
+    }
+
+    /// Queue messages to a peer that sent a group request. Unlike
+    /// [`send_all`](Self::send_all), this never waits: a full queue drops
+    /// its oldest message even with [`OverflowPolicy::Block`], as the
+    /// response to a group request is not worth holding up other peers.
+    pub async fn queue_group_sends<I: IntoIterator<Item = Packet>>(
+        &mut self,
+        packets: I,
+        addr: SocketAddr,
+    ) {
+        let index = self.route(&addr);
+        for packet in packets {
+            if !self.limit_sending(index, &packet, addr) {
+                self.outbound.push(index, packet, addr);
+            }
+        }
+        self.poll_outbound().await;
+    }
+
+    /// Hold up to `size` messages for each peer, by default
+    /// [`DEFAULT_OUTBOUND_QUEUE_SIZE`], and at least one.
+    pub fn set_outbound_queue_size(&mut self, size: usize) {
+        self.outbound.set_capacity(size);
+    }
+
+    /// Handle messages for a peer whose queue is full as `policy` says.
+    pub fn set_overflow_policy(&mut self, policy: OverflowPolicy) {
+        self.outbound.set_policy(policy);
+    }
+
+    /// Return what became of the messages sent so far.
+    pub fn outbound_stats(&self) -> OutboundStats {
+        self.outbound.stats()
+    }
+
+    /// Send what the transports take right away, so that messages do not
+    /// wait for the server to be polled again.
+    async fn poll_outbound(&mut self) {
+        future::poll_fn(|cx| {
+            self.outbound.poll_send(cx, &mut self.transports);
+            Poll::Ready(())
+        })
+        .await
+    }
+
+    /// Return the index of the transport to send to a peer on.
+    fn route(&mut self, addr: &SocketAddr) -> usize {
+        match self.transports.len() {
+            1 => 0,
+            _ => self.routes.get(addr).copied().unwrap_or(0),
+        }
+    }
+
+    /// Return whether the amplification limit holds back a message to a
+    /// peer on the transport `index`, or else account for it.
+    fn limit_sending(&mut self, index: usize, packet: &Packet, addr: SocketAddr) -> bool {
+        if self.amplification_limit.is_none()
+            || self.transports[index].get_ref().verifies_addresses()
+        {
+            return false;
+        }
+        let len = message::encoded_len(packet);
+        if !self.may_send(&addr, len) {
+            debug!("amplification limit holds back {} bytes to {}", len, addr);
+            return true;
+        }
+        self.count_traffic(addr, len, true);
+        false
     }
 
     /// Return the local address that the server is listening on. This can be useful when starting
     /// a server on a random port as part of unit testing.
     pub fn socket_addr(&self) -> std::io::Result<SocketAddr> {
-        self.socket.get_ref().local_addr()
+        self.transports[0].get_ref().local_addr()
     }
 
     /// join multicast - adds the multicast addresses to the listener
     pub fn join_multicast(&mut self, addr: IpAddr) {
-        assert!(addr.is_multicast());
-        let socket = self.socket.get_mut();
-        // determine wether IPv4 or IPv6 and
-        // join the appropriate multicast address
-        match socket.local_addr().unwrap() {
-            SocketAddr::V4(val) => {
-                match addr {
-                    IpAddr::V4(ipv4) => {
-                        let i = val.ip().clone();
-                        socket.join_multicast_v4(ipv4, i).unwrap();
-                        self.multicast_addresses.push(addr);
-                    }
-                    IpAddr::V6(_ipv6) => { /* handle IPv6 */ }
-                }
-            }
-            SocketAddr::V6(_val) => {
-                match addr {
-                    IpAddr::V4(_ipv4) => { /* handle IPv4 */ }
-                    IpAddr::V6(ipv6) => {
-                        socket.join_multicast_v6(&ipv6, 0).unwrap();
-                        self.multicast_addresses.push(addr);
-                        //socket.set_only_v6(true)?;
-                    }
-                }
+        let several = self.transports.len() > 1;
+        for transport in self.transports.iter_mut() {
+            match transport.get_mut().join_multicast(addr) {
+                // only some of several transports may support multicast
+                Err(e) if e.kind() == io::ErrorKind::Unsupported && several => {}
+                Err(e) => error!("join multicast error: {}", e),
+                Ok(()) => {}
             }
         }
     }
 
     /// leave multicast - remove the multicast address from the listener
     pub fn leave_multicast(&mut self, addr: IpAddr) {
-        assert!(addr.is_multicast());
-        let socket = self.socket.get_mut();
-        // determine wether IPv4 or IPv6 and
-        // leave the appropriate multicast address
-        match socket.local_addr().unwrap() {
-            SocketAddr::V4(val) => {
-                match addr {
-                    IpAddr::V4(ipv4) => {
-                        let i = val.ip().clone();
-                        socket.leave_multicast_v4(ipv4, i).unwrap();
-                        let index = self
-                            .multicast_addresses
-                            .iter()
-                            .position(|&item| item == addr)
-                            .unwrap();
-                        self.multicast_addresses.remove(index);
-                    }
-                    IpAddr::V6(_ipv6) => { /* handle IPv6 */ }
-                }
-            }
-            SocketAddr::V6(_val) => {
-                match addr {
-                    IpAddr::V4(_ipv4) => { /* handle IPv4 */ }
-                    IpAddr::V6(ipv6) => {
-                        socket.leave_multicast_v6(&ipv6, 0).unwrap();
-                        let index = self
-                            .multicast_addresses
-                            .iter()
-                            .position(|&item| item == addr)
-                            .unwrap();
-                        self.multicast_addresses.remove(index);
-                    }
-                }
+        let several = self.transports.len() > 1;
+        for transport in self.transports.iter_mut() {
+            match transport.get_mut().leave_multicast(addr) {
+                Err(e) if e.kind() == io::ErrorKind::Unsupported && several => {}
+                Err(e) => error!("leave multicast error: {}", e),
+                Ok(()) => {}
             }
         }
     }
@@ -353,23 +1599,6 @@ impl CoAPServer {
 
 impl Drop for CoAPServer {
     fn drop(&mut self) {
-        // unregister still existing multicast addresses
-        let socket = self.socket.get_mut();
-        for addr in &self.multicast_addresses {
-            match addr {
-                IpAddr::V4(ipv4) => match socket.local_addr().unwrap() {
-                    SocketAddr::V4(val) => {
-                        socket.leave_multicast_v4(*ipv4, val.ip().clone()).unwrap();
-                    }
-                    _ => {
-                        panic!("should not happen");
-                    }
-                },
-                IpAddr::V6(ipv6) => {
-                    socket.leave_multicast_v6(&ipv6, 0).unwrap();
-                }
-            }
-        }
         // stop server
         self.stop();
     }
@@ -379,20 +1608,40 @@ impl Stream for CoAPServer {
     type Item = Result<Message, io::Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if let Poll::Ready(Some((p, a))) = self.receiver.poll_next_unpin(cx) {
-            return Poll::Ready(Some(Ok(Message::NeedSend(p, a))));
+        let this = &mut *self;
+        this.outbound.poll_send(cx, &mut this.transports);
+
+        // start with a different transport every time so that none starves
+        let count = self.transports.len();
+        for n in 0..count {
+            let index = (self.next_transport + n) % count;
+            match self.transports[index].poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok((my_packet, addr)))) => {
+                    self.next_transport = (index + 1) % count;
+                    if count > 1 {
+                        self.routes.insert(addr, index);
+                    }
+                    if self.amplification_limit.is_some()
+                        && !self.transports[index].get_ref().verifies_addresses()
+                    {
+                        let len = message::encoded_len(&my_packet);
+                        self.count_traffic(addr, len, false);
+                    }
+                    return Poll::Ready(Some(Ok(match Signal::from_packet(&my_packet) {
+                        Some(signal) => Message::Signaling(signal, my_packet, addr),
+                        None => Message::Received(my_packet, addr),
+                    })));
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                _ => {}
+            }
         }
 
-        let result: Option<_> = futures::ready!(self.socket.poll_next_unpin(cx));
-
-        Poll::Ready(match result {
-            Some(Ok(message)) => {
-                let (my_packet, addr) = message;
-                Some(Ok(Message::Received(my_packet, addr)))
-            }
-            Some(Err(e)) => Some(Err(e)),
-            None => None,
-        })
+        if self.transports.iter().all(|transport| transport.is_done()) {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
     }
 }
 
@@ -508,6 +1757,48 @@ pub mod test {
         assert_eq!(recv_packet.message.payload, b"test-echo".to_vec());
     }
 
+    #[test]
+    fn test_piggybacked_response() {
+        let server_port = spawn_server("127.0.0.1:0", |req: CoapRequest<SocketAddr>| async {
+            let mut response = req.response?;
+            response.message.payload = req.message.payload;
+            Some(response)
+        })
+        .recv()
+        .unwrap();
+
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        for message_type in [MessageType::Confirmable, MessageType::NonConfirmable] {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Post);
+            request.set_path("/echo");
+            request.message.header.set_type(message_type);
+            request.message.header.message_id = 7;
+            request.message.payload = vec![0x2a; 100];
+            client.send(&request).unwrap();
+            let response = client.receive().unwrap().message;
+            // the payload reached the handler and went out as it was
+            assert_eq!(response.payload, request.message.payload);
+            if message_type == MessageType::Confirmable {
+                assert_eq!(response.header.get_type(), MessageType::Acknowledgement);
+                assert_eq!(response.header.message_id, 7);
+            }
+        }
+    }
+
+    #[test]
+    fn test_boxed_handler() {
+        type Handler = Box<dyn FnMut(CoapRequest<SocketAddr>) -> ResponseFuture + Send>;
+        let handler: Handler = Box::new(|req| Box::pin(request_handler(req)));
+        let server_port = spawn_server("127.0.0.1:0", handler).recv().unwrap();
+
+        let mut client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let response = client
+            .request_path("/boxed", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload, b"boxed".to_vec());
+    }
+
     #[test]
     #[ignore]
     fn test_echo_server_v6() {
@@ -628,6 +1919,679 @@ pub mod test {
         assert_eq!(rx2.recv_timeout(Duration::new(5, 0)).unwrap(), ());
     }
 
+    #[test]
+    fn test_methods() {
+        let server_port = spawn_server("127.0.0.1:0", |req: CoapRequest<SocketAddr>| async move {
+            let mut response = req.response?;
+            response.message.payload = format!("{:?}", req.message.header.code).into_bytes();
+            Some(response)
+        })
+        .recv()
+        .unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let send = |code: u8| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.message.header.code = MessageClass::from(code);
+            request.set_path("/config");
+            client.send(&request).unwrap();
+            client.receive().unwrap()
+        };
+
+        for (code, method) in [(5, Method::Fetch), (6, Method::Patch), (7, Method::IPatch)] {
+            let response = send(code);
+            assert_eq!(*response.get_status(), Status::Content);
+            let expected = format!("{:?}", MessageClass::Request(method));
+            assert_eq!(response.message.payload, expected.into_bytes());
+        }
+        let response = send(8);
+        assert_eq!(*response.get_status(), Status::MethodNotAllowed);
+        assert!(response.message.payload.is_empty());
+    }
+
+    #[test]
+    fn test_group_membership() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    // multicast needs a server on a real interface
+                    let mut server = Server::new("0.0.0.0:0").unwrap();
+                    server.set_group_membership_path(Some(group::DEFAULT_MEMBERSHIP_PATH));
+                    tx.send(server.socket_addr().unwrap().port()).unwrap();
+                    server
+                        .run(|req: CoapRequest<SocketAddr>| async { req.response })
+                        .await
+                        .unwrap();
+                })
+        });
+        let client = CoAPClient::new(format!("127.0.0.1:{}", rx.recv().unwrap())).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let send = |method: Method, path: &str, payload: &str| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(method);
+            request.set_path(path);
+            request.message.payload = payload.as_bytes().to_vec();
+            client.send(&request).unwrap();
+            client.receive().unwrap()
+        };
+
+        let response = send(Method::Get, "/coap-group", "");
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(content_format::get(&response.message), Some(group::CONTENT_FORMAT));
+        assert_eq!(response.message.payload, b"{}");
+        let response = send(Method::Post, "/coap-group", r#"{"a": "192.0.2.1"}"#);
+        assert_eq!(*response.get_status(), Status::BadRequest);
+        let response = send(Method::Post, "/coap-group", "{}");
+        assert_eq!(*response.get_status(), Status::BadRequest);
+
+        let response = send(Method::Post, "/coap-group", r#"{"n": "all", "a": "224.0.1.187"}"#);
+        assert_eq!(*response.get_status(), Status::Created);
+        let location = response.message.get_option(CoapOption::LocationPath).unwrap();
+        assert_eq!(location.iter().collect::<Vec<_>>(), [&b"coap-group".to_vec(), &b"0".to_vec()]);
+        let response = send(Method::Get, "/coap-group/0", "");
+        assert_eq!(response.message.payload, br#"{"n":"all","a":"224.0.1.187"}"#);
+        let response = send(Method::Get, "/coap-group", "");
+        assert_eq!(response.message.payload, br#"{"0":{"n":"all","a":"224.0.1.187"}}"#);
+
+        let response = send(Method::Delete, "/coap-group/0", "");
+        assert_eq!(*response.get_status(), Status::Deleted);
+        let response = send(Method::Delete, "/coap-group/0", "");
+        assert_eq!(*response.get_status(), Status::NotFound);
+        let response = send(Method::Put, "/coap-group", "");
+        assert_eq!(*response.get_status(), Status::MethodNotAllowed);
+    }
+
+    #[test]
+    fn test_pubsub() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    server.set_broker_path(Some(crate::pubsub::DEFAULT_PATH));
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|req: CoapRequest<SocketAddr>| async { req.response })
+                        .await
+                        .unwrap();
+                })
+        });
+        let addr = rx.recv().unwrap();
+        let publisher = CoAPClient::new(addr).unwrap();
+        let send = |method: Method, path: &str, payload: &str| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(method);
+            request.set_path(path);
+            request.message.payload = payload.as_bytes().to_vec();
+            publisher.send(&request).unwrap();
+            publisher.receive().unwrap()
+        };
+
+        let response = send(Method::Post, "/ps", "<temp>;ct=0");
+        assert_eq!(*response.get_status(), Status::Created);
+        let response = send(Method::Put, "/ps/temp", "21.5");
+        assert_eq!(*response.get_status(), Status::Changed);
+
+        let (notify, notifications) = std::sync::mpsc::channel();
+        let mut subscriber = CoAPClient::new(addr).unwrap();
+        subscriber
+            .observe("/ps/temp", move |packet| {
+                notify.send((packet.header.code, packet.payload)).unwrap();
+            })
+            .unwrap();
+        let next = || notifications.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(next().1, b"21.5");
+
+        let response = send(Method::Put, "/ps/temp", "22.0");
+        assert_eq!(*response.get_status(), Status::Changed);
+        assert_eq!(next().1, b"22.0");
+        let response = send(Method::Get, "/ps/temp", "");
+        assert_eq!(response.message.payload, b"22.0");
+
+        let response = send(Method::Delete, "/ps/temp", "");
+        assert_eq!(*response.get_status(), Status::Deleted);
+        assert_eq!(next().0, MessageClass::Response(Status::NotFound));
+        let response = send(Method::Put, "/ps/temp", "22.5");
+        assert_eq!(*response.get_status(), Status::NotFound);
+    }
+
+    #[test]
+    fn test_proxy() {
+        let server_port = spawn_server("127.0.0.1:0", request_handler).recv().unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+        request.set_method(Method::Get);
+        request.set_path("/test-echo");
+        request
+            .message
+            .add_option(CoapOption::ProxyUri, b"coap://example.com/temp".to_vec());
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::ProxyingNotSupported);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    server.set_proxy_handler(|mut req: CoapRequest<SocketAddr>| {
+                        Box::pin(async move {
+                            let target = proxy::target(&req.message)?;
+                            let response = req.response.as_mut()?;
+                            response.message.payload = target.as_str().as_bytes().to_vec();
+                            req.response
+                        }) as ResponseFuture
+                    });
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server.run(request_handler).await.unwrap();
+                })
+        });
+        let client = CoAPClient::new(rx.recv().unwrap()).unwrap();
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.message.payload, b"coap://example.com/temp");
+
+        // options safe to forward are passed on, even if not recognized
+        request.message.add_option(CoapOption::Oscore, vec![0x09]);
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.message.payload, b"coap://example.com/temp");
+        request.message.add_option(CoapOption::Unknown(65006), vec![]);
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::BadGateway);
+        request.message.clear_option(CoapOption::Unknown(65006));
+
+        // the server itself does not support OSCORE
+        request.message.clear_option(CoapOption::ProxyUri);
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::BadOption);
+
+        // other requests still reach the handler
+        request.message.clear_option(CoapOption::Oscore);
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.message.payload, b"test-echo");
+    }
+
+    #[test]
+    fn test_forward_proxy() {
+        let upstream_port = spawn_server("127.0.0.1:0", request_handler).recv().unwrap();
+        // a target that never answers
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    let mut proxy = ForwardProxy::new();
+                    proxy.set_timeout(Duration::from_millis(100));
+                    proxy.set_max_retransmit(0);
+                    server.set_forward_proxy(proxy);
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server.run(request_handler).await.unwrap();
+                })
+        });
+        let client = CoAPClient::new(rx.recv().unwrap()).unwrap();
+        let exchange = |uri: String, hop_limit: Option<u8>| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Get);
+            request.message.set_token(vec![0x42]);
+            request.message.add_option(CoapOption::ProxyUri, uri.into_bytes());
+            if let Some(hop_limit) = hop_limit {
+                request
+                    .message
+                    .add_option(CoapOption::Unknown(proxy::HOP_LIMIT), vec![hop_limit]);
+            }
+            client.send(&request).unwrap();
+            client.receive().unwrap()
+        };
+
+        let uri = format!("coap://127.0.0.1:{}/test-echo", upstream_port);
+        let response = exchange(uri.clone(), None);
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.get_token(), [0x42]);
+        assert_eq!(response.message.payload, b"test-echo");
+        let response = exchange(uri.clone(), Some(2));
+        assert_eq!(response.message.payload, b"test-echo");
+        let response = exchange(uri, Some(1));
+        assert_eq!(*response.get_status(), Status::HopLimitReached);
+
+        let response = exchange("ftp://127.0.0.1/test-echo".to_string(), None);
+        assert_eq!(*response.get_status(), Status::ProxyingNotSupported);
+        let uri = format!("coap://{}/test-echo", silent.local_addr().unwrap());
+        let response = exchange(uri, None);
+        assert_eq!(*response.get_status(), Status::GatewayTimeout);
+    }
+
+    #[test]
+    fn test_caching_proxy() {
+        use crate::cache::ResponseCache;
+        use coap_lite::option_value::OptionValueU32;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let upstream_calls = calls.clone();
+        let upstream_port = spawn_server("127.0.0.1:0", move |req: CoapRequest<SocketAddr>| {
+            upstream_calls.fetch_add(1, Ordering::SeqCst);
+            let mut response = req.response;
+            if let Some(ref mut response) = response {
+                let etag = req.message.get_option(CoapOption::ETag);
+                if etag.is_some_and(|values| values.contains(&vec![1])) {
+                    response.set_status(Status::Valid);
+                    response
+                        .message
+                        .add_option_as(CoapOption::MaxAge, OptionValueU32(60));
+                } else {
+                    // stale at once, to be validated the next time
+                    response
+                        .message
+                        .add_option_as(CoapOption::MaxAge, OptionValueU32(0));
+                    response.message.payload = b"21.5".to_vec();
+                }
+                response.message.add_option(CoapOption::ETag, vec![1]);
+            }
+            async { response }
+        })
+        .recv()
+        .unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    let mut proxy = ForwardProxy::new();
+                    proxy.set_cache(Some(ResponseCache::new()));
+                    server.set_forward_proxy(proxy);
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server.run(request_handler).await.unwrap();
+                })
+        });
+        let client = CoAPClient::new(rx.recv().unwrap()).unwrap();
+        let uri = format!("coap://127.0.0.1:{}/temp", upstream_port);
+        let exchange = |method: Method| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(method);
+            request
+                .message
+                .add_option(CoapOption::ProxyUri, uri.clone().into_bytes());
+            client.send(&request).unwrap();
+            client.receive().unwrap()
+        };
+
+        let response = exchange(Method::Get);
+        assert_eq!(response.message.payload, b"21.5");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // validated with the ETag, and served from the cache
+        let response = exchange(Method::Get);
+        assert_eq!(*response.get_status(), Status::Content);
+        assert_eq!(response.message.payload, b"21.5");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        // fresh now
+        let response = exchange(Method::Get);
+        assert_eq!(response.message.payload, b"21.5");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        exchange(Method::Put);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        exchange(Method::Get);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[test]
+    fn test_observe_proxy() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    tx.send((server.socket_addr().unwrap(), server.observe_registry()))
+                        .unwrap();
+                    server.run(request_handler).await.unwrap();
+                })
+        });
+        let (upstream_addr, upstream) = rx.recv().unwrap();
+        upstream.resource_changed("temp", b"21.5".to_vec());
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    let mut proxy = ForwardProxy::new();
+                    proxy.set_timeout(Duration::from_millis(500));
+                    server.set_forward_proxy(proxy);
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server.run(request_handler).await.unwrap();
+                })
+        });
+        let proxy_addr = rx.recv().unwrap();
+
+        let uri = format!("coap://{}/temp", upstream_addr);
+        let (tx, rx) = mpsc::channel();
+        let observe = |tx: mpsc::Sender<Vec<u8>>| {
+            let mut client = CoAPClient::new(proxy_addr).unwrap();
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request
+                .message
+                .add_option(CoapOption::ProxyUri, uri.clone().into_bytes());
+            let handler = move |message: Packet| tx.send(message.payload).unwrap();
+            client
+                .observe_request(&request, handler, Duration::from_secs(1))
+                .unwrap();
+            client
+        };
+        let mut first = observe(tx.clone());
+        let mut second = observe(tx);
+        for _ in 0..2 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), b"21.5");
+        }
+        // the target is observed once for both clients
+        assert_eq!(upstream.observers("temp"), Some(1));
+
+        upstream.resource_changed("temp", b"22.0".to_vec());
+        for _ in 0..2 {
+            assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), b"22.0");
+        }
+
+        first.unobserve();
+        assert_eq!(upstream.observers("temp"), Some(1));
+        second.unobserve();
+        // the proxy deregisters with the target on a thread of its own
+        for _ in 0..50 {
+            if upstream.observers("temp") == Some(0) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(upstream.observers("temp"), Some(0));
+    }
+
+    #[test]
+    fn test_blockwise_proxy() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let body: Vec<u8> = (0..100).collect();
+        let representation = body.clone();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let upstream_calls = calls.clone();
+        let upstream_port = spawn_server("127.0.0.1:0", move |req: CoapRequest<SocketAddr>| {
+            upstream_calls.fetch_add(1, Ordering::SeqCst);
+            let mut response = req.response;
+            if let Some(ref mut response) = response {
+                response.message.payload = representation.clone();
+            }
+            async { response }
+        })
+        .recv()
+        .unwrap();
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    let mut proxy = ForwardProxy::new();
+                    proxy.set_block_size(Some(40));
+                    server.set_forward_proxy(proxy);
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server.run(request_handler).await.unwrap();
+                })
+        });
+        let client = CoAPClient::new(rx.recv().unwrap()).unwrap();
+        let uri = format!("coap://127.0.0.1:{}/log", upstream_port);
+        let exchange = |block2: Option<BlockValue>| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Get);
+            request
+                .message
+                .add_option(CoapOption::ProxyUri, uri.clone().into_bytes());
+            if let Some(block2) = block2 {
+                request.message.add_option_as(CoapOption::Block2, block2);
+            }
+            client.send(&request).unwrap();
+            let response = client.receive().unwrap().message;
+            let block = response.get_first_option_as::<BlockValue>(CoapOption::Block2);
+            let block = block.unwrap().unwrap();
+            ((block.num, block.more, block.size()), response.payload)
+        };
+
+        // blocks of the size set, all from one exchange with the target
+        assert_eq!(exchange(None), ((0, true, 32), body[..32].to_vec()));
+        let block2 = |num| Some(BlockValue::new(num, false, 32).unwrap());
+        assert_eq!(exchange(block2(1)), ((1, true, 32), body[32..64].to_vec()));
+        assert_eq!(exchange(block2(2)), ((2, true, 32), body[64..96].to_vec()));
+        assert_eq!(exchange(block2(3)), ((3, false, 32), body[96..].to_vec()));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // a client asking for smaller blocks gets them
+        let block2 = Some(BlockValue::new(0, false, 16).unwrap());
+        assert_eq!(exchange(block2), ((0, true, 16), body[..16].to_vec()));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_reverse_proxy() {
+        let upstream_port = spawn_server("127.0.0.1:0", request_handler).recv().unwrap();
+        let upstream = format!("coap://127.0.0.1:{}", upstream_port);
+        let proxy = proxy::ReverseProxy::new()
+            .with_route("/devices/1", upstream.parse().unwrap())
+            .with_timeout(Duration::from_millis(500), 0);
+        let server_port = spawn_server("127.0.0.1:0", move |request| proxy.handle(request))
+            .recv()
+            .unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+        request.set_method(Method::Get);
+        request.set_path("/devices/1/test-echo");
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.message.payload, b"test-echo");
+
+        request.set_path("/devices/2/test-echo");
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::NotFound);
+    }
+
+    #[test]
+    fn test_balanced_proxy() {
+        let upstream_port = spawn_server("127.0.0.1:0", request_handler).recv().unwrap();
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstreams = vec![
+            format!("coap://127.0.0.1:{}", upstream_port).parse().unwrap(),
+            format!("coap://{}", silent.local_addr().unwrap()).parse().unwrap(),
+        ];
+        let proxy = proxy::ReverseProxy::new()
+            .with_pool("/", upstreams, proxy::Balance::RoundRobin)
+            .with_timeout(Duration::from_millis(200), 0)
+            .with_health_checks(Duration::from_millis(50), 1);
+        let server_port = spawn_server("127.0.0.1:0", move |request| proxy.handle(request))
+            .recv()
+            .unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+        request.set_method(Method::Get);
+        request.set_path("/test-echo");
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(response.message.payload, b"test-echo");
+
+        // the silent server fails its first ping and is left out
+        std::thread::sleep(Duration::from_millis(500));
+        for _ in 0..3 {
+            client.send(&request).unwrap();
+            let response = client.receive().unwrap();
+            assert_eq!(response.message.payload, b"test-echo");
+        }
+    }
+
+    #[test]
+    fn test_proxy_loop() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    let addr = server.socket_addr().unwrap();
+                    // each hop waits for the next one
+                    server.set_max_concurrent_handlers(8);
+                    // a proxy forwarding to itself
+                    let proxy = proxy::ReverseProxy::new()
+                        .with_route("/", format!("coap://{}", addr).parse().unwrap())
+                        .with_hop_limit(4);
+                    tx.send(addr).unwrap();
+                    server.run(move |request| proxy.handle(request)).await.unwrap();
+                })
+        });
+        let client = CoAPClient::new(rx.recv().unwrap()).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+        request.set_method(Method::Get);
+        request.set_path("/test-echo");
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::HopLimitReached);
+    }
+
+    #[test]
+    fn test_critical_options() {
+        let server_port = spawn_server("127.0.0.1:0", request_handler).recv().unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let send = |message_type: MessageType, option: u16| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Get);
+            request.set_path("/test");
+            request.message.header.set_type(message_type);
+            request.message.add_option(CoapOption::Unknown(option), vec![1]);
+            client.send(&request).unwrap();
+            client.receive()
+        };
+
+        // odd option numbers are critical, even ones elective
+        let response = send(MessageType::Confirmable, 65000).unwrap();
+        assert_eq!(response.message.payload, b"test".to_vec());
+        let response = send(MessageType::Confirmable, 65001).unwrap();
+        assert_eq!(*response.get_status(), Status::BadOption);
+        assert_eq!(
+            response.message.payload,
+            b"Unrecognized critical option 65001".to_vec()
+        );
+        assert!(send(MessageType::NonConfirmable, 65001).is_err());
+        let response = send(MessageType::Confirmable, qblock::Q_BLOCK2).unwrap();
+        assert_eq!(*response.get_status(), Status::Content);
+    }
+
+    #[test]
+    fn test_well_known_core() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::Builder::new()
+            .name(String::from("server"))
+            .spawn(move || {
+                tokio::runtime::Runtime::new()
+                    .unwrap()
+                    .block_on(async move {
+                        let mut server = server::Server::new("127.0.0.1:0").unwrap();
+                        server.add_link(
+                            Link::new("/sensors/temp")
+                                .with_attribute("rt", "temperature-c")
+                                .with_flag("obs"),
+                        );
+                        server.add_link(Link::new("/sensors/light").with_attribute("rt", "light-lux"));
+
+                        tx.send(server.socket_addr().unwrap().port()).unwrap();
+
+                        server.run(request_handler).await.unwrap();
+                    })
+            })
+            .unwrap();
+        let server_port = rx.recv().unwrap();
+
+        let links = CoAPClient::discover(&format!("coap://127.0.0.1:{}", server_port)).unwrap();
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].target, "/sensors/temp");
+        assert!(links[0].has_attribute("obs"));
+
+        let links =
+            CoAPClient::discover(&format!("coap://127.0.0.1:{}/?rt=light*", server_port)).unwrap();
+        assert_eq!(links, vec![Link::new("/sensors/light").with_attribute("rt", "light-lux")]);
+    }
+
+    #[test]
+    fn test_well_known_core_blocks() {
+        use crate::transport::MemoryTransport;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _runtime = runtime.enter();
+        let transport = MemoryTransport::new("10.0.0.1:5683".parse().unwrap());
+        let mut server: Server<std::future::Ready<Option<CoapResponse>>> =
+            Server::from_transport(transport);
+        for n in 0..100 {
+            server.add_link(Link::new(&format!("/sensors/{}", n)).with_attribute("rt", "temp"));
+        }
+        let document = link_format::serialize(server.links()).into_bytes();
+        let addr: SocketAddr = "10.0.0.2:5683".parse().unwrap();
+        let discover = |server: &mut Server<_>, block2: Option<BlockValue>| {
+            let mut packet = Packet::new();
+            packet.header.code = MessageClass::Request(Method::Get);
+            packet.add_option(CoapOption::UriPath, b".well-known".to_vec());
+            packet.add_option(CoapOption::UriPath, b"core".to_vec());
+            if let Some(block2) = block2 {
+                packet.add_option_as(CoapOption::Block2, block2);
+            }
+            let mut request = CoapRequest::from_packet(packet, addr);
+            assert!(server.handle_well_known_core(&mut request, addr));
+            request.response.unwrap().message
+        };
+
+        // cut into blocks that fit the path MTU, the first with Size2
+        let first = discover(&mut server, None);
+        let block = first.get_first_option_as::<BlockValue>(CoapOption::Block2);
+        let block = block.unwrap().unwrap();
+        assert_eq!((block.num, block.more, block.size()), (0, true, 1024));
+        assert_eq!(first.payload, document[..1024]);
+        assert_eq!(payload::size2(&first), Some(document.len()));
+
+        // a larger block asked for is cut to the path MTU as well
+        server.set_default_path_mtu(Some(600));
+        let second = discover(&mut server, Some(BlockValue::new(1, false, 1024).unwrap()));
+        let block = second.get_first_option_as::<BlockValue>(CoapOption::Block2);
+        let block = block.unwrap().unwrap();
+        assert_eq!((block.num, block.size()), (2, 512));
+        assert_eq!(second.payload, document[1024..1536]);
+        assert_eq!(payload::size2(&second), None);
+
+        let beyond = discover(&mut server, Some(BlockValue::new(100, false, 512).unwrap()));
+        assert_eq!(beyond.header.code, MessageClass::Response(Status::BadOption));
+
+        // a new link replaces the document
+        server.set_default_path_mtu(None);
+        server.add_link(Link::new("/x"));
+        let num = document.len() / 1024;
+        let last = discover(&mut server, Some(BlockValue::new(num, false, 1024).unwrap()));
+        assert!(last.payload.ends_with(b",</x>"));
+    }
+
     #[test]
     fn multicast_server_all_coap() {
         // segment not relevant with IPv4
@@ -773,4 +2737,467 @@ pub mod test {
 
         std::thread::sleep(std::time::Duration::from_secs(1));
     }
+
+    #[test]
+    fn test_signaling_not_dispatched() {
+        use crate::transport::{ClientTransport, MemoryTransport};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let server_addr: SocketAddr = "10.0.0.1:5683".parse().unwrap();
+        let transport = MemoryTransport::new(server_addr);
+        let endpoint = transport.connect("10.0.0.2:5683".parse().unwrap());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler_calls = calls.clone();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                Server::from_transport(transport)
+                    .run(move |req: CoapRequest<SocketAddr>| {
+                        handler_calls.fetch_add(1, Ordering::SeqCst);
+                        async { req.response }
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+
+        let mut release = Packet::new();
+        release.header.code = Signal::Release.message_class();
+        let mut ping = Packet::new();
+        ping.header.code = Signal::Ping.message_class();
+        ping.set_token(vec![4, 2]);
+        for packet in [release, ping] {
+            endpoint
+                .send_to(&packet.to_bytes().unwrap(), &server_addr)
+                .unwrap();
+        }
+
+        endpoint
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0; 1500];
+        let (n, _) = endpoint.recv_from(&mut buf).unwrap();
+        let pong = Packet::from_bytes(&buf[..n]).unwrap();
+        assert_eq!(Signal::from_packet(&pong), Some(Signal::Pong));
+        assert_eq!(pong.get_token(), &[4, 2]);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn test_several_transports() {
+        use crate::transport::MemoryTransport;
+
+        // the same peer address on both transports, as with UDP and TCP
+        let server_addr: SocketAddr = "10.0.0.1:5683".parse().unwrap();
+        let peer: SocketAddr = "10.0.0.2:5683".parse().unwrap();
+        let udp = MemoryTransport::new(server_addr);
+        let tcp = MemoryTransport::new(server_addr);
+        let endpoints = [udp.connect(peer), tcp.connect(peer)];
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::from_transport(udp);
+                assert_eq!(server.add_transport(tcp), 1);
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        let mut response = req.response?;
+                        response.message.payload = ingress()?.to_string().into_bytes();
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        assert_eq!(ingress(), None);
+
+        for (index, endpoint) in endpoints.into_iter().enumerate().rev() {
+            let mut client = CoAPClient::from_transport(endpoint, server_addr).unwrap();
+            let response = client
+                .request_path("/", Method::Get, None, None, None)
+                .unwrap();
+            assert_eq!(response.message.payload, index.to_string().into_bytes());
+        }
+    }
+
+    #[test]
+    fn test_group_sends() {
+        use crate::transport::{ClientTransport, MemoryTransport};
+        use futures::task::AtomicWaker;
+        use futures::{Sink, SinkExt};
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        /// A transport of group requests that takes no messages until
+        /// opened.
+        struct Stalled {
+            inner: MemoryTransport,
+            open: Arc<AtomicBool>,
+            waker: Arc<AtomicWaker>,
+        }
+        impl Transport for Stalled {
+            fn local_addr(&self) -> io::Result<SocketAddr> {
+                self.inner.local_addr()
+            }
+            fn multicast_group(&self, _addr: &SocketAddr) -> Option<IpAddr> {
+                Some(IpAddr::V4(Ipv4Addr::new(224, 0, 1, 187)))
+            }
+        }
+        impl Stream for Stalled {
+            type Item = io::Result<(Packet, SocketAddr)>;
+            fn poll_next(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+            ) -> Poll<Option<Self::Item>> {
+                self.inner.poll_next_unpin(cx)
+            }
+        }
+        impl Sink<(Packet, SocketAddr)> for Stalled {
+            type Error = io::Error;
+            fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.waker.register(cx.waker());
+                match self.open.load(Ordering::SeqCst) {
+                    true => Poll::Ready(Ok(())),
+                    false => Poll::Pending,
+                }
+            }
+            fn start_send(mut self: Pin<&mut Self>, item: (Packet, SocketAddr)) -> io::Result<()> {
+                self.inner.start_send_unpin(item)
+            }
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.inner.poll_flush_unpin(cx)
+            }
+            fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+                self.inner.poll_close_unpin(cx)
+            }
+        }
+
+        let server_addr: SocketAddr = "10.0.0.1:5683".parse().unwrap();
+        let unicast = MemoryTransport::new(server_addr);
+        let group = MemoryTransport::new(server_addr);
+        let client = unicast.connect("10.0.0.2:5683".parse().unwrap());
+        let member = group.connect("10.0.0.3:5683".parse().unwrap());
+        let open = Arc::new(AtomicBool::new(false));
+        let waker = Arc::new(AtomicWaker::new());
+        let group = Stalled {
+            inner: group,
+            open: open.clone(),
+            waker: waker.clone(),
+        };
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::from_transport(unicast);
+                server.add_transport(group);
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async { req.response })
+                    .await
+                    .unwrap();
+            })
+        });
+
+        let mut request = Packet::new();
+        request.header.set_type(MessageType::NonConfirmable);
+        request.header.code = MessageClass::Request(Method::Get);
+        member
+            .send_to(&request.to_bytes().unwrap(), &server_addr)
+            .unwrap();
+        member
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        let mut buf = [0; 1500];
+        assert!(member.recv_from(&mut buf).is_err());
+
+        // the stalled group response holds up no one else
+        let mut client = CoAPClient::from_transport(client, server_addr).unwrap();
+        let response = client.request_path("/", Method::Get, None, None, None);
+        assert!(response.is_ok());
+
+        open.store(true, Ordering::SeqCst);
+        waker.wake();
+        member.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert!(member.recv_from(&mut buf).is_ok());
+    }
+
+    #[test]
+    fn test_handler_concurrency() {
+        use crate::transport::{ClientTransport, MemoryTransport};
+        use std::time::Instant;
+
+        let server_addr: SocketAddr = "10.0.0.1:5683".parse().unwrap();
+        let transport = MemoryTransport::new(server_addr);
+        let endpoint = transport.connect("10.0.0.2:5683".parse().unwrap());
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::from_transport(transport);
+                server.set_max_concurrent_handlers(2);
+                server.set_handler_overflow(HandlerOverflow::Reject(Duration::from_secs(2)));
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        tokio::time::sleep(Duration::from_millis(300)).await;
+                        req.response
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+
+        let start = Instant::now();
+        for id in 0..3 {
+            let mut request = Packet::new();
+            request.header.set_type(MessageType::NonConfirmable);
+            request.header.code = MessageClass::Request(Method::Get);
+            request.header.message_id = id;
+            endpoint
+                .send_to(&request.to_bytes().unwrap(), &server_addr)
+                .unwrap();
+        }
+        endpoint
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buf = [0; 1500];
+        let mut codes = Vec::new();
+        for _ in 0..3 {
+            let (n, _) = endpoint.recv_from(&mut buf).unwrap();
+            let response = Packet::from_bytes(&buf[..n]).unwrap();
+            if response.header.code == MessageClass::Response(Status::ServiceUnavailable) {
+                let max_age = response.get_first_option_as::<OptionValueU32>(CoapOption::MaxAge);
+                assert_eq!(max_age.unwrap().unwrap().0, 2);
+            }
+            codes.push(response.header.code);
+        }
+        // the third is turned away at once, the others handled side by side
+        assert_eq!(codes[0], MessageClass::Response(Status::ServiceUnavailable));
+        assert_eq!(codes[1..], [MessageClass::Response(Status::Content); 2]);
+        assert!(start.elapsed() < Duration::from_millis(550));
+    }
+
+    #[test]
+    fn test_amplification_limit() {
+        use crate::echo::EchoPolicy;
+        use crate::transport::MemoryTransport;
+
+        let spawn = |server_addr: &str, echo: bool| {
+            let server_addr: SocketAddr = server_addr.parse().unwrap();
+            let transport = MemoryTransport::new(server_addr);
+            let endpoint = transport.connect("10.0.0.2:5683".parse().unwrap());
+            std::thread::spawn(move || {
+                tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                    let mut server = Server::from_transport(transport);
+                    server.set_amplification_limit(Some(DEFAULT_AMPLIFICATION_FACTOR));
+                    if echo {
+                        server.set_echo_policy(Some(EchoPolicy::new()));
+                    }
+                    server
+                        .run(|req: CoapRequest<SocketAddr>| async move {
+                            let len = if req.get_path() == "big" { 1000 } else { 10 };
+                            let mut response = req.response?;
+                            response.message.payload = vec![0; len];
+                            Some::<CoapResponse>(response)
+                        })
+                        .await
+                        .unwrap();
+                })
+            });
+            let client = CoAPClient::from_transport(endpoint, server_addr).unwrap();
+            client
+                .set_receive_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            client
+        };
+
+        let mut client = spawn("10.0.0.1:5683", false);
+        let response = client
+            .request_path("/small", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload.len(), 10);
+        assert!(client
+            .request_path("/big", Method::Get, None, None, None)
+            .is_err());
+
+        // the client answers the Echo challenge, which verifies its address
+        let mut client = spawn("10.0.0.3:5683", true);
+        let response = client
+            .request_path("/big", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload.len(), 1000);
+    }
+
+    #[test]
+    fn test_max_request_size() {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    server.set_max_request_size(Some(64));
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|req: CoapRequest<SocketAddr>| async { req.response })
+                        .await
+                        .unwrap();
+                })
+        });
+        let client = CoAPClient::new(rx.recv().unwrap()).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let upload = |payload: usize, block: Option<BlockValue>| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Put);
+            request.set_path("/upload");
+            request.message.payload = vec![0; payload];
+            if let Some(block) = block {
+                request.message.add_option_as(CoapOption::Block1, block);
+            }
+            client.send(&request).unwrap();
+            client.receive().unwrap()
+        };
+
+        let response = upload(64, None);
+        assert_ne!(*response.get_status(), Status::RequestEntityTooLarge);
+        let response = upload(65, None);
+        assert_eq!(*response.get_status(), Status::RequestEntityTooLarge);
+        let size1 = response
+            .message
+            .get_first_option_as::<OptionValueU32>(CoapOption::Size1)
+            .unwrap()
+            .unwrap();
+        assert_eq!(size1.0, 64);
+
+        // the third block of 32 bytes goes beyond the limit
+        let response = upload(32, Some(BlockValue::new(1, true, 32).unwrap()));
+        assert_ne!(*response.get_status(), Status::RequestEntityTooLarge);
+        let response = upload(32, Some(BlockValue::new(2, false, 32).unwrap()));
+        assert_eq!(*response.get_status(), Status::RequestEntityTooLarge);
+
+        // a body announced in Size1 is turned away with its first block
+        let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+        request.set_method(Method::Put);
+        request.set_path("/upload");
+        request.message.payload = vec![0; 32];
+        request
+            .message
+            .add_option_as(CoapOption::Block1, BlockValue::new(0, true, 32).unwrap());
+        payload::set_size(&mut request.message, CoapOption::Size1, 96);
+        client.send(&request).unwrap();
+        let response = client.receive().unwrap();
+        assert_eq!(*response.get_status(), Status::RequestEntityTooLarge);
+        assert_eq!(payload::size1(&response.message), Some(64));
+    }
+
+    #[test]
+    fn test_response_cache() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let (tx, rx) = mpsc::channel();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler_calls = calls.clone();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    server.set_response_cache(Some(ResponseCache::new()));
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(move |req: CoapRequest<SocketAddr>| {
+                            let call = handler_calls.fetch_add(1, Ordering::SeqCst);
+                            async move {
+                                let mut response = req.response?;
+                                response.message.payload = vec![call as u8];
+                                Some(response)
+                            }
+                        })
+                        .await
+                        .unwrap();
+                })
+        });
+        let mut client = CoAPClient::new(rx.recv().unwrap()).unwrap();
+        let mut request = |method| {
+            let response = client.request_path("/data", method, None, None, None);
+            response.unwrap().message.payload
+        };
+
+        assert_eq!(request(Method::Get), [0]);
+        assert_eq!(request(Method::Get), [0]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // a PUT changes the resource
+        request(Method::Put);
+        assert_eq!(request(Method::Get), [2]);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_size2() {
+        let server_port = spawn_server("127.0.0.1:0", |req: CoapRequest<SocketAddr>| async {
+            let mut response = req.response?;
+            response.message.payload = vec![0; 100];
+            Some(response)
+        })
+        .recv()
+        .unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+        request.set_method(Method::Get);
+        request.set_path("/data");
+        client.send(&request).unwrap();
+        assert_eq!(payload::size2(&client.receive().unwrap().message), None);
+
+        // Size2 0 in a request asks for the size of the body
+        payload::set_size(&mut request.message, CoapOption::Size2, 0);
+        client.send(&request).unwrap();
+        assert_eq!(payload::size2(&client.receive().unwrap().message), Some(100));
+    }
+
+    #[test]
+    fn test_block2_slicing() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let body: Vec<u8> = (0..1000).map(|n| n as u8).collect();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let (handler_body, handler_calls) = (body.clone(), calls.clone());
+        let server_port = spawn_server("127.0.0.1:0", move |req: CoapRequest<SocketAddr>| {
+            handler_calls.fetch_add(1, Ordering::SeqCst);
+            let body = handler_body.clone();
+            async move {
+                let mut response = req.response?;
+                response.message.payload = body;
+                Some(response)
+            }
+        })
+        .recv()
+        .unwrap();
+        let client = CoAPClient::new(format!("127.0.0.1:{}", server_port)).unwrap();
+        client
+            .set_receive_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let get = |message_id: u16, num: usize| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Get);
+            request.set_path("/large");
+            request.message.header.message_id = message_id;
+            request.message.set_token(vec![1]);
+            let block2 = BlockValue::new(num, false, 64).unwrap();
+            request.message.add_option_as(CoapOption::Block2, block2);
+            client.send(&request).unwrap();
+            client.receive().unwrap().message
+        };
+
+        // the handler answers once, the server slices its representation
+        let first = get(1, 0);
+        let block = first.get_first_option_as::<BlockValue>(CoapOption::Block2);
+        let block = block.unwrap().unwrap();
+        assert_eq!((block.num, block.more, block.size()), (0, true, 64));
+        assert_eq!(first.payload, body[..64]);
+
+        let fourth = get(2, 3);
+        let block = fourth.get_first_option_as::<BlockValue>(CoapOption::Block2);
+        let block = block.unwrap().unwrap();
+        assert_eq!((block.num, block.more, block.size()), (3, true, 64));
+        assert_eq!(fourth.payload, body[192..256]);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
 }
@@ -0,0 +1,60 @@
+//! *Too Many Requests* responses
+//! ([RFC 8516](https://tools.ietf.org/html/rfc8516)).
+//!
+//! An overloaded server answers 4.29 Too Many Requests, with the time after
+//! which the client may repeat the request in the Max-Age option. The client
+//! waits that long and repeats the request once, see
+//! [`CoAPClient::set_max_backoff`](crate::CoAPClient::set_max_backoff).
+use coap_lite::{
+    option_value::OptionValueU32, CoapOption, CoapResponse, MessageClass, Packet,
+    ResponseType as Status,
+};
+use std::time::Duration;
+
+/// The back-off of a 4.29 response without Max-Age, the default value of
+/// the option.
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(60);
+
+/// Turn `response` into 4.29 Too Many Requests, asking the client to wait
+/// `retry_after`, rounded up to whole seconds, before repeating the request.
+pub fn reject(response: &mut CoapResponse, retry_after: Duration) {
+    let mut seconds = retry_after.as_secs();
+    if retry_after.subsec_nanos() > 0 {
+        seconds += 1;
+    }
+    response.set_status(Status::TooManyRequests);
+    response.message.payload.clear();
+    response.message.clear_option(CoapOption::MaxAge);
+    response.message.add_option_as(
+        CoapOption::MaxAge,
+        OptionValueU32(u32::try_from(seconds).unwrap_or(u32::MAX)),
+    );
+}
+
+/// Return how long to wait before repeating a request answered with
+/// `response`, or `None` if it is no 4.29 response.
+pub fn retry_after(response: &Packet) -> Option<Duration> {
+    if response.header.code != MessageClass::Response(Status::TooManyRequests) {
+        return None;
+    }
+    let max_age = response
+        .get_first_option_as::<OptionValueU32>(CoapOption::MaxAge)
+        .and_then(|value| value.ok());
+    Some(max_age.map_or(DEFAULT_MAX_AGE, |value| Duration::from_secs(value.0.into())))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_retry_after() {
+        let mut response = CoapResponse::new(&Packet::new()).unwrap();
+        assert_eq!(retry_after(&response.message), None);
+        reject(&mut response, Duration::from_millis(2500));
+        assert_eq!(*response.get_status(), Status::TooManyRequests);
+        assert_eq!(retry_after(&response.message), Some(Duration::from_secs(3)));
+        response.message.clear_option(CoapOption::MaxAge);
+        assert_eq!(retry_after(&response.message), Some(DEFAULT_MAX_AGE));
+    }
+}
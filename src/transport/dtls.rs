@@ -0,0 +1,1983 @@
+//! CoAP over DTLS (coaps, [RFC 7252](https://tools.ietf.org/html/rfc7252)
+//! section 9).
+//!
+//! Clients authenticate with a pre-shared key ([`PskConfig`]), with X.509
+//! certificates ([`CertificateConfig`]) or with raw public keys
+//! ([`RawPublicKeyConfig`]); the server, [`DtlsTransport`], uses
+//! pre-shared keys of its clients ([`PskServerConfig`]), certificates or
+//! raw public keys and can require them from clients as well. Requires the
+//! `dtls` feature, which links OpenSSL. The mandatory cipher suites `TLS_PSK_WITH_AES_128_CCM_8` and
+//! `TLS_ECDHE_ECDSA_WITH_AES_128_CCM_8` are offered first, followed by their
+//! AES-GCM and AES-CBC counterparts.
+//!
+//! The server answers a ClientHello with a HelloVerifyRequest (RFC 6347
+//! section 4.2.1) and only sets up a session once the client returns the
+//! cookie in it, which shows that the client receives at its address. The
+//! cookie is checked without keeping any state, so spoofed ClientHellos
+//! cost the server nothing but the answer. Clients that do show their
+//! address are limited in the number of handshakes they start.
+//!
+//! The credentials of a running server can be replaced through its
+//! [`ServerCredentials`], e.g. to rotate certificates or update the keys of
+//! the clients, without dropping the established sessions.
+//!
+//! Clients can keep sessions in a [`SessionStore`], such as a
+//! [`SessionCache`], and resume them when they reconnect, e.g. after
+//! sleeping, instead of paying for a full handshake.
+use coap_lite::Packet;
+use futures::channel::mpsc::{self as futures_mpsc, UnboundedReceiver, UnboundedSender};
+use futures::{Sink, Stream, StreamExt};
+use log::debug;
+use lru_time_cache::LruCache;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{self, IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub use openssl;
+
+use openssl::asn1::Asn1Time;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::nid::Nid;
+use openssl::pkey::{HasPublic, Id, PKey, PKeyRef, Private, Public};
+use openssl::rand::rand_bytes;
+use openssl::sign::Signer;
+use openssl::ssl::{
+    ErrorCode, HandshakeError, Ssl, SslContext, SslContextBuilder, SslMethod, SslOptions, SslRef,
+    SslSession, SslStream, SslVerifyMode,
+};
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::{X509NameBuilder, X509NameRef, X509StoreContextRef, X509};
+
+use super::{ClientTransport, PeerIdentity, Transport};
+use crate::audit::{self, SecurityEvent, SecurityEventHandler};
+
+/// Default port of coaps.
+pub const DEFAULT_PORT: u16 = 5684;
+
+/// Default time allowed for the handshake.
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const PSK_CIPHER_LIST: &str = "PSK-AES128-CCM8:PSK-AES128-GCM-SHA256:PSK-AES128-CBC-SHA256";
+
+const CERTIFICATE_CIPHER_LIST: &str = "ECDHE-ECDSA-AES128-CCM8:ECDHE-ECDSA-AES128-GCM-SHA256:\
+                                       ECDHE-RSA-AES128-GCM-SHA256:ECDHE-ECDSA-AES128-SHA256";
+
+/// How long to wait for a handshake message before letting OpenSSL
+/// retransmit its last flight.
+const HANDSHAKE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Distinguishes the sessions of this crate in the cache of a server, which
+/// needs it to resume sessions of verified clients.
+const SESSION_ID_CONTEXT: &[u8] = b"coap";
+
+/// Default number of handshakes a client address may start per
+/// [`DEFAULT_HANDSHAKE_RATE_INTERVAL`].
+pub const DEFAULT_HANDSHAKE_RATE_LIMIT: u32 = 10;
+
+/// Default interval of the handshake rate limit.
+pub const DEFAULT_HANDSHAKE_RATE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many client addresses the handshake rate limit keeps track of.
+const RATE_LIMIT_CAPACITY: usize = 4096;
+
+/// Largest datagram sent, which fits the IPv6 minimum MTU of 1280 bytes.
+const DATAGRAM_MTU: u32 = 1232;
+
+/// How often the receive thread of a [`DtlsTransport`] checks whether the
+/// transport has been dropped.
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Pre-shared key credentials of a DTLS client.
+#[derive(Clone)]
+pub struct PskConfig {
+    identity: Vec<u8>,
+    key: Vec<u8>,
+    handshake_timeout: Duration,
+    session_store: Option<Arc<dyn SessionStore>>,
+}
+
+impl PskConfig {
+    /// Create credentials from the PSK identity and the key.
+    pub fn new<I: Into<Vec<u8>>, K: Into<Vec<u8>>>(identity: I, key: K) -> PskConfig {
+        PskConfig {
+            identity: identity.into(),
+            key: key.into(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            session_store: None,
+        }
+    }
+
+    /// Give up the handshake with a `TimedOut` error after `timeout`.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Return the time allowed for the handshake.
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// Keep sessions in `store` and resume them on the next connection to
+    /// the same server, saving the full handshake. Use one store per set of
+    /// credentials.
+    pub fn set_session_store(&mut self, store: Arc<dyn SessionStore>) {
+        self.session_store = Some(store);
+    }
+
+    fn context(&self) -> std::result::Result<SslContext, ErrorStack> {
+        let mut builder = SslContextBuilder::new(SslMethod::dtls_client())?;
+        builder.set_options(SslOptions::NO_QUERY_MTU);
+        builder.set_cipher_list(PSK_CIPHER_LIST)?;
+        // CCM_8 is below the default security level of OpenSSL 3
+        builder.set_security_level(0);
+        // there are no certificates with PSK
+        builder.set_verify(SslVerifyMode::NONE);
+        let identity = self.identity.clone();
+        let key = self.key.clone();
+        builder.set_psk_client_callback(move |_ssl, _hint, identity_buf, key_buf| {
+            // the identity is passed on as a C string
+            if identity.len() >= identity_buf.len() || key.len() > key_buf.len() {
+                return Err(ErrorStack::get());
+            }
+            identity_buf[..identity.len()].copy_from_slice(&identity);
+            identity_buf[identity.len()] = 0;
+            key_buf[..key.len()].copy_from_slice(&key);
+            Ok(key.len())
+        });
+        Ok(builder.build())
+    }
+}
+
+/// The pre-shared keys of the clients of a DTLS server, by PSK identity.
+#[derive(Clone)]
+pub struct PskServerConfig {
+    keys: HashMap<Vec<u8>, Vec<u8>>,
+    handshake_timeout: Duration,
+    handshake_rate_limit: (u32, Duration),
+}
+
+impl Default for PskServerConfig {
+    fn default() -> PskServerConfig {
+        PskServerConfig::new()
+    }
+}
+
+impl PskServerConfig {
+    /// Create a configuration without any clients.
+    pub fn new() -> PskServerConfig {
+        PskServerConfig {
+            keys: HashMap::new(),
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            handshake_rate_limit: (
+                DEFAULT_HANDSHAKE_RATE_LIMIT,
+                DEFAULT_HANDSHAKE_RATE_INTERVAL,
+            ),
+        }
+    }
+
+    /// Accept the client with the given PSK identity and key.
+    pub fn add_key<I: Into<Vec<u8>>, K: Into<Vec<u8>>>(&mut self, identity: I, key: K) {
+        self.keys.insert(identity.into(), key.into());
+    }
+
+    /// Give up the handshake with a `TimedOut` error after `timeout`.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Return the time allowed for the handshake.
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// Let a client address start at most `handshakes` handshakes per
+    /// `interval`.
+    pub fn set_handshake_rate_limit(&mut self, handshakes: u32, interval: Duration) {
+        self.handshake_rate_limit = (handshakes, interval);
+    }
+
+    fn context(&self) -> std::result::Result<SslContext, ErrorStack> {
+        let mut builder = SslContextBuilder::new(SslMethod::dtls_server())?;
+        expect_cookies(&mut builder);
+        builder.set_options(SslOptions::NO_QUERY_MTU);
+        builder.set_cipher_list(PSK_CIPHER_LIST)?;
+        builder.set_security_level(0);
+        builder.set_verify(SslVerifyMode::NONE);
+        let keys = self.keys.clone();
+        builder.set_psk_server_callback(move |_ssl, identity, key_buf| {
+            // a length of 0 rejects the identity
+            match identity.and_then(|identity| keys.get(identity)) {
+                Some(key) if key.len() <= key_buf.len() => {
+                    key_buf[..key.len()].copy_from_slice(key);
+                    Ok(key.len())
+                }
+                _ => Ok(0),
+            }
+        });
+        Ok(builder.build())
+    }
+}
+
+/// X.509 certificate credentials of a DTLS client or server.
+///
+/// Peers are verified against the trusted root certificates. A server asks
+/// clients for a certificate if it has roots to verify it with, and turns
+/// away clients without one if `set_require_client_certificate` is on.
+#[derive(Clone, Default)]
+pub struct CertificateConfig {
+    identity: Option<(Vec<X509>, PKey<Private>)>,
+    roots: Vec<X509>,
+    require_client_certificate: bool,
+    handshake_timeout: Option<Duration>,
+    handshake_rate_limit: Option<(u32, Duration)>,
+    session_store: Option<Arc<dyn SessionStore>>,
+}
+
+impl CertificateConfig {
+    /// Create a configuration without own certificate or trusted roots.
+    pub fn new() -> CertificateConfig {
+        CertificateConfig::default()
+    }
+
+    /// Present the given certificate chain, leaf first, and its private key.
+    pub fn set_identity(&mut self, cert_chain: Vec<X509>, key: PKey<Private>) {
+        self.identity = Some((cert_chain, key));
+    }
+
+    /// Trust the given root certificate, e.g. of a CA bundle loaded with
+    /// [`load_certs`].
+    pub fn add_root(&mut self, cert: X509) {
+        self.roots.push(cert);
+    }
+
+    /// Turn away clients without a valid certificate. Only applies to
+    /// servers.
+    pub fn set_require_client_certificate(&mut self, on: bool) {
+        self.require_client_certificate = on;
+    }
+
+    /// Give up the handshake with a `TimedOut` error after `timeout`.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = Some(timeout);
+    }
+
+    /// Return the time allowed for the handshake.
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout.unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT)
+    }
+
+    /// Let a client address start at most `handshakes` handshakes per
+    /// `interval`. Only applies to servers.
+    pub fn set_handshake_rate_limit(&mut self, handshakes: u32, interval: Duration) {
+        self.handshake_rate_limit = Some((handshakes, interval));
+    }
+
+    fn handshake_rate_limit(&self) -> (u32, Duration) {
+        self.handshake_rate_limit.unwrap_or((
+            DEFAULT_HANDSHAKE_RATE_LIMIT,
+            DEFAULT_HANDSHAKE_RATE_INTERVAL,
+        ))
+    }
+
+    /// Keep sessions in `store` and resume them on the next connection to
+    /// the same server, saving the full handshake. Use one store per set of
+    /// credentials.
+    pub fn set_session_store(&mut self, store: Arc<dyn SessionStore>) {
+        self.session_store = Some(store);
+    }
+
+    fn context(&self, method: SslMethod) -> std::result::Result<SslContextBuilder, ErrorStack> {
+        let mut builder = SslContextBuilder::new(method)?;
+        builder.set_options(SslOptions::NO_QUERY_MTU);
+        builder.set_cipher_list(CERTIFICATE_CIPHER_LIST)?;
+        builder.set_session_id_context(SESSION_ID_CONTEXT)?;
+        if let Some((cert_chain, key)) = &self.identity {
+            if let Some((leaf, intermediates)) = cert_chain.split_first() {
+                builder.set_certificate(leaf)?;
+                for cert in intermediates {
+                    builder.add_extra_chain_cert(cert.clone())?;
+                }
+            }
+            builder.set_private_key(key)?;
+            builder.check_private_key()?;
+        }
+        let mut store = X509StoreBuilder::new()?;
+        for root in &self.roots {
+            store.add_cert(root.clone())?;
+        }
+        builder.set_verify_cert_store(store.build())?;
+        Ok(builder)
+    }
+
+    fn client_ssl(&self, server_name: &str) -> std::result::Result<Ssl, ErrorStack> {
+        let mut builder = self.context(SslMethod::dtls_client())?;
+        builder.set_verify(SslVerifyMode::PEER);
+        let mut ssl = Ssl::new(&builder.build())?;
+        match server_name.parse::<IpAddr>() {
+            Ok(ip) => ssl.param_mut().set_ip(ip)?,
+            Err(_) => {
+                ssl.set_hostname(server_name)?;
+                ssl.param_mut().set_host(server_name)?;
+            }
+        }
+        Ok(ssl)
+    }
+
+    fn server_context(&self) -> std::result::Result<SslContext, ErrorStack> {
+        let mut builder = self.context(SslMethod::dtls_server())?;
+        expect_cookies(&mut builder);
+        builder.set_verify(if self.require_client_certificate {
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT
+        } else if !self.roots.is_empty() {
+            SslVerifyMode::PEER
+        } else {
+            SslVerifyMode::NONE
+        });
+        Ok(builder.build())
+    }
+}
+
+/// Raw public key credentials of a DTLS client or server
+/// ([RFC 7250](https://tools.ietf.org/html/rfc7250)).
+///
+/// Peers are authenticated by their public key alone, which must be one of
+/// the pinned keys or pass the key filter. A client only accepts servers
+/// with such a key; a server asks clients for their key only if it has keys
+/// pinned or a filter set, and then turns away clients without an accepted
+/// one.
+///
+/// OpenSSL before 3.2 cannot negotiate the raw public key certificate type,
+/// so the key travels in a minimal self-signed certificate, of which
+/// nothing but the SubjectPublicKeyInfo is looked at. Such peers do not
+/// interoperate with implementations that insist on the RFC 7250 type.
+#[derive(Clone)]
+pub struct RawPublicKeyConfig {
+    key: PKey<Private>,
+    pinned: Vec<Vec<u8>>,
+    key_filter: Option<KeyFilter>,
+    handshake_timeout: Duration,
+    handshake_rate_limit: (u32, Duration),
+    session_store: Option<Arc<dyn SessionStore>>,
+}
+
+/// Decides on the DER encoded SubjectPublicKeyInfo of a peer.
+type KeyFilter = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+impl RawPublicKeyConfig {
+    /// Create credentials from the own private key.
+    pub fn new(key: PKey<Private>) -> RawPublicKeyConfig {
+        RawPublicKeyConfig {
+            key,
+            pinned: Vec::new(),
+            key_filter: None,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            handshake_rate_limit: (
+                DEFAULT_HANDSHAKE_RATE_LIMIT,
+                DEFAULT_HANDSHAKE_RATE_INTERVAL,
+            ),
+            session_store: None,
+        }
+    }
+
+    /// Accept peers authenticating with the given public key.
+    pub fn add_pinned_key<T: HasPublic>(&mut self, key: &PKeyRef<T>) -> Result<()> {
+        let spki = key.public_key_to_der().map_err(ssl_error)?;
+        self.pinned.push(spki);
+        Ok(())
+    }
+
+    /// Also accept peers whose DER encoded SubjectPublicKeyInfo passes
+    /// `filter`, e.g. keys admitted at runtime.
+    pub fn set_key_filter<F: Fn(&[u8]) -> bool + Send + Sync + 'static>(&mut self, filter: F) {
+        self.key_filter = Some(Arc::new(filter));
+    }
+
+    /// Give up the handshake with a `TimedOut` error after `timeout`.
+    pub fn set_handshake_timeout(&mut self, timeout: Duration) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Return the time allowed for the handshake.
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    /// Let a client address start at most `handshakes` handshakes per
+    /// `interval`. Only applies to servers.
+    pub fn set_handshake_rate_limit(&mut self, handshakes: u32, interval: Duration) {
+        self.handshake_rate_limit = (handshakes, interval);
+    }
+
+    /// Keep sessions in `store` and resume them on the next connection to
+    /// the same server, saving the full handshake. Use one store per set of
+    /// credentials.
+    pub fn set_session_store(&mut self, store: Arc<dyn SessionStore>) {
+        self.session_store = Some(store);
+    }
+
+    fn context(
+        &self,
+        method: SslMethod,
+        mode: SslVerifyMode,
+    ) -> std::result::Result<SslContextBuilder, ErrorStack> {
+        let mut builder = SslContextBuilder::new(method)?;
+        builder.set_options(SslOptions::NO_QUERY_MTU);
+        builder.set_cipher_list(CERTIFICATE_CIPHER_LIST)?;
+        builder.set_session_id_context(SESSION_ID_CONTEXT)?;
+        let cert = self.wrap_key()?;
+        builder.set_certificate(&cert)?;
+        builder.set_private_key(&self.key)?;
+        builder.check_private_key()?;
+        let pinned = self.pinned.clone();
+        let key_filter = self.key_filter.clone();
+        builder.set_verify_callback(mode, move |_preverified, context| {
+            // the chain is meaningless, only the key of the peer counts
+            if context.error_depth() > 0 {
+                return true;
+            }
+            peer_key(context).is_some_and(|spki| {
+                pinned.contains(&spki) || key_filter.as_ref().is_some_and(|filter| filter(&spki))
+            })
+        });
+        Ok(builder)
+    }
+
+    fn server_context(&self) -> std::result::Result<SslContext, ErrorStack> {
+        let mode = if self.pinned.is_empty() && self.key_filter.is_none() {
+            SslVerifyMode::NONE
+        } else {
+            SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT
+        };
+        let mut builder = self.context(SslMethod::dtls_server(), mode)?;
+        expect_cookies(&mut builder);
+        Ok(builder.build())
+    }
+
+    /// Wrap the public key in a self-signed certificate.
+    fn wrap_key(&self) -> std::result::Result<X509, ErrorStack> {
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, "raw public key")?;
+        let name = name.build();
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(&name)?;
+        let not_before = Asn1Time::from_unix(0)?;
+        builder.set_not_before(&not_before)?;
+        // the latest time of RFC 5280, "no well-defined expiration date"
+        let not_after = Asn1Time::from_str_x509("99991231235959Z")?;
+        builder.set_not_after(&not_after)?;
+        builder.set_pubkey(&self.key)?;
+        let digest = match self.key.id() {
+            Id::ED25519 | Id::ED448 => MessageDigest::null(),
+            _ => MessageDigest::sha256(),
+        };
+        builder.sign(&self.key, digest)?;
+        Ok(builder.build())
+    }
+}
+
+/// Return the DER encoded SubjectPublicKeyInfo of the certificate being
+/// verified.
+fn peer_key(context: &X509StoreContextRef) -> Option<Vec<u8>> {
+    context
+        .current_cert()
+        .and_then(|cert| cert.public_key().ok())
+        .and_then(|key| key.public_key_to_der().ok())
+}
+
+/// Storage of DTLS sessions for resumption by clients, keyed by server.
+///
+/// Sessions are passed DER encoded so that a store can persist them; they
+/// contain the session secrets and need to be protected like keys.
+pub trait SessionStore: Send + Sync {
+    /// Return the session last stored for `server`.
+    fn get(&self, server: &str) -> Option<Vec<u8>>;
+
+    /// Store the session of `server`, replacing any previous one.
+    fn put(&self, server: &str, session: Vec<u8>);
+
+    /// Forget the session of `server`.
+    fn remove(&self, server: &str);
+}
+
+/// A stored session with the time it was stored.
+type StoredSession = (SystemTime, Vec<u8>);
+
+/// A [`SessionStore`] holding up to a number of sessions for a limited
+/// time, optionally saved to a file to survive restarts.
+pub struct SessionCache {
+    lifetime: Duration,
+    sessions: Mutex<LruCache<String, StoredSession>>,
+    path: Option<PathBuf>,
+}
+
+impl SessionCache {
+    /// Create a cache of up to `capacity` sessions, each resumed for at most
+    /// `lifetime` after it was established.
+    pub fn new(capacity: usize, lifetime: Duration) -> SessionCache {
+        SessionCache {
+            lifetime,
+            sessions: Mutex::new(LruCache::with_capacity(capacity)),
+            path: None,
+        }
+    }
+
+    /// Create a cache like [`SessionCache::new`] that is loaded from the file
+    /// at `path`, if there is one, and saved to it on every change.
+    pub fn persistent<P: Into<PathBuf>>(
+        path: P,
+        capacity: usize,
+        lifetime: Duration,
+    ) -> Result<SessionCache> {
+        let mut cache = SessionCache::new(capacity, lifetime);
+        let path = path.into();
+        match fs::read(&path) {
+            Ok(data) => {
+                let sessions = cache.sessions.get_mut().unwrap();
+                for (server, (stored, session)) in decode_sessions(&data)? {
+                    if !is_expired(stored, lifetime) {
+                        sessions.insert(server, (stored, session));
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        cache.path = Some(path);
+        Ok(cache)
+    }
+
+    fn save(&self, sessions: &LruCache<String, StoredSession>) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        // write a new file and move it into place, never leaving half of one
+        let temporary = path.with_extension("tmp");
+        let result = write_private(&temporary, &encode_sessions(sessions.peek_iter()))
+            .and_then(|_| fs::rename(&temporary, path));
+        if let Err(e) = result {
+            debug!("failed to save DTLS sessions to {}: {}", path.display(), e);
+        }
+    }
+}
+
+impl SessionStore for SessionCache {
+    fn get(&self, server: &str) -> Option<Vec<u8>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(server) {
+            Some((stored, session)) if !is_expired(*stored, self.lifetime) => Some(session.clone()),
+            Some(_) => {
+                sessions.remove(server);
+                self.save(&sessions);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn put(&self, server: &str, session: Vec<u8>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.insert(server.to_string(), (SystemTime::now(), session));
+        self.save(&sessions);
+    }
+
+    fn remove(&self, server: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.remove(server).is_some() {
+            self.save(&sessions);
+        }
+    }
+}
+
+fn is_expired(stored: SystemTime, lifetime: Duration) -> bool {
+    // a clock that went backwards keeps sessions alive, which the server
+    // still limits
+    stored.elapsed().is_ok_and(|age| age > lifetime)
+}
+
+/// Encode sessions as a sequence of server name, time of storage in
+/// seconds since the epoch and session, each length-prefixed.
+fn encode_sessions<'a, I>(sessions: I) -> Vec<u8>
+where
+    I: Iterator<Item = (&'a String, &'a StoredSession)>,
+{
+    let mut data = Vec::new();
+    for (server, (stored, session)) in sessions {
+        let secs = stored
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        data.extend_from_slice(&(server.len() as u16).to_be_bytes());
+        data.extend_from_slice(server.as_bytes());
+        data.extend_from_slice(&secs.to_be_bytes());
+        data.extend_from_slice(&(session.len() as u32).to_be_bytes());
+        data.extend_from_slice(session);
+    }
+    data
+}
+
+fn decode_sessions(mut data: &[u8]) -> Result<Vec<(String, StoredSession)>> {
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+        if data.len() < n {
+            return Err(Error::new(ErrorKind::InvalidData, "truncated session file"));
+        }
+        let (head, rest) = data.split_at(n);
+        *data = rest;
+        Ok(head)
+    }
+
+    let mut sessions = Vec::new();
+    while !data.is_empty() {
+        let len = u16::from_be_bytes(take(&mut data, 2)?.try_into().unwrap());
+        let server = String::from_utf8(take(&mut data, len as usize)?.to_vec())
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let secs = u64::from_be_bytes(take(&mut data, 8)?.try_into().unwrap());
+        let len = u32::from_be_bytes(take(&mut data, 4)?.try_into().unwrap());
+        let session = take(&mut data, len as usize)?.to_vec();
+        sessions.push((server, (UNIX_EPOCH + Duration::from_secs(secs), session)));
+    }
+    Ok(sessions)
+}
+
+/// Write a file only the owner can read.
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(data)
+}
+
+/// Load all certificates of a PEM file.
+pub fn load_certs<P: AsRef<Path>>(path: P) -> Result<Vec<X509>> {
+    X509::stack_from_pem(&fs::read(path)?).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Load the private key of a PEM file.
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> Result<PKey<Private>> {
+    PKey::private_key_from_pem(&fs::read(path)?).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Load the public key of a PEM file, e.g. to pin it.
+pub fn load_public_key<P: AsRef<Path>>(path: P) -> Result<PKey<Public>> {
+    PKey::public_key_from_pem(&fs::read(path)?).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Format a distinguished name like `CN=device-1, O=example`.
+fn format_name(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("?");
+            match entry.data().to_string() {
+                Ok(value) => format!("{}={}", key, value),
+                Err(_) => format!("{}=?", key),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn ssl_error(e: ErrorStack) -> Error {
+    Error::new(ErrorKind::InvalidInput, e)
+}
+
+/// A connected UDP socket as a stream of datagrams.
+#[derive(Debug)]
+struct Datagrams(net::UdpSocket);
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.0.recv(buf)
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.0.send(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct Session {
+    socket: net::UdpSocket,
+    stream: Mutex<SslStream<Datagrams>>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // tell the server, which would otherwise keep the session around
+        let _ = self.stream.lock().unwrap().shutdown();
+    }
+}
+
+/// A DTLS session with a server.
+pub struct DtlsClientTransport {
+    session: Arc<Session>,
+    peer: SocketAddr,
+}
+
+impl DtlsClientTransport {
+    /// Connect to a coaps server and perform the handshake with a pre-shared
+    /// key.
+    pub fn connect<A: ToSocketAddrs>(addr: A, config: &PskConfig) -> Result<DtlsClientTransport> {
+        let ssl = config
+            .context()
+            .and_then(|context| Ssl::new(&context))
+            .map_err(ssl_error)?;
+        let store = config.session_store.as_deref();
+        Self::handshake(addr, ssl, config.handshake_timeout, store, None)
+    }
+
+    /// Connect to a coaps server and perform the handshake with
+    /// certificates. `server_name` is used for SNI and verification of the
+    /// server certificate.
+    pub fn connect_with_certificate<A: ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: &CertificateConfig,
+    ) -> Result<DtlsClientTransport> {
+        let ssl = config.client_ssl(server_name).map_err(ssl_error)?;
+        let store = config.session_store.as_deref();
+        Self::handshake(
+            addr,
+            ssl,
+            config.handshake_timeout(),
+            store,
+            Some(server_name),
+        )
+    }
+
+    /// Connect to a coaps server and perform the handshake with raw public
+    /// keys.
+    pub fn connect_with_raw_public_key<A: ToSocketAddrs>(
+        addr: A,
+        config: &RawPublicKeyConfig,
+    ) -> Result<DtlsClientTransport> {
+        let ssl = config
+            .context(SslMethod::dtls_client(), SslVerifyMode::PEER)
+            .and_then(|builder| Ssl::new(&builder.build()))
+            .map_err(ssl_error)?;
+        let store = config.session_store.as_deref();
+        Self::handshake(addr, ssl, config.handshake_timeout, store, None)
+    }
+
+    /// Return whether the session was resumed rather than established with
+    /// a full handshake.
+    pub fn session_resumed(&self) -> bool {
+        self.session.stream.lock().unwrap().ssl().session_reused()
+    }
+
+    fn handshake<A: ToSocketAddrs>(
+        addr: A,
+        mut ssl: Ssl,
+        timeout: Duration,
+        store: Option<&dyn SessionStore>,
+        server_name: Option<&str>,
+    ) -> Result<DtlsClientTransport> {
+        let peer = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no address"))?;
+        let socket = match peer {
+            SocketAddr::V4(_) => net::UdpSocket::bind("0.0.0.0:0")?,
+            SocketAddr::V6(_) => net::UdpSocket::bind(":::0")?,
+        };
+        socket.connect(peer)?;
+        socket.set_read_timeout(Some(HANDSHAKE_POLL_INTERVAL))?;
+        ssl.set_mtu(DATAGRAM_MTU).map_err(ssl_error)?;
+
+        let server = match server_name {
+            Some(name) => format!("{}@{}", name, peer),
+            None => peer.to_string(),
+        };
+        let cached = store
+            .and_then(|store| store.get(&server))
+            .and_then(|der| SslSession::from_der(&der).ok());
+        if let Some(session) = cached {
+            // SAFETY: a decoded session belongs to no context, so it cannot
+            // be tied to a different one
+            unsafe { ssl.set_session(&session) }.map_err(ssl_error)?;
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut handshake = ssl.connect(Datagrams(socket.try_clone()?));
+        let stream = loop {
+            match handshake {
+                Ok(stream) => break stream,
+                // reading timed out, possibly a flight got lost
+                Err(HandshakeError::WouldBlock(mid)) => {
+                    if Instant::now() >= deadline {
+                        return Err(Error::new(ErrorKind::TimedOut, "DTLS handshake timed out"));
+                    }
+                    handshake = mid.handshake();
+                }
+                Err(HandshakeError::SetupFailure(e)) => return Err(ssl_error(e)),
+                Err(HandshakeError::Failure(mid)) => {
+                    if let Some(store) = store {
+                        store.remove(&server);
+                    }
+                    return Err(Error::new(ErrorKind::ConnectionRefused, mid.into_error()));
+                }
+            }
+        };
+        socket.set_read_timeout(None)?;
+        if let Some(store) = store {
+            match stream
+                .ssl()
+                .session()
+                .and_then(|session| session.to_der().ok())
+            {
+                Some(der) => store.put(&server, der),
+                None => store.remove(&server),
+            }
+        }
+
+        Ok(DtlsClientTransport {
+            session: Arc::new(Session {
+                socket,
+                stream: Mutex::new(stream),
+            }),
+            peer,
+        })
+    }
+}
+
+impl ClientTransport for DtlsClientTransport {
+    fn send_to(&self, buf: &[u8], _addr: &SocketAddr) -> Result<usize> {
+        // one record per message, which travels in one datagram
+        self.session
+            .stream
+            .lock()
+            .unwrap()
+            .ssl_write(buf)
+            .map_err(|e| e.into_io_error().unwrap_or_else(Error::other))
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let mut stream = self.session.stream.lock().unwrap();
+        match stream.ssl_read(buf) {
+            Ok(0) => Err(Error::new(ErrorKind::NotConnected, "DTLS session closed")),
+            Ok(n) => Ok((n, self.peer)),
+            Err(e) => Err(e.into_io_error().unwrap_or_else(Error::other)),
+        }
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.session.socket.set_read_timeout(dur)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        self.session.socket.read_timeout()
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn ClientTransport>> {
+        Ok(Box::new(DtlsClientTransport {
+            session: self.session.clone(),
+            peer: self.peer,
+        }))
+    }
+}
+
+/// The verified certificates, or wrapped raw public keys, of the clients of
+/// a [`DtlsTransport`], for authorization in request handlers.
+#[derive(Clone, Default)]
+pub struct PeerCertificates(Arc<Mutex<HashMap<SocketAddr, X509>>>);
+
+impl PeerCertificates {
+    /// Return the certificate the client at `addr` authenticated with.
+    pub fn certificate(&self, addr: &SocketAddr) -> Option<X509> {
+        self.0.lock().unwrap().get(addr).cloned()
+    }
+
+    /// Return the subject of the certificate of the client at `addr`, like
+    /// `CN=device-1, O=example`.
+    pub fn subject(&self, addr: &SocketAddr) -> Option<String> {
+        self.certificate(addr)
+            .map(|cert| format_name(cert.subject_name()))
+    }
+
+    /// Return the public key the client at `addr` authenticated with.
+    pub fn public_key(&self, addr: &SocketAddr) -> Option<PKey<Public>> {
+        self.certificate(addr)?.public_key().ok()
+    }
+}
+
+/// The identity of the client of each session.
+type Identities = Arc<Mutex<HashMap<SocketAddr, PeerIdentity>>>;
+
+/// Reads the identity of the client from an established session.
+type Identify = fn(&SslRef) -> PeerIdentity;
+
+/// The handler of security events, set once the transport is added to a
+/// server.
+type SecurityEvents = Arc<Mutex<Option<SecurityEventHandler>>>;
+
+/// Return the PSK identity the client used.
+fn psk_identity(ssl: &SslRef) -> PeerIdentity {
+    match ssl.psk_identity() {
+        Some(identity) => PeerIdentity::PskIdentity(identity.to_vec()),
+        None => PeerIdentity::Unauthenticated,
+    }
+}
+
+/// Return the subject of the verified certificate of the client.
+fn certificate_identity(ssl: &SslRef) -> PeerIdentity {
+    match ssl.peer_certificate() {
+        Some(cert) => PeerIdentity::CertSubject(format_name(cert.subject_name())),
+        None => PeerIdentity::Unauthenticated,
+    }
+}
+
+/// Return the raw public key of the client, if it was asked for one.
+fn raw_public_key_identity(ssl: &SslRef) -> PeerIdentity {
+    if ssl.verify_mode() == SslVerifyMode::NONE {
+        return PeerIdentity::Unauthenticated;
+    }
+    match ssl
+        .peer_certificate()
+        .and_then(|cert| cert.public_key().ok())
+        .and_then(|key| key.public_key_to_der().ok())
+    {
+        Some(spki) => PeerIdentity::RawPublicKey(spki),
+        None => PeerIdentity::Unauthenticated,
+    }
+}
+
+enum Event {
+    Datagram(Vec<u8>),
+    Send(Vec<u8>),
+}
+
+/// The running sessions by peer, each with an id and the sender to its
+/// thread.
+type Sessions = Arc<Mutex<HashMap<SocketAddr, (u64, mpsc::Sender<Event>)>>>;
+
+/// A DTLS server authenticated with pre-shared keys, certificates or raw
+/// public keys, one thread per session.
+///
+/// A thread receives all datagrams and hands them to the session of their
+/// sender. A ClientHello from an unknown peer starts a new session, and one
+/// on an established session replaces it.
+pub struct DtlsTransport {
+    socket: net::UdpSocket,
+    incoming: UnboundedReceiver<Result<(Packet, SocketAddr)>>,
+    sessions: Sessions,
+    peers: PeerCertificates,
+    identities: Identities,
+    credentials: ServerCredentials,
+    security_events: SecurityEvents,
+}
+
+impl DtlsTransport {
+    /// Bind a UDP socket to the given address and serve DTLS sessions
+    /// authenticated with the pre-shared keys of the clients on it.
+    pub fn bind_psk<A: ToSocketAddrs>(addr: A, config: &PskServerConfig) -> Result<DtlsTransport> {
+        let context = config.context().map_err(ssl_error)?;
+        Self::serve(
+            addr,
+            ServerCredentials::new(context, psk_identity),
+            config.handshake_timeout,
+            config.handshake_rate_limit,
+        )
+    }
+
+    /// Bind a UDP socket to the given address and serve DTLS sessions
+    /// authenticated with certificates on it.
+    pub fn bind<A: ToSocketAddrs>(addr: A, config: &CertificateConfig) -> Result<DtlsTransport> {
+        let context = config.server_context().map_err(ssl_error)?;
+        Self::serve(
+            addr,
+            ServerCredentials::new(context, certificate_identity),
+            config.handshake_timeout(),
+            config.handshake_rate_limit(),
+        )
+    }
+
+    /// Bind a UDP socket to the given address and serve DTLS sessions
+    /// authenticated with raw public keys on it.
+    pub fn bind_raw_public_key<A: ToSocketAddrs>(
+        addr: A,
+        config: &RawPublicKeyConfig,
+    ) -> Result<DtlsTransport> {
+        let context = config.server_context().map_err(ssl_error)?;
+        Self::serve(
+            addr,
+            ServerCredentials::new(context, raw_public_key_identity),
+            config.handshake_timeout,
+            config.handshake_rate_limit,
+        )
+    }
+
+    fn serve<A: ToSocketAddrs>(
+        addr: A,
+        credentials: ServerCredentials,
+        handshake_timeout: Duration,
+        handshake_rate_limit: (u32, Duration),
+    ) -> Result<DtlsTransport> {
+        let mut secret = [0; 32];
+        rand_bytes(&mut secret).map_err(ssl_error)?;
+        let cookie_key = PKey::hmac(&secret).map_err(ssl_error)?;
+        let socket = net::UdpSocket::bind(addr)?;
+        let receiver = socket.try_clone()?;
+        receiver.set_read_timeout(Some(RECV_POLL_INTERVAL))?;
+
+        let (tx, incoming) = futures_mpsc::unbounded();
+        let sessions = Sessions::default();
+        let peers = PeerCertificates::default();
+        let identities = Identities::default();
+        let security_events = SecurityEvents::default();
+        let endpoint = Endpoint {
+            socket: Arc::new(receiver),
+            credentials: credentials.clone(),
+            handshake_timeout,
+            handshake_rate_limit,
+            cookie_key,
+            sessions: sessions.clone(),
+            peers: peers.clone(),
+            identities: identities.clone(),
+            security_events: security_events.clone(),
+            incoming: tx,
+        };
+        thread::spawn(move || endpoint.receive());
+
+        Ok(DtlsTransport {
+            socket,
+            incoming,
+            sessions,
+            peers,
+            identities,
+            credentials,
+            security_events,
+        })
+    }
+
+    /// Return a handle to the certificates of the connected clients.
+    pub fn peer_certificates(&self) -> PeerCertificates {
+        self.peers.clone()
+    }
+
+    /// Return a handle to replace the credentials new sessions are
+    /// established with.
+    pub fn credentials(&self) -> ServerCredentials {
+        self.credentials.clone()
+    }
+}
+
+/// The credentials a [`DtlsTransport`] establishes new sessions with.
+///
+/// Reloading them, with the same or another kind of credentials, affects
+/// the handshakes started afterwards only; established sessions keep the
+/// credentials they were set up with. Sessions cannot be resumed across a
+/// reload. The handshake timeout and rate limit stay those the transport
+/// was bound with.
+#[derive(Clone)]
+pub struct ServerCredentials(Arc<RwLock<(SslContext, Identify)>>);
+
+impl ServerCredentials {
+    fn new(context: SslContext, identify: Identify) -> ServerCredentials {
+        ServerCredentials(Arc::new(RwLock::new((context, identify))))
+    }
+
+    /// Authenticate new sessions with the pre-shared keys of the clients.
+    pub fn reload_psk(&self, config: &PskServerConfig) -> Result<()> {
+        let context = config.context().map_err(ssl_error)?;
+        self.replace(context, psk_identity);
+        Ok(())
+    }
+
+    /// Authenticate new sessions with certificates.
+    pub fn reload(&self, config: &CertificateConfig) -> Result<()> {
+        let context = config.server_context().map_err(ssl_error)?;
+        self.replace(context, certificate_identity);
+        Ok(())
+    }
+
+    /// Authenticate new sessions with raw public keys.
+    pub fn reload_raw_public_key(&self, config: &RawPublicKeyConfig) -> Result<()> {
+        let context = config.server_context().map_err(ssl_error)?;
+        self.replace(context, raw_public_key_identity);
+        Ok(())
+    }
+
+    fn replace(&self, context: SslContext, identify: Identify) {
+        *self.0.write().unwrap() = (context, identify);
+    }
+
+    fn current(&self) -> (SslContext, Identify) {
+        self.0.read().unwrap().clone()
+    }
+}
+
+/// The state shared by the receive thread and the session threads.
+#[derive(Clone)]
+struct Endpoint {
+    socket: Arc<net::UdpSocket>,
+    credentials: ServerCredentials,
+    handshake_timeout: Duration,
+    handshake_rate_limit: (u32, Duration),
+    /// Authenticates the cookies of the HelloVerifyRequests.
+    cookie_key: PKey<Private>,
+    sessions: Sessions,
+    peers: PeerCertificates,
+    identities: Identities,
+    security_events: SecurityEvents,
+    incoming: UnboundedSender<Result<(Packet, SocketAddr)>>,
+}
+
+/// The datagrams of one peer as a stream, fed by the receive thread.
+#[derive(Debug)]
+struct SessionIo {
+    socket: Arc<net::UdpSocket>,
+    peer: SocketAddr,
+    queue: VecDeque<Vec<u8>>,
+    /// Drop what OpenSSL writes instead of sending it.
+    mute: bool,
+}
+
+impl Read for SessionIo {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let datagram = self.queue.pop_front().ok_or(ErrorKind::WouldBlock)?;
+        let n = datagram.len().min(buf.len());
+        buf[..n].copy_from_slice(&datagram[..n]);
+        Ok(n)
+    }
+}
+
+impl Write for SessionIo {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.mute {
+            return Ok(buf.len());
+        }
+        self.socket.send_to(buf, self.peer)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Return whether the datagram starts with an initial ClientHello, i.e. a
+/// handshake record of epoch 0 carrying handshake type 1.
+fn is_client_hello(datagram: &[u8]) -> bool {
+    datagram.len() > 13 && datagram[0] == 22 && datagram[3..5] == [0, 0] && datagram[13] == 1
+}
+
+/// Let OpenSSL continue the cookie exchange that the receive thread has
+/// done on its own.
+fn expect_cookies(builder: &mut SslContextBuilder) {
+    builder.set_options(SslOptions::COOKIE_EXCHANGE);
+    // the HelloVerifyRequest of OpenSSL is never sent, and the cookie it
+    // gets back has been checked already
+    builder.set_cookie_generate_cb(|_ssl, buf| {
+        buf[0] = 0;
+        Ok(1)
+    });
+    builder.set_cookie_verify_cb(|_ssl, _cookie| true);
+}
+
+/// Offsets in an initial ClientHello that consists of a single record and
+/// fragment.
+struct ClientHello {
+    /// The end of the record.
+    end: usize,
+    /// The client random.
+    random: std::ops::Range<usize>,
+    /// The cookie, preceded by its length byte.
+    cookie: std::ops::Range<usize>,
+}
+
+/// Where the ClientHello message starts, after the record and handshake
+/// headers.
+const CLIENT_HELLO_BODY: usize = 13 + 12;
+
+impl ClientHello {
+    fn parse(datagram: &[u8]) -> Option<ClientHello> {
+        let u24 =
+            |at: usize| u32::from_be_bytes([0, datagram[at], datagram[at + 1], datagram[at + 2]]);
+        if !is_client_hello(datagram) || datagram.len() < CLIENT_HELLO_BODY {
+            return None;
+        }
+        let end = 13 + u16::from_be_bytes([datagram[11], datagram[12]]) as usize;
+        let length = u24(14) as usize;
+        // fragmented ClientHellos cannot be checked without keeping state
+        if u24(19) != 0 || u24(22) as usize != length || CLIENT_HELLO_BODY + length != end {
+            return None;
+        }
+        let random = CLIENT_HELLO_BODY + 2..CLIENT_HELLO_BODY + 34;
+        let session_id = *datagram.get(random.end)? as usize;
+        let cookie_at = random.end + 1 + session_id;
+        let cookie = cookie_at + 1..cookie_at + 1 + *datagram.get(cookie_at)? as usize;
+        if cookie.end > end || end > datagram.len() {
+            return None;
+        }
+        Some(ClientHello {
+            end,
+            random,
+            cookie,
+        })
+    }
+
+    /// Return the ClientHello as the client sent it before the
+    /// HelloVerifyRequest, i.e. without cookie and with the previous record
+    /// and message sequence numbers.
+    fn without_cookie(&self, datagram: &[u8]) -> Vec<u8> {
+        let mut hello = datagram[..self.cookie.start].to_vec();
+        hello.extend_from_slice(&datagram[self.cookie.end..self.end]);
+        let cookie_length = self.cookie.len();
+        hello[self.cookie.start - 1] = 0;
+        let record_length = u16::from_be_bytes([hello[11], hello[12]]) - cookie_length as u16;
+        hello[11..13].copy_from_slice(&record_length.to_be_bytes());
+        let length = (record_length as u32 - 12).to_be_bytes();
+        hello[14..17].copy_from_slice(&length[1..]);
+        hello[22..25].copy_from_slice(&length[1..]);
+        let mut sequence = [0; 8];
+        sequence[2..].copy_from_slice(&hello[5..11]);
+        let sequence = u64::from_be_bytes(sequence).saturating_sub(1).to_be_bytes();
+        hello[5..11].copy_from_slice(&sequence[2..]);
+        let message_sequence = u16::from_be_bytes([hello[17], hello[18]]).saturating_sub(1);
+        hello[17..19].copy_from_slice(&message_sequence.to_be_bytes());
+        hello
+    }
+}
+
+/// Return a HelloVerifyRequest with `cookie` in answer to the ClientHello
+/// in `datagram`, with its record and message sequence numbers as RFC 6347
+/// asks of a stateless server.
+fn hello_verify_request(datagram: &[u8], cookie: &[u8]) -> Vec<u8> {
+    let length = (3 + cookie.len()) as u32;
+    // DTLS 1.0, which every client understands in a HelloVerifyRequest
+    let mut request = vec![22, 254, 255];
+    request.extend_from_slice(&datagram[3..11]);
+    request.extend_from_slice(&(12 + length as u16).to_be_bytes());
+    request.push(3);
+    request.extend_from_slice(&length.to_be_bytes()[1..]);
+    request.extend_from_slice(&datagram[17..19]);
+    request.extend_from_slice(&[0, 0, 0]);
+    request.extend_from_slice(&length.to_be_bytes()[1..]);
+    request.extend_from_slice(&[254, 255, cookie.len() as u8]);
+    request.extend_from_slice(cookie);
+    request
+}
+
+/// Counts the handshakes started by each client address.
+struct RateLimit {
+    limit: u32,
+    interval: Duration,
+    /// The start of the current interval and the handshakes in it.
+    addresses: LruCache<IpAddr, (Instant, u32)>,
+}
+
+impl RateLimit {
+    fn new(limit: u32, interval: Duration) -> RateLimit {
+        RateLimit {
+            limit,
+            interval,
+            addresses: LruCache::with_capacity(RATE_LIMIT_CAPACITY),
+        }
+    }
+
+    /// Count a handshake of `ip` and return whether it is within the limit.
+    fn admit(&mut self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let (start, count) = self.addresses.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= self.interval {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        *count <= self.limit
+    }
+}
+
+impl Endpoint {
+    fn receive(self) {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let mut buf = vec![0; 64 * 1024];
+        let (limit, interval) = self.handshake_rate_limit;
+        let mut handshakes = RateLimit::new(limit, interval);
+        while !self.incoming.is_closed() {
+            let (n, peer) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    let _ = self.incoming.unbounded_send(Err(e));
+                    break;
+                }
+            };
+            let datagram = buf[..n].to_vec();
+
+            let mut sessions = self.sessions.lock().unwrap();
+            if let Some((_, session)) = sessions.get(&peer) {
+                if session.send(Event::Datagram(datagram.clone())).is_ok() {
+                    continue;
+                }
+            }
+            let hello = match ClientHello::parse(&datagram) {
+                Some(hello) => hello,
+                None => {
+                    debug!("DTLS record from {} without session", peer);
+                    continue;
+                }
+            };
+            let cookie = match self.cookie(peer, &datagram[hello.random.clone()]) {
+                Some(cookie) => cookie,
+                None => continue,
+            };
+            let returned = &datagram[hello.cookie.clone()];
+            if returned.len() != cookie.len() || !memcmp::eq(returned, &cookie) {
+                let request = hello_verify_request(&datagram, &cookie);
+                if let Err(e) = self.socket.send_to(&request, peer) {
+                    debug!("HelloVerifyRequest to {} failed: {}", peer, e);
+                }
+                continue;
+            }
+            if !handshakes.admit(peer.ip()) {
+                debug!("too many DTLS handshakes from {}", peer.ip());
+                continue;
+            }
+            let first = hello.without_cookie(&datagram);
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            let (tx, rx) = mpsc::channel();
+            sessions.insert(peer, (id, tx));
+            let endpoint = self.clone();
+            thread::spawn(move || endpoint.run_session(id, peer, [first, datagram], rx));
+        }
+    }
+
+    /// Return the cookie for a client, which authenticates its address and
+    /// the random of its ClientHello.
+    fn cookie(&self, peer: SocketAddr, random: &[u8]) -> Option<Vec<u8>> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.cookie_key).ok()?;
+        let address = match peer.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        signer.update(&address).ok()?;
+        signer.update(&peer.port().to_be_bytes()).ok()?;
+        signer.update(random).ok()?;
+        signer.sign_to_vec().ok()
+    }
+
+    /// Run a session, starting with the ClientHello without and with the
+    /// cookie.
+    fn run_session(
+        self,
+        id: u64,
+        peer: SocketAddr,
+        hellos: [Vec<u8>; 2],
+        events: mpsc::Receiver<Event>,
+    ) {
+        let (context, identify) = self.credentials.current();
+        if let Some(stream) = self.accept(&context, peer, hellos, &events) {
+            if let Some(cert) = stream.ssl().peer_certificate() {
+                self.peers.0.lock().unwrap().insert(peer, cert);
+            }
+            let identity = identify(stream.ssl());
+            self.identities.lock().unwrap().insert(peer, identity);
+            self.serve(peer, stream, &events);
+        }
+
+        // a newer session of the same peer may have taken over already
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions
+            .get(&peer)
+            .is_some_and(|(current, _)| *current == id)
+        {
+            sessions.remove(&peer);
+            self.peers.0.lock().unwrap().remove(&peer);
+            self.identities.lock().unwrap().remove(&peer);
+        }
+    }
+
+    fn accept(
+        &self,
+        context: &SslContext,
+        peer: SocketAddr,
+        [first, hello]: [Vec<u8>; 2],
+        events: &mpsc::Receiver<Event>,
+    ) -> Option<SslStream<SessionIo>> {
+        let mut ssl = Ssl::new(context).ok()?;
+        ssl.set_mtu(DATAGRAM_MTU).ok()?;
+        let io = SessionIo {
+            socket: self.socket.clone(),
+            peer,
+            queue: VecDeque::from([first]),
+            mute: true,
+        };
+        let deadline = Instant::now() + self.handshake_timeout;
+        // replay the cookie exchange, which OpenSSL needs to have seen
+        let mut handshake = ssl.accept(io);
+        if let Err(HandshakeError::WouldBlock(mut mid)) = handshake {
+            mid.get_mut().mute = false;
+            mid.get_mut().queue.push_back(hello);
+            handshake = mid.handshake();
+        }
+        loop {
+            match handshake {
+                Ok(stream) => return Some(stream),
+                Err(HandshakeError::WouldBlock(mut mid)) => {
+                    match events.recv_timeout(HANDSHAKE_POLL_INTERVAL) {
+                        Ok(Event::Datagram(datagram)) => mid.get_mut().queue.push_back(datagram),
+                        Ok(Event::Send(_)) => debug!("no DTLS session with {} yet", peer),
+                        Err(mpsc::RecvTimeoutError::Timeout) if Instant::now() < deadline => {}
+                        Err(_) => {
+                            debug!("DTLS handshake with {} timed out", peer);
+                            self.handshake_failed(peer, "timed out".to_string());
+                            return None;
+                        }
+                    }
+                    handshake = mid.handshake();
+                }
+                Err(HandshakeError::SetupFailure(e)) => {
+                    debug!("DTLS setup failed: {}", e);
+                    return None;
+                }
+                Err(HandshakeError::Failure(mid)) => {
+                    debug!("DTLS handshake with {} failed: {}", peer, mid.error());
+                    self.handshake_failed(peer, mid.error().to_string());
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn handshake_failed(&self, peer: SocketAddr, reason: String) {
+        let handler = self.security_events.lock().unwrap().clone();
+        audit::emit(&handler, SecurityEvent::HandshakeFailed { peer, reason });
+    }
+
+    fn serve(
+        &self,
+        peer: SocketAddr,
+        mut stream: SslStream<SessionIo>,
+        events: &mpsc::Receiver<Event>,
+    ) {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            // a datagram may carry several records
+            loop {
+                match stream.ssl_read(&mut buf) {
+                    Ok(n) => match Packet::from_bytes(&buf[..n]) {
+                        Ok(packet) => {
+                            if self.incoming.unbounded_send(Ok((packet, peer))).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => debug!("invalid message from {}: {}", peer, e),
+                    },
+                    Err(e) if e.code() == ErrorCode::WANT_READ => break,
+                    Err(e) => {
+                        debug!("DTLS session with {} closed: {}", peer, e);
+                        return;
+                    }
+                }
+            }
+
+            match events.recv() {
+                Ok(Event::Datagram(datagram)) => {
+                    if is_client_hello(&datagram) {
+                        debug!("{} starts a new DTLS session", peer);
+                        return;
+                    }
+                    stream.get_mut().queue.push_back(datagram);
+                }
+                Ok(Event::Send(bytes)) => {
+                    if let Err(e) = stream.ssl_write(&bytes) {
+                        debug!("DTLS write to {} failed: {}", peer, e);
+                        return;
+                    }
+                }
+                Err(_) => {
+                    let _ = stream.shutdown();
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl Transport for DtlsTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn verifies_addresses(&self) -> bool {
+        true
+    }
+
+    fn peer_identity(&self, addr: &SocketAddr) -> PeerIdentity {
+        self.identities
+            .lock()
+            .unwrap()
+            .get(addr)
+            .cloned()
+            .unwrap_or(PeerIdentity::Unauthenticated)
+    }
+
+    fn set_security_event_handler(&mut self, handler: SecurityEventHandler) {
+        *self.security_events.lock().unwrap() = Some(handler);
+    }
+}
+
+impl Stream for DtlsTransport {
+    type Item = Result<(Packet, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_next_unpin(cx)
+    }
+}
+
+impl Sink<(Packet, SocketAddr)> for DtlsTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, (packet, addr): (Packet, SocketAddr)) -> Result<()> {
+        let bytes = packet
+            .to_bytes()
+            .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string()))?;
+        match self.sessions.lock().unwrap().get(&addr) {
+            Some((_, session)) if session.send(Event::Send(bytes)).is_ok() => {}
+            _ => debug!("no DTLS session with {}", addr),
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
+    use openssl::ssl::SslAcceptor;
+    use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair};
+
+    const IDENTITY: &[u8] = b"client";
+    const KEY: &[u8] = b"secretPSK";
+
+    /// Serve a single session that answers every request with `payload`.
+    fn spawn_psk_server(payload: &'static [u8]) -> SocketAddr {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut builder =
+                SslAcceptor::mozilla_intermediate_v5(SslMethod::dtls_server()).unwrap();
+            builder.set_cipher_list(PSK_CIPHER_LIST).unwrap();
+            builder.set_security_level(0);
+            builder.set_psk_server_callback(|_ssl, identity, key_buf| {
+                assert_eq!(identity, Some(IDENTITY));
+                key_buf[..KEY.len()].copy_from_slice(KEY);
+                Ok(KEY.len())
+            });
+            let acceptor = builder.build();
+
+            let (_, client) = socket.peek_from(&mut [0; 1]).unwrap();
+            socket.connect(client).unwrap();
+            let mut stream = acceptor.accept(Datagrams(socket)).unwrap();
+            let mut buf = [0; 1500];
+            while let Ok(n) = stream.ssl_read(&mut buf) {
+                let request = Packet::from_bytes(&buf[..n]).unwrap();
+                let mut response = CoapResponse::new(&request).unwrap();
+                response.message.payload = payload.to_vec();
+                stream
+                    .ssl_write(&response.message.to_bytes().unwrap())
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_dtls_psk() {
+        let server_addr = spawn_psk_server(b"secure");
+        let config = PskConfig::new(IDENTITY, KEY);
+        let url = format!("coaps://{}/hello", server_addr);
+        let response = CoAPClient::request_dtls(&url, Method::Get, None, &config).unwrap();
+        assert_eq!(response.message.payload, b"secure".to_vec());
+    }
+
+    #[test]
+    fn test_handshake_timeout() {
+        // a server that never answers
+        let silent = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut config = PskConfig::new(IDENTITY, KEY);
+        config.set_handshake_timeout(Duration::from_secs(1));
+        let error = DtlsClientTransport::connect(silent.local_addr().unwrap(), &config)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+    }
+
+    /// A minimal ClientHello offering a PSK cipher suite.
+    fn client_hello(cookie: &[u8], sequence: u8) -> Vec<u8> {
+        let mut body = vec![254, 253];
+        body.extend_from_slice(&[7; 32]);
+        body.push(0);
+        body.push(cookie.len() as u8);
+        body.extend_from_slice(cookie);
+        // TLS_PSK_WITH_AES_128_CCM_8 and no compression
+        body.extend_from_slice(&[0, 2, 0xc0, 0xa8, 1, 0]);
+        let length = (body.len() as u32).to_be_bytes();
+        let mut hello = vec![22, 254, 253, 0, 0, 0, 0, 0, 0, 0, sequence];
+        hello.extend_from_slice(&(12 + body.len() as u16).to_be_bytes());
+        hello.push(1);
+        hello.extend_from_slice(&length[1..]);
+        hello.extend_from_slice(&[0, sequence, 0, 0, 0]);
+        hello.extend_from_slice(&length[1..]);
+        hello.extend_from_slice(&body);
+        hello
+    }
+
+    /// Send a ClientHello and return the cookie of the HelloVerifyRequest.
+    fn request_cookie(socket: &net::UdpSocket, server: SocketAddr, hello: &[u8]) -> Vec<u8> {
+        socket.send_to(hello, server).unwrap();
+        let mut buf = [0; 1500];
+        let (n, _) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(buf[0], 22);
+        assert_eq!(buf[13], 3);
+        let cookie_length = buf[27] as usize;
+        assert_eq!(n, 28 + cookie_length);
+        buf[28..n].to_vec()
+    }
+
+    #[test]
+    fn test_cookie_exchange() {
+        let mut config = PskServerConfig::new();
+        config.add_key(IDENTITY, KEY);
+        config.set_handshake_rate_limit(1, Duration::from_secs(60));
+        let transport = DtlsTransport::bind_psk("127.0.0.1:0", &config).unwrap();
+        let server = transport.local_addr().unwrap();
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        // no session until the cookie comes back
+        let cookie = request_cookie(&client, server, &client_hello(&[], 0));
+        assert!(transport.sessions.lock().unwrap().is_empty());
+        let forged = client_hello(b"forged", 1);
+        assert_eq!(request_cookie(&client, server, &forged), cookie);
+        assert!(transport.sessions.lock().unwrap().is_empty());
+
+        // the ServerHello follows the HelloVerifyRequest
+        client.send_to(&client_hello(&cookie, 1), server).unwrap();
+        let mut buf = [0; 1500];
+        let (n, _) = client.recv_from(&mut buf).unwrap();
+        assert!(n > 25);
+        assert_eq!(buf[13], 2);
+        assert_eq!(buf[17..19], [0, 1]);
+        assert!(transport
+            .sessions
+            .lock()
+            .unwrap()
+            .contains_key(&client.local_addr().unwrap()));
+
+        // a second handshake from the same address exceeds the rate limit
+        let other = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        other
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        let cookie = request_cookie(&other, server, &client_hello(&[], 0));
+        other.send_to(&client_hello(&cookie, 1), server).unwrap();
+        assert!(other.recv_from(&mut buf).is_err());
+        assert_eq!(transport.sessions.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_reload_credentials() {
+        let (events_tx, events) = std::sync::mpsc::channel();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut config = PskServerConfig::new();
+                    config.add_key(IDENTITY, KEY);
+                    let transport = DtlsTransport::bind_psk("127.0.0.1:0", &config).unwrap();
+                    let credentials = transport.credentials();
+                    let mut server = Server::from_transport(transport);
+                    server.set_security_event_handler(move |event| {
+                        events_tx.send(event.clone()).unwrap();
+                    });
+                    tx.send((server.socket_addr().unwrap(), credentials))
+                        .unwrap();
+                    server
+                        .run(|req: CoapRequest<SocketAddr>| async { req.response })
+                        .await
+                        .unwrap();
+                })
+        });
+        let (server_addr, credentials) = rx.recv().unwrap();
+
+        let mut established =
+            CoAPClient::new_dtls(server_addr, &PskConfig::new(IDENTITY, KEY)).unwrap();
+        established
+            .request_path("/", Method::Get, None, None, None)
+            .unwrap();
+
+        let mut config = PskServerConfig::new();
+        config.add_key("rotated", "newSecretPSK");
+        credentials.reload_psk(&config).unwrap();
+        established
+            .request_path("/", Method::Get, None, None, None)
+            .unwrap();
+        let mut retired = PskConfig::new(IDENTITY, KEY);
+        retired.set_handshake_timeout(Duration::from_secs(2));
+        assert!(CoAPClient::new_dtls(server_addr, &retired).is_err());
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(event, SecurityEvent::HandshakeFailed { .. }));
+        let mut client =
+            CoAPClient::new_dtls(server_addr, &PskConfig::new("rotated", "newSecretPSK")).unwrap();
+        client
+            .request_path("/", Method::Get, None, None, None)
+            .unwrap();
+    }
+
+    /// A certificate with the given common name and subject alternative
+    /// names, signed by `issuer` or else a self-signed CA.
+    fn certificate(
+        common_name: &str,
+        names: &[&str],
+        issuer: Option<&(rcgen::Certificate, KeyPair)>,
+    ) -> (rcgen::Certificate, KeyPair) {
+        let names = names
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<_>>();
+        let mut params = CertificateParams::new(names).unwrap();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, common_name);
+        let key = KeyPair::generate().unwrap();
+        let cert = match issuer {
+            Some((issuer, issuer_key)) => params.signed_by(&key, issuer, issuer_key).unwrap(),
+            None => {
+                params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+                params.self_signed(&key).unwrap()
+            }
+        };
+        (cert, key)
+    }
+
+    fn certificate_config(
+        (cert, key): &(rcgen::Certificate, KeyPair),
+        root: &X509,
+    ) -> CertificateConfig {
+        let mut config = CertificateConfig::new();
+        config.set_identity(
+            vec![X509::from_pem(cert.pem().as_bytes()).unwrap()],
+            PKey::private_key_from_pem(key.serialize_pem().as_bytes()).unwrap(),
+        );
+        config.add_root(root.clone());
+        config.set_handshake_timeout(Duration::from_secs(5));
+        config
+    }
+
+    #[test]
+    fn test_dtls_certificates() {
+        let ca = certificate("test CA", &[], None);
+        let root = X509::from_pem(ca.0.pem().as_bytes()).unwrap();
+        let server_cert = certificate("server", &["localhost"], Some(&ca));
+
+        let mut server_config = certificate_config(&server_cert, &root);
+        server_config.set_require_client_certificate(true);
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let transport = DtlsTransport::bind("127.0.0.1:0", &server_config).unwrap();
+                    let peers = transport.peer_certificates();
+                    let mut server = Server::from_transport(transport);
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(move |req: CoapRequest<SocketAddr>| {
+                            let subject = peers.subject(&req.source.unwrap());
+                            async move {
+                                let mut response = req.response?;
+                                response.message.payload = subject?.into_bytes();
+                                Some::<CoapResponse>(response)
+                            }
+                        })
+                        .await
+                        .unwrap();
+                })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let config = certificate_config(&certificate("device-1", &[], Some(&ca)), &root);
+        let mut client =
+            CoAPClient::new_dtls_certificate(server_addr, "localhost", &config).unwrap();
+        let response = client
+            .request_path("/whoami", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload, b"CN=device-1".to_vec());
+
+        // the server name must match its certificate
+        assert!(
+            DtlsClientTransport::connect_with_certificate(server_addr, "example.com", &config)
+                .is_err()
+        );
+
+        // clients of another CA and without certificate are turned away
+        let stranger = certificate_config(&certificate("stranger", &[], None), &root);
+        assert!(
+            DtlsClientTransport::connect_with_certificate(server_addr, "localhost", &stranger)
+                .is_err()
+        );
+        let mut anonymous = CertificateConfig::new();
+        anonymous.add_root(root);
+        anonymous.set_handshake_timeout(Duration::from_secs(5));
+        assert!(DtlsClientTransport::connect_with_certificate(
+            server_addr,
+            "localhost",
+            &anonymous
+        )
+        .is_err());
+    }
+
+    fn generate_key() -> PKey<Private> {
+        let group = openssl::ec::EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        PKey::from_ec_key(openssl::ec::EcKey::generate(&group).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_dtls_raw_public_keys() {
+        let server_key = generate_key();
+        let client_key = generate_key();
+
+        let mut server_config = RawPublicKeyConfig::new(server_key.clone());
+        server_config.add_pinned_key(&client_key).unwrap();
+        let expected = client_key.public_key_to_der().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let transport =
+                        DtlsTransport::bind_raw_public_key("127.0.0.1:0", &server_config).unwrap();
+                    let peers = transport.peer_certificates();
+                    let mut server = Server::from_transport(transport);
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(move |req: CoapRequest<SocketAddr>| {
+                            let key = peers.public_key(&req.source.unwrap());
+                            let known = key.and_then(|key| key.public_key_to_der().ok())
+                                == Some(expected.clone());
+                            async move {
+                                let mut response = req.response?;
+                                response.message.payload =
+                                    if known { b"known".to_vec() } else { Vec::new() };
+                                Some::<CoapResponse>(response)
+                            }
+                        })
+                        .await
+                        .unwrap();
+                })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let mut config = RawPublicKeyConfig::new(client_key.clone());
+        config.add_pinned_key(&server_key).unwrap();
+        config.set_handshake_timeout(Duration::from_secs(5));
+        let mut client = CoAPClient::new_dtls_raw_public_key(server_addr, &config).unwrap();
+        let response = client
+            .request_path("/whoami", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload, b"known".to_vec());
+
+        // the server turns away keys it does not know
+        let mut stranger = RawPublicKeyConfig::new(generate_key());
+        stranger.add_pinned_key(&server_key).unwrap();
+        stranger.set_handshake_timeout(Duration::from_secs(5));
+        assert!(DtlsClientTransport::connect_with_raw_public_key(server_addr, &stranger).is_err());
+
+        // and the client servers it does not know
+        let mut wrong_pin = RawPublicKeyConfig::new(client_key);
+        wrong_pin.add_pinned_key(&generate_key()).unwrap();
+        wrong_pin.set_handshake_timeout(Duration::from_secs(5));
+        assert!(DtlsClientTransport::connect_with_raw_public_key(server_addr, &wrong_pin).is_err());
+    }
+
+    #[test]
+    fn test_session_resumption() {
+        let server_key = generate_key();
+        let server_config = RawPublicKeyConfig::new(server_key.clone());
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let transport =
+                        DtlsTransport::bind_raw_public_key("127.0.0.1:0", &server_config).unwrap();
+                    let mut server = Server::from_transport(transport);
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|req: CoapRequest<SocketAddr>| async { req.response })
+                        .await
+                        .unwrap();
+                })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let path = std::env::temp_dir().join(format!("coap-dtls-sessions-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let connect = |cache: Arc<SessionCache>| {
+            let mut config = RawPublicKeyConfig::new(generate_key());
+            config.add_pinned_key(&server_key).unwrap();
+            config.set_session_store(cache);
+            DtlsClientTransport::connect_with_raw_public_key(server_addr, &config).unwrap()
+        };
+        let lifetime = Duration::from_secs(3600);
+
+        let cache = Arc::new(SessionCache::persistent(&path, 8, lifetime).unwrap());
+        let first = connect(cache.clone());
+        assert!(!first.session_resumed());
+        drop(first);
+        let second = connect(cache);
+        assert!(second.session_resumed());
+        let mut client = CoAPClient::from_transport(second, server_addr).unwrap();
+        client
+            .request_path("/", Method::Get, None, None, None)
+            .unwrap();
+
+        // the session survives in the file
+        let reloaded = SessionCache::persistent(&path, 8, lifetime).unwrap();
+        assert!(connect(Arc::new(reloaded)).session_resumed());
+        // but not beyond its lifetime
+        let expired = SessionCache::persistent(&path, 8, Duration::ZERO).unwrap();
+        assert!(!connect(Arc::new(expired)).session_resumed());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,208 @@
+//! In-memory transport for tests: no sockets, no ports.
+//!
+//! A [`MemoryTransport`] is handed to the server and any number of
+//! [`MemoryClientTransport`]s are connected to it, each under an address of
+//! its choosing. Messages are still encoded and decoded, so the full message
+//! path is exercised.
+use coap_lite::Packet;
+use futures::{Sink, Stream};
+use log::debug;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::{ClientTransport, Transport};
+
+type Peers = Arc<Mutex<HashMap<SocketAddr, std_mpsc::Sender<Vec<u8>>>>>;
+
+/// The server side of the in-memory transport.
+pub struct MemoryTransport {
+    local_addr: SocketAddr,
+    incoming_tx: UnboundedSender<(Vec<u8>, SocketAddr)>,
+    incoming: UnboundedReceiver<(Vec<u8>, SocketAddr)>,
+    peers: Peers,
+}
+
+impl MemoryTransport {
+    /// Create a transport reporting `local_addr` as its address.
+    pub fn new(local_addr: SocketAddr) -> MemoryTransport {
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        MemoryTransport {
+            local_addr,
+            incoming_tx,
+            incoming,
+            peers: Peers::default(),
+        }
+    }
+
+    /// Create a client endpoint with the given address, connected to this
+    /// transport. Connecting again with the same address replaces the
+    /// earlier endpoint.
+    pub fn connect(&self, addr: SocketAddr) -> MemoryClientTransport {
+        let (tx, rx) = std_mpsc::channel();
+        self.peers.lock().unwrap().insert(addr, tx);
+        MemoryClientTransport {
+            addr,
+            server_addr: self.local_addr,
+            outgoing: self.incoming_tx.clone(),
+            incoming: Arc::new(Mutex::new(rx)),
+            read_timeout: Arc::default(),
+        }
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+impl Stream for MemoryTransport {
+    type Item = Result<(Packet, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_recv(cx).map(|message| {
+            message.map(|(bytes, addr)| {
+                Packet::from_bytes(&bytes)
+                    .map(|packet| (packet, addr))
+                    .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string()))
+            })
+        })
+    }
+}
+
+impl Sink<(Packet, SocketAddr)> for MemoryTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, (packet, addr): (Packet, SocketAddr)) -> Result<()> {
+        let bytes = packet
+            .to_bytes()
+            .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string()))?;
+        let peers = self.peers.lock().unwrap();
+        // like a datagram to nowhere, a message to an unknown peer is lost
+        match peers.get(&addr) {
+            Some(peer) if peer.send(bytes).is_ok() => {}
+            _ => debug!("no memory peer {}", addr),
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A client endpoint of a [`MemoryTransport`].
+pub struct MemoryClientTransport {
+    addr: SocketAddr,
+    server_addr: SocketAddr,
+    outgoing: UnboundedSender<(Vec<u8>, SocketAddr)>,
+    incoming: Arc<Mutex<std_mpsc::Receiver<Vec<u8>>>>,
+    read_timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl MemoryClientTransport {
+    /// Return the address the server sees for this endpoint.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl ClientTransport for MemoryClientTransport {
+    fn send_to(&self, buf: &[u8], _addr: &SocketAddr) -> Result<usize> {
+        self.outgoing
+            .send((buf.to_vec(), self.addr))
+            .map_err(|_| Error::new(ErrorKind::NotConnected, "memory transport closed"))?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let incoming = self.incoming.lock().unwrap();
+        let bytes = match timeout {
+            Some(timeout) => incoming.recv_timeout(timeout).map_err(|e| match e {
+                std_mpsc::RecvTimeoutError::Timeout => {
+                    Error::new(ErrorKind::WouldBlock, "receive timed out")
+                }
+                std_mpsc::RecvTimeoutError::Disconnected => {
+                    Error::new(ErrorKind::NotConnected, "memory transport closed")
+                }
+            })?,
+            None => incoming
+                .recv()
+                .map_err(|_| Error::new(ErrorKind::NotConnected, "memory transport closed"))?,
+        };
+        if bytes.len() > buf.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "message too large"));
+        }
+        buf[..bytes.len()].copy_from_slice(&bytes);
+        Ok((bytes.len(), self.server_addr))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        *self.read_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn ClientTransport>> {
+        Ok(Box::new(MemoryClientTransport {
+            addr: self.addr,
+            server_addr: self.server_addr,
+            outgoing: self.outgoing.clone(),
+            incoming: self.incoming.clone(),
+            read_timeout: self.read_timeout.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
+
+    #[test]
+    fn test_memory_transport() {
+        let transport = MemoryTransport::new("10.0.0.1:5683".parse().unwrap());
+        let first = transport.connect("10.0.0.2:5683".parse().unwrap());
+        let second = transport.connect("10.0.0.3:5683".parse().unwrap());
+
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                Server::from_transport(transport)
+                    .run(|req: CoapRequest<SocketAddr>| async move {
+                        let mut response = req.response?;
+                        response.message.payload = req.source.unwrap().to_string().into_bytes();
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+
+        for (endpoint, expected) in [(first, "10.0.0.2:5683"), (second, "10.0.0.3:5683")] {
+            let mut client = CoAPClient::from_transport(endpoint, "10.0.0.1:5683").unwrap();
+            let response = client
+                .request_path("/", Method::Get, None, None, None)
+                .unwrap();
+            assert_eq!(response.message.payload, expected.as_bytes().to_vec());
+        }
+    }
+}
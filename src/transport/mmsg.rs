@@ -0,0 +1,544 @@
+//! UDP transport receiving and sending batches of datagrams with one system
+//! call each, using the Linux `recvmmsg` and `sendmmsg`.
+//!
+//! Like [`UdpTransport`](super::UdpTransport) it runs on the Tokio reactor,
+//! but drains a readable socket of up to [`BATCH_SIZE`] datagrams at once,
+//! and sends the messages queued since the last flush together, e.g. the
+//! blocks of a Q-Block2 body. That cuts the per-packet overhead of servers
+//! ingesting high rates of telemetry.
+//!
+//! With [`MmsgTransport::set_segmentation_offload`], runs of equally sized
+//! datagrams to the same peer, like the notifications of a large observe
+//! fan-out or the blocks of a body, are handed to the kernel as a single
+//! datagram that it splits up (`UDP_SEGMENT`), and the datagrams the kernel
+//! coalesced on receipt (`UDP_GRO`) are split up again.
+use coap_lite::Packet;
+use futures::{Sink, Stream};
+use log::debug;
+use socket2::{SockAddr, SockAddrStorage};
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::net::{self, IpAddr, SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::ptr;
+use std::task::{ready, Context, Poll};
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+use super::Transport;
+use crate::message::pool::{BufferPool, PooledBuffer};
+use crate::message::{decode_datagram, write_packet};
+
+/// How many datagrams are received or sent with one system call.
+pub const BATCH_SIZE: usize = 32;
+/// Receive buffer size. Larger datagrams are truncated and dropped.
+const MAX_DATAGRAM_SIZE: usize = 8192;
+/// Receive buffer size for datagrams coalesced by the kernel.
+const MAX_COALESCED_SIZE: usize = 65535;
+/// Most datagrams the kernel splits a segmented one into.
+const MAX_SEGMENTS: usize = 64;
+/// Largest payload of a segmented datagram, the largest UDP payload over
+/// IPv4.
+const MAX_SEGMENTED_SIZE: usize = 65507;
+
+/// CoAP over UDP with batched socket I/O.
+pub struct MmsgTransport {
+    socket: UdpSocket,
+    buffers: Vec<Vec<u8>>,
+    /// Messages received with the last batch, not yet taken.
+    received: VecDeque<Result<(Packet, SocketAddr)>>,
+    /// Encoded messages waiting for the next flush.
+    outgoing: VecDeque<(PooledBuffer<'static>, SocketAddr)>,
+    segmentation: bool,
+}
+
+impl MmsgTransport {
+    /// Bind a UDP socket to the given address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<MmsgTransport> {
+        let socket = net::UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(MmsgTransport::from_socket(UdpSocket::from_std(socket)?))
+    }
+
+    /// Wrap an already bound socket.
+    pub fn from_socket(socket: UdpSocket) -> MmsgTransport {
+        MmsgTransport {
+            socket,
+            buffers: vec![vec![0; MAX_DATAGRAM_SIZE]; BATCH_SIZE],
+            received: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            segmentation: false,
+        }
+    }
+
+    /// Send runs of equally sized datagrams to the same peer as one
+    /// datagram the kernel segments, and receive datagrams the kernel
+    /// coalesced. This needs Linux 5.0 or later.
+    pub fn set_segmentation_offload(&mut self, on: bool) -> Result<()> {
+        let value = libc::c_int::from(on);
+        // SAFETY: the option value is a c_int that outlives the call
+        let result = unsafe {
+            libc::setsockopt(
+                self.socket.as_raw_fd(),
+                libc::SOL_UDP,
+                libc::UDP_GRO,
+                (&value as *const libc::c_int).cast(),
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(Error::last_os_error());
+        }
+        self.segmentation = on;
+        let size = if on {
+            MAX_COALESCED_SIZE
+        } else {
+            MAX_DATAGRAM_SIZE
+        };
+        self.buffers = vec![vec![0; size]; BATCH_SIZE];
+        Ok(())
+    }
+
+    /// Decode the datagrams of a batch into `received`.
+    fn decode(&mut self, datagrams: Vec<Datagram>) {
+        for (buffer, datagram) in self.buffers.iter().zip(datagrams) {
+            let addr = match datagram.addr {
+                Some(addr) if !datagram.truncated => addr,
+                _ => {
+                    debug!("dropped a truncated datagram or one of unknown origin");
+                    continue;
+                }
+            };
+            let bytes = &buffer[..datagram.len];
+            // the kernel may have coalesced datagrams of the same size
+            for segment in bytes.chunks(datagram.segment_size.unwrap_or(bytes.len()).max(1)) {
+                let message = decode_datagram(segment).map(|packet| (packet, addr));
+                self.received.push_back(message);
+            }
+        }
+    }
+}
+
+/// A datagram received with `recv_batch`.
+struct Datagram {
+    len: usize,
+    truncated: bool,
+    addr: Option<SocketAddr>,
+    /// The size of the datagrams the kernel coalesced this one from.
+    segment_size: Option<usize>,
+}
+
+impl Transport for MmsgTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn join_multicast(&mut self, addr: IpAddr) -> Result<()> {
+        assert!(addr.is_multicast());
+        match (self.socket.local_addr()?, addr) {
+            (SocketAddr::V4(val), IpAddr::V4(ipv4)) => {
+                self.socket.join_multicast_v4(ipv4, *val.ip())
+            }
+            (SocketAddr::V6(_), IpAddr::V6(ipv6)) => self.socket.join_multicast_v6(&ipv6, 0),
+            // the address family of the group does not match the socket
+            _ => Ok(()),
+        }
+    }
+
+    fn leave_multicast(&mut self, addr: IpAddr) -> Result<()> {
+        assert!(addr.is_multicast());
+        match (self.socket.local_addr()?, addr) {
+            (SocketAddr::V4(val), IpAddr::V4(ipv4)) => {
+                self.socket.leave_multicast_v4(ipv4, *val.ip())
+            }
+            (SocketAddr::V6(_), IpAddr::V6(ipv6)) => self.socket.leave_multicast_v6(&ipv6, 0),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Stream for MmsgTransport {
+    type Item = Result<(Packet, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(message) = self.received.pop_front() {
+                return Poll::Ready(Some(message));
+            }
+            ready!(self.socket.poll_recv_ready(cx))?;
+            let this = &mut *self;
+            let fd = this.socket.as_raw_fd();
+            let buffers = &mut this.buffers;
+            let segmentation = this.segmentation;
+            match this
+                .socket
+                .try_io(Interest::READABLE, || recv_batch(fd, buffers, segmentation))
+            {
+                Ok(datagrams) => this.decode(datagrams),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+}
+
+impl Sink<(Packet, SocketAddr)> for MmsgTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.outgoing.len() >= BATCH_SIZE {
+            return self.poll_flush(cx);
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, (packet, addr): (Packet, SocketAddr)) -> Result<()> {
+        let mut bytes = BufferPool::global().take();
+        write_packet(&packet, &mut *bytes)?;
+        self.outgoing.push_back((bytes, addr));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while !self.outgoing.is_empty() {
+            ready!(self.socket.poll_send_ready(cx))?;
+            let this = &mut *self;
+            let fd = this.socket.as_raw_fd();
+            let batch = this.outgoing.make_contiguous();
+            let batch = &batch[..batch.len().min(BATCH_SIZE)];
+            let segmentation = this.segmentation;
+            match this
+                .socket
+                .try_io(Interest::WRITABLE, || send_batch(fd, batch, segmentation))
+            {
+                Ok(sent) => {
+                    this.outgoing.drain(..sent);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => {
+                    // the first datagram failed, the others may still go out
+                    this.outgoing.pop_front();
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Space for the control message of a segment size, aligned for
+/// `cmsghdr`.
+type Control = [u64; 4];
+
+/// Receive up to one datagram per buffer, with the size of the segments
+/// the kernel coalesced it from if `segmentation` is on.
+fn recv_batch(fd: RawFd, buffers: &mut [Vec<u8>], segmentation: bool) -> Result<Vec<Datagram>> {
+    let mut addrs: Vec<SockAddrStorage> =
+        buffers.iter().map(|_| SockAddrStorage::zeroed()).collect();
+    let mut controls: Vec<Control> = vec![[0; 4]; buffers.len()];
+    let mut iovecs: Vec<libc::iovec> = buffers
+        .iter_mut()
+        .map(|buffer| libc::iovec {
+            iov_base: buffer.as_mut_ptr().cast(),
+            iov_len: buffer.len(),
+        })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(addrs.iter_mut())
+        .zip(controls.iter_mut())
+        .map(|((iovec, addr), control)| {
+            // SAFETY: all zeros is a valid mmsghdr
+            let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+            header.msg_hdr.msg_name = (addr as *mut SockAddrStorage).cast();
+            header.msg_hdr.msg_namelen = addr.size_of();
+            header.msg_hdr.msg_iov = iovec;
+            header.msg_hdr.msg_iovlen = 1;
+            if segmentation {
+                header.msg_hdr.msg_control = control.as_mut_ptr().cast();
+                header.msg_hdr.msg_controllen = mem::size_of::<Control>() as _;
+            }
+            header
+        })
+        .collect();
+    // SAFETY: the headers point to buffers, addresses and control buffers
+    // that outlive the call
+    let count = unsafe {
+        libc::recvmmsg(
+            fd,
+            headers.as_mut_ptr(),
+            headers.len() as _,
+            0,
+            ptr::null_mut(),
+        )
+    };
+    if count < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(headers
+        .iter()
+        .zip(addrs)
+        .take(count as usize)
+        .map(|(header, addr)| {
+            // SAFETY: the kernel initialized the address and its length
+            let addr = unsafe { SockAddr::new(addr, header.msg_hdr.msg_namelen) };
+            Datagram {
+                len: header.msg_len as usize,
+                truncated: header.msg_hdr.msg_flags & libc::MSG_TRUNC != 0,
+                addr: addr.as_socket(),
+                segment_size: segment_size(&header.msg_hdr),
+            }
+        })
+        .collect())
+}
+
+/// Return the segment size in the `UDP_GRO` control message of a received
+/// datagram.
+fn segment_size(header: &libc::msghdr) -> Option<usize> {
+    if header.msg_control.is_null() {
+        return None;
+    }
+    // SAFETY: the kernel wrote msg_controllen bytes of control messages
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(header);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                let size = ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int);
+                return usize::try_from(size).ok();
+            }
+            cmsg = libc::CMSG_NXTHDR(header, cmsg);
+        }
+    }
+    None
+}
+
+/// Split the datagrams into runs to send as one segmented datagram each, if
+/// `segmentation` is on: datagrams to the same peer of the same size, but
+/// for the last that may be shorter. Return the length of each run.
+fn segment(datagrams: &[(PooledBuffer<'_>, SocketAddr)], segmentation: bool) -> Vec<usize> {
+    let mut runs = Vec::new();
+    let mut rest = datagrams;
+    while let Some(((first, addr), others)) = rest.split_first() {
+        let mut len = 1;
+        let mut total = first.len();
+        if segmentation {
+            for (bytes, to) in others {
+                if to != addr
+                    || bytes.len() > first.len()
+                    || len == MAX_SEGMENTS
+                    || total + bytes.len() > MAX_SEGMENTED_SIZE
+                {
+                    break;
+                }
+                len += 1;
+                total += bytes.len();
+                // a shorter datagram can only be the last segment
+                if bytes.len() < first.len() {
+                    break;
+                }
+            }
+        }
+        runs.push(len);
+        rest = &rest[len..];
+    }
+    runs
+}
+
+/// Send the datagrams with one system call, and return how many were sent.
+fn send_batch(
+    fd: RawFd,
+    datagrams: &[(PooledBuffer<'_>, SocketAddr)],
+    segmentation: bool,
+) -> Result<usize> {
+    let runs = segment(datagrams, segmentation);
+    let mut iovecs: Vec<libc::iovec> = datagrams
+        .iter()
+        .map(|(bytes, _)| libc::iovec {
+            iov_base: bytes.as_ptr() as *mut libc::c_void,
+            iov_len: bytes.len(),
+        })
+        .collect();
+    let mut addrs = Vec::with_capacity(runs.len());
+    let mut start = 0;
+    for len in &runs {
+        addrs.push(SockAddr::from(datagrams[start].1));
+        start += len;
+    }
+    let mut controls: Vec<Control> = vec![[0; 4]; runs.len()];
+    let mut headers = Vec::with_capacity(runs.len());
+    let mut iovecs_left = &mut iovecs[..];
+    for ((len, addr), control) in runs.iter().zip(&addrs).zip(controls.iter_mut()) {
+        let (run, rest) = iovecs_left.split_at_mut(*len);
+        iovecs_left = rest;
+        // SAFETY: all zeros is a valid mmsghdr
+        let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+        header.msg_hdr.msg_name = addr.as_ptr() as *mut libc::c_void;
+        header.msg_hdr.msg_namelen = addr.len();
+        header.msg_hdr.msg_iov = run.as_mut_ptr();
+        header.msg_hdr.msg_iovlen = *len as _;
+        if *len > 1 {
+            header.msg_hdr.msg_control = control.as_mut_ptr().cast();
+            // SAFETY: the control buffer has room for a u16 control message
+            unsafe {
+                header.msg_hdr.msg_controllen = libc::CMSG_SPACE(2) as _;
+                let cmsg = libc::CMSG_FIRSTHDR(&header.msg_hdr);
+                (*cmsg).cmsg_level = libc::SOL_UDP;
+                (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                (*cmsg).cmsg_len = libc::CMSG_LEN(2) as _;
+                let size = run[0].iov_len as u16;
+                ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, size);
+            }
+        }
+        headers.push(header);
+    }
+    // SAFETY: the headers point to datagrams, addresses and control
+    // messages that outlive the call, which only reads them
+    let count = unsafe { libc::sendmmsg(fd, headers.as_mut_ptr(), headers.len() as _, 0) };
+    if count < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(runs.iter().take(count as usize).sum())
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse};
+    use futures::{SinkExt, StreamExt};
+    use std::time::Duration;
+
+    fn packet(message_id: u16) -> Packet {
+        let mut packet = Packet::new();
+        packet.header.message_id = message_id;
+        packet
+    }
+
+    #[test]
+    fn test_batches() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut transport = MmsgTransport::bind("127.0.0.1:0").unwrap();
+            let addr = transport.local_addr().unwrap();
+            let peer = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let peer_addr = peer.local_addr().unwrap();
+
+            // more than one batch waiting, and a datagram that is no message
+            let count = BATCH_SIZE as u16 + 5;
+            for id in 0..count {
+                peer.send_to(&packet(id).to_bytes().unwrap(), addr).unwrap();
+            }
+            peer.send_to(&[0xff], addr).unwrap();
+            for id in 0..count {
+                let (received, from) = transport.next().await.unwrap().unwrap();
+                assert_eq!(received.header.message_id, id);
+                assert_eq!(from, peer_addr);
+            }
+            assert!(transport.next().await.unwrap().is_err());
+
+            // queued messages go out together
+            for id in 0..count {
+                transport.feed((packet(id), peer_addr)).await.unwrap();
+            }
+            transport.flush().await.unwrap();
+            let mut buf = [0; 64];
+            for id in 0..count {
+                let (len, from) = peer.recv_from(&mut buf).unwrap();
+                assert_eq!(
+                    Packet::from_bytes(&buf[..len]).unwrap().header.message_id,
+                    id
+                );
+                assert_eq!(from, addr);
+            }
+        });
+    }
+
+    #[test]
+    fn test_segment() {
+        let a: SocketAddr = "192.0.2.1:5683".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:5683".parse().unwrap();
+        let datagram = |len, addr| {
+            let mut bytes = BufferPool::global().take();
+            bytes.resize(len, 0);
+            (bytes, addr)
+        };
+        let datagrams: Vec<_> = [(10, a), (10, a), (8, a), (10, a), (10, b), (12, b)]
+            .into_iter()
+            .map(|(len, addr)| datagram(len, addr))
+            .collect();
+        assert_eq!(segment(&datagrams, true), [3, 1, 1, 1]);
+        assert_eq!(segment(&datagrams, false), [1; 6]);
+        let many: Vec<_> = (0..MAX_SEGMENTS + 1).map(|_| datagram(10, a)).collect();
+        assert_eq!(segment(&many, true), [MAX_SEGMENTS, 1]);
+    }
+
+    #[test]
+    fn test_segmentation_offload() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut sender = MmsgTransport::bind("127.0.0.1:0").unwrap();
+            let mut receiver = MmsgTransport::bind("127.0.0.1:0").unwrap();
+            sender.set_segmentation_offload(true).unwrap();
+            receiver.set_segmentation_offload(true).unwrap();
+            let peer = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let to = [receiver.local_addr().unwrap(), peer.local_addr().unwrap()];
+
+            // equally sized messages and a shorter one
+            for addr in to {
+                for id in 0..10 {
+                    let mut message = packet(id);
+                    message.payload = vec![0x55; if id < 9 { 100 } else { 50 }];
+                    sender.feed((message, addr)).await.unwrap();
+                }
+            }
+            sender.flush().await.unwrap();
+            for id in 0..10 {
+                let (received, _) = receiver.next().await.unwrap().unwrap();
+                assert_eq!(received.header.message_id, id);
+            }
+            // a peer without offload gets them one by one
+            let mut buf = [0; 256];
+            for id in 0..10 {
+                let (len, _) = peer.recv_from(&mut buf).unwrap();
+                let received = Packet::from_bytes(&buf[..len]).unwrap();
+                assert_eq!(received.header.message_id, id);
+            }
+        });
+    }
+
+    #[test]
+    fn test_mmsg_transport() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let transport = MmsgTransport::bind("127.0.0.1:0").unwrap();
+                    let mut server = Server::from_transport(transport);
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|req: CoapRequest<SocketAddr>| async {
+                            let mut response = req.response?;
+                            response.message.payload = b"pong".to_vec();
+                            Some::<CoapResponse>(response)
+                        })
+                        .await
+                        .unwrap();
+                })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = CoAPClient::from_transport(socket, server_addr).unwrap();
+        let response = client
+            .request_path("/ping", coap_lite::RequestType::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload, b"pong".to_vec());
+    }
+}
@@ -0,0 +1,166 @@
+//! Transports carry encoded CoAP messages between endpoints.
+//!
+//! The server side is asynchronous: a [`Transport`] is a stream of received
+//! messages and a sink of outgoing ones, each paired with the address of the
+//! peer. The client side is blocking and uses [`ClientTransport`]. Dispatch,
+//! observe and reliability handling only talk to these traits, so UDP can be
+//! swapped for another transport without touching them.
+use coap_lite::Packet;
+use futures::{Sink, Stream};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use super::audit::SecurityEventHandler;
+
+#[cfg(feature = "dtls")]
+pub mod dtls;
+pub mod memory;
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+pub mod mmsg;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod shard;
+pub mod slip;
+pub mod tcp;
+#[cfg(feature = "tls")]
+pub mod tls;
+mod udp;
+#[cfg(unix)]
+pub mod unix;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub mod uring;
+#[cfg(feature = "websocket")]
+pub mod ws;
+
+#[cfg(feature = "dtls")]
+pub use self::dtls::DtlsTransport;
+pub use self::memory::MemoryTransport;
+#[cfg(all(target_os = "linux", feature = "mmsg"))]
+pub use self::mmsg::MmsgTransport;
+#[cfg(feature = "quic")]
+pub use self::quic::QuicTransport;
+pub use self::shard::ShardTransport;
+pub use self::slip::SlipTransport;
+pub use self::tcp::TcpTransport;
+pub use self::udp::{ThreadedUdpTransport, UdpTransport};
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub use self::uring::UringTransport;
+
+/// An asynchronous, datagram-oriented transport used by the server.
+pub trait Transport:
+    Stream<Item = Result<(Packet, SocketAddr)>>
+    + Sink<(Packet, SocketAddr), Error = Error>
+    + Unpin
+    + Send
+{
+    /// Return the local address of the transport.
+    fn local_addr(&self) -> Result<SocketAddr>;
+
+    /// Join a multicast group. Transports without multicast support return
+    /// an `Unsupported` error.
+    fn join_multicast(&mut self, _addr: IpAddr) -> Result<()> {
+        Err(unsupported("multicast"))
+    }
+
+    /// Leave a multicast group previously joined with `join_multicast`.
+    fn leave_multicast(&mut self, _addr: IpAddr) -> Result<()> {
+        Err(unsupported("multicast"))
+    }
+
+    /// Return the multicast groups the transport receives.
+    fn multicast_groups(&self) -> Vec<IpAddr> {
+        Vec::new()
+    }
+
+    /// Return the multicast group the last message of the peer was sent to,
+    /// or `None` if it was sent to the server alone or the transport cannot
+    /// tell.
+    fn multicast_group(&self, _addr: &SocketAddr) -> Option<IpAddr> {
+        None
+    }
+
+    /// Return whether the transport only delivers messages of peers that
+    /// have shown they receive at their address, e.g. by completing a
+    /// handshake. The server does not apply its amplification limit to
+    /// the peers of such transports.
+    fn verifies_addresses(&self) -> bool {
+        false
+    }
+
+    /// Return the identity the peer authenticated with. Transports without
+    /// authentication report every peer as unauthenticated.
+    fn peer_identity(&self, _addr: &SocketAddr) -> PeerIdentity {
+        PeerIdentity::Unauthenticated
+    }
+
+    /// Report failed handshakes and other security events to `handler`.
+    /// Transports without a security layer have nothing to report.
+    fn set_security_event_handler(&mut self, _handler: SecurityEventHandler) {}
+}
+
+/// The identity a peer proved with the security layer of its transport.
+/// Handlers get the identity of the client with
+/// [`server::peer_identity`](crate::server::peer_identity).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum PeerIdentity {
+    /// The transport does not authenticate peers, or the peer did not.
+    Unauthenticated,
+    /// The identity of the pre-shared key the peer used.
+    PskIdentity(Vec<u8>),
+    /// The subject of the verified certificate of the peer, like
+    /// `CN=device-1, O=example`.
+    CertSubject(String),
+    /// The DER encoded SubjectPublicKeyInfo of the raw public key of the
+    /// peer.
+    RawPublicKey(Vec<u8>),
+    /// The OSCORE Sender ID (kid) of the security context the peer used.
+    /// No transport produces it yet, as OSCORE is not implemented.
+    OscoreKid(Vec<u8>),
+}
+
+impl PeerIdentity {
+    /// Return whether the peer authenticated itself.
+    pub fn is_authenticated(&self) -> bool {
+        *self != PeerIdentity::Unauthenticated
+    }
+}
+
+/// A blocking, datagram-oriented transport used by the client.
+pub trait ClientTransport: Send {
+    /// Send an encoded message to the given peer.
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> Result<usize>;
+
+    /// Receive an encoded message together with the address of its sender.
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)>;
+
+    /// Set the timeout of `recv_from`. `None` blocks indefinitely.
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()>;
+
+    /// Return the timeout of `recv_from`.
+    fn read_timeout(&self) -> Result<Option<Duration>>;
+
+    /// Create an independent handle to the same transport, e.g. for the
+    /// observe thread.
+    fn try_clone(&self) -> Result<Box<dyn ClientTransport>>;
+
+    /// Enable or disable sending to broadcast addresses.
+    fn set_broadcast(&self, _on: bool) -> Result<()> {
+        Err(unsupported("broadcast"))
+    }
+
+    /// Return whether the transport is reliable and connection-oriented,
+    /// like TCP. Such transports size messages by the Max-Message-Size of
+    /// the peer instead of the path MTU.
+    fn is_reliable(&self) -> bool {
+        false
+    }
+}
+
+fn unsupported(what: &str) -> Error {
+    Error::new(
+        ErrorKind::Unsupported,
+        format!("{} is not supported by this transport", what),
+    )
+}
@@ -0,0 +1,429 @@
+//! CoAP over QUIC (experimental).
+//!
+//! Every exchange gets its own bidirectional stream: the client opens one
+//! per request, and the response as well as any later Observe notifications
+//! come back on it. Messages on a stream use the framing of CoAP over TCP
+//! ([RFC 8323](https://tools.ietf.org/html/rfc8323) section 3.2), and like
+//! over TCP there are no message types, IDs or acknowledgements. There is no
+//! CSM exchange: QUIC itself keeps the connection alive and lets it migrate
+//! between addresses. The connection negotiates the ALPN protocol `coap`, so
+//! the configurations built by the `tls` module can be used.
+//!
+//! Requires the `quic` feature. The mapping is not standardized yet and may
+//! change.
+use coap_lite::{MessageClass, MessageType, Packet};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use log::debug;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+use quinn::crypto::rustls::{QuicClientConfig, QuicServerConfig};
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
+
+use super::tcp::DEFAULT_MAX_MESSAGE_SIZE;
+use super::tls::rustls;
+use super::{ClientTransport, Transport};
+use crate::message::TcpCodec;
+
+/// Open exchanges of the server by peer and token, each with the sender of
+/// the task writing to its stream.
+type Exchanges = Arc<Mutex<HashMap<(SocketAddr, Vec<u8>), UnboundedSender<Packet>>>>;
+
+/// A QUIC endpoint serving CoAP, one stream per exchange.
+pub struct QuicTransport {
+    endpoint: Endpoint,
+    incoming: UnboundedReceiver<(Packet, SocketAddr)>,
+    exchanges: Exchanges,
+}
+
+impl QuicTransport {
+    /// Listen on the given address. Must be called from within a Tokio runtime.
+    pub fn bind<A: net::ToSocketAddrs>(
+        addr: A,
+        config: Arc<rustls::ServerConfig>,
+    ) -> Result<QuicTransport> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no address"))?;
+        let crypto = QuicServerConfig::try_from(config)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let endpoint = Endpoint::server(quinn::ServerConfig::with_crypto(Arc::new(crypto)), addr)?;
+
+        let (tx, incoming) = mpsc::unbounded_channel();
+        let exchanges = Exchanges::default();
+        tokio::spawn(accept_loop(endpoint.clone(), tx, exchanges.clone()));
+        Ok(QuicTransport {
+            endpoint,
+            incoming,
+            exchanges,
+        })
+    }
+}
+
+impl Drop for QuicTransport {
+    fn drop(&mut self) {
+        self.endpoint.close(0u32.into(), b"");
+    }
+}
+
+async fn accept_loop(
+    endpoint: Endpoint,
+    incoming: UnboundedSender<(Packet, SocketAddr)>,
+    exchanges: Exchanges,
+) {
+    while let Some(connecting) = endpoint.accept().await {
+        let incoming = incoming.clone();
+        let exchanges = exchanges.clone();
+        tokio::spawn(async move {
+            match connecting.await {
+                Ok(connection) => handle_connection(connection, incoming, exchanges).await,
+                Err(e) => debug!("QUIC connection failed: {}", e),
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    connection: Connection,
+    incoming: UnboundedSender<(Packet, SocketAddr)>,
+    exchanges: Exchanges,
+) {
+    loop {
+        match connection.accept_bi().await {
+            Ok((send, recv)) => {
+                // the address may change as the connection migrates
+                let peer = connection.remote_address();
+                tokio::spawn(serve_stream(
+                    send,
+                    recv,
+                    peer,
+                    incoming.clone(),
+                    exchanges.clone(),
+                ));
+            }
+            Err(e) => {
+                debug!(
+                    "QUIC connection to {} closed: {}",
+                    connection.remote_address(),
+                    e
+                );
+                break;
+            }
+        }
+    }
+}
+
+/// Pass the requests of a stream on and write the answers back, until a
+/// response without Observe option ends the exchange.
+async fn serve_stream(
+    send: SendStream,
+    recv: RecvStream,
+    peer: SocketAddr,
+    incoming: UnboundedSender<(Packet, SocketAddr)>,
+    exchanges: Exchanges,
+) {
+    let mut reader = FramedRead::new(recv, TcpCodec::new(DEFAULT_MAX_MESSAGE_SIZE));
+    let mut writer = FramedWrite::new(send, TcpCodec::new(DEFAULT_MAX_MESSAGE_SIZE));
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut tokens = Vec::new();
+    let mut reading = true;
+
+    loop {
+        tokio::select! {
+            message = reader.next(), if reading => match message {
+                Some(Ok(packet)) => {
+                    let token = packet.get_token().to_vec();
+                    exchanges
+                        .lock()
+                        .unwrap()
+                        .insert((peer, token.clone()), tx.clone());
+                    tokens.push(token);
+                    if incoming.send((packet, peer)).is_err() {
+                        break;
+                    }
+                }
+                Some(Err(e)) => {
+                    debug!("invalid message from {}: {}", peer, e);
+                    break;
+                }
+                // the client finished sending, answers may still follow
+                None => reading = false,
+            },
+            packet = rx.recv() => match packet {
+                Some(packet) => {
+                    let last = packet.get_observe_value().is_none();
+                    if writer.send(packet).await.is_err() || last {
+                        break;
+                    }
+                }
+                None => break,
+            },
+        }
+    }
+
+    let mut exchanges = exchanges.lock().unwrap();
+    for token in tokens {
+        // a later request with the same token may have taken over
+        let key = (peer, token);
+        if exchanges
+            .get(&key)
+            .is_some_and(|sender| sender.same_channel(&tx))
+        {
+            exchanges.remove(&key);
+        }
+    }
+    let _ = writer.get_mut().finish();
+}
+
+impl Transport for QuicTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.endpoint.local_addr()
+    }
+
+    fn verifies_addresses(&self) -> bool {
+        true
+    }
+}
+
+impl Stream for QuicTransport {
+    type Item = Result<(Packet, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_recv(cx).map(|message| message.map(Ok))
+    }
+}
+
+impl Sink<(Packet, SocketAddr)> for QuicTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, (packet, addr): (Packet, SocketAddr)) -> Result<()> {
+        // empty messages only matter for UDP reliability
+        if packet.header.code == MessageClass::Empty {
+            return Ok(());
+        }
+        let key = (addr, packet.get_token().to_vec());
+        match self.exchanges.lock().unwrap().get(&key) {
+            Some(exchange) if exchange.send(packet).is_ok() => {}
+            _ => debug!("no open exchange with {} for the message", addr),
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A client connection carrying CoAP over QUIC.
+///
+/// QUIC needs an async runtime, so the transport runs its own single-threaded
+/// Tokio runtime in the background. Every request opens a new stream.
+pub struct QuicClientTransport {
+    runtime: Arc<tokio::runtime::Runtime>,
+    endpoint: Endpoint,
+    connection: Connection,
+    incoming_tx: std_mpsc::Sender<Result<Packet>>,
+    incoming: Arc<Mutex<std_mpsc::Receiver<Result<Packet>>>>,
+    read_timeout: Arc<Mutex<Option<Duration>>>,
+}
+
+impl QuicClientTransport {
+    /// Connect to a CoAP over QUIC endpoint. `server_name` is used for SNI
+    /// and certificate verification.
+    pub fn connect<A: net::ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<QuicClientTransport> {
+        let peer = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no address"))?;
+        let crypto = QuicClientConfig::try_from(config)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_all()
+            .build()?;
+
+        let (endpoint, connection) = runtime.block_on(async {
+            let local_ip = match peer {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            };
+            let mut endpoint = Endpoint::client(SocketAddr::new(local_ip, 0))?;
+            endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+            let connection = endpoint
+                .connect(peer, server_name)
+                .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+                .await
+                .map_err(|e| Error::new(ErrorKind::ConnectionRefused, e))?;
+            Ok::<_, Error>((endpoint, connection))
+        })?;
+
+        let (incoming_tx, incoming) = std_mpsc::channel();
+        Ok(QuicClientTransport {
+            runtime: Arc::new(runtime),
+            endpoint,
+            connection,
+            incoming_tx,
+            incoming: Arc::new(Mutex::new(incoming)),
+            read_timeout: Arc::default(),
+        })
+    }
+}
+
+/// Hand every message of a stream to the client until the server finishes it.
+async fn read_stream(recv: RecvStream, incoming: std_mpsc::Sender<Result<Packet>>) {
+    let mut reader = FramedRead::new(recv, TcpCodec::new(DEFAULT_MAX_MESSAGE_SIZE));
+    while let Some(message) = reader.next().await {
+        let failed = message.is_err();
+        if incoming.send(message).is_err() || failed {
+            break;
+        }
+    }
+}
+
+impl ClientTransport for QuicClientTransport {
+    fn send_to(&self, buf: &[u8], _addr: &SocketAddr) -> Result<usize> {
+        let packet = Packet::from_bytes(buf)
+            .map_err(|cause| Error::new(ErrorKind::InvalidInput, cause.to_string()))?;
+        // empty messages only matter for UDP reliability
+        if packet.header.code == MessageClass::Empty {
+            return Ok(buf.len());
+        }
+
+        let connection = self.connection.clone();
+        let incoming = self.incoming_tx.clone();
+        self.runtime.block_on(async move {
+            let (send, recv) = connection
+                .open_bi()
+                .await
+                .map_err(|e| Error::new(ErrorKind::NotConnected, e))?;
+            let mut writer = FramedWrite::new(send, TcpCodec::new(DEFAULT_MAX_MESSAGE_SIZE));
+            writer.send(packet).await?;
+            // a request stream carries only the request
+            let _ = writer.get_mut().finish();
+            tokio::spawn(read_stream(recv, incoming));
+            Ok::<_, Error>(())
+        })?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        let timeout = *self.read_timeout.lock().unwrap();
+        let incoming = self.incoming.lock().unwrap();
+        let mut packet = match timeout {
+            Some(timeout) => incoming.recv_timeout(timeout).map_err(|e| match e {
+                std_mpsc::RecvTimeoutError::Timeout => {
+                    Error::new(ErrorKind::WouldBlock, "receive timed out")
+                }
+                std_mpsc::RecvTimeoutError::Disconnected => {
+                    Error::new(ErrorKind::NotConnected, "connection closed")
+                }
+            })?,
+            None => incoming
+                .recv()
+                .map_err(|_| Error::new(ErrorKind::NotConnected, "connection closed"))?,
+        }?;
+
+        packet.header.set_type(MessageType::NonConfirmable);
+        let bytes = packet
+            .to_bytes()
+            .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string()))?;
+        if bytes.len() > buf.len() {
+            return Err(Error::new(ErrorKind::InvalidData, "message too large"));
+        }
+        buf[..bytes.len()].copy_from_slice(&bytes);
+        Ok((bytes.len(), self.connection.remote_address()))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        *self.read_timeout.lock().unwrap() = dur;
+        Ok(())
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        Ok(*self.read_timeout.lock().unwrap())
+    }
+
+    fn is_reliable(&self) -> bool {
+        true
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn ClientTransport>> {
+        Ok(Box::new(QuicClientTransport {
+            runtime: self.runtime.clone(),
+            endpoint: self.endpoint.clone(),
+            connection: self.connection.clone(),
+            incoming_tx: self.incoming_tx.clone(),
+            incoming: self.incoming.clone(),
+            read_timeout: self.read_timeout.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::super::tls;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
+
+    #[test]
+    fn test_quic() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key =
+            rustls::pki_types::PrivateKeyDer::try_from(certified.key_pair.serialize_der()).unwrap();
+        let config = tls::server_config(vec![cert.clone()], key).unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let transport = QuicTransport::bind("127.0.0.1:0", config).unwrap();
+                    let mut server = Server::from_transport(transport);
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|req: CoapRequest<SocketAddr>| async {
+                            let mut response = req.response?;
+                            response.message.payload = req.message.payload;
+                            Some::<CoapResponse>(response)
+                        })
+                        .await
+                        .unwrap();
+                })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let mut client =
+            CoAPClient::new_quic(server_addr, "localhost", tls::client_config(roots).unwrap())
+                .unwrap();
+        for payload in [b"first".to_vec(), b"second".to_vec()] {
+            let response = client
+                .request_path("/echo", Method::Post, Some(payload.clone()), None, None)
+                .unwrap();
+            assert_eq!(response.message.payload, payload);
+        }
+    }
+}
@@ -0,0 +1,282 @@
+//! Sharding of a server transport across several dispatch loops.
+//!
+//! A [`Server`](crate::Server) dispatches the messages of its transports one
+//! after another, which limits it to a single core. [`shard`] splits a
+//! transport into [`ShardTransport`]s that each get the messages of a fixed
+//! part of the peers, chosen by a hash of their address. A server per shard,
+//! each on a thread or task of its own, then holds the deduplication,
+//! observe and block transfer state of its peers alone, and the servers run
+//! in parallel. The shards share the transport for sending.
+//!
+//! Multicast groups are joined by the transport, so they are joined with
+//! the server of one shard only; requests sent to them are still delivered
+//! to the shard of their sender.
+use coap_lite::Packet;
+use futures::task::{self, ArcWake};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use log::debug;
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::BuildHasher;
+use std::io::Result;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use super::{PeerIdentity, Transport};
+use crate::audit::SecurityEventHandler;
+
+/// Split `transport` into `shards` transports, each receiving the messages
+/// of the peers whose address hashes to it.
+pub fn shard<T: Transport + 'static>(transport: T, shards: usize) -> Vec<ShardTransport> {
+    let shards = shards.max(1);
+    let shared = Arc::new(Mutex::new(Shared {
+        transport: Box::new(transport),
+        seed: RandomState::new(),
+        queues: (0..shards).map(|_| Some(VecDeque::new())).collect(),
+        closed: false,
+    }));
+    let receivers = Arc::new(Wakers::new(shards));
+    let senders = Arc::new(Wakers::new(shards));
+    (0..shards)
+        .map(|index| ShardTransport {
+            index,
+            shared: shared.clone(),
+            receivers: receivers.clone(),
+            senders: senders.clone(),
+        })
+        .collect()
+}
+
+struct Shared {
+    transport: Box<dyn Transport>,
+    seed: RandomState,
+    /// The messages received for each shard, `None` once it is dropped.
+    queues: Vec<Option<VecDeque<(Packet, SocketAddr)>>>,
+    closed: bool,
+}
+
+/// The wakers of the shards waiting for the transport. A transport only
+/// wakes the task that polled it last, so it is polled with a waker that
+/// wakes all of them.
+struct Wakers(Mutex<Vec<Option<Waker>>>);
+
+impl Wakers {
+    fn new(shards: usize) -> Wakers {
+        Wakers(Mutex::new(vec![None; shards]))
+    }
+
+    fn register(&self, index: usize, waker: &Waker) {
+        self.0.lock().unwrap()[index] = Some(waker.clone());
+    }
+
+    fn wake_shard(&self, index: usize) {
+        if let Some(waker) = self.0.lock().unwrap()[index].take() {
+            waker.wake();
+        }
+    }
+}
+
+impl ArcWake for Wakers {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        for waker in arc_self
+            .0
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter_map(Option::take)
+        {
+            waker.wake();
+        }
+    }
+}
+
+/// One shard of a transport split with [`shard`].
+pub struct ShardTransport {
+    index: usize,
+    shared: Arc<Mutex<Shared>>,
+    receivers: Arc<Wakers>,
+    senders: Arc<Wakers>,
+}
+
+impl ShardTransport {
+    /// Return the index of the shard.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Poll the sink of the shared transport with `poll`.
+    fn poll_send(
+        &self,
+        cx: &mut Context<'_>,
+        poll: fn(&mut Box<dyn Transport>, &mut Context<'_>) -> Poll<Result<()>>,
+    ) -> Poll<Result<()>> {
+        let mut shared = self.shared.lock().unwrap();
+        self.senders.register(self.index, cx.waker());
+        let waker = task::waker(self.senders.clone());
+        poll(&mut shared.transport, &mut Context::from_waker(&waker))
+    }
+}
+
+impl Transport for ShardTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.shared.lock().unwrap().transport.local_addr()
+    }
+
+    fn join_multicast(&mut self, addr: IpAddr) -> Result<()> {
+        self.shared.lock().unwrap().transport.join_multicast(addr)
+    }
+
+    fn leave_multicast(&mut self, addr: IpAddr) -> Result<()> {
+        self.shared.lock().unwrap().transport.leave_multicast(addr)
+    }
+
+    fn multicast_groups(&self) -> Vec<IpAddr> {
+        self.shared.lock().unwrap().transport.multicast_groups()
+    }
+
+    fn multicast_group(&self, addr: &SocketAddr) -> Option<IpAddr> {
+        self.shared.lock().unwrap().transport.multicast_group(addr)
+    }
+
+    fn verifies_addresses(&self) -> bool {
+        self.shared.lock().unwrap().transport.verifies_addresses()
+    }
+
+    fn peer_identity(&self, addr: &SocketAddr) -> PeerIdentity {
+        self.shared.lock().unwrap().transport.peer_identity(addr)
+    }
+
+    fn set_security_event_handler(&mut self, handler: SecurityEventHandler) {
+        self.shared
+            .lock()
+            .unwrap()
+            .transport
+            .set_security_event_handler(handler)
+    }
+}
+
+impl Stream for ShardTransport {
+    type Item = Result<(Packet, SocketAddr)>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut shared = self.shared.lock().unwrap();
+        let shards = shared.queues.len();
+        loop {
+            let queue = shared.queues[self.index].as_mut();
+            if let Some(message) = queue.and_then(|queue| queue.pop_front()) {
+                return Poll::Ready(Some(Ok(message)));
+            }
+            if shared.closed {
+                return Poll::Ready(None);
+            }
+            self.receivers.register(self.index, cx.waker());
+            let waker = task::waker(self.receivers.clone());
+            let message = shared
+                .transport
+                .poll_next_unpin(&mut Context::from_waker(&waker));
+            let (packet, addr) = match message {
+                Poll::Ready(Some(Ok(message))) => message,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    shared.closed = true;
+                    ArcWake::wake_by_ref(&self.receivers);
+                    return Poll::Ready(None);
+                }
+                Poll::Pending => return Poll::Pending,
+            };
+            let index = (shared.seed.hash_one(addr) % shards as u64) as usize;
+            if index == self.index {
+                return Poll::Ready(Some(Ok((packet, addr))));
+            }
+            match shared.queues[index].as_mut() {
+                Some(queue) => {
+                    queue.push_back((packet, addr));
+                    self.receivers.wake_shard(index);
+                }
+                None => debug!("drop message of {} for stopped shard {}", addr, index),
+            }
+        }
+    }
+}
+
+impl Sink<(Packet, SocketAddr)> for ShardTransport {
+    type Error = std::io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_send(cx, |transport, cx| transport.poll_ready_unpin(cx))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: (Packet, SocketAddr)) -> Result<()> {
+        self.shared.lock().unwrap().transport.start_send_unpin(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_send(cx, |transport, cx| transport.poll_flush_unpin(cx))
+    }
+
+    // the other shards still send through the transport
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl Drop for ShardTransport {
+    fn drop(&mut self) {
+        if let Ok(mut shared) = self.shared.lock() {
+            shared.queues[self.index] = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::*;
+    use crate::transport::MemoryTransport;
+    use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_shards() {
+        let transport = MemoryTransport::new("10.0.0.1:5683".parse().unwrap());
+        let endpoints: Vec<_> = (2..18)
+            .map(|host| transport.connect(SocketAddr::from(([10, 0, 0, host], 5683))))
+            .collect();
+
+        for shard in shard(transport, 2) {
+            std::thread::spawn(move || {
+                let index = shard.index();
+                tokio::runtime::Runtime::new()
+                    .unwrap()
+                    .block_on(async move {
+                        Server::from_transport(shard)
+                            .run(move |req: CoapRequest<SocketAddr>| async move {
+                                let mut response = req.response?;
+                                response.message.payload = vec![index as u8];
+                                Some::<CoapResponse>(response)
+                            })
+                            .await
+                            .unwrap();
+                    })
+            });
+        }
+
+        let mut used = HashSet::new();
+        for endpoint in endpoints {
+            let mut client = CoAPClient::from_transport(endpoint, "10.0.0.1:5683").unwrap();
+            let mut shards = HashSet::new();
+            for _ in 0..3 {
+                let response = client
+                    .request_path("/", Method::Get, None, None, None)
+                    .unwrap();
+                shards.insert(response.message.payload[0]);
+            }
+            // a peer always reaches the same shard
+            assert_eq!(shards.len(), 1);
+            used.extend(shards);
+        }
+        assert_eq!(used.len(), 2);
+    }
+}
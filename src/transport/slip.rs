@@ -0,0 +1,211 @@
+//! CoAP over SLIP ([RFC 1055](https://tools.ietf.org/html/rfc1055)) framed
+//! serial links such as UART or RS-485.
+//!
+//! Messages use the regular UDP message format, each one wrapped in a SLIP
+//! frame. A serial link is point-to-point, so the peer is always reported as
+//! [`SERIAL_PEER`].
+use bytes::{Buf, BufMut, BytesMut};
+use coap_lite::Packet;
+use futures::{Sink, Stream};
+use log::debug;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use super::Transport;
+
+/// Placeholder address of the device at the other end of the link.
+pub const SERIAL_PEER: SocketAddr = SocketAddr::new(
+    std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    0,
+);
+
+const END: u8 = 0xc0;
+const ESC: u8 = 0xdb;
+const ESC_END: u8 = 0xdc;
+const ESC_ESC: u8 = 0xdd;
+
+/// Codec wrapping CoAP messages in SLIP frames. Frames that do not hold a
+/// valid message, e.g. because of line noise, are skipped.
+#[derive(Default)]
+pub struct SlipCodec {}
+
+impl SlipCodec {
+    pub fn new() -> SlipCodec {
+        SlipCodec {}
+    }
+}
+
+impl Decoder for SlipCodec {
+    type Item = Packet;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Packet>> {
+        while let Some(end) = buf.iter().position(|&b| b == END) {
+            let frame = buf.split_to(end);
+            buf.advance(1);
+            if frame.is_empty() {
+                continue;
+            }
+
+            let mut bytes = Vec::with_capacity(frame.len());
+            let mut escaped = false;
+            for &b in frame.iter() {
+                match (escaped, b) {
+                    (false, ESC) => escaped = true,
+                    (true, ESC_END) => {
+                        bytes.push(END);
+                        escaped = false;
+                    }
+                    (true, ESC_ESC) => {
+                        bytes.push(ESC);
+                        escaped = false;
+                    }
+                    (true, b) => {
+                        // protocol violation, keep the byte as RFC 1055 suggests
+                        bytes.push(b);
+                        escaped = false;
+                    }
+                    (false, b) => bytes.push(b),
+                }
+            }
+
+            match Packet::from_bytes(&bytes) {
+                Ok(packet) => return Ok(Some(packet)),
+                Err(e) => debug!("skip invalid slip frame: {}", e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Encoder<Packet> for SlipCodec {
+    type Error = Error;
+
+    fn encode(&mut self, packet: Packet, buf: &mut BytesMut) -> Result<()> {
+        let bytes = packet
+            .to_bytes()
+            .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string()))?;
+        buf.reserve(bytes.len() + 2);
+        // a leading END flushes any noise the receiver may have collected
+        buf.put_u8(END);
+        for b in bytes {
+            match b {
+                END => buf.extend_from_slice(&[ESC, ESC_END]),
+                ESC => buf.extend_from_slice(&[ESC, ESC_ESC]),
+                b => buf.put_u8(b),
+            }
+        }
+        buf.put_u8(END);
+        Ok(())
+    }
+}
+
+/// A SLIP framed transport over a byte stream, typically a serial port.
+pub struct SlipTransport<S> {
+    framed: Framed<S, SlipCodec>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> SlipTransport<S> {
+    pub fn new(stream: S) -> SlipTransport<S> {
+        SlipTransport {
+            framed: Framed::new(stream, SlipCodec::new()),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Transport for SlipTransport<S> {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(SERIAL_PEER)
+    }
+
+    fn verifies_addresses(&self) -> bool {
+        true
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Stream for SlipTransport<S> {
+    type Item = Result<(Packet, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.framed)
+            .poll_next(cx)
+            .map(|message| message.map(|result| result.map(|packet| (packet, SERIAL_PEER))))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Sink<(Packet, SocketAddr)> for SlipTransport<S> {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.framed).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, (packet, _addr): (Packet, SocketAddr)) -> Result<()> {
+        Pin::new(&mut self.framed).start_send(packet)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.framed).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.framed).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
+    use futures::{SinkExt, StreamExt};
+
+    #[test]
+    fn test_codec() {
+        let mut packet = Packet::new();
+        packet.payload = vec![1, END, 2, ESC, 3];
+
+        let mut codec = SlipCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(packet.clone(), &mut buf).unwrap();
+        assert!(!buf[1..buf.len() - 1].contains(&END));
+
+        // noise before the frame is skipped
+        let mut noisy = BytesMut::from(&[0x01, 0x02][..]);
+        noisy.extend_from_slice(&buf);
+        assert_eq!(codec.decode(&mut noisy).unwrap().unwrap().payload, packet.payload);
+        assert!(codec.decode(&mut noisy).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_slip_transport() {
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let (device, gateway) = tokio::io::duplex(1024);
+            tokio::spawn(async move {
+                Server::from_transport(SlipTransport::new(gateway))
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        let mut response = req.response?;
+                        response.message.payload = b"uart".to_vec();
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            });
+
+            let mut device = Framed::new(device, SlipCodec::new());
+            let mut request = CoapRequest::<SocketAddr>::new();
+            request.set_method(Method::Get);
+            request.set_path("/sensor");
+            request.message.header.message_id = 7;
+            device.send(request.message).await.unwrap();
+
+            let response = device.next().await.unwrap().unwrap();
+            assert_eq!(response.header.message_id, 7);
+            assert_eq!(response.payload, b"uart".to_vec());
+        });
+    }
+}
@@ -0,0 +1,1065 @@
+//! CoAP over TCP ([RFC 8323](https://tools.ietf.org/html/rfc8323)).
+//!
+//! Messages are framed with a length prefix instead of the UDP header, so
+//! there is no Message ID and no message type: the reliability of the
+//! connection replaces CON/ACK. Each side starts with a Capabilities and
+//! Settings Message (CSM) announcing e.g. the largest message it accepts.
+//!
+//! Connections are managed with the other signaling messages: Ping and Pong
+//! keep idle connections alive, Release closes a connection gracefully and
+//! Abort closes it after a protocol error.
+use bytes::BytesMut;
+use coap_lite::{option_value::OptionValueU32, CoapOption, MessageClass, MessageType, Packet};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use log::debug;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{self, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use super::{ClientTransport, Transport};
+use crate::audit::{self, SecurityEvent, SecurityEventHandler};
+pub use crate::message::TcpCodec;
+use crate::message::{self, Signal};
+#[cfg(feature = "tls")]
+use super::tls::rustls;
+
+/// Largest message size a peer may assume before it received our CSM.
+pub const DEFAULT_MAX_MESSAGE_SIZE: u32 = 1152;
+
+/// Max-Message-Size option of a CSM.
+const MAX_MESSAGE_SIZE_OPTION: u16 = 2;
+/// Block-Wise-Transfer option of a CSM.
+const BLOCK_WISE_TRANSFER_OPTION: u16 = 4;
+
+type Connections = Arc<Mutex<HashMap<SocketAddr, UnboundedSender<Packet>>>>;
+
+/// Settings a peer announced in its CSM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerSettings {
+    pub max_message_size: u32,
+    pub block_wise_transfer: bool,
+}
+
+impl Default for PeerSettings {
+    fn default() -> Self {
+        PeerSettings {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            block_wise_transfer: false,
+        }
+    }
+}
+
+impl PeerSettings {
+    fn update(&mut self, csm: &Packet) {
+        if let Some(Ok(OptionValueU32(size))) =
+            csm.get_first_option_as::<OptionValueU32>(CoapOption::from(MAX_MESSAGE_SIZE_OPTION))
+        {
+            self.max_message_size = size;
+        }
+        if csm
+            .get_option(CoapOption::from(BLOCK_WISE_TRANSFER_OPTION))
+            .is_some()
+        {
+            self.block_wise_transfer = true;
+        }
+    }
+}
+
+/// Why a connection was closed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// We released the connection, e.g. on shutdown.
+    Released,
+    /// The peer released the connection.
+    PeerReleased,
+    /// We aborted the connection after a protocol error.
+    Aborted(String),
+    /// The peer aborted the connection with the given diagnostic.
+    PeerAborted(String),
+    /// Nothing was received within the idle timeout, so we released the
+    /// connection.
+    IdleTimeout,
+    /// The connection was closed or failed without signaling.
+    Disconnected,
+}
+
+/// Lifecycle events of the connections of a [`TcpTransport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    Connected(SocketAddr),
+    Closed(SocketAddr, CloseReason),
+}
+
+type EventHandler = Arc<dyn Fn(&ConnectionEvent) + Send + Sync>;
+
+/// Connection management settings of a [`TcpTransport`].
+#[derive(Clone, Default)]
+struct ConnectionConfig {
+    ping_interval: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    event_handler: Option<EventHandler>,
+    security_event_handler: Option<SecurityEventHandler>,
+}
+
+impl ConnectionConfig {
+    fn emit(&self, event: ConnectionEvent) {
+        if let Some(ref handler) = self.event_handler {
+            handler(&event);
+        }
+    }
+
+    /// Return when the keepalive timer of a connection fires next.
+    fn next_timer(&self, last_received: Instant, pinged: bool) -> Option<Instant> {
+        let ping = self
+            .ping_interval
+            .filter(|_| !pinged)
+            .map(|interval| last_received + interval);
+        let idle = self.idle_timeout.map(|timeout| last_received + timeout);
+        match (ping, idle) {
+            (Some(ping), Some(idle)) => Some(ping.min(idle)),
+            (ping, idle) => ping.or(idle),
+        }
+    }
+}
+
+/// Build the CSM sent at the start of a connection.
+pub fn csm(max_message_size: u32, block_wise_transfer: bool) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.code = Signal::Csm.message_class();
+    packet.add_option_as(
+        CoapOption::from(MAX_MESSAGE_SIZE_OPTION),
+        OptionValueU32(max_message_size),
+    );
+    if block_wise_transfer {
+        packet.add_option(CoapOption::from(BLOCK_WISE_TRANSFER_OPTION), Vec::new());
+    }
+    packet
+}
+
+fn signal(signal: Signal, token: Vec<u8>) -> Packet {
+    let mut packet = Packet::new();
+    packet.header.code = signal.message_class();
+    packet.set_token(token);
+    packet
+}
+
+/// Build a Ping. The peer answers with a Pong carrying the same token.
+pub fn ping(token: Vec<u8>) -> Packet {
+    signal(Signal::Ping, token)
+}
+
+/// Build the Pong answering a Ping.
+pub fn pong(ping: &Packet) -> Packet {
+    signal(Signal::Pong, ping.get_token().to_vec())
+}
+
+/// Build a Release, announcing that the connection is closed gracefully.
+pub fn release() -> Packet {
+    signal(Signal::Release, Vec::new())
+}
+
+/// Build an Abort, announcing that the connection is closed because of a
+/// protocol error. The diagnostic is sent as payload.
+pub fn abort(diagnostic: &str) -> Packet {
+    let mut packet = signal(Signal::Abort, Vec::new());
+    packet.payload = diagnostic.as_bytes().to_vec();
+    packet
+}
+
+/// Return whether the packet is a CSM.
+pub fn is_csm(packet: &Packet) -> bool {
+    Signal::from_packet(packet) == Some(Signal::Csm)
+}
+
+/// Return whether the packet is a signaling message (code class 7).
+pub fn is_signaling(packet: &Packet) -> bool {
+    Signal::from_packet(packet).is_some()
+}
+
+/// A reliable connection that carries whole CoAP messages, e.g. a TCP
+/// stream with length-prefixed framing or a WebSocket.
+pub(super) trait Connection: Send + Sync {
+    fn write_packet(&self, packet: Packet) -> Result<()>;
+
+    fn read_packet(&self) -> Result<Packet>;
+
+    /// The underlying socket, used for timeouts.
+    fn socket(&self) -> &net::TcpStream;
+}
+
+/// A TCP stream, optionally secured with TLS, with length-prefixed framing.
+struct FramedConnection {
+    socket: net::TcpStream,
+    /// The TLS session. It is locked only while records are processed, not
+    /// while waiting for them, so that a blocked read does not hold up
+    /// writes.
+    #[cfg(feature = "tls")]
+    tls: Option<Mutex<rustls::ClientConnection>>,
+    read_buffer: Mutex<BytesMut>,
+}
+
+impl FramedConnection {
+    fn read_chunk(&self, chunk: &mut [u8]) -> Result<usize> {
+        #[cfg(feature = "tls")]
+        if let Some(ref tls) = self.tls {
+            return self.read_tls(tls, chunk);
+        }
+        (&self.socket).read(chunk)
+    }
+
+    #[cfg(feature = "tls")]
+    fn read_tls(&self, tls: &Mutex<rustls::ClientConnection>, chunk: &mut [u8]) -> Result<usize> {
+        let mut records = [0; 4096];
+        loop {
+            match tls.lock().unwrap().reader().read(chunk) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            let n = (&self.socket).read(&mut records)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut tls = tls.lock().unwrap();
+            let mut records = &records[..n];
+            while !records.is_empty() {
+                tls.read_tls(&mut records)?;
+                tls.process_new_packets()
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            }
+            // e.g. alerts or key updates
+            while tls.wants_write() {
+                tls.write_tls(&mut &self.socket)?;
+            }
+        }
+    }
+}
+
+impl Connection for FramedConnection {
+    fn write_packet(&self, packet: Packet) -> Result<()> {
+        let mut buf = BytesMut::new();
+        TcpCodec::new(u32::MAX).encode(packet, &mut buf)?;
+        #[cfg(feature = "tls")]
+        if let Some(ref tls) = self.tls {
+            let mut tls = tls.lock().unwrap();
+            tls.writer().write_all(&buf)?;
+            while tls.wants_write() {
+                tls.write_tls(&mut &self.socket)?;
+            }
+            return Ok(());
+        }
+        (&self.socket).write_all(&buf)
+    }
+
+    fn read_packet(&self) -> Result<Packet> {
+        let mut codec = TcpCodec::new(DEFAULT_MAX_MESSAGE_SIZE);
+        let mut buffer = self.read_buffer.lock().unwrap();
+        loop {
+            if let Some(packet) = codec.decode(&mut buffer)? {
+                return Ok(packet);
+            }
+            let mut chunk = [0; 1024];
+            let n = self.read_chunk(&mut chunk)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"));
+            }
+            buffer.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    fn socket(&self) -> &net::TcpStream {
+        &self.socket
+    }
+}
+
+/// A blocking CoAP over TCP, TLS or WebSocket connection used by the client.
+///
+/// Responses are matched to requests by token only. Received messages are
+/// handed to the client as non-confirmable, so it never tries to acknowledge
+/// them. Clones share the connection. Over TCP and TLS, clones can send
+/// while another one is blocked in a read; over WebSocket, a blocked read
+/// holds the connection for up to the read timeout.
+///
+/// Pings of the peer are answered while receiving. Once the connection has
+/// been released or aborted, by either side, sending fails with
+/// `NotConnected` and [`close_reason`](Self::close_reason) tells why.
+pub struct TcpClientTransport {
+    connection: Arc<dyn Connection>,
+    peer: SocketAddr,
+    settings: Arc<Mutex<PeerSettings>>,
+    close_reason: Arc<Mutex<Option<CloseReason>>>,
+}
+
+impl TcpClientTransport {
+    /// Connect to the peer and exchange CSMs.
+    pub fn connect<A: net::ToSocketAddrs>(addr: A) -> Result<TcpClientTransport> {
+        let socket = net::TcpStream::connect(addr)?;
+        socket.set_nodelay(true)?;
+        Self::handshake(FramedConnection {
+            socket,
+            #[cfg(feature = "tls")]
+            tls: None,
+            read_buffer: Mutex::default(),
+        })
+    }
+
+    /// Connect to the peer over TLS (coaps+tcp) and exchange CSMs.
+    /// `server_name` is sent as SNI and used to verify the certificate.
+    #[cfg(feature = "tls")]
+    pub fn connect_tls<A: net::ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: Arc<rustls::ClientConfig>,
+    ) -> Result<TcpClientTransport> {
+        let socket = net::TcpStream::connect(addr)?;
+        socket.set_nodelay(true)?;
+        let mut tls = super::tls::client_connection(server_name, config)?;
+        // the TLS handshake, before readers and writers share the session
+        while tls.is_handshaking() {
+            tls.complete_io(&mut &socket)?;
+        }
+        Self::handshake(FramedConnection {
+            socket,
+            tls: Some(Mutex::new(tls)),
+            read_buffer: Mutex::default(),
+        })
+    }
+
+    /// Send our CSM and wait for the one of the peer.
+    pub(super) fn handshake<C: Connection + 'static>(connection: C) -> Result<TcpClientTransport> {
+        let transport = TcpClientTransport {
+            peer: connection.socket().peer_addr()?,
+            connection: Arc::new(connection),
+            settings: Arc::default(),
+            close_reason: Arc::default(),
+        };
+
+        transport
+            .connection
+            .write_packet(csm(DEFAULT_MAX_MESSAGE_SIZE, true))?;
+        let socket = transport.connection.socket();
+        let timeout = socket.read_timeout()?;
+        socket.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let first = transport.connection.read_packet()?;
+        if !is_csm(&first) {
+            return Err(Error::new(ErrorKind::InvalidData, "peer did not send a csm"));
+        }
+        transport.settings.lock().unwrap().update(&first);
+        socket.set_read_timeout(timeout)?;
+        Ok(transport)
+    }
+
+    /// Return the settings announced by the peer.
+    pub fn peer_settings(&self) -> PeerSettings {
+        *self.settings.lock().unwrap()
+    }
+
+    /// Return why the connection was closed, or `None` while it is open.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason.lock().unwrap().clone()
+    }
+
+    /// Send a Ping and wait for the Pong, returning the round-trip time.
+    /// Waits for at most the read timeout. Messages other than signaling
+    /// received in the meantime are dropped, so do not ping while a request
+    /// is pending.
+    pub fn ping(&self) -> Result<Duration> {
+        self.check_open()?;
+        let start = Instant::now();
+        self.connection.write_packet(ping(Vec::new()))?;
+        loop {
+            let packet = self.read_packet()?;
+            if Signal::from_packet(&packet) == Some(Signal::Pong) {
+                return Ok(start.elapsed());
+            }
+            if !self.handle_signaling(&packet)? {
+                debug!(
+                    "drop message {} while waiting for pong",
+                    packet.header.get_code()
+                );
+            }
+        }
+    }
+
+    /// Release the connection gracefully.
+    pub fn release(&self) -> Result<()> {
+        self.check_open()?;
+        self.close(CloseReason::Released);
+        self.connection.write_packet(release())?;
+        self.connection.socket().shutdown(net::Shutdown::Both)
+    }
+
+    fn check_open(&self) -> Result<()> {
+        match self.close_reason() {
+            Some(reason) => Err(Error::new(
+                ErrorKind::NotConnected,
+                format!("connection closed: {:?}", reason),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn close(&self, reason: CloseReason) {
+        self.close_reason.lock().unwrap().get_or_insert(reason);
+    }
+
+    /// Read the next message, aborting the connection if it is malformed.
+    fn read_packet(&self) -> Result<Packet> {
+        match self.connection.read_packet() {
+            Err(e) if e.kind() == ErrorKind::InvalidData => {
+                debug!("abort connection to {}: {}", self.peer, e);
+                self.close(CloseReason::Aborted(e.to_string()));
+                let _ = self.connection.write_packet(abort(&e.to_string()));
+                let _ = self.connection.socket().shutdown(net::Shutdown::Both);
+                Err(e)
+            }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                self.close(CloseReason::Disconnected);
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    /// Handle a signaling message. Returns `false` if the packet is not one.
+    fn handle_signaling(&self, packet: &Packet) -> Result<bool> {
+        match Signal::from_packet(packet) {
+            Some(Signal::Csm) => self.settings.lock().unwrap().update(packet),
+            Some(Signal::Ping) => self.connection.write_packet(pong(packet))?,
+            Some(Signal::Pong) => {}
+            Some(Signal::Release) => {
+                self.close(CloseReason::PeerReleased);
+                return Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    "peer released the connection",
+                ));
+            }
+            Some(Signal::Abort) => {
+                let diagnostic = String::from_utf8_lossy(&packet.payload).into_owned();
+                self.close(CloseReason::PeerAborted(diagnostic.clone()));
+                return Err(Error::new(
+                    ErrorKind::ConnectionAborted,
+                    format!("peer aborted the connection: {}", diagnostic),
+                ));
+            }
+            Some(Signal::Unknown(_)) => {
+                debug!("ignore signaling message {}", packet.header.get_code());
+            }
+            None => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+impl ClientTransport for TcpClientTransport {
+    fn send_to(&self, buf: &[u8], _addr: &SocketAddr) -> Result<usize> {
+        self.check_open()?;
+        let packet = Packet::from_bytes(buf)
+            .map_err(|cause| Error::new(ErrorKind::InvalidInput, cause.to_string()))?;
+        // empty messages only matter for UDP reliability
+        if packet.header.code == MessageClass::Empty {
+            return Ok(buf.len());
+        }
+
+        let settings = self.peer_settings();
+        if buf.len() > settings.max_message_size as usize {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "message exceeds the peer's Max-Message-Size",
+            ));
+        }
+        let block_wise = packet.get_option(CoapOption::Block1).is_some()
+            || packet.get_option(CoapOption::Block2).is_some();
+        if block_wise && !settings.block_wise_transfer {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "peer does not support block-wise transfers",
+            ));
+        }
+
+        self.connection.write_packet(packet)?;
+        Ok(buf.len())
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        loop {
+            let mut packet = self.read_packet()?;
+            if self.handle_signaling(&packet)? {
+                continue;
+            }
+
+            packet.header.set_type(MessageType::NonConfirmable);
+            let bytes = packet
+                .to_bytes()
+                .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string()))?;
+            if bytes.len() > buf.len() {
+                return Err(Error::new(ErrorKind::InvalidData, "message too large"));
+            }
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            return Ok((bytes.len(), self.peer));
+        }
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.connection.socket().set_read_timeout(dur)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        self.connection.socket().read_timeout()
+    }
+
+    fn is_reliable(&self) -> bool {
+        true
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn ClientTransport>> {
+        Ok(Box::new(TcpClientTransport {
+            connection: self.connection.clone(),
+            peer: self.peer,
+            settings: self.settings.clone(),
+            close_reason: self.close_reason.clone(),
+        }))
+    }
+}
+
+/// A TCP listener serving CoAP over TCP, one task per connection.
+///
+/// Pings are answered and malformed messages abort the connection. Keepalive
+/// pings, the idle timeout and the event handler are off by default; changes
+/// apply to connections accepted afterwards.
+pub struct TcpTransport {
+    local_addr: SocketAddr,
+    incoming: UnboundedReceiver<(Packet, SocketAddr)>,
+    connections: Connections,
+    config: Arc<Mutex<ConnectionConfig>>,
+}
+
+impl TcpTransport {
+    /// Listen on the given address. Must be called from within a Tokio runtime.
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> Result<TcpTransport> {
+        Self::listen(TcpListener::bind(addr).await?, |stream| async {
+            Ok(Framed::new(stream, TcpCodec::new(DEFAULT_MAX_MESSAGE_SIZE)))
+        })
+    }
+
+    /// Listen for CoAP over TLS (coaps+tcp) connections on the given address.
+    /// Pass a [`ReloadableServerConfig`](super::tls::ReloadableServerConfig)
+    /// to replace the configuration later.
+    #[cfg(feature = "tls")]
+    pub async fn bind_tls<A: ToSocketAddrs, C: Into<super::tls::ReloadableServerConfig>>(
+        addr: A,
+        config: C,
+    ) -> Result<TcpTransport> {
+        let config = config.into();
+        Self::listen(TcpListener::bind(addr).await?, move |stream| {
+            let accept = config.acceptor().accept(stream);
+            async move { Ok(Framed::new(accept.await?, TcpCodec::new(DEFAULT_MAX_MESSAGE_SIZE))) }
+        })
+    }
+
+    /// Serve the connections of the listener. `upgrade` turns every accepted
+    /// stream into a message stream, e.g. by framing it after a TLS handshake.
+    pub(super) fn listen<F, Fut, S>(listener: TcpListener, upgrade: F) -> Result<TcpTransport>
+    where
+        F: Fn(TcpStream) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<S>> + Send + 'static,
+        S: MessageStream + Send + 'static,
+    {
+        let local_addr = listener.local_addr()?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let connections = Connections::default();
+        let config = Arc::<Mutex<ConnectionConfig>>::default();
+
+        tokio::spawn(accept_loop(
+            listener,
+            upgrade,
+            tx,
+            connections.clone(),
+            config.clone(),
+        ));
+        Ok(TcpTransport {
+            local_addr,
+            incoming: rx,
+            connections,
+            config,
+        })
+    }
+
+    /// Send a Ping on connections from which nothing was received for
+    /// `interval`.
+    pub fn set_ping_interval(&self, interval: Option<Duration>) {
+        self.config.lock().unwrap().ping_interval = interval;
+    }
+
+    /// Release connections from which nothing was received for `timeout`.
+    /// Combined with a shorter ping interval, this closes connections to
+    /// peers that stopped answering.
+    pub fn set_idle_timeout(&self, timeout: Option<Duration>) {
+        self.config.lock().unwrap().idle_timeout = timeout;
+    }
+
+    /// Call `handler` when a connection is established or closed.
+    pub fn set_event_handler<F: Fn(&ConnectionEvent) + Send + Sync + 'static>(&self, handler: F) {
+        self.config.lock().unwrap().event_handler = Some(Arc::new(handler));
+    }
+
+    /// Release the connection to the given peer gracefully.
+    pub fn release(&self, peer: SocketAddr) {
+        if let Some(connection) = self.connections.lock().unwrap().get(&peer) {
+            let _ = connection.send(release());
+        }
+    }
+}
+
+async fn accept_loop<F, Fut, S>(
+    listener: TcpListener,
+    upgrade: F,
+    incoming: UnboundedSender<(Packet, SocketAddr)>,
+    connections: Connections,
+    config: Arc<Mutex<ConnectionConfig>>,
+) where
+    F: Fn(TcpStream) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<S>> + Send + 'static,
+    S: MessageStream + Send + 'static,
+{
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let (tx, rx) = mpsc::unbounded_channel();
+                    connections.lock().unwrap().insert(peer, tx);
+                    let upgraded = upgrade(stream);
+                    let incoming = incoming.clone();
+                    let connections = connections.clone();
+                    let config = config.lock().unwrap().clone();
+                    tokio::spawn(async move {
+                        match upgraded.await {
+                            Ok(stream) => {
+                                handle_connection(stream, peer, incoming, rx, connections, config)
+                                    .await
+                            }
+                            Err(e) => {
+                                debug!("connection from {} failed: {}", peer, e);
+                                connections.lock().unwrap().remove(&peer);
+                                let event = SecurityEvent::HandshakeFailed {
+                                    peer,
+                                    reason: e.to_string(),
+                                };
+                                audit::emit(&config.security_event_handler, event);
+                            }
+                        }
+                    });
+                }
+                Err(e) => debug!("accept error: {}", e),
+            },
+            _ = incoming.closed() => break,
+        }
+    }
+}
+
+/// A connection of the server carrying whole messages.
+pub(super) trait MessageStream:
+    Stream<Item = Result<Packet>> + Sink<Packet, Error = Error> + Unpin
+{
+}
+
+impl<T: Stream<Item = Result<Packet>> + Sink<Packet, Error = Error> + Unpin> MessageStream for T {}
+
+async fn handle_connection<S: MessageStream>(
+    mut framed: S,
+    peer: SocketAddr,
+    incoming: UnboundedSender<(Packet, SocketAddr)>,
+    mut outgoing: UnboundedReceiver<Packet>,
+    connections: Connections,
+    config: ConnectionConfig,
+) {
+    config.emit(ConnectionEvent::Connected(peer));
+    let reason = serve_connection(&mut framed, peer, &incoming, &mut outgoing, &config).await;
+    debug!("connection to {} closed: {:?}", peer, reason);
+    connections.lock().unwrap().remove(&peer);
+    config.emit(ConnectionEvent::Closed(peer, reason));
+}
+
+async fn serve_connection<S: MessageStream>(
+    framed: &mut S,
+    peer: SocketAddr,
+    incoming: &UnboundedSender<(Packet, SocketAddr)>,
+    outgoing: &mut UnboundedReceiver<Packet>,
+    config: &ConnectionConfig,
+) -> CloseReason {
+    let mut settings: Option<PeerSettings> = None;
+    let mut last_received = Instant::now();
+    let mut pinged = false;
+
+    if let Err(e) = framed.send(csm(DEFAULT_MAX_MESSAGE_SIZE, true)).await {
+        debug!("send csm to {} failed: {}", peer, e);
+        return CloseReason::Disconnected;
+    }
+
+    loop {
+        let timer = config.next_timer(last_received, pinged);
+        let deadline = tokio::time::Instant::from_std(timer.unwrap_or_else(Instant::now));
+        tokio::select! {
+            frame = framed.next() => {
+                let packet = match frame {
+                    Some(Ok(packet)) => packet,
+                    Some(Err(e)) if e.kind() == ErrorKind::InvalidData => {
+                        return send_abort(framed, &e.to_string()).await;
+                    }
+                    Some(Err(e)) => {
+                        debug!("receive from {} failed: {}", peer, e);
+                        return CloseReason::Disconnected;
+                    }
+                    None => return CloseReason::Disconnected,
+                };
+                last_received = Instant::now();
+                pinged = false;
+
+                let signal = Signal::from_packet(&packet);
+                if signal == Some(Signal::Csm) {
+                    settings.get_or_insert_with(PeerSettings::default).update(&packet);
+                    continue;
+                }
+                if settings.is_none() {
+                    return send_abort(framed, "expected a csm").await;
+                }
+                match signal {
+                    Some(Signal::Ping) => {
+                        if framed.send(pong(&packet)).await.is_err() {
+                            return CloseReason::Disconnected;
+                        }
+                    }
+                    Some(Signal::Release) => return CloseReason::PeerReleased,
+                    Some(Signal::Abort) => {
+                        let diagnostic = String::from_utf8_lossy(&packet.payload).into_owned();
+                        return CloseReason::PeerAborted(diagnostic);
+                    }
+                    Some(Signal::Pong) | Some(Signal::Csm) => {}
+                    Some(Signal::Unknown(_)) => {
+                        debug!("ignore signaling message {} from {}", packet.header.get_code(), peer);
+                    }
+                    // messages without a code only matter for UDP reliability
+                    None if packet.header.code == MessageClass::Empty => {}
+                    None => {
+                        if incoming.send((packet, peer)).is_err() {
+                            return send_release(framed).await;
+                        }
+                    }
+                }
+            },
+            packet = outgoing.recv() => match packet {
+                Some(packet) if packet.header.code == MessageClass::Empty => {}
+                Some(packet) if Signal::from_packet(&packet) == Some(Signal::Release) => {
+                    return send_release(framed).await;
+                }
+                Some(packet) => {
+                    let max_message_size = settings.unwrap_or_default().max_message_size;
+                    let size = message::encoded_len(&packet);
+                    if size > max_message_size as usize {
+                        debug!("drop message of {} bytes to {}", size, peer);
+                        continue;
+                    }
+                    if let Err(e) = framed.send(packet).await {
+                        debug!("send to {} failed: {}", peer, e);
+                        return CloseReason::Disconnected;
+                    }
+                }
+                None => return send_release(framed).await,
+            },
+            _ = incoming.closed() => return send_release(framed).await,
+            _ = tokio::time::sleep_until(deadline), if timer.is_some() => {
+                let idle = config
+                    .idle_timeout
+                    .is_some_and(|timeout| last_received.elapsed() >= timeout);
+                if idle {
+                    let _ = framed.send(release()).await;
+                    return CloseReason::IdleTimeout;
+                }
+                pinged = true;
+                if framed.send(ping(Vec::new())).await.is_err() {
+                    return CloseReason::Disconnected;
+                }
+            }
+        }
+    }
+}
+
+async fn send_release<S: MessageStream>(framed: &mut S) -> CloseReason {
+    let _ = framed.send(release()).await;
+    CloseReason::Released
+}
+
+async fn send_abort<S: MessageStream>(framed: &mut S, diagnostic: &str) -> CloseReason {
+    let _ = framed.send(abort(diagnostic)).await;
+    CloseReason::Aborted(diagnostic.to_string())
+}
+
+impl Transport for TcpTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    fn verifies_addresses(&self) -> bool {
+        true
+    }
+
+    fn set_security_event_handler(&mut self, handler: SecurityEventHandler) {
+        self.config.lock().unwrap().security_event_handler = Some(handler);
+    }
+}
+
+impl Stream for TcpTransport {
+    type Item = Result<(Packet, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_recv(cx).map(|message| message.map(Ok))
+    }
+}
+
+impl Sink<(Packet, SocketAddr)> for TcpTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, (packet, addr): (Packet, SocketAddr)) -> Result<()> {
+        match self.connections.lock().unwrap().get(&addr) {
+            Some(connection) => {
+                if connection.send(packet).is_err() {
+                    debug!("connection to {} is closed", addr);
+                }
+            }
+            None => debug!("no connection to {}", addr),
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
+    use std::io::{Read, Write};
+
+    fn spawn_tcp_server() -> SocketAddr {
+        spawn_configured_tcp_server(|_| {})
+    }
+
+    fn spawn_configured_tcp_server<F: FnOnce(&TcpTransport) + Send + 'static>(
+        configure: F,
+    ) -> SocketAddr {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let transport = TcpTransport::bind("127.0.0.1:0").await.unwrap();
+                configure(&transport);
+                let mut server = Server::from_transport(transport);
+                tx.send(server.socket_addr().unwrap()).unwrap();
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        let mut response = req.response?;
+                        response.message.payload = req.message.payload;
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        rx.recv().unwrap()
+    }
+
+    #[test]
+    fn test_tcp_client() {
+        let server_addr = spawn_tcp_server();
+        let transport = TcpClientTransport::connect(server_addr).unwrap();
+        assert_eq!(
+            transport.peer_settings(),
+            PeerSettings {
+                max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+                block_wise_transfer: true,
+            }
+        );
+
+        let mut client = CoAPClient::from_transport(transport, server_addr).unwrap();
+        for payload in [b"one".to_vec(), b"two".to_vec()] {
+            let response = client
+                .request_path("/echo", Method::Post, Some(payload.clone()), None, None)
+                .unwrap();
+            assert_eq!(response.message.payload, payload);
+        }
+
+        let error = client
+            .request_path("/echo", Method::Post, Some(vec![0; 2000]), None, None)
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_tcp_server() {
+        let server_addr = spawn_tcp_server();
+        let mut stream = std::net::TcpStream::connect(server_addr).unwrap();
+        let mut codec = TcpCodec::new(u32::MAX);
+        let mut request = CoapRequest::<SocketAddr>::new();
+        request.set_method(Method::Post);
+        request.set_path("/echo");
+        request.message.set_token(vec![7]);
+        request.message.payload = b"hello".to_vec();
+
+        let mut buf = BytesMut::new();
+        codec.encode(csm(DEFAULT_MAX_MESSAGE_SIZE, false), &mut buf).unwrap();
+        codec.encode(request.message, &mut buf).unwrap();
+        stream.write_all(&buf).unwrap();
+
+        let mut received = BytesMut::new();
+        let mut messages = Vec::new();
+        while messages.len() < 2 {
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).unwrap();
+            assert!(n > 0);
+            received.extend_from_slice(&chunk[..n]);
+            while let Some(packet) = codec.decode(&mut received).unwrap() {
+                messages.push(packet);
+            }
+        }
+
+        assert!(is_csm(&messages[0]));
+        let mut settings = PeerSettings::default();
+        settings.update(&messages[0]);
+        assert!(settings.block_wise_transfer);
+        assert_eq!(messages[1].get_token(), &[7]);
+        assert_eq!(messages[1].payload, b"hello".to_vec());
+    }
+
+    fn read_messages(stream: &mut std::net::TcpStream, count: usize) -> Vec<Packet> {
+        let mut codec = TcpCodec::new(u32::MAX);
+        let mut received = BytesMut::new();
+        let mut messages = Vec::new();
+        while messages.len() < count {
+            let mut chunk = [0; 1024];
+            let n = stream.read(&mut chunk).unwrap();
+            assert!(n > 0);
+            received.extend_from_slice(&chunk[..n]);
+            while let Some(packet) = codec.decode(&mut received).unwrap() {
+                messages.push(packet);
+            }
+        }
+        messages
+    }
+
+    fn write_messages(stream: &mut std::net::TcpStream, messages: Vec<Packet>) {
+        let mut codec = TcpCodec::new(u32::MAX);
+        let mut buf = BytesMut::new();
+        for packet in messages {
+            codec.encode(packet, &mut buf).unwrap();
+        }
+        stream.write_all(&buf).unwrap();
+    }
+
+    fn recording_server() -> (SocketAddr, std::sync::mpsc::Receiver<ConnectionEvent>) {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let tx = Mutex::new(tx);
+        let server_addr = spawn_configured_tcp_server(move |transport| {
+            transport.set_event_handler(move |event| {
+                let _ = tx.lock().unwrap().send(event.clone());
+            })
+        });
+        (server_addr, rx)
+    }
+
+    fn closed_reason(events: &std::sync::mpsc::Receiver<ConnectionEvent>) -> CloseReason {
+        assert!(matches!(events.recv().unwrap(), ConnectionEvent::Connected(_)));
+        match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+            ConnectionEvent::Closed(_, reason) => reason,
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_tcp_ping_and_release() {
+        let (server_addr, events) = recording_server();
+        let mut stream = std::net::TcpStream::connect(server_addr).unwrap();
+        write_messages(
+            &mut stream,
+            vec![csm(DEFAULT_MAX_MESSAGE_SIZE, false), ping(vec![9])],
+        );
+        let messages = read_messages(&mut stream, 2);
+        assert!(is_csm(&messages[0]));
+        assert_eq!(Signal::from_packet(&messages[1]), Some(Signal::Pong));
+        assert_eq!(messages[1].get_token(), &[9]);
+
+        write_messages(&mut stream, vec![release()]);
+        assert_eq!(closed_reason(&events), CloseReason::PeerReleased);
+    }
+
+    #[test]
+    fn test_tcp_abort() {
+        let (server_addr, events) = recording_server();
+        let mut stream = std::net::TcpStream::connect(server_addr).unwrap();
+        let mut request = Packet::new();
+        request.header.code = MessageClass::Request(Method::Get);
+        write_messages(&mut stream, vec![request]);
+
+        let messages = read_messages(&mut stream, 2);
+        assert_eq!(Signal::from_packet(&messages[1]), Some(Signal::Abort));
+        assert_eq!(messages[1].payload, b"expected a csm".to_vec());
+        assert_eq!(
+            closed_reason(&events),
+            CloseReason::Aborted("expected a csm".to_string())
+        );
+    }
+
+    #[test]
+    fn test_tcp_keepalive() {
+        let (tx, events) = std::sync::mpsc::channel();
+        let tx = Mutex::new(tx);
+        let server_addr = spawn_configured_tcp_server(move |transport| {
+            transport.set_ping_interval(Some(Duration::from_millis(100)));
+            transport.set_idle_timeout(Some(Duration::from_millis(300)));
+            transport.set_event_handler(move |event| {
+                let _ = tx.lock().unwrap().send(event.clone());
+            });
+        });
+        let mut stream = std::net::TcpStream::connect(server_addr).unwrap();
+        write_messages(&mut stream, vec![csm(DEFAULT_MAX_MESSAGE_SIZE, false)]);
+
+        // the peer never answers the ping
+        let messages = read_messages(&mut stream, 3);
+        assert_eq!(Signal::from_packet(&messages[1]), Some(Signal::Ping));
+        assert_eq!(Signal::from_packet(&messages[2]), Some(Signal::Release));
+        assert_eq!(closed_reason(&events), CloseReason::IdleTimeout);
+    }
+
+    #[test]
+    fn test_tcp_client_ping_and_release() {
+        let (server_addr, events) = recording_server();
+        let transport = TcpClientTransport::connect(server_addr).unwrap();
+        transport
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        transport.ping().unwrap();
+        assert_eq!(transport.close_reason(), None);
+
+        transport.release().unwrap();
+        assert_eq!(transport.close_reason(), Some(CloseReason::Released));
+        let error = transport.send_to(&[0x40, 1, 0, 0], &server_addr).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::NotConnected);
+        assert_eq!(closed_reason(&events), CloseReason::PeerReleased);
+    }
+}
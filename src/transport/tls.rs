@@ -0,0 +1,281 @@
+//! CoAP over TLS (coaps+tcp, [RFC 8323](https://tools.ietf.org/html/rfc8323)
+//! section 9) on top of the TCP transport.
+//!
+//! Requires the `tls` feature. The helpers below build rustls configurations
+//! that negotiate the ALPN protocol `coap`. A server configuration can be
+//! replaced while the server runs through a [`ReloadableServerConfig`],
+//! e.g. to rotate certificates without dropping connections.
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+pub use tokio_rustls::rustls;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig};
+
+/// ALPN protocol identifier of CoAP over TLS.
+pub const ALPN_PROTOCOL: &[u8] = b"coap";
+
+/// Default port of coaps+tcp.
+pub const DEFAULT_PORT: u16 = 5684;
+
+/// Load all certificates of a PEM file.
+pub fn load_certs<P: AsRef<Path>>(path: P) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::certs(&mut reader).collect()
+}
+
+/// Load the first private key of a PEM file.
+pub fn load_private_key<P: AsRef<Path>>(path: P) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "no private key found"))
+}
+
+/// Build a server configuration presenting the given certificate chain.
+pub fn server_config(
+    cert_chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+) -> Result<Arc<ServerConfig>> {
+    let mut config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, key)
+        .map_err(tls_error)?;
+    config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// A server configuration that can be replaced while the server runs.
+///
+/// Connections accepted after [`reload`](Self::reload) are handshaken with
+/// the new configuration; established connections keep theirs. Clones
+/// share the configuration, so keep one to reload the configuration of a
+/// transport.
+#[derive(Clone)]
+pub struct ReloadableServerConfig(Arc<RwLock<Arc<ServerConfig>>>);
+
+impl ReloadableServerConfig {
+    /// Start with the given configuration.
+    pub fn new(config: Arc<ServerConfig>) -> ReloadableServerConfig {
+        ReloadableServerConfig(Arc::new(RwLock::new(config)))
+    }
+
+    /// Use `config` for the connections accepted from now on.
+    pub fn reload(&self, config: Arc<ServerConfig>) {
+        *self.0.write().unwrap() = config;
+    }
+
+    /// Return the configuration new connections are accepted with.
+    pub fn current(&self) -> Arc<ServerConfig> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub(super) fn acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        tokio_rustls::TlsAcceptor::from(self.current())
+    }
+}
+
+impl From<Arc<ServerConfig>> for ReloadableServerConfig {
+    fn from(config: Arc<ServerConfig>) -> ReloadableServerConfig {
+        ReloadableServerConfig::new(config)
+    }
+}
+
+/// Build a client configuration trusting the given root certificates.
+pub fn client_config(roots: RootCertStore) -> Result<Arc<ClientConfig>> {
+    let mut config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    config.alpn_protocols = vec![ALPN_PROTOCOL.to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// A client session together with the socket it runs on.
+#[cfg(feature = "websocket")]
+pub(super) type ClientStream = rustls::StreamOwned<ClientConnection, std::net::TcpStream>;
+
+/// Start a client session on a connected socket.
+#[cfg(feature = "websocket")]
+pub(super) fn client_stream(
+    socket: std::net::TcpStream,
+    server_name: &str,
+    config: Arc<ClientConfig>,
+) -> Result<ClientStream> {
+    let connection = client_connection(server_name, config)?;
+    Ok(rustls::StreamOwned::new(connection, socket))
+}
+
+/// Start a client session whose records the caller exchanges with the peer.
+pub(super) fn client_connection(
+    server_name: &str,
+    config: Arc<ClientConfig>,
+) -> Result<ClientConnection> {
+    let name = ServerName::try_from(server_name.to_string())
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    ClientConnection::new(config, name).map_err(tls_error)
+}
+
+fn provider() -> Arc<rustls::crypto::CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn tls_error(e: rustls::Error) -> Error {
+    Error::new(ErrorKind::InvalidInput, e)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::super::TcpTransport;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
+    use std::net::SocketAddr;
+
+    fn self_signed() -> (CertificateDer<'static>, Arc<ServerConfig>) {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivateKeyDer::try_from(certified.key_pair.serialize_der()).unwrap();
+        (cert.clone(), server_config(vec![cert], key).unwrap())
+    }
+
+    fn trusting(cert: &CertificateDer<'static>) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(cert.clone()).unwrap();
+        client_config(roots).unwrap()
+    }
+
+    #[test]
+    fn test_tls() {
+        let (cert, config) = self_signed();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let transport = TcpTransport::bind_tls("127.0.0.1:0", config).await.unwrap();
+                let mut server = Server::from_transport(transport);
+                tx.send(server.socket_addr().unwrap()).unwrap();
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        let mut response = req.response?;
+                        response.message.payload = b"secure".to_vec();
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let mut client = CoAPClient::new_tls(server_addr, "localhost", trusting(&cert)).unwrap();
+        let response = client
+            .request_path("/", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload, b"secure".to_vec());
+
+        let untrusted = client_config(RootCertStore::empty()).unwrap();
+        assert!(CoAPClient::new_tls(server_addr, "localhost", untrusted).is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let (old_cert, old_config) = self_signed();
+        let (new_cert, new_config) = self_signed();
+        let config = ReloadableServerConfig::new(old_config);
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let reloadable = config.clone();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let transport = TcpTransport::bind_tls("127.0.0.1:0", reloadable)
+                    .await
+                    .unwrap();
+                let mut server = Server::from_transport(transport);
+                tx.send(server.socket_addr().unwrap()).unwrap();
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        let mut response = req.response?;
+                        response.message.payload = b"secure".to_vec();
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let mut established =
+            CoAPClient::new_tls(server_addr, "localhost", trusting(&old_cert)).unwrap();
+        established
+            .request_path("/", Method::Get, None, None, None)
+            .unwrap();
+
+        config.reload(new_config);
+        let response = established
+            .request_path("/", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload, b"secure".to_vec());
+        assert!(CoAPClient::new_tls(server_addr, "localhost", trusting(&old_cert)).is_err());
+        let mut client =
+            CoAPClient::new_tls(server_addr, "localhost", trusting(&new_cert)).unwrap();
+        let response = client
+            .request_path("/", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload, b"secure".to_vec());
+    }
+
+    #[test]
+    fn test_send_while_receiving() {
+        use super::super::{tcp::TcpClientTransport, ClientTransport};
+        use coap_lite::Packet;
+        use std::time::{Duration, Instant};
+
+        let (cert, config) = self_signed();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let transport = TcpTransport::bind_tls("127.0.0.1:0", config).await.unwrap();
+                let mut server = Server::from_transport(transport);
+                tx.send(server.socket_addr().unwrap()).unwrap();
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        let mut response = req.response?;
+                        response.message.payload = b"secure".to_vec();
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let transport =
+            TcpClientTransport::connect_tls(server_addr, "localhost", trusting(&cert)).unwrap();
+        transport.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let receiver = transport.try_clone().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0; 1500];
+            let (n, _) = receiver.recv_from(&mut buf).unwrap();
+            tx.send(Packet::from_bytes(&buf[..n]).unwrap()).unwrap();
+        });
+        // let the receiver block in its read
+        std::thread::sleep(Duration::from_millis(200));
+
+        let mut request = CoapRequest::<SocketAddr>::new();
+        request.set_path("/");
+        request.message.set_token(vec![1]);
+        let start = Instant::now();
+        transport
+            .send_to(&request.message.to_bytes().unwrap(), &server_addr)
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        let response = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(response.payload, b"secure".to_vec());
+    }
+}
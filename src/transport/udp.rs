@@ -0,0 +1,461 @@
+use coap_lite::Packet;
+use futures::channel::mpsc::{self, UnboundedReceiver};
+use futures::{Sink, Stream, StreamExt};
+use lru_time_cache::LruCache;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::io::{Error, ErrorKind, Result};
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio_util::udp::UdpFramed;
+
+use super::{ClientTransport, Transport};
+use crate::message::pool::BufferPool;
+use crate::message::{write_packet, Codec};
+
+/// CoAP over UDP (RFC 7252), the default transport of the server.
+pub struct UdpTransport {
+    socket: UdpFramed<Codec>,
+    multicast_addresses: Vec<IpAddr>,
+    dedicated_multicast: bool,
+    listeners: Vec<MulticastListener>,
+    /// The group the last message of a peer was sent to, if received by a
+    /// listener.
+    groups: LruCache<SocketAddr, IpAddr>,
+}
+
+/// How many peers the transport remembers the group of.
+const GROUP_CAPACITY: usize = 1024;
+
+/// A socket receiving a single multicast group on the port of the transport.
+struct MulticastListener {
+    group: IpAddr,
+    socket: UdpFramed<Codec>,
+}
+
+impl UdpTransport {
+    /// Bind a UDP socket to the given address.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UdpTransport> {
+        let std_socket = net::UdpSocket::bind(addr)?;
+        std_socket.set_nonblocking(true)?;
+        Ok(UdpTransport::from_socket(UdpSocket::from_std(std_socket)?))
+    }
+
+    /// Wrap an already bound socket.
+    pub fn from_socket(socket: UdpSocket) -> UdpTransport {
+        UdpTransport {
+            socket: UdpFramed::new(socket, Codec::new()),
+            multicast_addresses: Vec::new(),
+            dedicated_multicast: false,
+            listeners: Vec::new(),
+            groups: LruCache::with_capacity(GROUP_CAPACITY),
+        }
+    }
+
+    /// Receive the multicast groups joined from now on with dedicated
+    /// sockets, one per group, bound to the group address and the port of
+    /// the transport. Requests to a group then no longer arrive on the
+    /// unicast socket, and responses are sent from the unicast socket so
+    /// that their source is a unicast address, as RFC 7252 section 8.2
+    /// requires.
+    pub fn set_dedicated_multicast(&mut self, on: bool) -> Result<()> {
+        if on {
+            let socket = SockRef::from(self.socket.get_ref());
+            // let the listeners share the port
+            socket.set_reuse_address(true)?;
+            // Linux hands multicast to every socket on the port by default
+            #[cfg(target_os = "linux")]
+            match self.socket.get_ref().local_addr()? {
+                SocketAddr::V4(_) => socket.set_multicast_all_v4(false)?,
+                SocketAddr::V6(_) => socket.set_multicast_all_v6(false)?,
+            }
+        }
+        self.dedicated_multicast = on;
+        Ok(())
+    }
+
+    fn open_listener(&self, group: IpAddr) -> Result<Option<MulticastListener>> {
+        let local_addr = self.socket.get_ref().local_addr()?;
+        let socket = Socket::new(
+            Domain::for_address(local_addr),
+            Type::DGRAM,
+            Some(Protocol::UDP),
+        )?;
+        socket.set_reuse_address(true)?;
+        // binding to the group keeps unicast and other groups out; Windows
+        // only binds to local addresses
+        let bind_ip = if cfg!(windows) {
+            match group {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            }
+        } else {
+            group
+        };
+        match (local_addr, group) {
+            (SocketAddr::V4(val), IpAddr::V4(ipv4)) => {
+                socket.bind(&SocketAddr::new(bind_ip, val.port()).into())?;
+                // join on the interface of the unicast socket, if bound to one
+                socket.join_multicast_v4(&ipv4, val.ip())?;
+            }
+            (SocketAddr::V6(val), IpAddr::V6(ipv6)) => {
+                socket.set_only_v6(true)?;
+                socket.bind(&SocketAddr::new(bind_ip, val.port()).into())?;
+                socket.join_multicast_v6(&ipv6, val.scope_id())?;
+            }
+            // the address family of the group does not match the socket
+            _ => return Ok(None),
+        }
+        socket.set_nonblocking(true)?;
+        Ok(Some(MulticastListener {
+            group,
+            socket: UdpFramed::new(UdpSocket::from_std(socket.into())?, Codec::new()),
+        }))
+    }
+}
+
+impl Transport for UdpTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.get_ref().local_addr()
+    }
+
+    fn join_multicast(&mut self, addr: IpAddr) -> Result<()> {
+        assert!(addr.is_multicast());
+        if self.dedicated_multicast {
+            if !self.listeners.iter().any(|listener| listener.group == addr) {
+                if let Some(listener) = self.open_listener(addr)? {
+                    self.listeners.push(listener);
+                }
+            }
+            return Ok(());
+        }
+        let socket = self.socket.get_mut();
+        // determine wether IPv4 or IPv6 and
+        // join the appropriate multicast address
+        match (socket.local_addr()?, addr) {
+            (SocketAddr::V4(val), IpAddr::V4(ipv4)) => {
+                socket.join_multicast_v4(ipv4, *val.ip())?;
+            }
+            (SocketAddr::V6(_val), IpAddr::V6(ipv6)) => {
+                socket.join_multicast_v6(&ipv6, 0)?;
+            }
+            // the address family of the group does not match the socket
+            _ => return Ok(()),
+        }
+        self.multicast_addresses.push(addr);
+        Ok(())
+    }
+
+    fn multicast_groups(&self) -> Vec<IpAddr> {
+        let listeners = self.listeners.iter().map(|listener| listener.group);
+        self.multicast_addresses.iter().copied().chain(listeners).collect()
+    }
+
+    fn multicast_group(&self, addr: &SocketAddr) -> Option<IpAddr> {
+        self.groups.peek(addr).copied()
+    }
+
+    fn leave_multicast(&mut self, addr: IpAddr) -> Result<()> {
+        assert!(addr.is_multicast());
+        if let Some(i) = self
+            .listeners
+            .iter()
+            .position(|listener| listener.group == addr)
+        {
+            // closing the socket leaves the group
+            self.listeners.remove(i);
+            return Ok(());
+        }
+        let socket = self.socket.get_mut();
+        match (socket.local_addr()?, addr) {
+            (SocketAddr::V4(val), IpAddr::V4(ipv4)) => {
+                socket.leave_multicast_v4(ipv4, *val.ip())?;
+            }
+            (SocketAddr::V6(_val), IpAddr::V6(ipv6)) => {
+                socket.leave_multicast_v6(&ipv6, 0)?;
+            }
+            _ => return Ok(()),
+        }
+        self.multicast_addresses.retain(|&item| item != addr);
+        Ok(())
+    }
+}
+
+impl Drop for UdpTransport {
+    fn drop(&mut self) {
+        // unregister still existing multicast addresses
+        for addr in self.multicast_addresses.clone() {
+            let _ = self.leave_multicast(addr);
+        }
+    }
+}
+
+impl Stream for UdpTransport {
+    type Item = Result<(Packet, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(item) = Pin::new(&mut self.socket).poll_next(cx) {
+            if let Some(Ok((_, addr))) = &item {
+                self.groups.remove(addr);
+            }
+            return Poll::Ready(item);
+        }
+        let mut received = None;
+        for listener in self.listeners.iter_mut() {
+            if let Poll::Ready(Some(item)) = Pin::new(&mut listener.socket).poll_next(cx) {
+                received = Some((listener.group, item));
+                break;
+            }
+        }
+        match received {
+            Some((group, item)) => {
+                if let Ok((_, addr)) = &item {
+                    self.groups.insert(*addr, group);
+                }
+                Poll::Ready(Some(item))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Sink<(Packet, SocketAddr)> for UdpTransport {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.socket).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: (Packet, SocketAddr)) -> Result<()> {
+        Pin::new(&mut self.socket).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.socket).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.socket).poll_close(cx)
+    }
+}
+
+/// How often the receive thread of a [`ThreadedUdpTransport`] checks whether
+/// the transport has been dropped.
+const RECV_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// CoAP over UDP without an async reactor: a thread blocks on the socket and
+/// hands received messages to the stream, and messages are sent directly.
+/// Works on any executor.
+pub struct ThreadedUdpTransport {
+    socket: net::UdpSocket,
+    incoming: UnboundedReceiver<Result<(Packet, SocketAddr)>>,
+}
+
+impl ThreadedUdpTransport {
+    /// Bind a UDP socket to the given address and start the receive thread.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<ThreadedUdpTransport> {
+        let socket = net::UdpSocket::bind(addr)?;
+        let receiver = socket.try_clone()?;
+        receiver.set_read_timeout(Some(RECV_POLL_INTERVAL))?;
+
+        let (tx, incoming) = mpsc::unbounded();
+        thread::spawn(move || {
+            let mut buf = vec![0; 64 * 1024];
+            while !tx.is_closed() {
+                let message = match receiver.recv_from(&mut buf) {
+                    Ok((len, addr)) => Packet::from_bytes(&buf[..len])
+                        .map(|packet| (packet, addr))
+                        .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string())),
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                        ) =>
+                    {
+                        continue
+                    }
+                    Err(e) => Err(e),
+                };
+                if tx.unbounded_send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(ThreadedUdpTransport { socket, incoming })
+    }
+}
+
+impl Transport for ThreadedUdpTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn join_multicast(&mut self, addr: IpAddr) -> Result<()> {
+        assert!(addr.is_multicast());
+        match (self.socket.local_addr()?, addr) {
+            (SocketAddr::V4(val), IpAddr::V4(ipv4)) => {
+                self.socket.join_multicast_v4(&ipv4, val.ip())
+            }
+            (SocketAddr::V6(_), IpAddr::V6(ipv6)) => self.socket.join_multicast_v6(&ipv6, 0),
+            // the address family of the group does not match the socket
+            _ => Ok(()),
+        }
+    }
+
+    fn leave_multicast(&mut self, addr: IpAddr) -> Result<()> {
+        assert!(addr.is_multicast());
+        match (self.socket.local_addr()?, addr) {
+            (SocketAddr::V4(val), IpAddr::V4(ipv4)) => {
+                self.socket.leave_multicast_v4(&ipv4, val.ip())
+            }
+            (SocketAddr::V6(_), IpAddr::V6(ipv6)) => self.socket.leave_multicast_v6(&ipv6, 0),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Stream for ThreadedUdpTransport {
+    type Item = Result<(Packet, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_next_unpin(cx)
+    }
+}
+
+impl Sink<(Packet, SocketAddr)> for ThreadedUdpTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, (packet, addr): (Packet, SocketAddr)) -> Result<()> {
+        let mut bytes = BufferPool::global().take();
+        write_packet(&packet, &mut *bytes)?;
+        self.socket.send_to(&bytes, addr)?;
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl ClientTransport for net::UdpSocket {
+    fn send_to(&self, buf: &[u8], addr: &SocketAddr) -> Result<usize> {
+        net::UdpSocket::send_to(self, buf, addr)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        net::UdpSocket::recv_from(self, buf)
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        net::UdpSocket::set_read_timeout(self, dur)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        net::UdpSocket::read_timeout(self)
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn ClientTransport>> {
+        Ok(Box::new(net::UdpSocket::try_clone(self)?))
+    }
+
+    fn set_broadcast(&self, on: bool) -> Result<()> {
+        net::UdpSocket::set_broadcast(self, on)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse};
+
+    #[test]
+    fn test_udp_transport() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::from_transport(UdpTransport::bind("127.0.0.1:0").unwrap());
+                tx.send(server.socket_addr().unwrap()).unwrap();
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        let mut response = req.response?;
+                        response.message.payload = b"pong".to_vec();
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = CoAPClient::from_transport(socket, server_addr).unwrap();
+        let response = client
+            .request_path("/ping", coap_lite::RequestType::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload, b"pong".to_vec());
+    }
+
+    #[test]
+    #[ignore]
+    fn test_dedicated_multicast() {
+        let group = IpAddr::V4(Ipv4Addr::new(224, 0, 1, 187));
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                // multicast needs a server on a real interface
+                let mut transport = UdpTransport::bind("0.0.0.0:0").unwrap();
+                transport.set_dedicated_multicast(true).unwrap();
+                transport.join_multicast(group).unwrap();
+                let mut server = Server::from_transport(transport);
+                tx.send(server.socket_addr().unwrap().port()).unwrap();
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        let mut response = req.response?;
+                        response.message.payload = b"pong".to_vec();
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let port = rx.recv().unwrap();
+
+        let mut request = CoapRequest::<SocketAddr>::new();
+        request.set_method(coap_lite::RequestType::Get);
+        request.set_path("/ping");
+        request
+            .message
+            .header
+            .set_type(coap_lite::MessageType::NonConfirmable);
+        request.message.set_token(vec![1]);
+        let bytes = request.message.to_bytes().unwrap();
+
+        let socket = net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut buf = [0; 1500];
+        for destination in [group, IpAddr::V4(Ipv4Addr::LOCALHOST)] {
+            socket.send_to(&bytes, (destination, port)).unwrap();
+            let (n, source) = socket.recv_from(&mut buf).unwrap();
+            assert_eq!(
+                Packet::from_bytes(&buf[..n]).unwrap().payload,
+                b"pong".to_vec()
+            );
+            // the answer always comes from a unicast address
+            assert!(!source.ip().is_multicast());
+            assert_eq!(source.port(), port);
+        }
+    }
+}
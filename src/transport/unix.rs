@@ -0,0 +1,272 @@
+//! CoAP over Unix domain datagram sockets, for local IPC without opening
+//! network ports.
+//!
+//! The dispatch code identifies peers by `SocketAddr`, so every peer path is
+//! given a synthetic address in `fd00::/64` for as long as the transport
+//! lives. Peers must bind to a path, as unnamed sockets cannot be answered.
+use coap_lite::Packet;
+use futures::{ready, Sink, Stream};
+use log::debug;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::os::unix::net as std_unix;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::ReadBuf;
+use tokio::net::UnixDatagram;
+
+use super::{ClientTransport, Transport};
+
+/// Synthetic address standing for the local socket.
+pub const LOCAL_ADDR: SocketAddr = SocketAddr::new(
+    std::net::IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 0)),
+    0,
+);
+
+fn synthetic_addr(index: u32) -> SocketAddr {
+    SocketAddr::new(
+        Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, (index >> 16) as u16, index as u16).into(),
+        0,
+    )
+}
+
+/// A Unix domain datagram socket serving CoAP.
+pub struct UnixTransport {
+    socket: UnixDatagram,
+    path: PathBuf,
+    peers: HashMap<PathBuf, SocketAddr>,
+    paths: HashMap<SocketAddr, PathBuf>,
+    pending: Option<(Vec<u8>, PathBuf)>,
+    buf: Vec<u8>,
+}
+
+impl UnixTransport {
+    /// Bind to the given path. A stale socket file at the path is replaced
+    /// and the file is removed again when the transport is dropped.
+    pub fn bind<P: AsRef<Path>>(path: P) -> Result<UnixTransport> {
+        let path = path.as_ref().to_path_buf();
+        remove_stale(&path)?;
+        Ok(UnixTransport {
+            socket: UnixDatagram::bind(&path)?,
+            path,
+            peers: HashMap::new(),
+            paths: HashMap::new(),
+            pending: None,
+            buf: vec![0; 1500],
+        })
+    }
+
+    /// Return the path the transport is bound to.
+    pub fn local_path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Return the path of the peer behind a synthetic address.
+    pub fn peer_path(&self, addr: &SocketAddr) -> Option<&Path> {
+        self.paths.get(addr).map(PathBuf::as_path)
+    }
+
+    fn peer_addr(&mut self, path: &Path) -> SocketAddr {
+        if let Some(addr) = self.peers.get(path) {
+            return *addr;
+        }
+        let addr = synthetic_addr(self.peers.len() as u32 + 1);
+        self.peers.insert(path.to_path_buf(), addr);
+        self.paths.insert(addr, path.to_path_buf());
+        addr
+    }
+
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if let Some((ref bytes, ref path)) = self.pending {
+            if let Err(e) = ready!(self.socket.poll_send_to(cx, bytes, path)) {
+                debug!("send to {:?} failed: {}", path, e);
+            }
+            self.pending = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UnixTransport {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Transport for UnixTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(LOCAL_ADDR)
+    }
+
+    fn verifies_addresses(&self) -> bool {
+        true
+    }
+}
+
+impl Stream for UnixTransport {
+    type Item = Result<(Packet, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            let mut buf = ReadBuf::new(&mut this.buf);
+            let from = ready!(this.socket.poll_recv_from(cx, &mut buf))?;
+            let len = buf.filled().len();
+            let path = match from.as_pathname() {
+                Some(path) => path.to_path_buf(),
+                None => {
+                    debug!("drop message from an unnamed socket");
+                    continue;
+                }
+            };
+            let packet = Packet::from_bytes(&this.buf[..len])
+                .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string()));
+            let addr = this.peer_addr(&path);
+            return Poll::Ready(Some(packet.map(|packet| (packet, addr))));
+        }
+    }
+}
+
+impl Sink<(Packet, SocketAddr)> for UnixTransport {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_send_pending(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, (packet, addr): (Packet, SocketAddr)) -> Result<()> {
+        let bytes = packet
+            .to_bytes()
+            .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string()))?;
+        match self.paths.get(&addr) {
+            Some(path) => {
+                let path = path.clone();
+                self.pending = Some((bytes, path));
+            }
+            None => debug!("no unix peer for {}", addr),
+        }
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_send_pending(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.poll_send_pending(cx)
+    }
+}
+
+/// A blocking Unix domain datagram socket connected to a server path.
+pub struct UnixClientTransport {
+    socket: std_unix::UnixDatagram,
+    path: Option<PathBuf>,
+}
+
+impl UnixClientTransport {
+    /// Bind to `local_path` and connect to the server at `peer_path`. The
+    /// local socket file is removed when the transport is dropped.
+    pub fn connect<P: AsRef<Path>, Q: AsRef<Path>>(
+        local_path: P,
+        peer_path: Q,
+    ) -> Result<UnixClientTransport> {
+        let local_path = local_path.as_ref().to_path_buf();
+        remove_stale(&local_path)?;
+        let socket = std_unix::UnixDatagram::bind(&local_path)?;
+        socket.connect(peer_path)?;
+        Ok(UnixClientTransport {
+            socket,
+            path: Some(local_path),
+        })
+    }
+}
+
+impl Drop for UnixClientTransport {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl ClientTransport for UnixClientTransport {
+    fn send_to(&self, buf: &[u8], _addr: &SocketAddr) -> Result<usize> {
+        self.socket.send(buf)
+    }
+
+    fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, SocketAddr)> {
+        Ok((self.socket.recv(buf)?, LOCAL_ADDR))
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> Result<()> {
+        self.socket.set_read_timeout(dur)
+    }
+
+    fn read_timeout(&self) -> Result<Option<Duration>> {
+        self.socket.read_timeout()
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn ClientTransport>> {
+        // only the original handle owns the socket file
+        Ok(Box::new(UnixClientTransport {
+            socket: self.socket.try_clone()?,
+            path: None,
+        }))
+    }
+}
+
+/// Remove a socket file left behind by an earlier process, but nothing else.
+fn remove_stale(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse, RequestType as Method};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("coap-{}-{}.sock", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_unix_transport() {
+        let server_path = temp_path("server");
+        let path = server_path.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let mut server = Server::from_transport(UnixTransport::bind(&path).unwrap());
+                tx.send(()).unwrap();
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async move {
+                        let mut response = req.response?;
+                        response.message.payload = req.source.unwrap().to_string().into_bytes();
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        rx.recv().unwrap();
+
+        let client_path = temp_path("client");
+        let mut client = CoAPClient::new_unix(&client_path, &server_path).unwrap();
+        let response = client
+            .request_path("/", Method::Get, None, None, None)
+            .unwrap();
+        assert_eq!(response.message.payload, synthetic_addr(1).to_string().into_bytes());
+
+        drop(client);
+        assert!(!client_path.exists());
+    }
+}
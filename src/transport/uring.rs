@@ -0,0 +1,503 @@
+//! UDP transport driven by Linux io_uring, for servers handling many
+//! messages per second.
+//!
+//! A dedicated thread owns the ring. It keeps a batch of receives in flight
+//! at all times and submits outgoing messages together with the
+//! resubmitted receives, so a busy server needs far fewer system calls than
+//! with the readiness-based [`UdpTransport`](super::UdpTransport).
+use coap_lite::Packet;
+use futures::{Sink, Stream};
+use io_uring::{opcode, squeue, types, IoUring};
+use log::{debug, error};
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::net::{
+    self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs,
+};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::task::{Context, Poll};
+use std::thread::{self, JoinHandle};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use super::Transport;
+
+/// Number of receives kept in flight.
+const RECV_SLOTS: usize = 64;
+/// Size of the submission queue.
+const RING_ENTRIES: u32 = 256;
+/// Receive buffer size. Larger datagrams are truncated and dropped.
+const MAX_DATAGRAM_SIZE: usize = 8192;
+
+/// User data of the eventfd read that wakes the ring thread.
+const WAKE: u64 = u64::MAX;
+/// User data of cancellations, whose completions are ignored.
+const CANCEL: u64 = u64::MAX - 1;
+
+type Incoming = UnboundedSender<Result<(Packet, SocketAddr)>>;
+type Outgoing = std_mpsc::Receiver<(Vec<u8>, SocketAddr)>;
+
+/// CoAP over UDP with the socket I/O performed by io_uring.
+pub struct UringTransport {
+    socket: net::UdpSocket,
+    incoming: UnboundedReceiver<Result<(Packet, SocketAddr)>>,
+    outgoing: std_mpsc::Sender<(Vec<u8>, SocketAddr)>,
+    wake: Arc<OwnedFd>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl UringTransport {
+    /// Bind a UDP socket to the given address and start the ring thread.
+    /// Fails if the kernel does not support io_uring.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<UringTransport> {
+        UringTransport::from_socket(net::UdpSocket::bind(addr)?)
+    }
+
+    /// Drive an already bound socket.
+    pub fn from_socket(socket: net::UdpSocket) -> Result<UringTransport> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if wake < 0 {
+            return Err(Error::last_os_error());
+        }
+        let wake = Arc::new(unsafe { OwnedFd::from_raw_fd(wake) });
+
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let (outgoing, outgoing_rx) = std_mpsc::channel();
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let ring_thread = RingThread {
+            ring,
+            socket: socket.try_clone()?,
+            wake: wake.clone(),
+            wake_buf: Box::new(0),
+            incoming: incoming_tx,
+            outgoing: outgoing_rx,
+            shutdown: shutdown.clone(),
+            recv_slots: (0..RECV_SLOTS).map(|_| Slot::recv()).collect(),
+            send_slots: HashMap::new(),
+            next_send: RECV_SLOTS as u64,
+            pending: 0,
+            closing: false,
+        };
+        let thread = thread::Builder::new()
+            .name("coap-uring".to_string())
+            .spawn(move || ring_thread.run())?;
+
+        Ok(UringTransport {
+            socket,
+            incoming,
+            outgoing,
+            wake,
+            shutdown,
+            thread: Some(thread),
+        })
+    }
+
+    fn wake(&self) {
+        let one = 1u64;
+        unsafe {
+            libc::write(
+                self.wake.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                mem::size_of::<u64>(),
+            );
+        }
+    }
+}
+
+impl Transport for UringTransport {
+    fn local_addr(&self) -> Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    fn join_multicast(&mut self, addr: IpAddr) -> Result<()> {
+        assert!(addr.is_multicast());
+        match (self.socket.local_addr()?, addr) {
+            (SocketAddr::V4(val), IpAddr::V4(ipv4)) => {
+                self.socket.join_multicast_v4(&ipv4, val.ip())
+            }
+            (SocketAddr::V6(_), IpAddr::V6(ipv6)) => self.socket.join_multicast_v6(&ipv6, 0),
+            // the address family of the group does not match the socket
+            _ => Ok(()),
+        }
+    }
+
+    fn leave_multicast(&mut self, addr: IpAddr) -> Result<()> {
+        assert!(addr.is_multicast());
+        match (self.socket.local_addr()?, addr) {
+            (SocketAddr::V4(val), IpAddr::V4(ipv4)) => {
+                self.socket.leave_multicast_v4(&ipv4, val.ip())
+            }
+            (SocketAddr::V6(_), IpAddr::V6(ipv6)) => self.socket.leave_multicast_v6(&ipv6, 0),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for UringTransport {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Release);
+        self.wake();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Stream for UringTransport {
+    type Item = Result<(Packet, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming.poll_recv(cx)
+    }
+}
+
+impl Sink<(Packet, SocketAddr)> for UringTransport {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, (packet, addr): (Packet, SocketAddr)) -> Result<()> {
+        let bytes = packet
+            .to_bytes()
+            .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string()))?;
+        self.outgoing
+            .send((bytes, addr))
+            .map_err(|_| Error::new(ErrorKind::BrokenPipe, "io_uring thread stopped"))?;
+        self.wake();
+        Ok(())
+    }
+
+    // messages are handed to the ring thread in `start_send`, which submits
+    // them as soon as it wakes up
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Buffer and message header of one receive or send. The header points
+/// into the slot itself, so a slot must not move while its operation is in
+/// flight: receive slots live in a vector that is never resized and send
+/// slots are boxed.
+struct Slot {
+    buf: Vec<u8>,
+    addr: libc::sockaddr_storage,
+    addr_len: libc::socklen_t,
+    iov: libc::iovec,
+    msg: libc::msghdr,
+}
+
+// the raw pointers only refer to the slot's own fields
+unsafe impl Send for Slot {}
+
+impl Slot {
+    fn recv() -> Slot {
+        Slot::new(
+            vec![0; MAX_DATAGRAM_SIZE],
+            mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t,
+        )
+    }
+
+    fn send(buf: Vec<u8>, addr: &SocketAddr) -> Box<Slot> {
+        let mut slot = Box::new(Slot::new(buf, 0));
+        slot.addr_len = write_sockaddr(addr, &mut slot.addr);
+        slot
+    }
+
+    fn new(buf: Vec<u8>, addr_len: libc::socklen_t) -> Slot {
+        Slot {
+            buf,
+            addr: unsafe { mem::zeroed() },
+            addr_len,
+            iov: unsafe { mem::zeroed() },
+            msg: unsafe { mem::zeroed() },
+        }
+    }
+
+    /// Point the message header at the slot's buffer and address.
+    fn prepare(&mut self) -> *mut libc::msghdr {
+        self.iov.iov_base = self.buf.as_mut_ptr() as *mut libc::c_void;
+        self.iov.iov_len = self.buf.len();
+        self.msg.msg_name = &mut self.addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        self.msg.msg_namelen = self.addr_len;
+        self.msg.msg_iov = &mut self.iov;
+        self.msg.msg_iovlen = 1;
+        self.msg.msg_flags = 0;
+        &mut self.msg
+    }
+}
+
+struct RingThread {
+    ring: IoUring,
+    socket: net::UdpSocket,
+    wake: Arc<OwnedFd>,
+    wake_buf: Box<u64>,
+    incoming: Incoming,
+    outgoing: Outgoing,
+    shutdown: Arc<AtomicBool>,
+    recv_slots: Vec<Slot>,
+    send_slots: HashMap<u64, Box<Slot>>,
+    next_send: u64,
+    /// Operations submitted but not yet completed.
+    pending: usize,
+    closing: bool,
+}
+
+impl RingThread {
+    fn run(mut self) {
+        if let Err(e) = self.event_loop() {
+            error!("io_uring transport stopped: {}", e);
+            let _ = self.incoming.send(Err(e));
+            // the kernel may still use the buffers of in-flight operations,
+            // so they must outlive the ring
+            mem::forget(self.recv_slots);
+            mem::forget(self.send_slots);
+            mem::forget(self.wake_buf);
+        }
+    }
+
+    fn event_loop(&mut self) -> Result<()> {
+        for index in 0..RECV_SLOTS {
+            self.submit_recv(index)?;
+        }
+        self.submit_wake()?;
+
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+
+            let completions: Vec<(u64, i32)> = self
+                .ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            for (user_data, result) in completions {
+                self.complete(user_data, result)?;
+            }
+
+            if !self.closing && self.shutdown.load(Ordering::Acquire) {
+                self.closing = true;
+                for index in 0..RECV_SLOTS as u64 {
+                    self.push(&opcode::AsyncCancel::new(index).build().user_data(CANCEL))?;
+                }
+                self.push(&opcode::AsyncCancel::new(WAKE).build().user_data(CANCEL))?;
+            }
+            if self.closing && self.pending == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    fn complete(&mut self, user_data: u64, result: i32) -> Result<()> {
+        if user_data == CANCEL {
+            return Ok(());
+        }
+        self.pending -= 1;
+
+        if user_data == WAKE {
+            if !self.closing {
+                self.submit_wake()?;
+                while let Ok((bytes, addr)) = self.outgoing.try_recv() {
+                    self.submit_send(bytes, addr)?;
+                }
+            }
+        } else if user_data < RECV_SLOTS as u64 {
+            let index = user_data as usize;
+            if result >= 0 {
+                self.received(index, result as usize);
+            } else if result != -libc::ECANCELED {
+                let _ = self.incoming.send(Err(Error::from_raw_os_error(-result)));
+            }
+            if !self.closing {
+                self.submit_recv(index)?;
+            }
+        } else if let Some(slot) = self.send_slots.remove(&user_data) {
+            if result < 0 {
+                let addr = read_sockaddr(&slot.addr);
+                debug!(
+                    "send to {:?} failed: {}",
+                    addr,
+                    Error::from_raw_os_error(-result)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn received(&mut self, index: usize, len: usize) {
+        let slot = &self.recv_slots[index];
+        if slot.msg.msg_flags & libc::MSG_TRUNC != 0 {
+            debug!("dropping datagram larger than {} bytes", MAX_DATAGRAM_SIZE);
+            return;
+        }
+        let addr = match read_sockaddr(&slot.addr) {
+            Some(addr) => addr,
+            None => return,
+        };
+        let message = Packet::from_bytes(&slot.buf[..len])
+            .map(|packet| (packet, addr))
+            .map_err(|cause| Error::new(ErrorKind::InvalidData, cause.to_string()));
+        let _ = self.incoming.send(message);
+    }
+
+    fn submit_recv(&mut self, index: usize) -> Result<()> {
+        let msg = self.recv_slots[index].prepare();
+        let entry = opcode::RecvMsg::new(types::Fd(self.socket.as_raw_fd()), msg)
+            .build()
+            .user_data(index as u64);
+        self.push(&entry)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn submit_send(&mut self, bytes: Vec<u8>, addr: SocketAddr) -> Result<()> {
+        let mut slot = Slot::send(bytes, &addr);
+        let user_data = self.next_send;
+        self.next_send += 1;
+        let entry = opcode::SendMsg::new(types::Fd(self.socket.as_raw_fd()), slot.prepare())
+            .build()
+            .user_data(user_data);
+        self.send_slots.insert(user_data, slot);
+        self.push(&entry)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn submit_wake(&mut self) -> Result<()> {
+        let entry = opcode::Read::new(
+            types::Fd(self.wake.as_raw_fd()),
+            &mut *self.wake_buf as *mut u64 as *mut u8,
+            mem::size_of::<u64>() as u32,
+        )
+        .build()
+        .user_data(WAKE);
+        self.push(&entry)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Queue an entry, submitting the queue first when it is full.
+    fn push(&mut self, entry: &squeue::Entry) -> Result<()> {
+        loop {
+            // the buffers referenced by the entry are owned by `self` and
+            // are kept until the operation completes
+            if unsafe { self.ring.submission().push(entry) }.is_ok() {
+                return Ok(());
+            }
+            self.ring.submit()?;
+        }
+    }
+}
+
+fn write_sockaddr(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(addr.ip().octets()),
+            };
+            mem::size_of::<libc::sockaddr_in>() as libc::socklen_t
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_addr = libc::in6_addr {
+                s6_addr: addr.ip().octets(),
+            };
+            sin6.sin6_scope_id = addr.scope_id();
+            mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t
+        }
+    }
+}
+
+fn read_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(sin.sin_addr.s_addr.to_ne_bytes()),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::*;
+    use coap_lite::{CoapRequest, CoapResponse};
+
+    #[test]
+    fn test_sockaddr() {
+        for addr in ["192.0.2.1:5683", "[2001:db8::1]:61616"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let mut storage = unsafe { mem::zeroed() };
+            write_sockaddr(&addr, &mut storage);
+            assert_eq!(read_sockaddr(&storage), Some(addr));
+        }
+    }
+
+    #[test]
+    fn test_uring_transport() {
+        // dropping cancels the in-flight receives and stops the ring thread
+        drop(UringTransport::bind("127.0.0.1:0").unwrap());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut server =
+                        Server::from_transport(UringTransport::bind("127.0.0.1:0").unwrap());
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(|req: CoapRequest<SocketAddr>| async {
+                            let mut response = req.response?;
+                            response.message.payload = b"pong".to_vec();
+                            Some::<CoapResponse>(response)
+                        })
+                        .await
+                        .unwrap();
+                })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut client = CoAPClient::from_transport(socket, server_addr).unwrap();
+        for _ in 0..3 {
+            let response = client
+                .request_path("/ping", coap_lite::RequestType::Get, None, None, None)
+                .unwrap();
+            assert_eq!(response.message.payload, b"pong".to_vec());
+        }
+    }
+}
@@ -0,0 +1,319 @@
+//! CoAP over WebSockets (coap+ws and coaps+ws,
+//! [RFC 8323](https://tools.ietf.org/html/rfc8323) section 4).
+//!
+//! Every CoAP message travels in one binary WebSocket message using the
+//! framing of CoAP over TCP, minus the length, which the WebSocket already
+//! carries. Connections use the `coap` subprotocol on `/.well-known/coap`
+//! and start with the same CSM exchange as TCP. Requires the `websocket`
+//! feature; coaps+ws additionally requires `tls`.
+use coap_lite::Packet;
+use futures::{future, SinkExt, StreamExt};
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, ToSocketAddrs};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::{HeaderValue, StatusCode};
+use tungstenite::{Message, WebSocket};
+
+use super::tcp::{Connection, MessageStream, TcpClientTransport, TcpTransport};
+use crate::message;
+
+/// WebSocket subprotocol of CoAP.
+pub const SUBPROTOCOL: &str = "coap";
+
+/// Path of the CoAP WebSocket endpoint.
+pub const PATH: &str = "/.well-known/coap";
+
+const PROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+
+/// Encode a message for a binary WebSocket message.
+pub fn encode(packet: &Packet) -> Result<Vec<u8>> {
+    // the UDP header without the Message ID
+    let mut data = Vec::with_capacity(message::encoded_len(packet) - 2);
+    // the Len nibble is always zero, leaving only the token length
+    data.push(packet.get_token().len() as u8);
+    data.push(packet.header.code.into());
+    message::write_body(packet, &mut data)?;
+    Ok(data)
+}
+
+/// Decode a binary WebSocket message.
+pub fn decode(data: &[u8]) -> Result<Packet> {
+    if data.len() < 2 || data[0] >> 4 != 0 {
+        return Err(Error::new(ErrorKind::InvalidData, "invalid message"));
+    }
+    message::join_header(data[0], data[1], &data[2..])
+}
+
+impl TcpTransport {
+    /// Listen for CoAP over WebSocket (coap+ws) connections on the given address.
+    pub async fn bind_ws<A: ToSocketAddrs>(addr: A) -> Result<TcpTransport> {
+        Self::listen(TcpListener::bind(addr).await?, accept)
+    }
+
+    /// Listen for CoAP over secure WebSocket (coaps+ws) connections on the
+    /// given address, with a configuration that may be reloaded like the one
+    /// of [`bind_tls`](TcpTransport::bind_tls).
+    #[cfg(feature = "tls")]
+    pub async fn bind_wss<A: ToSocketAddrs, C: Into<super::tls::ReloadableServerConfig>>(
+        addr: A,
+        config: C,
+    ) -> Result<TcpTransport> {
+        let config = config.into();
+        Self::listen(TcpListener::bind(addr).await?, move |stream| {
+            let handshake = config.acceptor().accept(stream);
+            async move { accept(handshake.await?).await }
+        })
+    }
+}
+
+async fn accept<S>(stream: S) -> Result<impl MessageStream + Send>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let ws = tokio_tungstenite::accept_hdr_async(stream, negotiate)
+        .await
+        .map_err(ws_error)?;
+    Ok(ws
+        .sink_map_err(ws_error)
+        .with(|packet: Packet| future::ready(encode(&packet).map(Message::binary)))
+        .filter_map(|message| {
+            future::ready(match message {
+                Ok(Message::Binary(data)) => Some(decode(&data)),
+                Ok(_) => None,
+                Err(e) => Some(Err(ws_error(e))),
+            })
+        }))
+}
+
+// the signature is given by tungstenite
+#[allow(clippy::result_large_err)]
+fn negotiate(request: &Request, mut response: Response) -> std::result::Result<Response, ErrorResponse> {
+    if request.uri().path() != PATH {
+        return Err(reject(StatusCode::NOT_FOUND, "unknown path"));
+    }
+    let offered = request
+        .headers()
+        .get(PROTOCOL_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|p| p.trim() == SUBPROTOCOL));
+    if !offered {
+        return Err(reject(StatusCode::BAD_REQUEST, "coap subprotocol required"));
+    }
+    response
+        .headers_mut()
+        .insert(PROTOCOL_HEADER, HeaderValue::from_static(SUBPROTOCOL));
+    Ok(response)
+}
+
+fn reject(status: StatusCode, reason: &str) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(reason.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+fn ws_error(e: tungstenite::Error) -> Error {
+    match e {
+        tungstenite::Error::Io(e) => e,
+        e => Error::other(e),
+    }
+}
+
+enum WsStream {
+    Plain(net::TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<super::tls::ClientStream>),
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            WsStream::Plain(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            WsStream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            WsStream::Plain(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            WsStream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            WsStream::Plain(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            WsStream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+struct WsConnection {
+    socket: net::TcpStream,
+    ws: Mutex<WebSocket<WsStream>>,
+}
+
+impl WsConnection {
+    fn open(socket: net::TcpStream, stream: WsStream, scheme: &str, host: &str) -> Result<WsConnection> {
+        let mut request = format!("{}://{}{}", scheme, host, PATH)
+            .into_client_request()
+            .map_err(ws_error)?;
+        request
+            .headers_mut()
+            .insert(PROTOCOL_HEADER, HeaderValue::from_static(SUBPROTOCOL));
+        let (ws, _response) = tungstenite::client(request, stream)
+            .map_err(|e| Error::new(ErrorKind::ConnectionRefused, e.to_string()))?;
+        Ok(WsConnection {
+            socket,
+            ws: Mutex::new(ws),
+        })
+    }
+}
+
+impl Connection for WsConnection {
+    fn write_packet(&self, packet: Packet) -> Result<()> {
+        let message = Message::binary(encode(&packet)?);
+        self.ws.lock().unwrap().send(message).map_err(ws_error)
+    }
+
+    fn read_packet(&self) -> Result<Packet> {
+        let mut ws = self.ws.lock().unwrap();
+        loop {
+            match ws.read().map_err(ws_error)? {
+                Message::Binary(data) => return decode(&data),
+                Message::Close(_) => {
+                    return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed"))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn socket(&self) -> &net::TcpStream {
+        &self.socket
+    }
+}
+
+impl TcpClientTransport {
+    /// Connect to a CoAP over WebSocket (coap+ws) endpoint. `host` is sent in
+    /// the Host header of the upgrade request.
+    pub fn connect_ws<A: net::ToSocketAddrs>(addr: A, host: &str) -> Result<TcpClientTransport> {
+        let socket = net::TcpStream::connect(addr)?;
+        socket.set_nodelay(true)?;
+        let stream = WsStream::Plain(socket.try_clone()?);
+        Self::handshake(WsConnection::open(socket, stream, "ws", host)?)
+    }
+
+    /// Connect to a CoAP over secure WebSocket (coaps+ws) endpoint.
+    /// `server_name` is used for SNI, certificate verification and the Host
+    /// header.
+    #[cfg(feature = "tls")]
+    pub fn connect_wss<A: net::ToSocketAddrs>(
+        addr: A,
+        server_name: &str,
+        config: std::sync::Arc<super::tls::rustls::ClientConfig>,
+    ) -> Result<TcpClientTransport> {
+        let socket = net::TcpStream::connect(addr)?;
+        socket.set_nodelay(true)?;
+        let tls = super::tls::client_stream(socket.try_clone()?, server_name, config)?;
+        let stream = WsStream::Tls(Box::new(tls));
+        Self::handshake(WsConnection::open(socket, stream, "wss", server_name)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::super::*;
+    use super::*;
+    use coap_lite::{CoapOption, CoapRequest, CoapResponse, MessageClass, RequestType as Method};
+    use std::net::SocketAddr;
+
+    #[test]
+    fn test_encode_decode() {
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(Method::Get);
+        packet.set_token(vec![1, 2]);
+        packet.add_option(CoapOption::UriPath, b"a".to_vec());
+        packet.payload = b"x".to_vec();
+
+        let data = encode(&packet).unwrap();
+        assert_eq!(&data[..3], &[0x02, 0x01, 1]);
+        let decoded = decode(&data).unwrap();
+        assert_eq!(decoded.get_token(), packet.get_token());
+        assert_eq!(decoded.payload, packet.payload);
+        assert!(decode(&[0x12, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_websocket() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let transport = TcpTransport::bind_ws("127.0.0.1:0").await.unwrap();
+                let mut server = Server::from_transport(transport);
+                tx.send(server.socket_addr().unwrap()).unwrap();
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        let mut response = req.response?;
+                        response.message.payload = req.message.payload;
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let mut client = CoAPClient::new_ws(server_addr).unwrap();
+        let response = client
+            .request_path("/echo", Method::Post, Some(b"ws".to_vec()), None, None)
+            .unwrap();
+        assert_eq!(response.message.payload, b"ws".to_vec());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_secure_websocket() {
+        use super::super::tls::{self, rustls};
+
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = rustls::pki_types::PrivateKeyDer::try_from(certified.key_pair.serialize_der()).unwrap();
+        let config = tls::server_config(vec![cert.clone()], key).unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new().unwrap().block_on(async move {
+                let transport = TcpTransport::bind_wss("127.0.0.1:0", config).await.unwrap();
+                let mut server = Server::from_transport(transport);
+                tx.send(server.socket_addr().unwrap()).unwrap();
+                server
+                    .run(|req: CoapRequest<SocketAddr>| async {
+                        let mut response = req.response?;
+                        response.message.payload = b"wss".to_vec();
+                        Some::<CoapResponse>(response)
+                    })
+                    .await
+                    .unwrap();
+            })
+        });
+        let server_addr = rx.recv().unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let transport =
+            TcpClientTransport::connect_wss(server_addr, "localhost", tls::client_config(roots).unwrap())
+                .unwrap();
+        let mut client = CoAPClient::from_transport(transport, server_addr).unwrap();
+        let response = client.request_path("/", Method::Get, None, None, None).unwrap();
+        assert_eq!(response.message.payload, b"wss".to_vec());
+    }
+}
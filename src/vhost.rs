@@ -0,0 +1,158 @@
+//! Name-based virtual hosting.
+//!
+//! [`VirtualHosts`] dispatches requests to a handler per Uri-Host, so that
+//! one server can serve several origins behind different DNS names. A
+//! request without a Uri-Host addresses the server by its IP address and
+//! goes to the default handler, as do requests for hosts without a handler
+//! of their own. Without a default handler, those are answered with 4.04
+//! Not Found.
+use coap_lite::{CoapOption, CoapRequest, CoapResponse, ResponseType as Status};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+/// The future of a response from one of the handlers.
+pub type ResponseFuture = Pin<Box<dyn Future<Output = Option<CoapResponse>> + Send>>;
+
+type Handler = Box<dyn FnMut(CoapRequest<SocketAddr>) -> ResponseFuture + Send>;
+
+/// Handlers by Uri-Host, with an optional default.
+#[derive(Default)]
+pub struct VirtualHosts {
+    hosts: HashMap<String, Handler>,
+    default: Option<Handler>,
+}
+
+impl VirtualHosts {
+    /// Create a dispatcher without any hosts.
+    pub fn new() -> VirtualHosts {
+        VirtualHosts::default()
+    }
+
+    /// Serve requests for `host` with `handler`. Host names are compared
+    /// case-insensitively.
+    pub fn with_host<F, R>(mut self, host: &str, handler: F) -> VirtualHosts
+    where
+        F: FnMut(CoapRequest<SocketAddr>) -> R + Send + 'static,
+        R: Future<Output = Option<CoapResponse>> + Send + 'static,
+    {
+        self.hosts.insert(normalize(host), boxed(handler));
+        self
+    }
+
+    /// Serve requests for any other host with `handler`.
+    pub fn with_default<F, R>(mut self, handler: F) -> VirtualHosts
+    where
+        F: FnMut(CoapRequest<SocketAddr>) -> R + Send + 'static,
+        R: Future<Output = Option<CoapResponse>> + Send + 'static,
+    {
+        self.default = Some(boxed(handler));
+        self
+    }
+
+    /// Pass `request` to the handler of its host, to be called from the
+    /// handler given to [`Server::run`](crate::Server::run).
+    pub fn handle(&mut self, mut request: CoapRequest<SocketAddr>) -> ResponseFuture {
+        let handler = match host(&request) {
+            Some(host) => self.hosts.get_mut(&host),
+            None => None,
+        };
+        match handler.or(self.default.as_mut()) {
+            Some(handler) => handler(request),
+            None => {
+                if let Some(ref mut response) = request.response {
+                    response.set_status(Status::NotFound);
+                }
+                Box::pin(async move { request.response })
+            }
+        }
+    }
+}
+
+fn boxed<F, R>(mut handler: F) -> Handler
+where
+    F: FnMut(CoapRequest<SocketAddr>) -> R + Send + 'static,
+    R: Future<Output = Option<CoapResponse>> + Send + 'static,
+{
+    Box::new(move |request| Box::pin(handler(request)))
+}
+
+/// Return the Uri-Host of a request, normalized.
+pub fn host(request: &CoapRequest<SocketAddr>) -> Option<String> {
+    let host = request.message.get_option(CoapOption::UriHost)?.front()?;
+    Some(normalize(&String::from_utf8_lossy(host)))
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::*;
+    use super::*;
+    use coap_lite::{MessageClass, Packet, RequestType as Method};
+
+    fn respond(
+        name: &'static str,
+    ) -> impl FnMut(CoapRequest<SocketAddr>) -> ResponseFuture + Send + 'static {
+        move |request: CoapRequest<SocketAddr>| {
+            Box::pin(async move {
+                let mut response = request.response?;
+                response.message.payload = name.as_bytes().to_vec();
+                Some(response)
+            })
+        }
+    }
+
+    #[test]
+    fn test_virtual_hosts() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async move {
+                    let mut hosts = VirtualHosts::new()
+                        .with_host("sensors.example.com", respond("sensors"))
+                        .with_host("lights.example.com", respond("lights"))
+                        .with_default(respond("default"));
+                    let mut server = Server::new("127.0.0.1:0").unwrap();
+                    tx.send(server.socket_addr().unwrap()).unwrap();
+                    server
+                        .run(move |request| hosts.handle(request))
+                        .await
+                        .unwrap();
+                });
+        });
+        let client = CoAPClient::new(rx.recv().unwrap()).unwrap();
+        let send = |host: Option<&str>| {
+            let mut request: CoapRequest<SocketAddr> = CoapRequest::new();
+            request.set_method(Method::Get);
+            request.set_path("/status");
+            request.message.set_token(vec![1]);
+            if let Some(host) = host {
+                request
+                    .message
+                    .add_option(CoapOption::UriHost, host.as_bytes().to_vec());
+            }
+            client.send(&request).unwrap();
+            client.receive().unwrap().message.payload
+        };
+
+        assert_eq!(send(Some("sensors.example.com")), b"sensors");
+        assert_eq!(send(Some("Lights.Example.com.")), b"lights");
+        assert_eq!(send(Some("other.example.com")), b"default");
+        assert_eq!(send(None), b"default");
+    }
+
+    #[test]
+    fn test_no_default() {
+        let mut hosts = VirtualHosts::new().with_host("sensors.example.com", respond("sensors"));
+        let mut packet = Packet::new();
+        packet.header.code = MessageClass::Request(Method::Get);
+        let request = CoapRequest::from_packet(packet, "127.0.0.1:5683".parse().unwrap());
+        let response = futures::executor::block_on(hosts.handle(request)).unwrap();
+        assert_eq!(*response.get_status(), Status::NotFound);
+    }
+}